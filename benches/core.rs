@@ -0,0 +1,228 @@
+// Benchmarks of the paths run per frame or per peer. `cargo bench` compares with
+// the previous run and reports the changes beyond the noise threshold.
+//
+// As a gate, save a baseline on the base branch and compare with it:
+//
+//     cargo bench --bench core -- --save-baseline main
+//     cargo bench --bench core -- --baseline main
+//
+// the second fails if a benchmark is slower than the baseline by more than
+// `MAX_REGRESSION`, even at the lower bound of its confidence interval.
+use criterion::{black_box, criterion_group, BatchSize, Criterion, Throughput};
+use hbb_common::{
+    bytes::{Bytes, BytesMut},
+    bytes_codec::BytesCodec,
+    compress::{compress, decompress, Codec},
+    config::{load_path, store_path, PeerConfig},
+    password_security::{decrypt_vec_or_original, encrypt_vec_or_original, symmetric_crypt},
+    tokio_util::codec::{Decoder, Encoder},
+};
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+const PEERS: usize = 1000;
+const MAX_REGRESSION: f64 = 0.10;
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("hbb_bench_{}_{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn peer() -> PeerConfig {
+    let mut peer = PeerConfig::default();
+    peer.info.username = "user".to_owned();
+    peer.info.hostname = "host".to_owned();
+    peer.info.platform = "Linux".to_owned();
+    for i in 0..50 {
+        peer.ui_flutter
+            .insert(format!("key{}", i), format!("value{}", i));
+    }
+    peer
+}
+
+fn payload(len: usize) -> Vec<u8> {
+    // compressible like typical protobuf and text, not a constant run
+    (0..len)
+        .map(|i| (i % 251) as u8 ^ (i / 4096) as u8)
+        .collect()
+}
+
+fn bench_config(c: &mut Criterion) {
+    let dir = temp_dir("config");
+    let path = dir.join("peer.toml");
+    let peer = peer();
+    c.bench_function("config/store", |b| {
+        let mut n = 0;
+        b.iter(|| {
+            // a different value each time, unchanged stores are skipped
+            let mut peer = peer.clone();
+            peer.direct_failures = n;
+            n += 1;
+            store_path(path.clone(), peer).unwrap()
+        })
+    });
+    c.bench_function("config/load", |b| {
+        b.iter(|| load_path::<PeerConfig>(black_box(path.clone())))
+    });
+
+    let paths: Vec<PathBuf> = (0..PEERS)
+        .map(|i| {
+            let path = dir.join(format!("{}.toml", i));
+            store_path(path.clone(), &peer).unwrap();
+            path
+        })
+        .collect();
+    let mut group = c.benchmark_group("config");
+    group.throughput(Throughput::Elements(PEERS as _));
+    group.sample_size(10);
+    group.bench_function("load_peers", |b| {
+        b.iter(|| {
+            paths
+                .iter()
+                .map(|p| load_path::<PeerConfig>(p.clone()))
+                .filter(|p| !p.info.platform.is_empty())
+                .count()
+        })
+    });
+    group.finish();
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+fn bench_compress(c: &mut Criterion) {
+    let data = payload(128 * 1024);
+    let mut group = c.benchmark_group("compress");
+    group.throughput(Throughput::Bytes(data.len() as _));
+    group.bench_function("zstd", |b| b.iter(|| compress(black_box(&data))));
+    let compressed = compress(&data);
+    group.bench_function("zstd_decompress", |b| {
+        b.iter(|| decompress(black_box(&compressed)))
+    });
+    for codec in [Codec::Lz4, Codec::Brotli] {
+        let compressor = codec.compressor();
+        group.bench_function(codec.name(), |b| {
+            b.iter(|| compressor.compress(black_box(&data)))
+        });
+        let compressed = compressor.compress(&data);
+        group.bench_function(format!("{}_decompress", codec.name()), |b| {
+            b.iter(|| compressor.decompress(black_box(&compressed)))
+        });
+    }
+    group.finish();
+}
+
+fn bench_codec(c: &mut Criterion) {
+    let frame = Bytes::from(payload(16 * 1024));
+    let mut group = c.benchmark_group("bytes_codec");
+    group.throughput(Throughput::Bytes(frame.len() as _));
+    group.bench_function("encode_decode", |b| {
+        b.iter_batched(
+            || BytesMut::with_capacity(frame.len() + 4),
+            |mut buf| {
+                let mut codec = BytesCodec::new();
+                codec.encode(frame.clone(), &mut buf).unwrap();
+                codec.decode(&mut buf).unwrap().unwrap()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn bench_crypt(c: &mut Criterion) {
+    let data = payload(64 * 1024);
+    let mut group = c.benchmark_group("crypt");
+    group.throughput(Throughput::Bytes(data.len() as _));
+    group.bench_function("symmetric", |b| {
+        b.iter(|| {
+            let encrypted = symmetric_crypt(black_box(&data), true).unwrap();
+            symmetric_crypt(&encrypted, false).unwrap()
+        })
+    });
+    let password = b"password of a peer".to_vec();
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("peer_password", |b| {
+        b.iter(|| {
+            let encrypted = encrypt_vec_or_original(black_box(&password), "01", 128);
+            decrypt_vec_or_original(&encrypted, "01")
+        })
+    });
+    group.finish();
+}
+
+fn config() -> Criterion {
+    Criterion::default()
+        .noise_threshold(0.05)
+        .significance_level(0.01)
+        .measurement_time(Duration::from_secs(3))
+}
+
+criterion_group! {
+    name = benches;
+    config = config();
+    targets = bench_config, bench_compress, bench_codec, bench_crypt
+}
+
+fn criterion_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("CRITERION_HOME") {
+        return dir.into();
+    }
+    let target = std::env::var_os("CARGO_TARGET_DIR").unwrap_or_else(|| "target".into());
+    Path::new(&target).join("criterion")
+}
+
+// The mean changes of the benchmarks compared since `since`, the lower bound of
+// which is above `MAX_REGRESSION`.
+fn regressions(dir: &Path, since: SystemTime) -> Vec<(PathBuf, f64)> {
+    let mut res = Vec::new();
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return res;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            res.extend(regressions(&path, since));
+            continue;
+        }
+        let fresh = entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .map_or(false, |t| t >= since);
+        if !fresh || !path.ends_with("change/estimates.json") {
+            continue;
+        }
+        let Ok(data) = std::fs::read(&path) else {
+            continue;
+        };
+        let lower = serde_json::from_slice::<serde_json::Value>(&data)
+            .ok()
+            .and_then(|x| x["mean"]["confidence_interval"]["lower_bound"].as_f64());
+        if let Some(lower) = lower.filter(|x| *x > MAX_REGRESSION) {
+            let name = path.ancestors().nth(2).unwrap_or(&path).to_path_buf();
+            res.push((name, lower));
+        }
+    }
+    res
+}
+
+fn main() {
+    let since = SystemTime::now();
+    benches();
+    Criterion::default().configure_from_args().final_summary();
+    if !std::env::args().any(|x| x.starts_with("--baseline")) {
+        return;
+    }
+    let regressions = regressions(&criterion_dir(), since);
+    for (name, change) in regressions.iter() {
+        eprintln!(
+            "{} regressed by at least {:.1}%",
+            name.display(),
+            change * 100.0
+        );
+    }
+    if !regressions.is_empty() {
+        std::process::exit(1);
+    }
+}
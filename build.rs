@@ -11,4 +11,44 @@ fn main() {
         .customize(protobuf_codegen::Customize::default().tokio_bytes(true))
         .run()
         .expect("Codegen failed.");
+    // the rerun-if-env-changed below disables the default of any file
+    println!("cargo:rerun-if-changed=protos");
+    println!("cargo:rerun-if-changed=build.rs");
+
+    embed_custom_config();
+}
+
+// The custom config embedded by `custom_config`: HBB_CUSTOM_CONFIG is the path of
+// the custom.toml, signed with the key of HBB_CUSTOM_CONFIG_PK, its detached
+// signature in base64 at HBB_CUSTOM_CONFIG_SIG or next to it as custom.toml.sig.
+// Empty files are embedded without.
+fn embed_custom_config() {
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    for k in [
+        "HBB_CUSTOM_CONFIG",
+        "HBB_CUSTOM_CONFIG_SIG",
+        "HBB_CUSTOM_CONFIG_PK",
+    ] {
+        println!("cargo:rerun-if-env-changed={}", k);
+    }
+    let (config, sig) = match std::env::var("HBB_CUSTOM_CONFIG") {
+        Ok(path) if !path.is_empty() => {
+            let sig_path =
+                std::env::var("HBB_CUSTOM_CONFIG_SIG").unwrap_or_else(|_| format!("{}.sig", path));
+            println!("cargo:rerun-if-changed={}", path);
+            println!("cargo:rerun-if-changed={}", sig_path);
+            (
+                std::fs::read(&path).expect("Failed to read HBB_CUSTOM_CONFIG"),
+                std::fs::read(&sig_path)
+                    .expect("Failed to read the signature of the custom config"),
+            )
+        }
+        _ => (vec![], vec![]),
+    };
+    std::fs::write(format!("{}/custom.toml", out_dir), config).unwrap();
+    std::fs::write(format!("{}/custom.toml.sig", out_dir), sig).unwrap();
+    println!(
+        "cargo:rustc-env=HBB_CUSTOM_CONFIG_PK={}",
+        std::env::var("HBB_CUSTOM_CONFIG_PK").unwrap_or_default()
+    );
 }
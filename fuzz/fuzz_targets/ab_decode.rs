@@ -0,0 +1,17 @@
+// The address book and group caches: decrypt, decompress and parse. The input
+// is also encrypted first, else few inputs would get past the decryption.
+#![no_main]
+use hbb_common::{
+    config::{Ab, Group},
+    password_security::symmetric_crypt,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Ab::decode(data);
+    let _ = Group::decode(data);
+    if let Ok(data) = symmetric_crypt(data, true) {
+        let _ = Ab::decode(&data);
+        let _ = Group::decode(&data);
+    }
+});
@@ -0,0 +1,26 @@
+// Frames of untrusted peers, split at arbitrary points as by the network.
+#![no_main]
+use hbb_common::{
+    bytes::BytesMut,
+    bytes_codec::BytesCodec,
+    tokio_util::codec::Decoder,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&split, data)) = data.split_first() else {
+        return;
+    };
+    for raw in [false, true] {
+        let mut codec = BytesCodec::new();
+        codec.set_max_packet_length(64 * 1024);
+        if raw {
+            codec.set_raw();
+        }
+        let mut buf = BytesMut::new();
+        for chunk in data.chunks(split.max(1) as usize) {
+            buf.extend_from_slice(chunk);
+            while let Ok(Some(_)) = codec.decode(&mut buf) {}
+        }
+    }
+});
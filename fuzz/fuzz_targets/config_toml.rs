@@ -0,0 +1,20 @@
+// Config files may be corrupt or edited by hand, loading must not panic.
+#![no_main]
+use hbb_common::{
+    config::{Config, Config2, LocalConfig, PeerConfig},
+    toml,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(s) = std::str::from_utf8(data) else {
+        return;
+    };
+    let _ = toml::from_str::<Config>(s);
+    let _ = toml::from_str::<Config2>(s);
+    let _ = toml::from_str::<LocalConfig>(s);
+    if let Ok(peer) = toml::from_str::<PeerConfig>(s) {
+        // what is loaded is stored again
+        let _ = toml::to_string(&peer);
+    }
+});
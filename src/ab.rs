@@ -0,0 +1,1001 @@
+// Incremental synchronization of the address book with the api-server.
+//
+// Instead of uploading the whole JSON of a book after every edit, which grows
+// with the number of peers until it hits the cap of `compress`, only the peers
+// changed since the last synchronization are sent, as an `AbChangeset` computed
+// by `AbEntry::diff` from the book as last synchronized and as edited. Every peer
+// carries the `etag` the api-server gave its last version, so the server can
+// reject the changes made on an older one. The changes of the server are pulled
+// the same way, from the `revision` of the book, and applied with
+// `AbEntry::apply`.
+//
+// When the book of the server changed since, e.g. by another console, the local
+// edits are merged into it with `Ab::merge` instead of overwriting it, three-way
+// against the book as last synchronized, field by field, and the fields changed
+// on both sides are reported as `AbConflict`s.
+//
+// The edits made while the api-server is unreachable are kept in the `AbQueue`,
+// stored encrypted with a key of the user that survives a refresh of the access
+// token, and replayed on the book pulled after reconnecting, except those made on
+// a peer changed remotely since. The processes of the app queue under a file
+// lock, and a queue that can not be read is moved aside instead of dropped.
+//
+// A book shared with less than full `AbPermission` can not be changed locally,
+// the edits fail with an `AbError`, and is never sent back.
+use crate::{
+    config::{account_open, user_open, user_seal, Ab, AbEntry, AbPeer, Config, APP_NAME, TOKEN_AB},
+    ResultType,
+};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::PathBuf,
+};
+
+/// The oldest ops are dropped beyond.
+const MAX_PENDING: usize = 10_000;
+
+/// What may be changed in a shared book.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AbPermission {
+    Read,
+    /// The peers, with the tags of the book.
+    ReadWrite,
+    /// Also the tags of the book.
+    Full,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AbError {
+    ReadOnly {
+        guid: String,
+    },
+    NotPermitted {
+        guid: String,
+    },
+    UnknownBook {
+        guid: String,
+    },
+    /// The peer was changed or removed remotely since.
+    Conflict {
+        guid: String,
+        id: String,
+    },
+}
+
+impl std::fmt::Display for AbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ReadOnly { guid } => write!(f, "Address book {} is read-only", guid),
+            Self::NotPermitted { guid } => {
+                write!(f, "Not permitted to change address book {}", guid)
+            }
+            Self::UnknownBook { guid } => write!(f, "Unknown address book {}", guid),
+            Self::Conflict { guid, id } => {
+                write!(f, "Peer {} of address book {} changed since", id, guid)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AbError {}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AbChangeset {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub guid: String,
+    /// The revision of the book the changes are made on, or, from the api-server,
+    /// its revision after them.
+    #[serde(default)]
+    pub revision: i64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub upserted: Vec<AbPeer>,
+    /// Only the `id` and `etag` of the peers are set.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<AbPeer>,
+    /// Set if the tags of the book changed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag_colors: Option<String>,
+}
+
+impl AbChangeset {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.upserted.is_empty()
+            && self.removed.is_empty()
+            && self.tags.is_none()
+            && self.tag_colors.is_none()
+    }
+}
+
+impl AbEntry {
+    pub fn permission(&self) -> AbPermission {
+        if self.read_only {
+            return AbPermission::Read;
+        }
+        match self.permission.as_str() {
+            "" | "full" => AbPermission::Full,
+            "read-write" => AbPermission::ReadWrite,
+            _ => AbPermission::Read,
+        }
+    }
+
+    pub fn check(&self, required: AbPermission) -> Result<(), AbError> {
+        let permission = self.permission();
+        if permission >= required {
+            Ok(())
+        } else if permission == AbPermission::Read {
+            Err(AbError::ReadOnly {
+                guid: self.guid.clone(),
+            })
+        } else {
+            Err(AbError::NotPermitted {
+                guid: self.guid.clone(),
+            })
+        }
+    }
+
+    /// The changes from `self`, the book as last synchronized, to `edited`.
+    pub fn diff(&self, edited: &AbEntry) -> AbChangeset {
+        let old: HashMap<&str, &AbPeer> = self.peers.iter().map(|p| (p.id.as_str(), p)).collect();
+        let new: HashMap<&str, &AbPeer> = edited.peers.iter().map(|p| (p.id.as_str(), p)).collect();
+        let upserted = edited
+            .peers
+            .iter()
+            .filter(|p| old.get(p.id.as_str()) != Some(p))
+            .cloned()
+            .collect();
+        let removed = self
+            .peers
+            .iter()
+            .filter(|p| !new.contains_key(p.id.as_str()))
+            .map(|p| AbPeer {
+                id: p.id.clone(),
+                etag: p.etag.clone(),
+                ..Default::default()
+            })
+            .collect();
+        AbChangeset {
+            guid: self.guid.clone(),
+            revision: self.revision,
+            upserted,
+            removed,
+            tags: (self.tags != edited.tags).then(|| edited.tags.clone()),
+            tag_colors: (self.tag_colors != edited.tag_colors).then(|| edited.tag_colors.clone()),
+        }
+    }
+
+    /// Applies the changes pulled from the api-server, returns false if they are
+    /// not newer than the book.
+    pub fn apply(&mut self, changeset: &AbChangeset) -> bool {
+        if changeset.revision != 0 && changeset.revision <= self.revision {
+            return false;
+        }
+        self.peers
+            .retain(|p| !changeset.removed.iter().any(|r| r.id == p.id));
+        for peer in changeset.upserted.iter() {
+            match self.peers.iter_mut().find(|p| p.id == peer.id) {
+                Some(p) => *p = peer.clone(),
+                None => self.peers.push(peer.clone()),
+            }
+        }
+        if let Some(tags) = &changeset.tags {
+            self.tags = tags.clone();
+        }
+        if let Some(tag_colors) = &changeset.tag_colors {
+            self.tag_colors = tag_colors.clone();
+        }
+        self.revision = self.revision.max(changeset.revision);
+        true
+    }
+}
+
+impl Ab {
+    /// The changes of all books, the non-empty ones only. A book not synchronized
+    /// yet is sent whole, the changes not permitted in a shared book are not sent.
+    pub fn diff(&self, edited: &Ab) -> Vec<AbChangeset> {
+        let mut res = vec![];
+        for e in edited.ab_entries.iter() {
+            let old = self.ab_entries.iter().find(|x| x.guid == e.guid);
+            // of the api-server, not of the edits
+            let permission = old.unwrap_or(e).permission();
+            if permission == AbPermission::Read {
+                continue;
+            }
+            let mut changeset = match old {
+                Some(old) => old.diff(e),
+                None => AbEntry {
+                    guid: e.guid.clone(),
+                    ..Default::default()
+                }
+                .diff(e),
+            };
+            if permission < AbPermission::Full {
+                changeset.tags = None;
+                changeset.tag_colors = None;
+            }
+            if !changeset.is_empty() {
+                res.push(changeset);
+            }
+        }
+        res
+    }
+
+    /// Applies the changes pulled from the api-server to the books of their guid,
+    /// returns whether any changed, then the book is to be `save`d.
+    pub fn apply(&mut self, changesets: &[AbChangeset]) -> bool {
+        let mut changed = false;
+        for changeset in changesets {
+            match self
+                .ab_entries
+                .iter_mut()
+                .find(|e| e.guid == changeset.guid)
+            {
+                Some(entry) => changed |= entry.apply(changeset),
+                None => log::warn!("Changes of unknown address book {}", changeset.guid),
+            }
+        }
+        changed
+    }
+}
+
+/// How `Ab::merge` resolves a field with different values locally and remotely.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// The value changed last by `AbPeer::modified`, the remote one if not known,
+    /// e.g. for the fields of the book.
+    LastWriterWins,
+    PreferRemote,
+    /// Keeps the local values, the conflicts are to be resolved by the user with
+    /// `Ab::resolve`.
+    Manual,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AbConflict {
+    pub guid: String,
+    /// The id of the peer, empty for a field of the book.
+    pub id: String,
+    /// The name of the field in the json, e.g. "alias", empty for the whole peer,
+    /// removed on one side and changed on the other, Null on that side.
+    pub field: String,
+    pub local: Value,
+    pub remote: Value,
+    /// Whether the remote value is kept.
+    pub remote_won: bool,
+}
+
+impl AbPeer {
+    /// Records a local change of `field`, e.g. "alias", for
+    /// `MergeStrategy::LastWriterWins`.
+    pub fn touch(&mut self, field: &str) {
+        self.modified.insert(field.to_owned(), crate::get_time());
+    }
+
+    fn to_map(&self) -> Map<String, Value> {
+        match serde_json::to_value(self) {
+            Ok(Value::Object(map)) => map,
+            _ => Map::new(),
+        }
+    }
+
+    fn set_field(&mut self, field: &str, v: Value) {
+        let mut map = self.to_map();
+        if v.is_null() {
+            map.remove(field);
+        } else {
+            map.insert(field.to_owned(), v);
+        }
+        match serde_json::from_value(Value::Object(map)) {
+            Ok(peer) => *self = peer,
+            Err(err) => log::error!("Failed to set {} of ab peer: {}", field, err),
+        }
+    }
+}
+
+impl Ab {
+    /// Merges the local edits into the book of the api-server, three-way against
+    /// `base`, the books as last synchronized: the books are the remote ones, what
+    /// changed on one side only is taken from it, e.g. a peer or tag added or
+    /// removed, and the fields changed on both to different values are resolved
+    /// by `strategy` and reported, with the resolved ones. The result is to be
+    /// sent with the `etag`s of the remote peers. The local edits of a read-only
+    /// book are dropped.
+    pub fn merge(
+        base: &Ab,
+        local: &Ab,
+        remote: &Ab,
+        strategy: MergeStrategy,
+    ) -> (Ab, Vec<AbConflict>) {
+        let mut merged = remote.clone();
+        merged.access_token = local.access_token.clone();
+        let mut conflicts = vec![];
+        let empty = AbEntry::default();
+        for entry in merged.ab_entries.iter_mut() {
+            if entry.permission() == AbPermission::Read {
+                continue;
+            }
+            if let Some(l) = local.ab_entries.iter().find(|l| l.guid == entry.guid) {
+                let b = base
+                    .ab_entries
+                    .iter()
+                    .find(|b| b.guid == entry.guid)
+                    .unwrap_or(&empty);
+                merge_entry(b, l, entry, strategy, &mut conflicts);
+            }
+        }
+        (merged, conflicts)
+    }
+
+    /// Sets the value of a conflict reported by `merge`, e.g. as chosen by the user.
+    pub fn resolve(&mut self, conflict: &AbConflict, take_remote: bool) -> Result<(), AbError> {
+        let entry = self.entry_mut(&conflict.guid)?;
+        let v = if take_remote {
+            &conflict.remote
+        } else {
+            &conflict.local
+        };
+        if conflict.id.is_empty() {
+            entry.check(AbPermission::Full)?;
+            if conflict.field == "tag_colors" {
+                entry.tag_colors = v.as_str().unwrap_or_default().to_owned();
+            }
+            return Ok(());
+        }
+        entry.check(AbPermission::ReadWrite)?;
+        if conflict.field.is_empty() {
+            // the whole peer, removed if Null
+            entry.peers.retain(|p| p.id != conflict.id);
+            if let Ok(peer) = serde_json::from_value::<AbPeer>(v.clone()) {
+                entry.peers.push(peer);
+            }
+            return Ok(());
+        }
+        let Some(peer) = entry.peers.iter_mut().find(|p| p.id == conflict.id) else {
+            return Err(AbError::Conflict {
+                guid: conflict.guid.clone(),
+                id: conflict.id.clone(),
+            });
+        };
+        peer.set_field(&conflict.field, v.clone());
+        peer.touch(&conflict.field);
+        Ok(())
+    }
+
+    /// Applies a local edit to the book of `guid`, to be queued with `AbQueue::push`
+    /// if offline.
+    pub fn edit(&mut self, guid: &str, op: &AbOp) -> Result<(), AbError> {
+        op.apply(self.entry_mut(guid)?)
+    }
+
+    fn entry_mut(&mut self, guid: &str) -> Result<&mut AbEntry, AbError> {
+        self.ab_entries
+            .iter_mut()
+            .find(|e| e.guid == guid)
+            .ok_or_else(|| AbError::UnknownBook {
+                guid: guid.to_owned(),
+            })
+    }
+}
+
+fn find<'a>(peers: &'a [AbPeer], id: &str) -> Option<&'a AbPeer> {
+    peers.iter().find(|p| p.id == id)
+}
+
+fn whole_peer(p: &AbPeer) -> Value {
+    serde_json::to_value(p).unwrap_or(Value::Null)
+}
+
+fn merge_entry(
+    base: &AbEntry,
+    local: &AbEntry,
+    merged: &mut AbEntry,
+    strategy: MergeStrategy,
+    conflicts: &mut Vec<AbConflict>,
+) {
+    let guid = merged.guid.clone();
+    let full = merged.permission() == AbPermission::Full;
+    if full {
+        for tag in local.tags.iter().filter(|t| !base.tags.contains(t)) {
+            if !merged.tags.contains(tag) {
+                merged.tags.push(tag.clone());
+            }
+        }
+        // removed locally
+        merged
+            .tags
+            .retain(|t| local.tags.contains(t) || !base.tags.contains(t));
+        if local.tag_colors != base.tag_colors && local.tag_colors != merged.tag_colors {
+            if merged.tag_colors == base.tag_colors {
+                merged.tag_colors = local.tag_colors.clone();
+            } else {
+                let remote_won = strategy != MergeStrategy::Manual;
+                conflicts.push(AbConflict {
+                    guid: guid.clone(),
+                    id: String::new(),
+                    field: "tag_colors".to_owned(),
+                    local: Value::String(local.tag_colors.clone()),
+                    remote: Value::String(merged.tag_colors.clone()),
+                    remote_won,
+                });
+                if !remote_won {
+                    merged.tag_colors = local.tag_colors.clone();
+                }
+            }
+        }
+    }
+    // a peer removed on one side and changed on the other is a conflict, the
+    // remote side wins unless `Manual`, the time of a removal is not known
+    let remote_won = strategy != MergeStrategy::Manual;
+    for b in base
+        .peers
+        .iter()
+        .filter(|b| find(&local.peers, &b.id).is_none())
+    {
+        let Some(i) = merged.peers.iter().position(|r| r.id == b.id) else {
+            continue;
+        };
+        if same_content(&merged.peers[i], b) {
+            merged.peers.remove(i);
+            continue;
+        }
+        conflicts.push(AbConflict {
+            guid: guid.clone(),
+            id: b.id.clone(),
+            field: String::new(),
+            local: Value::Null,
+            remote: whole_peer(&merged.peers[i]),
+            remote_won,
+        });
+        if !remote_won {
+            merged.peers.remove(i);
+        }
+    }
+    for l in local.peers.iter() {
+        let b = find(&base.peers, &l.id);
+        match merged.peers.iter_mut().find(|r| r.id == l.id) {
+            Some(r) if r != l => merge_peer(&guid, b, l, r, strategy, conflicts),
+            Some(_) => {}
+            // added locally
+            None if b.is_none() => merged.peers.push(l.clone()),
+            // removed remotely
+            None if b.map_or(false, |b| same_content(b, l)) => {}
+            None => {
+                conflicts.push(AbConflict {
+                    guid: guid.clone(),
+                    id: l.id.clone(),
+                    field: String::new(),
+                    local: whole_peer(l),
+                    remote: Value::Null,
+                    remote_won,
+                });
+                if !remote_won {
+                    merged.peers.push(l.clone());
+                }
+            }
+        }
+    }
+}
+
+fn merge_peer(
+    guid: &str,
+    base: Option<&AbPeer>,
+    local: &AbPeer,
+    merged: &mut AbPeer,
+    strategy: MergeStrategy,
+    conflicts: &mut Vec<AbConflict>,
+) {
+    let b = base.map(AbPeer::to_map).unwrap_or_default();
+    let l = local.to_map();
+    let r = merged.to_map();
+    let fields: BTreeSet<&String> = l
+        .keys()
+        .chain(r.keys())
+        .filter(|k| !matches!(k.as_str(), "id" | "etag" | "modified"))
+        .collect();
+    let time = |p: &AbPeer, field: &str| p.modified.get(field).copied().unwrap_or(0);
+    let mut kept_local = vec![];
+    for field in fields {
+        let lv = l.get(field).cloned().unwrap_or(Value::Null);
+        let rv = r.get(field).cloned().unwrap_or(Value::Null);
+        let bv = b.get(field).cloned().unwrap_or(Value::Null);
+        // the same, or changed remotely only
+        if lv == rv || lv == bv {
+            continue;
+        }
+        // changed locally only
+        if rv == bv {
+            kept_local.push((field.clone(), lv));
+            continue;
+        }
+        let remote_won = match strategy {
+            MergeStrategy::LastWriterWins => time(local, field) <= time(merged, field),
+            MergeStrategy::PreferRemote => true,
+            MergeStrategy::Manual => false,
+        };
+        if !remote_won {
+            kept_local.push((field.clone(), lv.clone()));
+        }
+        conflicts.push(AbConflict {
+            guid: guid.to_owned(),
+            id: local.id.clone(),
+            field: field.clone(),
+            local: lv,
+            remote: rv,
+            remote_won,
+        });
+    }
+    for (field, v) in kept_local {
+        merged.set_field(&field, v);
+    }
+    for (field, t) in local.modified.iter() {
+        let m = merged.modified.entry(field.clone()).or_default();
+        *m = (*m).max(*t);
+    }
+}
+
+/// An edit of a book made offline, with the `etag` of the peer it was made on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum AbOp {
+    Add {
+        peer: AbPeer,
+    },
+    Remove {
+        id: String,
+        etag: String,
+    },
+    Tag {
+        id: String,
+        tags: Vec<String>,
+        etag: String,
+    },
+}
+
+impl AbOp {
+    /// Applies the op to `entry` if permitted, fails with `AbError::Conflict` if it
+    /// conflicts with a change of the peer: another peer of the id is added, or
+    /// the peer is changed or removed.
+    pub fn apply(&self, entry: &mut AbEntry) -> Result<(), AbError> {
+        entry.check(AbPermission::ReadWrite)?;
+        let guid = entry.guid.clone();
+        let conflict = |id: &str| AbError::Conflict {
+            guid: guid.clone(),
+            id: id.to_owned(),
+        };
+        match self {
+            AbOp::Add { peer } => match entry.peers.iter().find(|p| p.id == peer.id) {
+                Some(p) if same_content(p, peer) => {}
+                Some(_) => return Err(conflict(&peer.id)),
+                None => entry.peers.push(peer.clone()),
+            },
+            AbOp::Remove { id, etag } => match entry.peers.iter().position(|p| p.id == *id) {
+                Some(i) if entry.peers[i].etag == *etag => {
+                    entry.peers.remove(i);
+                }
+                Some(_) => return Err(conflict(id)),
+                None => {}
+            },
+            AbOp::Tag { id, tags, etag } => {
+                if tags.iter().any(|t| !entry.tags.contains(t)) {
+                    entry.check(AbPermission::Full)?;
+                }
+                let err = conflict(id);
+                let Some(peer) = entry.peers.iter_mut().find(|p| p.id == *id) else {
+                    return Err(err);
+                };
+                if peer.etag != *etag {
+                    return Err(err);
+                }
+                peer.tags = tags.clone();
+                peer.touch("tags");
+                for tag in tags {
+                    if !entry.tags.contains(tag) {
+                        entry.tags.push(tag.clone());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn same_content(a: &AbPeer, b: &AbPeer) -> bool {
+    let strip = |p: &AbPeer| AbPeer {
+        etag: String::new(),
+        modified: HashMap::new(),
+        ..p.clone()
+    };
+    strip(a) == strip(b)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingOp {
+    pub guid: String,
+    pub op: AbOp,
+    /// ms
+    pub time: i64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AbQueue {
+    pub ops: Vec<PendingOp>,
+}
+
+#[derive(Debug, Default)]
+pub struct Replayed {
+    /// To be sent to the api-server, then the queue is to be `remove`d.
+    pub changesets: Vec<AbChangeset>,
+    /// The ops not applied, to be shown to the user.
+    pub conflicts: Vec<(PendingOp, AbError)>,
+}
+
+impl AbQueue {
+    fn path() -> PathBuf {
+        let filename = format!("{}_ab_pending", APP_NAME.read().unwrap().clone());
+        Config::path(filename)
+    }
+
+    /// Held until dropped, None if the lock failed, then the queue is used unlocked.
+    fn lock() -> Option<std::fs::File> {
+        use fs2::FileExt;
+        let path = Self::path().with_extension("lock");
+        let res = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.lock_exclusive().map(|_| file));
+        match res {
+            Ok(file) => Some(file),
+            Err(err) => {
+                log::error!("Failed to lock the pending ab ops: {}", err);
+                None
+            }
+        }
+    }
+
+    pub fn load() -> Self {
+        let _lock = Self::lock();
+        Self::load_locked()
+    }
+
+    fn load_locked() -> Self {
+        let path = Self::path();
+        let Ok(data) = std::fs::read(&path) else {
+            return Self::default();
+        };
+        // written with the key of the account by older versions
+        let queue = user_open(TOKEN_AB, &data)
+            .or_else(|| account_open(TOKEN_AB, &data))
+            .and_then(|x| serde_json::from_slice(&x).ok());
+        match queue {
+            Some(queue) => queue,
+            None => {
+                // e.g. the store of the user is locked, kept to be recovered
+                let aside = path.with_extension(format!("unreadable-{}", crate::get_time()));
+                log::warn!(
+                    "Failed to read the pending ab ops, moved to {}",
+                    aside.display()
+                );
+                std::fs::rename(&path, &aside).ok();
+                Self::default()
+            }
+        }
+    }
+
+    pub fn store(&self) {
+        let _lock = Self::lock();
+        self.store_locked();
+    }
+
+    // written to a temporary file first, a crash does not leave a truncated queue
+    fn store_locked(&self) {
+        let res = (|| -> ResultType<()> {
+            let data = user_seal(TOKEN_AB, &serde_json::to_vec(self)?);
+            let path = Self::path();
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, data)?;
+            std::fs::rename(&tmp, &path)?;
+            Ok(())
+        })();
+        if let Err(err) = res {
+            log::error!("Failed to store the pending ab ops: {}", err);
+        }
+    }
+
+    pub fn remove() {
+        let _lock = Self::lock();
+        std::fs::remove_file(Self::path()).ok();
+    }
+
+    /// Queues an edit of the book of `guid` made offline, after it was applied to
+    /// the cache with `Ab::edit`.
+    pub fn push(guid: &str, op: AbOp) {
+        let _lock = Self::lock();
+        let mut queue = Self::load_locked();
+        queue.ops.push(PendingOp {
+            guid: guid.to_owned(),
+            op,
+            time: crate::get_time(),
+        });
+        if queue.ops.len() > MAX_PENDING {
+            let n = queue.ops.len() - MAX_PENDING;
+            log::warn!("Too many pending ab ops, dropping the {} oldest", n);
+            queue.ops.drain(..n);
+        }
+        queue.store_locked();
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Replays the ops in order on `remote`, the book pulled after reconnecting.
+    pub fn replay(&self, remote: &mut Ab) -> Replayed {
+        let before = remote.clone();
+        let mut conflicts = vec![];
+        for op in self.ops.iter() {
+            if let Err(err) = remote.edit(&op.guid, &op.op) {
+                log::info!("Pending ab op not applied: {}", err);
+                conflicts.push((op.clone(), err));
+            }
+        }
+        Replayed {
+            changesets: before.diff(remote),
+            conflicts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(id: &str, alias: &str, etag: &str) -> AbPeer {
+        AbPeer {
+            id: id.to_owned(),
+            alias: alias.to_owned(),
+            etag: etag.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_changeset() {
+        let synced = AbEntry {
+            guid: "g".to_owned(),
+            peers: vec![
+                peer("1", "a", "e1"),
+                peer("2", "b", "e2"),
+                peer("3", "c", "e3"),
+            ],
+            tags: vec!["t".to_owned()],
+            revision: 7,
+            ..Default::default()
+        };
+        let mut edited = synced.clone();
+        edited.peers[0].alias = "a2".to_owned();
+        edited.peers.remove(1);
+        edited.peers.push(peer("4", "d", ""));
+        let changeset = synced.diff(&edited);
+        assert_eq!(changeset.revision, 7);
+        assert_eq!(
+            changeset.upserted,
+            vec![peer("1", "a2", "e1"), peer("4", "d", "")]
+        );
+        assert_eq!(changeset.removed, vec![peer("2", "", "e2")]);
+        assert_eq!(changeset.tags, None);
+        assert!(synced.diff(&synced).is_empty());
+        let json = serde_json::to_string(&changeset).unwrap();
+        assert!(!json.contains("\"c\""));
+
+        // the same changes confirmed by the api-server
+        let mut pulled = changeset.clone();
+        pulled.revision = 8;
+        let mut ab = Ab {
+            ab_entries: vec![synced.clone()],
+            ..Default::default()
+        };
+        assert!(ab.apply(&[pulled.clone()]));
+        assert!(!ab.apply(&[pulled]));
+        let entry = &ab.ab_entries[0];
+        assert_eq!(entry.revision, 8);
+        assert_eq!(entry.peers, edited.peers);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut base = AbEntry {
+            guid: "g".to_owned(),
+            peers: vec![
+                peer("1", "a", "e1"),
+                peer("2", "b", "e2"),
+                peer("4", "d", "e4"),
+                peer("5", "e", "e5"),
+                peer("6", "f", "e6"),
+            ],
+            tags: vec!["old".to_owned()],
+            ..Default::default()
+        };
+        base.peers[1].hostname = "h".to_owned();
+        let mut local = base.clone();
+        local.peers[0].alias = "local".to_owned();
+        local.peers[0].modified.insert("alias".to_owned(), 200);
+        local.peers[1].tags = vec!["x".to_owned()];
+        local.peers[1].modified.insert("tags".to_owned(), 100);
+        // only changed locally
+        local.peers[1].hostname = "h2".to_owned();
+        local.peers.push(peer("3", "c", ""));
+        local.tags = vec!["x".to_owned()];
+        // removed locally: unchanged and changed remotely since
+        local.peers.retain(|p| p.id != "4" && p.id != "5");
+        let mut remote = base.clone();
+        remote.peers[0].alias = "remote".to_owned();
+        remote.peers[0].etag = "e1'".to_owned();
+        remote.peers[0].modified.insert("alias".to_owned(), 100);
+        remote.peers[1].tags = vec!["y".to_owned()];
+        remote.peers[1].modified.insert("tags".to_owned(), 300);
+        remote.peers[3].alias = "e2".to_owned();
+        remote.peers[3].etag = "e5'".to_owned();
+        // removed remotely, not changed locally
+        remote.peers.retain(|p| p.id != "6");
+        let ab = |e: &AbEntry| Ab {
+            ab_entries: vec![e.clone()],
+            ..Default::default()
+        };
+        let (base, local, remote) = (ab(&base), ab(&local), ab(&remote));
+        let ids = |ab: &Ab| -> Vec<String> {
+            ab.ab_entries[0]
+                .peers
+                .iter()
+                .map(|p| p.id.clone())
+                .collect()
+        };
+
+        let (merged, conflicts) = Ab::merge(&base, &local, &remote, MergeStrategy::LastWriterWins);
+        let entry = &merged.ab_entries[0];
+        assert_eq!(ids(&merged), vec!["1", "2", "5", "3"]);
+        assert_eq!(entry.peers[0].alias, "local");
+        assert_eq!(entry.peers[0].etag, "e1'");
+        assert_eq!(entry.peers[0].modified.get("alias"), Some(&200));
+        assert_eq!(entry.peers[1].tags, vec!["y".to_owned()]);
+        assert_eq!(entry.peers[1].hostname, "h2");
+        assert_eq!(entry.tags, vec!["x".to_owned()]);
+        assert_eq!(conflicts.len(), 3);
+        assert_eq!(
+            (conflicts[0].id.as_str(), conflicts[0].field.as_str()),
+            ("5", "")
+        );
+        assert!(conflicts[0].local.is_null() && conflicts[0].remote_won);
+        assert!(!conflicts[1].remote_won);
+        assert!(conflicts[2].remote_won);
+
+        let (merged, conflicts) = Ab::merge(&base, &local, &remote, MergeStrategy::PreferRemote);
+        assert_eq!(merged.ab_entries[0].peers[0].alias, "remote");
+        assert!(conflicts.iter().all(|c| c.remote_won));
+
+        let (mut merged, conflicts) = Ab::merge(&base, &local, &remote, MergeStrategy::Manual);
+        assert_eq!(ids(&merged), vec!["1", "2", "3"]);
+        assert_eq!(merged.ab_entries[0].peers[0].alias, "local");
+        assert_eq!(conflicts[1].field, "alias");
+        assert_eq!(conflicts[1].remote, Value::String("remote".to_owned()));
+        assert!(merged.resolve(&conflicts[1], true).is_ok());
+        assert_eq!(merged.ab_entries[0].peers[0].alias, "remote");
+        assert!(merged.resolve(&conflicts[0], true).is_ok());
+        assert_eq!(ids(&merged), vec!["1", "2", "3", "5"]);
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut remote = Ab {
+            ab_entries: vec![AbEntry {
+                guid: "g".to_owned(),
+                peers: vec![peer("1", "a", "e1'"), peer("2", "b", "e2")],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let op = |op| PendingOp {
+            guid: "g".to_owned(),
+            op,
+            time: 0,
+        };
+        let queue = AbQueue {
+            ops: vec![
+                op(AbOp::Add {
+                    peer: peer("3", "c", ""),
+                }),
+                op(AbOp::Tag {
+                    id: "3".to_owned(),
+                    tags: vec!["t".to_owned()],
+                    etag: String::new(),
+                }),
+                // changed remotely since
+                op(AbOp::Remove {
+                    id: "1".to_owned(),
+                    etag: "e1".to_owned(),
+                }),
+                op(AbOp::Remove {
+                    id: "2".to_owned(),
+                    etag: "e2".to_owned(),
+                }),
+            ],
+        };
+        let json = serde_json::to_string(&queue).unwrap();
+        assert!(json.contains(r#""op":"remove""#));
+        assert_eq!(serde_json::from_str::<AbQueue>(&json).unwrap(), queue);
+        let replayed = queue.replay(&mut remote);
+        assert_eq!(
+            replayed.conflicts,
+            vec![(
+                queue.ops[2].clone(),
+                AbError::Conflict {
+                    guid: "g".to_owned(),
+                    id: "1".to_owned()
+                }
+            )]
+        );
+        let entry = &remote.ab_entries[0];
+        let ids: Vec<&str> = entry.peers.iter().map(|p| p.id.as_str()).collect();
+        assert_eq!(ids, vec!["1", "3"]);
+        assert_eq!(entry.peers[1].tags, vec!["t".to_owned()]);
+        assert_eq!(entry.tags, vec!["t".to_owned()]);
+        assert_eq!(replayed.changesets.len(), 1);
+        assert_eq!(replayed.changesets[0].removed, vec![peer("2", "", "e2")]);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let mut ab = Ab {
+            ab_entries: vec![AbEntry {
+                guid: "shared".to_owned(),
+                peers: vec![peer("1", "a", "e1")],
+                tags: vec!["t".to_owned()],
+                permission: "read".to_owned(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let remove = AbOp::Remove {
+            id: "1".to_owned(),
+            etag: "e1".to_owned(),
+        };
+        let read_only = AbError::ReadOnly {
+            guid: "shared".to_owned(),
+        };
+        assert_eq!(ab.edit("shared", &remove), Err(read_only));
+        assert_eq!(
+            ab.edit("other", &remove),
+            Err(AbError::UnknownBook {
+                guid: "other".to_owned()
+            })
+        );
+        let mut edited = ab.clone();
+        edited.ab_entries[0].peers.clear();
+        assert!(ab.diff(&edited).is_empty());
+
+        ab.ab_entries[0].permission = "read-write".to_owned();
+        let tag = |t: &str| AbOp::Tag {
+            id: "1".to_owned(),
+            tags: vec![t.to_owned()],
+            etag: "e1".to_owned(),
+        };
+        assert!(ab.edit("shared", &tag("t")).is_ok());
+        assert_eq!(
+            ab.edit("shared", &tag("new")),
+            Err(AbError::NotPermitted {
+                guid: "shared".to_owned()
+            })
+        );
+        assert!(ab.edit("shared", &remove).is_ok());
+        let error: crate::error::Error = AbError::ReadOnly {
+            guid: "shared".to_owned(),
+        }
+        .into();
+        assert!(matches!(error, crate::error::Error::PolicyDenied(_)));
+    }
+}
@@ -0,0 +1,234 @@
+// Access control for incoming connections, configured with `OPTION_WHITELIST`.
+//
+// The option is a list of rules separated by commas or new lines, the first
+// rule matching the remote decides:
+//   192.168.1.10         allow an ip
+//   10.0.0.0/8, fd00::/8 allow an IPv4 / IPv6 range
+//   123456789, 12345*    allow an id, `*` and `?` are wildcards
+//   !1.2.3.4, !9876*     deny
+// If nothing matches, the remote is denied if there is any allow rule, and
+// allowed otherwise. The old comma-separated ip list is a valid rule list.
+use crate::{
+    bail,
+    config::{keys, Config},
+    ResultType,
+};
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Target {
+    Net(IpAddr, u8),
+    Id(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    allow: bool,
+    target: Target,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Deny,
+}
+
+// Compare IPv4-mapped IPv6 addresses as IPv4.
+fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        _ => ip,
+    }
+}
+
+fn parse_net(s: &str) -> Option<ResultType<(IpAddr, u8)>> {
+    let (addr, prefix) = match s.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (s, None),
+    };
+    let ip = normalize(addr.parse::<IpAddr>().ok()?);
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        None => max,
+        Some(p) => match p.parse::<u8>() {
+            Ok(p) if p <= max => p,
+            _ => return Some(Err(anyhow::anyhow!("Invalid prefix length in {}", s))),
+        },
+    };
+    Some(Ok((ip, prefix)))
+}
+
+fn net_contains(net: &IpAddr, prefix: u8, ip: &IpAddr) -> bool {
+    match (net, ip) {
+        (IpAddr::V4(net), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(*net) & mask == u32::from(*ip) & mask
+        }
+        (IpAddr::V6(net), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(*net) & mask == u128::from(*ip) & mask
+        }
+        _ => false,
+    }
+}
+
+fn wildcard_match(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => s.is_empty(),
+        Some((b'*', rest)) => (0..=s.len()).any(|i| wildcard_match(rest, &s[i..])),
+        Some((b'?', rest)) => !s.is_empty() && wildcard_match(rest, &s[1..]),
+        Some((c, rest)) => s.first() == Some(c) && wildcard_match(rest, &s[1..]),
+    }
+}
+
+impl Rule {
+    pub fn parse(s: &str) -> ResultType<Self> {
+        let (allow, s) = match s.strip_prefix('!') {
+            Some(s) => (false, s.trim()),
+            None => (true, s),
+        };
+        if s.is_empty() {
+            bail!("Empty rule");
+        }
+        let target = match parse_net(s) {
+            Some(net) => {
+                let (ip, prefix) = net?;
+                Target::Net(ip, prefix)
+            }
+            None => {
+                if s.contains('/') {
+                    bail!("Invalid ip range: {}", s);
+                }
+                if !s
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "*?-_.@".contains(c))
+                {
+                    bail!("Invalid ip or id: {}", s);
+                }
+                // collapse `**` so that matching stays cheap
+                let mut id = String::with_capacity(s.len());
+                for c in s.chars() {
+                    if !(c == '*' && id.ends_with('*')) {
+                        id.push(c);
+                    }
+                }
+                Target::Id(id)
+            }
+        };
+        Ok(Self { allow, target })
+    }
+
+    fn matches(&self, ip: Option<&IpAddr>, id: &str) -> bool {
+        match &self.target {
+            Target::Net(net, prefix) => ip.map_or(false, |ip| net_contains(net, *prefix, ip)),
+            Target::Id(pattern) => {
+                !id.is_empty() && wildcard_match(pattern.as_bytes(), id.as_bytes())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    pub fn parse(s: &str) -> ResultType<Self> {
+        let mut rules = vec![];
+        for x in s.split(|c| c == ',' || c == '\n' || c == ';') {
+            let x = x.trim();
+            if x.is_empty() {
+                continue;
+            }
+            rules.push(Rule::parse(x)?);
+        }
+        Ok(Self { rules })
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// `ip` is None if the remote address is unknown, e.g. relayed connections.
+    pub fn check(&self, ip: Option<&IpAddr>, id: &str) -> Decision {
+        let ip = ip.map(|ip| normalize(*ip));
+        for rule in self.rules.iter() {
+            if rule.matches(ip.as_ref(), id) {
+                return if rule.allow {
+                    Decision::Allow
+                } else {
+                    Decision::Deny
+                };
+            }
+        }
+        if self.rules.iter().any(|r| r.allow) {
+            Decision::Deny
+        } else {
+            Decision::Allow
+        }
+    }
+}
+
+/// Check the remote against the configured rules. An invalid rule list denies
+/// everything rather than silently opening access.
+pub fn check_access(ip: Option<&IpAddr>, id: &str) -> bool {
+    let rules = Config::get_option(keys::OPTION_WHITELIST);
+    match Acl::parse(&rules) {
+        Ok(acl) => acl.check(ip, id) == Decision::Allow,
+        Err(err) => {
+            log::error!("Invalid access control rules: {}", err);
+            false
+        }
+    }
+}
+
+/// For the settings UI, returns the error message of the first invalid rule.
+pub fn validate(rules: &str) -> ResultType<()> {
+    Acl::parse(rules).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_acl() {
+        let acl = Acl::parse("!10.0.0.5, 10.0.0.0/8\n fd00::/8, 12345*, !9*").unwrap();
+        assert_eq!(acl.check(Some(&ip("10.1.2.3")), ""), Decision::Allow);
+        assert_eq!(acl.check(Some(&ip("10.0.0.5")), "123456"), Decision::Deny);
+        assert_eq!(acl.check(Some(&ip("::ffff:10.1.2.3")), ""), Decision::Allow);
+        assert_eq!(acl.check(Some(&ip("fd12::1")), ""), Decision::Allow);
+        assert_eq!(acl.check(Some(&ip("fe80::1")), ""), Decision::Deny);
+        assert_eq!(acl.check(None, "123456789"), Decision::Allow);
+        assert_eq!(acl.check(None, "912345"), Decision::Deny);
+        assert_eq!(acl.check(Some(&ip("8.8.8.8")), "777"), Decision::Deny);
+        // deny rules only
+        let acl = Acl::parse("!1.2.3.0/24").unwrap();
+        assert_eq!(acl.check(Some(&ip("1.2.3.4")), ""), Decision::Deny);
+        assert_eq!(acl.check(Some(&ip("1.2.4.4")), ""), Decision::Allow);
+        // legacy format
+        let acl = Acl::parse("1.2.3.4,5.6.7.8,").unwrap();
+        assert_eq!(acl.check(Some(&ip("5.6.7.8")), ""), Decision::Allow);
+        assert_eq!(acl.check(Some(&ip("5.6.7.9")), ""), Decision::Deny);
+        assert!(Acl::parse("").unwrap().is_empty());
+        assert_eq!(
+            Acl::parse("0.0.0.0/0")
+                .unwrap()
+                .check(Some(&ip("9.9.9.9")), ""),
+            Decision::Allow
+        );
+        assert!(validate("1.2.3.4/33").is_err());
+        assert!(validate("1.2.3/24").is_err());
+        assert!(validate("12 34").is_err());
+        assert!(validate("!").is_err());
+    }
+}
@@ -0,0 +1,357 @@
+// The client of the api-server, the option `api-server`, for e.g. the refreshes
+// of the address book and the groups.
+//
+// A `get` is conditional and cached: the body of the last response of a url is
+// streamed to `<APP_NAME>_api_cache`, sealed with a key of the access token, and
+// its ETag and Last-Modified are kept in `LocalConfig`, see `config::ApiCache`,
+// both by a key of the token and the url, so the copy of another account is never
+// returned. The validators are sent back as If-None-Match and If-Modified-Since
+// while the copy is there, so an unchanged payload of megabytes is answered with
+// a 304 and read from the copy. Only the last copy of a url is kept, a new one of
+// another account or token replaces it.
+//
+// A request failing to connect or answered with 429, 502, 503 or 504 is retried
+// with `Backoff`, waiting as long as a Retry-After asks within its bounds. A POST
+// is only retried on 429 and 503, which it was not processed with.
+use crate::{
+    bail,
+    config::{self, keys, ApiCache, Config, LocalConfig, APP_NAME},
+    http::{self, Response},
+    ResultType,
+};
+use rand::Rng;
+use std::{
+    io::{Read, Write},
+    path::PathBuf,
+};
+
+const TIMEOUT: u64 = 30_000;
+/// Of a body streamed to the cache.
+const MAX_BODY: u64 = 1024 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    /// Including the first.
+    pub attempts: u32,
+    /// ms, doubled on every retry
+    pub base: u64,
+    pub max: u64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            attempts: 4,
+            base: 500,
+            max: 8_000,
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay before the retry after `attempt`, 0 the first, with up to a
+    /// quarter of jitter, so clients failing together do not retry together.
+    pub fn delay(&self, attempt: u32) -> u64 {
+        let delay = self
+            .base
+            .saturating_mul(1u64 << attempt.min(32))
+            .min(self.max);
+        delay + rand::thread_rng().gen_range(0..=delay / 4)
+    }
+}
+
+pub struct Fetched {
+    /// False if the copy cached before is still valid.
+    pub modified: bool,
+    /// Of the response or of the copy.
+    pub body: Box<dyn Read + Send>,
+}
+
+/// The url of `path` on the api-server.
+pub fn url(path: &str) -> ResultType<String> {
+    let server = Config::get_option(keys::OPTION_API_SERVER);
+    if server.is_empty() {
+        bail!("No api server");
+    }
+    Ok(format!(
+        "{}/{}",
+        server.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    ))
+}
+
+fn is_retryable(method: &str, status: u16) -> bool {
+    match status {
+        429 | 503 => true,
+        502 | 504 => method != "POST",
+        _ => false,
+    }
+}
+
+/// Seconds only, not an http date.
+fn retry_after(res: &Response) -> Option<u64> {
+    res.header("Retry-After")?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(|x| x.saturating_mul(1000))
+}
+
+/// Sends a request to `path` with the access token `token`, retried with
+/// `backoff`. Returns the last response if all the attempts are answered with
+/// an error status.
+pub async fn request(
+    method: &str,
+    path: &str,
+    token: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    backoff: Backoff,
+) -> ResultType<Response> {
+    let max = http::MAX_RESPONSE as _;
+    let (mut res, buf) = send(method, path, token, headers, body, backoff, max, || {
+        Ok(Vec::new())
+    })
+    .await?;
+    res.body = buf;
+    Ok(res)
+}
+
+/// `request` with the body written to a new sink of `sink` for every attempt.
+#[allow(clippy::too_many_arguments)]
+async fn send<W: Write>(
+    method: &str,
+    path: &str,
+    token: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    backoff: Backoff,
+    max: u64,
+    mut sink: impl FnMut() -> ResultType<W>,
+) -> ResultType<(Response, W)> {
+    let url = url(path)?;
+    let auth = format!("Bearer {}", token);
+    let mut all = vec![("Accept", "application/json")];
+    if !token.is_empty() {
+        all.push(("Authorization", auth.as_str()));
+    }
+    if !body.is_empty() {
+        all.push(("Content-Type", "application/json"));
+    }
+    all.extend_from_slice(headers);
+    let mut attempt = 0;
+    loop {
+        let mut w = sink()?;
+        let delay = match http::request_to(method, &url, &all, body, TIMEOUT, &mut w, max).await {
+            Ok(res) if attempt + 1 < backoff.attempts && is_retryable(method, res.status) => {
+                let delay = backoff.delay(attempt);
+                retry_after(&res).map_or(delay, |x| x.min(backoff.max).max(delay))
+            }
+            Ok(res) => return Ok((res, w)),
+            // may have been processed
+            Err(err) if method == "POST" => return Err(err),
+            Err(err) if attempt + 1 >= backoff.attempts => return Err(err),
+            Err(err) => {
+                log::debug!("Failed to {} {}: {}", method, path, err);
+                backoff.delay(attempt)
+            }
+        };
+        attempt += 1;
+        crate::clock::sleep(std::time::Duration::from_millis(delay)).await;
+    }
+}
+
+fn conditional_headers(cache: &ApiCache) -> Vec<(&'static str, &str)> {
+    let mut headers = vec![];
+    if !cache.etag.is_empty() {
+        headers.push(("If-None-Match", cache.etag.as_str()));
+    }
+    if !cache.last_modified.is_empty() {
+        headers.push(("If-Modified-Since", cache.last_modified.as_str()));
+    }
+    headers
+}
+
+/// Without any if the response can not be validated later.
+fn validators(res: &Response, url: &str, now: i64) -> ApiCache {
+    ApiCache {
+        etag: res.header("ETag").unwrap_or_default().to_owned(),
+        last_modified: res.header("Last-Modified").unwrap_or_default().to_owned(),
+        time: now,
+        url: url.to_owned(),
+    }
+}
+
+fn cache_dir() -> PathBuf {
+    Config::path(format!("{}_api_cache", APP_NAME.read().unwrap().clone()))
+}
+
+/// The key of the copy of `url` for the account of `token`.
+fn cache_key(token: &str, url: &str) -> String {
+    let mut hasher = blake3::Hasher::new_derive_key("hbb_common api cache entry");
+    hasher.update(token.as_bytes());
+    hasher.update(&[0]);
+    hasher.update(url.as_bytes());
+    hasher.finalize().to_hex().to_string()
+}
+
+fn open_cached(key: &str, token: &str) -> Option<Box<dyn Read + Send>> {
+    let file = std::fs::File::open(cache_dir().join(key)).ok()?;
+    let reader = config::api_cache_reader(std::io::BufReader::new(file), token).ok()?;
+    Some(Box::new(reader))
+}
+
+fn remove_copies(keys: Vec<String>) {
+    let dir = cache_dir();
+    for key in keys {
+        std::fs::remove_file(dir.join(key)).ok();
+    }
+}
+
+/// A conditional GET of `path`, its body kept for the next one. A response
+/// with an error status is an error.
+pub async fn get(path: &str, token: &str) -> ResultType<Fetched> {
+    let url = url(path)?;
+    let key = cache_key(token, &url);
+    let dir = cache_dir();
+    std::fs::create_dir_all(&dir)?;
+    let file = dir.join(&key);
+    // only with the copy to answer a 304 with
+    let cache = LocalConfig::get_api_cache(&key).filter(|_| file.is_file());
+    let headers = cache.as_ref().map(conditional_headers).unwrap_or_default();
+    let tmp = dir.join(format!("{}.{}.tmp", key, rand::random::<u32>()));
+    let res = send(
+        "GET",
+        path,
+        token,
+        &headers,
+        &[],
+        Backoff::default(),
+        MAX_BODY,
+        || {
+            let file = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+            Ok(config::api_cache_writer(file, token)?)
+        },
+    )
+    .await;
+    let (res, writer) = match res {
+        Ok(res) if res.0.status == 304 && cache.is_some() => {
+            drop(res);
+            std::fs::remove_file(&tmp).ok();
+            if let Some(body) = open_cached(&key, token) {
+                return Ok(Fetched {
+                    modified: false,
+                    body,
+                });
+            }
+            // e.g. removed meanwhile, fetched in full the next time
+            remove_copies(vec![key]);
+            bail!("Failed to read the copy of {}", path);
+        }
+        Ok(res) if res.0.is_success() => res,
+        Ok((res, writer)) => {
+            drop(writer);
+            std::fs::remove_file(&tmp).ok();
+            bail!("HTTP status {} of {}", res.status, path);
+        }
+        Err(err) => {
+            std::fs::remove_file(&tmp).ok();
+            return Err(err);
+        }
+    };
+    let stored = writer
+        .finish()
+        .and_then(|mut x| x.flush())
+        .and_then(|_| std::fs::rename(&tmp, &file));
+    if let Err(err) = stored {
+        std::fs::remove_file(&tmp).ok();
+        return Err(err.into());
+    }
+    let cache = validators(&res, &url, crate::get_time());
+    remove_copies(LocalConfig::set_api_cache(&key, Some(cache)));
+    let Some(body) = open_cached(&key, token) else {
+        bail!("Failed to read the response of {}", path);
+    };
+    Ok(Fetched {
+        modified: true,
+        body,
+    })
+}
+
+/// Forgets the copies of `path` of all the accounts, e.g. on logout.
+pub fn forget(path: &str) {
+    if let Ok(url) = url(path) {
+        remove_copies(LocalConfig::remove_api_caches(&url));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    #[test]
+    fn test_api_cache() {
+        let _t = test_config();
+        let backoff = Backoff::default();
+        assert!((500..=625).contains(&backoff.delay(0)));
+        assert!((1000..=1250).contains(&backoff.delay(1)));
+        assert!((8000..=10000).contains(&backoff.delay(10)));
+        assert!((8000..=10000).contains(&backoff.delay(100)));
+        assert!(is_retryable("GET", 502));
+        assert!(!is_retryable("POST", 502));
+        assert!(!is_retryable("GET", 404));
+
+        let res = Response {
+            status: 200,
+            headers: vec![
+                ("etag".to_owned(), "\"v2\"".to_owned()),
+                ("Retry-After".to_owned(), "3".to_owned()),
+            ],
+            body: vec![],
+        };
+        assert_eq!(retry_after(&res), Some(3000));
+        Config::set_option(
+            keys::OPTION_API_SERVER.to_owned(),
+            "https://api.example.com/".to_owned(),
+        );
+        let url = url("/api/ab").unwrap();
+        assert_eq!(url, "https://api.example.com/api/ab");
+        let cache = validators(&res, &url, 1);
+        assert_eq!(
+            conditional_headers(&cache),
+            vec![("If-None-Match", "\"v2\"")]
+        );
+        assert!(conditional_headers(&validators(&Response::default(), &url, 1)).is_empty());
+
+        // by account
+        let (a, b) = (cache_key("token-a", &url), cache_key("token-b", &url));
+        assert_ne!(a, b);
+        let dir = cache_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut writer = config::api_cache_writer(Vec::new(), "token-a").unwrap();
+        writer.write_all(b"{\"peers\": []}").unwrap();
+        std::fs::write(dir.join(&a), writer.finish().unwrap()).unwrap();
+        assert!(LocalConfig::set_api_cache(&a, Some(cache.clone())).is_empty());
+        assert_eq!(LocalConfig::get_api_cache(&a), Some(cache.clone()));
+        let mut body = String::new();
+        open_cached(&a, "token-a")
+            .unwrap()
+            .read_to_string(&mut body)
+            .unwrap();
+        assert_eq!(body, "{\"peers\": []}");
+        body.clear();
+        assert!(open_cached(&a, "token-b")
+            .unwrap()
+            .read_to_string(&mut body)
+            .is_err());
+        // the copy of another account is replaced
+        assert_eq!(
+            LocalConfig::set_api_cache(&b, Some(cache.clone())),
+            vec![a.clone()]
+        );
+        assert!(LocalConfig::get_api_cache(&a).is_none());
+        forget("api/ab");
+        assert!(LocalConfig::get_api_cache(&b).is_none());
+    }
+}
@@ -0,0 +1,570 @@
+// A folder streamed as one tar archive, for folders of many small files where
+// the round trips per file dominate. Built while being read and unpacked
+// while being received, nothing is staged on disk but the `.download` files
+// of the normal transfer, which are only moved into place, with the backups
+// of the files replaced, once the hash of the whole archive is verified, see
+// `Unpacker::commit`.
+//
+// ustar with pax headers for long names and sizes from 8 GiB, regular files
+// only. Empty folders are still sent by `fs` as usual.
+use crate::{
+    backup, bail, checksum_cache,
+    config::{keys, Config},
+    fs_provider::provider,
+    message_proto::FileEntry,
+    ResultType,
+};
+use std::{
+    io::{Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+const BLOCK: u64 = 512;
+// ustar size field: 11 octal digits
+const MAX_USTAR_SIZE: u64 = (1 << 33) - 1;
+/// Folders are archived from this many files ...
+pub const MIN_FILES: usize = 64;
+/// ... of at most this average size.
+pub const MAX_AVERAGE_SIZE: u64 = 256 * 1024;
+// of a pax header, which is kept in memory
+const MAX_PAX_SIZE: u64 = 64 * 1024;
+
+/// `OPTION_ALLOW_FILE_TRANSFER_ARCHIVE` and many small `files`, the peer must
+/// have `Features.file_archive` too.
+pub fn should_archive(files: &[FileEntry]) -> bool {
+    if files.len() < MIN_FILES || !Config::get_bool_option(keys::OPTION_ALLOW_FILE_TRANSFER_ARCHIVE)
+    {
+        return false;
+    }
+    let total: u64 = files.iter().map(|f| f.size).sum();
+    total / files.len() as u64 <= MAX_AVERAGE_SIZE
+}
+
+#[inline]
+fn padding(size: u64) -> u64 {
+    (BLOCK - size % BLOCK) % BLOCK
+}
+
+fn tar_name(name: &str) -> String {
+    name.replace('\\', "/")
+}
+
+// (prefix, name) of a ustar header, None if a pax header is needed.
+fn split_name(name: &str) -> Option<(&str, &str)> {
+    if name.len() <= 100 {
+        return Some(("", name));
+    }
+    let mut i = name.len().min(156);
+    while i > 0 {
+        i -= 1;
+        if name.as_bytes()[i] == b'/' && name.len() - i - 1 <= 100 && i <= 155 {
+            return Some((&name[..i], &name[i + 1..]));
+        }
+    }
+    None
+}
+
+fn pax_records(name: &str, size: u64) -> Vec<u8> {
+    let mut res = Vec::new();
+    let mut add = |k: &str, v: &str| {
+        // the length includes itself
+        let body = format!(" {}={}\n", k, v);
+        let mut len = body.len() + 1;
+        while len.to_string().len() + body.len() != len {
+            len += 1;
+        }
+        res.extend_from_slice(format!("{}{}", len, body).as_bytes());
+    };
+    if split_name(name).is_none() {
+        add("path", name);
+    }
+    if size > MAX_USTAR_SIZE {
+        add("size", &size.to_string());
+    }
+    res
+}
+
+fn octal(field: &mut [u8], value: u64) {
+    let s = format!("{:0width$o}", value, width = field.len() - 1);
+    let s = s.as_bytes();
+    let start = s.len().saturating_sub(field.len() - 1);
+    field[..field.len() - 1].copy_from_slice(&s[start..]);
+    field[field.len() - 1] = 0;
+}
+
+fn header(name: &str, size: u64, modified: u64, typeflag: u8) -> [u8; BLOCK as usize] {
+    let mut h = [0u8; BLOCK as usize];
+    let (prefix, short) = split_name(name).unwrap_or(("", ""));
+    let short = if short.is_empty() {
+        &name[..name.len().min(100)]
+    } else {
+        short
+    };
+    h[..short.len()].copy_from_slice(short.as_bytes());
+    octal(&mut h[100..108], 0o644);
+    octal(&mut h[108..116], 0);
+    octal(&mut h[116..124], 0);
+    octal(&mut h[124..136], size.min(MAX_USTAR_SIZE));
+    octal(&mut h[136..148], modified);
+    h[156] = typeflag;
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+    h[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    h[148..156].copy_from_slice(b"        ");
+    let sum: u64 = h.iter().map(|x| *x as u64).sum();
+    octal(&mut h[148..155], sum);
+    h[155] = b' ';
+    h
+}
+
+fn entry_headers(entry: &FileEntry) -> Vec<u8> {
+    let name = tar_name(&entry.name);
+    let mut res = Vec::new();
+    let pax = pax_records(&name, entry.size);
+    if !pax.is_empty() {
+        res.extend_from_slice(&header("PaxHeader", pax.len() as _, 0, b'x'));
+        let len = pax.len() as u64;
+        res.extend(pax);
+        res.resize(res.len() + padding(len) as usize, 0);
+    }
+    res.extend_from_slice(&header(&name, entry.size, entry.modified_time, b'0'));
+    res
+}
+
+/// Exact length of the archive of `files`.
+pub fn archive_size(files: &[FileEntry]) -> u64 {
+    files
+        .iter()
+        .map(|f| entry_headers(f).len() as u64 + f.size + padding(f.size))
+        .sum::<u64>()
+        + 2 * BLOCK
+}
+
+/// Reads the archive of `files` under `root`. A file changed since the
+/// listing is cut or padded with zeros to its listed size, so that the
+/// archive keeps the announced length.
+pub struct Packer {
+    root: PathBuf,
+    files: Vec<FileEntry>,
+    next: usize,
+    // headers or the end of the archive to emit
+    pending: Vec<u8>,
+    pending_pos: usize,
+    file: Option<std::fs::File>,
+    // data and padding left of the current entry
+    remaining: u64,
+    pad: u64,
+    done: bool,
+}
+
+impl Packer {
+    pub fn new(root: PathBuf, files: Vec<FileEntry>) -> Self {
+        Self {
+            root,
+            files,
+            next: 0,
+            pending: Vec::new(),
+            pending_pos: 0,
+            file: None,
+            remaining: 0,
+            pad: 0,
+            done: false,
+        }
+    }
+
+    fn next_entry(&mut self) {
+        self.pending_pos = 0;
+        let Some(entry) = self.files.get(self.next) else {
+            self.done = true;
+            self.pending = vec![0u8; 2 * BLOCK as usize];
+            return;
+        };
+        self.next += 1;
+        self.pending = entry_headers(entry);
+        self.remaining = entry.size;
+        self.pad = padding(entry.size);
+        let path = self.root.join(&entry.name);
+        self.file = match provider().open_read(&path) {
+            Ok(f) => Some(f),
+            Err(err) => {
+                log::error!("Failed to open {:?} to archive: {}", path, err);
+                None
+            }
+        };
+    }
+}
+
+fn zeros(buf: &mut [u8], len: u64) -> usize {
+    let n = buf.len().min(len as usize);
+    buf[..n].iter_mut().for_each(|x| *x = 0);
+    n
+}
+
+impl Read for Packer {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.pending_pos < self.pending.len() {
+                let n = buf.len().min(self.pending.len() - self.pending_pos);
+                buf[..n].copy_from_slice(&self.pending[self.pending_pos..self.pending_pos + n]);
+                self.pending_pos += n;
+                return Ok(n);
+            }
+            if self.remaining > 0 {
+                let len = buf.len().min(self.remaining as usize);
+                let mut n = match self.file.as_mut() {
+                    Some(f) => f.read(&mut buf[..len])?,
+                    None => 0,
+                };
+                if n == 0 {
+                    // shrunk or unreadable
+                    self.file = None;
+                    n = zeros(buf, self.remaining);
+                }
+                self.remaining -= n as u64;
+                return Ok(n);
+            }
+            self.file = None;
+            if self.pad > 0 {
+                let n = zeros(buf, self.pad);
+                self.pad -= n as u64;
+                return Ok(n);
+            }
+            if self.done {
+                return Ok(0);
+            }
+            self.next_entry();
+        }
+    }
+}
+
+// Only plain relative names are extracted.
+fn safe_path(root: &Path, name: &str) -> Option<PathBuf> {
+    let mut path = root.to_path_buf();
+    let mut empty = true;
+    for c in Path::new(name).components() {
+        match c {
+            Component::Normal(x) => {
+                path.push(x);
+                empty = false;
+            }
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    if empty {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+fn parse_octal(field: &[u8]) -> ResultType<u64> {
+    let s = std::str::from_utf8(field)?;
+    let s = s.trim_matches(|c: char| c == '\0' || c == ' ');
+    if s.is_empty() {
+        return Ok(0);
+    }
+    Ok(u64::from_str_radix(s, 8)?)
+}
+
+fn parse_str(field: &[u8]) -> String {
+    let end = field.iter().position(|x| *x == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).to_string()
+}
+
+enum State {
+    Header,
+    Pax(Vec<u8>),
+    Data(Option<(std::fs::File, PathBuf, u64)>),
+    End,
+}
+
+#[inline]
+fn download_path(path: &Path) -> PathBuf {
+    let mut res = path.as_os_str().to_owned();
+    res.push(".download");
+    PathBuf::from(res)
+}
+
+/// Extracts an archive written in pieces under `root`, into `.download`
+/// files until `commit`.
+pub struct Unpacker {
+    root: PathBuf,
+    header: Vec<u8>,
+    state: State,
+    remaining: u64,
+    pad: u64,
+    // from the pax header of the next entry
+    pax_path: Option<String>,
+    pax_size: Option<u64>,
+    // extracted: the destination and its mtime
+    extracted: Vec<(PathBuf, u64)>,
+}
+
+impl Unpacker {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            header: Vec::with_capacity(BLOCK as _),
+            state: State::Header,
+            remaining: 0,
+            pad: 0,
+            pax_path: None,
+            pax_size: None,
+            extracted: Vec::new(),
+        }
+    }
+
+    /// Files extracted so far.
+    #[inline]
+    pub fn files(&self) -> usize {
+        self.extracted.len()
+    }
+
+    fn on_header(&mut self) -> ResultType<()> {
+        let h = std::mem::take(&mut self.header);
+        if h.iter().all(|x| *x == 0) {
+            self.state = State::End;
+            return Ok(());
+        }
+        let mut sum: u64 = h.iter().map(|x| *x as u64).sum();
+        sum -= h[148..156].iter().map(|x| *x as u64).sum::<u64>();
+        sum += 8 * b' ' as u64;
+        if parse_octal(&h[148..156])? != sum {
+            bail!("Invalid archive header checksum");
+        }
+        let size = match self.pax_size.take() {
+            Some(size) => size,
+            None => parse_octal(&h[124..136])?,
+        };
+        let name = match self.pax_path.take() {
+            Some(name) => name,
+            None => {
+                let prefix = parse_str(&h[345..500]);
+                let name = parse_str(&h[..100]);
+                if prefix.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", prefix, name)
+                }
+            }
+        };
+        let modified = parse_octal(&h[136..148]).unwrap_or(0);
+        self.remaining = size;
+        self.pad = padding(size);
+        self.state = match h[156] {
+            b'x' if size > MAX_PAX_SIZE => bail!("Pax header of {} bytes", size),
+            b'x' => State::Pax(Vec::new()),
+            b'0' | 0 => {
+                let Some(path) = safe_path(&self.root, &name) else {
+                    bail!("Invalid name in archive: {}", name);
+                };
+                let path = crate::sandbox::check_path(&path)?;
+                if let Some(parent) = path.parent() {
+                    provider().create_dir_all(parent)?;
+                }
+                if crate::fs::has_link_below(&self.root, &name) {
+                    bail!("Link in the path of {}", name);
+                }
+                let file = provider().open_write(&download_path(&path))?;
+                State::Data(Some((file, path, modified)))
+            }
+            b'5' => {
+                let Some(path) = safe_path(&self.root, &name) else {
+                    bail!("Invalid name in archive: {}", name);
+                };
+                let path = crate::sandbox::check_path(&path)?;
+                // before creating it, which would follow the link
+                if crate::fs::has_link_below(&self.root, &name) {
+                    bail!("Link in the path of {}", name);
+                }
+                provider().create_dir_all(&path)?;
+                State::Data(None)
+            }
+            // links and others are not extracted
+            _ => State::Data(None),
+        };
+        self.end_entry_if_done()
+    }
+
+    fn end_entry_if_done(&mut self) -> ResultType<()> {
+        if self.remaining > 0 || self.pad > 0 {
+            return Ok(());
+        }
+        match std::mem::replace(&mut self.state, State::Header) {
+            State::Pax(data) => self.parse_pax(&data),
+            State::Data(Some((file, path, modified))) => {
+                drop(file);
+                self.extracted.push((path, modified));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn parse_pax(&mut self, data: &[u8]) {
+        for line in data.split(|x| *x == b'\n') {
+            let line = String::from_utf8_lossy(line);
+            let Some((_, kv)) = line.split_once(' ') else {
+                continue;
+            };
+            match kv.split_once('=') {
+                Some(("path", v)) => self.pax_path = Some(v.to_owned()),
+                Some(("size", v)) => self.pax_size = v.parse().ok(),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn write(&mut self, mut data: &[u8]) -> ResultType<()> {
+        while !data.is_empty() {
+            if let State::End = self.state {
+                // the rest of the end blocks
+                return Ok(());
+            }
+            if let State::Header = self.state {
+                let n = data.len().min(BLOCK as usize - self.header.len());
+                self.header.extend_from_slice(&data[..n]);
+                data = &data[n..];
+                if self.header.len() == BLOCK as usize {
+                    self.on_header()?;
+                }
+                continue;
+            }
+            if self.remaining > 0 {
+                let n = data.len().min(self.remaining as usize);
+                match &mut self.state {
+                    State::Pax(buf) => buf.extend_from_slice(&data[..n]),
+                    State::Data(Some((file, _, _))) => file.write_all(&data[..n])?,
+                    _ => {}
+                }
+                self.remaining -= n as u64;
+                data = &data[n..];
+            } else {
+                let n = data.len().min(self.pad as usize);
+                self.pad -= n as u64;
+                data = &data[n..];
+            }
+            self.end_entry_if_done()?;
+        }
+        Ok(())
+    }
+
+    /// The archive is complete.
+    pub fn finish(&self) -> ResultType<()> {
+        match self.state {
+            State::End => Ok(()),
+            State::Header if self.header.is_empty() => Ok(()),
+            _ => bail!("Truncated archive"),
+        }
+    }
+
+    /// Moves the files extracted into place, once the archive is complete and
+    /// verified. A file which could not be backed up is kept as `.download`
+    /// rather than lose the original.
+    pub fn commit(&mut self) -> ResultType<()> {
+        self.finish()?;
+        for (path, modified) in self.extracted.drain(..) {
+            if backup::is_enabled() && provider().stat(&path).is_ok() {
+                if let Err(err) = backup::backup(&path) {
+                    log::error!("Failed to back up {:?}, not overwritten: {}", path, err);
+                    continue;
+                }
+            }
+            provider().rename(&download_path(&path), &path)?;
+            checksum_cache::invalidate(&path);
+            filetime::set_file_mtime(&path, filetime::FileTime::from_unix_time(modified as _, 0))
+                .ok();
+        }
+        Ok(())
+    }
+
+    /// Removes the files extracted, e.g. if the archive does not verify.
+    pub fn discard(&mut self) {
+        if let State::Data(Some((_, path, _))) = std::mem::replace(&mut self.state, State::End) {
+            provider().remove(&download_path(&path)).ok();
+        }
+        for (path, _) in self.extracted.drain(..) {
+            provider().remove(&download_path(&path)).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_archive() {
+        let base = tempfile::tempdir().unwrap();
+        let src = base.path().join("src");
+        let long = format!("{}/{}", "d".repeat(120), "f".repeat(90));
+        std::fs::create_dir_all(src.join(long.rsplit_once('/').unwrap().0)).unwrap();
+        std::fs::write(src.join(&long), b"long name").unwrap();
+        let name = "x".repeat(150);
+        std::fs::write(src.join(&name), vec![7u8; 1000]).unwrap();
+        std::fs::write(src.join("empty"), b"").unwrap();
+        let files: Vec<FileEntry> = [(long.as_str(), 9u64), (name.as_str(), 1000), ("empty", 0)]
+            .iter()
+            .map(|(name, size)| FileEntry {
+                name: name.to_string(),
+                size: *size,
+                modified_time: 1_000_000_000,
+                ..Default::default()
+            })
+            .collect();
+        let mut packer = Packer::new(src.clone(), files.clone());
+        let mut data = Vec::new();
+        packer.read_to_end(&mut data).unwrap();
+        assert_eq!(data.len() as u64, archive_size(&files));
+        let dest = base.path().join("dest");
+        let mut unpacker = Unpacker::new(dest.clone());
+        for chunk in data.chunks(333) {
+            unpacker.write(chunk).unwrap();
+        }
+        unpacker.finish().unwrap();
+        assert_eq!(unpacker.files(), 3);
+        // not in place before the commit
+        assert!(!dest.join("empty").exists());
+        assert!(dest.join("empty.download").exists());
+        unpacker.commit().unwrap();
+        assert_eq!(std::fs::read(dest.join(&long)).unwrap(), b"long name");
+        assert_eq!(std::fs::read(dest.join(&name)).unwrap(), vec![7u8; 1000]);
+        assert!(!dest.join("empty.download").exists());
+        assert!(safe_path(&dest, "../x").is_none());
+        assert!(safe_path(&dest, "/x").is_none());
+
+        let dest = base.path().join("discarded");
+        let mut unpacker = Unpacker::new(dest.clone());
+        unpacker.write(&data).unwrap();
+        unpacker.discard();
+        assert!(!dest.join("empty.download").exists());
+        assert!(!dest.join("empty").exists());
+
+        let mut unpacker = Unpacker::new(dest);
+        let pax = header("PaxHeader", MAX_PAX_SIZE + 1, 0, b'x');
+        assert!(unpacker.write(&pax).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_link_in_path() {
+        let base = tempfile::tempdir().unwrap();
+        let outside = base.path().join("outside");
+        let dest = base.path().join("dest");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::create_dir_all(&dest).unwrap();
+        std::os::unix::fs::symlink(&outside, dest.join("link")).unwrap();
+        let mut unpacker = Unpacker::new(dest.clone());
+        assert!(unpacker.write(&header("link/sub", 0, 0, b'5')).is_err());
+        assert!(!outside.join("sub").exists());
+        let mut unpacker = Unpacker::new(dest.clone());
+        assert!(unpacker.write(&header("link/a.txt", 0, 0, b'0')).is_err());
+        let mut unpacker = Unpacker::new(dest.clone());
+        unpacker.write(&header("dir/sub", 0, 0, b'5')).unwrap();
+        assert!(dest.join("dir/sub").is_dir());
+    }
+}
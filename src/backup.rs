@@ -0,0 +1,137 @@
+// Versioned backups of files overwritten by incoming transfers, enabled by
+// `OPTION_ALLOW_FILE_TRANSFER_BACKUP`.
+//
+// The original is moved to `.rustdesk-backup` next to it as
+// `<name>.<time in ms>`, a rename on the same file system. The oldest backups
+// of a folder are pruned beyond `OPTION_FILE_TRANSFER_BACKUP_MAX_SIZE`.
+use crate::{
+    bail,
+    config::{keys, Config},
+    get_time, ResultType,
+};
+use std::path::{Path, PathBuf};
+
+pub const DIR: &str = ".rustdesk-backup";
+const DEFAULT_MAX_SIZE_MB: u64 = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backup {
+    pub path: PathBuf,
+    /// The file to restore to.
+    pub original: PathBuf,
+    /// Milliseconds since the epoch.
+    pub time: i64,
+    pub size: u64,
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    Config::get_bool_option(keys::OPTION_ALLOW_FILE_TRANSFER_BACKUP)
+}
+
+/// Bytes kept in each backup folder.
+pub fn max_size() -> u64 {
+    Config::get_option(keys::OPTION_FILE_TRANSFER_BACKUP_MAX_SIZE)
+        .parse::<u64>()
+        .unwrap_or(DEFAULT_MAX_SIZE_MB)
+        .saturating_mul(1024 * 1024)
+}
+
+/// Move `path` away before it is overwritten, None if there is no such file.
+pub fn backup(path: &Path) -> ResultType<Option<PathBuf>> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Ok(None);
+    };
+    let dir = parent.join(DIR);
+    std::fs::create_dir_all(&dir)?;
+    let mut time = get_time();
+    let mut dest;
+    loop {
+        dest = dir.join(format!("{}.{}", name.to_string_lossy(), time));
+        if !dest.exists() {
+            break;
+        }
+        time += 1;
+    }
+    std::fs::rename(path, &dest)?;
+    prune(&dir, max_size());
+    Ok(Some(dest))
+}
+
+fn parse(path: &Path) -> Option<Backup> {
+    let name = path.file_name()?.to_str()?;
+    let (original, time) = name.rsplit_once('.')?;
+    let time = time.parse::<i64>().ok()?;
+    let dir = path.parent()?;
+    let meta = std::fs::metadata(path).ok()?;
+    Some(Backup {
+        path: path.to_path_buf(),
+        original: dir.parent()?.join(original),
+        time,
+        size: meta.len(),
+    })
+}
+
+/// Backups of the files in `dir`, newest first.
+pub fn list(dir: &Path) -> Vec<Backup> {
+    let Ok(entries) = std::fs::read_dir(dir.join(DIR)) else {
+        return Vec::new();
+    };
+    let mut res: Vec<Backup> = entries.flatten().filter_map(|e| parse(&e.path())).collect();
+    res.sort_by(|a, b| b.time.cmp(&a.time));
+    res
+}
+
+/// Put the backup back, the current file if any is backed up first.
+pub fn restore(backup: &Backup) -> ResultType<()> {
+    if !backup.path.is_file() {
+        bail!("Backup {:?} not found", backup.path);
+    }
+    self::backup(&backup.original)?;
+    std::fs::rename(&backup.path, &backup.original)?;
+    Ok(())
+}
+
+fn prune(dir: &Path, max_size: u64) {
+    let Some(parent) = dir.parent() else {
+        return;
+    };
+    let backups = list(parent);
+    let mut total = 0;
+    for b in backups {
+        total += b.size;
+        if total > max_size {
+            std::fs::remove_file(&b.path).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+        let path = dir.join("a.txt");
+        std::fs::write(&path, b"v1").unwrap();
+        assert!(backup(&path).unwrap().is_some());
+        assert!(!path.exists());
+        std::fs::write(&path, b"v2").unwrap();
+        backup(&path).unwrap();
+        let backups = list(&dir);
+        assert_eq!(backups.len(), 2);
+        assert_eq!(backups[0].original, path);
+        assert_eq!(std::fs::read(&backups[0].path).unwrap(), b"v2");
+        std::fs::write(&path, b"v3").unwrap();
+        restore(&backups[1]).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"v1");
+        assert_eq!(list(&dir).len(), 2);
+        prune(&dir.join(DIR), 2);
+        assert_eq!(list(&dir).len(), 1);
+    }
+}
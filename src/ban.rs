@@ -0,0 +1,203 @@
+// Brute-force protection for the server side.
+//
+// Failed authentication attempts are counted per remote id or ip in a sliding
+// window, too many of them ban the remote. Bans are kept in the `_bans` config
+// file so that restarting the service does not lift them, repeated bans of the
+// same remote last longer each time.
+use crate::{
+    config::{common_load, common_store},
+    get_time,
+    security::events::{self, SecurityEvent},
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+pub const FAILURE_WINDOW: i64 = 5 * 60 * 1000;
+pub const MAX_FAILURES: usize = 6;
+pub const BAN_DURATION: i64 = 10 * 60 * 1000;
+const MAX_BAN_DURATION: i64 = 24 * 3600 * 1000;
+// How long a ban is remembered after it expired, for escalation
+const BAN_MEMORY: i64 = 7 * 24 * 3600 * 1000;
+// Remotes whose failures are counted, a flood of attempts from random ids or
+// ips must not grow the map without bound.
+pub const MAX_TRACKED: usize = 4096;
+
+lazy_static::lazy_static! {
+    static ref BANS: Mutex<Bans> = Mutex::new(Bans::load());
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Ban {
+    #[serde(default)]
+    until: i64,
+    #[serde(default)]
+    count: u32,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Bans {
+    #[serde(default)]
+    bans: HashMap<String, Ban>,
+    #[serde(skip)]
+    failures: HashMap<String, VecDeque<i64>>,
+}
+
+impl Bans {
+    fn load() -> Self {
+        let mut bans = common_load::<Bans>("_bans");
+        let now = get_time();
+        bans.bans.retain(|_, b| now < b.until + BAN_MEMORY);
+        bans
+    }
+
+    fn store(&self) {
+        common_store(self, "_bans");
+    }
+
+    fn is_banned(&self, key: &str, now: i64) -> bool {
+        self.bans.get(key).map_or(false, |b| now < b.until)
+    }
+
+    // Drops the remotes without failure in the window, and if still full, the one
+    // failed least recently.
+    fn evict(&mut self, now: i64) {
+        self.failures
+            .retain(|_, x| x.back().map_or(false, |t| now - t <= FAILURE_WINDOW));
+        if self.failures.len() < MAX_TRACKED {
+            return;
+        }
+        let oldest = self
+            .failures
+            .iter()
+            .min_by_key(|(_, x)| x.back().copied().unwrap_or_default())
+            .map(|(k, _)| k.clone());
+        if let Some(oldest) = oldest {
+            self.failures.remove(&oldest);
+        }
+    }
+
+    // Returns true if the failure banned `key`.
+    fn record_failure(&mut self, key: &str, now: i64) -> bool {
+        if !self.failures.contains_key(key) && self.failures.len() >= MAX_TRACKED {
+            self.evict(now);
+        }
+        let failures = self.failures.entry(key.to_owned()).or_default();
+        while failures.front().map_or(false, |t| now - t > FAILURE_WINDOW) {
+            failures.pop_front();
+        }
+        failures.push_back(now);
+        if failures.len() < MAX_FAILURES {
+            return false;
+        }
+        self.failures.remove(key);
+        let ban = self.bans.entry(key.to_owned()).or_default();
+        let duration = BAN_DURATION
+            .saturating_mul(1 << ban.count.min(16))
+            .min(MAX_BAN_DURATION);
+        ban.until = now + duration;
+        ban.count += 1;
+        log::warn!(
+            "{} banned for {}s after {} failed attempts",
+            key,
+            duration / 1000,
+            MAX_FAILURES
+        );
+        true
+    }
+
+    fn clear(&mut self, key: &str) -> bool {
+        self.failures.remove(key);
+        self.bans.remove(key).is_some()
+    }
+}
+
+/// `key` is the remote id or ip, check both if both are known.
+pub fn is_banned(key: &str) -> bool {
+    BANS.lock().unwrap().is_banned(key, get_time())
+}
+
+/// Returns true if `key` is banned from now on.
+pub fn record_failure(key: &str) -> bool {
+    let mut bans = BANS.lock().unwrap();
+    let banned = bans.record_failure(key, get_time());
+    if banned {
+        bans.store();
+        drop(bans);
+        events::emit(SecurityEvent::Banned {
+            key: key.to_owned(),
+        });
+    }
+    banned
+}
+
+/// Lift the ban and reset the failure count, e.g. after a successful login or
+/// manually by the user.
+pub fn clear(key: &str) {
+    let mut bans = BANS.lock().unwrap();
+    if bans.clear(key) {
+        bans.store();
+    }
+}
+
+/// Currently banned remotes with the time (ms) the ban ends.
+pub fn get_bans() -> Vec<(String, i64)> {
+    let now = get_time();
+    BANS.lock()
+        .unwrap()
+        .bans
+        .iter()
+        .filter(|(_, b)| now < b.until)
+        .map(|(k, b)| (k.clone(), b.until))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ban() {
+        let mut bans = Bans::default();
+        let key = "1.2.3.4";
+        for i in 0..MAX_FAILURES - 1 {
+            assert!(!bans.record_failure(key, i as i64 * 1000));
+        }
+        // the first failure is out of the window
+        let t = FAILURE_WINDOW + 500;
+        assert!(!bans.record_failure(key, t));
+        assert!(bans.record_failure(key, t));
+        assert!(bans.is_banned(key, t + 1000));
+        assert!(!bans.is_banned("123456789", t + 1000));
+        let t = t + BAN_DURATION;
+        assert!(!bans.is_banned(key, t));
+        // the second ban lasts longer
+        for _ in 0..MAX_FAILURES {
+            bans.record_failure(key, t);
+        }
+        assert!(bans.is_banned(key, t + BAN_DURATION));
+        assert!(!bans.is_banned(key, t + BAN_DURATION * 2));
+        assert!(bans.clear(key));
+        assert!(!bans.is_banned(key, t));
+    }
+
+    #[test]
+    fn test_tracked_bound() {
+        let mut bans = Bans::default();
+        for i in 0..MAX_TRACKED * 2 {
+            bans.record_failure(&i.to_string(), i as i64);
+        }
+        assert_eq!(bans.failures.len(), MAX_TRACKED);
+        // the most recent are kept
+        assert!(bans
+            .failures
+            .contains_key(&(MAX_TRACKED * 2 - 1).to_string()));
+        assert!(!bans.failures.contains_key("0"));
+        // out of the window
+        let now = FAILURE_WINDOW * 2;
+        bans.record_failure("1.2.3.4", now);
+        assert_eq!(bans.failures.len(), 1);
+    }
+}
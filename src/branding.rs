@@ -0,0 +1,220 @@
+// The identity of a custom build set at runtime, instead of editing the
+// constants of config.rs: the app name, the organization, the ports and the
+// builtin servers and key.
+//
+// A `Branding` is built from the values of the caller or from a blob signed by
+// the vendor, embedded into the binary, and applied once at startup before any
+// config is loaded: the paths of the configs are derived from the app name, so
+// it can not change once they are used. The values not set keep the builtin
+// `RENDEZVOUS_SERVERS`, `RS_PUB_KEY`, `RENDEZVOUS_PORT` and `RELAY_PORT`, which are
+// to be read with the functions of this module.
+use crate::{
+    bail,
+    config::{APP_NAME, RELAY_PORT, RENDEZVOUS_PORT, RENDEZVOUS_SERVERS, RS_PUB_KEY},
+    ResultType,
+};
+use serde_derive::Deserialize;
+use sodiumoxide::{base64, crypto::sign};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    RwLock,
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Branding {
+    #[serde(default)]
+    app_name: Option<String>,
+    /// Used on macOS only, see `config::ORG`.
+    #[serde(default)]
+    org: Option<String>,
+    #[serde(default)]
+    rendezvous_servers: Option<Vec<String>>,
+    #[serde(default)]
+    rs_pub_key: Option<String>,
+    #[serde(default)]
+    rendezvous_port: Option<i32>,
+    /// `rendezvous_port` + 1 if only that is set.
+    #[serde(default)]
+    relay_port: Option<i32>,
+}
+
+lazy_static::lazy_static! {
+    static ref BRANDING: RwLock<Branding> = Default::default();
+}
+
+// set once the path of a config is used
+static SEALED: AtomicBool = AtomicBool::new(false);
+
+impl Branding {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn app_name(mut self, v: &str) -> Self {
+        self.app_name = Some(v.to_owned());
+        self
+    }
+
+    pub fn org(mut self, v: &str) -> Self {
+        self.org = Some(v.to_owned());
+        self
+    }
+
+    pub fn rendezvous_servers(mut self, v: &[&str]) -> Self {
+        self.rendezvous_servers = Some(v.iter().map(|x| x.to_string()).collect());
+        self
+    }
+
+    pub fn rs_pub_key(mut self, v: &str) -> Self {
+        self.rs_pub_key = Some(v.to_owned());
+        self
+    }
+
+    pub fn rendezvous_port(mut self, v: i32) -> Self {
+        self.rendezvous_port = Some(v);
+        self
+    }
+
+    pub fn relay_port(mut self, v: i32) -> Self {
+        self.relay_port = Some(v);
+        self
+    }
+
+    /// A JSON object with the fields in kebab-case, e.g. "app-name", signed with
+    /// `crypto_sign` by the key of the vendor, whose public key is `pk`.
+    pub fn from_signed_blob(blob: &[u8], pk: &[u8]) -> ResultType<Self> {
+        let Some(pk) = sign::PublicKey::from_slice(pk) else {
+            bail!("Invalid public key of the branding");
+        };
+        let Ok(data) = sign::verify(blob, &pk) else {
+            bail!("Invalid signature of the branding");
+        };
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    fn validate(&self) -> ResultType<()> {
+        if let Some(name) = &self.app_name {
+            // a path component of the configs
+            if name.is_empty() || name.contains(|c| matches!(c, '/' | '\\' | ':' | '.')) {
+                bail!("Invalid app name: {:?}", name);
+            }
+        }
+        if let Some(servers) = &self.rendezvous_servers {
+            if servers.is_empty() || servers.iter().any(|x| x.trim().is_empty()) {
+                bail!("Invalid rendezvous servers: {:?}", servers);
+            }
+        }
+        if let Some(key) = &self.rs_pub_key {
+            let valid = base64::decode(key, base64::Variant::Original)
+                .map_or(false, |x| x.len() == sign::PUBLICKEYBYTES);
+            if !valid {
+                bail!("Invalid public key of the rendezvous server");
+            }
+        }
+        for port in [self.rendezvous_port, self.relay_port].iter().flatten() {
+            // and the ports after them, see `websocket`
+            if !(1..=65535 - 3).contains(port) {
+                bail!("Invalid port: {}", port);
+            }
+        }
+        Ok(())
+    }
+
+    /// Validates and applies all values, or none. Fails once a config was loaded.
+    pub fn apply(self) -> ResultType<()> {
+        self.validate()?;
+        let mut lock = BRANDING.write().unwrap();
+        if SEALED.load(Ordering::SeqCst) {
+            bail!("Branding must be applied before the config is loaded");
+        }
+        if let Some(name) = &self.app_name {
+            *APP_NAME.write().unwrap() = name.clone();
+        }
+        #[cfg(target_os = "macos")]
+        if let Some(org) = &self.org {
+            *crate::config::ORG.write().unwrap() = org.clone();
+        }
+        log::info!("Branding applied: {:?}", self);
+        *lock = self;
+        Ok(())
+    }
+}
+
+/// Called when the path of a config is first used, `Branding::apply` fails after.
+#[inline]
+pub(crate) fn seal() {
+    SEALED.store(true, Ordering::Relaxed);
+}
+
+pub fn rendezvous_servers() -> Vec<String> {
+    match &BRANDING.read().unwrap().rendezvous_servers {
+        Some(servers) => servers.clone(),
+        None => RENDEZVOUS_SERVERS.iter().map(|x| x.to_string()).collect(),
+    }
+}
+
+pub fn rs_pub_key() -> String {
+    BRANDING
+        .read()
+        .unwrap()
+        .rs_pub_key
+        .clone()
+        .unwrap_or_else(|| RS_PUB_KEY.to_owned())
+}
+
+pub fn rendezvous_port() -> i32 {
+    BRANDING
+        .read()
+        .unwrap()
+        .rendezvous_port
+        .unwrap_or(RENDEZVOUS_PORT)
+}
+
+pub fn relay_port() -> i32 {
+    let b = BRANDING.read().unwrap();
+    b.relay_port
+        .or_else(|| b.rendezvous_port.map(|x| x + 1))
+        .unwrap_or(RELAY_PORT)
+}
+
+/// The WebSocket ports follow the rendezvous and relay ports.
+#[inline]
+pub fn ws_rendezvous_port() -> i32 {
+    rendezvous_port() + 2
+}
+
+#[inline]
+pub fn ws_relay_port() -> i32 {
+    relay_port() + 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_branding() {
+        let (pk, sk) = sign::gen_keypair();
+        let json = br#"{"app-name": "AcmeDesk", "rendezvous-servers": ["rs.acme.com"], "rendezvous-port": 31116}"#;
+        let blob = sign::sign(json, &sk);
+        let branding = Branding::from_signed_blob(&blob, &pk.0).unwrap();
+        assert_eq!(
+            branding,
+            Branding::new()
+                .app_name("AcmeDesk")
+                .rendezvous_servers(&["rs.acme.com"])
+                .rendezvous_port(31116)
+        );
+        assert!(branding.validate().is_ok());
+        let mut tampered = blob.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(Branding::from_signed_blob(&tampered, &pk.0).is_err());
+        let (other, _) = sign::gen_keypair();
+        assert!(Branding::from_signed_blob(&blob, &other.0).is_err());
+        assert!(Branding::new().app_name("../x").validate().is_err());
+        assert!(Branding::new().rs_pub_key("abc").validate().is_err());
+        assert!(Branding::new().relay_port(70000).validate().is_err());
+        assert!(Branding::new().rendezvous_servers(&[]).validate().is_err());
+    }
+}
@@ -0,0 +1,187 @@
+// Certificate based authentication, for deployments that can not rely on
+// passwords or on confirming the peer key on first use.
+//
+// The certificates are issued by a deployment CA whose sign pk is configured
+// with `OPTION_CA_PUBLIC_KEYS`, intermediate CAs are allowed.
+use crate::{
+    bail,
+    config::{keys, option2bool, Config},
+    get_time,
+    message_proto::{Certificate, CertificateChain},
+    protobuf::Message as _,
+    ResultType,
+};
+use sodiumoxide::{base64, crypto::sign};
+
+pub const MAX_CHAIN_LEN: usize = 4;
+
+pub fn is_enabled() -> bool {
+    option2bool(
+        keys::OPTION_ENABLE_CERTIFICATE_AUTH,
+        &Config::get_option(keys::OPTION_ENABLE_CERTIFICATE_AUTH),
+    )
+}
+
+pub fn get_ca_public_keys() -> Vec<Vec<u8>> {
+    Config::get_option(keys::OPTION_CA_PUBLIC_KEYS)
+        .split(',')
+        .filter_map(|x| base64::decode(x.trim(), base64::Variant::Original).ok())
+        .filter(|x| x.len() == sign::PUBLICKEYBYTES)
+        .collect()
+}
+
+/// Our own chain, to be sent in `LoginRequest.certificate_chain`.
+pub fn get_my_certificate_chain() -> Option<CertificateChain> {
+    let v = Config::get_option(keys::OPTION_CERTIFICATE_CHAIN);
+    if v.is_empty() {
+        return None;
+    }
+    let data = base64::decode(v.trim(), base64::Variant::Original).ok()?;
+    CertificateChain::parse_from_bytes(&data).ok()
+}
+
+pub fn set_my_certificate_chain(chain: &CertificateChain) -> ResultType<()> {
+    let data = chain.write_to_bytes()?;
+    Config::set_option(
+        keys::OPTION_CERTIFICATE_CHAIN.to_owned(),
+        base64::encode(data, base64::Variant::Original),
+    );
+    Ok(())
+}
+
+fn signed_data(cert: &Certificate) -> Vec<u8> {
+    let mut data = Vec::with_capacity(cert.subject.len() + cert.pk.len() + 24);
+    data.extend_from_slice(&(cert.subject.len() as u32).to_le_bytes());
+    data.extend_from_slice(cert.subject.as_bytes());
+    data.extend_from_slice(&cert.pk);
+    data.extend_from_slice(&cert.not_before.to_le_bytes());
+    data.extend_from_slice(&cert.not_after.to_le_bytes());
+    data.push(cert.is_ca as u8);
+    data
+}
+
+/// Issue `cert` with the issuer's sign sk, for provisioning tools.
+pub fn sign_certificate(cert: &mut Certificate, issuer_sk: &[u8]) -> ResultType<()> {
+    let Some(sk) = sign::SecretKey::from_slice(issuer_sk) else {
+        bail!("Invalid issuer key");
+    };
+    cert.signature = sign::sign_detached(&signed_data(cert), &sk)
+        .to_bytes()
+        .to_vec()
+        .into();
+    Ok(())
+}
+
+fn is_signed_by(cert: &Certificate, issuer_pk: &[u8]) -> bool {
+    let (Some(pk), Ok(sig)) = (
+        sign::PublicKey::from_slice(issuer_pk),
+        sign::Signature::from_bytes(&cert.signature),
+    ) else {
+        return false;
+    };
+    sign::verify_detached(&sig, &signed_data(cert), &pk)
+}
+
+/// Validate the chain presented by `peer_id`, `peer_pk` is the sign pk the peer
+/// proved to own during the handshake.
+pub fn verify_chain(
+    chain: &CertificateChain,
+    peer_id: &str,
+    peer_pk: &[u8],
+    ca_pks: &[Vec<u8>],
+    now: i64,
+) -> ResultType<()> {
+    let certs = &chain.certs;
+    if certs.is_empty() {
+        bail!("Empty certificate chain");
+    }
+    if certs.len() > MAX_CHAIN_LEN {
+        bail!("Certificate chain too long");
+    }
+    let leaf = &certs[0];
+    if leaf.subject != peer_id {
+        bail!("Certificate subject mismatch");
+    }
+    if &leaf.pk[..] != peer_pk {
+        bail!("Certificate key mismatch");
+    }
+    for (i, cert) in certs.iter().enumerate() {
+        if now < cert.not_before || now > cert.not_after {
+            bail!(
+                "Certificate of {} is expired or not yet valid",
+                cert.subject
+            );
+        }
+        if i > 0 && !cert.is_ca {
+            bail!("{} is not allowed to issue certificates", cert.subject);
+        }
+        let signed = match certs.get(i + 1) {
+            Some(issuer) => is_signed_by(cert, &issuer.pk),
+            None => ca_pks.iter().any(|ca| is_signed_by(cert, ca)),
+        };
+        if !signed {
+            bail!("Invalid signature on certificate of {}", cert.subject);
+        }
+    }
+    Ok(())
+}
+
+/// Verify against the configured CAs, fails if certificate auth is disabled.
+pub fn verify_peer(chain: &CertificateChain, peer_id: &str, peer_pk: &[u8]) -> ResultType<()> {
+    if !is_enabled() {
+        bail!("Certificate authentication is disabled");
+    }
+    let ca_pks = get_ca_public_keys();
+    if ca_pks.is_empty() {
+        bail!("No trusted CA configured");
+    }
+    verify_chain(chain, peer_id, peer_pk, &ca_pks, get_time())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn issue(
+        subject: &str,
+        pk: &sign::PublicKey,
+        is_ca: bool,
+        issuer: &sign::SecretKey,
+    ) -> Certificate {
+        let mut cert = Certificate {
+            subject: subject.to_owned(),
+            pk: pk.0.to_vec().into(),
+            not_before: 0,
+            not_after: 1000,
+            is_ca,
+            ..Default::default()
+        };
+        sign_certificate(&mut cert, &issuer.0).unwrap();
+        cert
+    }
+
+    #[test]
+    fn test_verify_chain() {
+        let (ca_pk, ca_sk) = sign::gen_keypair();
+        let (im_pk, im_sk) = sign::gen_keypair();
+        let (peer_pk, _) = sign::gen_keypair();
+        let cas = vec![ca_pk.0.to_vec()];
+        let mut chain = CertificateChain::new();
+        chain.certs.push(issue("123", &peer_pk, false, &im_sk));
+        chain.certs.push(issue("team", &im_pk, true, &ca_sk));
+        assert!(verify_chain(&chain, "123", &peer_pk.0, &cas, 500).is_ok());
+        assert!(verify_chain(&chain, "124", &peer_pk.0, &cas, 500).is_err());
+        assert!(verify_chain(&chain, "123", &im_pk.0, &cas, 500).is_err());
+        assert!(verify_chain(&chain, "123", &peer_pk.0, &cas, 2000).is_err());
+        assert!(verify_chain(&chain, "123", &peer_pk.0, &[], 500).is_err());
+        // intermediate without CA flag
+        chain.certs[1] = issue("team", &im_pk, false, &ca_sk);
+        assert!(verify_chain(&chain, "123", &peer_pk.0, &cas, 500).is_err());
+        // leaf signed directly by the CA
+        let mut chain = CertificateChain::new();
+        chain.certs.push(issue("123", &peer_pk, false, &ca_sk));
+        assert!(verify_chain(&chain, "123", &peer_pk.0, &cas, 500).is_ok());
+        chain.certs[0].not_after = 2000;
+        assert!(verify_chain(&chain, "123", &peer_pk.0, &cas, 500).is_err());
+    }
+}
@@ -0,0 +1,233 @@
+// Persistent cache of the blake3 of whole files, keyed by path, size and
+// modification time, so that unchanged files are not read again by verify-only
+// jobs. The entries of a path are dropped when it is written or renamed, see
+// `invalidate`. A manifest asked with hashes is hashed from the content, see
+// `hash_content`, as a change keeping size and mtime would go unnoticed here.
+//
+// Files modified in the last seconds are not cached, a change within the
+// resolution of the mtime would go unnoticed. The least recently used entries
+// are evicted beyond `MAX_ENTRIES`.
+use crate::config::Config;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const FILE: &str = "checksum_cache.json";
+pub const MAX_ENTRIES: usize = 20_000;
+const RACY: Duration = Duration::from_secs(2);
+const STORE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct Entry {
+    hash: String,
+    // milliseconds since the epoch
+    used: i64,
+}
+
+#[derive(Debug, Default)]
+struct Cache {
+    entries: HashMap<String, Entry>,
+    loaded: bool,
+    dirty: bool,
+    stored: Option<Instant>,
+}
+
+lazy_static::lazy_static! {
+    static ref CACHE: Mutex<Cache> = Default::default();
+}
+
+impl Cache {
+    fn load(&mut self) {
+        if self.loaded {
+            return;
+        }
+        self.loaded = true;
+        if let Ok(data) = std::fs::read(Config::path(FILE)) {
+            self.entries = serde_json::from_slice(&data).unwrap_or_default();
+        }
+    }
+
+    fn store(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        match serde_json::to_vec(&self.entries) {
+            Ok(data) => {
+                let path = Config::path(FILE);
+                let tmp = path.with_extension("tmp");
+                if let Err(err) =
+                    std::fs::write(&tmp, data).and_then(|_| std::fs::rename(&tmp, &path))
+                {
+                    log::error!("Failed to store checksum cache: {}", err);
+                }
+            }
+            Err(err) => log::error!("Failed to serialize checksum cache: {}", err),
+        }
+        self.dirty = false;
+        self.stored = Some(Instant::now());
+    }
+
+    fn evict(&mut self) {
+        if self.entries.len() <= MAX_ENTRIES {
+            return;
+        }
+        let mut used: Vec<i64> = self.entries.values().map(|e| e.used).collect();
+        used.sort_unstable();
+        // make room for a while
+        let threshold = used[self.entries.len() - MAX_ENTRIES * 9 / 10];
+        self.entries.retain(|_, e| e.used >= threshold);
+    }
+}
+
+fn stamp(meta: &std::fs::Metadata) -> Option<(u64, u128)> {
+    let modified = meta.modified().ok()?;
+    let elapsed = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or_default();
+    if elapsed < RACY {
+        return None;
+    }
+    Some((
+        meta.len(),
+        modified.duration_since(UNIX_EPOCH).ok()?.as_nanos(),
+    ))
+}
+
+// Also of a path removed or renamed away, by its parent.
+fn canonical(path: &Path) -> String {
+    let res = path.canonicalize().ok().or_else(|| {
+        let parent = path.parent()?.canonicalize().ok()?;
+        Some(parent.join(path.file_name()?))
+    });
+    res.unwrap_or_else(|| path.to_path_buf())
+        .to_string_lossy()
+        .to_string()
+}
+
+// The entries of a path share the prefix up to its size.
+fn key(path: &Path, (size, modified): (u64, u128)) -> String {
+    format!("{}\n{}\n{}", canonical(path), size, modified)
+}
+
+/// Cached hash of `path` if it did not change since.
+pub fn get(path: &Path) -> Option<blake3::Hash> {
+    let stamp = stamp(&std::fs::metadata(path).ok()?)?;
+    let mut cache = CACHE.lock().unwrap();
+    cache.load();
+    let entry = cache.entries.get_mut(&key(path, stamp))?;
+    entry.used = crate::get_time();
+    blake3::Hash::from_hex(&entry.hash).ok()
+}
+
+/// Remember the hash of `path` computed from the content with `meta`, the
+/// metadata taken before reading it. Ignored if the file changed since.
+pub fn insert(path: &Path, meta: &std::fs::Metadata, hash: blake3::Hash) {
+    let Some(stamp) = stamp(meta) else {
+        return;
+    };
+    if std::fs::metadata(path).ok().and_then(|m| self::stamp(&m)) != Some(stamp) {
+        return;
+    }
+    let mut cache = CACHE.lock().unwrap();
+    cache.load();
+    cache.entries.insert(
+        key(path, stamp),
+        Entry {
+            hash: hash.to_hex().to_string(),
+            used: crate::get_time(),
+        },
+    );
+    cache.dirty = true;
+    cache.evict();
+    if cache.stored.map_or(true, |t| t.elapsed() > STORE_INTERVAL) {
+        cache.store();
+    }
+}
+
+/// blake3 of the whole file, from the cache if unchanged.
+pub fn hash_file(path: &Path) -> std::io::Result<blake3::Hash> {
+    if let Some(hash) = get(path) {
+        return Ok(hash);
+    }
+    hash_content(path)
+}
+
+/// blake3 of the whole file read again, the cache is only updated.
+pub fn hash_content(path: &Path) -> std::io::Result<blake3::Hash> {
+    let mut file = std::fs::File::open(path)?;
+    let meta = file.metadata()?;
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    let hash = hasher.finalize();
+    insert(path, &meta, hash);
+    Ok(hash)
+}
+
+/// Drop the entries of `path`, e.g. when it is written or renamed.
+pub fn invalidate(path: &Path) {
+    let prefix = format!("{}\n", canonical(path));
+    let mut cache = CACHE.lock().unwrap();
+    cache.load();
+    let len = cache.entries.len();
+    cache.entries.retain(|k, _| !k.starts_with(&prefix));
+    if cache.entries.len() != len {
+        cache.dirty = true;
+    }
+}
+
+pub fn clear() {
+    let mut cache = CACHE.lock().unwrap();
+    cache.entries.clear();
+    cache.loaded = true;
+    cache.dirty = true;
+    cache.store();
+}
+
+/// Store pending changes, e.g. when a job finished.
+pub fn flush() {
+    CACHE.lock().unwrap().store();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evict() {
+        let mut cache = Cache {
+            loaded: true,
+            ..Default::default()
+        };
+        for i in 0..MAX_ENTRIES + 1 {
+            cache.entries.insert(
+                i.to_string(),
+                Entry {
+                    hash: String::new(),
+                    used: i as _,
+                },
+            );
+        }
+        cache.evict();
+        assert_eq!(cache.entries.len(), MAX_ENTRIES * 9 / 10);
+        assert!(cache.entries.contains_key(&MAX_ENTRIES.to_string()));
+        assert!(!cache.entries.contains_key("0"));
+    }
+
+    #[test]
+    fn test_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        std::fs::write(&path, b"a").unwrap();
+        let k = key(&path, (1, 2));
+        assert_ne!(k, key(&path, (1, 3)));
+        assert_ne!(k, key(&path, (2, 2)));
+        std::fs::remove_file(&path).unwrap();
+        // the same after removal
+        assert_eq!(k, key(&path, (1, 2)));
+        assert!(k.starts_with(&format!("{}\n", canonical(&path))));
+    }
+}
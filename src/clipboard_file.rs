@@ -0,0 +1,437 @@
+// Copy and paste of files over the Cliprdr messages, shared by all platforms
+// instead of being chunked by each consumer. Gated by
+// `OPTION_ENABLE_FILE_COPY_PASTE`.
+//
+// The side files are copied on sends a `CliprdrFileManifest` and serves
+// `CliprdrFileContentsRequest`s with a `Source`. The side pasting them fetches
+// the files in order into a folder with a `Sink`, keeping a few requests in
+// flight. Either side may send `CliprdrFileCancel`.
+use crate::{
+    bail,
+    config::{keys, Config},
+    fs::{get_path, get_recursive_files, get_string, has_link_below},
+    fs_provider::provider,
+    message_proto::*,
+    sandbox, ResultType,
+};
+use std::{
+    collections::VecDeque,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
+
+pub const CHUNK_SIZE: u32 = 512 * 1024;
+// requests in flight of a sink
+const WINDOW: usize = 4;
+// dw_flags and msg_flags, as in MS-RDPECLIP
+const FILECONTENTS_RANGE: i32 = 0x2;
+const CB_RESPONSE_OK: i32 = 0x1;
+const CB_RESPONSE_FAIL: i32 = 0x2;
+
+#[inline]
+pub fn is_enabled() -> bool {
+    Config::get_bool_option(keys::OPTION_ENABLE_FILE_COPY_PASTE)
+}
+
+fn new_cliprdr(f: impl FnOnce(&mut Cliprdr)) -> Message {
+    let mut cliprdr = Cliprdr::new();
+    f(&mut cliprdr);
+    let mut msg = Message::new();
+    msg.set_cliprdr(cliprdr);
+    msg
+}
+
+pub fn new_cancel(clip_data_id: i32) -> Message {
+    new_cliprdr(|c| {
+        c.set_cancel(CliprdrFileCancel {
+            clip_data_id,
+            ..Default::default()
+        })
+    })
+}
+
+// Only plain relative names are accepted from the peer.
+fn is_relative(name: &str) -> bool {
+    !name.is_empty()
+        && Path::new(name)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// The files copied on this side.
+pub struct Source {
+    clip_data_id: i32,
+    // full path, manifest entry
+    files: Vec<(PathBuf, CliprdrFile)>,
+    // list index and file being read
+    open: Option<(i32, std::fs::File)>,
+}
+
+impl Source {
+    /// `paths` are the copied files and folders, folders are sent with
+    /// their content.
+    pub fn new(clip_data_id: i32, paths: &[PathBuf]) -> ResultType<Self> {
+        if !is_enabled() {
+            bail!("File copy and paste is disabled");
+        }
+        let mut files = Vec::new();
+        for path in paths {
+            let base = path
+                .file_name()
+                .map(|x| x.to_string_lossy().to_string())
+                .unwrap_or_default();
+            for entry in get_recursive_files(&get_string(path), true)? {
+                let (full, name) = if entry.name.is_empty() {
+                    (path.clone(), base.clone())
+                } else {
+                    (
+                        path.join(&entry.name),
+                        get_string(&get_path(&base).join(&entry.name)),
+                    )
+                };
+                files.push((
+                    full,
+                    CliprdrFile {
+                        name,
+                        size: entry.size,
+                        ..Default::default()
+                    },
+                ));
+            }
+        }
+        Ok(Self {
+            clip_data_id,
+            files,
+            open: None,
+        })
+    }
+
+    pub fn manifest(&self) -> Message {
+        let files = self.files.iter().map(|(_, f)| f.clone()).collect();
+        new_cliprdr(|c| {
+            c.set_manifest(CliprdrFileManifest {
+                clip_data_id: self.clip_data_id,
+                files,
+                ..Default::default()
+            })
+        })
+    }
+
+    fn read(&mut self, req: &CliprdrFileContentsRequest) -> ResultType<Vec<u8>> {
+        let Some((path, _)) = self.files.get(req.list_index as usize) else {
+            bail!("Wrong list index {}", req.list_index);
+        };
+        if self.open.as_ref().map(|(i, _)| *i) != Some(req.list_index) {
+            self.open = Some((req.list_index, provider().open_read(path)?));
+        }
+        let Some((_, file)) = self.open.as_mut() else {
+            bail!("No file");
+        };
+        let position =
+            ((req.n_position_high as u32 as u64) << 32) | req.n_position_low as u32 as u64;
+        file.seek(SeekFrom::Start(position))?;
+        let len = (req.cb_requested.max(0) as u32).min(CHUNK_SIZE);
+        let mut buf = Vec::with_capacity(len as _);
+        file.take(len as _).read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// The response to send back, None if the message is not for this source.
+    pub fn handle(&mut self, cliprdr: &Cliprdr) -> Option<Message> {
+        if cliprdr.has_cancel() {
+            if cliprdr.cancel().clip_data_id == self.clip_data_id {
+                self.open = None;
+            }
+            return None;
+        }
+        if !cliprdr.has_file_contents_request() {
+            return None;
+        }
+        let req = cliprdr.file_contents_request();
+        if !req.have_clip_data_id || req.clip_data_id != self.clip_data_id {
+            return None;
+        }
+        let (msg_flags, requested_data) = match self.read(req) {
+            Ok(data) => (CB_RESPONSE_OK, data),
+            Err(err) => {
+                log::error!("Failed to read clipboard file: {}", err);
+                (CB_RESPONSE_FAIL, Vec::new())
+            }
+        };
+        Some(new_cliprdr(|c| {
+            c.set_file_contents_response(CliprdrFileContentsResponse {
+                msg_flags,
+                stream_id: req.stream_id,
+                requested_data: requested_data.into(),
+                ..Default::default()
+            })
+        }))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkState {
+    Running,
+    Done,
+    Cancelled,
+}
+
+// request in flight
+#[derive(Debug)]
+struct Pending {
+    stream_id: i32,
+    list_index: usize,
+    len: u64,
+}
+
+/// Fetches the files of a manifest into a folder.
+pub struct Sink {
+    clip_data_id: i32,
+    dir: PathBuf,
+    files: Vec<CliprdrFile>,
+    pending: VecDeque<Pending>,
+    next_stream_id: i32,
+    // next file and offset to request
+    next: (usize, u64),
+    // file being written
+    open: Option<(usize, std::fs::File)>,
+    // files completely written
+    completed: usize,
+    finished_size: u64,
+    total_size: u64,
+    state: SinkState,
+}
+
+impl Sink {
+    pub fn new(manifest: &CliprdrFileManifest, dir: PathBuf) -> ResultType<Self> {
+        if !is_enabled() {
+            bail!("File copy and paste is disabled");
+        }
+        if let Some(f) = manifest.files.iter().find(|f| !is_relative(&f.name)) {
+            bail!("Invalid clipboard file name {}", f.name);
+        }
+        Ok(Self {
+            clip_data_id: manifest.clip_data_id,
+            dir,
+            files: manifest.files.clone(),
+            pending: VecDeque::new(),
+            next_stream_id: 0,
+            next: (0, 0),
+            open: None,
+            completed: 0,
+            finished_size: 0,
+            total_size: manifest.files.iter().map(|f| f.size).sum(),
+            state: SinkState::Running,
+        })
+    }
+
+    #[inline]
+    pub fn state(&self) -> SinkState {
+        self.state
+    }
+
+    /// (finished, total) bytes
+    #[inline]
+    pub fn progress(&self) -> (u64, u64) {
+        (self.finished_size, self.total_size)
+    }
+
+    /// Paths of the files written so far.
+    pub fn completed_files(&self) -> Vec<PathBuf> {
+        self.files[..self.completed]
+            .iter()
+            .map(|f| self.dir.join(&f.name))
+            .collect()
+    }
+
+    fn create(&mut self, list_index: usize) -> ResultType<std::fs::File> {
+        let name = &self.files[list_index].name;
+        let path = sandbox::check_path(&self.dir.join(name))?;
+        // before creating the folders, which would follow the link
+        if has_link_below(&self.dir, name) {
+            bail!("Link in the path of {}", name);
+        }
+        if let Some(parent) = path.parent() {
+            provider().create_dir_all(parent)?;
+        }
+        provider().open_write(&path)
+    }
+
+    // Empty files have nothing to request.
+    fn skip_empty(&mut self) -> ResultType<()> {
+        while self.next.0 < self.files.len() && self.files[self.next.0].size == 0 {
+            self.create(self.next.0)?;
+            self.next.0 += 1;
+            if self.pending.is_empty() {
+                self.completed = self.next.0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Requests to send to keep the window full.
+    pub fn requests(&mut self) -> ResultType<Vec<Message>> {
+        let mut res = Vec::new();
+        if self.state != SinkState::Running {
+            return Ok(res);
+        }
+        self.skip_empty()?;
+        while self.pending.len() < WINDOW && self.next.0 < self.files.len() {
+            let (list_index, offset) = self.next;
+            let size = self.files[list_index].size;
+            let len = (size - offset).min(CHUNK_SIZE as u64);
+            self.next_stream_id = self.next_stream_id.wrapping_add(1);
+            let req = CliprdrFileContentsRequest {
+                stream_id: self.next_stream_id,
+                list_index: list_index as _,
+                dw_flags: FILECONTENTS_RANGE,
+                n_position_low: offset as u32 as i32,
+                n_position_high: (offset >> 32) as u32 as i32,
+                cb_requested: len as _,
+                have_clip_data_id: true,
+                clip_data_id: self.clip_data_id,
+                ..Default::default()
+            };
+            res.push(new_cliprdr(|c| c.set_file_contents_request(req)));
+            self.pending.push_back(Pending {
+                stream_id: self.next_stream_id,
+                list_index,
+                len,
+            });
+            self.next = if offset + len >= size {
+                (list_index + 1, 0)
+            } else {
+                (list_index, offset + len)
+            };
+            self.skip_empty()?;
+        }
+        if self.pending.is_empty() && self.next.0 >= self.files.len() {
+            self.completed = self.files.len();
+            self.state = SinkState::Done;
+        }
+        Ok(res)
+    }
+
+    /// Handle a message of the peer, returns the next requests.
+    pub fn handle(&mut self, cliprdr: &Cliprdr) -> ResultType<Vec<Message>> {
+        if cliprdr.has_cancel() {
+            if cliprdr.cancel().clip_data_id == self.clip_data_id {
+                self.abort();
+            }
+            return Ok(Vec::new());
+        }
+        if !cliprdr.has_file_contents_response() || self.state != SinkState::Running {
+            return Ok(Vec::new());
+        }
+        let resp = cliprdr.file_contents_response();
+        let Some(pending) = self.pending.pop_front() else {
+            bail!("Unexpected clipboard file response");
+        };
+        if pending.stream_id != resp.stream_id {
+            self.abort();
+            bail!("Unexpected clipboard file stream {}", resp.stream_id);
+        }
+        if resp.msg_flags & CB_RESPONSE_FAIL != 0 || resp.requested_data.len() as u64 != pending.len
+        {
+            self.abort();
+            bail!(
+                "Failed to get clipboard file {}",
+                self.files[pending.list_index].name
+            );
+        }
+        if self.open.as_ref().map(|(i, _)| *i) != Some(pending.list_index) {
+            let file = self.create(pending.list_index)?;
+            self.open = Some((pending.list_index, file));
+        }
+        if let Some((_, file)) = self.open.as_mut() {
+            file.write_all(&resp.requested_data)?;
+        }
+        self.finished_size += pending.len;
+        let done = match self.pending.front() {
+            Some(p) => p.list_index != pending.list_index,
+            None => self.next.0 != pending.list_index,
+        };
+        if done {
+            self.open = None;
+            self.completed = pending.list_index + 1;
+        }
+        self.requests()
+    }
+
+    /// Stop and remove the files not completed, the returned message tells
+    /// the peer.
+    pub fn cancel(&mut self) -> Message {
+        self.abort();
+        new_cancel(self.clip_data_id)
+    }
+
+    fn abort(&mut self) {
+        if self.state != SinkState::Running {
+            return;
+        }
+        self.state = SinkState::Cancelled;
+        self.pending.clear();
+        self.open = None;
+        for f in self.files[self.completed..].iter() {
+            provider().remove(&self.dir.join(&f.name)).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_paste() {
+        let base = tempfile::tempdir().unwrap();
+        let src = base.path().join("src");
+        std::fs::create_dir_all(src.join("dir")).unwrap();
+        let big: Vec<u8> = (0..CHUNK_SIZE as usize * 3 + 7).map(|i| i as u8).collect();
+        std::fs::write(src.join("dir/big.bin"), &big).unwrap();
+        std::fs::write(src.join("dir/empty"), b"").unwrap();
+        std::fs::write(src.join("a.txt"), b"hello").unwrap();
+        let mut source = Source::new(7, &[src.join("dir"), src.join("a.txt")]).unwrap();
+        let manifest = source.manifest();
+        let dest = base.path().join("dest");
+        let mut sink = Sink::new(manifest.cliprdr().manifest(), dest.clone()).unwrap();
+        let mut requests: VecDeque<Message> = sink.requests().unwrap().into();
+        assert!(requests.len() > 1);
+        while let Some(req) = requests.pop_front() {
+            let resp = source.handle(req.cliprdr()).unwrap();
+            requests.extend(sink.handle(resp.cliprdr()).unwrap());
+        }
+        assert_eq!(sink.state(), SinkState::Done);
+        assert_eq!(
+            sink.progress(),
+            (big.len() as u64 + 5, big.len() as u64 + 5)
+        );
+        assert_eq!(std::fs::read(dest.join("dir/big.bin")).unwrap(), big);
+        assert_eq!(std::fs::read(dest.join("a.txt")).unwrap(), b"hello");
+        assert!(dest.join("dir/empty").exists());
+        assert!(!is_relative("../x"));
+        assert!(!is_relative("/etc/passwd"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_link_in_path() {
+        let base = tempfile::tempdir().unwrap();
+        let outside = base.path().join("outside");
+        let dest = base.path().join("dest");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::create_dir_all(&dest).unwrap();
+        std::os::unix::fs::symlink(&outside, dest.join("dir")).unwrap();
+        let manifest = CliprdrFileManifest {
+            clip_data_id: 7,
+            files: vec![CliprdrFile {
+                name: "dir/sub/a.txt".to_owned(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut sink = Sink::new(&manifest, dest).unwrap();
+        assert!(sink.requests().is_err());
+        assert!(!outside.join("sub").exists());
+    }
+}
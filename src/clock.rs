@@ -0,0 +1,214 @@
+// The source of time of `get_time` and the expiries built on it, e.g. trusted
+// devices after 90 days, password policy and session tickets, and of the timers
+// of `sleep`, e.g. `crate::sleep`, the deferred config stores, the telemetry
+// flushes and the api retries.
+//
+// `MockClock` only moves when advanced, so time-dependent logic can be tested
+// without sleeping. `set_thread_clock` replaces the clock for the current thread
+// only, tests doing so can run in parallel.
+//
+// `Instant` and `SystemTime` are those of `web_time`, the std ones on the other
+// targets, as the std ones panic in the browser.
+use arc_swap::ArcSwap;
+use std::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use web_time::{Instant, SystemTime, UNIX_EPOCH};
+
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+pub trait Clock: Send + Sync {
+    /// Milliseconds since the unix epoch.
+    fn now_ms(&self) -> i64;
+
+    /// Monotonic, for durations. Only comparable with the instants of the same
+    /// clock, those of a `MockClock` are not real ones.
+    fn instant(&self) -> Instant;
+
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0) as _
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        // the timers of tokio use std::time::Instant
+        #[cfg(target_arch = "wasm32")]
+        return crate::wasm::sleep(duration);
+        #[cfg(not(target_arch = "wasm32"))]
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Starts at the given time and only moves with `advance`, sleeps complete when
+/// advanced past their deadline. Clones share the time.
+#[derive(Clone)]
+pub struct MockClock(Arc<MockInner>);
+
+struct MockInner {
+    start_ms: i64,
+    start: Instant,
+    offset: Mutex<Duration>,
+    advanced: tokio::sync::Notify,
+}
+
+impl MockClock {
+    pub fn new(now_ms: i64) -> Self {
+        Self(Arc::new(MockInner {
+            start_ms: now_ms,
+            start: Instant::now(),
+            offset: Default::default(),
+            advanced: Default::default(),
+        }))
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.0.offset.lock().unwrap() += duration;
+        self.0.advanced.notify_waiters();
+    }
+
+    fn offset(&self) -> Duration {
+        *self.0.offset.lock().unwrap()
+    }
+}
+
+impl Clock for MockClock {
+    fn now_ms(&self) -> i64 {
+        self.0.start_ms + self.offset().as_millis() as i64
+    }
+
+    fn instant(&self) -> Instant {
+        self.0.start + self.offset()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        let clock = self.clone();
+        let deadline = self.instant() + duration;
+        Box::pin(async move {
+            loop {
+                let advanced = clock.0.advanced.notified();
+                tokio::pin!(advanced);
+                // registered before the check, an advance in between is not missed
+                advanced.as_mut().enable();
+                if clock.instant() >= deadline {
+                    return;
+                }
+                advanced.await;
+            }
+        })
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref CLOCK: ArcSwap<Arc<dyn Clock>> = ArcSwap::from_pointee(Arc::new(SystemClock) as Arc<dyn Clock>);
+}
+
+thread_local! {
+    static THREAD_CLOCK: RefCell<Option<Arc<dyn Clock>>> = RefCell::new(None);
+}
+
+/// Replaces the clock of all threads without their own.
+pub fn set_clock(clock: Arc<dyn Clock>) {
+    CLOCK.store(Arc::new(clock));
+}
+
+pub fn reset_clock() {
+    set_clock(Arc::new(SystemClock));
+}
+
+/// Replaces the clock of the current thread, None restores the global one.
+pub fn set_thread_clock(clock: Option<Arc<dyn Clock>>) {
+    THREAD_CLOCK.with(|c| *c.borrow_mut() = clock);
+}
+
+pub fn current() -> Arc<dyn Clock> {
+    THREAD_CLOCK
+        .with(|c| c.borrow().clone())
+        .unwrap_or_else(|| (**CLOCK.load()).clone())
+}
+
+#[inline]
+pub fn now_ms() -> i64 {
+    if let Some(ms) = THREAD_CLOCK.with(|c| c.borrow().as_ref().map(|c| c.now_ms())) {
+        return ms;
+    }
+    CLOCK.load().now_ms()
+}
+
+#[inline]
+pub fn instant() -> Instant {
+    current().instant()
+}
+
+/// `tokio::time::sleep` of the current clock, for timers such as REG_INTERVAL.
+pub fn sleep(duration: Duration) -> Sleep {
+    current().sleep(duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TrustedDevice;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new(1_000_000);
+        set_thread_clock(Some(Arc::new(clock.clone())));
+        assert_eq!(crate::get_time(), 1_000_000);
+        let device = TrustedDevice {
+            time: crate::get_time(),
+            ..Default::default()
+        };
+        let start = instant();
+        clock.advance(Duration::from_secs(89 * 24 * 3600));
+        assert!(!device.outdate());
+        clock.advance(Duration::from_secs(2 * 24 * 3600));
+        assert!(device.outdate());
+        assert_eq!(instant() - start, Duration::from_secs(91 * 24 * 3600));
+        set_thread_clock(None);
+        assert!(crate::get_time() > 1_000_000_000_000);
+    }
+
+    #[tokio::test]
+    async fn test_mock_sleep() {
+        let clock = MockClock::new(0);
+        let sleep = clock.sleep(Duration::from_millis(crate::config::REG_INTERVAL as _));
+        let task = tokio::spawn(sleep);
+        clock.advance(Duration::from_secs(10));
+        tokio::task::yield_now().await;
+        assert!(!task.is_finished());
+        clock.advance(Duration::from_secs(5));
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // the timers of the crate, on the thread of the test
+        set_thread_clock(Some(Arc::new(clock.clone())));
+        let task = tokio::spawn(crate::sleep(60.));
+        tokio::task::yield_now().await;
+        assert!(!task.is_finished());
+        clock.advance(Duration::from_secs(60));
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap();
+        set_thread_clock(None);
+    }
+}
@@ -1,12 +1,24 @@
-use std::{cell::RefCell, io};
-use zstd::bulk::Compressor;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use zstd::{
+    bulk::Compressor as ZstdCompressor,
+    dict::{DecoderDictionary, EncoderDictionary},
+};
 
 // The library supports regular compression levels from 1 up to ZSTD_maxCLevel(),
 // which is currently 22. Levels >= 20
 // Default level is ZSTD_CLEVEL_DEFAULT==3.
 // value 0 means default, which is controlled by ZSTD_CLEVEL_DEFAULT
 thread_local! {
-    static COMPRESSOR: RefCell<io::Result<Compressor<'static>>> = RefCell::new(Compressor::new(crate::config::COMPRESS_LEVEL));
+    static COMPRESSOR: RefCell<io::Result<ZstdCompressor<'static>>> = RefCell::new(ZstdCompressor::new(crate::config::COMPRESS_LEVEL));
 }
 
 pub fn compress(data: &[u8]) -> Vec<u8> {
@@ -32,3 +44,755 @@ pub fn compress(data: &[u8]) -> Vec<u8> {
 pub fn decompress(data: &[u8]) -> Vec<u8> {
     zstd::decode_all(data).unwrap_or_default()
 }
+
+// Limit of decompressed payloads, against decompression bombs.
+pub const MAX_PAYLOAD: u64 = 64 * 1024 * 1024;
+
+// Streaming zstd producing the frames of `compress`, so the other side may use
+// either. Memory is bounded by the chunks instead of the whole payload.
+
+/// Compresses chunk by chunk, the output of all calls concatenated is one frame.
+pub struct StreamEncoder {
+    inner: zstd::stream::write::Encoder<'static, Vec<u8>>,
+}
+
+impl StreamEncoder {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            inner: zstd::stream::write::Encoder::new(Vec::new(), crate::config::COMPRESS_LEVEL)?,
+        })
+    }
+
+    /// The compressed output so far, may be empty while zstd buffers.
+    pub fn compress_chunk(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.inner.write_all(data)?;
+        Ok(std::mem::take(self.inner.get_mut()))
+    }
+
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        self.inner.finish()
+    }
+}
+
+/// Decompresses chunk by chunk, failing once the output exceeds `limit`.
+pub struct StreamDecoder {
+    inner: zstd::stream::write::Decoder<'static, Vec<u8>>,
+    total: u64,
+    limit: u64,
+}
+
+impl StreamDecoder {
+    pub fn new(limit: u64) -> io::Result<Self> {
+        Ok(Self {
+            inner: zstd::stream::write::Decoder::new(Vec::new())?,
+            total: 0,
+            limit,
+        })
+    }
+
+    pub fn decompress_chunk(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.inner.write_all(data)?;
+        self.inner.flush()?;
+        let out = std::mem::take(self.inner.get_mut());
+        self.total += out.len() as u64;
+        if self.total > self.limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("decompressed data exceeds {} bytes", self.limit),
+            ));
+        }
+        Ok(out)
+    }
+}
+
+/// Compresses `reader` into `writer`, returns the compressed size.
+pub fn compress_stream<R: Read, W: Write>(reader: R, writer: W) -> io::Result<u64> {
+    let mut encoder = zstd::stream::read::Encoder::new(reader, crate::config::COMPRESS_LEVEL)?;
+    let mut writer = writer;
+    io::copy(&mut encoder, &mut writer)
+}
+
+/// Decompresses `reader` into `writer`, returns the decompressed size.
+pub fn decompress_stream<R: Read, W: Write>(reader: R, writer: W, limit: u64) -> io::Result<u64> {
+    let mut writer = writer;
+    io::copy(&mut decoder(reader, limit)?, &mut writer)
+}
+
+/// A writer compressing into `writer`, e.g. for `serde_json::to_writer`, to be
+/// `finish`ed.
+pub fn encoder<W: Write>(writer: W) -> io::Result<zstd::stream::write::Encoder<'static, W>> {
+    zstd::stream::write::Encoder::new(writer, crate::config::COMPRESS_LEVEL)
+}
+
+/// A reader of the decompressed data, e.g. for `serde_json::from_reader`,
+/// failing once more than `limit` bytes are read.
+pub fn decoder<R: Read>(reader: R, limit: u64) -> io::Result<impl Read> {
+    Ok(Limited {
+        inner: zstd::stream::read::Decoder::new(reader)?,
+        remaining: limit,
+    })
+}
+
+struct Limited<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> Read for Limited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            // distinguish the end of data from reaching the limit
+            let mut probe = [0u8; 1];
+            if self.inner.read(&mut probe)? == 0 {
+                return Ok(0);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "decompressed data exceeds the limit",
+            ));
+        }
+        let len = buf
+            .len()
+            .min(self.remaining.min(usize::MAX as u64) as usize);
+        let n = self.inner.read(&mut buf[..len])?;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+const BROTLI_QUALITY: u32 = 9;
+const BROTLI_WINDOW: u32 = 22;
+
+/// Compression codecs, the value is the bit in `Features.compression_codecs`.
+/// Zstd is what peers without the flags use.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Codec {
+    #[default]
+    Zstd = 0,
+    /// fast, for low-power devices
+    Lz4 = 1,
+    /// higher ratio, for limited bandwidth
+    Brotli = 2,
+}
+
+impl Codec {
+    pub const ALL: [Codec; 3] = [Codec::Zstd, Codec::Lz4, Codec::Brotli];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "lz4",
+            Codec::Brotli => "brotli",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|c| c.name() == name)
+    }
+
+    pub fn from_u32(v: u32) -> Option<Self> {
+        Self::ALL.iter().copied().find(|c| *c as u32 == v)
+    }
+
+    /// `OPTION_COMPRESSION_CODEC`, zstd if not set.
+    pub fn preferred() -> Self {
+        let name = crate::config::Config::get_option(crate::config::keys::OPTION_COMPRESSION_CODEC);
+        Self::from_name(&name).unwrap_or_default()
+    }
+
+    pub fn compressor(self) -> &'static dyn Compressor {
+        match self {
+            Codec::Zstd => &Zstd,
+            Codec::Lz4 => &Lz4,
+            Codec::Brotli => &Brotli,
+        }
+    }
+}
+
+pub trait Compressor: Send + Sync {
+    fn codec(&self) -> Codec;
+    /// Empty on error.
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+    /// Empty on error.
+    fn decompress(&self, data: &[u8]) -> Vec<u8>;
+}
+
+struct Zstd;
+
+impl Compressor for Zstd {
+    fn codec(&self) -> Codec {
+        Codec::Zstd
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        compress(data)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let res = zstd::stream::read::Decoder::new(data)
+            .and_then(|d| d.take(MAX_PAYLOAD + 1).read_to_end(&mut out));
+        if let Err(err) = res {
+            crate::log::debug!("Failed to decompress zstd: {}", err);
+            out.clear();
+        } else if out.len() as u64 > MAX_PAYLOAD {
+            crate::log::debug!("Refuse to decompress more than {} bytes", MAX_PAYLOAD);
+            out.clear();
+        }
+        out
+    }
+}
+
+struct Lz4;
+
+impl Compressor for Lz4 {
+    fn codec(&self) -> Codec {
+        Codec::Lz4
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        lz4_flex::block::compress_prepend_size(data)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Vec<u8> {
+        // the size is prepended as u32 le, checked before allocating
+        let size = match data.get(..4) {
+            Some(b) => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as u64,
+            None => return Vec::new(),
+        };
+        if size > MAX_PAYLOAD {
+            crate::log::debug!("Refuse to decompress {} bytes", size);
+            return Vec::new();
+        }
+        lz4_flex::block::decompress_size_prepended(data).unwrap_or_else(|err| {
+            crate::log::debug!("Failed to decompress lz4: {}", err);
+            Vec::new()
+        })
+    }
+}
+
+struct Brotli;
+
+impl Compressor for Brotli {
+    fn codec(&self) -> Codec {
+        Codec::Brotli
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut reader = brotli::CompressorReader::new(data, 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+        if let Err(err) = reader.read_to_end(&mut out) {
+            crate::log::debug!("Failed to compress brotli: {}", err);
+            out.clear();
+        }
+        out
+    }
+
+    fn decompress(&self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let res = brotli::Decompressor::new(data, 4096)
+            .take(MAX_PAYLOAD + 1)
+            .read_to_end(&mut out);
+        if let Err(err) = res {
+            crate::log::debug!("Failed to decompress brotli: {}", err);
+            out.clear();
+        } else if out.len() as u64 > MAX_PAYLOAD {
+            crate::log::debug!("Refuse to decompress more than {} bytes", MAX_PAYLOAD);
+            out.clear();
+        }
+        out
+    }
+}
+
+/// For `Features.compression_codecs`.
+pub fn codec_flags() -> u32 {
+    Codec::ALL.iter().fold(0, |flags, c| flags | 1 << *c as u32)
+}
+
+/// Codecs of a session. Each side compresses with its preferred codec if the
+/// peer supports it, announced in `Features.compression_codec`, so no extra
+/// round trip is needed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionCodecs {
+    pub encoder: Codec,
+    pub decoder: Codec,
+}
+
+impl SessionCodecs {
+    /// With the `compression_codecs` and `compression_codec` of the peer.
+    pub fn negotiate(peer_flags: u32, peer_preferred: u32) -> Self {
+        Self::negotiate_with(Codec::preferred(), peer_flags, peer_preferred)
+    }
+
+    fn negotiate_with(preferred: Codec, peer_flags: u32, peer_preferred: u32) -> Self {
+        let encoder = if peer_flags & (1 << preferred as u32) != 0 {
+            preferred
+        } else {
+            Codec::Zstd
+        };
+        let decoder = Codec::from_u32(peer_preferred)
+            .filter(|c| peer_flags & (1 << *c as u32) != 0)
+            .unwrap_or_default();
+        Self { encoder, decoder }
+    }
+
+    #[inline]
+    pub fn compress(&self, data: &[u8]) -> Vec<u8> {
+        self.encoder.compressor().compress(data)
+    }
+
+    #[inline]
+    pub fn decompress(&self, data: &[u8]) -> Vec<u8> {
+        self.decoder.compressor().decompress(data)
+    }
+}
+
+/// Byte counts and time of a stream, e.g. a session or a transfer job, to see
+/// whether compression pays off on its content. All streams add to `total_stats`.
+#[derive(Debug, Default)]
+pub struct CompressionStats {
+    raw_in: AtomicU64,
+    compressed_out: AtomicU64,
+    compress_nanos: AtomicU64,
+    compressed_in: AtomicU64,
+    raw_out: AtomicU64,
+    decompress_nanos: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde_derive::Serialize)]
+pub struct StatsSnapshot {
+    /// before compressing
+    pub raw_in: u64,
+    pub compressed_out: u64,
+    pub compress_time: Duration,
+    /// before decompressing
+    pub compressed_in: u64,
+    pub raw_out: u64,
+    pub decompress_time: Duration,
+}
+
+lazy_static::lazy_static! {
+    static ref TOTAL_STATS: CompressionStats = Default::default();
+}
+
+/// Of all streams since start.
+pub fn total_stats() -> StatsSnapshot {
+    TOTAL_STATS.snapshot()
+}
+
+impl CompressionStats {
+    fn add(&self, a: &AtomicU64, b: &AtomicU64, t: &AtomicU64, x: usize, y: usize, d: Duration) {
+        a.fetch_add(x as u64, Ordering::Relaxed);
+        b.fetch_add(y as u64, Ordering::Relaxed);
+        t.fetch_add(d.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_compress(&self, raw: usize, compressed: usize, elapsed: Duration) {
+        for s in [self, &*TOTAL_STATS] {
+            s.add(
+                &s.raw_in,
+                &s.compressed_out,
+                &s.compress_nanos,
+                raw,
+                compressed,
+                elapsed,
+            );
+        }
+    }
+
+    pub fn record_decompress(&self, compressed: usize, raw: usize, elapsed: Duration) {
+        for s in [self, &*TOTAL_STATS] {
+            s.add(
+                &s.compressed_in,
+                &s.raw_out,
+                &s.decompress_nanos,
+                compressed,
+                raw,
+                elapsed,
+            );
+        }
+    }
+
+    /// `compressor.compress` recorded, failures count as not compressed.
+    pub fn compress(&self, compressor: &dyn Compressor, data: &[u8]) -> Vec<u8> {
+        let start = Instant::now();
+        let out = compressor.compress(data);
+        let len = if out.is_empty() {
+            data.len()
+        } else {
+            out.len()
+        };
+        self.record_compress(data.len(), len, start.elapsed());
+        out
+    }
+
+    pub fn decompress(&self, compressor: &dyn Compressor, data: &[u8]) -> Vec<u8> {
+        let start = Instant::now();
+        let out = compressor.decompress(data);
+        self.record_decompress(data.len(), out.len(), start.elapsed());
+        out
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let get = |a: &AtomicU64| a.load(Ordering::Relaxed);
+        StatsSnapshot {
+            raw_in: get(&self.raw_in),
+            compressed_out: get(&self.compressed_out),
+            compress_time: Duration::from_nanos(get(&self.compress_nanos)),
+            compressed_in: get(&self.compressed_in),
+            raw_out: get(&self.raw_out),
+            decompress_time: Duration::from_nanos(get(&self.decompress_nanos)),
+        }
+    }
+}
+
+impl StatsSnapshot {
+    /// Compressed / raw size of the sent data, 1.0 without data.
+    pub fn ratio(&self) -> f64 {
+        if self.raw_in == 0 {
+            1.0
+        } else {
+            self.compressed_out as f64 / self.raw_in as f64
+        }
+    }
+
+    /// Bytes not sent thanks to compression, negative if it grew the data.
+    pub fn saved(&self) -> i64 {
+        self.raw_in as i64 - self.compressed_out as i64
+    }
+
+    /// Compressing throughput in bytes per second.
+    pub fn compress_speed(&self) -> f64 {
+        let secs = self.compress_time.as_secs_f64();
+        if secs > 0.0 {
+            self.raw_in as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Whether compressing saves enough, with the threshold of `is_incompressible`.
+    pub fn is_paying_off(&self) -> bool {
+        self.raw_in == 0 || !is_incompressible(self.raw_in as _, self.compressed_out as _)
+    }
+}
+
+// (offset, magic) of formats which are compressed already
+const MAGICS: &[(usize, &[u8])] = &[
+    (0, b"PK\x03\x04"),         // zip, docx, jar, apk
+    (0, b"\x1f\x8b"),           // gzip
+    (0, b"7z\xbc\xaf\x27\x1c"), // 7z
+    (0, b"Rar!\x1a\x07"),       // rar
+    (0, b"BZh"),                // bzip2
+    (0, b"\xfd7zXZ\x00"),       // xz
+    (0, b"\x28\xb5\x2f\xfd"),   // zstd
+    (0, b"\x89PNG"),            // png
+    (0, b"\xff\xd8\xff"),       // jpeg
+    (0, b"GIF8"),               // gif
+    (8, b"WEBP"),               // webp
+    (4, b"ftyp"),               // mp4, mov, heic
+    (0, b"\x1a\x45\xdf\xa3"),   // mkv, webm
+    (0, b"ID3"),                // mp3
+    (0, b"OggS"),               // ogg, opus
+    (0, b"fLaC"),               // flac
+];
+
+/// Whether `data`, the start of a file, looks like an already compressed format.
+pub fn is_compressed_magic(data: &[u8]) -> bool {
+    MAGICS.iter().any(|(offset, magic)| {
+        data.len() >= offset + magic.len() && &data[*offset..offset + magic.len()] == *magic
+    })
+}
+
+// Compressing further is not worth the cpu if the first chunk saved less.
+const INCOMPRESSIBLE_RATIO: f64 = 0.95;
+
+/// Probe with the compressed size of the first chunk of a stream.
+#[inline]
+pub fn is_incompressible(raw_len: usize, compressed_len: usize) -> bool {
+    compressed_len as f64 >= raw_len as f64 * INCOMPRESSIBLE_RATIO
+}
+
+// Workloads of many small similar payloads, compressed with a shared zstd
+// dictionary. The value is the bit in `Features.zstd_dictionaries`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PayloadClass {
+    Clipboard = 0,
+    ConfigSync = 1,
+    Control = 2,
+}
+
+pub const MAX_DICTIONARY_SIZE: usize = 110 * 1024;
+
+pub struct Dictionary {
+    // the zstd id of a trained dictionary, else derived from the content
+    id: u32,
+    encoder: EncoderDictionary<'static>,
+    decoder: DecoderDictionary<'static>,
+}
+
+fn dictionary_id(data: &[u8]) -> u32 {
+    match zstd::zstd_safe::get_dict_id_from_dict(data) {
+        Some(id) => id.get(),
+        None => {
+            let hash = blake3::hash(data);
+            let mut id = [0u8; 4];
+            id.copy_from_slice(&hash.as_bytes()[..4]);
+            u32::from_le_bytes(id)
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref DICTIONARIES: RwLock<HashMap<PayloadClass, Arc<Dictionary>>> = Default::default();
+}
+
+/// Train a dictionary from typical payloads of a class, e.g. at build time.
+/// Both sides have to register the same one.
+pub fn train_dictionary<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> io::Result<Vec<u8>> {
+    zstd::dict::from_samples(samples, max_size.min(MAX_DICTIONARY_SIZE))
+}
+
+pub fn register_dictionary(class: PayloadClass, data: &[u8]) {
+    let dict = Dictionary {
+        id: dictionary_id(data),
+        encoder: EncoderDictionary::copy(data, crate::config::COMPRESS_LEVEL),
+        decoder: DecoderDictionary::copy(data),
+    };
+    DICTIONARIES.write().unwrap().insert(class, Arc::new(dict));
+}
+
+/// For `Features.zstd_dictionaries`.
+pub fn dictionary_flags() -> u32 {
+    DICTIONARIES
+        .read()
+        .unwrap()
+        .keys()
+        .fold(0, |flags, class| flags | 1 << *class as u32)
+}
+
+/// For `Features.zstd_dictionary_ids`, the id of each dictionary in the order of
+/// the bits of `dictionary_flags`.
+pub fn dictionary_ids() -> Vec<u32> {
+    let dictionaries = DICTIONARIES.read().unwrap();
+    let mut ids: Vec<(u32, u32)> = dictionaries
+        .iter()
+        .map(|(class, dict)| (*class as u32, dict.id))
+        .collect();
+    ids.sort();
+    ids.into_iter().map(|(_, id)| id).collect()
+}
+
+/// Dictionaries usable with a peer, the classes both sides have registered
+/// the same dictionary of.
+#[derive(Default, Clone)]
+pub struct DictionaryCodec {
+    dictionaries: HashMap<PayloadClass, Arc<Dictionary>>,
+}
+
+impl DictionaryCodec {
+    /// `peer_ids` are `Features.zstd_dictionary_ids`, a dictionary without the
+    /// same id on the peer, e.g. of another version, is not used.
+    pub fn negotiate(peer_flags: u32, peer_ids: &[u32]) -> Self {
+        let mut peer: HashMap<u32, u32> = HashMap::new();
+        let mut ids = peer_ids.iter();
+        for bit in 0..32 {
+            if peer_flags & (1 << bit) != 0 {
+                let Some(id) = ids.next() else {
+                    break;
+                };
+                peer.insert(bit, *id);
+            }
+        }
+        let dictionaries = DICTIONARIES
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(class, dict)| peer.get(&(**class as u32)) == Some(&dict.id))
+            .map(|(class, dict)| (*class, dict.clone()))
+            .collect();
+        Self { dictionaries }
+    }
+
+    #[inline]
+    pub fn has(&self, class: PayloadClass) -> bool {
+        self.dictionaries.contains_key(&class)
+    }
+
+    /// Falls back to `compress` without dictionary for this class.
+    pub fn compress(&self, class: PayloadClass, data: &[u8]) -> Vec<u8> {
+        let Some(dict) = self.dictionaries.get(&class) else {
+            return compress(data);
+        };
+        match ZstdCompressor::with_prepared_dictionary(&dict.encoder)
+            .and_then(|mut c| c.compress(data))
+        {
+            Ok(res) => res,
+            Err(err) => {
+                crate::log::debug!("Failed to compress with dictionary: {}", err);
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn decompress(&self, class: PayloadClass, data: &[u8]) -> Vec<u8> {
+        let Some(dict) = self.dictionaries.get(&class) else {
+            return decompress(data);
+        };
+        // the id is written in the frame of a trained dictionary
+        if let Some(id) = zstd::zstd_safe::get_dict_id_from_frame(data) {
+            if id.get() != dict.id {
+                crate::log::debug!("Frame of another dictionary: {}", id);
+                return Vec::new();
+            }
+        }
+        let mut out = Vec::new();
+        let res = zstd::stream::read::Decoder::with_prepared_dictionary(data, &dict.decoder)
+            .and_then(|d| d.take(MAX_PAYLOAD + 1).read_to_end(&mut out));
+        if let Err(err) = res {
+            crate::log::debug!("Failed to decompress with dictionary: {}", err);
+            out.clear();
+        } else if out.len() as u64 > MAX_PAYLOAD {
+            crate::log::debug!("Refuse to decompress more than {} bytes", MAX_PAYLOAD);
+            out.clear();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skip_compression() {
+        assert!(is_compressed_magic(b"PK\x03\x04\x14\x00"));
+        assert!(is_compressed_magic(b"\x00\x00\x00\x20ftypisom"));
+        assert!(is_compressed_magic(b"RIFF\x00\x00\x00\x00WEBPVP8"));
+        assert!(!is_compressed_magic(b"RIFF\x00\x00\x00\x00WAVE"));
+        assert!(!is_compressed_magic(b"ftyp"));
+        assert!(!is_compressed_magic(b""));
+        let text = b"hello world ".repeat(1000);
+        assert!(!is_incompressible(text.len(), compress(&text).len()));
+        let random: Vec<u8> = (0..10000).map(|_| rand::random::<u8>()).collect();
+        assert!(is_incompressible(random.len(), compress(&random).len()));
+    }
+
+    #[test]
+    fn test_codecs() {
+        let text = b"hello world ".repeat(1000);
+        for codec in Codec::ALL {
+            let c = codec.compressor();
+            assert_eq!(c.codec(), codec);
+            let compressed = c.compress(&text);
+            assert!(compressed.len() < text.len());
+            assert_eq!(c.decompress(&compressed), text);
+            assert_eq!(Codec::from_name(codec.name()), Some(codec));
+        }
+        assert!(Lz4.decompress(&[0xff, 0xff, 0xff, 0xff, 0]).is_empty());
+        let bomb = compress(&vec![0u8; MAX_PAYLOAD as usize + 1]);
+        assert!(Zstd.decompress(&bomb).is_empty());
+        let bomb = Brotli.compress(&vec![0u8; MAX_PAYLOAD as usize + 1]);
+        assert!(Brotli.decompress(&bomb).is_empty());
+        let all = codec_flags();
+        let s = SessionCodecs::negotiate_with(Codec::Lz4, all, Codec::Brotli as u32);
+        assert_eq!((s.encoder, s.decoder), (Codec::Lz4, Codec::Brotli));
+        // an old peer
+        let s = SessionCodecs::negotiate_with(Codec::Lz4, 0, 0);
+        assert_eq!(s, SessionCodecs::default());
+    }
+
+    #[test]
+    fn test_stats() {
+        let stats = CompressionStats::default();
+        let text = b"hello world ".repeat(1000);
+        let compressed = stats.compress(Codec::Zstd.compressor(), &text);
+        assert_eq!(
+            stats.decompress(Codec::Zstd.compressor(), &compressed),
+            text
+        );
+        let s = stats.snapshot();
+        assert_eq!(
+            (s.raw_in, s.raw_out),
+            (text.len() as u64, text.len() as u64)
+        );
+        assert_eq!(s.compressed_out, compressed.len() as u64);
+        assert!(s.ratio() < 0.1 && s.saved() > 0 && s.is_paying_off());
+        assert!(total_stats().raw_in >= s.raw_in);
+        let s = CompressionStats::default();
+        s.record_compress(1000, 990, Duration::from_millis(1));
+        assert!(!s.snapshot().is_paying_off());
+    }
+
+    #[test]
+    fn test_stream() {
+        let text = b"hello world ".repeat(100_000);
+        let mut encoder = StreamEncoder::new().unwrap();
+        let mut compressed = Vec::new();
+        for chunk in text.chunks(64 * 1024) {
+            compressed.extend(encoder.compress_chunk(chunk).unwrap());
+        }
+        compressed.extend(encoder.finish().unwrap());
+        assert_eq!(decompress(&compressed), text);
+        let mut decoder = StreamDecoder::new(MAX_PAYLOAD).unwrap();
+        let mut out = Vec::new();
+        for chunk in compressed.chunks(100) {
+            out.extend(decoder.decompress_chunk(chunk).unwrap());
+        }
+        assert_eq!(out, text);
+        let mut decoder = StreamDecoder::new(1000).unwrap();
+        assert!(decoder.decompress_chunk(&compressed).is_err());
+
+        let mut compressed = Vec::new();
+        compress_stream(&text[..], &mut compressed).unwrap();
+        let mut out = Vec::new();
+        let n = decompress_stream(&compressed[..], &mut out, text.len() as _).unwrap();
+        assert_eq!((n as usize, out), (text.len(), text.clone()));
+        assert!(decompress_stream(&compressed[..], io::sink(), text.len() as u64 - 1).is_err());
+    }
+
+    #[test]
+    fn test_dictionary() {
+        let samples: Vec<Vec<u8>> = (0..1000)
+            .map(|i| {
+                format!(
+                    "{{\"type\":\"clipboard\",\"format\":\"text/plain\",\"seq\":{},\"content\":\"item {}\"}}",
+                    i,
+                    i * 7
+                )
+                .into_bytes()
+            })
+            .collect();
+        let dict = train_dictionary(&samples, 4096).unwrap();
+        register_dictionary(PayloadClass::Clipboard, &dict);
+        assert_ne!(dictionary_flags() & 1, 0);
+        let ids = dictionary_ids();
+        assert_eq!(ids[0], dictionary_id(&dict));
+        let codec = DictionaryCodec::negotiate(1 << PayloadClass::Clipboard as u32, &ids);
+        assert!(codec.has(PayloadClass::Clipboard));
+        assert!(!DictionaryCodec::negotiate(0, &[]).has(PayloadClass::Clipboard));
+        // another dictionary of the class, or an old peer without ids
+        assert!(!DictionaryCodec::negotiate(1, &[ids[0] ^ 1]).has(PayloadClass::Clipboard));
+        assert!(!DictionaryCodec::negotiate(1, &[]).has(PayloadClass::Clipboard));
+        let payload = &samples[500];
+        let with_dict = codec.compress(PayloadClass::Clipboard, payload);
+        assert!(with_dict.len() < compress(payload).len());
+        assert_eq!(
+            &codec.decompress(PayloadClass::Clipboard, &with_dict),
+            payload
+        );
+        // no dictionary for this class
+        let plain = codec.compress(PayloadClass::Control, payload);
+        assert_eq!(&decompress(&plain), payload);
+        let other = train_dictionary(&samples[..500], 2048).unwrap();
+        let mut compressor = ZstdCompressor::with_dictionary(3, &other).unwrap();
+        let frame = compressor.compress(payload).unwrap();
+        assert!(codec.decompress(PayloadClass::Clipboard, &frame).is_empty());
+        let bomb = codec.compress(
+            PayloadClass::Clipboard,
+            &vec![0u8; MAX_PAYLOAD as usize + 1],
+        );
+        assert!(codec.decompress(PayloadClass::Clipboard, &bomb).is_empty());
+    }
+}
@@ -30,21 +30,42 @@ use serde_derive::{Deserialize, Serialize}; ///   派生宏：自动生成 Seria
 use serde_json;                       ///   JSON 序列化/反序列化库
 use sodiumoxide::base64;              ///   libsodium 提供的 Base64 编解码
 use sodiumoxide::crypto::sign;        ///   数字签名相关功能
+use sodiumoxide::crypto::auth::hmacsha256; ///   config file integrity
+use sodiumoxide::crypto::secretbox;   ///   caches encrypted with the key of the account
+use sodiumoxide::crypto::secretstream; ///   and streamed
+use dashmap::DashMap;                 ///   concurrent map
+use zeroize::{Zeroize, Zeroizing};   ///   scrub secrets in memory
 
 
 
 ///   ==================== 本地模块导入 ====================
 use crate::{
-    compress::{compress, decompress}, ///   数据压缩与解压函数
+    compress::{self, compress}, ///   数据压缩与解压函数
     log,                              ///   日志模块
+    options::{get_or, is_option_can_save}, ///   option resolution, shared with `web_config`
+    security::events::{self, SecurityEvent}, ///   audit hooks
+    secrets,                          ///   OS secret store, see `store_secret`
+    settings_map::SettingsMap,        ///   lock-free option maps
     password_security::{              ///   密码安全模块
         decrypt_str_or_original,      ///   解密字符串（失败返回原串）
         decrypt_vec_or_original,      ///   解密字节数据（失败返回原数据）
         encrypt_str_or_original,      ///   加密字符串（失败返回原串）
         encrypt_vec_or_original,      ///   加密字节数据（失败返回原数据）
+        hash_secret,                  ///   argon2id verification hash
+        PasswordPolicy,               ///   password rules of the deployment
+        PasswordViolation,
         symmetric_crypt,              ///   对称加密功能
+        verify_secret_hash,           ///   verify against hash_secret output
     },
 };
+pub use crate::options::{
+    keys, option2bool, BUILTIN_SETTINGS, DEFAULT_DISPLAY_SETTINGS, DEFAULT_LOCAL_SETTINGS,
+    DEFAULT_SETTINGS, HARD_SETTINGS, OVERWRITE_DISPLAY_SETTINGS, OVERWRITE_LOCAL_SETTINGS,
+    OVERWRITE_SETTINGS,
+};
+///   the configuration distributed by MDM, also as `config::mdm`
+#[cfg(not(target_arch = "wasm32"))]
+pub use crate::mdm;
 
 ///   ==================== 全局常量定义 ====================
 pub const RENDEZVOUS_TIMEOUT: u64 = 12_000;   ///   集结/协商超时：12 秒（单位毫秒）
@@ -57,7 +78,7 @@ pub const REG_INTERVAL: i64 = 15_000;         ///   心跳/注册间隔：15 秒
 pub const COMPRESS_LEVEL: i32 = 3;            ///   压缩级别：推荐 3（速度与压缩比平衡）
 
 const SERIAL: i32 = 3;                        ///   序列化版本号（用途需结合代码逻辑）
-const PASSWORD_ENC_VERSION: &str = "00";      ///   密码加密版本标识，用于兼容性
+const PASSWORD_ENC_VERSION: &str = "03";      ///   密码加密版本标识，用于兼容性
 
 pub const ENCRYPT_MAX_LEN: usize = 128;       ///   敏感信息（如密码/PIN）最大加密长度（字节）
 
@@ -74,7 +95,7 @@ pub const COMPRESS_LEVEL: i32 = 3;     ///   数据压缩级别，范围通常
 
 const SERIAL: i32 = 3;                 ///   序列号 / 版本号，可能用于数据结构版本控制、配置版本等
 
-const PASSWORD_ENC_VERSION: &str = "00"; ///   密码加密版本标识，用于标识当前使用的加密算法版本，便于兼容旧版本
+const PASSWORD_ENC_VERSION: &str = "03"; ///   密码加密版本标识，用于标识当前使用的加密算法版本，便于兼容旧版本
 
 pub const ENCRYPT_MAX_LEN: usize = 128;  ///   最大加密长度（单位：字节），用于密码、PIN 等敏感信息，超出部分可能不加密
                                            ///   注意：该限制仅适用于特定数据，不是全部数据都受此限制
@@ -121,7 +142,7 @@ lazy_static::lazy_static! {
     static ref LOCAL_CONFIG: RwLock<LocalConfig> = RwLock::new(LocalConfig::load());    ///   全局共享的 LocalConfig（可能是本地个性化配置，如语言、主题）
     static ref STATUS: RwLock<Status> = RwLock::new(Status::load());    ///   全局共享的状态信息（如连接状态、运行状态等）
     static ref TRUSTED_DEVICES: RwLock<(Vec<TrustedDevice>, bool)> = Default::default();    ///   可信设备列表，包含设备信息和一个布尔值（可能表示是否已更新/加载）
-    static ref ONLINE: Mutex<HashMap<String, i64>> = Default::default();            ///   当前在线的用户/设备，用 HashMap<String, i64> 表示，可能是 device_id -> 最后心跳时间戳
+    static ref ONLINE: DashMap<String, i64> = Default::default();            ///   当前在线的用户/设备，用 HashMap<String, i64> 表示，可能是 device_id -> 最后心跳时间戳
     ///  ✅ 作用：这些变量保存了程序运行时需要的​​核心配置和状态信息​​，使用 RwLock或 Mutex保证线程安全，用 lazy_static延迟加载。
 
     
@@ -133,6 +154,13 @@ lazy_static::lazy_static! {
 
     
     static ref KEY_PAIR: Mutex<Option<KeyPair>> = Default::default();            ///   当前程序的密钥对（可能是非对称加密的公钥/私钥），类型是 Vec<u8> 的元组✅ 作用：存储当前设备的加密密钥对，用 Mutex保证线程安全，初始值为 None。
+    static ref KEY_UUID: Mutex<Option<Vec<u8>>> = Default::default();            ///   pk used as uuid where machine uid is unavailable, stable across key rotation
+    static ref TAMPERED_CONFIGS: RwLock<HashSet<String>> = Default::default();            ///   config files whose hmac did not match on load
+    static ref RECOVERED_CONFIGS: RwLock<Vec<ConfigRecovery>> = Default::default();            ///   config files found corrupted on load, see `load_path`
+    static ref UNAVAILABLE_SECRETS: RwLock<HashSet<String>> = Default::default();            ///   marked in the config but not loaded from the secret store
+    static ref TOKEN_STORE: RwLock<TokenStore> = RwLock::new(TokenStore::load());            ///   access tokens of ab / group, see `TokenStore`
+    static ref TOKEN_REFRESH_HOOK: RwLock<Option<TokenRefreshHook>> = Default::default();
+    static ref SESSION_KEY_CACHE: RwLock<SessionKeyCache> = RwLock::new(SessionKeyCache::load());            ///   symmetric keys of recent sessions, see `SessionKeyCache`
 
     ///  🧩 用户默认配置与覆盖配置
     ///   用户默认配置 + 最后加载时间
@@ -141,18 +169,7 @@ lazy_static::lazy_static! {
     pub static ref NEW_STORED_PEER_CONFIG: Mutex<HashSet<String>> = Default::default();        ///   新存储的对等端（peer）配置（HashSet<String>），可能是设备 ID 等
 
     ///   默认设置 / 覆盖设置 / 显示设置 / 本地设置 等，都是键值对形式的配置（HashMap<String, String>）
-    pub static ref DEFAULT_SETTINGS: RwLock<HashMap<String, String>> = Default::default();
-    pub static ref OVERWRITE_SETTINGS: RwLock<HashMap<String, String>> = Default::default();
-    pub static ref DEFAULT_DISPLAY_SETTINGS: RwLock<HashMap<String, String>> = Default::default();
-    pub static ref OVERWRITE_DISPLAY_SETTINGS: RwLock<HashMap<String, String>> = Default::default();
-    pub static ref DEFAULT_LOCAL_SETTINGS: RwLock<HashMap<String, String>> = Default::default();
-    pub static ref OVERWRITE_LOCAL_SETTINGS: RwLock<HashMap<String, String>> = Default::default();
-    pub static ref HARD_SETTINGS: RwLock<HashMap<String, String>> = {
-        let mut map = HashMap::new();
-        map.insert("password".to_string(), "YXhx.369".to_string());
-        RwLock::new(map)
-    };
-    pub static ref BUILTIN_SETTINGS: RwLock<HashMap<String, String>> = Default::default();
+    ///   DEFAULT_SETTINGS 等见 `crate::options`
     ///  ✅ 作用：定义了非常丰富的配置存储结构，包括：
     ///  默认配置 vs 用户覆盖配置
     ///  普通设置、显示设置、本地化设置等
@@ -162,6 +179,8 @@ lazy_static::lazy_static! {
 
 lazy_static::lazy_static! {
     pub static ref APP_DIR: RwLock<String> = Default::default();        ///   当前应用的数据目录 / 安装目录（字符串形式，延迟初始化）
+    static ref CONFIG_ROOT: RwLock<Option<PathBuf>> = Default::default();        ///   see `Config::set_config_root`
+    static ref SERVICE_CONFIG_ROOT: Option<PathBuf> = service_config_root();        ///   the root declared by the service manager, if any
 }
 
 ///   仅在 Android / iOS 平台定义：应用主目录（可能是沙盒内路径）
@@ -170,6 +189,12 @@ lazy_static::lazy_static! {
     pub static ref APP_HOME_DIR: RwLock<String> = Default::default();
 }
 
+///   iOS: container of the app group shared with the extensions, see `Config::set_app_group_dir`
+#[cfg(target_os = "ios")]
+lazy_static::lazy_static! {
+    static ref APP_GROUP_DIR: RwLock<String> = Default::default();
+}
+
 
 
 
@@ -232,13 +257,20 @@ pub const WS_RELAY_PORT: i32 = 2119;
 ​​///  WebSocket 端口​​：可能是为了支持浏览器或其他 WebSocket 客户端接入
 ​​///  RS_PUB_KEY​​：可能是服务器的身份公钥，用于加密通信或身份验证
 
+///   Applies the signed custom config embedded at build time, see `crate::custom_config`.
+///   The hard-coded defaults below are only used by builds without one, secrets such as
+///   the permanent password only come with the custom config, see examples/custom.toml.
 pub fn init_default_settings() {
-    DEFAULT_SETTINGS.write().unwrap().insert("password".to_string(), "Bai21359869".to_string());
-    ///   固定密码 Config::set_permanent_password("Bai21359869");
-    
-    DEFAULT_SETTINGS.write().unwrap().insert("unlock_pin".to_string(), "0.369".to_string());
-    ///   固定PIN Config::set_unlock_pin("0.369");
-
+    match crate::custom_config::load_embedded() {
+        Ok(true) => return,
+        Ok(false) => {}
+        Err(err) => {
+            ///   not falling back, the defaults are not those of the vendor
+            log::error!("Failed to load the embedded custom config: {}", err);
+            return;
+        }
+    }
+    ///   固定密码和 PIN 不写在源码里，见 examples/custom.toml 的 password 和 unlock_pin
     DEFAULT_SETTINGS.write().unwrap().insert("temporary-password-length".to_string(), "6".to_string());
     DEFAULT_SETTINGS.write().unwrap().insert("allow-numeric-one-time-password".to_string(), "Y".to_string());
     ///   一次性密码相关
@@ -362,6 +394,48 @@ pub struct Config {
     key_confirmed: bool,  ///   密钥是否已经被用户确认（比如首次配对后点击确认）
     #[serde(default, deserialize_with = "deserialize_hashmap_string_bool")]
     keys_confirmed: HashMap<String, bool>,  ///   每个设备的密钥确认状态
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_option_keypair"
+    )]
+    old_key_pair: Option<KeyPair>, ///   previous key pair, kept during the rollover grace period
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        deserialize_with = "deserialize_vec_u8"
+    )]
+    key_rollover: Vec<u8>, ///   new pk signed by the old key, see `verify_key_rollover`
+    #[serde(default, deserialize_with = "deserialize_i64")]
+    key_rollover_time: i64,
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        deserialize_with = "deserialize_vec_u8"
+    )]
+    key_uuid: Vec<u8>, ///   the first pk, see `Config::get_key_uuid`
+    #[serde(
+        default,
+        skip_serializing_if = "String::is_empty",
+        deserialize_with = "deserialize_string"
+    )]
+    password_hash: String, ///   argon2id hash of password, see `Config::verify_permanent_password`
+    #[serde(default, deserialize_with = "deserialize_i64")]
+    password_time: i64, ///   when password was set (ms), for the policy expiry
+}
+
+///   Scrub the secrets of a temporary copy, e.g. in `store`, so they do not
+///   linger in freed memory or core dumps. Not a Drop impl, which would forbid
+///   the struct update syntax.
+impl Config {
+    fn zeroize_secrets(&mut self) {
+        self.password.zeroize();
+        self.password_hash.zeroize();
+        self.key_pair.0.zeroize();
+        if let Some(old) = self.old_key_pair.as_mut() {
+            old.0.zeroize();
+        }
+    }
 }
 
 
@@ -377,6 +451,36 @@ pub struct Socks5Server {
     pub password: String,///   代理密码（如有）
 }
 
+impl Socks5Server {
+    ///   Scrubs the password of a copy no longer needed.
+    pub fn zeroize_secrets(&mut self) {
+        self.password.zeroize();
+    }
+}
+
+///   `username` and `password` may be `keyring:<name>` instead, a secret of the credential
+///   manager of the OS, so the proxy password is not in RustDesk2.toml even encrypted.
+pub const KEYRING_PREFIX: &str = "keyring:";
+
+impl Socks5Server {
+    ///   With the `keyring:` references replaced by the secrets, at connect time, as they
+    ///   may change or only be unlocked after login.
+    pub async fn resolve_credentials(&self) -> Result<Socks5Server> {
+        Ok(Socks5Server {
+            proxy: self.proxy.clone(),
+            username: resolve_credential(&self.username).await?,
+            password: resolve_credential(&self.password).await?,
+        })
+    }
+}
+
+async fn resolve_credential(v: &str) -> Result<String> {
+    match v.strip_prefix(KEYRING_PREFIX) {
+        Some(name) => secrets::get_credential(name).await,
+        None => Ok(v.to_owned()),
+    }
+}
+
 ///   more variable configs
 ///  🧩 4. 核心配置结构体 2：Config2（网络 / 选项 / 设备信任等）
 ///  ✅ 作用：保存与 ​​网络连接策略、设备信任、用户 PIN、代理、扩展选项​​ 相关的信息，是对 Config的补充。
@@ -388,10 +492,20 @@ pub struct Config2 {
     nat_type: i32,                          ///   NAT 类型（可能用于打洞策略）
     #[serde(default, deserialize_with = "deserialize_i32")]
     serial: i32,                            ///   配置序列号 / 版本
+    #[serde(default, deserialize_with = "deserialize_i32")]
+    server_list_serial: i32,                ///   serial of the server list, see `server_list`
     #[serde(default, deserialize_with = "deserialize_string")]
     unlock_pin: String,                     ///   解锁 PIN 码（可能是设备本地锁屏）
+    #[serde(
+        default,
+        skip_serializing_if = "String::is_empty",
+        deserialize_with = "deserialize_string"
+    )]
+    unlock_pin_hash: String,                ///   argon2id hash of unlock_pin
     #[serde(default, deserialize_with = "deserialize_string")]
     trusted_devices: String,                ///   可信设备列表（可能是序列化字符串）
+    #[serde(default, deserialize_with = "deserialize_bool")]
+    trusted_devices_endorsed: bool,         ///   trusted_devices endorsed with our key, legacy lists are endorsed once on load
 
     #[serde(default)]
     socks: Option<Socks5Server>,                ///   可选的 SOCKS5 代理配置
@@ -401,6 +515,16 @@ pub struct Config2 {
     pub options: HashMap<String, String>,           ///   其他杂项配置（键值对）
 }
 
+impl Config2 {
+    fn zeroize_secrets(&mut self) {
+        self.unlock_pin.zeroize();
+        self.unlock_pin_hash.zeroize();
+        if let Some(socks) = self.socks.as_mut() {
+            socks.zeroize_secrets();
+        }
+    }
+}
+
 
 
 ///  🧩 5. 屏幕分辨率结构体：Resolution
@@ -618,12 +742,118 @@ pub struct PeerInfoSerde {
     pub platform: String,///   远程操作系统平台（Windows/macOS/Linux）
 }
 
+///   The fields of `PeerConfig` shown in the peer grid, stored next to the peer file
+///   (`<id>.meta`), so listing thousands of peers does not parse the large options and
+///   ui_flutter maps. The peer file keeps them too, for older versions. The last
+///   connected time is the modified time of the peer file.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PeerMeta {
+    #[serde(default)]
+    pub info: PeerInfoSerde,
+    #[serde(default, deserialize_with = "deserialize_string")]
+    pub alias: String,
+}
+
 #[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
 pub struct TransferSerde {
     #[serde(default, deserialize_with = "deserialize_vec_string")]
     pub write_jobs: Vec<String>,///   当前写任务（文件传输）
     #[serde(default, deserialize_with = "deserialize_vec_string")]
     pub read_jobs: Vec<String>, ///   当前读任务
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub write_records: Vec<TransferJobRecord>, ///   checkpoints of write_jobs, see `fs::TransferJob::checkpoint`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub read_records: Vec<TransferJobRecord>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TransferFileRecord {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub modified: u64,
+    ///   bytes already transferred
+    #[serde(default)]
+    pub offset: u64,
+    ///   base64 sha256 of each `fs::RESUME_CHUNK_SIZE` chunk below offset
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunk_hashes: Vec<String>,
+    ///   hex blake3 of the whole file once it is completed
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub blake3: String,
+    ///   result of comparing `blake3` with the peer's, None if not checked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verified: Option<bool>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TransferJobRecord {
+    #[serde(default)]
+    pub id: i32,
+    #[serde(default)]
+    pub remote: String,
+    #[serde(default)]
+    pub to: String,
+    #[serde(default)]
+    pub show_hidden: bool,
+    #[serde(default)]
+    pub is_remote: bool,
+    #[serde(default)]
+    pub file_num: i32,
+    #[serde(default)]
+    pub files: Vec<TransferFileRecord>,
+    ///   `fs::JobType::Mirror`, `files` are the changed ones only
+    #[serde(default)]
+    pub is_mirror: bool,
+}
+
+impl TransferSerde {
+    ///   Legacy job strings (`fs::TransferJobMeta` json) without a record become
+    ///   records without files, which are listed again on resume.
+    pub fn migrate(&mut self) {
+        fn migrate_jobs(jobs: &mut Vec<String>, records: &mut Vec<TransferJobRecord>) {
+            for job in jobs.drain(..) {
+                let Ok(v) = serde_json::from_str::<serde_json::Value>(&job) else {
+                    continue;
+                };
+                let id = v["id"].as_i64().unwrap_or_default() as i32;
+                if records.iter().any(|r| r.id == id) {
+                    continue;
+                }
+                records.push(TransferJobRecord {
+                    id,
+                    remote: v["remote"].as_str().unwrap_or_default().to_owned(),
+                    to: v["to"].as_str().unwrap_or_default().to_owned(),
+                    show_hidden: v["show_hidden"].as_bool().unwrap_or_default(),
+                    is_remote: v["is_remote"].as_bool().unwrap_or_default(),
+                    file_num: v["file_num"].as_i64().unwrap_or_default() as i32,
+                    files: vec![],
+                    ///   legacy jobs predate the mirror jobs
+                    is_mirror: false,
+                });
+            }
+        }
+        migrate_jobs(&mut self.write_jobs, &mut self.write_records);
+        migrate_jobs(&mut self.read_jobs, &mut self.read_records);
+    }
+
+    ///   Add or replace the checkpoint of a job.
+    pub fn update_record(&mut self, record: TransferJobRecord, is_write: bool) {
+        let records = if is_write {
+            &mut self.write_records
+        } else {
+            &mut self.read_records
+        };
+        records.retain(|r| r.id != record.id);
+        records.push(record);
+    }
+
+    pub fn remove_record(&mut self, id: i32) {
+        self.write_records.retain(|r| r.id != id);
+        self.read_records.retain(|r| r.id != id);
+    }
 }
 
 
@@ -632,7 +862,19 @@ pub struct TransferSerde {
 ///  可用于判断某个对等设备是否“在线”或最近活跃。
 #[inline]
 pub fn get_online_state() -> i64 {
-    *ONLINE.lock().unwrap().values().max().unwrap_or(&0)
+    ONLINE.iter().map(|x| *x.value()).max().unwrap_or(0)
+}
+
+///   内存中当前配置的 toml 文本（未脱敏），供 diagnostics 打包前脱敏使用
+pub(crate) fn config_snapshots() -> Vec<(&'static str, String)> {
+    let config = toml::to_string_pretty(&*CONFIG.read().unwrap());
+    let config2 = toml::to_string_pretty(&*CONFIG2.read().unwrap());
+    let local = toml::to_string_pretty(&*LOCAL_CONFIG.read().unwrap());
+    vec![
+        ("config.toml", config.unwrap_or_default()),
+        ("config2.toml", config2.unwrap_or_default()),
+        ("local.toml", local.unwrap_or_default()),
+    ]
 }
 
 ///  🧩 4. 平台相关路径修正函数：patch()
@@ -647,14 +889,10 @@ fn patch(path: PathBuf) -> PathBuf {
     ///   Windows: 替换系统目录为服务账户目录
     ///   macOS: 替换 Application Support 为 Preferences
     ///   Linux: 如果是 root 用户，尝试获取当前普通用户的主目录
+    ///   BSD: the same, with the home of the user behind root from the password database
     if let Some(_tmp) = path.to_str() {
         #[cfg(windows)]
-        return _tmp
-            .replace(
-                "system32\\config\\systemprofile",
-                "ServiceProfiles\\LocalService",
-            )
-            .into();
+        return rebase_system_profile(path.clone()).unwrap_or(path);
         #[cfg(target_os = "macos")]
         return _tmp.replace("Application Support", "Preferences").into();
         #[cfg(target_os = "linux")]
@@ -671,10 +909,207 @@ fn patch(path: PathBuf) -> PathBuf {
                 }
             }
         }
+        #[cfg(any(target_os = "freebsd", target_os = "openbsd", target_os = "netbsd"))]
+        {
+            if _tmp == "/root" {
+                if let Some(home) = crate::platform::bsd::user_home() {
+                    return home;
+                }
+            }
+        }
     }
     path
 }
 
+///   The profile of SYSTEM is not meant for application data, services run as SYSTEM
+///   use the one of LocalService instead, so the config stays the same if the service
+///   is run as LocalService.
+#[cfg(windows)]
+fn rebase_system_profile(path: PathBuf) -> Option<PathBuf> {
+    if crate::platform::service_context() != crate::platform::ServiceContext::WindowsService {
+        return None;
+    }
+    let home = dirs_next::home_dir()?;
+    // the profile is <windows>\system32\config\systemprofile
+    let windows = home.parent()?.parent()?.parent()?;
+    let rest = path.strip_prefix(&home).ok()?;
+    Some(windows.join("ServiceProfiles").join("LocalService").join(rest))
+}
+
+///   The IPC sockets, in `/tmp/<APP_NAME>` as before, so the service running as root and
+///   the UI of the user derive the same path, and so do older versions. Like /tmp/.X11-unix
+///   it is world-writable and sticky, a user can not remove or replace the sockets of
+///   another. It must be owned by root or by the user, root takes it over from a user who
+///   created it first. In a sandbox, where /tmp is not the one of the host, the runtime
+///   dir of the app, created with 0700.
+#[cfg(not(any(windows, target_os = "android")))]
+fn ipc_dir() -> PathBuf {
+    use std::os::unix::{
+        fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+        io::AsRawFd,
+    };
+    let app = APP_NAME.read().unwrap().clone();
+    if let Some(dir) = &crate::platform::sandbox_info().runtime_dir {
+        let dir = dir.join(&app);
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir).ok();
+        return dir;
+    }
+    let uid = unsafe { libc::geteuid() };
+    let dir = PathBuf::from(format!("/tmp/{app}"));
+    fs::DirBuilder::new().mode(0o1777).create(&dir).ok();
+    ///   checked and fixed through the fd, the dir can not be swapped for a symlink meanwhile
+    let checked = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_DIRECTORY)
+        .open(&dir)
+        .and_then(|f| {
+            let m = f.metadata()?;
+            if m.uid() != 0 && m.uid() != uid {
+                if uid != 0 {
+                    return Ok(false);
+                }
+                if unsafe { libc::fchown(f.as_raw_fd(), 0, 0) } != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            ///   the mode is masked by the umask on creation
+            if (m.uid() == uid || uid == 0) && m.mode() & 0o7777 != 0o1777 {
+                f.set_permissions(fs::Permissions::from_mode(0o1777))?;
+            }
+            Ok(true)
+        });
+    match checked {
+        Ok(true) => dir,
+        res => {
+            log::error!("IPC dir {:?} is not owned by root or uid {}: {:?}", dir, uid, res);
+            ///   only reachable by this user
+            let dir = Config::path("ipc");
+            fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir).ok();
+            dir
+        }
+    }
+}
+
+///   Copies the files in `from` and its subdirs, e.g. `peers`, which are not in `to` yet.
+#[cfg(target_os = "ios")]
+fn copy_missing_files(from: &Path, to: &Path) {
+    if from == to {
+        return;
+    }
+    let Ok(entries) = fs::read_dir(from) else {
+        return;
+    };
+    if let Err(err) = fs::create_dir_all(to) {
+        log::error!("Failed to create {}: {}", to.display(), err);
+        return;
+    }
+    for entry in entries.flatten() {
+        let (path, target) = (entry.path(), to.join(entry.file_name()));
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            copy_missing_files(&path, &target);
+        } else if file_type.is_file() && !target.exists() {
+            if let Err(err) = fs::copy(&path, &target) {
+                log::error!("Failed to copy {} to {}: {}", path.display(), target.display(), err);
+            }
+        }
+    }
+}
+
+///   Read once into SERVICE_CONFIG_ROOT, the service context does not change.
+fn service_config_root() -> Option<PathBuf> {
+    match crate::platform::service_context() {
+        // set with ConfigurationDirectory= in the unit, the first if several
+        crate::platform::ServiceContext::SystemdUnit => std::env::var("CONFIGURATION_DIRECTORY")
+            .ok()?
+            .split(':')
+            .next()
+            .filter(|x| !x.is_empty())
+            .map(PathBuf::from),
+        _ => None,
+    }
+}
+
+///   Decrypt a sensitive field in place, returns true if it should be stored again,
+///   i.e. it is not encrypted or encrypted with an older version ("00").
+///   Storing re-encrypts it with PASSWORD_ENC_VERSION, so the migration happens on first load.
+fn decrypt_field(v: &mut String, name: &str) -> bool {
+    let (decrypted, succ, store) = decrypt_str_or_original(v, PASSWORD_ENC_VERSION);
+    if succ && store {
+        log::info!("Migrate {} to encryption version {}", name, PASSWORD_ENC_VERSION);
+    }
+    *v = decrypted;
+    store
+}
+
+pub(crate) const SECRET_PASSWORD: &str = "permanent-password";
+pub(crate) const SECRET_UNLOCK_PIN: &str = "unlock-pin";
+pub(crate) const SECRET_PRIVATE_KEY: &str = "private-key";
+
+///   The value of a secret field to store, `secrets::MARKER` if it is put into the
+///   secret store of the OS, encrypted otherwise. The marker is kept for a secret that
+///   could not be loaded, an empty value must not replace it.
+fn store_secret(v: &str, name: &str) -> String {
+    if v.is_empty() {
+        if is_secret_unavailable(name) {
+            return secrets::MARKER.to_owned();
+        }
+        secrets::remove(name);
+    } else if secrets::put(name, v.as_bytes()) {
+        UNAVAILABLE_SECRETS.write().unwrap().remove(name);
+        return secrets::MARKER.to_owned();
+    }
+    encrypt_str_or_original(v, PASSWORD_ENC_VERSION, ENCRYPT_MAX_LEN)
+}
+
+fn is_secret_unavailable(name: &str) -> bool {
+    UNAVAILABLE_SECRETS.read().unwrap().contains(name)
+}
+
+///   The secret `name` marked in the config, an error if the secret store does not
+///   have it, e.g. the Keychain is locked or the item was deleted. The secret is then
+///   empty until loaded again, and stored as the marker, see `store_secret`.
+fn load_marked_secret(name: &str) -> crate::ResultType<Vec<u8>> {
+    match secrets::load(name) {
+        Ok(v) => {
+            UNAVAILABLE_SECRETS.write().unwrap().remove(name);
+            Ok(v)
+        }
+        Err(err) => {
+            UNAVAILABLE_SECRETS.write().unwrap().insert(name.to_owned());
+            Err(err)
+        }
+    }
+}
+
+///   `decrypt_field` of a field written by `store_secret`, also true if it should
+///   be moved into the secret store.
+fn load_secret(v: &mut String, name: &str) -> bool {
+    if v == secrets::MARKER {
+        *v = match load_marked_secret(name) {
+            Ok(secret) => String::from_utf8_lossy(&secret).into_owned(),
+            Err(err) => {
+                log::error!("Failed to load secret {}: {}", name, err);
+                Default::default()
+            }
+        };
+        return false;
+    }
+    let store = decrypt_field(v, name);
+    store || (!v.is_empty() && secrets::store().is_some())
+}
+
+fn decrypt_vec_field(v: &mut Vec<u8>, name: &str) -> bool {
+    let (decrypted, succ, store) = decrypt_vec_or_original(v, PASSWORD_ENC_VERSION);
+    if succ && store {
+        log::info!("Migrate {} to encryption version {}", name, PASSWORD_ENC_VERSION);
+    }
+    *v = decrypted;
+    store
+}
+
 ///  🧩 5. Config2 的加载、保存与访问接口
 ///  ✅ 作用：提供了 Config2（补充配置，如代理、NAT 类型、解锁 PIN、功能选项等）的：
 ​​///  加载（load）​​：从磁盘读取，同时解密敏感字段
@@ -683,19 +1118,16 @@ fn patch(path: PathBuf) -> PathBuf {
 impl Config2 {
     fn load() -> Config2 {
         /* 加载并解密敏感字段，如 socks密码、unlock_pin */
+        let mut store = Config::check_integrity("2");
         let mut config = Config::load_::<Config2>("2");
-        let mut store = false;
-        if let Some(mut socks) = config.socks {
-            let (password, _, store2) =
-                decrypt_str_or_original(&socks.password, PASSWORD_ENC_VERSION);
-            socks.password = password;
-            config.socks = Some(socks);
-            store |= store2;
+        if let Some(socks) = config.socks.as_mut() {
+            store |= decrypt_field(&mut socks.password, "socks password");
+        }
+        store |= load_secret(&mut config.unlock_pin, SECRET_UNLOCK_PIN);
+        if !config.unlock_pin.is_empty() && config.unlock_pin_hash.is_empty() {
+            config.unlock_pin_hash = hash_secret(&config.unlock_pin);
+            store = true;
         }
-        let (unlock_pin, _, store2) =
-            decrypt_str_or_original(&config.unlock_pin, PASSWORD_ENC_VERSION);
-        config.unlock_pin = unlock_pin;
-        store |= store2;
         if store {
             config.store();
         }
@@ -709,15 +1141,28 @@ impl Config2 {
 
     fn store(&self) {
         /* 加密敏感字段并保存 */ 
+        if defer_in_batch(Self::file(), self, Self::store) {
+            return;
+        }
+        let hash = content_hash(self);
         let mut config = self.clone();
-        if let Some(mut socks) = config.socks {
-            socks.password =
+        if let Some(socks) = config.socks.as_mut() {
+            let password =
                 encrypt_str_or_original(&socks.password, PASSWORD_ENC_VERSION, ENCRYPT_MAX_LEN);
-            config.socks = Some(socks);
+            socks.password.zeroize();
+            socks.password = password;
         }
-        config.unlock_pin =
-            encrypt_str_or_original(&config.unlock_pin, PASSWORD_ENC_VERSION, ENCRYPT_MAX_LEN);
-        Config::store_(&config, "2");
+        let unlock_pin = store_secret(&config.unlock_pin, SECRET_UNLOCK_PIN);
+        config.unlock_pin.zeroize();
+        config.unlock_pin = unlock_pin;
+        Config::store_signed(&config, "2", hash);
+        config.zeroize_secrets();
+    }
+
+    ///   `store` without blocking the caller, see `store_deferred`.
+    fn store_deferred(&self) {
+        let config = self.clone();
+        store_deferred(Self::file(), Box::new(move || config.store()));
     }
 
     pub fn get() -> Config2 {
@@ -725,9 +1170,20 @@ impl Config2 {
         return CONFIG2.read().unwrap().clone();
     }
 
-    pub fn set(cfg: Config2) -> bool {
+    pub fn set(mut cfg: Config2) -> bool {
         /* 更新全局 Config2 并持久化 */
+        ///   argon2 is slow, hash before taking the write lock
+        let hash = if CONFIG2.read().unwrap().unlock_pin != cfg.unlock_pin {
+            Some(hash_secret(&cfg.unlock_pin))
+        } else {
+            None
+        };
         let mut lock = CONFIG2.write().unwrap();
+        if let Some(hash) = hash {
+            cfg.unlock_pin_hash = hash;
+        } else if lock.unlock_pin == cfg.unlock_pin {
+            cfg.unlock_pin_hash = lock.unlock_pin_hash.clone();
+        }
         if *lock == cfg {
             return false;
         }
@@ -748,74 +1204,782 @@ pub fn load_path<T: serde::Serialize + serde::de::DeserializeOwned + Default + s
     file: PathBuf,
 ) -> T {
     /* 基于 confy 库从文件加载任意配置结构体，出错时返回默认值 */
+    if let Some(cfg) = load_memory(&file) {
+        return cfg;
+    }
     let cfg = match confy::load_path(&file) {
         Ok(config) => config,
         Err(err) => {
-            if let confy::ConfyError::GeneralLoadError(err) = &err {
-                if err.kind() == std::io::ErrorKind::NotFound {
-                    return T::default();
+            let corrupted = match &err {
+                confy::ConfyError::BadTomlData(_) => true,
+                confy::ConfyError::GeneralLoadError(err) => {
+                    if err.kind() == std::io::ErrorKind::NotFound {
+                        return T::default();
+                    }
+                    false
                 }
-            }
+                _ => false,
+            };
             log::error!("Failed to load config '{}': {}", file.display(), err);
+            if corrupted {
+                return recover_config(&file).unwrap_or_default();
+            }
             T::default()
         }
     };
     cfg
 }
 
+///   A config file found corrupted on load, see `Config::get_recovered_configs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigRecovery {
+    pub path: String,
+    ///   where the corrupted file was moved to, None if it could not be moved
+    pub quarantined: Option<String>,
+    ///   true if restored from the backup, otherwise the defaults are used and e.g.
+    ///   the id and key pair are lost
+    pub restored: bool,
+}
+
+///   Suffix of the copy of the last stored content, see `backup_config`.
+const BACKUP_SUFFIX: &str = ".bak";
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+///   Copies `path` just stored, and signed if it is, to its backup, from which it is
+///   restored if found corrupted later, e.g. after a crash or a disk error.
+fn backup_config(path: &Path) {
+    if read_memory(path, |_| ()).is_some() {
+        return;
+    }
+    if let Err(err) = fs::copy(path, with_suffix(path, BACKUP_SUFFIX)) {
+        log::warn!("Failed to back up config '{}': {}", path.display(), err);
+    }
+}
+
+///   Moves the corrupted `file` aside and restores it from its backup, so the id and
+///   key pair are not silently replaced with new ones. None if there is no valid backup.
+fn recover_config<T: serde::de::DeserializeOwned>(file: &Path) -> Option<T> {
+    let quarantined = with_suffix(file, &format!(".corrupt-{}", crate::get_time()));
+    let quarantined = match fs::rename(file, &quarantined) {
+        Ok(_) => Some(quarantined.to_string_lossy().to_string()),
+        Err(err) => {
+            log::error!("Failed to quarantine config '{}': {}", file.display(), err);
+            None
+        }
+    };
+    let backup = with_suffix(file, BACKUP_SUFFIX);
+    ///   a backup of a signed file must pass the integrity check itself
+    let verified = match check_config_integrity(&backup) {
+        ConfigIntegrity::Ok => true,
+        ConfigIntegrity::Unsigned => !is_signed(file),
+        ConfigIntegrity::TamperDetected => false,
+    };
+    if !verified {
+        log::error!("Backup of config '{}' failed the integrity check", file.display());
+    }
+    let cfg = fs::read_to_string(&backup)
+        .ok()
+        .filter(|_| verified)
+        .and_then(|content| toml::from_str::<T>(&content).ok());
+    if cfg.is_some() {
+        // otherwise written with the next store
+        if let Err(err) = fs::copy(&backup, file) {
+            log::error!("Failed to restore config '{}': {}", file.display(), err);
+        }
+    }
+    let path = file.to_string_lossy().to_string();
+    if cfg.is_some() {
+        log::warn!("Config '{}' was corrupted, restored from backup", path);
+        events::emit(SecurityEvent::ConfigRecovered { path: path.clone() });
+    } else {
+        log::error!("Config '{}' was corrupted and no valid backup was found", path);
+        events::emit(SecurityEvent::ConfigLost { path: path.clone() });
+    }
+    RECOVERED_CONFIGS.write().unwrap().push(ConfigRecovery {
+        path,
+        quarantined,
+        restored: cfg.is_some(),
+    });
+    cfg
+}
+
+///   Moves the tampered `file` aside, and restores its backup only if it passes the
+///   integrity check itself.
+fn quarantine_tampered(file: &Path) {
+    let quarantined = with_suffix(file, &format!(".tampered-{}", crate::get_time()));
+    if let Err(err) = fs::rename(file, &quarantined) {
+        log::error!("Failed to quarantine config '{}': {}", file.display(), err);
+        // not loaded anyway
+        fs::remove_file(file).ok();
+        return;
+    }
+    let backup = with_suffix(file, BACKUP_SUFFIX);
+    if backup.exists() && check_config_integrity(&backup) == ConfigIntegrity::Ok {
+        match fs::copy(&backup, file) {
+            Ok(_) => log::warn!("Config '{}' restored from backup", file.display()),
+            Err(err) => log::error!("Failed to restore config '{}': {}", file.display(), err),
+        }
+    }
+}
+
 #[inline]
 pub fn store_path<T: serde::Serialize>(path: PathBuf, cfg: T) -> crate::ResultType<()> {
+    store_path_if_changed(path, cfg).map(|_| ())
+}
+
+///   Returns false if skipped, because the file still holds what was last written
+///   with the same content.
+fn store_path_if_changed<T: serde::Serialize>(path: PathBuf, cfg: T) -> crate::ResultType<bool> {
+    let hash = content_hash(&cfg);
+    store_path_hashed(path, cfg, hash)
+}
+
+lazy_static::lazy_static! {
+    ///   the hashes of `WRITTEN` are keyed, they may be of plaintext secrets
+    static ref CONTENT_HASH_KEY: [u8; 32] = rand::random();
+}
+
+///   Of what `cfg` is serialized to.
+fn content_hash<T: serde::Serialize>(cfg: &T) -> Option<blake3::Hash> {
+    let content = toml::to_string(cfg).ok()?;
+    Some(blake3::keyed_hash(&CONTENT_HASH_KEY, content.as_bytes()))
+}
+
+///   `store_path_if_changed` with `hash` of the content, see `content_hash`.
+fn store_path_hashed<T: serde::Serialize>(
+    path: PathBuf,
+    cfg: T,
+    hash: Option<blake3::Hash>,
+) -> crate::ResultType<bool> {
     /* 基于 confy 保存配置，Unix 下设置 0600 权限 */
+    // a newer state is written now, drop the deferred older one
+    if !IN_DEFERRED_STORE.with(|x| x.get()) {
+        PENDING_STORES.lock().unwrap().remove(&path);
+    }
+    if let Some(res) = with_memory(&path, |files| -> crate::ResultType<()> {
+        files.insert(path.clone(), (toml::to_string(&cfg)?.into_bytes(), SystemTime::now()));
+        Ok(())
+    }) {
+        return res.map(|_| true);
+    }
+    if hash.map_or(false, |hash| is_unchanged(&path, &hash)) {
+        return Ok(false);
+    }
     #[cfg(not(windows))]
     {
         use std::os::unix::fs::PermissionsExt;
-        Ok(confy::store_path_perms(
-            path,
-            cfg,
-            fs::Permissions::from_mode(0o600),
-        )?)
+        confy::store_path_perms(&path, cfg, fs::Permissions::from_mode(0o600))?;
     }
     #[cfg(windows)]
     {
-        Ok(confy::store_path(path, cfg)?)
+        confy::store_path(&path, cfg)?;
+    }
+    if let Some(hash) = hash {
+        WRITTEN.lock().unwrap().insert(path.clone(), (hash, file_stamp(&path)));
     }
+    Ok(true)
 }
 
-///  🧩 7. Config 的加载与存储（含 ID 生成与加密逻辑）
-///  ✅ 作用：Config是最核心的配置结构体之一，负责：
-///  设备唯一标识符（ID）的生成与持久化
-///  密码、密钥对、加密字段的解密 / 加密
-///  兼容性处理（比如老版本没有 enc_id 的情况）
-///  设备首次启动时生成合法 ID（循环尝试直到成功）
+lazy_static::lazy_static! {
+    ///   content hash of the last store and the (size, modified time) of the file after it
+    static ref WRITTEN: Mutex<HashMap<PathBuf, (blake3::Hash, Option<(u64, SystemTime)>)>> = Default::default();
+}
 
-impl Config {
-    fn load_<T: serde::Serialize + serde::de::DeserializeOwned + Default + std::fmt::Debug>(
-        suffix: &str,
-    ) -> T {
-        /* 加载任意配置结构体（模板方法）*/
-        let file = Self::file_(suffix);
-        let cfg = load_path(file);
-        if suffix.is_empty() {
-            log::trace!("{:?}", cfg);
-        }
-        cfg
+fn file_stamp(path: &Path) -> Option<(u64, SystemTime)> {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.len(), meta.modified().ok()?))
+}
+
+///   Not modified outside since last written with `hash`.
+fn is_unchanged(path: &Path, hash: &blake3::Hash) -> bool {
+    match WRITTEN.lock().unwrap().get(path) {
+        Some((h, Some(stamp))) => h == hash && file_stamp(path).as_ref() == Some(stamp),
+        _ => false,
     }
+}
 
-    fn store_<T: serde::Serialize>(config: &T, suffix: &str) {
-        /* 存储任意配置结构体 */
-        let file = Self::file_(suffix);
-        if let Err(err) = store_path(file, config) {
-            log::error!("Failed to store {suffix} config: {err}");
+///   After changing a file written by `store_path` ourselves, e.g. by signing it.
+fn refresh_written(path: &Path) {
+    if let Some(entry) = WRITTEN.lock().unwrap().get_mut(path) {
+        entry.1 = file_stamp(path);
+    }
+}
+
+type MemoryFiles = HashMap<PathBuf, (Vec<u8>, SystemTime)>;
+
+lazy_static::lazy_static! {
+    ///   config files kept in memory instead of on disk, see `TestConfig`
+    static ref MEMORY_BACKEND: RwLock<Option<MemoryFiles>> = Default::default();
+}
+
+///   Whether `MEMORY_BACKEND` is set, so the loads and stores outside of tests do not
+///   take its lock.
+static MEMORY_IN_USE: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+#[inline]
+fn memory_in_use(path: &Path) -> bool {
+    MEMORY_IN_USE.load(std::sync::atomic::Ordering::Acquire) && path.starts_with(Config::path(""))
+}
+
+///   None if the memory backend is not in use, or `path` is not in the config dir,
+///   e.g. a temp file of a test, the files are on disk.
+fn with_memory<R>(path: &Path, f: impl FnOnce(&mut MemoryFiles) -> R) -> Option<R> {
+    if !memory_in_use(path) {
+        return None;
+    }
+    MEMORY_BACKEND.write().unwrap().as_mut().map(f)
+}
+
+///   `with_memory` for reading, the loads of tests do not wait for each other.
+fn read_memory<R>(path: &Path, f: impl FnOnce(&MemoryFiles) -> R) -> Option<R> {
+    if !memory_in_use(path) {
+        return None;
+    }
+    MEMORY_BACKEND.read().unwrap().as_ref().map(f)
+}
+
+///   `fs::read`, from memory if in use.
+fn read_bytes(path: &Path) -> std::io::Result<Vec<u8>> {
+    match read_memory(path, |files| files.get(path).map(|x| x.0.clone())) {
+        Some(Some(data)) => Ok(data),
+        Some(None) => Err(std::io::ErrorKind::NotFound.into()),
+        None => fs::read(path),
+    }
+}
+
+///   The default if missing or invalid, None if the memory backend is not in use.
+fn load_memory<T: serde::de::DeserializeOwned + Default>(path: &Path) -> Option<T> {
+    let data = read_memory(path, |files| files.get(path).map(|x| x.0.clone()))?;
+    let Some(data) = data else {
+        return Some(T::default());
+    };
+    match toml::from_str(&String::from_utf8_lossy(&data)) {
+        Ok(cfg) => Some(cfg),
+        Err(err) => {
+            log::error!("Failed to load config '{}': {}", path.display(), err);
+            Some(T::default())
         }
     }
+}
 
-    fn load() -> Config {
-        /* 加载 Config，解密字段如 password, enc_id，必要时生成新设备 ID */
-        let mut config = Config::load_::<Config>("");
-        let mut store = false;
-        let (password, _, store1) = decrypt_str_or_original(&config.password, PASSWORD_ENC_VERSION);
-        config.password = password;
-        store |= store1;
+///   The files directly in `dir` with their modified time, None if on disk.
+fn memory_files_in(dir: &Path) -> Option<Vec<(PathBuf, SystemTime)>> {
+    read_memory(dir, |files| {
+        files
+            .iter()
+            .filter(|(p, _)| p.parent() == Some(dir))
+            .map(|(p, x)| (p.clone(), x.1))
+            .collect()
+    })
+}
+
+#[cfg(any(test, feature = "test-config"))]
+lazy_static::lazy_static! {
+    static ref TEST_CONFIG_LOCK: Mutex<()> = Default::default();
+}
+
+///   Keeps the config files in memory and resets the globals while it lives, so tests
+///   do not touch the real config or see each other's changes. Tests holding it run
+///   one at a time, as the globals are shared by the process.
+///   Only for tests, and with the `test-config` feature for those of the dependents.
+#[cfg(any(test, feature = "test-config"))]
+pub struct TestConfig {
+    _lock: std::sync::MutexGuard<'static, ()>,
+}
+
+#[cfg(any(test, feature = "test-config"))]
+pub fn test_config() -> TestConfig {
+    // a failed test must not block the others
+    let lock = TEST_CONFIG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    *MEMORY_BACKEND.write().unwrap() = Some(Default::default());
+    MEMORY_IN_USE.store(true, std::sync::atomic::Ordering::Release);
+    reset();
+    TestConfig { _lock: lock }
+}
+
+#[cfg(any(test, feature = "test-config"))]
+impl TestConfig {
+    ///   Sets a file, e.g. a config written by an older version.
+    pub fn set_file(&self, path: &Path, data: &[u8]) {
+        with_memory(path, |files| files.insert(path.to_owned(), (data.to_vec(), SystemTime::now())));
+    }
+
+    pub fn file(&self, path: &Path) -> Option<Vec<u8>> {
+        read_memory(path, |files| files.get(path).map(|x| x.0.clone())).flatten()
+    }
+}
+
+#[cfg(any(test, feature = "test-config"))]
+impl Drop for TestConfig {
+    fn drop(&mut self) {
+        PENDING_STORES.lock().unwrap().clear();
+        MEMORY_IN_USE.store(false, std::sync::atomic::Ordering::Release);
+        *MEMORY_BACKEND.write().unwrap() = None;
+        reset();
+    }
+}
+
+///   Reloads the global configs from their files and clears the caches and settings
+///   set at runtime, as on a fresh start. Only for tests, see `TestConfig`.
+#[cfg(any(test, feature = "test-config"))]
+pub fn reset() {
+    PENDING_STORES.lock().unwrap().clear();
+    WRITTEN.lock().unwrap().clear();
+    ONLINE.clear();
+    *KEY_PAIR.lock().unwrap() = None;
+    *KEY_UUID.lock().unwrap() = None;
+    *INTEGRITY.lock().unwrap() = None;
+    TAMPERED_CONFIGS.write().unwrap().clear();
+    RECOVERED_CONFIGS.write().unwrap().clear();
+    UNAVAILABLE_SECRETS.write().unwrap().clear();
+    NEW_STORED_PEER_CONFIG.lock().unwrap().clear();
+    ACCOUNT_USER_KEYS.lock().unwrap().clear();
+    *TRUSTED_DEVICES.write().unwrap() = Default::default();
+    for settings in [
+        &*DEFAULT_SETTINGS,
+        &*OVERWRITE_SETTINGS,
+        &*DEFAULT_DISPLAY_SETTINGS,
+        &*OVERWRITE_DISPLAY_SETTINGS,
+        &*DEFAULT_LOCAL_SETTINGS,
+        &*OVERWRITE_LOCAL_SETTINGS,
+        &*BUILTIN_SETTINGS,
+    ] {
+        settings.clear();
+    }
+    // loaded before taking the locks, loading may read the other configs
+    let config = Config::load();
+    *CONFIG.write().unwrap() = config;
+    let config2 = Config2::load();
+    *CONFIG2.write().unwrap() = config2;
+    let local = LocalConfig::load();
+    *LOCAL_CONFIG.write().unwrap() = local;
+    let status = Status::load();
+    *STATUS.write().unwrap() = status;
+    let tokens = TokenStore::load();
+    *TOKEN_STORE.write().unwrap() = tokens;
+    let session_keys = SessionKeyCache::load();
+    *SESSION_KEY_CACHE.write().unwrap() = session_keys;
+    let user_default = UserDefaultConfig::load();
+    *USER_DEFAULT_CONFIG.write().unwrap() = (user_default, Instant::now());
+}
+
+static STORE_BATCH_DEPTH: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+pub struct StoreBatch(());
+
+///   Stores of `Config`, `Config2` and `LocalConfig` while the returned guard lives are
+///   written once when it is dropped, e.g. around the setters of a first-run initialization.
+pub fn batch_stores() -> StoreBatch {
+    STORE_BATCH_DEPTH.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    StoreBatch(())
+}
+
+impl Drop for StoreBatch {
+    fn drop(&mut self) {
+        if STORE_BATCH_DEPTH.fetch_sub(1, std::sync::atomic::Ordering::SeqCst) == 1 {
+            flush_pending_stores();
+        }
+    }
+}
+
+///   Queues `store` of `cfg` if in a batch, the last one of a file wins.
+fn defer_in_batch<T: Clone + Send + 'static>(path: PathBuf, cfg: &T, store: fn(&T)) -> bool {
+    if STORE_BATCH_DEPTH.load(std::sync::atomic::Ordering::SeqCst) == 0 {
+        return false;
+    }
+    let cfg = cfg.clone();
+    PENDING_STORES
+        .lock()
+        .unwrap()
+        .insert(path, Box::new(move || store(&cfg)));
+    true
+}
+
+///   Successive deferred stores of a file within this delay are written once.
+const STORE_COALESCE: Duration = Duration::from_millis(200);
+
+lazy_static::lazy_static! {
+    ///   latest deferred store per file
+    static ref PENDING_STORES: Mutex<HashMap<PathBuf, Box<dyn FnOnce() + Send>>> = Default::default();
+}
+
+thread_local! {
+    static IN_DEFERRED_STORE: std::cell::Cell<bool> = std::cell::Cell::new(false);
+}
+
+///   Like `store_path`, with serializing and fsync on the blocking pool instead of
+///   the runtime thread.
+pub async fn store_path_async<T: serde::Serialize + Send + 'static>(
+    path: PathBuf,
+    cfg: T,
+) -> crate::ResultType<()> {
+    tokio::task::spawn_blocking(move || store_path(path, cfg)).await?
+}
+
+///   Runs `job` writing `path` on the blocking pool after `STORE_COALESCE`, a later
+///   job of the same file replaces it. Runs it now outside of a tokio runtime.
+fn store_deferred(path: PathBuf, job: Box<dyn FnOnce() + Send>) {
+    let Ok(handle) = tokio::runtime::Handle::try_current() else {
+        job();
+        return;
+    };
+    if PENDING_STORES.lock().unwrap().insert(path.clone(), job).is_some() {
+        return;
+    }
+    handle.spawn(async move {
+        crate::clock::sleep(STORE_COALESCE).await;
+        let job = PENDING_STORES.lock().unwrap().remove(&path);
+        if let Some(job) = job {
+            tokio::task::spawn_blocking(move || {
+                IN_DEFERRED_STORE.with(|x| x.set(true));
+                job();
+                IN_DEFERRED_STORE.with(|x| x.set(false));
+            })
+            .await
+            .ok();
+        }
+    });
+}
+
+///   Writes deferred stores now, e.g. before exiting.
+pub fn flush_pending_stores() {
+    let jobs: Vec<_> = PENDING_STORES.lock().unwrap().drain().collect();
+    for (_, job) in jobs {
+        job();
+    }
+}
+
+const HMAC_LINE_PREFIX: &str = "# hmac: ";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigIntegrity {
+    Ok,
+    ///   no hmac yet, written by an older version, it is signed on next store
+    Unsigned,
+    ///   the file was modified outside of the application, or its hmac removed
+    TamperDetected,
+}
+
+pub(crate) const SECRET_CONFIG_INTEGRITY: &str = "config-integrity";
+
+///   The random key of the hmac and the files signed with it, so a file signed once
+///   and found without hmac was tampered with, not written by an older version.
+///   Kept by the secret store, or without one in `<APP_NAME>_integrity` beside the
+///   config, readable by the owner only like the config.
+///   key (32 bytes) | signed file paths, '\n' separated
+#[derive(Default)]
+struct IntegrityState {
+    key: Option<hmacsha256::Key>,
+    signed: HashSet<String>,
+}
+
+lazy_static::lazy_static! {
+    static ref INTEGRITY: Mutex<Option<IntegrityState>> = Default::default();
+}
+
+fn integrity_file() -> PathBuf {
+    Config::path(format!("{}_integrity", *APP_NAME.read().unwrap()))
+}
+
+impl IntegrityState {
+    fn load() -> Self {
+        let data = secrets::get(SECRET_CONFIG_INTEGRITY)
+            .or_else(|| read_bytes(&integrity_file()).ok())
+            .unwrap_or_default();
+        if data.len() < hmacsha256::KEYBYTES {
+            return Self::default();
+        }
+        let (key, signed) = data.split_at(hmacsha256::KEYBYTES);
+        Self {
+            key: hmacsha256::Key::from_slice(key),
+            signed: String::from_utf8_lossy(signed)
+                .split('\n')
+                .filter(|x| !x.is_empty())
+                .map(|x| x.to_owned())
+                .collect(),
+        }
+    }
+
+    fn store(&self) {
+        let Some(key) = self.key.as_ref() else {
+            return;
+        };
+        let mut data = Zeroizing::new(key.0.to_vec());
+        for path in self.signed.iter() {
+            data.extend_from_slice(path.as_bytes());
+            data.push(b'\n');
+        }
+        if secrets::put(SECRET_CONFIG_INTEGRITY, &data) {
+            return;
+        }
+        if let Err(err) = store_bytes(integrity_file(), &data) {
+            log::error!("Failed to store the config integrity key: {}", err);
+        }
+    }
+}
+
+fn with_integrity<R>(f: impl FnOnce(&mut IntegrityState) -> R) -> R {
+    let mut lock = INTEGRITY.lock().unwrap();
+    f(lock.get_or_insert_with(IntegrityState::load))
+}
+
+///   Created with the first file signed, not derived from the machine uuid which is
+///   sent to the rendezvous server.
+fn config_hmac_key() -> hmacsha256::Key {
+    with_integrity(|state| {
+        if state.key.is_none() {
+            state.key = Some(hmacsha256::gen_key());
+            state.store();
+        }
+        state.key.clone().unwrap_or_else(hmacsha256::gen_key)
+    })
+}
+
+fn config_hmac(content: &str) -> String {
+    let tag = hmacsha256::authenticate(content.as_bytes(), &config_hmac_key());
+    base64::encode(tag.0, base64::Variant::Original)
+}
+
+fn mark_signed(path: &Path) {
+    let path = path.to_string_lossy().to_string();
+    with_integrity(|state| {
+        if state.signed.insert(path) {
+            state.store();
+        }
+    });
+}
+
+fn is_signed(path: &Path) -> bool {
+    let path = path.to_string_lossy().to_string();
+    with_integrity(|state| state.signed.contains(&path))
+}
+
+///   Split the trailing hmac line off the file content.
+fn split_hmac_line(content: &str) -> (&str, Option<&str>) {
+    let trimmed = content.trim_end_matches('\n');
+    let pos = trimmed.rfind('\n').map_or(0, |p| p + 1);
+    match trimmed[pos..].strip_prefix(HMAC_LINE_PREFIX) {
+        Some(tag) => (&content[..pos], Some(tag)),
+        None => (content, None),
+    }
+}
+
+///   Append the hmac as a toml comment, so older versions can still read the file.
+pub fn sign_config_file(path: &Path) -> crate::ResultType<()> {
+    // nothing on disk to protect
+    if read_memory(path, |_| ()).is_some() {
+        return Ok(());
+    }
+    let content = fs::read_to_string(path)?;
+    let (content, _) = split_hmac_line(&content);
+    let mut content = content.to_owned();
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+    let tag = config_hmac(&content);
+    content.push_str(HMAC_LINE_PREFIX);
+    content.push_str(&tag);
+    content.push('\n');
+    fs::write(path, content)?;
+    mark_signed(path);
+    Ok(())
+}
+
+pub fn check_config_integrity(path: &Path) -> ConfigIntegrity {
+    let Ok(content) = fs::read_to_string(path) else {
+        return ConfigIntegrity::Ok;
+    };
+    match split_hmac_line(&content) {
+        (_, None) if is_signed(path) => ConfigIntegrity::TamperDetected,
+        (_, None) => ConfigIntegrity::Unsigned,
+        (content, Some(tag)) => {
+            let Ok(tag) = base64::decode(tag, base64::Variant::Original) else {
+                return ConfigIntegrity::TamperDetected;
+            };
+            let Some(tag) = hmacsha256::Tag::from_slice(&tag) else {
+                return ConfigIntegrity::TamperDetected;
+            };
+            if hmacsha256::verify(&tag, content.as_bytes(), &config_hmac_key()) {
+                ConfigIntegrity::Ok
+            } else {
+                ConfigIntegrity::TamperDetected
+            }
+        }
+    }
+}
+
+pub(crate) fn store_bytes(path: PathBuf, data: &[u8]) -> crate::ResultType<()> {
+    if with_memory(&path, |files| files.insert(path.clone(), (data.to_vec(), SystemTime::now()))).is_some() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::File::create(&path)?;
+    #[cfg(not(windows))]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(data)?;
+    Ok(())
+}
+
+///   CBOR instead of TOML for peer files, much faster to decode with thousands of peers.
+///   Older versions can not read them, so the TOML files are only removed once the
+///   binary one is written.
+#[cfg(feature = "binary-config")]
+const BINARY_EXT: &str = "cbor";
+
+#[cfg(feature = "binary-config")]
+fn load_binary<T: serde::de::DeserializeOwned>(path: &Path) -> Option<T> {
+    let data = read_bytes(path).ok()?;
+    match ciborium::de::from_reader(&data[..]) {
+        Ok(cfg) => Some(cfg),
+        Err(err) => {
+            log::error!("Failed to load config '{}': {}", path.display(), err);
+            None
+        }
+    }
+}
+
+#[cfg(feature = "binary-config")]
+fn store_binary<T: serde::Serialize>(path: PathBuf, cfg: &T) -> crate::ResultType<()> {
+    let mut data = Vec::new();
+    ciborium::ser::into_writer(cfg, &mut data)?;
+    store_bytes(path, &data)
+}
+
+///  🧩 7. Config 的加载与存储（含 ID 生成与加密逻辑）
+///  ✅ 作用：Config是最核心的配置结构体之一，负责：
+///  设备唯一标识符（ID）的生成与持久化
+///  密码、密钥对、加密字段的解密 / 加密
+///  兼容性处理（比如老版本没有 enc_id 的情况）
+///  设备首次启动时生成合法 ID（循环尝试直到成功）
+
+impl Config {
+    fn load_<T: serde::Serialize + serde::de::DeserializeOwned + Default + std::fmt::Debug>(
+        suffix: &str,
+    ) -> T {
+        /* 加载任意配置结构体（模板方法）*/
+        let file = Self::file_(suffix);
+        let cfg = load_path(file);
+        if suffix.is_empty() {
+            log::trace!("{:?}", cfg);
+        }
+        cfg
+    }
+
+    ///   Returns false if not written, see `store_path_if_changed`.
+    fn store_<T: serde::Serialize>(config: &T, suffix: &str) -> bool {
+        /* 存储任意配置结构体 */
+        Self::store_hashed(config, suffix, content_hash(config))
+    }
+
+    ///   `store_` with `hash` of the content, see `content_hash`, of the plaintext if
+    ///   `config` holds encrypted secrets, their ciphertext differs on every store.
+    fn store_hashed<T: serde::Serialize>(
+        config: &T,
+        suffix: &str,
+        hash: Option<blake3::Hash>,
+    ) -> bool {
+        let written = Self::write_hashed(config, suffix, hash);
+        if written {
+            backup_config(&Self::file_(suffix));
+        }
+        written
+    }
+
+    ///   `store_hashed` without the backup.
+    fn write_hashed<T: serde::Serialize>(
+        config: &T,
+        suffix: &str,
+        hash: Option<blake3::Hash>,
+    ) -> bool {
+        match store_path_hashed(Self::file_(suffix), config, hash) {
+            Ok(written) => written,
+            Err(err) => {
+                log::error!("Failed to store {suffix} config: {err}");
+                false
+            }
+        }
+    }
+
+    ///   To be called before loading the file. A tampered file is not loaded: it is
+    ///   moved aside and restored from its backup if that is intact, otherwise the
+    ///   defaults are used. Returns true if the file should be stored (signed) again.
+    fn check_integrity(suffix: &str) -> bool {
+        let file = Self::file_(suffix);
+        match check_config_integrity(&file) {
+            ConfigIntegrity::Ok => false,
+            ConfigIntegrity::Unsigned => true,
+            ConfigIntegrity::TamperDetected => {
+                log::error!(
+                    "Tamper detected, config '{}' was modified outside of the application",
+                    file.display()
+                );
+                quarantine_tampered(&file);
+                TAMPERED_CONFIGS
+                    .write()
+                    .unwrap()
+                    .insert(file.to_string_lossy().to_string());
+                events::emit(SecurityEvent::ConfigTampered {
+                    path: file.to_string_lossy().to_string(),
+                });
+                false
+            }
+        }
+    }
+
+    ///   Config files that failed the integrity check on load, distinct from parse errors.
+    pub fn get_tampered_configs() -> Vec<String> {
+        TAMPERED_CONFIGS.read().unwrap().iter().cloned().collect()
+    }
+
+    ///   Config files found corrupted on load since start, whether restored from their
+    ///   backup or lost, to be shown to the user.
+    pub fn get_recovered_configs() -> Vec<ConfigRecovery> {
+        RECOVERED_CONFIGS.read().unwrap().clone()
+    }
+
+    fn store_signed<T: serde::Serialize>(config: &T, suffix: &str, hash: Option<blake3::Hash>) {
+        if !Self::write_hashed(config, suffix, hash) {
+            return;
+        }
+        let file = Self::file_(suffix);
+        if let Err(err) = sign_config_file(&file) {
+            log::error!("Failed to sign {suffix} config: {err}");
+        } else {
+            refresh_written(&file);
+            TAMPERED_CONFIGS
+                .write()
+                .unwrap()
+                .remove(&file.to_string_lossy().to_string());
+            ///   after signing, so the backup passes the integrity check on restore
+            backup_config(&file);
+        }
+    }
+
+    fn load() -> Config {
+        /* 加载 Config，解密字段如 password, enc_id，必要时生成新设备 ID */
+        let mut store = Config::check_integrity("");
+        let mut config = Config::load_::<Config>("");
+        store |= load_secret(&mut config.password, SECRET_PASSWORD);
+        store |= config.load_private_key();
+        if !config.password.is_empty() && config.password_hash.is_empty() {
+            config.password_hash = hash_secret(&config.password);
+            store = true;
+        }
+        if !config.password.is_empty() && config.password_time == 0 {
+            ///   set by an older version, the expiry runs from now on
+            config.password_time = crate::get_time();
+            store = true;
+        }
         let mut id_valid = false;
         let (id, encrypted, store2) = decrypt_str_or_original(&config.enc_id, PASSWORD_ENC_VERSION);
         if encrypted {
@@ -847,6 +2011,14 @@ impl Config {
                 }
             }
         }
+        if config.old_key_pair.is_some()
+            && crate::get_time() - config.key_rollover_time > KEY_ROLLOVER_GRACE
+        {
+            log::info!("Key rollover grace period is over, drop the old key pair");
+            config.old_key_pair = None;
+            config.key_rollover.clear();
+            store = true;
+        }
         if store {
             config.store();
         }
@@ -854,18 +2026,50 @@ impl Config {
     }
 
     fn store(&self) {
+        if defer_in_batch(Self::file(), self, Self::store) {
+            return;
+        }
+        let hash = content_hash(self);
         let mut config = self.clone();
-        config.password =
-            encrypt_str_or_original(&config.password, PASSWORD_ENC_VERSION, ENCRYPT_MAX_LEN);
+        let password = store_secret(&config.password, SECRET_PASSWORD);
+        config.password.zeroize();
+        config.password = password;
+        if !config.key_pair.0.is_empty() && secrets::put(SECRET_PRIVATE_KEY, &config.key_pair.0) {
+            UNAVAILABLE_SECRETS.write().unwrap().remove(SECRET_PRIVATE_KEY);
+            config.key_pair.0.zeroize();
+            config.key_pair.0 = secrets::MARKER.as_bytes().to_vec();
+        } else if config.key_pair.0.is_empty() && is_secret_unavailable(SECRET_PRIVATE_KEY) {
+            config.key_pair.0 = secrets::MARKER.as_bytes().to_vec();
+        }
         config.enc_id = encrypt_str_or_original(&config.id, PASSWORD_ENC_VERSION, ENCRYPT_MAX_LEN);
         config.id = "".to_owned();
-        Config::store_(&config, "");
+        Config::store_signed(&config, "", hash);
+        config.zeroize_secrets();
     }
 
     pub fn file() -> PathBuf {
         Self::file_("")
     }
 
+    ///   The private key from the secret store if written by `store` there, true if it
+    ///   should be moved into the store.
+    fn load_private_key(&mut self) -> bool {
+        if self.key_pair.0 != secrets::MARKER.as_bytes() {
+            return !self.key_pair.0.is_empty() && secrets::store().is_some();
+        }
+        self.key_pair.0 = load_marked_secret(SECRET_PRIVATE_KEY).unwrap_or_else(|err| {
+            log::error!("Failed to load the private key: {}", err);
+            Default::default()
+        });
+        false
+    }
+
+    ///   Secrets marked in the config but not loaded from the secret store, they are
+    ///   kept there and empty until loaded, e.g. after the Keychain is unlocked.
+    pub fn get_unavailable_secrets() -> Vec<String> {
+        UNAVAILABLE_SECRETS.read().unwrap().iter().cloned().collect()
+    }
+
     fn file_(suffix: &str) -> PathBuf {
         let name = format!("{}{}", *APP_NAME.read().unwrap(), suffix);
         Config::with_extension(Self::path(name))
@@ -890,7 +2094,50 @@ impl Config {
         }
     }
 
+    ///   Stores the config in `root` instead of the default location of the platform,
+    ///   e.g. for a service with its own state directory. To be called at startup,
+    ///   before the config is first accessed.
+    pub fn set_config_root(root: PathBuf) {
+        *CONFIG_ROOT.write().unwrap() = Some(root);
+    }
+
+    ///   The root set with `set_config_root`, or declared by the service manager, e.g.
+    ///   with ConfigurationDirectory= of a systemd unit. None for the default location.
+    pub fn config_root() -> Option<PathBuf> {
+        CONFIG_ROOT
+            .read()
+            .unwrap()
+            .clone()
+            .or_else(|| SERVICE_CONFIG_ROOT.clone())
+    }
+
+    ///   iOS: stores the config in the container of an app group, e.g. from
+    ///   `containerURLForSecurityApplicationGroupIdentifier`, so the app and its broadcast
+    ///   upload extension have the same id and settings. The files of the app dir are
+    ///   copied there the first time. To be called before the config is first accessed.
+    #[cfg(target_os = "ios")]
+    pub fn set_app_group_dir(dir: &str) {
+        let old = Self::path("");
+        *APP_GROUP_DIR.write().unwrap() = dir.to_owned();
+        copy_missing_files(&old, &Self::path(""));
+    }
+
     pub fn path<P: AsRef<Path>>(p: P) -> PathBuf {
+        ///   derived from APP_NAME, which can not change after, see `Branding::apply`
+        crate::branding::seal();
+        if let Some(mut path) = Self::config_root() {
+            path.push(p);
+            return path;
+        }
+        #[cfg(target_os = "ios")]
+        {
+            let group = APP_GROUP_DIR.read().unwrap().clone();
+            if !group.is_empty() {
+                let mut path: PathBuf = group.into();
+                path.push(p);
+                return path;
+            }
+        }
         #[cfg(any(target_os = "android", target_os = "ios"))]
         {
             let mut path: PathBuf = APP_DIR.read().unwrap().clone().into();
@@ -904,6 +2151,8 @@ impl Config {
             #[cfg(target_os = "macos")]
             let org = ORG.read().unwrap().clone();
             ///   /var/root for root
+            ///   Flatpak/Snap set XDG_CONFIG_HOME/HOME to the writable dirs of the app, the
+            ///   same as `platform::sandbox_info().config_dir`
             if let Some(project) =
                 directories_next::ProjectDirs::from("", &org, &APP_NAME.read().unwrap())
             {
@@ -924,8 +2173,19 @@ impl Config {
                 return path.clone();
             }
         }
-        #[cfg(target_os = "linux")]
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "freebsd",
+            target_os = "openbsd",
+            target_os = "netbsd"
+        ))]
         {
+            ///   Flatpak/Snap: the home of the host is read-only or per revision
+            if let Some(dir) = &crate::platform::sandbox_info().data_dir {
+                let path = dir.join("logs").join(&*APP_NAME.read().unwrap());
+                std::fs::create_dir_all(&path).ok();
+                return path;
+            }
             let mut path = Self::get_home();
             path.push(format!(".local/share/logs/{}", *APP_NAME.read().unwrap()));
             std::fs::create_dir_all(&path).ok();
@@ -958,19 +2218,22 @@ impl Config {
                 postfix
             )
         }
-        #[cfg(not(windows))]
+        #[cfg(target_os = "android")]
         {
             use std::os::unix::fs::PermissionsExt;
-            #[cfg(target_os = "android")]
             let mut path: PathBuf =
                 format!("{}/{}", *APP_DIR.read().unwrap(), *APP_NAME.read().unwrap()).into();
-            #[cfg(not(target_os = "android"))]
-            let mut path: PathBuf = format!("/tmp/{}", *APP_NAME.read().unwrap()).into();
             fs::create_dir(&path).ok();
             fs::set_permissions(&path, fs::Permissions::from_mode(0o0777)).ok();
             path.push(format!("ipc{postfix}"));
             path.to_str().unwrap_or("").to_owned()
         }
+        #[cfg(not(any(windows, target_os = "android")))]
+        {
+            let mut path = ipc_dir();
+            path.push(format!("ipc{postfix}"));
+            path.to_str().unwrap_or("").to_owned()
+        }
     }
 
     pub fn icon_path() -> PathBuf {
@@ -1002,15 +2265,12 @@ impl Config {
             rendezvous_server = CONFIG2.read().unwrap().rendezvous_server.clone();
         }
         if rendezvous_server.is_empty() {
-            rendezvous_server = Self::get_rendezvous_servers()
-                .drain(..)
-                .next()
-                .unwrap_or_default();
-        }
-        if !rendezvous_server.contains(':') {
-            rendezvous_server = format!("{rendezvous_server}:{RENDEZVOUS_PORT}");
+            ///   not measured yet, e.g. the first run
+            rendezvous_server =
+                crate::server_selector::nearest(&Self::get_rendezvous_servers())
+                    .unwrap_or_default();
         }
-        rendezvous_server
+        crate::socket_client::check_port(rendezvous_server, crate::branding::rendezvous_port())
     }
 
     pub fn get_rendezvous_servers() -> Vec<String> {
@@ -1026,43 +2286,65 @@ impl Config {
         if !s.is_empty() {
             return vec![s];
         }
-        let serial_obsolute = CONFIG2.read().unwrap().serial > SERIAL;
+        let serial_obsolute = {
+            let config = CONFIG2.read().unwrap();
+            config.serial > SERIAL || config.server_list_serial > 0
+        };
         if serial_obsolute {
             let ss: Vec<String> = Self::get_option("rendezvous-servers")
                 .split(',')
-                .filter(|x| x.contains('.'))
+                .filter(|x| x.contains('.') || crate::is_ipv6_str(x))
                 .map(|x| x.to_owned())
                 .collect();
             if !ss.is_empty() {
                 return ss;
             }
         }
-        return RENDEZVOUS_SERVERS.iter().map(|x| x.to_string()).collect();
+        return crate::branding::rendezvous_servers();
     }
 
     pub fn reset_online() {
-        *ONLINE.lock().unwrap() = Default::default();
+        ONLINE.clear();
     }
 
     pub fn update_latency(host: &str, latency: i64) {
-        ONLINE.lock().unwrap().insert(host.to_owned(), latency);
-        let mut host = "".to_owned();
-        let mut delay = i64::MAX;
-        for (tmp_host, tmp_delay) in ONLINE.lock().unwrap().iter() {
-            if tmp_delay > &0 && tmp_delay < &delay {
-                delay = *tmp_delay;
-                host = tmp_host.to_string();
-            }
+        if latency > 0 {
+            crate::metrics::RENDEZVOUS_LATENCY.observe(latency as f64);
         }
-        if !host.is_empty() {
+        ONLINE.insert(host.to_owned(), latency);
+        let Some(host) = ONLINE
+            .iter()
+            .filter(|x| *x.value() > 0)
+            .min_by_key(|x| *x.value())
+            .map(|x| x.key().clone())
+        else {
+            return;
+        };
+        if host == CONFIG2.read().unwrap().rendezvous_server {
+            return;
+        }
+        // store without holding the lock
+        let config = {
             let mut config = CONFIG2.write().unwrap();
-            if host != config.rendezvous_server {
-                log::debug!("Update rendezvous_server in config to {}", host);
-                log::debug!("{:?}", *ONLINE.lock().unwrap());
-                config.rendezvous_server = host;
-                config.store();
+            if host == config.rendezvous_server {
+                return;
             }
-        }
+            log::debug!("Update rendezvous_server in config to {}", host);
+            log::debug!("{:?}", *ONLINE);
+            config.rendezvous_server = host;
+            config.clone()
+        };
+        config.store_deferred();
+    }
+
+    ///   各 rendezvous 服务器最近一次测得的延迟（毫秒），未连通为 -1 或 0
+    pub fn get_latencies() -> Vec<(String, i64)> {
+        let mut latencies: Vec<_> = ONLINE
+            .iter()
+            .map(|x| (x.key().clone(), *x.value()))
+            .collect();
+        latencies.sort();
+        latencies
     }
 
     pub fn set_id(id: &str) {
@@ -1081,6 +2363,14 @@ impl Config {
         }
         config.nat_type = nat_type;
         config.store();
+        // telemetry reads the options
+        drop(config);
+        use crate::{protobuf::Enum, rendezvous_proto::NatType};
+        crate::telemetry::record(crate::telemetry::Event::NatType {
+            nat_type: NatType::from_i32(nat_type)
+                .map(|x| format!("{:?}", x))
+                .unwrap_or_default(),
+        });
     }
 
     pub fn get_nat_type() -> i32 {
@@ -1100,30 +2390,35 @@ impl Config {
         std::cmp::max(CONFIG2.read().unwrap().serial, SERIAL)
     }
 
-    #[cfg(any(target_os = "android", target_os = "ios"))]
-    fn gen_id() -> Option<String> {
-        Self::get_auto_id()
+    ///   Kept apart from `get_serial`, which the rendezvous server updates.
+    pub fn set_server_list_serial(serial: i32) {
+        let mut config = CONFIG2.write().unwrap();
+        if serial == config.server_list_serial {
+            return;
+        }
+        config.server_list_serial = serial;
+        config.store();
     }
 
-    #[cfg(not(any(target_os = "android", target_os = "ios")))]
-    fn gen_id() -> Option<String> {
-        let hostname_as_id = BUILTIN_SETTINGS
-            .read()
-            .unwrap()
-            .get(keys::OPTION_ALLOW_HOSTNAME_AS_ID)
-            .map(|v| option2bool(keys::OPTION_ALLOW_HOSTNAME_AS_ID, v))
-            .unwrap_or(false);
-        if hostname_as_id {
-            match whoami::fallible::hostname() {
-                Ok(h) => Some(h.replace(" ", "-")),
-                Err(e) => {
-                    log::warn!("Failed to get hostname, \"{}\", fallback to auto id", e);
-                    Self::get_auto_id()
-                }
-            }
-        } else {
-            Self::get_auto_id()
+    pub fn get_server_list_serial() -> i32 {
+        CONFIG2.read().unwrap().server_list_serial
+    }
+
+    ///   Forgets the server in use and the latencies, e.g. when the servers changed, so the
+    ///   nearest of the new ones is chosen.
+    pub fn reset_rendezvous_server() {
+        Self::reset_online();
+        let mut config = CONFIG2.write().unwrap();
+        if config.rendezvous_server.is_empty() {
+            return;
         }
+        config.rendezvous_server = "".to_owned();
+        config.store();
+    }
+
+    ///   The hostname is never the main id, see `get_register_ids`.
+    fn gen_id() -> Option<String> {
+        Self::get_auto_id()
     }
 
     fn get_auto_id() -> Option<String> {
@@ -1136,18 +2431,10 @@ impl Config {
             );
         }
 
+        ///   from the UUID of the platform, the MAC address only without, see `machine_id`
         #[cfg(not(any(target_os = "android", target_os = "ios")))]
         {
-            let mut id = 0u32;
-            if let Ok(Some(ma)) = mac_address::get_mac_address() {
-                for x in &ma.bytes()[2..] {
-                    id = (id << 8) | (*x as u32);
-                }
-                id &= 0x1FFFFFFF;
-                Some(id.to_string())
-            } else {
-                None
-            }
+            crate::machine_id::auto_id().map(|id| id.to_string())
         }
     }
 
@@ -1159,7 +2446,7 @@ impl Config {
         Self::get_auto_password_with_chars(length, NUM_CHARS)
     }
 
-    fn get_auto_password_with_chars(length: usize, chars: &[char]) -> String {
+    pub(crate) fn get_auto_password_with_chars(length: usize, chars: &[char]) -> String {
         let mut rng = rand::thread_rng();
         (0..length)
             .map(|_| chars[rng.gen::<usize>() % chars.len()])
@@ -1180,6 +2467,14 @@ impl Config {
             config.keys_confirmed = Default::default();
         }
         config.store();
+        drop(config);
+        if !v {
+            SessionKeyCache::clear();
+        }
+        events::emit(SecurityEvent::KeyConfirmed {
+            host: None,
+            confirmed: v,
+        });
     }
 
     pub fn get_host_key_confirmed(host: &str) -> bool {
@@ -1193,16 +2488,37 @@ impl Config {
         let mut config = CONFIG.write().unwrap();
         config.keys_confirmed.insert(host.to_owned(), v);
         config.store();
+        drop(config);
+        if !v {
+            SessionKeyCache::clear();
+        }
+        events::emit(SecurityEvent::KeyConfirmed {
+            host: Some(host.to_owned()),
+            confirmed: v,
+        });
+    }
+
+    pub fn get_key_pair() -> KeyPair {
+        Self::try_get_key_pair().unwrap_or_else(|err| {
+            log::error!("{}", err);
+            Default::default()
+        })
     }
 
-    pub fn get_key_pair() -> KeyPair {
+    ///   `get_key_pair`, an error instead of a new key pair if the private key is in the
+    ///   secret store but could not be loaded from it, the id would change otherwise.
+    pub fn try_get_key_pair() -> crate::ResultType<KeyPair> {
         ///   lock here to make sure no gen_keypair more than once
         ///   no use of CONFIG directly here to ensure no recursive calling in Config::load because of password dec which calling this function
         let mut lock = KEY_PAIR.lock().unwrap();
         if let Some(p) = lock.as_ref() {
-            return p.clone();
+            return Ok(p.clone());
         }
         let mut config = Config::load_::<Config>("");
+        config.load_private_key();
+        if config.key_pair.0.is_empty() && is_secret_unavailable(SECRET_PRIVATE_KEY) {
+            crate::bail!("The private key is in the secret store but could not be loaded");
+        }
         if config.key_pair.0.is_empty() {
             log::info!("Generated new keypair for id: {}", config.id);
             let (pk, sk) = sign::gen_keypair();
@@ -1215,7 +2531,80 @@ impl Config {
             });
         }
         *lock = Some(config.key_pair.clone());
-        config.key_pair
+        Ok(std::mem::take(&mut config.key_pair))
+    }
+
+    ///   The pk that `crate::get_uuid` falls back to, it must not change with
+    ///   `rotate_key_pair`, otherwise the local encrypted data can not be decrypted.
+    pub fn get_key_uuid() -> Vec<u8> {
+        if let Some(v) = KEY_UUID.lock().unwrap().as_ref() {
+            return v.clone();
+        }
+        let mut config = Config::load_::<Config>("");
+        let v = if config.key_uuid.is_empty() {
+            Self::get_key_pair().1
+        } else {
+            std::mem::take(&mut config.key_uuid)
+        };
+        *KEY_UUID.lock().unwrap() = Some(v.clone());
+        v
+    }
+
+    ///   Replace the sign key pair and return the rollover proof, i.e. the new pk
+    ///   signed by the old key. Peers and servers pinning the old pk can check it with
+    ///   `verify_key_rollover` and update their pin. The old key pair is kept for
+    ///   KEY_ROLLOVER_GRACE, so connections already pinned to it still work meanwhile.
+    pub fn rotate_key_pair() -> crate::ResultType<Vec<u8>> {
+        ///   make sure CONFIG is loaded before locking KEY_PAIR, loading may call get_key_pair
+        let id = Self::get_id();
+        let key_uuid = Self::get_key_uuid();
+        let old = Self::get_key_pair();
+        let Some(old_sk) = sign::SecretKey::from_slice(&old.0) else {
+            crate::bail!("Invalid key pair");
+        };
+        let mut lock = KEY_PAIR.lock().unwrap();
+        let (pk, sk) = sign::gen_keypair();
+        let now = crate::get_time();
+        let proof = sign_key_rollover(&old_sk, &id, &pk.0, now);
+        let mut config = CONFIG.write().unwrap();
+        if config.key_uuid.is_empty() {
+            config.key_uuid = key_uuid;
+        }
+        config.old_key_pair = Some(old);
+        config.key_pair = (sk.0.to_vec(), pk.0.into());
+        config.key_rollover = proof.clone();
+        config.key_rollover_time = now;
+        ///   the new pk has to be confirmed by the servers again
+        config.key_confirmed = false;
+        config.keys_confirmed = Default::default();
+        config.store();
+        SessionKeyCache::clear();
+        *lock = Some(config.key_pair.clone());
+        log::info!("Rotated keypair for id: {}", id);
+        drop(config);
+        drop(lock);
+        events::emit(SecurityEvent::KeyRotated);
+        Ok(proof)
+    }
+
+    ///   The rollover proof of the last rotation, during the grace period.
+    pub fn get_key_rollover() -> Option<Vec<u8>> {
+        let config = CONFIG.read().unwrap();
+        if config.key_rollover.is_empty()
+            || crate::get_time() - config.key_rollover_time > KEY_ROLLOVER_GRACE
+        {
+            return None;
+        }
+        Some(config.key_rollover.clone())
+    }
+
+    ///   The key pair before the last rotation, during the grace period.
+    pub fn get_old_key_pair() -> Option<KeyPair> {
+        let config = CONFIG.read().unwrap();
+        if crate::get_time() - config.key_rollover_time > KEY_ROLLOVER_GRACE {
+            return None;
+        }
+        config.old_key_pair.clone()
     }
 
     pub fn no_register_device() -> bool {
@@ -1238,6 +2627,73 @@ impl Config {
         id
     }
 
+    pub fn get_extra_ids() -> Vec<String> {
+        let id = Self::get_id();
+        let mut ids: Vec<String> = vec![];
+        for x in Self::get_option(keys::OPTION_EXTRA_IDS).split(',') {
+            let x = x.trim();
+            if x.is_empty() || x == id || ids.iter().any(|y| y == x) {
+                continue;
+            }
+            if !crate::is_valid_custom_id(x) {
+                log::warn!("Ignore invalid extra id: {}", x);
+                continue;
+            }
+            ids.push(x.to_owned());
+        }
+        ids
+    }
+
+    pub fn set_extra_ids(ids: &[String]) {
+        Self::set_option(keys::OPTION_EXTRA_IDS.to_owned(), ids.join(","));
+    }
+
+    ///   The hostname registered as an alias with OPTION_ALLOW_HOSTNAME_AS_ID,
+    ///   None if not allowed or not a valid custom id (e.g. with a dot).
+    #[cfg(not(any(target_os = "android", target_os = "ios")))]
+    fn hostname_alias() -> Option<String> {
+        let hostname_as_id = BUILTIN_SETTINGS
+            .read()
+            .unwrap()
+            .get(keys::OPTION_ALLOW_HOSTNAME_AS_ID)
+            .map(|v| option2bool(keys::OPTION_ALLOW_HOSTNAME_AS_ID, v))
+            .unwrap_or(false);
+        if !hostname_as_id {
+            return None;
+        }
+        let h = match whoami::fallible::hostname() {
+            Ok(h) => h.replace(" ", "-"),
+            Err(e) => {
+                log::warn!("Failed to get hostname, \"{}\"", e);
+                return None;
+            }
+        };
+        if !crate::is_valid_custom_id(&h) {
+            log::warn!("Ignore hostname not valid as id: {}", h);
+            return None;
+        }
+        Some(h)
+    }
+
+    ///   The main id first, then the hostname alias (if allowed) and the extra ids.
+    ///   With OPTION_ALLOW_HOSTNAME_AS_ID the hostname is registered as an alias
+    ///   instead of replacing the numeric id, so both can be used.
+    pub fn get_register_ids() -> Vec<String> {
+        let mut ids = vec![Self::get_id()];
+        #[cfg(not(any(target_os = "android", target_os = "ios")))]
+        if let Some(h) = Self::hostname_alias() {
+            if !ids.contains(&h) {
+                ids.push(h);
+            }
+        }
+        for x in Self::get_extra_ids() {
+            if !ids.contains(&x) {
+                ids.push(x);
+            }
+        }
+        ids
+    }
+
     pub fn get_id_or(b: String) -> String {
         let a = CONFIG.read().unwrap().id.clone();
         if a.is_empty() {
@@ -1256,7 +2712,10 @@ impl Config {
 
     #[inline]
     fn purify_options(v: &mut HashMap<String, String>) {
-        v.retain(|k, v| is_option_can_save(&OVERWRITE_SETTINGS, k, &DEFAULT_SETTINGS, v));
+        v.retain(|k, v| {
+            is_option_valid(k, v)
+                && is_option_can_save(&OVERWRITE_SETTINGS, k, &DEFAULT_SETTINGS, v)
+        });
     }
 
     pub fn set_options(mut v: HashMap<String, String>) {
@@ -1284,6 +2743,12 @@ impl Config {
     }
 
     pub fn set_option(k: String, v: String) {
+        if !is_option_valid(&k, &v) {
+            return;
+        }
+        if k == keys::OPTION_LOG_LEVEL {
+            crate::logging::set_spec(&v).ok();
+        }
         if !is_option_can_save(&OVERWRITE_SETTINGS, &k, &DEFAULT_SETTINGS, &v) {
             let mut config = CONFIG2.write().unwrap();
             if config.options.remove(&k).is_some() {
@@ -1312,22 +2777,65 @@ impl Config {
         log::info!("id updated from {} to {}", id, new_id);
     }
 
+    ///   A password violating `PasswordPolicy` is refused, see `set_permanent_password_checked`
+    ///   for the violations.
     pub fn set_permanent_password(password: &str) {
+        if let Err(violations) = Self::set_permanent_password_checked(password) {
+            log::error!("Refuse permanent password: {:?}", violations);
+        }
+    }
+
+    ///   Empty password clears it, otherwise it must satisfy `PasswordPolicy`.
+    pub fn set_permanent_password_checked(password: &str) -> Result<(), Vec<PasswordViolation>> {
         if HARD_SETTINGS
             .read()
             .unwrap()
             .get("password")
             .map_or(false, |v| v == password)
         {
-            return;
+            return Ok(());
         }
-        let mut config = CONFIG.write().unwrap();
-        if password == config.password {
-            return;
+        if !password.is_empty() {
+            PasswordPolicy::get().check(password)?;
+        }
+        if password == CONFIG.read().unwrap().password {
+            return Ok(());
         }
+        ///   argon2 is slow, hash before taking the write lock
+        let hash = hash_secret(password);
+        let mut config = CONFIG.write().unwrap();
         config.password = password.into();
+        config.password_hash = hash;
+        config.password_time = crate::get_time();
         config.store();
+        drop(config);
         Self::clear_trusted_devices();
+        events::emit(SecurityEvent::PasswordChanged);
+        Ok(())
+    }
+
+    ///   The permanent password is older than the policy allows and should be changed.
+    pub fn is_permanent_password_expired() -> bool {
+        let config = CONFIG.read().unwrap();
+        if config.password.is_empty() {
+            return false;
+        }
+        PasswordPolicy::get().is_expired(config.password_time, crate::get_time())
+    }
+
+    ///   Check against the stored hash, without decrypting the password.
+    pub fn verify_permanent_password(password: &str) -> bool {
+        if password.is_empty() {
+            return false;
+        }
+        let hash = CONFIG.read().unwrap().password_hash.clone();
+        if !hash.is_empty() {
+            return verify_secret_hash(&hash, password);
+        }
+        ///   no stored password, the hard coded one is used
+        let stored = Self::get_permanent_password();
+        stored.len() == password.len()
+            && sodiumoxide::utils::memcmp(stored.as_bytes(), password.as_bytes())
     }
 
     pub fn get_permanent_password() -> String {
@@ -1423,6 +2931,17 @@ impl Config {
     }
 
     pub fn get_socks() -> Option<Socks5Server> {
+        ///   CONFIG2 is unlocked before the option is read
+        Self::get_configured_socks().or_else(crate::system_proxy::get)
+    }
+
+    ///   `get_socks` for a connection to `target`, "host:port", without the system proxy if
+    ///   its exceptions, e.g. NO_PROXY, exclude the host.
+    pub fn get_socks_for(target: &str) -> Option<Socks5Server> {
+        Self::get_configured_socks().or_else(|| crate::system_proxy::get_for(target))
+    }
+
+    fn get_configured_socks() -> Option<Socks5Server> {
         Self::get_socks_from_custom_client_advanced_settings(&OVERWRITE_SETTINGS.read().unwrap())
             .or(CONFIG2.read().unwrap().socks.clone())
             .or(Self::get_socks_from_custom_client_advanced_settings(
@@ -1455,6 +2974,9 @@ impl Config {
         {
             return NetworkType::ProxySocks;
         }
+        if crate::system_proxy::get().is_some() {
+            return NetworkType::ProxySocks;
+        }
         NetworkType::Direct
     }
 
@@ -1462,13 +2984,40 @@ impl Config {
         CONFIG2.read().unwrap().unlock_pin.clone()
     }
 
+    pub fn verify_unlock_pin(pin: &str) -> bool {
+        let (stored, hash) = {
+            let config = CONFIG2.read().unwrap();
+            (
+                Zeroizing::new(config.unlock_pin.clone()),
+                config.unlock_pin_hash.clone(),
+            )
+        };
+        ///   hashed without holding the lock, compared in constant time if not hashed yet
+        let ok = if hash.is_empty() {
+            !pin.is_empty()
+                && stored.len() == pin.len()
+                && sodiumoxide::utils::memcmp(stored.as_bytes(), pin.as_bytes())
+        } else {
+            verify_secret_hash(&hash, pin)
+        };
+        if !ok {
+            events::emit(SecurityEvent::FailedPinAttempt);
+        }
+        ok
+    }
+
     pub fn set_unlock_pin(pin: &str) {
-        let mut config = CONFIG2.write().unwrap();
-        if pin == config.unlock_pin {
+        if pin == CONFIG2.read().unwrap().unlock_pin {
             return;
         }
+        ///   argon2 is slow, hash before taking the write lock
+        let hash = hash_secret(pin);
+        let mut config = CONFIG2.write().unwrap();
         config.unlock_pin = pin.to_string();
+        config.unlock_pin_hash = hash;
         config.store();
+        drop(config);
+        events::emit(SecurityEvent::UnlockPinChanged);
     }
 
     pub fn get_trusted_devices_json() -> String {
@@ -1480,13 +3029,37 @@ impl Config {
         if synced {
             return devices;
         }
-        let devices = CONFIG2.read().unwrap().trusted_devices.clone();
-        let (devices, succ, store) = decrypt_str_or_original(&devices, PASSWORD_ENC_VERSION);
+        let (devices, endorsed) = {
+            let config = CONFIG2.read().unwrap();
+            (config.trusted_devices.clone(), config.trusted_devices_endorsed)
+        };
+        let (devices, succ, mut store) = decrypt_str_or_original(&devices, PASSWORD_ENC_VERSION);
         if succ {
             let mut devices: Vec<TrustedDevice> =
                 serde_json::from_str(&devices).unwrap_or_default();
             let len = devices.len();
-            devices.retain(|d| !d.outdate());
+            let (sk, pk) = Self::get_key_pair();
+            let old_pk = Self::get_old_key_pair().map(|x| x.1);
+            devices.retain_mut(|d| {
+                if d.outdate() {
+                    return false;
+                }
+                if !endorsed {
+                    ///   written before endorsements, trusted as before, and endorsed once
+                    store |= d.endorse(&sk);
+                    return true;
+                }
+                if d.is_endorsed(&pk) {
+                    return true;
+                }
+                if old_pk.as_ref().map_or(false, |old| d.is_endorsed(old)) {
+                    ///   endorsed before the key rollover
+                    store |= d.endorse(&sk);
+                    return true;
+                }
+                log::warn!("Drop trusted device {} not endorsed by this machine", d.id);
+                false
+            });
             if store || devices.len() != len {
                 Self::set_trusted_devices(devices.clone());
             }
@@ -1508,33 +3081,72 @@ impl Config {
         let devices = encrypt_str_or_original(&devices, PASSWORD_ENC_VERSION, max_len);
         let mut config = CONFIG2.write().unwrap();
         config.trusted_devices = devices;
+        config.trusted_devices_endorsed = true;
         config.store();
         *TRUSTED_DEVICES.write().unwrap() = (trusted_devices, true);
     }
 
-    pub fn add_trusted_device(device: TrustedDevice) {
+    pub fn add_trusted_device(mut device: TrustedDevice) {
+        ///   no login message carries `pk` and `signature` yet, so unsigned records are
+        ///   trusted on the endorsement of this machine alone, signed ones have to verify
+        if (!device.pk.is_empty() || !device.signature.is_empty()) && !device.verify() {
+            log::error!("Refuse to add trusted device {} with invalid signature", device.id);
+            return;
+        }
+        if !device.endorse(&Self::get_key_pair().0) {
+            log::error!("Failed to endorse trusted device {}", device.id);
+            return;
+        }
         let mut devices = Self::get_trusted_devices();
         devices.retain(|d| d.hwid != device.hwid);
+        let event = SecurityEvent::TrustedDeviceAdded {
+            id: device.id.clone(),
+            hwid: device.hwid.clone(),
+        };
         devices.push(device);
         Self::set_trusted_devices(devices);
+        events::emit(event);
     }
 
     pub fn remove_trusted_devices(hwids: &Vec<Bytes>) {
         let mut devices = Self::get_trusted_devices();
         devices.retain(|d| !hwids.contains(&d.hwid));
         Self::set_trusted_devices(devices);
+        events::emit(SecurityEvent::TrustedDevicesRemoved {
+            hwids: hwids.clone(),
+        });
     }
 
     pub fn clear_trusted_devices() {
         Self::set_trusted_devices(Default::default());
+        events::emit(SecurityEvent::TrustedDevicesCleared);
     }
 
     pub fn get() -> Config {
         return CONFIG.read().unwrap().clone();
     }
 
-    pub fn set(cfg: Config) -> bool {
+    pub fn set(mut cfg: Config) -> bool {
+        let old = CONFIG.read().unwrap().password.clone();
+        if old != cfg.password && !cfg.password.is_empty() {
+            if let Err(violations) = PasswordPolicy::get().check(&cfg.password) {
+                log::error!("Refuse permanent password: {:?}", violations);
+                cfg.password = old;
+            }
+        }
+        ///   argon2 is slow, hash before taking the write lock
+        let hash = if CONFIG.read().unwrap().password != cfg.password {
+            Some(hash_secret(&cfg.password))
+        } else {
+            None
+        };
         let mut lock = CONFIG.write().unwrap();
+        if let Some(hash) = hash {
+            cfg.password_hash = hash;
+            cfg.password_time = crate::get_time();
+        } else if lock.password == cfg.password {
+            cfg.password_hash = lock.password_hash.clone();
+        }
         if *lock == cfg {
             return false;
         }
@@ -1554,32 +3166,81 @@ impl Config {
     }
 }
 
+const KEY_ROLLOVER_MAGIC: &[u8] = b"KR01";
+pub const KEY_ROLLOVER_GRACE: i64 = 7 * 24 * 3600 * 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyRollover {
+    pub id: String,
+    pub pk: Vec<u8>,
+    pub time: i64,
+}
+
+///   magic | time (i64 le) | new pk | id, signed by the old key
+fn sign_key_rollover(old_sk: &sign::SecretKey, id: &str, pk: &[u8], time: i64) -> Vec<u8> {
+    let mut data = KEY_ROLLOVER_MAGIC.to_vec();
+    data.extend_from_slice(&time.to_le_bytes());
+    data.extend_from_slice(pk);
+    data.extend_from_slice(id.as_bytes());
+    sign::sign(&data, old_sk)
+}
+
+///   Check a rollover proof against the pinned (old) pk, returns the new pk to pin.
+pub fn verify_key_rollover(proof: &[u8], pinned_pk: &[u8]) -> Option<KeyRollover> {
+    let pinned_pk = sign::PublicKey::from_slice(pinned_pk)?;
+    let data = sign::verify(proof, &pinned_pk).ok()?;
+    let header = KEY_ROLLOVER_MAGIC.len() + 8;
+    if data.len() < header + sign::PUBLICKEYBYTES || !data.starts_with(KEY_ROLLOVER_MAGIC) {
+        return None;
+    }
+    let mut time = [0u8; 8];
+    time.copy_from_slice(&data[KEY_ROLLOVER_MAGIC.len()..header]);
+    Some(KeyRollover {
+        time: i64::from_le_bytes(time),
+        pk: data[header..header + sign::PUBLICKEYBYTES].to_vec(),
+        id: String::from_utf8_lossy(&data[header + sign::PUBLICKEYBYTES..]).to_string(),
+    })
+}
+
 const PEERS: &str = "peers";
 
+#[derive(Debug, Clone)]
+pub struct PreloadOptions {
+    ///   files of the first batch, the rest is skipped if it loads fast
+    pub batch_size: usize,
+    ///   files opened at once
+    pub concurrency: usize,
+    pub cancel: tokio_util::sync::CancellationToken,
+}
+
+impl Default for PreloadOptions {
+    fn default() -> Self {
+        Self {
+            batch_size: PeerConfig::BATCH_LOADING_COUNT,
+            concurrency: PeerConfig::BATCH_LOADING_COUNT,
+            cancel: Default::default(),
+        }
+    }
+}
+
 impl PeerConfig {
     pub fn load(id: &str) -> PeerConfig {
         let _lock = CONFIG.read().unwrap();
-        match confy::load_path(Self::path(id)) {
-            Ok(config) => {
-                let mut config: PeerConfig = config;
-                let mut store = false;
-                let (password, _, store2) =
-                    decrypt_vec_or_original(&config.password, PASSWORD_ENC_VERSION);
-                config.password = password;
-                store = store || store2;
-                for opt in ["rdp_password", "os-username", "os-password"] {
-                    if let Some(v) = config.options.get_mut(opt) {
-                        let (encrypted, _, store2) =
-                            decrypt_str_or_original(v, PASSWORD_ENC_VERSION);
-                        *v = encrypted;
-                        store = store || store2;
-                    }
-                }
-                if store {
-                    config.store_(id);
+        #[cfg(feature = "binary-config")]
+        {
+            let path = Self::binary_path(id);
+            // a newer toml file is written by an older version
+            if crate::get_modified_time(&path) >= crate::get_modified_time(&Self::path(id)) {
+                if let Some(config) = load_binary(&path) {
+                    return Self::decrypt_loaded(id, config);
                 }
-                config
             }
+        }
+        if let Some(config) = load_memory(&Self::path(id)) {
+            return Self::decrypt_loaded(id, config);
+        }
+        match confy::load_path(Self::path(id)) {
+            Ok(config) => Self::decrypt_loaded(id, config),
             Err(err) => {
                 if let confy::ConfyError::GeneralLoadError(err) = &err {
                     if err.kind() == std::io::ErrorKind::NotFound {
@@ -1592,6 +3253,20 @@ impl PeerConfig {
         }
     }
 
+    fn decrypt_loaded(id: &str, mut config: PeerConfig) -> PeerConfig {
+        let mut store = false;
+        store |= decrypt_vec_field(&mut config.password, "peer password");
+        for opt in ["rdp_password", "os-username", "os-password"] {
+            if let Some(v) = config.options.get_mut(opt) {
+                store |= decrypt_field(v, opt);
+            }
+        }
+        if store {
+            config.store_(id);
+        }
+        config
+    }
+
     pub fn store(&self, id: &str) {
         let _lock = CONFIG.read().unwrap();
         self.store_(id);
@@ -1606,14 +3281,84 @@ impl PeerConfig {
                 *v = encrypt_str_or_original(v, PASSWORD_ENC_VERSION, ENCRYPT_MAX_LEN)
             }
         }
-        if let Err(err) = store_path(Self::path(id), config) {
-            log::error!("Failed to store config: {}", err);
+        #[cfg(feature = "binary-config")]
+        {
+            match store_binary(Self::binary_path(id), &config) {
+                Ok(()) => {
+                    fs::remove_file(Self::path(id)).ok();
+                }
+                Err(err) => log::error!("Failed to store config: {}", err),
+            }
+        }
+        #[cfg(not(feature = "binary-config"))]
+        {
+            if let Err(err) = store_path(Self::path(id), config) {
+                log::error!("Failed to store config: {}", err);
+            }
+        }
+        if let Err(err) = store_path(Self::meta_path(id), self.meta()) {
+            log::error!("Failed to store peer meta: {}", err);
         }
         NEW_STORED_PEER_CONFIG.lock().unwrap().insert(id.to_owned());
     }
 
     pub fn remove(id: &str) {
+        let removed = with_memory(&Self::path(id), |files| {
+            files.remove(&Self::path(id));
+            files.remove(&Self::meta_path(id));
+            #[cfg(feature = "binary-config")]
+            {
+                files.remove(&Self::binary_path(id));
+            }
+        });
+        if removed.is_some() {
+            return;
+        }
         fs::remove_file(Self::path(id)).ok();
+        fs::remove_file(Self::meta_path(id)).ok();
+        #[cfg(feature = "binary-config")]
+        fs::remove_file(Self::binary_path(id)).ok();
+    }
+
+    #[cfg(feature = "binary-config")]
+    fn binary_path(id: &str) -> PathBuf {
+        Self::path(id).with_extension(BINARY_EXT)
+    }
+
+    pub fn meta(&self) -> PeerMeta {
+        PeerMeta {
+            info: self.info.clone(),
+            alias: self.options.get("alias").cloned().unwrap_or_default(),
+        }
+    }
+
+    fn meta_path(id: &str) -> PathBuf {
+        Self::path(id).with_extension("meta")
+    }
+
+    ///   Without parsing the peer file if its meta file is up to date.
+    pub fn load_meta(id: &str) -> PeerMeta {
+        let modified = crate::get_modified_time(&Self::path(id));
+        Self::load_meta_(id, modified)
+    }
+
+    fn load_meta_(id: &str, modified: SystemTime) -> PeerMeta {
+        let meta_path = Self::meta_path(id);
+        if crate::get_modified_time(&meta_path) >= modified {
+            let _lock = CONFIG.read().unwrap();
+            if let Ok(meta) = confy::load_path::<PeerMeta>(&meta_path) {
+                if !meta.info.platform.is_empty() {
+                    return meta;
+                }
+            }
+        }
+        // missing, or the peer file was written by an older version
+        let meta = Self::load(id).meta();
+        if !meta.info.platform.is_empty() {
+            let _lock = CONFIG.read().unwrap();
+            store_path(meta_path, &meta).ok();
+        }
+        meta
     }
 
     fn path(id: &str) -> PathBuf {
@@ -1644,21 +3389,28 @@ impl PeerConfig {
     pub fn get_vec_id_modified_time_path(
         id_filters: &Option<Vec<String>>,
     ) -> Vec<(String, SystemTime, PathBuf)> {
-        if let Ok(peers) = Config::path(PEERS).read_dir() {
-            let mut vec_id_modified_time_path = peers
+        let dir = Config::path(PEERS);
+        let memory = memory_files_in(&dir);
+        let files = match &memory {
+            Some(files) => Ok(files.iter().map(|x| x.0.clone()).collect::<Vec<_>>()),
+            None => dir.read_dir().map(|peers| {
+                peers
+                    .filter_map(|res| res.ok())
+                    .map(|res| res.path())
+                    .filter(|p| p.is_file())
+                    .collect()
+            }),
+        };
+        if let Ok(files) = files {
+            let mut vec_id_modified_time_path = files
                 .into_iter()
-                .filter_map(|res| match res {
-                    Ok(res) => {
-                        let p = res.path();
-                        if p.is_file()
-                            && p.extension().map(|p| p.to_str().unwrap_or("")) == Some("toml")
-                        {
-                            Some(p)
-                        } else {
-                            None
-                        }
-                    }
-                    _ => None,
+                .filter(|p| {
+                    let ext = p.extension().map(|p| p.to_str().unwrap_or(""));
+                    #[cfg(feature = "binary-config")]
+                    let is_binary = ext == Some(BINARY_EXT);
+                    #[cfg(not(feature = "binary-config"))]
+                    let is_binary = false;
+                    ext == Some("toml") || is_binary
                 })
                 .map(|p| {
                     let id = p
@@ -1683,11 +3435,20 @@ impl PeerConfig {
                     filters.contains(id)
                 })
                 .map(|(id, p)| {
-                    let t = crate::get_modified_time(&p);
+                    let t = memory
+                        .as_ref()
+                        .and_then(|files| files.iter().find(|x| x.0 == p).map(|x| x.1))
+                        .unwrap_or_else(|| crate::get_modified_time(&p));
                     (id, t, p)
                 })
                 .collect::<Vec<_>>();
             vec_id_modified_time_path.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+            // both the toml and the binary file of a peer, keep the newer one
+            #[cfg(feature = "binary-config")]
+            {
+                let mut seen = HashSet::new();
+                vec_id_modified_time_path.retain(|(id, _, _)| seen.insert(id.clone()));
+            }
             vec_id_modified_time_path
         } else {
             vec![]
@@ -1700,30 +3461,40 @@ impl PeerConfig {
     }
 
     #[tokio::main(flavor = "current_thread")]
-    async fn preload_peers_async() {
+    async fn preload_peers_async(options: PreloadOptions) {
+        use futures::StreamExt;
         let now = std::time::Instant::now();
         let vec_id_modified_time_path = Self::get_vec_id_modified_time_path(&None);
         let total_count = vec_id_modified_time_path.len();
-        let mut futs = vec![];
-        for (_, _, path) in vec_id_modified_time_path.into_iter() {
-            futs.push(Self::preload_file_async(path));
-            if futs.len() >= Self::BATCH_LOADING_COUNT {
-                let first_load_start = std::time::Instant::now();
-                futures::future::join_all(futs).await;
-                if first_load_start.elapsed().as_millis() < 10 {
-                    ///   No need to preload the rest if the first load is fast.
+        let batch_size = options.batch_size.max(1);
+        let mut first = true;
+        for batch in vec_id_modified_time_path.chunks(batch_size) {
+            if options.cancel.is_cancelled() {
+                log::info!("Preload peers cancelled after {:?}", now.elapsed());
+                return;
+            }
+            let batch_start = std::time::Instant::now();
+            let loading = futures::stream::iter(batch.iter())
+                .map(|(_, _, path)| Self::preload_file_async(path.clone()))
+                .buffer_unordered(options.concurrency.max(1))
+                .collect::<Vec<_>>();
+            tokio::select! {
+                _ = loading => {}
+                _ = options.cancel.cancelled() => {
+                    log::info!("Preload peers cancelled after {:?}", now.elapsed());
                     return;
                 }
-                futs = vec![];
             }
-        }
-        if !futs.is_empty() {
-            futures::future::join_all(futs).await;
+            // No need to preload the rest if the first full batch is fast.
+            if first && batch.len() == batch_size && batch_start.elapsed().as_millis() < 10 {
+                return;
+            }
+            first = false;
         }
         log::info!(
             "Preload peers done in {:?}, batch_count: {}, total: {}",
             now.elapsed(),
-            Self::BATCH_LOADING_COUNT,
+            batch_size,
             total_count
         );
     }
@@ -1733,10 +3504,16 @@ impl PeerConfig {
     ///   The reason is that the Windows has "Microsoft Defender Antivirus Service" running in the background, which will scan the file when it's opened the first time.
     ///   So we have to preload all peers in a background thread to avoid the delay when opening the file the first time.
     ///   We can temporarily stop "Microsoft Defender Antivirus Service" or add the fold to the white list, to verify this. But don't do this in the release version.
-    pub fn preload_peers() {
-        std::thread::spawn(|| {
-            Self::preload_peers_async();
-        });
+    pub fn preload_peers() -> std::thread::JoinHandle<()> {
+        Self::preload_peers_with(PreloadOptions::default())
+    }
+
+    ///   `preload_peers` with the batch size, the number of files opened at once and a
+    ///   token to stop it, e.g. when the user opens a connection.
+    pub fn preload_peers_with(options: PreloadOptions) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            Self::preload_peers_async(options);
+        })
     }
 
     pub fn peers(id_filters: Option<Vec<String>>) -> Vec<(String, SystemTime, PeerConfig)> {
@@ -1782,7 +3559,66 @@ impl PeerConfig {
         (peers, to)
     }
 
+    ///   `peers` for listing, see `PeerMeta`.
+    pub fn peers_meta(id_filters: Option<Vec<String>>) -> Vec<(String, SystemTime, PeerMeta)> {
+        let vec_id_modified_time_path = Self::get_vec_id_modified_time_path(&id_filters);
+        Self::batch_peers_meta(
+            &vec_id_modified_time_path,
+            0,
+            Some(vec_id_modified_time_path.len()),
+        )
+        .0
+    }
+
+    ///   `batch_peers` for listing, see `PeerMeta`.
+    pub fn batch_peers_meta(
+        all: &Vec<(String, SystemTime, PathBuf)>,
+        from: usize,
+        to: Option<usize>,
+    ) -> (Vec<(String, SystemTime, PeerMeta)>, usize) {
+        if from >= all.len() {
+            return (vec![], 0);
+        }
+        let to = match to {
+            Some(to) => to.min(all.len()),
+            None => (from + Self::BATCH_LOADING_COUNT).min(all.len()),
+        };
+        if to <= from {
+            return (vec![], from);
+        }
+        let peers: Vec<_> = all[from..to]
+            .iter()
+            .map(|(id, t, p)| {
+                let meta = PeerConfig::load_meta_(id, *t);
+                if meta.info.platform.is_empty() {
+                    fs::remove_file(p).ok();
+                    fs::remove_file(Self::meta_path(id)).ok();
+                }
+                (id.clone(), *t, meta)
+            })
+            .filter(|p| !p.2.info.platform.is_empty())
+            .collect();
+        (peers, to)
+    }
+
     pub fn exists(id: &str) -> bool {
+        if let Some(exists) = read_memory(&Self::path(id), |files| {
+            #[cfg(feature = "binary-config")]
+            {
+                if files.contains_key(&Self::binary_path(id)) {
+                    return true;
+                }
+            }
+            files.contains_key(&Self::path(id))
+        }) {
+            return exists;
+        }
+        #[cfg(feature = "binary-config")]
+        {
+            if Self::binary_path(id).exists() {
+                return true;
+            }
+        }
         Self::path(id).exists()
     }
 
@@ -1961,7 +3797,7 @@ serde_field_bool!(
     "SyncInitClipboard::default_sync_init_clipboard"
 );
 
-#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
 pub struct LocalConfig {
     #[serde(default, deserialize_with = "deserialize_string")]
     remote_id: String, ///   latest used one
@@ -1976,6 +3812,38 @@ pub struct LocalConfig {
     ///   Various data for flutter ui
     #[serde(default, deserialize_with = "deserialize_hashmap_string_string")]
     ui_flutter: HashMap<String, String>,
+    ///   The validators of the last responses of the api-server by account and url, see `api`
+    #[serde(
+        default,
+        skip_serializing_if = "HashMap::is_empty",
+        deserialize_with = "deserialize_hashmap_api_cache"
+    )]
+    api_cache: HashMap<String, ApiCache>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ApiCache {
+    #[serde(
+        default,
+        skip_serializing_if = "String::is_empty",
+        deserialize_with = "deserialize_string"
+    )]
+    pub etag: String,
+    #[serde(
+        default,
+        skip_serializing_if = "String::is_empty",
+        deserialize_with = "deserialize_string"
+    )]
+    pub last_modified: String,
+    ///   ms, when the response was received
+    #[serde(default, deserialize_with = "deserialize_i64")]
+    pub time: i64,
+    #[serde(
+        default,
+        skip_serializing_if = "String::is_empty",
+        deserialize_with = "deserialize_string"
+    )]
+    pub url: String,
 }
 
 impl LocalConfig {
@@ -1984,6 +3852,9 @@ impl LocalConfig {
     }
 
     fn store(&self) {
+        if defer_in_batch(Config::file_("_local"), self, Self::store) {
+            return;
+        }
         Config::store_(self, "_local");
     }
 
@@ -2111,6 +3982,60 @@ impl LocalConfig {
             config.store();
         }
     }
+
+    ///   `key` of the account and the url, see `api`.
+    pub fn get_api_cache(key: &str) -> Option<ApiCache> {
+        LOCAL_CONFIG.read().unwrap().api_cache.get(key).cloned()
+    }
+
+    ///   None to remove it. Returns the keys of the entries of the same url it replaces,
+    ///   e.g. of another account, whose copies are to be removed.
+    pub fn set_api_cache(key: &str, cache: Option<ApiCache>) -> Vec<String> {
+        let mut config = LOCAL_CONFIG.write().unwrap();
+        if config.api_cache.get(key) == cache.as_ref() {
+            return vec![];
+        }
+        let mut replaced = vec![];
+        match cache {
+            Some(cache) => {
+                ///   also those kept by the url only before
+                replaced = config
+                    .api_cache
+                    .iter()
+                    .filter(|(k, v)| *k != key && (v.url == cache.url || **k == cache.url))
+                    .map(|(k, _)| k.clone())
+                    .collect();
+                for k in replaced.iter() {
+                    config.api_cache.remove(k);
+                }
+                config.api_cache.insert(key.to_owned(), cache);
+            }
+            None => {
+                config.api_cache.remove(key);
+            }
+        }
+        config.store();
+        replaced
+    }
+
+    ///   Removes the entries of `url` of all the accounts, returns their keys.
+    pub fn remove_api_caches(url: &str) -> Vec<String> {
+        let mut config = LOCAL_CONFIG.write().unwrap();
+        let keys: Vec<String> = config
+            .api_cache
+            .iter()
+            .filter(|(k, v)| v.url == url || *k == url)
+            .map(|(k, _)| k.clone())
+            .collect();
+        if keys.is_empty() {
+            return keys;
+        }
+        for k in keys.iter() {
+            config.api_cache.remove(k);
+        }
+        config.store();
+        keys
+    }
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
@@ -2144,6 +4069,9 @@ pub struct LanPeers {
 impl LanPeers {
     pub fn load() -> LanPeers {
         let _lock = CONFIG.read().unwrap();
+        if let Some(peers) = load_memory(&Config::file_("_lan_peers")) {
+            return peers;
+        }
         match confy::load_path(Config::file_("_lan_peers")) {
             Ok(peers) => peers,
             Err(err) => {
@@ -2182,6 +4110,7 @@ impl UserDefaultConfig {
         let mut cfg = USER_DEFAULT_CONFIG.write().unwrap();
         ///   we do so, because default config may changed in another process, but we don't sync it
         ///   but no need to read every time, give a small interval to avoid too many redundant read waste
+        ///   a real instant, as set at startup, not one of a mock clock
         if cfg.1.elapsed() > Duration::from_secs(1) {
             *cfg = (Self::load(), Instant::now());
         }
@@ -2283,7 +4212,7 @@ impl UserDefaultConfig {
     }
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
 pub struct AbPeer {
     #[serde(
         default,
@@ -2323,6 +4252,22 @@ pub struct AbPeer {
     pub alias: String,
     #[serde(default, deserialize_with = "deserialize_vec_string")]
     pub tags: Vec<String>,
+    ///   set by the api-server on every change of the peer, sent back with the changes
+    ///   made on it, see `ab::AbChangeset`
+    #[serde(
+        default,
+        deserialize_with = "deserialize_string",
+        skip_serializing_if = "String::is_empty"
+    )]
+    pub etag: String,
+    ///   when each field was last changed here, in ms, by its name in the json, see
+    ///   `ab::MergeStrategy::LastWriterWins`
+    #[serde(
+        default,
+        deserialize_with = "deserialize_hashmap_string_i64",
+        skip_serializing_if = "HashMap::is_empty"
+    )]
+    pub modified: HashMap<String, i64>,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
@@ -2349,6 +4294,26 @@ pub struct AbEntry {
         skip_serializing_if = "String::is_empty"
     )]
     pub tag_colors: String,
+    ///   the revision of the api-server the peers are synchronized to
+    #[serde(default, deserialize_with = "deserialize_i64")]
+    pub revision: i64,
+    ///   shared read-only, e.g. to technicians, see `ab::AbPermission`
+    #[serde(default, deserialize_with = "deserialize_bool")]
+    pub read_only: bool,
+    ///   "read", "read-write" or "full", the last if empty
+    #[serde(
+        default,
+        deserialize_with = "deserialize_string",
+        skip_serializing_if = "String::is_empty"
+    )]
+    pub permission: String,
+    ///   who shared the book, empty for the own ones
+    #[serde(
+        default,
+        deserialize_with = "deserialize_string",
+        skip_serializing_if = "String::is_empty"
+    )]
+    pub owner: String,
 }
 
 impl AbEntry {
@@ -2367,6 +4332,9 @@ pub struct Ab {
     pub access_token: String,
     #[serde(default, deserialize_with = "deserialize_vec_abentry")]
     pub ab_entries: Vec<AbEntry>,
+    ///   the fields of newer api-servers, kept in the cache for the app
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
 }
 
 impl Ab {
@@ -2376,36 +4344,53 @@ impl Ab {
     }
 
     pub fn store(json: String) {
-        if let Ok(mut file) = std::fs::File::create(Self::path()) {
-            let data = compress(json.as_bytes());
-            let max_len = 64 * 1024 * 1024;
-            if data.len() > max_len {
-                ///   maxlen of function decompress
-                log::error!("ab data too large, {} > {}", data.len(), max_len);
-                return;
-            }
-            if let Ok(data) = symmetric_crypt(&data, true) {
-                file.write_all(&data).ok();
-            }
-        };
+        Self::store_from(json.as_bytes());
+    }
+
+    ///   Parses the json of the api-server from `reader`, e.g. the body of the response,
+    ///   without the whole of it in memory, and stores it.
+    pub fn store_from<R: Read>(reader: R) {
+        match serde_json::from_reader::<_, Ab>(std::io::BufReader::new(reader)) {
+            Ok(ab) => ab.save(),
+            Err(err) => log::error!("Invalid ab json: {}", err),
+        }
     }
 
     pub fn load() -> Ab {
-        if let Ok(mut file) = std::fs::File::open(Self::path()) {
-            let mut data = vec![];
-            if file.read_to_end(&mut data).is_ok() {
-                if let Ok(data) = symmetric_crypt(&data, false) {
-                    let data = decompress(&data);
-                    if let Ok(ab) = serde_json::from_str::<Ab>(&String::from_utf8_lossy(&data)) {
-                        return ab;
-                    }
-                }
+        if let Some(mut ab) = load_cache::<Ab>(&Self::path(), TOKEN_AB) {
+            if ab.access_token.is_empty() {
+                ab.access_token = TokenStore::get(TOKEN_AB).unwrap_or_default();
             }
-        };
+            return ab;
+        }
         Self::remove();
         Ab::default()
     }
 
+    ///   Stores the address book, e.g. after changes were applied, see `ab`, without the
+    ///   whole json in memory. The access token is kept by `TokenStore`.
+    pub fn save(&self) {
+        #[derive(Serialize)]
+        struct Cache<'a> {
+            ab_entries: &'a [AbEntry],
+            #[serde(flatten)]
+            extra: &'a HashMap<String, serde_json::Value>,
+        }
+        TokenStore::take(TOKEN_AB, &self.access_token);
+        let cache = Cache {
+            ab_entries: &self.ab_entries,
+            extra: &self.extra,
+        };
+        if let Err(err) = store_cache(&Self::path(), TOKEN_AB, &cache) {
+            log::error!("Failed to store ab: {}", err);
+        }
+    }
+
+    ///   Decrypts, decompresses and parses the content of the cache file.
+    pub fn decode(data: &[u8]) -> Option<Ab> {
+        decode_cache(TOKEN_AB, data)
+    }
+
     pub fn remove() {
         std::fs::remove_file(Self::path()).ok();
     }
@@ -2455,83 +4440,751 @@ pub struct GroupPeer {
         skip_serializing_if = "String::is_empty"
     )]
     pub login_name: String,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_string",
+        skip_serializing_if = "String::is_empty"
+    )]
+    pub device_group_name: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct GroupUser {
+    #[serde(
+        default,
+        deserialize_with = "deserialize_string",
+        skip_serializing_if = "String::is_empty"
+    )]
+    pub name: String,
+    ///   "admin", "operator" or "viewer", see `group::Role`
+    #[serde(
+        default,
+        deserialize_with = "deserialize_string",
+        skip_serializing_if = "String::is_empty"
+    )]
+    pub role: String,
+    ///   the actions granted beyond those of the role, see `group::Action`
+    #[serde(default, deserialize_with = "deserialize_vec_string")]
+    pub permissions: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct DeviceGroup {
+    #[serde(
+        default,
+        deserialize_with = "deserialize_string",
+        skip_serializing_if = "String::is_empty"
+    )]
+    pub name: String,
+    ///   the actions allowed on its peers to the users not admin, all if empty
+    #[serde(default, deserialize_with = "deserialize_vec_string")]
+    pub features: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct Group {
+    #[serde(
+        default,
+        deserialize_with = "deserialize_string",
+        skip_serializing_if = "String::is_empty"
+    )]
+    pub access_token: String,
+    #[serde(default, deserialize_with = "deserialize_vec_groupuser")]
+    pub users: Vec<GroupUser>,
+    #[serde(default, deserialize_with = "deserialize_vec_grouppeer")]
+    pub peers: Vec<GroupPeer>,
+    #[serde(default, deserialize_with = "deserialize_vec_devicegroup")]
+    pub device_groups: Vec<DeviceGroup>,
+    ///   See `Ab::extra`.
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+impl Group {
+    fn path() -> PathBuf {
+        let filename = format!("{}_group", APP_NAME.read().unwrap().clone());
+        Config::path(filename)
+    }
+
+    pub fn store(json: String) {
+        Self::store_from(json.as_bytes());
+    }
+
+    ///   See `Ab::store_from`.
+    pub fn store_from<R: Read>(reader: R) {
+        match serde_json::from_reader::<_, Self>(std::io::BufReader::new(reader)) {
+            Ok(group) => group.save(),
+            Err(err) => log::error!("Invalid group json: {}", err),
+        }
+    }
+
+    pub fn load() -> Self {
+        if let Some(mut group) = load_cache::<Self>(&Self::path(), TOKEN_GROUP) {
+            if group.access_token.is_empty() {
+                group.access_token = TokenStore::get(TOKEN_GROUP).unwrap_or_default();
+            }
+            return group;
+        }
+        Self::remove();
+        Self::default()
+    }
+
+    ///   See `Ab::save`.
+    pub fn save(&self) {
+        #[derive(Serialize)]
+        struct Cache<'a> {
+            users: &'a [GroupUser],
+            peers: &'a [GroupPeer],
+            device_groups: &'a [DeviceGroup],
+            #[serde(flatten)]
+            extra: &'a HashMap<String, serde_json::Value>,
+        }
+        TokenStore::take(TOKEN_GROUP, &self.access_token);
+        let cache = Cache {
+            users: &self.users,
+            peers: &self.peers,
+            device_groups: &self.device_groups,
+            extra: &self.extra,
+        };
+        if let Err(err) = store_cache(&Self::path(), TOKEN_GROUP, &cache) {
+            log::error!("Failed to store group: {}", err);
+        }
+        crate::group::invalidate();
+    }
+
+    ///   See `Ab::decode`.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        decode_cache(TOKEN_GROUP, data)
+    }
+
+    pub fn remove() {
+        std::fs::remove_file(Self::path()).ok();
+        crate::group::invalidate();
+    }
+}
+
+pub const TOKEN_AB: &str = "ab";
+pub const TOKEN_GROUP: &str = "group";
+
+///   The caches of the address book and the groups are encrypted with a key of the
+///   logged-in account instead of the machine-wide key of `symmetric_crypt`, so another
+///   local user or a copy of the disk can not read them: a key derived from the access
+///   token and a random key of the user, kept by `secrets::user_store`, e.g. wrapped with
+///   DPAPI or in the Secret Service, or without one, the uuid of the machine. The cache
+///   is fetched again after a new login or a refresh of the token.
+const ACCOUNT_CACHE_MAGIC: &[u8] = b"hbbac1";
+
+lazy_static::lazy_static! {
+    ///   token name -> the random key of the user, None if there is no store of the user
+    static ref ACCOUNT_USER_KEYS: Mutex<HashMap<String, Option<Zeroizing<Vec<u8>>>>> =
+        Default::default();
+}
+
+fn account_user_key(token_name: &str) -> Option<Zeroizing<Vec<u8>>> {
+    let mut keys = ACCOUNT_USER_KEYS.lock().unwrap();
+    if let Some(key) = keys.get(token_name) {
+        return key.clone();
+    }
+    let key = (|| {
+        let store = secrets::user_store()?;
+        let name = format!("{}-cache-key", token_name);
+        match store.get(&name) {
+            Ok(Some(key)) if key.len() == secretbox::KEYBYTES => return Some(Zeroizing::new(key)),
+            Ok(_) => {}
+            Err(err) => {
+                log::error!("Failed to get the key of the {} cache: {}", token_name, err);
+                return None;
+            }
+        }
+        let key = Zeroizing::new(secretbox::gen_key().0.to_vec());
+        match store.set(&name, &key) {
+            Ok(_) => Some(key),
+            Err(err) => {
+                log::error!("Failed to keep the key of the {} cache: {}", token_name, err);
+                None
+            }
+        }
+    })();
+    keys.insert(token_name.to_owned(), key.clone());
+    key
+}
+
+fn account_cache_key(token_name: &str) -> Option<secretbox::Key> {
+    let token = TokenStore::get(token_name).filter(|x| !x.is_empty())?;
+    let mut material = token.into_bytes();
+    match account_user_key(token_name) {
+        Some(key) => material.extend_from_slice(&key),
+        None => material.extend_from_slice(&crate::get_uuid()),
+    }
+    let key = secretbox::Key(blake3::derive_key("hbb_common account cache", &material));
+    material.zeroize();
+    Some(key)
+}
+
+///   None if it can not be encrypted, e.g. without a key of the machine either.
+pub(crate) fn account_seal(token_name: &str, data: &[u8]) -> Option<Vec<u8>> {
+    let Some(key) = account_cache_key(token_name) else {
+        ///   not logged in, as before
+        return symmetric_crypt(data, true).ok();
+    };
+    Some(seal_with(ACCOUNT_CACHE_MAGIC, data, &key))
+}
+
+fn seal_with(magic: &[u8], data: &[u8], key: &secretbox::Key) -> Vec<u8> {
+    let nonce = secretbox::gen_nonce();
+    let mut res = magic.to_vec();
+    res.extend_from_slice(&nonce.0);
+    res.extend(secretbox::seal(data, &nonce, key));
+    res
+}
+
+fn open_with(data: &[u8], key: &secretbox::Key) -> Option<Vec<u8>> {
+    if data.len() < secretbox::NONCEBYTES {
+        return None;
+    }
+    let (nonce, data) = data.split_at(secretbox::NONCEBYTES);
+    let nonce = secretbox::Nonce::from_slice(nonce)?;
+    secretbox::open(data, &nonce, key).ok()
+}
+
+pub(crate) fn account_open(token_name: &str, data: &[u8]) -> Option<Vec<u8>> {
+    let Some(data) = data.strip_prefix(ACCOUNT_CACHE_MAGIC) else {
+        ///   written with the machine-wide key before, encrypted with the account on the
+        ///   next store
+        return symmetric_crypt(data, false).ok();
+    };
+    open_with(data, &account_cache_key(token_name)?)
+}
+
+///   Encrypted with the random key of the user only, or the uuid of the machine, not
+///   with the access token, for what must survive its refresh, e.g. the edits made
+///   offline, see `ab::AbQueue`.
+const USER_CACHE_MAGIC: &[u8] = b"hbbuc1";
+
+fn user_cache_key(token_name: &str) -> secretbox::Key {
+    let material = match account_user_key(token_name) {
+        Some(key) => key,
+        None => Zeroizing::new(crate::get_uuid()),
+    };
+    secretbox::Key(blake3::derive_key("hbb_common user cache", &material))
+}
+
+pub(crate) fn user_seal(token_name: &str, data: &[u8]) -> Vec<u8> {
+    seal_with(USER_CACHE_MAGIC, data, &user_cache_key(token_name))
+}
+
+pub(crate) fn user_open(token_name: &str, data: &[u8]) -> Option<Vec<u8>> {
+    open_with(data.strip_prefix(USER_CACHE_MAGIC)?, &user_cache_key(token_name))
+}
+
+///   The caches are written streaming, without the whole json in memory and without
+///   the cap of `compress::MAX_PAYLOAD`: the magic, the header of a secretstream, then
+///   the compressed json in chunks encrypted one by one, each prefixed with its length
+///   and the last one tagged final, so a truncated file is detected.
+const ACCOUNT_STREAM_MAGIC: &[u8] = b"hbbac2";
+const ACCOUNT_STREAM_CHUNK: usize = 64 * 1024;
+
+fn account_stream_key(token_name: &str) -> Option<secretstream::Key> {
+    account_cache_key(token_name).and_then(|k| secretstream::Key::from_slice(&k.0))
+}
+
+fn stream_error(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+pub(crate) struct SealWriter<W: Write> {
+    inner: W,
+    stream: secretstream::Stream<secretstream::Push>,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> SealWriter<W> {
+    fn new(mut inner: W, key: &secretstream::Key) -> std::io::Result<Self> {
+        let (stream, header) =
+            secretstream::Stream::init_push(key).map_err(|_| stream_error("init_push"))?;
+        inner.write_all(ACCOUNT_STREAM_MAGIC)?;
+        inner.write_all(&header.0)?;
+        Ok(Self {
+            inner,
+            stream,
+            buf: Vec::with_capacity(ACCOUNT_STREAM_CHUNK),
+        })
+    }
+
+    fn push(&mut self, tag: secretstream::Tag) -> std::io::Result<()> {
+        let chunk = self
+            .stream
+            .push(&self.buf, None, tag)
+            .map_err(|_| stream_error("push"))?;
+        self.inner.write_all(&(chunk.len() as u32).to_le_bytes())?;
+        self.inner.write_all(&chunk)?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> std::io::Result<W> {
+        self.push(secretstream::Tag::Final)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for SealWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let n = data.len().min(ACCOUNT_STREAM_CHUNK - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == ACCOUNT_STREAM_CHUNK {
+            self.push(secretstream::Tag::Message)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub(crate) struct OpenReader<R: Read> {
+    inner: R,
+    stream: secretstream::Stream<secretstream::Pull>,
+    buf: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+impl<R: Read> OpenReader<R> {
+    ///   After the magic.
+    fn new(mut inner: R, key: &secretstream::Key) -> std::io::Result<Self> {
+        let mut header = [0u8; secretstream::HEADERBYTES];
+        inner.read_exact(&mut header)?;
+        let header =
+            secretstream::Header::from_slice(&header).ok_or_else(|| stream_error("header"))?;
+        let stream =
+            secretstream::Stream::init_pull(&header, key).map_err(|_| stream_error("init_pull"))?;
+        Ok(Self {
+            inner,
+            stream,
+            buf: vec![],
+            pos: 0,
+            finished: false,
+        })
+    }
+}
+
+impl<R: Read> Read for OpenReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            ///   UnexpectedEof if truncated
+            let mut len = [0u8; 4];
+            self.inner.read_exact(&mut len)?;
+            let len = u32::from_le_bytes(len) as usize;
+            if len > ACCOUNT_STREAM_CHUNK + secretstream::ABYTES {
+                return Err(stream_error("chunk too large"));
+            }
+            let mut chunk = vec![0u8; len];
+            self.inner.read_exact(&mut chunk)?;
+            let (data, tag) = self
+                .stream
+                .pull(&chunk, None)
+                .map_err(|_| stream_error("pull"))?;
+            self.finished = matches!(tag, secretstream::Tag::Final);
+            self.buf = data;
+            self.pos = 0;
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+///   The bodies kept by `api` are sealed like the caches, with a key of the access token
+///   and the random key of the user, or without one, the uuid of the machine.
+fn api_cache_key(token: &str) -> Option<secretstream::Key> {
+    let mut material = Zeroizing::new(token.as_bytes().to_vec());
+    match account_user_key("api") {
+        Some(key) => material.extend_from_slice(&key),
+        None => material.extend_from_slice(&crate::get_uuid()),
+    }
+    secretstream::Key::from_slice(&blake3::derive_key("hbb_common api cache", &material))
+}
+
+pub(crate) fn api_cache_writer<W: Write>(inner: W, token: &str) -> std::io::Result<SealWriter<W>> {
+    let key = api_cache_key(token).ok_or_else(|| stream_error("key"))?;
+    SealWriter::new(inner, &key)
+}
+
+pub(crate) fn api_cache_reader<R: Read>(
+    mut inner: R,
+    token: &str,
+) -> std::io::Result<OpenReader<R>> {
+    let mut magic = [0u8; ACCOUNT_STREAM_MAGIC.len()];
+    inner.read_exact(&mut magic)?;
+    if &magic[..] != ACCOUNT_STREAM_MAGIC {
+        return Err(stream_error("magic"));
+    }
+    let key = api_cache_key(token).ok_or_else(|| stream_error("key"))?;
+    OpenReader::new(inner, &key)
+}
+
+///   Serializes, compresses and encrypts `value` into a temporary file renamed over
+///   `path`.
+fn store_cache<T: serde::Serialize>(
+    path: &Path,
+    token_name: &str,
+    value: &T,
+) -> crate::ResultType<()> {
+    ///   written aside and renamed over the cache, not truncated by a crash
+    let tmp = path.with_extension("tmp");
+    let Some(key) = account_stream_key(token_name) else {
+        ///   not logged in, as before
+        let data = compress(&serde_json::to_vec(value)?);
+        let Some(data) = account_seal(token_name, &data) else {
+            crate::bail!("failed to encrypt");
+        };
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, path)?;
+        return Ok(());
+    };
+    let file = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+    let mut encoder = compress::encoder(SealWriter::new(file, &key)?)?;
+    serde_json::to_writer(&mut encoder, value)?;
+    encoder.finish()?.finish()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+///   The caches are authenticated, so the decompressed size is not limited.
+fn parse_cache<R: Read, T: serde::de::DeserializeOwned>(reader: R) -> Option<T> {
+    compress::decoder(reader, u64::MAX)
+        .and_then(|r| serde_json::from_reader::<_, T>(r).map_err(Into::into))
+        .ok()
+}
+
+fn load_cache<T: serde::de::DeserializeOwned>(path: &Path, token_name: &str) -> Option<T> {
+    use std::io::BufRead;
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path).ok()?);
+    if reader.fill_buf().ok()?.starts_with(ACCOUNT_STREAM_MAGIC) {
+        reader.consume(ACCOUNT_STREAM_MAGIC.len());
+        let key = account_stream_key(token_name)?;
+        return parse_cache(OpenReader::new(reader, &key).ok()?);
+    }
+    let mut data = vec![];
+    reader.read_to_end(&mut data).ok()?;
+    decode_cache(token_name, &data)
+}
+
+fn decode_cache<T: serde::de::DeserializeOwned>(token_name: &str, data: &[u8]) -> Option<T> {
+    if let Some(data) = data.strip_prefix(ACCOUNT_STREAM_MAGIC) {
+        let key = account_stream_key(token_name)?;
+        return parse_cache(OpenReader::new(data, &key).ok()?);
+    }
+    ///   written at once before
+    let data = account_open(token_name, data)?;
+    parse_cache(&data[..])
+}
+
+///   Called with the expired token, returns the refreshed one.
+pub type TokenRefreshHook = Box<dyn Fn(&str, &AccessToken) -> Option<AccessToken> + Send + Sync>;
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AccessToken {
+    #[serde(default, deserialize_with = "deserialize_string")]
+    pub token: String,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_string",
+        skip_serializing_if = "String::is_empty"
+    )]
+    pub refresh_token: String,
+    ///   ms, 0 means no expiry
+    #[serde(default, deserialize_with = "deserialize_i64")]
+    pub expire: i64,
+}
+
+impl AccessToken {
+    pub fn is_expired(&self) -> bool {
+        self.expire > 0 && self.expire <= crate::get_time()
+    }
+
+    ///   Scrubs a copy no longer needed, like `Config::zeroize_secrets`.
+    pub fn zeroize_secrets(&mut self) {
+        self.token.zeroize();
+        self.refresh_token.zeroize();
+    }
+}
+
+///   Access tokens kept apart from the ab / group blobs, encrypted as a whole with
+///   PASSWORD_ENC_VERSION.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TokenStore {
+    #[serde(default)]
+    tokens: HashMap<String, AccessToken>,
+}
+
+impl TokenStore {
+    fn path() -> PathBuf {
+        let filename = format!("{}_tokens", APP_NAME.read().unwrap().clone());
+        Config::path(filename)
+    }
+
+    fn load() -> TokenStore {
+        let Ok(data) = read_bytes(&Self::path()) else {
+            return Default::default();
+        };
+        let (data, succ, _) = decrypt_vec_or_original(&data, PASSWORD_ENC_VERSION);
+        if succ {
+            if let Ok(store) = serde_json::from_slice::<TokenStore>(&data) {
+                return store;
+            }
+        }
+        log::error!("Failed to load token store");
+        Default::default()
+    }
+
+    fn store(&self) {
+        let Ok(json) = serde_json::to_vec(self) else {
+            return;
+        };
+        let data = encrypt_vec_or_original(&json, PASSWORD_ENC_VERSION, usize::MAX);
+        if let Err(err) = store_bytes(Self::path(), &data) {
+            log::error!("Failed to store token store: {}", err);
+        }
+    }
+
+    ///   None if missing or expired and the refresh hook could not refresh it.
+    pub fn get(name: &str) -> Option<String> {
+        let mut token = TOKEN_STORE.read().unwrap().tokens.get(name).cloned()?;
+        if !token.is_expired() {
+            return Some(std::mem::take(&mut token.token));
+        }
+        let refreshed = TOKEN_REFRESH_HOOK
+            .read()
+            .unwrap()
+            .as_ref()
+            .and_then(|hook| hook(name, &token));
+        token.zeroize_secrets();
+        match refreshed {
+            Some(refreshed) if !refreshed.is_expired() => {
+                let value = refreshed.token.clone();
+                let mut store = TOKEN_STORE.write().unwrap();
+                if let Some(mut old) = store.tokens.insert(name.to_owned(), refreshed) {
+                    old.zeroize_secrets();
+                }
+                store.store();
+                Some(value)
+            }
+            Some(mut refreshed) => {
+                refreshed.zeroize_secrets();
+                log::info!("Access token of {} expired", name);
+                None
+            }
+            None => {
+                log::info!("Access token of {} expired", name);
+                None
+            }
+        }
+    }
+
+    pub fn set(name: &str, token: AccessToken) {
+        let mut store = TOKEN_STORE.write().unwrap();
+        if store.tokens.get(name) == Some(&token) {
+            return;
+        }
+        let old = if token.token.is_empty() {
+            store.tokens.remove(name)
+        } else {
+            store.tokens.insert(name.to_owned(), token)
+        };
+        if let Some(mut old) = old {
+            old.zeroize_secrets();
+        }
+        store.store();
+    }
+
+    pub fn remove(name: &str) {
+        let mut store = TOKEN_STORE.write().unwrap();
+        if let Some(mut old) = store.tokens.remove(name) {
+            old.zeroize_secrets();
+            store.store();
+        }
+    }
+
+    pub fn set_refresh_hook(hook: TokenRefreshHook) {
+        *TOKEN_REFRESH_HOOK.write().unwrap() = Some(hook);
+    }
+
+    ///   Keeps the access token of a cache, which is stored without it, keeping the
+    ///   expiry if unchanged.
+    fn take(name: &str, token: &str) {
+        if token.is_empty() {
+            return;
+        }
+        let same = TOKEN_STORE
+            .read()
+            .unwrap()
+            .tokens
+            .get(name)
+            .map_or(false, |old| old.token == token);
+        if !same {
+            Self::set(
+                name,
+                AccessToken {
+                    token: token.to_owned(),
+                    refresh_token: String::new(),
+                    expire: 0,
+                },
+            );
+        }
+    }
 }
 
+///   Cached session keys expire quickly, they only serve fast reconnects.
+pub const SESSION_KEY_TTL: i64 = 5 * 60 * 1000;
+///   Minimum length of the nonce each side sends for a session with a cached key.
+pub const SESSION_NONCE_LEN: usize = 16;
+
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
-pub struct GroupUser {
-    #[serde(
-        default,
-        deserialize_with = "deserialize_string",
-        skip_serializing_if = "String::is_empty"
-    )]
-    pub name: String,
+struct CachedSessionKey {
+    #[serde(default, deserialize_with = "deserialize_vec_u8")]
+    key: Vec<u8>,
+    ///   sha256 of the peer sign pk the key was negotiated with
+    #[serde(default, deserialize_with = "deserialize_vec_u8")]
+    pk_hash: Vec<u8>,
+    #[serde(default, deserialize_with = "deserialize_i64")]
+    expire: i64,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, Clone)]
-pub struct DeviceGroup {
-    #[serde(
-        default,
-        deserialize_with = "deserialize_string",
-        skip_serializing_if = "String::is_empty"
-    )]
-    pub name: String,
+impl CachedSessionKey {
+    ///   Scrubs an entry removed from the cache.
+    fn zeroize_secrets(&mut self) {
+        self.key.zeroize();
+    }
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, Clone)]
-pub struct Group {
-    #[serde(
-        default,
-        deserialize_with = "deserialize_string",
-        skip_serializing_if = "String::is_empty"
-    )]
-    pub access_token: String,
-    #[serde(default, deserialize_with = "deserialize_vec_groupuser")]
-    pub users: Vec<GroupUser>,
-    #[serde(default, deserialize_with = "deserialize_vec_grouppeer")]
-    pub peers: Vec<GroupPeer>,
-    #[serde(default, deserialize_with = "deserialize_vec_devicegroup")]
-    pub device_groups: Vec<DeviceGroup>,
+///   Symmetric keys negotiated with peers, so that a quick reconnect can skip the
+///   asymmetric handshake. Encrypted as a whole with PASSWORD_ENC_VERSION.
+///   A cached key is never used as is, the counters of `tcp::Encrypt` restart at 0
+///   in every session, see `SessionKeyCache::get`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SessionKeyCache {
+    #[serde(default)]
+    keys: HashMap<String, CachedSessionKey>,
 }
 
-impl Group {
+impl SessionKeyCache {
     fn path() -> PathBuf {
-        let filename = format!("{}_group", APP_NAME.read().unwrap().clone());
+        let filename = format!("{}_session_keys", APP_NAME.read().unwrap().clone());
         Config::path(filename)
     }
 
-    pub fn store(json: String) {
-        if let Ok(mut file) = std::fs::File::create(Self::path()) {
-            let data = compress(json.as_bytes());
-            let max_len = 64 * 1024 * 1024;
-            if data.len() > max_len {
-                ///   maxlen of function decompress
-                return;
-            }
-            if let Ok(data) = symmetric_crypt(&data, true) {
-                file.write_all(&data).ok();
+    fn load() -> SessionKeyCache {
+        let Ok(data) = read_bytes(&Self::path()) else {
+            return Default::default();
+        };
+        let (data, succ, _) = decrypt_vec_or_original(&data, PASSWORD_ENC_VERSION);
+        if succ {
+            if let Ok(mut cache) = serde_json::from_slice::<SessionKeyCache>(&data) {
+                cache.remove_expired(crate::get_time());
+                return cache;
             }
+        }
+        Default::default()
+    }
+
+    fn store(&self) {
+        let Ok(json) = serde_json::to_vec(self) else {
+            return;
         };
+        let data = encrypt_vec_or_original(&json, PASSWORD_ENC_VERSION, usize::MAX);
+        if let Err(err) = store_bytes(Self::path(), &data) {
+            log::error!("Failed to store session key cache: {}", err);
+        }
     }
 
-    pub fn load() -> Self {
-        if let Ok(mut file) = std::fs::File::open(Self::path()) {
-            let mut data = vec![];
-            if file.read_to_end(&mut data).is_ok() {
-                if let Ok(data) = symmetric_crypt(&data, false) {
-                    let data = decompress(&data);
-                    if let Ok(group) = serde_json::from_str::<Self>(&String::from_utf8_lossy(&data))
-                    {
-                        return group;
-                    }
-                }
+    fn remove_expired(&mut self, now: i64) {
+        self.keys.retain(|_, k| {
+            if k.expire > now {
+                return true;
             }
-        };
-        Self::remove();
-        Self::default()
+            k.zeroize_secrets();
+            false
+        });
     }
 
-    pub fn remove() {
-        std::fs::remove_file(Self::path()).ok();
+    fn pk_hash(pk: &[u8]) -> Vec<u8> {
+        sodiumoxide::crypto::hash::sha256::hash(pk).0.to_vec()
+    }
+
+    ///   The key of a new session with `peer_id`, derived from the cached key and
+    ///   the random nonces both sides send for this session, so no nonce of a
+    ///   previous session is reused when the counters restart at 0.
+    ///   None if missing, expired or negotiated with another pk of `peer_id`,
+    ///   the entry is dropped in the latter cases, or if a nonce is too short.
+    pub fn get(
+        peer_id: &str,
+        peer_pk: &[u8],
+        client_nonce: &[u8],
+        server_nonce: &[u8],
+    ) -> Option<Vec<u8>> {
+        if client_nonce.len() < SESSION_NONCE_LEN || server_nonce.len() < SESSION_NONCE_LEN {
+            return None;
+        }
+        let mut cache = SESSION_KEY_CACHE.write().unwrap();
+        let entry = cache.keys.get(peer_id)?;
+        if entry.expire > crate::get_time() && entry.pk_hash == Self::pk_hash(peer_pk) {
+            let key = hmacsha256::Key::from_slice(&entry.key)?;
+            let mut data = b"session-key-cache".to_vec();
+            for nonce in [client_nonce, server_nonce] {
+                data.extend_from_slice(&(nonce.len() as u32).to_le_bytes());
+                data.extend_from_slice(nonce);
+            }
+            return Some(hmacsha256::authenticate(&data, &key).0.to_vec());
+        }
+        if let Some(mut old) = cache.keys.remove(peer_id) {
+            old.zeroize_secrets();
+        }
+        cache.store();
+        None
+    }
+
+    pub fn set(peer_id: &str, peer_pk: &[u8], key: &[u8]) {
+        let mut cache = SESSION_KEY_CACHE.write().unwrap();
+        let now = crate::get_time();
+        cache.remove_expired(now);
+        let old = cache.keys.insert(
+            peer_id.to_owned(),
+            CachedSessionKey {
+                key: key.to_vec(),
+                pk_hash: Self::pk_hash(peer_pk),
+                expire: now + SESSION_KEY_TTL,
+            },
+        );
+        if let Some(mut old) = old {
+            old.zeroize_secrets();
+        }
+        cache.store();
+    }
+
+    ///   E.g. the peer pk changed or the session failed with the cached key.
+    pub fn remove(peer_id: &str) {
+        let mut cache = SESSION_KEY_CACHE.write().unwrap();
+        if let Some(mut old) = cache.keys.remove(peer_id) {
+            old.zeroize_secrets();
+            cache.store();
+        }
+    }
+
+    pub fn clear() {
+        let mut cache = SESSION_KEY_CACHE.write().unwrap();
+        if !cache.keys.is_empty() {
+            cache.keys.values_mut().for_each(CachedSessionKey::zeroize_secrets);
+            cache.keys.clear();
+            cache.store();
+        }
     }
 }
 
@@ -2542,6 +5195,16 @@ pub struct TrustedDevice {
     pub id: String,
     pub name: String,
     pub platform: String,
+    ///   sign pk of the controlling device
+    #[serde(default)]
+    pub pk: Bytes,
+    ///   signature of the controlling device over (hwid, id, time, pk)
+    #[serde(default)]
+    pub signature: Bytes,
+    ///   signature of this machine over the same data when the device was added, the
+    ///   trust anchor checked on load, as `pk` and `signature` come with the record
+    #[serde(default)]
+    pub endorsement: Bytes,
 }
 
 impl TrustedDevice {
@@ -2549,6 +5212,56 @@ impl TrustedDevice {
         const DAYS_90: i64 = 90 * 24 * 60 * 60 * 1000;
         self.time + DAYS_90 < crate::get_time()
     }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = self.hwid.to_vec();
+        data.extend_from_slice(&(self.id.len() as u32).to_le_bytes());
+        data.extend_from_slice(self.id.as_bytes());
+        data.extend_from_slice(&self.time.to_le_bytes());
+        data.extend_from_slice(&self.pk);
+        data
+    }
+
+    ///   Controlling side, attest with its own sign key pair.
+    pub fn sign(&mut self, sk: &[u8]) -> bool {
+        let Some(sk) = sign::SecretKey::from_slice(sk) else {
+            return false;
+        };
+        self.pk = sk.public_key().0.to_vec().into();
+        self.signature = sign::sign_detached(&self.signed_data(), &sk).to_bytes().to_vec().into();
+        true
+    }
+
+    ///   Controlled side, only that the controlling device attested the record with
+    ///   `pk`, which comes with the record. The caller also has to check `pk` against
+    ///   the key the device presented when it was added.
+    pub fn verify(&self) -> bool {
+        Self::verify_with(&self.signature, &self.signed_data(), &self.pk)
+    }
+
+    fn verify_with(signature: &[u8], data: &[u8], pk: &[u8]) -> bool {
+        let (Some(pk), Ok(sig)) = (
+            sign::PublicKey::from_slice(pk),
+            sign::Signature::from_bytes(signature),
+        ) else {
+            return false;
+        };
+        sign::verify_detached(&sig, data, &pk)
+    }
+
+    ///   Controlled side, with the sign key pair of this machine when adding the device.
+    fn endorse(&mut self, sk: &[u8]) -> bool {
+        let Some(sk) = sign::SecretKey::from_slice(sk) else {
+            return false;
+        };
+        self.endorsement = sign::sign_detached(&self.signed_data(), &sk).to_bytes().to_vec().into();
+        true
+    }
+
+    ///   Endorsed by this machine with the key pair of `pk`.
+    fn is_endorsed(&self, pk: &[u8]) -> bool {
+        Self::verify_with(&self.endorsement, &self.signed_data(), pk)
+    }
 }
 
 deserialize_default!(deserialize_string, String);
@@ -2564,37 +5277,28 @@ deserialize_default!(deserialize_vec_groupuser, Vec<GroupUser>);
 deserialize_default!(deserialize_vec_grouppeer, Vec<GroupPeer>);
 deserialize_default!(deserialize_vec_devicegroup, Vec<DeviceGroup>);
 deserialize_default!(deserialize_keypair, KeyPair);
+deserialize_default!(deserialize_option_keypair, Option<KeyPair>);
+deserialize_default!(deserialize_i64, i64);
 deserialize_default!(deserialize_size, Size);
 deserialize_default!(deserialize_hashmap_string_string, HashMap<String, String>);
 deserialize_default!(deserialize_hashmap_string_bool,  HashMap<String, bool>);
+deserialize_default!(deserialize_hashmap_string_i64, HashMap<String, i64>);
 deserialize_default!(deserialize_hashmap_resolutions, HashMap<String, Resolution>);
+deserialize_default!(deserialize_hashmap_api_cache, HashMap<String, ApiCache>);
 
-#[inline]
-fn get_or(
-    a: &RwLock<HashMap<String, String>>,
-    b: &HashMap<String, String>,
-    c: &RwLock<HashMap<String, String>>,
-    k: &str,
-) -> Option<String> {
-    a.read()
-        .unwrap()
-        .get(k)
-        .or(b.get(k))
-        .or(c.read().unwrap().get(k))
-        .cloned()
-}
-
-#[inline]
-fn is_option_can_save(
-    overwrite: &RwLock<HashMap<String, String>>,
-    k: &str,
-    defaults: &RwLock<HashMap<String, String>>,
-    v: &str,
-) -> bool {
-    if overwrite.read().unwrap().contains_key(k)
-        || defaults.read().unwrap().get(k).map_or(false, |x| x == v)
-    {
-        return false;
+///   Reject values that would be misinterpreted later, e.g. a broken access rule list.
+fn is_option_valid(k: &str, v: &str) -> bool {
+    if k == keys::OPTION_WHITELIST {
+        if let Err(err) = crate::acl::validate(v) {
+            log::error!("Invalid {}: {}", k, err);
+            return false;
+        }
+    }
+    if k == keys::OPTION_LOG_LEVEL && !v.is_empty() {
+        if let Err(err) = flexi_logger::LogSpecification::parse(v) {
+            log::error!("Invalid {}: {}", k, err);
+            return false;
+        }
     }
     true
 }
@@ -2651,345 +5355,11 @@ pub fn is_disable_installation() -> bool {
     is_some_hard_opton("disable-installation")
 }
 
-///   This function must be kept the same as the one in flutter and sciter code.
-///   flutter: flutter/lib/common.dart -> option2bool()
-///   sciter: Does not have the function, but it should be kept the same.
-pub fn option2bool(option: &str, value: &str) -> bool {
-    if option.starts_with("enable-") {
-        value != "N"
-    } else if option.starts_with("allow-")
-        || option == "stop-service"
-        || option == keys::OPTION_DIRECT_SERVER
-        || option == "force-always-relay"
-    {
-        value == "Y"
-    } else {
-        value != "N"
-    }
-}
-
 pub fn use_ws() -> bool {
     let option = keys::OPTION_ALLOW_WEBSOCKET;
     option2bool(option, &Config::get_option(option))
 }
 
-pub mod keys {
-    pub const OPTION_VIEW_ONLY: &str = "view_only";
-    pub const OPTION_SHOW_MONITORS_TOOLBAR: &str = "show_monitors_toolbar";
-    pub const OPTION_COLLAPSE_TOOLBAR: &str = "collapse_toolbar";
-    pub const OPTION_SHOW_REMOTE_CURSOR: &str = "show_remote_cursor";
-    pub const OPTION_FOLLOW_REMOTE_CURSOR: &str = "follow_remote_cursor";
-    pub const OPTION_FOLLOW_REMOTE_WINDOW: &str = "follow_remote_window";
-    pub const OPTION_ZOOM_CURSOR: &str = "zoom-cursor";
-    pub const OPTION_SHOW_QUALITY_MONITOR: &str = "show_quality_monitor";
-    pub const OPTION_DISABLE_AUDIO: &str = "disable_audio";
-    pub const OPTION_ENABLE_REMOTE_PRINTER: &str = "enable-remote-printer";
-    pub const OPTION_ENABLE_FILE_COPY_PASTE: &str = "enable-file-copy-paste";
-    pub const OPTION_DISABLE_CLIPBOARD: &str = "disable_clipboard";
-    pub const OPTION_LOCK_AFTER_SESSION_END: &str = "lock_after_session_end";
-    pub const OPTION_PRIVACY_MODE: &str = "privacy_mode";
-    pub const OPTION_TOUCH_MODE: &str = "touch-mode";
-    pub const OPTION_I444: &str = "i444";
-    pub const OPTION_REVERSE_MOUSE_WHEEL: &str = "reverse_mouse_wheel";
-    pub const OPTION_SWAP_LEFT_RIGHT_MOUSE: &str = "swap-left-right-mouse";
-    pub const OPTION_DISPLAYS_AS_INDIVIDUAL_WINDOWS: &str = "displays_as_individual_windows";
-    pub const OPTION_USE_ALL_MY_DISPLAYS_FOR_THE_REMOTE_SESSION: &str =
-        "use_all_my_displays_for_the_remote_session";
-    pub const OPTION_VIEW_STYLE: &str = "view_style";
-    pub const OPTION_SCROLL_STYLE: &str = "scroll_style";
-    pub const OPTION_IMAGE_QUALITY: &str = "image_quality";
-    pub const OPTION_CUSTOM_IMAGE_QUALITY: &str = "custom_image_quality";
-    pub const OPTION_CUSTOM_FPS: &str = "custom-fps";
-    pub const OPTION_CODEC_PREFERENCE: &str = "codec-preference";
-    pub const OPTION_SYNC_INIT_CLIPBOARD: &str = "sync-init-clipboard";
-    pub const OPTION_THEME: &str = "theme";
-    pub const OPTION_LANGUAGE: &str = "lang";
-    pub const OPTION_REMOTE_MENUBAR_DRAG_LEFT: &str = "remote-menubar-drag-left";
-    pub const OPTION_REMOTE_MENUBAR_DRAG_RIGHT: &str = "remote-menubar-drag-right";
-    pub const OPTION_HIDE_AB_TAGS_PANEL: &str = "hideAbTagsPanel";
-    pub const OPTION_ENABLE_CONFIRM_CLOSING_TABS: &str = "enable-confirm-closing-tabs";
-    pub const OPTION_ENABLE_OPEN_NEW_CONNECTIONS_IN_TABS: &str =
-        "enable-open-new-connections-in-tabs";
-    pub const OPTION_TEXTURE_RENDER: &str = "use-texture-render";
-    pub const OPTION_ALLOW_D3D_RENDER: &str = "allow-d3d-render";
-    pub const OPTION_ENABLE_CHECK_UPDATE: &str = "enable-check-update";
-    pub const OPTION_ALLOW_AUTO_UPDATE: &str = "allow-auto-update";
-    pub const OPTION_SYNC_AB_WITH_RECENT_SESSIONS: &str = "sync-ab-with-recent-sessions";
-    pub const OPTION_SYNC_AB_TAGS: &str = "sync-ab-tags";
-    pub const OPTION_FILTER_AB_BY_INTERSECTION: &str = "filter-ab-by-intersection";
-    pub const OPTION_ACCESS_MODE: &str = "access-mode";
-    pub const OPTION_ENABLE_KEYBOARD: &str = "enable-keyboard";
-    pub const OPTION_ENABLE_CLIPBOARD: &str = "enable-clipboard";
-    pub const OPTION_ENABLE_FILE_TRANSFER: &str = "enable-file-transfer";
-    pub const OPTION_ENABLE_CAMERA: &str = "enable-camera";
-    pub const OPTION_ENABLE_TERMINAL: &str = "enable-terminal";
-    pub const OPTION_TERMINAL_PERSISTENT: &str = "terminal-persistent";
-    pub const OPTION_ENABLE_AUDIO: &str = "enable-audio";
-    pub const OPTION_ENABLE_TUNNEL: &str = "enable-tunnel";
-    pub const OPTION_ENABLE_REMOTE_RESTART: &str = "enable-remote-restart";
-    pub const OPTION_ENABLE_RECORD_SESSION: &str = "enable-record-session";
-    pub const OPTION_ENABLE_BLOCK_INPUT: &str = "enable-block-input";
-    pub const OPTION_ALLOW_REMOTE_CONFIG_MODIFICATION: &str = "allow-remote-config-modification";
-    pub const OPTION_ALLOW_NUMERNIC_ONE_TIME_PASSWORD: &str = "allow-numeric-one-time-password";
-    pub const OPTION_ENABLE_LAN_DISCOVERY: &str = "enable-lan-discovery";
-    pub const OPTION_DIRECT_SERVER: &str = "direct-server";
-    pub const OPTION_DIRECT_ACCESS_PORT: &str = "direct-access-port";
-    pub const OPTION_WHITELIST: &str = "whitelist";
-    pub const OPTION_ALLOW_AUTO_DISCONNECT: &str = "allow-auto-disconnect";
-    pub const OPTION_AUTO_DISCONNECT_TIMEOUT: &str = "auto-disconnect-timeout";
-    pub const OPTION_ALLOW_ONLY_CONN_WINDOW_OPEN: &str = "allow-only-conn-window-open";
-    pub const OPTION_ALLOW_AUTO_RECORD_INCOMING: &str = "allow-auto-record-incoming";
-    pub const OPTION_ALLOW_AUTO_RECORD_OUTGOING: &str = "allow-auto-record-outgoing";
-    pub const OPTION_VIDEO_SAVE_DIRECTORY: &str = "video-save-directory";
-    pub const OPTION_ENABLE_ABR: &str = "enable-abr";
-    pub const OPTION_ALLOW_REMOVE_WALLPAPER: &str = "allow-remove-wallpaper";
-    pub const OPTION_ALLOW_ALWAYS_SOFTWARE_RENDER: &str = "allow-always-software-render";
-    pub const OPTION_ALLOW_LINUX_HEADLESS: &str = "allow-linux-headless";
-    pub const OPTION_ENABLE_HWCODEC: &str = "enable-hwcodec";
-    pub const OPTION_APPROVE_MODE: &str = "approve-mode";
-    pub const OPTION_VERIFICATION_METHOD: &str = "verification-method";
-    pub const OPTION_TEMPORARY_PASSWORD_LENGTH: &str = "temporary-password-length";
-    pub const OPTION_CUSTOM_RENDEZVOUS_SERVER: &str = "custom-rendezvous-server";
-    pub const OPTION_API_SERVER: &str = "api-server";
-    pub const OPTION_KEY: &str = "key";
-    pub const OPTION_ALLOW_WEBSOCKET: &str = "allow-websocket";
-    pub const OPTION_PRESET_ADDRESS_BOOK_NAME: &str = "preset-address-book-name";
-    pub const OPTION_PRESET_ADDRESS_BOOK_TAG: &str = "preset-address-book-tag";
-    pub const OPTION_PRESET_ADDRESS_BOOK_ALIAS: &str = "preset-address-book-alias";
-    pub const OPTION_PRESET_ADDRESS_BOOK_PASSWORD: &str = "preset-address-book-password";
-    pub const OPTION_PRESET_ADDRESS_BOOK_NOTE: &str = "preset-address-book-note";
-    pub const OPTION_PRESET_DEVICE_USERNAME: &str = "preset-device-username";
-    pub const OPTION_PRESET_DEVICE_NAME: &str = "preset-device-name";
-    pub const OPTION_PRESET_NOTE: &str = "preset-note";
-    pub const OPTION_ENABLE_DIRECTX_CAPTURE: &str = "enable-directx-capture";
-    pub const OPTION_ENABLE_ANDROID_SOFTWARE_ENCODING_HALF_SCALE: &str =
-        "enable-android-software-encoding-half-scale";
-    pub const OPTION_ENABLE_TRUSTED_DEVICES: &str = "enable-trusted-devices";
-    pub const OPTION_AV1_TEST: &str = "av1-test";
-    pub const OPTION_TRACKPAD_SPEED: &str = "trackpad-speed";
-    pub const OPTION_REGISTER_DEVICE: &str = "register-device";
-    pub const OPTION_RELAY_SERVER: &str = "relay-server";
-    pub const OPTION_SHOW_VIRTUAL_MOUSE: &str = "show-virtual-mouse";
-    ///   joystick is the virtual mouse.
-    ///   So `OPTION_SHOW_VIRTUAL_MOUSE` should also be set if `OPTION_SHOW_VIRTUAL_JOYSTICK` is set.
-    pub const OPTION_SHOW_VIRTUAL_JOYSTICK: &str = "show-virtual-joystick";
-
-    ///   built-in options
-    pub const OPTION_DISPLAY_NAME: &str = "display-name";
-    pub const OPTION_DISABLE_UDP: &str = "disable-udp";
-    pub const OPTION_PRESET_DEVICE_GROUP_NAME: &str = "preset-device-group-name";
-    pub const OPTION_PRESET_USERNAME: &str = "preset-user-name";
-    pub const OPTION_PRESET_STRATEGY_NAME: &str = "preset-strategy-name";
-    pub const OPTION_REMOVE_PRESET_PASSWORD_WARNING: &str = "remove-preset-password-warning";
-    pub const OPTION_HIDE_SECURITY_SETTINGS: &str = "hide-security-settings";
-    pub const OPTION_HIDE_NETWORK_SETTINGS: &str = "hide-network-settings";
-    pub const OPTION_HIDE_SERVER_SETTINGS: &str = "hide-server-settings";
-    pub const OPTION_HIDE_PROXY_SETTINGS: &str = "hide-proxy-settings";
-    pub const OPTION_HIDE_REMOTE_PRINTER_SETTINGS: &str = "hide-remote-printer-settings";
-    pub const OPTION_HIDE_WEBSOCKET_SETTINGS: &str = "hide-websocket-settings";
-
-    ///   Connection punch-through options
-    pub const OPTION_ENABLE_UDP_PUNCH: &str = "enable-udp-punch";
-    pub const OPTION_ENABLE_IPV6_PUNCH: &str = "enable-ipv6-punch";
-    pub const OPTION_HIDE_USERNAME_ON_CARD: &str = "hide-username-on-card";
-    pub const OPTION_HIDE_HELP_CARDS: &str = "hide-help-cards";
-    pub const OPTION_DEFAULT_CONNECT_PASSWORD: &str = "default-connect-password";
-    pub const OPTION_HIDE_TRAY: &str = "hide-tray";
-    pub const OPTION_ONE_WAY_CLIPBOARD_REDIRECTION: &str = "one-way-clipboard-redirection";
-    pub const OPTION_ALLOW_LOGON_SCREEN_PASSWORD: &str = "allow-logon-screen-password";
-    pub const OPTION_ONE_WAY_FILE_TRANSFER: &str = "one-way-file-transfer";
-    pub const OPTION_ALLOW_HTTPS_21114: &str = "allow-https-2114";
-    pub const OPTION_ALLOW_HOSTNAME_AS_ID: &str = "allow-hostname-as-id";
-    pub const OPTION_HIDE_POWERED_BY_ME: &str = "hide-powered-by-me";
-    pub const OPTION_MAIN_WINDOW_ALWAYS_ON_TOP: &str = "main-window-always-on-top";
-
-    ///   flutter local options
-    pub const OPTION_FLUTTER_REMOTE_MENUBAR_STATE: &str = "remoteMenubarState";
-    pub const OPTION_FLUTTER_PEER_SORTING: &str = "peer-sorting";
-    pub const OPTION_FLUTTER_PEER_TAB_INDEX: &str = "peer-tab-index";
-    pub const OPTION_FLUTTER_PEER_TAB_ORDER: &str = "peer-tab-order";
-    pub const OPTION_FLUTTER_PEER_TAB_VISIBLE: &str = "peer-tab-visible";
-    pub const OPTION_FLUTTER_PEER_CARD_UI_TYLE: &str = "peer-card-ui-type";
-    pub const OPTION_FLUTTER_CURRENT_AB_NAME: &str = "current-ab-name";
-    pub const OPTION_ALLOW_REMOTE_CM_MODIFICATION: &str = "allow-remote-cm-modification";
-
-    pub const OPTION_PRINTER_INCOMING_JOB_ACTION: &str = "printer-incomming-job-action";
-    pub const OPTION_PRINTER_ALLOW_AUTO_PRINT: &str = "allow-printer-auto-print";
-    pub const OPTION_PRINTER_SELECTED_NAME: &str = "printer-selected-name";
-
-    ///   android floating window options
-    pub const OPTION_DISABLE_FLOATING_WINDOW: &str = "disable-floating-window";
-    pub const OPTION_FLOATING_WINDOW_SIZE: &str = "floating-window-size";
-    pub const OPTION_FLOATING_WINDOW_UNTOUCHABLE: &str = "floating-window-untouchable";
-    pub const OPTION_FLOATING_WINDOW_TRANSPARENCY: &str = "floating-window-transparency";
-    pub const OPTION_FLOATING_WINDOW_SVG: &str = "floating-window-svg";
-
-    ///   android keep screen on
-    pub const OPTION_KEEP_SCREEN_ON: &str = "keep-screen-on";
-
-    pub const OPTION_DISABLE_GROUP_PANEL: &str = "disable-group-panel";
-    pub const OPTION_DISABLE_DISCOVERY_PANEL: &str = "disable-discovery-panel";
-    pub const OPTION_PRE_ELEVATE_SERVICE: &str = "pre-elevate-service";
-
-    ///   proxy settings
-    ///   The following options are not real keys, they are just used for custom client advanced settings.
-    ///   The real keys are in Config2::socks.
-    pub const OPTION_PROXY_URL: &str = "proxy-url";
-    pub const OPTION_PROXY_USERNAME: &str = "proxy-username";
-    pub const OPTION_PROXY_PASSWORD: &str = "proxy-password";
-
-    ///   DEFAULT_DISPLAY_SETTINGS, OVERWRITE_DISPLAY_SETTINGS
-    pub const KEYS_DISPLAY_SETTINGS: &[&str] = &[
-        OPTION_VIEW_ONLY,
-        OPTION_SHOW_MONITORS_TOOLBAR,
-        OPTION_COLLAPSE_TOOLBAR,
-        OPTION_SHOW_REMOTE_CURSOR,
-        OPTION_FOLLOW_REMOTE_CURSOR,
-        OPTION_FOLLOW_REMOTE_WINDOW,
-        OPTION_ZOOM_CURSOR,
-        OPTION_SHOW_QUALITY_MONITOR,
-        OPTION_DISABLE_AUDIO,
-        OPTION_ENABLE_FILE_COPY_PASTE,
-        OPTION_DISABLE_CLIPBOARD,
-        OPTION_LOCK_AFTER_SESSION_END,
-        OPTION_PRIVACY_MODE,
-        OPTION_TOUCH_MODE,
-        OPTION_I444,
-        OPTION_REVERSE_MOUSE_WHEEL,
-        OPTION_SWAP_LEFT_RIGHT_MOUSE,
-        OPTION_DISPLAYS_AS_INDIVIDUAL_WINDOWS,
-        OPTION_USE_ALL_MY_DISPLAYS_FOR_THE_REMOTE_SESSION,
-        OPTION_VIEW_STYLE,
-        OPTION_TERMINAL_PERSISTENT,
-        OPTION_SCROLL_STYLE,
-        OPTION_IMAGE_QUALITY,
-        OPTION_CUSTOM_IMAGE_QUALITY,
-        OPTION_CUSTOM_FPS,
-        OPTION_CODEC_PREFERENCE,
-        OPTION_SYNC_INIT_CLIPBOARD,
-        OPTION_TRACKPAD_SPEED,
-    ];
-    ///   DEFAULT_LOCAL_SETTINGS, OVERWRITE_LOCAL_SETTINGS
-    pub const KEYS_LOCAL_SETTINGS: &[&str] = &[
-        OPTION_THEME,
-        OPTION_LANGUAGE,
-        OPTION_ENABLE_CONFIRM_CLOSING_TABS,
-        OPTION_ENABLE_OPEN_NEW_CONNECTIONS_IN_TABS,
-        OPTION_TEXTURE_RENDER,
-        OPTION_ALLOW_D3D_RENDER,
-        OPTION_SYNC_AB_WITH_RECENT_SESSIONS,
-        OPTION_SYNC_AB_TAGS,
-        OPTION_FILTER_AB_BY_INTERSECTION,
-        OPTION_REMOTE_MENUBAR_DRAG_LEFT,
-        OPTION_REMOTE_MENUBAR_DRAG_RIGHT,
-        OPTION_HIDE_AB_TAGS_PANEL,
-        OPTION_FLUTTER_REMOTE_MENUBAR_STATE,
-        OPTION_FLUTTER_PEER_SORTING,
-        OPTION_FLUTTER_PEER_TAB_INDEX,
-        OPTION_FLUTTER_PEER_TAB_ORDER,
-        OPTION_FLUTTER_PEER_TAB_VISIBLE,
-        OPTION_FLUTTER_PEER_CARD_UI_TYLE,
-        OPTION_FLUTTER_CURRENT_AB_NAME,
-        OPTION_DISABLE_FLOATING_WINDOW,
-        OPTION_FLOATING_WINDOW_SIZE,
-        OPTION_FLOATING_WINDOW_UNTOUCHABLE,
-        OPTION_FLOATING_WINDOW_TRANSPARENCY,
-        OPTION_FLOATING_WINDOW_SVG,
-        OPTION_KEEP_SCREEN_ON,
-        OPTION_DISABLE_GROUP_PANEL,
-        OPTION_DISABLE_DISCOVERY_PANEL,
-        OPTION_PRE_ELEVATE_SERVICE,
-        OPTION_ALLOW_REMOTE_CM_MODIFICATION,
-        OPTION_ALLOW_AUTO_RECORD_OUTGOING,
-        OPTION_VIDEO_SAVE_DIRECTORY,
-        OPTION_ENABLE_UDP_PUNCH,
-        OPTION_ENABLE_IPV6_PUNCH,
-        OPTION_TOUCH_MODE,
-        OPTION_SHOW_VIRTUAL_MOUSE,
-        OPTION_SHOW_VIRTUAL_JOYSTICK,
-    ];
-    ///   DEFAULT_SETTINGS, OVERWRITE_SETTINGS
-    pub const KEYS_SETTINGS: &[&str] = &[
-        OPTION_ACCESS_MODE,
-        OPTION_ENABLE_KEYBOARD,
-        OPTION_ENABLE_CLIPBOARD,
-        OPTION_ENABLE_FILE_TRANSFER,
-        OPTION_ENABLE_CAMERA,
-        OPTION_ENABLE_TERMINAL,
-        OPTION_ENABLE_REMOTE_PRINTER,
-        OPTION_ENABLE_AUDIO,
-        OPTION_ENABLE_TUNNEL,
-        OPTION_ENABLE_REMOTE_RESTART,
-        OPTION_ENABLE_RECORD_SESSION,
-        OPTION_ENABLE_BLOCK_INPUT,
-        OPTION_ALLOW_REMOTE_CONFIG_MODIFICATION,
-        OPTION_ALLOW_NUMERNIC_ONE_TIME_PASSWORD,
-        OPTION_ENABLE_LAN_DISCOVERY,
-        OPTION_DIRECT_SERVER,
-        OPTION_DIRECT_ACCESS_PORT,
-        OPTION_WHITELIST,
-        OPTION_ALLOW_AUTO_DISCONNECT,
-        OPTION_AUTO_DISCONNECT_TIMEOUT,
-        OPTION_ALLOW_ONLY_CONN_WINDOW_OPEN,
-        OPTION_ALLOW_AUTO_RECORD_INCOMING,
-        OPTION_ENABLE_ABR,
-        OPTION_ALLOW_REMOVE_WALLPAPER,
-        OPTION_ALLOW_ALWAYS_SOFTWARE_RENDER,
-        OPTION_ALLOW_LINUX_HEADLESS,
-        OPTION_ENABLE_HWCODEC,
-        OPTION_APPROVE_MODE,
-        OPTION_VERIFICATION_METHOD,
-        OPTION_TEMPORARY_PASSWORD_LENGTH,
-        OPTION_PROXY_URL,
-        OPTION_PROXY_USERNAME,
-        OPTION_PROXY_PASSWORD,
-        OPTION_CUSTOM_RENDEZVOUS_SERVER,
-        OPTION_API_SERVER,
-        OPTION_KEY,
-        OPTION_ALLOW_WEBSOCKET,
-        OPTION_PRESET_ADDRESS_BOOK_NAME,
-        OPTION_PRESET_ADDRESS_BOOK_TAG,
-        OPTION_PRESET_ADDRESS_BOOK_ALIAS,
-        OPTION_PRESET_ADDRESS_BOOK_PASSWORD,
-        OPTION_PRESET_ADDRESS_BOOK_NOTE,
-        OPTION_PRESET_DEVICE_USERNAME,
-        OPTION_PRESET_DEVICE_NAME,
-        OPTION_PRESET_NOTE,
-        OPTION_ENABLE_DIRECTX_CAPTURE,
-        OPTION_ENABLE_ANDROID_SOFTWARE_ENCODING_HALF_SCALE,
-        OPTION_ENABLE_TRUSTED_DEVICES,
-        OPTION_RELAY_SERVER,
-    ];
-
-    ///   BUILDIN_SETTINGS
-    pub const KEYS_BUILDIN_SETTINGS: &[&str] = &[
-        OPTION_DISPLAY_NAME,
-        OPTION_DISABLE_UDP,
-        OPTION_PRESET_DEVICE_GROUP_NAME,
-        OPTION_PRESET_USERNAME,
-        OPTION_PRESET_STRATEGY_NAME,
-        OPTION_REMOVE_PRESET_PASSWORD_WARNING,
-        OPTION_HIDE_SECURITY_SETTINGS,
-        OPTION_HIDE_NETWORK_SETTINGS,
-        OPTION_HIDE_SERVER_SETTINGS,
-        OPTION_HIDE_PROXY_SETTINGS,
-        OPTION_HIDE_REMOTE_PRINTER_SETTINGS,
-        OPTION_HIDE_WEBSOCKET_SETTINGS,
-        OPTION_HIDE_USERNAME_ON_CARD,
-        OPTION_HIDE_HELP_CARDS,
-        OPTION_DEFAULT_CONNECT_PASSWORD,
-        OPTION_HIDE_TRAY,
-        OPTION_ONE_WAY_CLIPBOARD_REDIRECTION,
-        OPTION_ALLOW_LOGON_SCREEN_PASSWORD,
-        OPTION_ONE_WAY_FILE_TRANSFER,
-        OPTION_ALLOW_HTTPS_21114,
-        OPTION_ALLOW_HOSTNAME_AS_ID,
-        OPTION_REGISTER_DEVICE,
-        OPTION_HIDE_POWERED_BY_ME,
-        OPTION_MAIN_WINDOW_ALWAYS_ON_TOP,
-    ];
-}
-
 pub fn common_load<
     T: serde::Serialize + serde::de::DeserializeOwned + Default + std::fmt::Debug,
 >(
@@ -3206,6 +5576,137 @@ mod tests {
         LOCAL_CONFIG.write().unwrap().options.clear();
     }
 
+    #[test]
+    fn test_config_integrity() {
+        let _t = test_config();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("integrity.toml");
+        fs::write(&path, "id = \"123\"\npassword = \"abc\"\n").unwrap();
+        assert_eq!(check_config_integrity(&path), ConfigIntegrity::Unsigned);
+        sign_config_file(&path).unwrap();
+        assert_eq!(check_config_integrity(&path), ConfigIntegrity::Ok);
+        ///   signing again replaces the old hmac
+        sign_config_file(&path).unwrap();
+        assert_eq!(check_config_integrity(&path), ConfigIntegrity::Ok);
+        assert_eq!(
+            fs::read_to_string(&path)
+                .unwrap()
+                .matches(HMAC_LINE_PREFIX)
+                .count(),
+            1
+        );
+        let signed = fs::read_to_string(&path).unwrap();
+        let content = signed.replace("abc", "abd");
+        fs::write(&path, content).unwrap();
+        assert_eq!(check_config_integrity(&path), ConfigIntegrity::TamperDetected);
+        ///   the hmac line removed
+        fs::write(&path, split_hmac_line(&signed).0).unwrap();
+        assert_eq!(check_config_integrity(&path), ConfigIntegrity::TamperDetected);
+        ///   the key is not derived from the uuid, and kept after a restart
+        reset();
+        fs::write(&path, &signed).unwrap();
+        assert_eq!(check_config_integrity(&path), ConfigIntegrity::Ok);
+    }
+
+    #[test]
+    fn test_trusted_device_signature() {
+        let (_, sk) = sign::gen_keypair();
+        let mut device = TrustedDevice {
+            hwid: vec![1, 2, 3].into(),
+            time: crate::get_time(),
+            id: "123456789".to_owned(),
+            ..Default::default()
+        };
+        assert!(!device.verify());
+        assert!(device.sign(&sk.0));
+        assert!(device.verify());
+        ///   a record signed by any key verifies, only the endorsement is trusted
+        let (pk, own) = sign::gen_keypair();
+        assert!(!device.is_endorsed(&pk.0));
+        assert!(device.endorse(&own.0));
+        assert!(device.is_endorsed(&pk.0));
+        device.id = "987654321".to_owned();
+        assert!(!device.verify());
+        assert!(!device.is_endorsed(&pk.0));
+    }
+
+    #[test]
+    fn test_trusted_devices_endorsed() {
+        let _t = test_config();
+        let (_, sk) = sign::gen_keypair();
+        let device = |hwid: u8| {
+            let mut device = TrustedDevice {
+                hwid: vec![hwid].into(),
+                time: crate::get_time(),
+                id: "123456789".to_owned(),
+                ..Default::default()
+            };
+            device.sign(&sk.0);
+            device
+        };
+        ///   a legacy list is kept and endorsed
+        let legacy = serde_json::to_string(&vec![device(1)]).unwrap();
+        CONFIG2.write().unwrap().trusted_devices =
+            encrypt_str_or_original(&legacy, PASSWORD_ENC_VERSION, usize::MAX);
+        assert_eq!(Config::get_trusted_devices().len(), 1);
+        assert!(CONFIG2.read().unwrap().trusted_devices_endorsed);
+        Config::add_trusted_device(device(2));
+        ///   a record injected afterwards, signed by the attacker's own key
+        let mut devices = Config::get_trusted_devices();
+        devices.push(device(3));
+        let json = serde_json::to_string(&devices).unwrap();
+        CONFIG2.write().unwrap().trusted_devices =
+            encrypt_str_or_original(&json, PASSWORD_ENC_VERSION, usize::MAX);
+        TRUSTED_DEVICES.write().unwrap().1 = false;
+        let hwids: Vec<_> = Config::get_trusted_devices()
+            .into_iter()
+            .map(|d| d.hwid)
+            .collect();
+        assert_eq!(hwids, vec![Bytes::from(vec![1u8]), Bytes::from(vec![2u8])]);
+        ///   unsigned records are accepted, records with a bad signature are not
+        Config::add_trusted_device(TrustedDevice {
+            hwid: vec![4].into(),
+            time: crate::get_time(),
+            id: "123456789".to_owned(),
+            ..Default::default()
+        });
+        let mut forged = device(5);
+        forged.id = "987654321".to_owned();
+        Config::add_trusted_device(forged);
+        let hwids: Vec<_> = Config::get_trusted_devices()
+            .into_iter()
+            .map(|d| d.hwid)
+            .collect();
+        assert_eq!(
+            hwids,
+            vec![
+                Bytes::from(vec![1u8]),
+                Bytes::from(vec![2u8]),
+                Bytes::from(vec![4u8])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_key_rollover() {
+        let (old_pk, old_sk) = sign::gen_keypair();
+        let (new_pk, _) = sign::gen_keypair();
+        let proof = sign_key_rollover(&old_sk, "123456789", &new_pk.0, 1000);
+        assert_eq!(
+            verify_key_rollover(&proof, &old_pk.0),
+            Some(KeyRollover {
+                id: "123456789".to_owned(),
+                pk: new_pk.0.to_vec(),
+                time: 1000,
+            })
+        );
+        assert_eq!(verify_key_rollover(&proof, &new_pk.0), None);
+        let mut tampered = proof.clone();
+        let n = tampered.len();
+        tampered[n - 1] ^= 1;
+        assert_eq!(verify_key_rollover(&tampered, &old_pk.0), None);
+    }
+
     #[test]
     fn test_config_deserialize() {
         let wrong_type_str = r#"
@@ -3290,6 +5791,7 @@ mod tests {
         let cfg: PeerConfig = Default::default();
         cfg.store(&peerconfig_id);
         assert_eq!(PeerConfig::load(&peerconfig_id), cfg);
+        assert_eq!(PeerConfig::load_meta(&peerconfig_id), cfg.meta());
 
         #[cfg(not(windows))]
         {
@@ -3305,4 +5807,454 @@ mod tests {
             );
         }
     }
+
+    #[tokio::test]
+    async fn test_store_deferred() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("deferred.toml");
+        let count = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        for i in 0..10 {
+            let (path2, count) = (path.clone(), count.clone());
+            store_deferred(
+                path.clone(),
+                Box::new(move || {
+                    count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    store_path(path2, HashMap::from([("i", i)])).ok();
+                }),
+            );
+        }
+        tokio::time::sleep(STORE_COALESCE * 3).await;
+        assert_eq!(count.load(std::sync::atomic::Ordering::SeqCst), 1);
+        let stored: HashMap<String, i32> = load_path(path.clone());
+        assert_eq!(stored.get("i"), Some(&9));
+        store_path_async(path.clone(), HashMap::from([("i", 10)]))
+            .await
+            .unwrap();
+        let stored: HashMap<String, i32> = load_path(path.clone());
+        assert_eq!(stored.get("i"), Some(&10));
+    }
+
+    #[test]
+    fn test_recover_config() {
+        // not reset by other tests meanwhile
+        let _t = test_config();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recover.toml");
+        store_path(path.clone(), HashMap::from([("id", "123")])).unwrap();
+        backup_config(&path);
+        fs::write(&path, "id = \"12").unwrap();
+        let cfg: HashMap<String, String> = load_path(path.clone());
+        assert_eq!(cfg.get("id").map(|x| x.as_str()), Some("123"));
+        let recovery = Config::get_recovered_configs()
+            .into_iter()
+            .find(|x| x.path == path.to_string_lossy())
+            .unwrap();
+        assert!(recovery.restored);
+        let quarantined = PathBuf::from(recovery.quarantined.unwrap());
+        assert_eq!(fs::read_to_string(&quarantined).unwrap(), "id = \"12");
+        let cfg: HashMap<String, String> = load_path(path.clone());
+        assert_eq!(cfg.get("id").map(|x| x.as_str()), Some("123"));
+        // a forged backup
+        let backup = with_suffix(&path, BACKUP_SUFFIX);
+        fs::write(&backup, format!("id = \"456\"\n{}AAAA\n", HMAC_LINE_PREFIX)).unwrap();
+        fs::write(&path, "[[id").unwrap();
+        let cfg: HashMap<String, String> = load_path(path.clone());
+        assert!(cfg.is_empty());
+        // no backup
+        fs::remove_file(&backup).unwrap();
+        fs::write(&path, "[[id").unwrap();
+        let cfg: HashMap<String, String> = load_path(path.clone());
+        assert!(cfg.is_empty());
+        assert!(Config::get_recovered_configs()
+            .iter()
+            .any(|x| x.path == path.to_string_lossy() && !x.restored));
+    }
+
+    #[test]
+    fn test_store_if_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dedup.toml");
+        let cfg = HashMap::from([("a", 1)]);
+        assert!(store_path_if_changed(path.clone(), &cfg).unwrap());
+        assert!(!store_path_if_changed(path.clone(), &cfg).unwrap());
+        assert!(store_path_if_changed(path.clone(), HashMap::from([("a", 2)])).unwrap());
+        // modified outside
+        fs::write(&path, "a = 3\nb = 4\n").unwrap();
+        assert!(store_path_if_changed(path.clone(), HashMap::from([("a", 2)])).unwrap());
+        // encrypted differently on every store, deduplicated by the plaintext
+        let plain = HashMap::from([("password", "secret")]);
+        let encrypted = |x: &HashMap<&str, &str>| {
+            let password =
+                encrypt_str_or_original(x["password"], PASSWORD_ENC_VERSION, ENCRYPT_MAX_LEN);
+            HashMap::from([("password", password)])
+        };
+        let hash = content_hash(&plain);
+        assert!(store_path_hashed(path.clone(), encrypted(&plain), hash).unwrap());
+        assert!(!store_path_hashed(path.clone(), encrypted(&plain), hash).unwrap());
+    }
+
+    #[test]
+    fn test_memory_backend() {
+        let t = test_config();
+        let id = "test_memory_peer";
+        assert!(!PeerConfig::exists(id));
+        PeerConfig::default().store(id);
+        assert!(PeerConfig::exists(id));
+        assert!(!Config::path(PEERS).join(format!("{id}.toml")).exists());
+        let ids: Vec<_> = PeerConfig::get_vec_id_modified_time_path(&None)
+            .into_iter()
+            .map(|x| x.0)
+            .collect();
+        assert_eq!(ids, vec![id.to_owned()]);
+        PeerConfig::remove(id);
+        assert!(!PeerConfig::exists(id));
+
+        LocalConfig::set_option("test-memory".to_owned(), "1".to_owned());
+        assert!(t.file(&Config::file_("_local")).is_some());
+        reset();
+        assert_eq!(LocalConfig::get_option("test-memory"), "1");
+        drop(t);
+        let _t = test_config();
+        assert_eq!(LocalConfig::get_option("test-memory"), "");
+    }
+
+    #[test]
+    fn test_register_ids() {
+        let _t = test_config();
+        BUILTIN_SETTINGS
+            .write()
+            .unwrap()
+            .insert(keys::OPTION_ALLOW_HOSTNAME_AS_ID.to_owned(), "Y".to_owned());
+        let id = Config::get_id();
+        assert!(id.chars().all(|c| c.is_ascii_digit()), "{}", id);
+        Config::set_option(
+            keys::OPTION_EXTRA_IDS.to_owned(),
+            format!("{id}, office-pc,office-pc,bad.id,1abcdef"),
+        );
+        assert_eq!(Config::get_extra_ids(), vec!["office-pc"]);
+        let ids = Config::get_register_ids();
+        assert_eq!(ids[0], id);
+        assert_eq!(ids.last().map(|x| x.as_str()), Some("office-pc"));
+        assert!(ids[1..].iter().all(|x| crate::is_valid_custom_id(x)));
+    }
+
+    #[test]
+    fn test_session_key_cache() {
+        let _t = test_config();
+        let key = secretbox::gen_key();
+        SessionKeyCache::set("123", b"pk", &key.0);
+        let (a, b, c) = ([1u8; 16], [2u8; 16], [3u8; 16]);
+        let k1 = SessionKeyCache::get("123", b"pk", &a, &b).unwrap();
+        assert_eq!(SessionKeyCache::get("123", b"pk", &a, &b), Some(k1.clone()));
+        let k2 = SessionKeyCache::get("123", b"pk", &a, &c).unwrap();
+        assert_ne!(k1, k2);
+        assert_ne!(k1, key.0.to_vec());
+        assert!(SessionKeyCache::get("123", b"pk", &a, &b[..8]).is_none());
+        assert!(SessionKeyCache::get("123", b"other", &a, &b).is_none());
+        assert!(SessionKeyCache::get("123", b"pk", &a, &b).is_none());
+    }
+
+    #[test]
+    fn test_set_password_policy() {
+        let _t = test_config();
+        OVERWRITE_SETTINGS
+            .write()
+            .unwrap()
+            .insert(keys::OPTION_PASSWORD_MIN_LENGTH.to_owned(), "8".to_owned());
+        assert!(Config::set_permanent_password_checked("12345678").is_ok());
+        let mut cfg = Config::get();
+        cfg.password = "1234".to_owned();
+        Config::set(cfg);
+        assert!(Config::verify_permanent_password("12345678"));
+        let mut cfg = Config::get();
+        cfg.password = "abcdefgh".to_owned();
+        Config::set(cfg);
+        assert!(Config::verify_permanent_password("abcdefgh"));
+    }
+
+    #[test]
+    fn test_transfer_migrate() {
+        let mut transfer = TransferSerde {
+            write_jobs: vec![
+                r#"{"id":3,"remote":"/a","to":"/b","show_hidden":true,"is_remote":true,"file_num":2}"#
+                    .to_owned(),
+                "not json".to_owned(),
+            ],
+            read_jobs: vec![r#"{"id":4,"remote":"/tmp/b","to":"/home/b"}"#.to_owned()],
+            ..Default::default()
+        };
+        transfer.update_record(
+            TransferJobRecord {
+                id: 4,
+                is_mirror: true,
+                ..Default::default()
+            },
+            false,
+        );
+        transfer.migrate();
+        assert!(transfer.write_jobs.is_empty() && transfer.read_jobs.is_empty());
+        assert_eq!(
+            transfer.write_records,
+            vec![TransferJobRecord {
+                id: 3,
+                remote: "/a".to_owned(),
+                to: "/b".to_owned(),
+                show_hidden: true,
+                is_remote: true,
+                file_num: 2,
+                files: vec![],
+                is_mirror: false,
+            }]
+        );
+        // the record of a job is kept
+        assert_eq!(transfer.read_records.len(), 1);
+        assert!(transfer.read_records[0].is_mirror);
+    }
+
+    #[test]
+    fn test_account_cache() {
+        let _t = test_config();
+        let data = b"{\"ab_entries\":[]}";
+        let token = |v: &str| AccessToken {
+            token: v.to_owned(),
+            refresh_token: String::new(),
+            expire: 0,
+        };
+        TokenStore::set(TOKEN_AB, token("token-a"));
+        let sealed = account_seal(TOKEN_AB, data).unwrap();
+        assert!(sealed.starts_with(ACCOUNT_CACHE_MAGIC));
+        assert_eq!(account_open(TOKEN_AB, &sealed).unwrap(), data);
+        let queued = user_seal(TOKEN_AB, data);
+        // another account, or a refresh of the token
+        TokenStore::set(TOKEN_AB, token("token-b"));
+        assert!(account_open(TOKEN_AB, &sealed).is_none());
+        assert_eq!(user_open(TOKEN_AB, &queued).unwrap(), data);
+        assert!(user_open(TOKEN_AB, &sealed).is_none());
+        // written before, with the key of the machine
+        let legacy = symmetric_crypt(data, true).unwrap();
+        assert_eq!(account_open(TOKEN_AB, &legacy).unwrap(), data);
+        TokenStore::remove(TOKEN_AB);
+    }
+
+    #[test]
+    fn test_cache_stream() {
+        let _t = test_config();
+        TokenStore::set(
+            TOKEN_GROUP,
+            AccessToken {
+                token: "token-s".to_owned(),
+                refresh_token: String::new(),
+                expire: 0,
+            },
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("group");
+        let n = 20000;
+        let group = Group {
+            peers: (0..n)
+                .map(|i| GroupPeer {
+                    id: i.to_string(),
+                    hostname: format!("host-{}", i),
+                    // not compressible
+                    login_name: format!("{:016x}", (i as u64).wrapping_mul(0x9E3779B97F4A7C15)),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        store_cache(&path, TOKEN_GROUP, &group).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        assert!(data.starts_with(ACCOUNT_STREAM_MAGIC));
+        // several chunks
+        assert!(data.len() > ACCOUNT_STREAM_CHUNK);
+        let loaded: Group = load_cache(&path, TOKEN_GROUP).unwrap();
+        assert_eq!(loaded.peers.len(), n);
+        let decoded: Group = decode_cache(TOKEN_GROUP, &data).unwrap();
+        assert_eq!(decoded.peers[n - 1].hostname, format!("host-{}", n - 1));
+        assert!(decode_cache::<Group>(TOKEN_GROUP, &data[..data.len() - 1]).is_none());
+        // the fields unknown to this version are kept
+        let json = r#"{"users": [], "peers": [{"id": "1"}], "next_cursor": "abc"}"#;
+        let group: Group = serde_json::from_reader(json.as_bytes()).unwrap();
+        store_cache(&path, TOKEN_GROUP, &group).unwrap();
+        let loaded: Group = load_cache(&path, TOKEN_GROUP).unwrap();
+        assert_eq!(loaded.extra.get("next_cursor"), Some(&serde_json::json!("abc")));
+        TokenStore::remove(TOKEN_GROUP);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_ipc_dir_shared() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        if crate::platform::sandbox_info().runtime_dir.is_some() {
+            return;
+        }
+        let path = PathBuf::from(Config::ipc_path("_test"));
+        let dir = path.parent().unwrap();
+        assert_eq!(dir, Path::new(&format!("/tmp/{}", *APP_NAME.read().unwrap())));
+        let m = fs::symlink_metadata(dir).unwrap();
+        let uid = unsafe { libc::geteuid() };
+        assert!(m.is_dir() && (m.uid() == 0 || m.uid() == uid));
+        if m.uid() == uid {
+            assert_eq!(m.permissions().mode() & 0o7777, 0o1777);
+        }
+    }
+
+    use proptest::prelude::*;
+
+    fn option_key() -> impl Strategy<Value = String> {
+        prop_oneof![
+            prop::sample::select(vec![
+                "enable-lan-discovery",
+                "allow-auto-disconnect",
+                "stop-service",
+                keys::OPTION_DIRECT_SERVER,
+                "force-always-relay",
+                keys::OPTION_LOG_LEVEL,
+                keys::OPTION_WHITELIST,
+            ])
+            .prop_map(str::to_owned),
+            "[a-z-]{1,12}",
+        ]
+    }
+
+    fn option_value() -> impl Strategy<Value = String> {
+        prop_oneof![
+            Just("Y".to_owned()),
+            Just("N".to_owned()),
+            Just(String::new()),
+            "\\PC{0,8}",
+        ]
+    }
+
+    fn options() -> impl Strategy<Value = HashMap<String, String>> {
+        prop::collection::hash_map(option_key(), option_value(), 0..8)
+    }
+
+    ///   What is loaded is stored and loaded again unchanged.
+    fn round_trip<T>(x: &T) -> T
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug,
+    {
+        let once: T = toml::from_str(&toml::to_string(x).unwrap()).unwrap();
+        let twice: T = toml::from_str(&toml::to_string(&once).unwrap()).unwrap();
+        assert_eq!(once, twice);
+        once
+    }
+
+    // The precedence contract, the Flutter side follows the same rules:
+    // "Y" is on and "N" is off for every option. Any other value, including
+    // empty, is off for `allow-` and the few opt-in options, and on otherwise.
+    proptest! {
+        #[test]
+        fn prop_option2bool(k in option_key(), v in option_value()) {
+            prop_assert!(option2bool(&k, "Y"));
+            prop_assert!(!option2bool(&k, "N"));
+            let opt_in = !k.starts_with("enable-")
+                && (k.starts_with("allow-")
+                    || k == "stop-service"
+                    || k == keys::OPTION_DIRECT_SERVER
+                    || k == "force-always-relay");
+            if v != "Y" && v != "N" {
+                prop_assert_eq!(option2bool(&k, &v), !opt_in);
+            }
+        }
+
+        // overwrite, then the stored options, then the defaults
+        #[test]
+        fn prop_get_or(a in options(), b in options(), c in options(), k in option_key()) {
+            let expected = a.get(&k).or_else(|| b.get(&k)).or_else(|| c.get(&k)).cloned();
+            let (a, c) = (SettingsMap::new(a), SettingsMap::new(c));
+            prop_assert_eq!(get_or(&a, &b, &c, &k), expected);
+        }
+
+        // overwritten options, and those equal to the default, are not stored
+        #[test]
+        fn prop_purify_options(v in options(), overwrite in options(), defaults in options()) {
+            let _t = test_config();
+            *OVERWRITE_SETTINGS.write().unwrap() = overwrite.clone();
+            *DEFAULT_SETTINGS.write().unwrap() = defaults.clone();
+            let mut purified = v.clone();
+            Config::purify_options(&mut purified);
+            prop_assert!(purified.len() <= v.len());
+            for (k, x) in &v {
+                let keep = is_option_valid(k, x)
+                    && !overwrite.contains_key(k)
+                    && defaults.get(k) != Some(x);
+                prop_assert_eq!(purified.get(k), if keep { Some(x) } else { None });
+            }
+            let mut again = purified.clone();
+            Config::purify_options(&mut again);
+            prop_assert_eq!(again, purified);
+        }
+
+        #[test]
+        fn prop_config_round_trip(
+            id in "[0-9a-z]{0,12}",
+            password in "\\PC{0,16}",
+            salt in "[0-9a-zA-Z]{0,6}",
+            pk in prop::collection::vec(any::<u8>(), 0..64),
+            keys_confirmed in prop::collection::hash_map("\\PC{1,8}", any::<bool>(), 0..4),
+            password_time in any::<i64>(),
+        ) {
+            let mut config = Config::default();
+            config.id = id;
+            config.password = password;
+            config.salt = salt;
+            config.key_pair = (pk.clone(), pk);
+            config.keys_confirmed = keys_confirmed;
+            config.password_time = password_time;
+            prop_assert_eq!(round_trip(&config), config);
+        }
+
+        #[test]
+        fn prop_config2_round_trip(
+            rendezvous_server in "\\PC{0,16}",
+            nat_type in any::<i32>(),
+            serial in any::<i32>(),
+            options in options(),
+            proxy in prop::option::of(("\\PC{0,16}", "\\PC{0,8}", "\\PC{0,8}")),
+        ) {
+            let mut config = Config2::default();
+            config.rendezvous_server = rendezvous_server;
+            config.nat_type = nat_type;
+            config.serial = serial;
+            config.options = options;
+            config.socks = proxy.map(|(proxy, username, password)| Socks5Server {
+                proxy,
+                username,
+                password,
+            });
+            prop_assert_eq!(round_trip(&config), config);
+        }
+
+        #[test]
+        fn prop_local_config_round_trip(
+            remote_id in "\\PC{0,12}",
+            size in any::<(i32, i32, i32, i32)>(),
+            fav in prop::collection::vec("\\PC{0,12}", 0..4),
+            options in options(),
+            ui_flutter in options(),
+        ) {
+            let mut config = LocalConfig::default();
+            config.remote_id = remote_id;
+            config.size = size;
+            config.fav = fav;
+            config.options = options;
+            config.ui_flutter = ui_flutter;
+            prop_assert_eq!(round_trip(&config), config);
+        }
+
+        #[test]
+        fn prop_peer_config_round_trip(
+            password in prop::collection::vec(any::<u8>(), 0..64),
+            size in any::<(i32, i32, i32, i32)>(),
+            options in options(),
+        ) {
+            let mut config = toml::from_str::<PeerConfig>("").unwrap();
+            config.password = password;
+            config.size = size;
+            config.options = options;
+            prop_assert_eq!(round_trip(&config), config);
+        }
+    }
 }
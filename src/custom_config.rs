@@ -0,0 +1,169 @@
+// The default configuration of a custom build, a signed custom.toml embedded at
+// build time, see build.rs, instead of settings hard-coded in
+// `config::init_default_settings`:
+//
+//     rendezvous-servers = ["rs.example.com"]
+//     rs-pub-key = "..."
+//     [default-settings]
+//     verification-method = "password"
+//     [override-settings]
+//     [hard-settings]
+//
+// It is only applied if its signature verifies with the key given at build time,
+// i.e. the file built with was released by the holder of the secret key, e.g. not
+// edited in a shared build pipeline since, and not truncated or mixed up with the
+// one of another build. The key is embedded with it, so this does not protect a
+// binary patched afterwards. The server list and key are applied with `Branding`,
+// so it must be loaded before any config. An example is examples/custom.toml.
+use crate::{
+    bail,
+    branding::Branding,
+    options::{
+        BUILTIN_SETTINGS, DEFAULT_DISPLAY_SETTINGS, DEFAULT_LOCAL_SETTINGS, DEFAULT_SETTINGS,
+        HARD_SETTINGS, OVERWRITE_DISPLAY_SETTINGS, OVERWRITE_LOCAL_SETTINGS, OVERWRITE_SETTINGS,
+    },
+    ResultType,
+};
+use serde_derive::Deserialize;
+use sodiumoxide::{base64, crypto::sign};
+use std::collections::HashMap;
+
+const EMBEDDED: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/custom.toml"));
+const EMBEDDED_SIG: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/custom.toml.sig"));
+const EMBEDDED_PK: &str = env!("HBB_CUSTOM_CONFIG_PK");
+
+type Map = HashMap<String, String>;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CustomConfig {
+    #[serde(default)]
+    pub rendezvous_servers: Vec<String>,
+    #[serde(default)]
+    pub rs_pub_key: String,
+    #[serde(default)]
+    pub default_settings: Map,
+    #[serde(default)]
+    pub override_settings: Map,
+    #[serde(default)]
+    pub hard_settings: Map,
+    #[serde(default)]
+    pub builtin_settings: Map,
+    #[serde(default)]
+    pub default_display_settings: Map,
+    #[serde(default)]
+    pub override_display_settings: Map,
+    #[serde(default)]
+    pub default_local_settings: Map,
+    #[serde(default)]
+    pub override_local_settings: Map,
+}
+
+impl CustomConfig {
+    /// `sig` is the detached signature of `data` in base64, `pk` the public key in
+    /// base64.
+    pub fn parse(data: &[u8], sig: &[u8], pk: &str) -> ResultType<Self> {
+        let decode = |x: &[u8]| {
+            base64::decode(String::from_utf8_lossy(x).trim(), base64::Variant::Original)
+                .unwrap_or_default()
+        };
+        let (Some(pk), Ok(sig)) = (
+            sign::PublicKey::from_slice(&decode(pk.as_bytes())),
+            sign::Signature::from_bytes(&decode(sig)),
+        ) else {
+            bail!("Invalid key or signature of the custom config");
+        };
+        if !sign::verify_detached(&sig, data, &pk) {
+            bail!("Invalid signature of the custom config");
+        }
+        Ok(toml::from_str(std::str::from_utf8(data)?)?)
+    }
+
+    /// Applies the server list and key with `Branding`, and adds the settings to
+    /// the settings maps.
+    pub fn apply(self) -> ResultType<()> {
+        let mut branding = Branding::new();
+        if !self.rendezvous_servers.is_empty() {
+            let servers: Vec<&str> = self.rendezvous_servers.iter().map(|x| x.as_str()).collect();
+            branding = branding.rendezvous_servers(&servers);
+        }
+        if !self.rs_pub_key.is_empty() {
+            branding = branding.rs_pub_key(&self.rs_pub_key);
+        }
+        if branding != Branding::new() {
+            branding.apply()?;
+        }
+        for (target, settings) in [
+            (&*DEFAULT_SETTINGS, self.default_settings),
+            (&*OVERWRITE_SETTINGS, self.override_settings),
+            (&*HARD_SETTINGS, self.hard_settings),
+            (&*BUILTIN_SETTINGS, self.builtin_settings),
+            (&*DEFAULT_DISPLAY_SETTINGS, self.default_display_settings),
+            (&*OVERWRITE_DISPLAY_SETTINGS, self.override_display_settings),
+            (&*DEFAULT_LOCAL_SETTINGS, self.default_local_settings),
+            (&*OVERWRITE_LOCAL_SETTINGS, self.override_local_settings),
+        ] {
+            if !settings.is_empty() {
+                target.write().unwrap().extend(settings);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[inline]
+pub fn is_embedded() -> bool {
+    !EMBEDDED.is_empty()
+}
+
+/// Verifies and applies the embedded custom config, returns false if none is
+/// embedded. To be called at startup before any config is loaded.
+pub fn load_embedded() -> ResultType<bool> {
+    if !is_embedded() {
+        return Ok(false);
+    }
+    CustomConfig::parse(EMBEDDED, EMBEDDED_SIG, EMBEDDED_PK)?.apply()?;
+    log::info!("Embedded custom config applied");
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let (pk, sk) = sign::gen_keypair();
+        let data = br#"
+rendezvous-servers = ["rs.example.com"]
+[default-settings]
+verification-method = "password"
+[hard-settings]
+password-min-length = "12"
+"#;
+        let encode = |x: &[u8]| base64::encode(x, base64::Variant::Original);
+        let sig = encode(sign::sign_detached(data, &sk).to_bytes().as_ref());
+        let config = CustomConfig::parse(data, sig.as_bytes(), &encode(&pk.0)).unwrap();
+        assert_eq!(config.rendezvous_servers, vec!["rs.example.com".to_owned()]);
+        assert_eq!(
+            config
+                .default_settings
+                .get("verification-method")
+                .map(|x| x.as_str()),
+            Some("password")
+        );
+        assert_eq!(
+            config
+                .hard_settings
+                .get("password-min-length")
+                .map(|x| x.as_str()),
+            Some("12")
+        );
+        let mut tampered = data.to_vec();
+        tampered[0] = b'#';
+        assert!(CustomConfig::parse(&tampered, sig.as_bytes(), &encode(&pk.0)).is_err());
+        assert!(CustomConfig::parse(data, b"", &encode(&pk.0)).is_err());
+        let (other, _) = sign::gen_keypair();
+        assert!(CustomConfig::parse(data, sig.as_bytes(), &encode(&other.0)).is_err());
+    }
+}
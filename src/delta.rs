@@ -0,0 +1,355 @@
+// rsync style delta encoding for re-sending files the receiver has an older
+// version of.
+//
+// The receiver sends the signature of its file: a weak rolling checksum and a
+// truncated blake3 of every full block. The sender slides a window over the new
+// file and replaces the blocks found in the signature with their index.
+//
+// Signature: block size (u32 le) | (weak (u32 le) | strong (16 bytes))*
+// Ops: (0 | index (u32 le)) copy a block of the old file,
+//      (1 | len (u32 le) | data) literal data
+use crate::{bail, ResultType};
+use std::{
+    collections::HashMap,
+    io::{Read, Seek, SeekFrom},
+};
+
+const STRONG_LEN: usize = 16;
+const OP_COPY: u8 = 0;
+const OP_DATA: u8 = 1;
+// Literal data is flushed in pieces so that the ops of a block stay bounded.
+const MAX_LITERAL: usize = 64 * 1024;
+pub const MIN_BLOCK_SIZE: u32 = 1 << 10;
+pub const MAX_BLOCK_SIZE: u32 = 1 << 20;
+
+/// Capability flags for `Features.file_delta_block_sizes`, bit n set means
+/// block size 1 << n is supported.
+pub fn block_size_flags() -> u32 {
+    let mut flags = 0;
+    let mut size = MIN_BLOCK_SIZE;
+    while size <= MAX_BLOCK_SIZE {
+        flags |= size;
+        size <<= 1;
+    }
+    flags
+}
+
+/// The block size both sides support closest to sqrt(file_size), like rsync,
+/// None if delta transfer is not possible.
+pub fn choose_block_size(local_flags: u32, peer_flags: u32, file_size: u64) -> Option<u32> {
+    let common = local_flags & peer_flags;
+    if common == 0 {
+        return None;
+    }
+    let target = ((file_size as f64).sqrt() as u64).max(1);
+    (0..32)
+        .map(|n| 1u32 << n)
+        .filter(|size| common & size != 0)
+        .min_by_key(|size| (*size as u64).abs_diff(target))
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(data: &[u8]) -> Self {
+        let mut r = Self {
+            len: data.len() as _,
+            ..Default::default()
+        };
+        for (i, x) in data.iter().enumerate() {
+            r.a = r.a.wrapping_add(*x as u32);
+            r.b = r.b.wrapping_add((data.len() - i) as u32 * *x as u32);
+        }
+        r
+    }
+
+    fn roll(&mut self, out: u8, new: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(new as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong(data: &[u8]) -> [u8; STRONG_LEN] {
+    let mut res = [0u8; STRONG_LEN];
+    res.copy_from_slice(&blake3::hash(data).as_bytes()[..STRONG_LEN]);
+    res
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub block_size: u32,
+    blocks: Vec<(u32, [u8; STRONG_LEN])>,
+}
+
+impl Signature {
+    /// Signature of the full blocks of `reader`, the tail is always sent as is.
+    pub fn compute<R: Read>(mut reader: R, block_size: u32) -> ResultType<Self> {
+        if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+            bail!("Invalid block size {}", block_size);
+        }
+        let mut blocks = vec![];
+        let mut buf = vec![0u8; block_size as usize];
+        loop {
+            let mut n = 0;
+            while n < buf.len() {
+                let m = reader.read(&mut buf[n..])?;
+                if m == 0 {
+                    break;
+                }
+                n += m;
+            }
+            if n < buf.len() {
+                break;
+            }
+            blocks.push((Rolling::new(&buf).digest(), strong(&buf)));
+        }
+        Ok(Self { block_size, blocks })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(4 + self.blocks.len() * (4 + STRONG_LEN));
+        res.extend_from_slice(&self.block_size.to_le_bytes());
+        for (weak, strong) in self.blocks.iter() {
+            res.extend_from_slice(&weak.to_le_bytes());
+            res.extend_from_slice(strong);
+        }
+        res
+    }
+
+    pub fn from_bytes(data: &[u8]) -> ResultType<Self> {
+        if data.len() < 4 || (data.len() - 4) % (4 + STRONG_LEN) != 0 {
+            bail!("Invalid delta signature");
+        }
+        let block_size = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+            bail!("Invalid block size {}", block_size);
+        }
+        let blocks = data[4..]
+            .chunks(4 + STRONG_LEN)
+            .map(|x| {
+                let mut strong = [0u8; STRONG_LEN];
+                strong.copy_from_slice(&x[4..]);
+                (u32::from_le_bytes([x[0], x[1], x[2], x[3]]), strong)
+            })
+            .collect();
+        Ok(Self { block_size, blocks })
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Copy(u32),
+    Data(Vec<u8>),
+}
+
+pub fn encode_ops(ops: &[Op]) -> Vec<u8> {
+    let mut res = vec![];
+    for op in ops {
+        match op {
+            Op::Copy(index) => {
+                res.push(OP_COPY);
+                res.extend_from_slice(&index.to_le_bytes());
+            }
+            Op::Data(data) => {
+                res.push(OP_DATA);
+                res.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                res.extend_from_slice(data);
+            }
+        }
+    }
+    res
+}
+
+pub fn decode_ops(mut data: &[u8]) -> ResultType<Vec<Op>> {
+    let mut ops = vec![];
+    while let Some((tag, rest)) = data.split_first() {
+        if rest.len() < 4 {
+            bail!("Truncated delta op");
+        }
+        let n = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+        data = &rest[4..];
+        match *tag {
+            OP_COPY => ops.push(Op::Copy(n)),
+            OP_DATA => {
+                if data.len() < n as usize {
+                    bail!("Truncated delta data");
+                }
+                ops.push(Op::Data(data[..n as usize].to_vec()));
+                data = &data[n as usize..];
+            }
+            _ => bail!("Invalid delta op {}", tag),
+        }
+    }
+    Ok(ops)
+}
+
+/// Streaming encoder on the sender side, feed the new file with `update`.
+#[derive(Debug)]
+pub struct Encoder {
+    block_size: usize,
+    index: HashMap<u32, Vec<(u32, [u8; STRONG_LEN])>>,
+    data: Vec<u8>,
+    // start of the pending literal data and of the window in `data`
+    literal: usize,
+    pos: usize,
+    rolling: Option<Rolling>,
+}
+
+impl Encoder {
+    pub fn new(signature: &Signature) -> Self {
+        let mut index: HashMap<u32, Vec<_>> = HashMap::new();
+        for (i, (weak, strong)) in signature.blocks.iter().enumerate() {
+            index.entry(*weak).or_default().push((i as u32, *strong));
+        }
+        Self {
+            block_size: signature.block_size as _,
+            index,
+            data: vec![],
+            literal: 0,
+            pos: 0,
+            rolling: None,
+        }
+    }
+
+    fn find(&self, weak: u32, window: &[u8]) -> Option<u32> {
+        let candidates = self.index.get(&weak)?;
+        let strong = strong(window);
+        candidates
+            .iter()
+            .find(|(_, s)| *s == strong)
+            .map(|(i, _)| *i)
+    }
+
+    fn flush_literal(&mut self, end: usize, ops: &mut Vec<Op>) {
+        if end > self.literal {
+            ops.push(Op::Data(self.data[self.literal..end].to_vec()));
+            self.literal = end;
+        }
+    }
+
+    pub fn update(&mut self, input: &[u8]) -> Vec<Op> {
+        let mut ops = vec![];
+        self.data.extend_from_slice(input);
+        let bs = self.block_size;
+        while self.pos + bs <= self.data.len() {
+            let rolling = match self.rolling {
+                Some(r) => r,
+                None => Rolling::new(&self.data[self.pos..self.pos + bs]),
+            };
+            if let Some(i) = self.find(rolling.digest(), &self.data[self.pos..self.pos + bs]) {
+                self.flush_literal(self.pos, &mut ops);
+                ops.push(Op::Copy(i));
+                self.pos += bs;
+                self.literal = self.pos;
+                self.rolling = None;
+                continue;
+            }
+            if self.pos - self.literal >= MAX_LITERAL {
+                self.flush_literal(self.pos, &mut ops);
+            }
+            if self.pos + bs == self.data.len() {
+                // wait for the next byte to roll
+                self.rolling = Some(rolling);
+                break;
+            }
+            let mut rolling = rolling;
+            rolling.roll(self.data[self.pos], self.data[self.pos + bs]);
+            self.rolling = Some(rolling);
+            self.pos += 1;
+        }
+        if self.literal > 0 {
+            self.data.drain(..self.literal);
+            self.pos -= self.literal;
+            self.literal = 0;
+        }
+        ops
+    }
+
+    /// The remaining data at the end of the file.
+    pub fn finish(&mut self) -> Vec<Op> {
+        let mut ops = vec![];
+        let end = self.data.len();
+        self.flush_literal(end, &mut ops);
+        self.data.clear();
+        self.literal = 0;
+        self.pos = 0;
+        self.rolling = None;
+        ops
+    }
+}
+
+/// Rebuild the new data on the receiver side from `base`, the file the
+/// signature was computed with.
+pub fn apply<R: Read + Seek>(ops: &[Op], base: &mut R, block_size: u32) -> ResultType<Vec<u8>> {
+    let mut out = vec![];
+    for op in ops {
+        match op {
+            Op::Copy(index) => {
+                base.seek(SeekFrom::Start(*index as u64 * block_size as u64))?;
+                let start = out.len();
+                out.resize(start + block_size as usize, 0);
+                base.read_exact(&mut out[start..])?;
+            }
+            Op::Data(data) => out.extend_from_slice(data),
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta() {
+        let bs = MIN_BLOCK_SIZE as usize;
+        let old: Vec<u8> = (0..bs * 8 + 100).map(|i| (i * 7 % 251) as u8).collect();
+        let mut new = old.clone();
+        new[bs * 2 + 5] ^= 0xff;
+        new.splice(bs * 5..bs * 5, b"inserted".iter().cloned());
+        new.truncate(new.len() - 50);
+        let sig = Signature::compute(&old[..], bs as _).unwrap();
+        assert_eq!(sig.len(), 8);
+        let sig = Signature::from_bytes(&sig.to_bytes()).unwrap();
+        let mut encoder = Encoder::new(&sig);
+        let mut ops = vec![];
+        for chunk in new.chunks(333) {
+            ops.extend(encoder.update(chunk));
+        }
+        ops.extend(encoder.finish());
+        let copies = ops.iter().filter(|op| matches!(op, Op::Copy(_))).count();
+        assert!(copies >= 6, "{}", copies);
+        let ops = decode_ops(&encode_ops(&ops)).unwrap();
+        let out = apply(&ops, &mut std::io::Cursor::new(&old), bs as _).unwrap();
+        assert_eq!(out, new);
+        assert!(decode_ops(&[OP_DATA, 10, 0, 0, 0, 1]).is_err());
+        assert_eq!(
+            choose_block_size(block_size_flags(), block_size_flags(), 1 << 30),
+            Some(1 << 15)
+        );
+        assert_eq!(choose_block_size(block_size_flags(), 1 << 5, 100), None);
+        assert_eq!(choose_block_size(block_size_flags(), 0, 100), None);
+    }
+}
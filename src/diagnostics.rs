@@ -0,0 +1,484 @@
+// A zip of recent logs, config snapshots, the NAT type, server latencies and
+// version info to attach to bug reports. The zip is AES encrypted with a random
+// password shown to the user, who shares it with the developer separately.
+//
+// Secrets are masked by `logging::redact`, and the own and peer IDs are
+// replaced by placeholders, so the bundle does not tell whose machines they are.
+//
+// `self_test` checks the connectivity to the configured servers, for the
+// checklist the UI shows when a connection fails.
+use crate::{
+    bail,
+    config::{self, keys, Config, PeerConfig},
+    logging,
+    protobuf::{Enum, Message as _},
+    rendezvous_proto::*,
+    socket_client::{self, split_host_port},
+    tcp::FramedStream,
+    websocket, ResultType, Stream,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use regex::Regex;
+use serde_derive::Serialize;
+use std::{
+    fmt::Write as _,
+    future::Future,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Instant, SystemTime},
+};
+use zip::{write::SimpleFileOptions, AesMode, CompressionMethod, ZipWriter};
+
+/// Logs included, the newest first.
+const MAX_LOG_SIZE: u64 = 20 * 1024 * 1024;
+const PASSWORD_LEN: usize = 16;
+const CHECK_TIMEOUT: u64 = 3_000;
+
+#[derive(Debug, Clone)]
+pub struct Bundle {
+    pub path: PathBuf,
+    pub password: String,
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// Writes the bundle with a random password to a new dir of the temp dir, only
+/// accessible to this user, so another user can neither read nor replace it.
+pub fn collect_bundle() -> ResultType<Bundle> {
+    let app = config::APP_NAME.read().unwrap().clone();
+    let dir = std::env::temp_dir().join(format!("{}_diagnostics_{}", app, random_string(8)));
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    // fails if it exists, e.g. created by another user beforehand
+    builder.create(&dir)?;
+    let path = dir.join(format!(
+        "{}_diagnostics_{}.zip",
+        app,
+        chrono::Local::now().format("%Y%m%d%H%M%S")
+    ));
+    let password = random_string(PASSWORD_LEN);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    if let Err(err) = options
+        .open(&path)
+        .map_err(Into::into)
+        .and_then(|file| write_bundle(file, &password))
+    {
+        std::fs::remove_dir_all(&dir).ok();
+        return Err(err);
+    }
+    Ok(Bundle { path, password })
+}
+
+/// Exports the bundle to `path`, which must be within the allowed paths, see
+/// `sandbox`.
+pub fn collect_bundle_to(path: &Path, password: &str) -> ResultType<()> {
+    let path = crate::sandbox::check_path(path)?;
+    write_bundle(std::fs::File::create(path)?, password)
+}
+
+fn write_bundle(file: std::fs::File, password: &str) -> ResultType<()> {
+    let redactor = Redactor::new();
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .with_aes_encryption(AesMode::Aes256, password);
+    let mut zip = ZipWriter::new(file);
+    zip.start_file("version.txt", options)?;
+    zip.write_all(version_info().as_bytes())?;
+    zip.start_file("network.txt", options)?;
+    zip.write_all(redactor.apply(&network_info()).as_bytes())?;
+    for (name, text) in config::config_snapshots() {
+        zip.start_file(format!("config/{}", name), options)?;
+        zip.write_all(redactor.apply(&text).as_bytes())?;
+    }
+    let dir = Config::log_path();
+    for file in recent_logs(&dir, MAX_LOG_SIZE) {
+        let Ok(data) = std::fs::read(&file) else {
+            continue;
+        };
+        let name = file.strip_prefix(&dir).unwrap_or(&file);
+        zip.start_file(format!("logs/{}", name.to_string_lossy()), options)?;
+        zip.write_all(redactor.apply(&String::from_utf8_lossy(&data)).as_bytes())?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+fn version_info() -> String {
+    format!(
+        "version: {}\nos: {}\narch: {}\nos_version: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        crate::whoami::distro(),
+    )
+}
+
+fn network_info() -> String {
+    let nat_type = Config::get_nat_type();
+    let mut s = format!(
+        "nat_type: {:?} ({})\nrendezvous_server: {}\nrendezvous_servers: {:?}\n",
+        NatType::from_i32(nat_type),
+        nat_type,
+        Config::get_rendezvous_server(),
+        Config::get_rendezvous_servers(),
+    );
+    s.push_str("latencies (ms):\n");
+    for (host, latency) in Config::get_latencies() {
+        writeln!(s, "  {}: {}", host, latency).ok();
+    }
+    s
+}
+
+/// The plain log files of `dir` and its subdirs, the newest first, up to `max`
+/// bytes. Compressed rotated files are skipped, they cannot be redacted.
+fn recent_logs(dir: &Path, max: u64) -> Vec<PathBuf> {
+    let mut files: Vec<(PathBuf, u64, SystemTime)> = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let Ok(meta) = entry.metadata() else {
+                continue;
+            };
+            let path = entry.path();
+            if meta.is_dir() {
+                dirs.push(path);
+            } else if path.extension().map_or(false, |e| e == "log") {
+                let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                files.push((path, meta.len(), modified));
+            }
+        }
+    }
+    files.sort_by(|a, b| b.2.cmp(&a.2));
+    let mut total = 0;
+    files
+        .into_iter()
+        .take_while(|f| {
+            total += f.1;
+            total <= max
+        })
+        .map(|f| f.0)
+        .collect()
+}
+
+/// Masks secrets and replaces the own ID with `<id>` and peer IDs with
+/// `<peer-N>`.
+struct Redactor {
+    ids: Option<(Regex, Vec<String>)>,
+}
+
+impl Redactor {
+    fn new() -> Self {
+        let mut ids = vec![Config::get_id()];
+        ids.extend(
+            PeerConfig::get_vec_id_modified_time_path(&None)
+                .into_iter()
+                .map(|x| x.0),
+        );
+        Self::with_ids(ids)
+    }
+
+    fn with_ids(mut ids: Vec<String>) -> Self {
+        // too short ids would match unrelated numbers
+        ids.retain(|id| id.len() >= 3);
+        if ids.is_empty() {
+            return Self { ids: None };
+        }
+        // the longest first, so an id containing another is replaced whole
+        let mut sorted = ids.clone();
+        sorted.sort_by(|a, b| b.len().cmp(&a.len()));
+        let pattern = sorted
+            .iter()
+            .map(|id| regex::escape(id))
+            .collect::<Vec<_>>()
+            .join("|");
+        Self {
+            ids: Regex::new(&format!(r"\b(?:{})\b", pattern))
+                .ok()
+                .map(|re| (re, ids)),
+        }
+    }
+
+    fn apply(&self, text: &str) -> String {
+        let text = logging::redact(text);
+        let Some((re, ids)) = &self.ids else {
+            return text.into_owned();
+        };
+        re.replace_all(&text, |c: &regex::Captures| {
+            match ids.iter().position(|id| id == &c[0]) {
+                Some(0) => "<id>".to_owned(),
+                Some(i) => format!("<peer-{}>", i),
+                None => c[0].to_owned(),
+            }
+        })
+        .into_owned()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum CheckStatus {
+    Passed,
+    Failed,
+    /// Not applicable, e.g. the proxy check without a proxy.
+    Skipped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub target: String,
+    pub status: CheckStatus,
+    /// The error, or the result, e.g. the resolved addresses.
+    pub detail: String,
+    pub elapsed_ms: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SelfTestReport {
+    pub checks: Vec<Check>,
+    /// `NatType`, UNKNOWN_NAT if the test failed.
+    pub nat_type: i32,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|c| c.status != CheckStatus::Failed)
+    }
+}
+
+async fn check<F, T>(name: &'static str, target: &str, f: F) -> Check
+where
+    F: Future<Output = ResultType<T>>,
+    T: ToString,
+{
+    let start = Instant::now();
+    let res = crate::timeout(CHECK_TIMEOUT, f).await;
+    let (status, detail) = match res {
+        Ok(Ok(v)) => (CheckStatus::Passed, v.to_string()),
+        Ok(Err(err)) => (CheckStatus::Failed, err.to_string()),
+        Err(_) => (CheckStatus::Failed, "timeout".to_owned()),
+    };
+    Check {
+        name,
+        target: target.to_owned(),
+        status,
+        detail,
+        elapsed_ms: start.elapsed().as_millis() as _,
+    }
+}
+
+fn skipped(name: &'static str, detail: &str) -> Check {
+    Check {
+        name,
+        target: String::new(),
+        status: CheckStatus::Skipped,
+        detail: detail.to_owned(),
+        elapsed_ms: 0,
+    }
+}
+
+/// Checks DNS resolution of the rendezvous server, the TCP, UDP and websocket
+/// ports of the rendezvous and relay servers (21116-21119 by default, or the
+/// configured ones), the proxy and the NAT type. The checks run concurrently,
+/// each bounded by a few seconds.
+pub async fn self_test() -> SelfTestReport {
+    let rendezvous = Config::get_rendezvous_server();
+    let Some((host, port)) = split_host_port(&rendezvous) else {
+        return SelfTestReport {
+            checks: vec![Check {
+                name: "rendezvous_server",
+                target: rendezvous,
+                status: CheckStatus::Failed,
+                detail: "invalid address".to_owned(),
+                elapsed_ms: 0,
+            }],
+            ..Default::default()
+        };
+    };
+    let mut relay = Config::get_option(keys::OPTION_RELAY_SERVER);
+    if relay.is_empty() {
+        relay = format!("{}:{}", host, port + 1);
+    } else if split_host_port(&relay).is_none() {
+        relay = socket_client::check_port(relay, port + 1);
+    }
+    let ws_rendezvous = format!("ws://{}:{}", host, port + 2);
+    let ws_relay = split_host_port(&relay)
+        .map(|(host, port)| format!("ws://{}:{}", host, port + 2))
+        .unwrap_or_default();
+    let proxy = async {
+        match Config::get_socks() {
+            Some(conf) => {
+                check("proxy", &conf.proxy, async {
+                    FramedStream::connect(rendezvous.as_str(), None, &conf, CHECK_TIMEOUT).await?;
+                    Ok("connected")
+                })
+                .await
+            }
+            None => skipped("proxy", "no proxy configured"),
+        }
+    };
+    let nat = async {
+        let start = Instant::now();
+        let res = crate::timeout(CHECK_TIMEOUT * 2, test_nat_type(&host, port)).await;
+        let nat_type = match &res {
+            Ok(Ok(nat_type)) => *nat_type,
+            _ => NatType::UNKNOWN_NAT,
+        };
+        let (status, detail) = match res {
+            Ok(Ok(nat_type)) => (CheckStatus::Passed, format!("{:?}", nat_type)),
+            Ok(Err(err)) => (CheckStatus::Failed, err.to_string()),
+            Err(_) => (CheckStatus::Failed, "timeout".to_owned()),
+        };
+        let check = Check {
+            name: "nat_type",
+            target: format!("{}:{}", host, port - 1),
+            status,
+            detail,
+            elapsed_ms: start.elapsed().as_millis() as _,
+        };
+        (check, nat_type)
+    };
+    let (dns, rendezvous_tcp, rendezvous_udp, relay_tcp, rendezvous_ws, relay_ws, proxy, nat) = futures::join!(
+        check("dns", &rendezvous, async {
+            let addrs: Vec<_> = tokio::net::lookup_host(rendezvous.as_str())
+                .await?
+                .map(|a| a.ip().to_string())
+                .collect();
+            Ok(addrs.join(", "))
+        }),
+        check("rendezvous_tcp", &rendezvous, async {
+            socket_client::connect_tcp_local(rendezvous.as_str(), None, CHECK_TIMEOUT).await?;
+            Ok("connected")
+        }),
+        check("rendezvous_udp", &rendezvous, test_udp(&rendezvous)),
+        check("relay_tcp", &relay, async {
+            socket_client::connect_tcp_local(relay.as_str(), None, CHECK_TIMEOUT).await?;
+            Ok("connected")
+        }),
+        check("rendezvous_ws", &ws_rendezvous, async {
+            websocket::WsFramedStream::new(&ws_rendezvous, None, None, CHECK_TIMEOUT).await?;
+            Ok("connected")
+        }),
+        check("relay_ws", &ws_relay, async {
+            websocket::WsFramedStream::new(&ws_relay, None, None, CHECK_TIMEOUT).await?;
+            Ok("connected")
+        }),
+        proxy,
+        nat,
+    );
+    SelfTestReport {
+        checks: vec![
+            dns,
+            rendezvous_tcp,
+            rendezvous_udp,
+            relay_tcp,
+            rendezvous_ws,
+            relay_ws,
+            proxy,
+            nat.0,
+        ],
+        nat_type: nat.1.value(),
+    }
+}
+
+/// Sends a NAT test request, which registers nothing unlike the periodic
+/// registration, any reply passes.
+async fn test_udp(rendezvous: &str) -> ResultType<&'static str> {
+    let (mut socket, addr) = socket_client::new_udp_for(rendezvous, CHECK_TIMEOUT).await?;
+    let mut msg = RendezvousMessage::new();
+    msg.set_test_nat_request(TestNatRequest {
+        serial: Config::get_serial(),
+        ..Default::default()
+    });
+    socket.send(&msg, addr).await?;
+    match socket.next_timeout(CHECK_TIMEOUT).await {
+        Some(Ok(_)) => Ok("replied"),
+        Some(Err(err)) => Err(err),
+        None => bail!("no reply"),
+    }
+}
+
+/// The ports seen by the server from the same local port to its NAT test port
+/// (rendezvous port - 1) and rendezvous port, they differ behind a symmetric
+/// NAT.
+async fn test_nat_type(host: &str, port: i32) -> ResultType<NatType> {
+    let mut local = None;
+    let mut ports = Vec::new();
+    for port in [port - 1, port] {
+        let target = format!("{}:{}", host, port);
+        let mut stream: Stream =
+            socket_client::connect_tcp_local(target.as_str(), local, CHECK_TIMEOUT).await?;
+        if local.is_none() {
+            local = Some(stream.local_addr());
+        }
+        let mut msg = RendezvousMessage::new();
+        msg.set_test_nat_request(TestNatRequest {
+            serial: Config::get_serial(),
+            ..Default::default()
+        });
+        stream.send(&msg).await?;
+        let Some(Ok(bytes)) = stream.next_timeout(CHECK_TIMEOUT).await else {
+            bail!("no reply from {}", target);
+        };
+        match RendezvousMessage::parse_from_bytes(&bytes)?.union {
+            Some(rendezvous_message::Union::TestNatResponse(res)) => ports.push(res.port),
+            _ => bail!("unexpected reply from {}", target),
+        }
+    }
+    Ok(if ports[0] == ports[1] {
+        NatType::ASYMMETRIC
+    } else {
+        NatType::SYMMETRIC
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redactor() {
+        let redactor = Redactor::with_ids(vec![
+            "123456789".to_owned(),
+            "987654321".to_owned(),
+            "12".to_owned(),
+        ]);
+        assert_eq!(
+            redactor.apply(r#"id = "123456789", connect to 987654321, password: "x", 12"#),
+            r#"id = "<id>", connect to <peer-1>, password: ***, 12"#
+        );
+        assert_eq!(redactor.apply("1234567890"), "1234567890");
+    }
+
+    #[test]
+    fn test_recent_logs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+        std::fs::create_dir_all(dir.join("server")).unwrap();
+        for (i, name) in ["a.log", "server/b.log", "c.log", "d.log.gz"]
+            .iter()
+            .enumerate()
+        {
+            let path = dir.join(name);
+            std::fs::write(&path, vec![b'x'; 100]).unwrap();
+            let t = filetime::FileTime::from_unix_time(1_000_000 + i as i64, 0);
+            filetime::set_file_mtime(&path, t).unwrap();
+        }
+        assert_eq!(
+            recent_logs(&dir, 250),
+            vec![dir.join("c.log"), dir.join("server/b.log")]
+        );
+    }
+}
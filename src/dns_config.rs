@@ -0,0 +1,312 @@
+// The server settings of a deployment from a TXT record of its domain, so
+// onboarding a machine only requires typing the domain:
+//
+//     _rustdesk-cfg.example.com. TXT "v=1;host=rs.example.com;port=21116;relay=rs.example.com:21117;api=https://api.example.com;key=<base64>;sig=<base64>"
+//
+// `sig` is the detached signature of "<domain>;" followed by the record up to
+// ";sig=", e.g. "example.com;v=1;host=...", so a record can not be served for
+// another domain. It is verified with the option `dns-config-pub-key`, which must
+// be set, e.g. by the custom client, as the keys of a rendezvous server are the
+// ones a spoofed record would replace.
+//
+// The settings are applied as overwrite settings, in the layer `Layer::Dns` below
+// the managed ones, so a policy or a MDM profile still wins over the record:
+//   host, port  custom-rendezvous-server, "host:port" if the port is given
+//   relay       relay-server
+//   api         api-server, https only
+//   key         key
+// and the record is kept in `<APP_NAME>_dns_config.toml`, to be verified and
+// applied again by `load` at startup without a lookup. The record is looked up
+// with the resolvers of the system, with a public one if it has none only with
+// the option `allow-dns-config-public-resolver`, as it leaks the domain.
+use crate::{
+    bail,
+    config::{keys, load_path, store_path, Config, APP_NAME},
+    options::OVERWRITE_SETTINGS,
+    server_list,
+    settings_map::Layer,
+    ResultType,
+};
+use serde_derive::{Deserialize, Serialize};
+use sodiumoxide::{base64, crypto::sign};
+use std::{collections::HashMap, path::PathBuf};
+
+pub const PREFIX: &str = "_rustdesk-cfg";
+const VERSION: &str = "1";
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DnsConfig {
+    pub domain: String,
+    pub record: String,
+}
+
+impl DnsConfig {
+    fn path() -> PathBuf {
+        let filename = format!("{}_dns_config.toml", APP_NAME.read().unwrap().clone());
+        Config::path(filename)
+    }
+
+    pub fn load() -> Self {
+        load_path(Self::path())
+    }
+
+    fn store(&self) {
+        if let Err(err) = store_path(Self::path(), self) {
+            log::error!("Failed to store the dns config: {}", err);
+        }
+    }
+}
+
+/// Verifies `record` of `domain` with any of `keys`, in base64, and returns the
+/// overwrite settings.
+pub fn parse(domain: &str, record: &str, keys: &[String]) -> ResultType<HashMap<String, String>> {
+    if keys.is_empty() {
+        bail!("No key to verify the dns config with");
+    }
+    let Some(pos) = record.find(";sig=") else {
+        bail!("No signature in the dns config");
+    };
+    let (signed, sig) = (&record[..pos], &record[pos + ";sig=".len()..]);
+    let message = format!("{};{}", domain, signed);
+    let decode = |x: &str| base64::decode(x.trim(), base64::Variant::Original);
+    let Some(sig) = decode(sig)
+        .ok()
+        .and_then(|x| sign::Signature::from_bytes(&x).ok())
+    else {
+        bail!("Invalid signature of the dns config");
+    };
+    let verified = keys.iter().any(|key| {
+        decode(key)
+            .ok()
+            .and_then(|x| sign::PublicKey::from_slice(&x))
+            .map_or(false, |pk| {
+                sign::verify_detached(&sig, message.as_bytes(), &pk)
+            })
+    });
+    if !verified {
+        bail!("Invalid signature of the dns config");
+    }
+    let fields: HashMap<&str, &str> = signed
+        .split(';')
+        .filter_map(|x| x.split_once('='))
+        .map(|(k, v)| (k.trim(), v.trim()))
+        .collect();
+    if fields.get("v") != Some(&VERSION) {
+        bail!("Unsupported version of the dns config");
+    }
+    let Some(host) = fields.get("host").filter(|x| !x.is_empty()) else {
+        bail!("No host in the dns config");
+    };
+    let host = match fields.get("port") {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    if !server_list::is_valid_server(&host) {
+        bail!("Invalid host {} in the dns config", host);
+    }
+    let mut settings = HashMap::new();
+    settings.insert(keys::OPTION_CUSTOM_RENDEZVOUS_SERVER.to_owned(), host);
+    if let Some(relay) = fields.get("relay") {
+        if !server_list::is_valid_server(relay) {
+            bail!("Invalid relay {} in the dns config", relay);
+        }
+        settings.insert(keys::OPTION_RELAY_SERVER.to_owned(), relay.to_string());
+    }
+    if let Some(api) = fields.get("api") {
+        if !api.starts_with("https://") || url::Url::parse(api).is_err() {
+            bail!("Invalid api server {} in the dns config", api);
+        }
+        settings.insert(keys::OPTION_API_SERVER.to_owned(), api.to_string());
+    }
+    if let Some(key) = fields.get("key") {
+        let valid = decode(key)
+            .ok()
+            .and_then(|x| sign::PublicKey::from_slice(&x))
+            .is_some();
+        if !valid {
+            bail!("Invalid key in the dns config");
+        }
+        settings.insert(keys::OPTION_KEY.to_owned(), key.to_string());
+    }
+    Ok(settings)
+}
+
+/// The keys a record is verified with, none if the option is not set.
+pub fn keys() -> Vec<String> {
+    let key = Config::get_option(keys::OPTION_DNS_CONFIG_PUB_KEY);
+    if key.is_empty() {
+        return vec![];
+    }
+    vec![key]
+}
+
+/// Applies `settings` in place of those applied before.
+fn apply(settings: HashMap<String, String>) {
+    OVERWRITE_SETTINGS.set_layer(Layer::Dns, settings);
+}
+
+fn is_valid_domain(domain: &str) -> bool {
+    domain.len() <= 253
+        && domain.contains('.')
+        && domain.split('.').all(|x| {
+            !x.is_empty()
+                && x.len() <= 63
+                && !x.starts_with('-')
+                && x.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// The TXT records of `name`, the strings of each joined.
+async fn lookup_txt(name: &str) -> ResultType<Vec<String>> {
+    use hickory_resolver::{
+        config::{ResolverConfig, ResolverOpts},
+        TokioAsyncResolver,
+    };
+    let resolver = match TokioAsyncResolver::tokio_from_system_conf() {
+        Ok(resolver) => resolver,
+        // e.g. no resolv.conf on Android
+        Err(_) if Config::get_bool_option(keys::OPTION_ALLOW_DNS_CONFIG_PUBLIC_RESOLVER) => {
+            TokioAsyncResolver::tokio(ResolverConfig::cloudflare(), ResolverOpts::default())
+        }
+        Err(err) => bail!("No resolver for the dns config: {}", err),
+    };
+    let lookup = resolver.txt_lookup(name).await?;
+    Ok(lookup
+        .iter()
+        .map(|txt| {
+            txt.txt_data()
+                .iter()
+                .map(|x| String::from_utf8_lossy(x))
+                .collect()
+        })
+        .collect())
+}
+
+/// Looks up, verifies and applies the record of `domain`, and keeps it for
+/// `load`. Returns the settings applied.
+pub async fn bootstrap(domain: &str) -> ResultType<HashMap<String, String>> {
+    let domain = domain.trim().trim_end_matches('.').to_lowercase();
+    if !is_valid_domain(&domain) {
+        bail!("Invalid domain {}", domain);
+    }
+    let name = format!("{}.{}.", PREFIX, domain);
+    let records = lookup_txt(&name).await?;
+    let version = format!("v={};", VERSION);
+    let Some(record) = records.into_iter().find(|x| x.starts_with(&version)) else {
+        bail!("No dns config in {}", name);
+    };
+    let settings = parse(&domain, &record, &keys())?;
+    DnsConfig { domain, record }.store();
+    apply(settings.clone());
+    log::info!("Dns config of {} applied", name);
+    Ok(settings)
+}
+
+/// Applies the kept record again, to be called at startup. Returns false if
+/// none is kept or it does not verify anymore.
+pub fn load() -> bool {
+    let config = DnsConfig::load();
+    if config.record.is_empty() {
+        return false;
+    }
+    match parse(&config.domain, &config.record, &keys()) {
+        Ok(settings) => {
+            apply(settings);
+            true
+        }
+        Err(err) => {
+            log::error!(
+                "Failed to apply the dns config of {}: {}",
+                config.domain,
+                err
+            );
+            false
+        }
+    }
+}
+
+/// Removes the settings applied and the kept record.
+pub fn remove() {
+    apply(HashMap::new());
+    DnsConfig::default().store();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    #[test]
+    fn test_parse() {
+        let _t = test_config();
+        let (pk, sk) = sign::gen_keypair();
+        let encode = |x: &[u8]| base64::encode(x, base64::Variant::Original);
+        let keys = vec![encode(&pk.0)];
+        let domain = "example.com";
+        let sign = |x: &str| {
+            let message = format!("{};{}", domain, x);
+            let sig = sign::sign_detached(message.as_bytes(), &sk);
+            format!("{};sig={}", x, encode(sig.to_bytes().as_ref()))
+        };
+        let parse = |x: &str, keys: &[String]| parse(domain, x, keys);
+        let signed = format!(
+            "v=1;host=rs.example.com;port=21116;relay=rs.example.com:21117;api=https://api.example.com;key={}",
+            encode(&pk.0)
+        );
+        let settings = parse(&sign(&signed), &keys).unwrap();
+        assert_eq!(
+            settings
+                .get(keys::OPTION_CUSTOM_RENDEZVOUS_SERVER)
+                .map(|x| x.as_str()),
+            Some("rs.example.com:21116")
+        );
+        assert_eq!(settings.len(), 4);
+        let tampered = sign(&signed).replace("rs.example.com:21117", "rs.evil.com:21117");
+        assert!(parse(&tampered, &keys).is_err());
+        assert!(parse(&signed, &keys).is_err());
+        assert!(super::parse("evil.com", &sign(&signed), &keys).is_err());
+        assert!(parse(&sign(&signed), &[]).is_err());
+        // not pinned
+        assert!(super::keys().is_empty());
+        Config::set_option(keys::OPTION_DNS_CONFIG_PUB_KEY.to_owned(), keys[0].clone());
+        assert_eq!(super::keys(), keys);
+        assert!(parse(
+            &sign("v=1;host=rs.example.com;api=http://api.example.com"),
+            &keys
+        )
+        .is_err());
+        assert!(parse(&sign("v=2;host=rs.example.com"), &keys).is_err());
+        assert!(is_valid_domain("example.com"));
+        assert!(!is_valid_domain("example..com"));
+
+        apply(settings);
+        assert_eq!(
+            Config::get_option(keys::OPTION_API_SERVER),
+            "https://api.example.com"
+        );
+        apply(parse(&sign("v=1;host=rs.example.com"), &keys).unwrap());
+        assert_eq!(Config::get_option(keys::OPTION_API_SERVER), "");
+        // below the policies
+        let mut policy = HashMap::new();
+        policy.insert(
+            keys::OPTION_CUSTOM_RENDEZVOUS_SERVER.to_owned(),
+            "rs.corp.example.com".to_owned(),
+        );
+        OVERWRITE_SETTINGS.set_layer(Layer::Policy, policy);
+        assert_eq!(
+            Config::get_option(keys::OPTION_CUSTOM_RENDEZVOUS_SERVER),
+            "rs.corp.example.com"
+        );
+        OVERWRITE_SETTINGS.set_layer(Layer::Policy, HashMap::new());
+        assert_eq!(
+            Config::get_option(keys::OPTION_CUSTOM_RENDEZVOUS_SERVER),
+            "rs.example.com"
+        );
+        remove();
+        assert_eq!(
+            Config::get_option(keys::OPTION_CUSTOM_RENDEZVOUS_SERVER),
+            ""
+        );
+    }
+}
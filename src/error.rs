@@ -0,0 +1,148 @@
+// Typed errors of the public API, so callers can branch on the cause of a
+// failure instead of matching on messages. Only `sandbox` returns it so far, the
+// other functions return `ResultType`, classified with `Error::classify`.
+//
+// `Error` converts into `anyhow::Error`, so functions returning it can still be
+// called with `?` from ones returning `ResultType`. The other way round,
+// `Error::classify` recovers the cause from an `anyhow::Error` of the functions
+// not migrated yet.
+use crate::{ab::AbError, proxy::ProxyError, transfer_policy::Rejection};
+use thiserror::Error as ThisError;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Variants are added as more of the API is migrated.
+#[derive(Debug, ThisError)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Parse error: {0}")]
+    Parse(String),
+    #[error("Timeout")]
+    Timeout,
+    #[error("Proxy error: {0}")]
+    Proxy(#[from] ProxyError),
+    /// Refused by a setting of the administrator, e.g. the allowed paths, the
+    /// transfer limits or a read-only address book, retrying does not help.
+    #[error("Policy denied: {0}")]
+    PolicyDenied(String),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl Error {
+    /// The typed cause of an error returned as `anyhow::Error`, `Other` if unknown.
+    pub fn classify(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<Error>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        let err = match err.downcast::<std::io::Error>() {
+            Ok(err) => return Self::from(err),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<ProxyError>() {
+            Ok(err) => return Self::from(err),
+            Err(err) => err,
+        };
+        if err.is::<tokio::time::error::Elapsed>() {
+            return Self::Timeout;
+        }
+        if let Some(err) = err.downcast_ref::<Rejection>() {
+            return Self::from(err.clone());
+        }
+        if let Some(err) = err.downcast_ref::<AbError>() {
+            return Self::from(err.clone());
+        }
+        if let Some(err) = err.downcast_ref::<toml::de::Error>() {
+            return Self::Parse(err.to_string());
+        }
+        if let Some(err) = err.downcast_ref::<serde_json::Error>() {
+            return Self::Parse(err.to_string());
+        }
+        if let Some(err) = err.downcast_ref::<protobuf::Error>() {
+            return Self::Parse(err.to_string());
+        }
+        Self::Other(err)
+    }
+
+    /// Whether the same operation may succeed later.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Timeout | Self::Proxy(_) => true,
+            Self::Io(err) => matches!(
+                err.kind(),
+                std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::ConnectionRefused
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::WouldBlock
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl From<tokio::time::error::Elapsed> for Error {
+    fn from(_: tokio::time::error::Elapsed) -> Self {
+        Self::Timeout
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(err: toml::de::Error) -> Self {
+        Self::Parse(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Parse(err.to_string())
+    }
+}
+
+impl From<protobuf::Error> for Error {
+    fn from(err: protobuf::Error) -> Self {
+        Self::Parse(err.to_string())
+    }
+}
+
+impl From<Rejection> for Error {
+    fn from(err: Rejection) -> Self {
+        Self::PolicyDenied(err.to_string())
+    }
+}
+
+impl From<AbError> for Error {
+    fn from(err: AbError) -> Self {
+        match err {
+            AbError::ReadOnly { .. } | AbError::NotPermitted { .. } => {
+                Self::PolicyDenied(err.to_string())
+            }
+            _ => Self::Other(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let err: anyhow::Error = std::io::Error::from(std::io::ErrorKind::ConnectionReset).into();
+        let err = Error::classify(err);
+        assert!(matches!(err, Error::Io(_)));
+        assert!(err.is_transient());
+        let err: anyhow::Error = Error::PolicyDenied("x".to_owned()).into();
+        assert!(matches!(Error::classify(err), Error::PolicyDenied(_)));
+        let err = Error::classify(toml::from_str::<toml::Value>("a =").unwrap_err().into());
+        assert!(matches!(err, Error::Parse(_)));
+        assert!(!err.is_transient());
+        let err = Error::classify(anyhow::anyhow!("unknown"));
+        assert!(matches!(err, Error::Other(_)));
+        assert_eq!(err.to_string(), "unknown");
+    }
+}
@@ -0,0 +1,366 @@
+// XOR based forward error correction for UDP media datagrams.
+//
+// Data packets are sent immediately, after every `k` of them `m` parity packets
+// are appended. Parity `j` covers the data packets whose index % m == j, so one
+// loss per stripe can be recovered without waiting for a retransmission.
+//
+// Wire format: group (u32 le) | index (u8) | k (u8) | m (u8) | body
+// m is fixed for a group, a new redundancy only applies from the next group on,
+// so all the packets of a group carry the same m.
+// Parity packets have PARITY_FLAG set in index, their body is the xor of
+// (payload length (u16 le) + payload) of the covered data packets.
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::{HashMap, VecDeque};
+
+const HEADER_LEN: usize = 7;
+const PARITY_FLAG: u8 = 0x80;
+pub const DEFAULT_GROUP_SIZE: usize = 10;
+pub const MAX_GROUP_SIZE: usize = 0x7f;
+pub const MAX_PARITY: usize = 5;
+// How many groups are kept on the receiving side for late packets.
+const MAX_PENDING_GROUPS: usize = 16;
+
+/// Parity packets per group for the observed loss rate (0.0 ~ 1.0).
+pub fn parity_for_loss(loss: f32, k: usize) -> usize {
+    let m = if loss < 0.01 {
+        0
+    } else if loss < 0.05 {
+        1
+    } else if loss < 0.1 {
+        2
+    } else if loss < 0.2 {
+        3
+    } else {
+        MAX_PARITY
+    };
+    m.min(k / 2).min(MAX_PARITY)
+}
+
+fn packet(group: u32, index: u8, k: usize, m: usize, body: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + body.len());
+    buf.put_u32_le(group);
+    buf.put_u8(index);
+    buf.put_u8(k as _);
+    buf.put_u8(m as _);
+    buf.put_slice(body);
+    buf.freeze()
+}
+
+fn xor_into(dst: &mut Vec<u8>, payload: &[u8]) {
+    let len = (payload.len() as u16).to_le_bytes();
+    let need = payload.len() + 2;
+    if dst.len() < need {
+        dst.resize(need, 0);
+    }
+    for (d, s) in dst.iter_mut().zip(len.iter().chain(payload.iter())) {
+        *d ^= s;
+    }
+}
+
+pub struct FecEncoder {
+    group: u32,
+    k: usize,
+    m: usize,
+    // m of the next group
+    next_m: usize,
+    count: usize,
+    pending: Vec<Bytes>,
+}
+
+impl Default for FecEncoder {
+    fn default() -> Self {
+        Self::new(DEFAULT_GROUP_SIZE, 0)
+    }
+}
+
+impl FecEncoder {
+    pub fn new(k: usize, m: usize) -> Self {
+        let k = k.clamp(1, MAX_GROUP_SIZE);
+        let m = m.min(MAX_PARITY).min(k);
+        Self {
+            group: 0,
+            k,
+            m,
+            next_m: m,
+            count: 0,
+            pending: Vec::with_capacity(k),
+        }
+    }
+
+    /// Parity packets of the next group.
+    #[inline]
+    pub fn parity(&self) -> usize {
+        self.next_m
+    }
+
+    /// Adapt the redundancy to the loss rate reported by the receiver,
+    /// takes effect with the next group.
+    pub fn update_loss(&mut self, loss: f32) {
+        let m = parity_for_loss(loss, self.k);
+        if m != self.next_m {
+            log::debug!("FEC parity {} -> {} (loss {:.3})", self.next_m, m, loss);
+            self.next_m = m;
+        }
+    }
+
+    /// Returns the datagrams to send for `payload`, i.e. the data packet and
+    /// the parity packets if the group is complete.
+    pub fn encode(&mut self, payload: &[u8]) -> Vec<Bytes> {
+        if payload.len() > u16::MAX as usize {
+            log::error!("FEC payload too large: {}", payload.len());
+            return vec![];
+        }
+        if self.count == 0 {
+            self.m = self.next_m;
+        }
+        let mut out = vec![packet(self.group, self.count as _, self.k, self.m, payload)];
+        if self.m > 0 {
+            self.pending.push(Bytes::copy_from_slice(payload));
+        }
+        self.count += 1;
+        if self.count >= self.k {
+            out.extend(self.finish_group());
+        }
+        out
+    }
+
+    /// Emit parity for a partial group, e.g. at the end of a video frame.
+    pub fn flush(&mut self) -> Vec<Bytes> {
+        if self.count == 0 {
+            return vec![];
+        }
+        self.finish_group()
+    }
+
+    fn finish_group(&mut self) -> Vec<Bytes> {
+        // k of a parity packet is the real group size, which is smaller after flush
+        let k = self.count;
+        let m = self.m.min(self.pending.len());
+        let mut out = Vec::with_capacity(m);
+        for j in 0..m {
+            let mut parity = vec![];
+            for payload in self.pending.iter().skip(j).step_by(m) {
+                xor_into(&mut parity, payload);
+            }
+            out.push(packet(self.group, PARITY_FLAG | j as u8, k, m, &parity));
+        }
+        self.pending.clear();
+        self.count = 0;
+        self.group = self.group.wrapping_add(1);
+        out
+    }
+}
+
+#[derive(Default)]
+struct Group {
+    k: usize,
+    m: usize,
+    // k is only final once a parity packet told us
+    k_final: bool,
+    received: usize,
+    data: Vec<Option<Bytes>>,
+    parity: Vec<Option<Bytes>>,
+}
+
+impl Group {
+    fn is_missing(&self, i: usize) -> bool {
+        self.data.get(i).map_or(true, |x| x.is_none())
+    }
+
+    fn recover(&mut self) -> Vec<Bytes> {
+        let mut out = vec![];
+        if !self.k_final || self.m == 0 {
+            return out;
+        }
+        for j in 0..self.m {
+            let Some(Some(parity)) = self.parity.get(j) else {
+                continue;
+            };
+            let missing: Vec<usize> = (j..self.k)
+                .step_by(self.m)
+                .filter(|i| self.is_missing(*i))
+                .collect();
+            if missing.len() != 1 {
+                continue;
+            }
+            let mut buf = parity.to_vec();
+            for i in (j..self.k).step_by(self.m) {
+                if let Some(Some(payload)) = self.data.get(i) {
+                    xor_into(&mut buf, payload);
+                }
+            }
+            if buf.len() < 2 {
+                continue;
+            }
+            let len = u16::from_le_bytes([buf[0], buf[1]]) as usize;
+            if len + 2 > buf.len() {
+                continue;
+            }
+            let payload = Bytes::copy_from_slice(&buf[2..2 + len]);
+            let i = missing[0];
+            if self.data.len() <= i {
+                self.data.resize(i + 1, None);
+            }
+            self.data[i] = Some(payload.clone());
+            out.push(payload);
+        }
+        out
+    }
+}
+
+#[derive(Default)]
+pub struct FecDecoder {
+    groups: HashMap<u32, Group>,
+    order: VecDeque<u32>,
+    received: u64,
+    expected: u64,
+    recovered: u64,
+}
+
+impl FecDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the payloads that became available with `packet`, recovered
+    /// payloads may be out of order.
+    pub fn decode(&mut self, packet: &[u8]) -> Vec<Bytes> {
+        if packet.len() < HEADER_LEN {
+            return vec![];
+        }
+        let group_id = u32::from_le_bytes([packet[0], packet[1], packet[2], packet[3]]);
+        let index = packet[4];
+        let k = packet[5] as usize;
+        let m = packet[6] as usize;
+        let body = Bytes::copy_from_slice(&packet[HEADER_LEN..]);
+        if !self.groups.contains_key(&group_id) {
+            self.order.push_back(group_id);
+            if self.order.len() > MAX_PENDING_GROUPS {
+                if let Some(old) = self.order.pop_front() {
+                    if let Some(g) = self.groups.remove(&old) {
+                        self.account(&g);
+                    }
+                }
+            }
+        }
+        let group = self.groups.entry(group_id).or_default();
+        let mut out = vec![];
+        if index & PARITY_FLAG == 0 {
+            let i = index as usize;
+            if !group.k_final {
+                group.k = k;
+                group.m = m;
+            }
+            if group.data.len() <= i {
+                group.data.resize(i + 1, None);
+            }
+            if group.data[i].is_some() {
+                return out;
+            }
+            group.received += 1;
+            group.data[i] = Some(body.clone());
+            out.push(body);
+        } else {
+            let j = (index & !PARITY_FLAG) as usize;
+            group.k = k;
+            group.m = m;
+            group.k_final = true;
+            if group.parity.len() <= j {
+                group.parity.resize(j + 1, None);
+            }
+            group.parity[j] = Some(body);
+        }
+        let recovered = group.recover();
+        self.recovered += recovered.len() as u64;
+        out.extend(recovered);
+        out
+    }
+
+    fn account(&mut self, g: &Group) {
+        self.expected += g.k.max(g.received) as u64;
+        self.received += g.received as u64;
+    }
+
+    /// Raw loss rate (before recovery) of the groups already out of the window,
+    /// to be reported back for `FecEncoder::update_loss`.
+    pub fn loss_rate(&self) -> f32 {
+        if self.expected == 0 {
+            return 0.;
+        }
+        1. - self.received as f32 / self.expected as f32
+    }
+
+    #[inline]
+    pub fn recovered(&self) -> u64 {
+        self.recovered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recover() {
+        let mut enc = FecEncoder::new(4, 2);
+        let mut dec = FecDecoder::new();
+        let payloads: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; i as usize * 3 + 1]).collect();
+        let mut packets = vec![];
+        for p in payloads.iter() {
+            packets.extend(enc.encode(p));
+        }
+        assert_eq!(packets.len(), 6);
+        let mut got = vec![];
+        // lose one packet of each stripe
+        for (i, p) in packets.iter().enumerate() {
+            if i == 0 || i == 3 {
+                continue;
+            }
+            got.extend(dec.decode(p));
+        }
+        got.sort();
+        let mut expected: Vec<Bytes> = payloads.into_iter().map(Bytes::from).collect();
+        expected.sort();
+        assert_eq!(got, expected);
+        assert_eq!(dec.recovered(), 2);
+    }
+
+    #[test]
+    fn test_flush_and_adapt() {
+        let mut enc = FecEncoder::new(10, 1);
+        let mut dec = FecDecoder::new();
+        let a = enc.encode(b"hello");
+        let b = enc.encode(b"world!");
+        let parity = enc.flush();
+        assert_eq!(parity.len(), 1);
+        assert!(dec.decode(&a[0]).len() == 1);
+        assert_eq!(dec.decode(&parity[0]), vec![Bytes::from_static(b"world!")]);
+        assert!(dec.decode(&b[0]).is_empty());
+        enc.update_loss(0.);
+        assert_eq!(enc.parity(), 0);
+        enc.update_loss(0.3);
+        assert_eq!(enc.parity(), MAX_PARITY);
+    }
+
+    #[test]
+    fn test_update_at_group_boundary() {
+        let mut enc = FecEncoder::new(4, 0);
+        let mut dec = FecDecoder::new();
+        let mut packets = enc.encode(b"a");
+        enc.update_loss(0.3);
+        packets.extend(enc.encode(b"bb"));
+        packets.extend(enc.encode(b"ccc"));
+        packets.extend(enc.encode(b"dddd"));
+        // the group started without parity
+        assert_eq!(packets.len(), 4);
+        assert!(packets.iter().all(|p| p[6] == 0));
+        let packets = enc.encode(b"e");
+        assert_eq!(packets[0][6] as usize, 2);
+        let mut packets: Vec<Bytes> = packets.into_iter().chain(enc.encode(b"ff")).collect();
+        packets.extend(enc.flush());
+        assert_eq!(packets.len(), 4);
+        assert!(packets.iter().all(|p| p[6] == 2));
+        let got: Vec<Bytes> = packets[1..].iter().flat_map(|p| dec.decode(p)).collect();
+        assert!(got.contains(&Bytes::from_static(b"e")));
+    }
+}
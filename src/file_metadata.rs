@@ -0,0 +1,247 @@
+// Metadata of transferred files beyond the modification time: permissions,
+// access time and extended attributes, enabled by
+// `OPTION_ALLOW_PRESERVE_FILE_METADATA`.
+//
+// The sender puts `FileMetadata` on the block ending a file, the receiver
+// applies what the platform supports when the file is completed. Bits like
+// setuid are never applied.
+use crate::{
+    config::{keys, Config},
+    message_proto::{FileMetadata, FileXattr},
+};
+use std::path::Path;
+
+// Larger attributes (e.g. resource forks) are skipped.
+const MAX_XATTRS_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub permissions: bool,
+    pub timestamps: bool,
+    pub xattrs: bool,
+    // NTFS alternate data streams
+    pub ads: bool,
+}
+
+/// What this platform can read and apply.
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        permissions: cfg!(unix),
+        timestamps: true,
+        xattrs: cfg!(any(target_os = "linux", target_os = "macos")),
+        ads: false,
+    }
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    Config::get_bool_option(keys::OPTION_ALLOW_PRESERVE_FILE_METADATA)
+}
+
+fn secs(t: std::io::Result<std::time::SystemTime>) -> u64 {
+    t.ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn read(path: &Path) -> FileMetadata {
+    let mut res = FileMetadata::new();
+    if let Ok(meta) = std::fs::metadata(path) {
+        res.accessed_time = secs(meta.accessed());
+        res.modified_time = secs(meta.modified());
+        res.readonly = meta.permissions().readonly();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            res.mode = meta.permissions().mode() & 0o7777;
+        }
+    }
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    {
+        res.xattrs = xattr::read_all(path);
+    }
+    res
+}
+
+pub fn apply(path: &Path, meta: &FileMetadata) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = meta.mode & 0o777;
+        if mode != 0 {
+            let permissions = std::fs::Permissions::from_mode(mode);
+            if let Err(err) = std::fs::set_permissions(path, permissions) {
+                log::warn!("Failed to set permissions of {:?}: {}", path, err);
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        if meta.readonly {
+            if let Ok(m) = std::fs::metadata(path) {
+                let mut permissions = m.permissions();
+                permissions.set_readonly(true);
+                std::fs::set_permissions(path, permissions).ok();
+            }
+        }
+    }
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    {
+        for x in meta.xattrs.iter() {
+            xattr::set(path, x);
+        }
+    }
+    // last, setting attributes may touch the times
+    if meta.modified_time != 0 {
+        let mtime = filetime::FileTime::from_unix_time(meta.modified_time as _, 0);
+        let atime = if meta.accessed_time != 0 {
+            filetime::FileTime::from_unix_time(meta.accessed_time as _, 0)
+        } else {
+            mtime
+        };
+        filetime::set_file_times(path, atime, mtime).ok();
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod xattr {
+    use super::*;
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    // Other namespaces need privileges or are managed by the system.
+    #[cfg(target_os = "linux")]
+    fn is_allowed(name: &str) -> bool {
+        name.starts_with("user.")
+    }
+
+    // Of the system attributes only the Finder info and tags, never e.g. the
+    // quarantine, provenance or sandbox ones, which Gatekeeper and TCC rely on.
+    #[cfg(target_os = "macos")]
+    pub(super) fn is_allowed(name: &str) -> bool {
+        const ALLOWED: &[&str] = &[
+            "com.apple.FinderInfo",
+            "com.apple.metadata:_kMDItemUserTags",
+        ];
+        !name.starts_with("com.apple.") || ALLOWED.contains(&name)
+    }
+
+    fn c_path(path: &Path) -> Option<CString> {
+        CString::new(path.as_os_str().as_bytes()).ok()
+    }
+
+    unsafe fn list(path: &CString, buf: *mut libc::c_char, size: usize) -> isize {
+        #[cfg(target_os = "linux")]
+        return libc::listxattr(path.as_ptr(), buf, size);
+        #[cfg(target_os = "macos")]
+        return libc::listxattr(path.as_ptr(), buf, size, 0);
+    }
+
+    unsafe fn get(path: &CString, name: &CString, buf: *mut libc::c_void, size: usize) -> isize {
+        #[cfg(target_os = "linux")]
+        return libc::getxattr(path.as_ptr(), name.as_ptr(), buf, size);
+        #[cfg(target_os = "macos")]
+        return libc::getxattr(path.as_ptr(), name.as_ptr(), buf, size, 0, 0);
+    }
+
+    pub fn read_all(path: &Path) -> Vec<FileXattr> {
+        let Some(path) = c_path(path) else {
+            return Vec::new();
+        };
+        let n = unsafe { list(&path, std::ptr::null_mut(), 0) };
+        if n <= 0 {
+            return Vec::new();
+        }
+        let mut names = vec![0u8; n as usize];
+        let n = unsafe { list(&path, names.as_mut_ptr() as _, names.len()) };
+        if n <= 0 {
+            return Vec::new();
+        }
+        names.truncate(n as usize);
+        let mut res = Vec::new();
+        let mut total = 0;
+        for name in names.split(|c| *c == 0).filter(|x| !x.is_empty()) {
+            let Ok(name_str) = std::str::from_utf8(name) else {
+                continue;
+            };
+            let Ok(c_name) = CString::new(name) else {
+                continue;
+            };
+            if !is_allowed(name_str) {
+                continue;
+            }
+            let len = unsafe { get(&path, &c_name, std::ptr::null_mut(), 0) };
+            if len < 0 || total + len as usize > MAX_XATTRS_SIZE {
+                continue;
+            }
+            let mut value = vec![0u8; len as usize];
+            let len = unsafe { get(&path, &c_name, value.as_mut_ptr() as _, value.len()) };
+            if len < 0 {
+                continue;
+            }
+            value.truncate(len as usize);
+            total += value.len();
+            res.push(FileXattr {
+                name: name_str.to_owned(),
+                value: value.into(),
+                ..Default::default()
+            });
+        }
+        res
+    }
+
+    pub fn set(path: &Path, x: &FileXattr) {
+        if !is_allowed(&x.name) {
+            return;
+        }
+        let (Some(path_c), Ok(name)) = (c_path(path), CString::new(x.name.as_bytes())) else {
+            return;
+        };
+        let value = x.value.as_ptr() as *const libc::c_void;
+        #[cfg(target_os = "linux")]
+        let res =
+            unsafe { libc::setxattr(path_c.as_ptr(), name.as_ptr(), value, x.value.len(), 0) };
+        #[cfg(target_os = "macos")]
+        let res =
+            unsafe { libc::setxattr(path_c.as_ptr(), name.as_ptr(), value, x.value.len(), 0, 0) };
+        if res != 0 {
+            log::debug!(
+                "Failed to set xattr {} of {:?}: {}",
+                x.name,
+                path,
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hbb_metadata");
+        std::fs::write(&path, b"#!/bin/sh\n").unwrap();
+        let mut meta = read(&path);
+        assert_ne!(meta.modified_time, 0);
+        meta.modified_time = 1_000_000_000;
+        meta.accessed_time = 1_000_000_100;
+        #[cfg(unix)]
+        {
+            meta.mode = 0o4755;
+        }
+        apply(&path, &meta);
+        let applied = read(&path);
+        assert_eq!(applied.modified_time, 1_000_000_000);
+        #[cfg(unix)]
+        assert_eq!(applied.mode, 0o755);
+        #[cfg(target_os = "macos")]
+        {
+            assert!(!xattr::is_allowed("com.apple.quarantine"));
+            assert!(!xattr::is_allowed("com.apple.provenance"));
+            assert!(xattr::is_allowed("com.apple.FinderInfo"));
+        }
+    }
+}
@@ -1,26 +1,37 @@
-#[cfg(windows)]
-use std::os::windows::prelude::*;
 use std::{
+    collections::HashMap,
     fmt::{Debug, Display},
     io::Cursor,
     path::{Path, PathBuf},
     sync::atomic::{AtomicI32, Ordering},
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::SystemTime,
 };
 
 use serde_derive::{Deserialize, Serialize};
 use serde_json::json;
 use tokio::{
-    fs::{File, OpenOptions},
+    fs::File,
     io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufStream as TokioBufStream},
 };
 
-use crate::{anyhow::anyhow, bail, get_version_number, message_proto::*, ResultType, Stream};
-// https://doc.rust-lang.org/std/os/windows/fs/trait.MetadataExt.html
 use crate::{
-    compress::{compress, decompress},
-    config::Config,
+    anyhow::anyhow, bail, get_version_number, message_proto::*, protobuf::MessageField, ResultType,
+    Stream,
+};
+use crate::{
+    archive, backup, checksum_cache,
+    compress::{
+        is_compressed_magic, is_incompressible, CompressionStats, SessionCodecs, StatsSnapshot,
+    },
+    config::{Config, TransferFileRecord, TransferJobRecord},
+    delta, file_metadata,
+    fs_provider::provider,
+    progress::{self, JobProgress, TransferEvent},
+    sandbox::{self, check_path},
+    trace::Span,
+    transfer_policy::TransferPolicy,
 };
+use sodiumoxide::{base64, crypto::hash::sha256};
 
 static NEXT_JOB_ID: AtomicI32 = AtomicI32::new(1);
 
@@ -33,88 +44,14 @@ pub fn update_next_job_id(id: i32) {
 }
 
 pub fn read_dir(path: &Path, include_hidden: bool) -> ResultType<FileDirectory> {
-    let mut dir = FileDirectory {
-        path: get_string(path),
-        ..Default::default()
-    };
     #[cfg(windows)]
     if "/" == &get_string(path) {
-        let drives = unsafe { winapi::um::fileapi::GetLogicalDrives() };
-        for i in 0..32 {
-            if drives & (1 << i) != 0 {
-                let name = format!(
-                    "{}:",
-                    std::char::from_u32('A' as u32 + i as u32).unwrap_or('A')
-                );
-                dir.entries.push(FileEntry {
-                    name,
-                    entry_type: FileType::DirDrive.into(),
-                    ..Default::default()
-                });
-            }
-        }
-        return Ok(dir);
-    }
-    for entry in path.read_dir()?.flatten() {
-        let p = entry.path();
-        let name = p
-            .file_name()
-            .map(|p| p.to_str().unwrap_or(""))
-            .unwrap_or("")
-            .to_owned();
-        if name.is_empty() {
-            continue;
-        }
-        let mut is_hidden = false;
-        let meta;
-        if let Ok(tmp) = std::fs::symlink_metadata(&p) {
-            meta = tmp;
-        } else {
-            continue;
-        }
-        // docs.microsoft.com/en-us/windows/win32/fileio/file-attribute-constants
-        #[cfg(windows)]
-        if meta.file_attributes() & 0x2 != 0 {
-            is_hidden = true;
-        }
-        #[cfg(not(windows))]
-        if name.find('.').unwrap_or(usize::MAX) == 0 {
-            is_hidden = true;
-        }
-        if is_hidden && !include_hidden {
-            continue;
-        }
-        let (entry_type, size) = {
-            if p.is_dir() {
-                if meta.file_type().is_symlink() {
-                    (FileType::DirLink.into(), 0)
-                } else {
-                    (FileType::Dir.into(), 0)
-                }
-            } else if meta.file_type().is_symlink() {
-                (FileType::FileLink.into(), 0)
-            } else {
-                (FileType::File.into(), meta.len())
-            }
-        };
-        let modified_time = meta
-            .modified()
-            .map(|x| {
-                x.duration_since(std::time::SystemTime::UNIX_EPOCH)
-                    .map(|x| x.as_secs())
-                    .unwrap_or(0)
-            })
-            .unwrap_or(0);
-        dir.entries.push(FileEntry {
-            name: get_file_name(&p),
-            entry_type,
-            is_hidden,
-            size,
-            modified_time,
-            ..Default::default()
-        });
+        return provider().list(path, include_hidden);
     }
-    Ok(dir)
+    let resolved = check_path(path)?;
+    let mut fd = provider().list(&resolved, include_hidden)?;
+    fd.path = get_string(path);
+    Ok(fd)
 }
 
 #[inline]
@@ -140,57 +77,150 @@ pub fn get_home_as_string() -> String {
     get_string(&Config::get_home())
 }
 
+/// How symbolic links inside transferred folders are handled, from
+/// `OPTION_FILE_TRANSFER_SYMLINK`. A link given as the path to transfer is
+/// always followed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    #[default]
+    Skip,
+    /// Send the content, links to folders already visited are skipped.
+    Follow,
+    /// Send `link_target` and let the receiver create the link, see
+    /// `is_safe_link_target`.
+    Recreate,
+}
+
+impl SymlinkPolicy {
+    pub fn get() -> Self {
+        match Config::get_option(crate::config::keys::OPTION_FILE_TRANSFER_SYMLINK).as_str() {
+            "follow" => Self::Follow,
+            "recreate" => Self::Recreate,
+            _ => Self::Skip,
+        }
+    }
+}
+
+// Whether a directory of `name` below `root` is a link, which writing would follow.
+pub(crate) fn has_link_below(root: &Path, name: &str) -> bool {
+    let mut path = root.to_path_buf();
+    let Some(parent) = Path::new(name).parent() else {
+        return false;
+    };
+    parent.components().any(|c| {
+        path.push(c);
+        provider().is_link(&path)
+    })
+}
+
+/// Whether the link `name`, relative to the root of the transfer, points
+/// inside the root. Absolute targets and targets escaping the root are
+/// refused, they could make later files of the job be written elsewhere.
+pub fn is_safe_link_target(name: &str, target: &str) -> bool {
+    use std::path::Component;
+    let mut depth = 0i32;
+    for c in Path::new(name)
+        .parent()
+        .into_iter()
+        .flat_map(|p| p.components())
+    {
+        match c {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            _ => return false,
+        }
+    }
+    if target.is_empty() {
+        return false;
+    }
+    for c in Path::new(target).components() {
+        match c {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => {
+                depth -= 1;
+                if depth < 0 {
+                    return false;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
 fn read_dir_recursive(
     path: &Path,
     prefix: &Path,
     include_hidden: bool,
+    policy: SymlinkPolicy,
+    visited: &mut Vec<PathBuf>,
 ) -> ResultType<Vec<FileEntry>> {
     let mut files = Vec::new();
-    if path.is_dir() {
-        // to-do: symbol link handling, cp the link rather than the content
-        // to-do: file mode, for unix
+    let Ok(stat) = provider().stat(path) else {
+        bail!("Not exists");
+    };
+    if stat.entry_type.enum_value() == Ok(FileType::Dir) {
+        if policy == SymlinkPolicy::Follow {
+            // link loops
+            let canonical = provider().canonicalize(path)?;
+            if visited.contains(&canonical) {
+                return Ok(files);
+            }
+            visited.push(canonical);
+        }
         let fd = read_dir(path, include_hidden)?;
         for entry in fd.entries.iter() {
-            match entry.entry_type.enum_value() {
-                Ok(FileType::File) => {
+            let entry_type = entry.entry_type.enum_value();
+            match (entry_type, policy) {
+                (Ok(FileType::File), _) => {
                     let mut entry = entry.clone();
                     entry.name = get_string(&prefix.join(entry.name));
                     files.push(entry);
                 }
-                Ok(FileType::Dir) => {
+                (Ok(FileType::Dir), _) | (Ok(FileType::DirLink), SymlinkPolicy::Follow) => {
                     if let Ok(mut tmp) = read_dir_recursive(
                         &path.join(&entry.name),
                         &prefix.join(&entry.name),
                         include_hidden,
+                        policy,
+                        visited,
                     ) {
                         for entry in tmp.drain(0..) {
                             files.push(entry);
                         }
                     }
                 }
+                (Ok(FileType::FileLink), SymlinkPolicy::Follow) => {
+                    // dangling links are skipped
+                    if let Ok(stat) = provider().stat(&path.join(&entry.name)) {
+                        if stat.entry_type.enum_value() == Ok(FileType::File) {
+                            let mut entry = entry.clone();
+                            entry.name = get_string(&prefix.join(entry.name));
+                            entry.entry_type = FileType::File.into();
+                            entry.size = stat.size;
+                            files.push(entry);
+                        }
+                    }
+                }
+                (Ok(FileType::FileLink), SymlinkPolicy::Recreate)
+                | (Ok(FileType::DirLink), SymlinkPolicy::Recreate) => {
+                    if let Ok(target) = provider().read_link(&path.join(&entry.name)) {
+                        let mut entry = entry.clone();
+                        entry.name = get_string(&prefix.join(entry.name));
+                        entry.link_target = get_string(&target);
+                        files.push(entry);
+                    }
+                }
                 _ => {}
             }
         }
         Ok(files)
-    } else if path.is_file() {
-        let (size, modified_time) = if let Ok(meta) = std::fs::metadata(path) {
-            (
-                meta.len(),
-                meta.modified()
-                    .map(|x| {
-                        x.duration_since(std::time::SystemTime::UNIX_EPOCH)
-                            .map(|x| x.as_secs())
-                            .unwrap_or(0)
-                    })
-                    .unwrap_or(0),
-            )
-        } else {
-            (0, 0)
-        };
+    } else if stat.entry_type.enum_value() == Ok(FileType::File) {
         files.push(FileEntry {
             entry_type: FileType::File.into(),
-            size,
-            modified_time,
+            size: stat.size,
+            modified_time: stat.modified_time,
             ..Default::default()
         });
         Ok(files)
@@ -200,7 +230,14 @@ fn read_dir_recursive(
 }
 
 pub fn get_recursive_files(path: &str, include_hidden: bool) -> ResultType<Vec<FileEntry>> {
-    read_dir_recursive(&get_path(path), &get_path(""), include_hidden)
+    let path = check_path(&get_path(path))?;
+    read_dir_recursive(
+        &path,
+        &get_path(""),
+        include_hidden,
+        SymlinkPolicy::get(),
+        &mut Vec::new(),
+    )
 }
 
 fn read_empty_dirs_recursive(
@@ -209,7 +246,10 @@ fn read_empty_dirs_recursive(
     include_hidden: bool,
 ) -> ResultType<Vec<FileDirectory>> {
     let mut dirs = Vec::new();
-    if path.is_dir() {
+    let Ok(stat) = provider().stat(path) else {
+        bail!("Not exists");
+    };
+    if stat.entry_type.enum_value() == Ok(FileType::Dir) {
         // to-do: symbol link handling, cp the link rather than the content
         // to-do: file mode, for unix
         let fd = read_dir(path, include_hidden)?;
@@ -233,24 +273,21 @@ fn read_empty_dirs_recursive(
                 }
             }
         }
-        Ok(dirs)
-    } else if path.is_file() {
-        Ok(dirs)
-    } else {
-        bail!("Not exists");
     }
+    Ok(dirs)
 }
 
 pub fn get_empty_dirs_recursive(
     path: &str,
     include_hidden: bool,
 ) -> ResultType<Vec<FileDirectory>> {
-    read_empty_dirs_recursive(&get_path(path), &get_path(""), include_hidden)
+    let path = check_path(&get_path(path))?;
+    read_empty_dirs_recursive(&path, &get_path(""), include_hidden)
 }
 
 #[inline]
 pub fn is_file_exists(file_path: &str) -> bool {
-    return Path::new(file_path).exists();
+    return provider().stat(Path::new(file_path)).is_ok();
 }
 
 #[inline]
@@ -263,6 +300,8 @@ pub fn can_enable_overwrite_detection(version: i64) -> bool {
 pub enum JobType {
     Generic = 0,
     Printer = 1,
+    // One-way sync of a local directory to the peer, see `TransferJob::new_mirror`
+    Mirror = 2,
 }
 
 impl Default for JobType {
@@ -276,6 +315,8 @@ impl From<JobType> for file_transfer_send_request::FileType {
         match t {
             JobType::Generic => file_transfer_send_request::FileType::Generic,
             JobType::Printer => file_transfer_send_request::FileType::Printer,
+            // the peer receives a generic job
+            JobType::Mirror => file_transfer_send_request::FileType::Generic,
         }
     }
 }
@@ -285,6 +326,7 @@ impl From<i32> for JobType {
         match value {
             0 => JobType::Generic,
             1 => JobType::Printer,
+            2 => JobType::Mirror,
             _ => JobType::Generic,
         }
     }
@@ -351,6 +393,9 @@ impl DataSource {
 enum DataStream {
     FileStream(File),
     BufStream(TokioBufStream<Cursor<Vec<u8>>>),
+    // a folder sent as one archive, see `TransferJob::pack_as_archive`
+    Pack(Box<archive::Packer>),
+    Unpack(Box<archive::Unpacker>),
 }
 
 impl Debug for DataStream {
@@ -358,6 +403,8 @@ impl Debug for DataStream {
         match self {
             DataStream::FileStream(fs) => write!(f, "{:?}", fs),
             DataStream::BufStream(_) => write!(f, "BufStream"),
+            DataStream::Pack(_) => write!(f, "Pack"),
+            DataStream::Unpack(_) => write!(f, "Unpack"),
         }
     }
 }
@@ -367,6 +414,8 @@ impl DataStream {
         match self {
             DataStream::FileStream(fs) => fs.write_all(buf).await?,
             DataStream::BufStream(bs) => bs.write_all(buf).await?,
+            DataStream::Unpack(u) => u.write(buf)?,
+            DataStream::Pack(_) => bail!("Can not write to an archive being read"),
         }
         Ok(())
     }
@@ -375,6 +424,8 @@ impl DataStream {
         match self {
             DataStream::FileStream(fs) => fs.read(buf).await,
             DataStream::BufStream(bs) => bs.read(buf).await,
+            DataStream::Pack(p) => std::io::Read::read(p.as_mut(), buf),
+            DataStream::Unpack(_) => Err(std::io::ErrorKind::Unsupported.into()),
         }
     }
 }
@@ -383,6 +434,117 @@ impl DataStream {
 pub struct FileDigest {
     pub size: u64,
     pub modified: u64,
+    // hashes of the received chunks of the .download file, see `TransferJob::checkpoint`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunk_hashes: Vec<String>,
+}
+
+// Granularity of the hashes kept for resuming.
+pub const RESUME_CHUNK_SIZE: usize = 1024 * 1024;
+
+fn hash_chunk(data: &[u8]) -> String {
+    base64::encode(sha256::hash(data), base64::Variant::Original)
+}
+
+fn read_full(f: &mut std::fs::File, buf: &mut [u8]) -> std::io::Result<usize> {
+    use std::io::Read;
+    let mut n = 0;
+    while n < buf.len() {
+        let m = f.read(&mut buf[n..])?;
+        if m == 0 {
+            break;
+        }
+        n += m;
+    }
+    Ok(n)
+}
+
+// `known` extended with the hashes of the full chunks after them.
+fn hash_new_chunks(path: &str, known: &[String]) -> ResultType<Vec<String>> {
+    use std::io::{Seek, SeekFrom};
+    let mut f = provider().open_read(Path::new(path))?;
+    f.seek(SeekFrom::Start((known.len() * RESUME_CHUNK_SIZE) as u64))?;
+    let mut res = known.to_vec();
+    let mut buf = vec![0u8; RESUME_CHUNK_SIZE];
+    while read_full(&mut f, &mut buf)? == RESUME_CHUNK_SIZE {
+        res.push(hash_chunk(&buf));
+    }
+    Ok(res)
+}
+
+// Extends the chunk hashes in the `.digest` file of `path` with the chunks
+// received since, up to `contiguous` if given. Returns the length of the
+// `.download` file and the hashes, None if it does not exist.
+fn update_chunk_hashes(path: &str, contiguous: Option<u64>) -> Option<(u64, Vec<String>)> {
+    let download_path = format!("{}.download", path);
+    let digest_path = format!("{}.digest", path);
+    let stat = provider().stat(Path::new(&download_path)).ok()?;
+    let mut digest = provider()
+        .read(Path::new(&digest_path))
+        .ok()
+        .and_then(|c| serde_json::from_slice::<FileDigest>(&c).ok())
+        .unwrap_or_default();
+    match hash_new_chunks(&download_path, &digest.chunk_hashes) {
+        Ok(mut hashes) => {
+            if let Some(len) = contiguous {
+                // not the holes of a parallel transfer
+                hashes.truncate((len / RESUME_CHUNK_SIZE as u64) as usize);
+            }
+            digest.chunk_hashes = hashes;
+            let data = json!(digest).to_string();
+            provider()
+                .write(Path::new(&digest_path), data.as_bytes())
+                .ok();
+        }
+        Err(err) => log::warn!("Failed to hash {}: {}", download_path, err),
+    }
+    Some((stat.size, digest.chunk_hashes))
+}
+
+/// Length of the prefix of `path` matching `hashes`, the data after it can not
+/// be trusted and has to be transferred again. Blocking.
+pub fn verified_length(path: &str, hashes: &[String]) -> u64 {
+    let Ok(mut f) = provider().open_read(Path::new(path)) else {
+        return 0;
+    };
+    let mut buf = vec![0u8; RESUME_CHUNK_SIZE];
+    let mut len = 0;
+    for hash in hashes {
+        match read_full(&mut f, &mut buf) {
+            Ok(n) if n == RESUME_CHUNK_SIZE && &hash_chunk(&buf) == hash => len += n as u64,
+            _ => break,
+        }
+    }
+    len
+}
+
+// `checksum_cache::hash_file` on the blocking pool.
+async fn hash_file_blocking(path: PathBuf) -> ResultType<blake3::Hash> {
+    Ok(tokio::task::spawn_blocking(move || checksum_cache::hash_file(&path)).await??)
+}
+
+// blake3 of the first `len` bytes of `path`, to continue hashing after a seek.
+fn hash_file_prefix(path: &str, len: u64) -> ResultType<blake3::Hasher> {
+    use std::io::Read;
+    let mut hasher = blake3::Hasher::new();
+    let f = provider().open_read(Path::new(path))?;
+    std::io::copy(&mut f.take(len), &mut hasher)?;
+    Ok(hasher)
+}
+
+/// Reset the offset of the current file of `record` to what the local
+/// `.download` file can prove, e.g. before showing the progress of a resumed job.
+/// Blocking, it reads the file.
+pub fn verify_checkpoint(record: &mut TransferJobRecord) {
+    let file_num = record.file_num.max(0) as usize;
+    let base = get_path(&record.to);
+    if let Some(file) = record.files.get_mut(file_num) {
+        let path = get_string(&TransferJob::join(&base, &file.name));
+        let len = verified_length(&format!("{}.download", path), &file.chunk_hashes);
+        file.offset = file.offset.min(len);
+        file.chunk_hashes
+            .truncate((file.offset / RESUME_CHUNK_SIZE as u64) as usize);
+    }
 }
 
 #[derive(Default, Serialize, Debug)]
@@ -399,7 +561,7 @@ pub struct TransferJob {
     pub file_num: i32,
     #[serde(skip_serializing)]
     pub files: Vec<FileEntry>,
-    pub conn_id: i32, // server only
+    pub conn_id: i32, // the connection of the job, job ids are only unique per connection
 
     #[serde(skip_serializing)]
     data_stream: Option<DataStream>,
@@ -414,6 +576,218 @@ pub struct TransferJob {
     default_overwrite_strategy: Option<bool>,
     #[serde(skip_serializing)]
     digest: FileDigest,
+    // Set from `verify_only` of the request, only hashes are sent and compared
+    pub verify_only: bool,
+    #[serde(skip_serializing)]
+    hasher: blake3::Hasher,
+    #[serde(skip_serializing)]
+    checks: HashMap<i32, FileCheck>,
+    // `Features.file_delta_block_sizes` of the peer
+    peer_delta_flags: u32,
+    delta_block_size: u32,
+    #[serde(skip_serializing)]
+    delta_base: Option<std::fs::File>,
+    #[serde(skip_serializing)]
+    delta_encoder: Option<delta::Encoder>,
+    // None until decided on the first chunk of the current file
+    #[serde(skip_serializing)]
+    skip_compression: Option<bool>,
+    #[serde(skip_serializing)]
+    compression: std::sync::Arc<CompressionStats>,
+    // zstd both ways unless negotiated with the `Features` of the peer
+    #[serde(skip_serializing)]
+    codecs: SessionCodecs,
+    #[serde(skip_serializing)]
+    span: Span,
+    concurrency: usize,
+    // receiving, `Features.file_parallel` was sent to the peer
+    #[serde(skip_serializing)]
+    accept_positioned: bool,
+    #[serde(skip_serializing)]
+    parallel: Option<ParallelReader>,
+    // merged ranges of the current file written by positioned blocks
+    #[serde(skip_serializing)]
+    written: Vec<(u64, u64)>,
+    #[serde(skip_serializing)]
+    tracker: progress::Tracker,
+    // whether `FileFinished` of the current file is reported
+    #[serde(skip_serializing)]
+    file_reported: bool,
+    preserve_metadata: bool,
+    // the peer accepts `hole` blocks
+    sparse: bool,
+    #[serde(skip_serializing)]
+    holes: Option<Holes>,
+    // the files are sent as one archive, see `pack_as_archive`
+    archive: bool,
+    // received on the block ending the current file, applied by `modify_time`
+    #[serde(skip_serializing)]
+    pending_metadata: Option<FileMetadata>,
+    // the file number and the path checked by the sandbox of the file being
+    // written, used from then on so a symlink swapped in later is not followed
+    #[serde(skip_serializing)]
+    target: Option<(i32, PathBuf)>,
+}
+
+// Files from this size are split by `TransferJob::set_concurrency`.
+pub const PARALLEL_MIN_FILE_SIZE: u64 = 64 * 1024 * 1024;
+pub const MAX_CONCURRENCY: usize = 8;
+const BLOCK_SIZE: usize = 128 * 1024;
+
+/// `OPTION_FILE_TRANSFER_CONCURRENCY`, 1 if not set.
+pub fn get_concurrency() -> usize {
+    Config::get_option(crate::config::keys::OPTION_FILE_TRANSFER_CONCURRENCY)
+        .parse::<usize>()
+        .unwrap_or(1)
+        .clamp(1, MAX_CONCURRENCY)
+}
+
+// Sequential readers of consecutive ranges of a file, read in turn.
+#[derive(Debug)]
+struct ParallelReader {
+    // file, position, end
+    ranges: Vec<(File, u64, u64)>,
+    next: usize,
+}
+
+impl ParallelReader {
+    async fn open(path: &Path, start: u64, end: u64, n: usize) -> ResultType<Self> {
+        let n = n.max(1) as u64;
+        let block = BLOCK_SIZE as u64;
+        let step = ((end.saturating_sub(start) + n - 1) / n + block - 1) / block * block;
+        let mut ranges = Vec::new();
+        let mut pos = start;
+        while pos < end {
+            let mut f = File::from_std(provider().open_read(path)?);
+            f.seek(std::io::SeekFrom::Start(pos)).await?;
+            let range_end = (pos + step.max(block)).min(end);
+            ranges.push((f, pos, range_end));
+            pos = range_end;
+        }
+        Ok(Self { ranges, next: 0 })
+    }
+
+    async fn next_block(&mut self) -> ResultType<Option<(u64, Vec<u8>)>> {
+        for _ in 0..self.ranges.len() {
+            let i = self.next;
+            self.next = (self.next + 1) % self.ranges.len();
+            let (f, pos, end) = &mut self.ranges[i];
+            if *pos >= *end {
+                continue;
+            }
+            let mut buf = vec![0u8; ((*end - *pos) as usize).min(BLOCK_SIZE)];
+            let mut n = 0;
+            while n < buf.len() {
+                let m = f.read(&mut buf[n..]).await?;
+                if m == 0 {
+                    bail!("File truncated during transfer");
+                }
+                n += m;
+            }
+            let offset = *pos;
+            *pos += buf.len() as u64;
+            return Ok(Some((offset, buf)));
+        }
+        Ok(None)
+    }
+}
+
+fn add_range(ranges: &mut Vec<(u64, u64)>, start: u64, end: u64) {
+    ranges.push((start, end));
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (s, e) in ranges.drain(..) {
+        match merged.last_mut() {
+            Some(last) if s <= last.1 => last.1 = last.1.max(e),
+            _ => merged.push((s, e)),
+        }
+    }
+    *ranges = merged;
+}
+
+// Data regions of a sparse file being read, from SEEK_DATA / SEEK_HOLE.
+#[derive(Debug)]
+struct Holes {
+    // None if the file has no holes or they can not be found on this platform
+    file: Option<std::fs::File>,
+    pos: u64,
+    size: u64,
+    // end of the data region `pos` is in
+    data_end: u64,
+}
+
+impl Holes {
+    fn open(path: &Path, pos: u64) -> Self {
+        let mut res = Self {
+            file: None,
+            pos,
+            size: u64::MAX,
+            data_end: u64::MAX,
+        };
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::MetadataExt;
+            if let Ok(file) = provider().open_read(Path::new(path)) {
+                if let Ok(meta) = file.metadata() {
+                    if meta.blocks() * 512 < meta.len() {
+                        res.file = Some(file);
+                        res.size = meta.len();
+                        res.data_end = pos;
+                    }
+                }
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = path;
+        res
+    }
+
+    #[cfg(target_os = "linux")]
+    fn seek(&self, file: &std::fs::File, whence: libc::c_int) -> u64 {
+        use std::os::unix::io::AsRawFd;
+        let res = unsafe { libc::lseek(file.as_raw_fd(), self.pos as _, whence) };
+        // ENXIO: no data after pos
+        if res < 0 {
+            self.size
+        } else {
+            (res as u64).min(self.size)
+        }
+    }
+
+    // Length of the hole at the current position, skipped. 0 if in data.
+    fn skip_hole(&mut self) -> u64 {
+        if self.pos < self.data_end {
+            return 0;
+        }
+        let start = self.pos;
+        #[cfg(target_os = "linux")]
+        if let Some(file) = self.file.as_ref() {
+            self.pos = self.seek(file, libc::SEEK_DATA);
+            self.data_end = self.seek(file, libc::SEEK_HOLE);
+        }
+        self.pos - start
+    }
+
+    #[inline]
+    fn data_len(&self) -> u64 {
+        self.data_end.saturating_sub(self.pos)
+    }
+}
+
+fn hash_zeros(hasher: &mut blake3::Hasher, mut len: u64) {
+    const ZEROS: [u8; 64 * 1024] = [0u8; 64 * 1024];
+    while len > 0 {
+        let n = len.min(ZEROS.len() as u64) as usize;
+        hasher.update(&ZEROS[..n]);
+        len -= n as u64;
+    }
+}
+
+/// Result of comparing the blake3 of a received file with the sender's.
+#[derive(Debug, Clone)]
+pub struct FileCheck {
+    pub hash: String,
+    pub verified: bool,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
@@ -452,9 +826,13 @@ fn get_ext(name: &str) -> &str {
 
 #[inline]
 fn is_compressed_file(name: &str) -> bool {
-    let compressed_exts = ["xz", "gz", "zip", "7z", "rar", "bz2", "tgz", "png", "jpg"];
-    let ext = get_ext(name);
-    compressed_exts.contains(&ext)
+    let compressed_exts = [
+        "xz", "gz", "zip", "7z", "rar", "bz2", "tgz", "zst", "png", "jpg", "jpeg", "gif", "webp",
+        "heic", "mp4", "mkv", "mov", "webm", "avi", "mp3", "aac", "ogg", "opus", "flac", "jar",
+        "apk", "docx", "xlsx", "pptx",
+    ];
+    let ext = get_ext(name).to_lowercase();
+    compressed_exts.contains(&ext.as_str())
 }
 
 impl TransferJob {
@@ -471,8 +849,12 @@ impl TransferJob {
         enable_overwrite_detection: bool,
     ) -> Self {
         log::info!("new write {}", data_source);
+        let mut files = files;
+        sanitize_remote_names(&mut files);
         let total_size = files.iter().map(|x| x.size).sum();
-        Self {
+        let span = Span::transfer_job(id, &remote, false);
+        crate::metrics::TRANSFER_JOBS_WRITE.inc();
+        let job = Self {
             id,
             r#type,
             remote,
@@ -483,8 +865,13 @@ impl TransferJob {
             files,
             total_size,
             enable_overwrite_detection,
+            span,
             ..Default::default()
+        };
+        if job.links_due(-1) {
+            job.recreate_links();
         }
+        job
     }
 
     pub fn new_read(
@@ -507,6 +894,8 @@ impl TransferJob {
             }
             DataSource::MemoryCursor(c) => (Vec::new(), c.get_ref().len() as u64),
         };
+        let span = Span::transfer_job(id, &remote, true);
+        crate::metrics::TRANSFER_JOBS_READ.inc();
         Ok(Self {
             id,
             r#type,
@@ -518,6 +907,7 @@ impl TransferJob {
             files,
             total_size,
             enable_overwrite_detection,
+            span,
             ..Default::default()
         })
     }
@@ -532,14 +922,91 @@ impl TransferJob {
         }
     }
 
+    /// Entered by `read` and `write`, e.g. for the events of the caller.
+    #[inline]
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Of the blocks this job compressed or decompressed.
+    #[inline]
+    pub fn compression_stats(&self) -> StatsSnapshot {
+        self.compression.snapshot()
+    }
+
     #[inline]
     pub fn files(&self) -> &Vec<FileEntry> {
         &self.files
     }
 
     #[inline]
-    pub fn set_files(&mut self, files: Vec<FileEntry>) {
+    pub fn set_files(&mut self, mut files: Vec<FileEntry>) {
+        sanitize_remote_names(&mut files);
         self.files = files;
+        if self.links_due(-1) {
+            self.recreate_links();
+        }
+    }
+
+    // Whether only links follow `file_num`, which are then created, after the
+    // files are written, so no file is written through a link of the transfer.
+    fn links_due(&self, file_num: i32) -> bool {
+        self.files
+            .iter()
+            .skip((file_num + 1).max(0) as usize)
+            .all(|f| !f.link_target.is_empty())
+    }
+
+    // Links sent with `SymlinkPolicy::Recreate` have no data, the sender skips
+    // them. Existing files are not replaced, and a link resolving outside of
+    // the destination is not created.
+    fn recreate_links(&self) {
+        let DataSource::FilePath(p) = &self.data_source else {
+            return;
+        };
+        if self.r#type == JobType::Printer {
+            return;
+        }
+        let Ok(root) = provider().canonicalize(Path::new(p)) else {
+            return;
+        };
+        for entry in self.files.iter().filter(|f| !f.link_target.is_empty()) {
+            if !is_safe_link_target(&entry.name, &entry.link_target) {
+                log::warn!(
+                    "Skip link {} to {} outside of the transfer",
+                    entry.name,
+                    entry.link_target
+                );
+                continue;
+            }
+            let Ok(path) = check_path(&Self::join(p, &entry.name)) else {
+                continue;
+            };
+            if provider().is_link(&path)
+                || provider().stat(&path).is_ok()
+                || has_link_below(p, &entry.name)
+            {
+                continue;
+            }
+            if let Some(pp) = path.parent() {
+                provider().create_dir_all(pp).ok();
+            }
+            let target = path
+                .parent()
+                .map(|pp| sandbox::resolve(&pp.join(&entry.link_target)));
+            if !matches!(target, Some(Ok(t)) if sandbox::is_within(&t, &root)) {
+                log::warn!(
+                    "Skip link {} resolving outside of the destination",
+                    entry.name
+                );
+                continue;
+            }
+            let dir = entry.entry_type.enum_value() == Ok(FileType::DirLink);
+            let res = provider().symlink(Path::new(&entry.link_target), &path, dir);
+            if let Err(err) = res {
+                log::warn!("Failed to create link {:?}: {}", path, err);
+            }
+        }
     }
 
     #[inline]
@@ -574,23 +1041,46 @@ impl TransferJob {
     }
 
     pub fn modify_time(&self) {
-        if self.r#type == JobType::Printer {
+        if self.r#type == JobType::Printer || self.archive {
             return;
         }
         if let DataSource::FilePath(p) = &self.data_source {
             let file_num = self.file_num as usize;
+            if self
+                .checks
+                .get(&self.file_num)
+                .map_or(false, |c| !c.verified)
+            {
+                // corrupted, already removed
+                return;
+            }
             if file_num < self.files.len() {
                 let entry = &self.files[file_num];
-                let path = Self::join(p, &entry.name);
+                let path = self.target(p, file_num);
                 let download_path = format!("{}.download", get_string(&path));
                 let digest_path = format!("{}.digest", get_string(&path));
-                std::fs::remove_file(digest_path).ok();
-                std::fs::rename(download_path, &path).ok();
-                filetime::set_file_mtime(
-                    &path,
-                    filetime::FileTime::from_unix_time(entry.modified_time as _, 0),
-                )
-                .ok();
+                provider().remove(Path::new(&digest_path)).ok();
+                if provider().stat(Path::new(&download_path)).is_ok() && backup::is_enabled() {
+                    if let Err(err) = backup::backup(&path) {
+                        // keep both rather than lose the original
+                        log::error!("Failed to back up {:?}, not overwritten: {}", path, err);
+                        return;
+                    }
+                }
+                provider().rename(Path::new(&download_path), &path).ok();
+                checksum_cache::invalidate(&path);
+                if self.links_due(self.file_num) {
+                    self.recreate_links();
+                }
+                if let Some(m) = self.pending_metadata.as_ref() {
+                    file_metadata::apply(&path, m);
+                } else {
+                    filetime::set_file_mtime(
+                        &path,
+                        filetime::FileTime::from_unix_time(entry.modified_time as _, 0),
+                    )
+                    .ok();
+                }
             }
         }
     }
@@ -602,12 +1092,11 @@ impl TransferJob {
         if let DataSource::FilePath(p) = &self.data_source {
             let file_num = self.file_num as usize;
             if file_num < self.files.len() {
-                let entry = &self.files[file_num];
-                let path = Self::join(p, &entry.name);
+                let path = self.target(p, file_num);
                 let download_path = format!("{}.download", get_string(&path));
                 let digest_path = format!("{}.digest", get_string(&path));
-                std::fs::remove_file(download_path).ok();
-                std::fs::remove_file(digest_path).ok();
+                provider().remove(Path::new(&download_path)).ok();
+                provider().remove(Path::new(&digest_path)).ok();
             }
         }
     }
@@ -626,44 +1115,81 @@ impl TransferJob {
     }
 
     pub async fn write(&mut self, block: FileTransferBlock) -> ResultType<()> {
+        let span = self.span.clone();
+        span.instrument(self.write_(block)).await
+    }
+
+    async fn write_(&mut self, block: FileTransferBlock) -> ResultType<()> {
         if block.id != self.id {
             bail!("Wrong id");
         }
+        if self.verify_only {
+            return self.verify_existing(&block).await;
+        }
         match &self.data_source {
             DataSource::FilePath(p) => {
+                // not borrowed from `self` across the reporters
+                let p = p.clone();
                 let file_num = block.file_num as usize;
                 if file_num >= self.files.len() {
                     bail!("Wrong file number");
                 }
                 if file_num != self.file_num as usize || self.data_stream.is_none() {
+                    if self.data_stream.is_some() {
+                        self.report_file_finished();
+                    }
                     self.modify_time();
                     if let Some(DataStream::FileStream(file)) = self.data_stream.as_mut() {
                         file.sync_all().await?;
                     }
+                    self.pending_metadata = None;
                     self.file_num = block.file_num;
-                    let entry = &self.files[file_num];
-                    let (path, digest_path) = if self.r#type == JobType::Printer {
-                        (p.to_string_lossy().to_string(), None)
+                    if self.archive {
+                        if !TransferPolicy::get().is_empty() {
+                            // the files could not be checked one by one
+                            bail!("Archives are not accepted with transfer policies");
+                        }
+                        let p = check_path(&p)?;
+                        provider().create_dir_all(&p)?;
+                        let unpacker = archive::Unpacker::new(p);
+                        self.data_stream = Some(DataStream::Unpack(Box::new(unpacker)));
+                        self.hasher.reset();
+                        self.report_file_started();
                     } else {
-                        let path = Self::join(p, &entry.name);
-                        if let Some(pp) = path.parent() {
-                            std::fs::create_dir_all(pp).ok();
+                        let entry = &self.files[file_num];
+                        let (path, digest_path) = if self.r#type == JobType::Printer {
+                            (p.to_string_lossy().to_string(), None)
+                        } else {
+                            let path = check_path(&Self::join(&p, &entry.name))?;
+                            if let Some(pp) = path.parent() {
+                                provider().create_dir_all(pp).ok();
+                            }
+                            if has_link_below(&p, &entry.name) {
+                                bail!("Link in the path of {}", entry.name);
+                            }
+                            let file_path = get_string(&path);
+                            self.target = Some((block.file_num, path));
+                            (
+                                format!("{}.download", &file_path),
+                                Some(format!("{}.digest", &file_path)),
+                            )
+                        };
+                        self.admit_file(file_num)?;
+                        if let Some(dp) = digest_path.as_ref() {
+                            if provider().stat(Path::new(dp)).is_ok() {
+                                provider().remove(Path::new(dp))?;
+                            }
                         }
-                        let file_path = get_string(&path);
-                        (
-                            format!("{}.download", &file_path),
-                            Some(format!("{}.digest", &file_path)),
-                        )
-                    };
-                    if let Some(dp) = digest_path.as_ref() {
-                        if Path::new(dp).exists() {
-                            std::fs::remove_file(dp)?;
+                        let file = File::from_std(provider().open_write(Path::new(&path))?);
+                        self.data_stream = Some(DataStream::FileStream(file));
+                        self.hasher.reset();
+                        self.written.clear();
+                        self.report_file_started();
+                        if let Some(dp) = digest_path.as_ref() {
+                            let data = json!(self.digest).to_string();
+                            provider().write(Path::new(dp), data.as_bytes()).ok();
                         }
                     }
-                    self.data_stream = Some(DataStream::FileStream(File::create(&path).await?));
-                    if let Some(dp) = digest_path.as_ref() {
-                        std::fs::write(dp, json!(self.digest).to_string()).ok();
-                    }
                 }
             }
             DataSource::MemoryCursor(c) => {
@@ -672,26 +1198,436 @@ impl TransferJob {
                 }
             }
         }
+        if block.hole > 0 {
+            let Some(DataStream::FileStream(file)) = self.data_stream.as_mut() else {
+                bail!("Hole without file");
+            };
+            let size = self
+                .files
+                .get(block.file_num as usize)
+                .map_or(0, |f| f.size);
+            // left unallocated
+            let end = file.stream_position().await?.saturating_add(block.hole);
+            if end > size {
+                bail!("Hole beyond the size {} of the file", size);
+            }
+            file.set_len(end).await?;
+            file.seek(std::io::SeekFrom::Start(end)).await?;
+            hash_zeros(&mut self.hasher, block.hole);
+            self.finished_size += block.hole;
+            self.report_progress();
+            return Ok(());
+        }
+        let decompressed;
+        let patched;
+        let mut data: &[u8] = &block.data;
         if block.compressed {
-            let tmp = decompress(&block.data);
-            self.data_stream
-                .as_mut()
-                .ok_or(anyhow!("data stream is None"))?
-                .write_all(&tmp)
-                .await?;
-            self.finished_size += tmp.len() as u64;
+            decompressed = self
+                .compression
+                .decompress(self.codecs.decoder.compressor(), data);
+            data = &decompressed;
+        }
+        if block.is_delta {
+            patched = self.apply_delta(data)?;
+            data = &patched;
+        }
+        if block.positioned {
+            if !self.accept_positioned {
+                bail!("Positioned block without file_parallel");
+            }
+            self.write_at(block.offset, data).await?;
         } else {
             self.data_stream
                 .as_mut()
-                .ok_or(anyhow!("file is None"))?
-                .write_all(&block.data)
+                .ok_or(anyhow!("data stream is None"))?
+                .write_all(data)
                 .await?;
-            self.finished_size += block.data.len() as u64;
+            self.hasher.update(data);
         }
+        self.finished_size += data.len() as u64;
         self.transferred += block.data.len() as u64;
+        if matches!(self.data_source, DataSource::FilePath(_)) {
+            self.charge(data.len() as u64)?;
+        }
+        if !block.hash.is_empty() {
+            // end of the file, release it before renaming the download file over it
+            self.delta_base = None;
+        }
+        if let Some(m) = block.metadata.as_ref() {
+            // applied only if allowed here, whatever the sender does
+            if file_metadata::is_enabled() {
+                self.pending_metadata = Some(m.clone());
+            }
+        }
+        self.report_progress();
+        // Peers not sending the hash are not verified
+        if !block.hash.is_empty() && matches!(self.data_source, DataSource::FilePath(_)) {
+            let local = if self.written.is_empty() {
+                self.hasher.finalize()
+            } else {
+                self.hash_written().await?
+            };
+            if !self.record_check(block.file_num, Some(local), &block.hash) {
+                if let Some(DataStream::Unpack(mut unpacker)) = self.data_stream.take() {
+                    unpacker.discard();
+                } else {
+                    self.remove_download_file();
+                }
+                bail!("Integrity check failed");
+            }
+            if let Some(DataStream::Unpack(unpacker)) = self.data_stream.as_mut() {
+                // verified, into place only now
+                unpacker.commit()?;
+            }
+            self.report_file_finished();
+        }
+        Ok(())
+    }
+
+    fn report_file_started(&mut self) {
+        self.file_reported = false;
+        Self::emit_file_started(self.conn_id, self.id, self.file_num, &self.files);
+    }
+
+    // By value, for callers holding a borrow of `files`.
+    fn emit_file_started(conn_id: i32, id: i32, file_num: i32, files: &[FileEntry]) {
+        if !progress::has_subscribers() {
+            return;
+        }
+        if let Some(entry) = files.get(file_num as usize) {
+            progress::emit(TransferEvent::FileStarted {
+                conn_id,
+                id,
+                file_num,
+                name: entry.name.clone(),
+                size: entry.size,
+            });
+        }
+    }
+
+    // Of `file_num` for readers, which have moved on to the next file already.
+    fn report_file_finished_at(&mut self, file_num: i32) {
+        if self.file_reported {
+            return;
+        }
+        self.file_reported = true;
+        if !progress::has_subscribers() {
+            return;
+        }
+        if let Some(entry) = self.files.get(file_num as usize) {
+            progress::emit(TransferEvent::FileFinished {
+                conn_id: self.conn_id,
+                id: self.id,
+                file_num,
+                name: entry.name.clone(),
+            });
+        }
+    }
+
+    #[inline]
+    fn report_file_finished(&mut self) {
+        self.report_file_finished_at(self.file_num);
+    }
+
+    /// Emit `TransferEvent::Progress`, throttled to `progress::MIN_INTERVAL`.
+    pub fn report_progress(&mut self) {
+        if !progress::has_subscribers() {
+            return;
+        }
+        let Some((speed, eta)) = self.tracker.sample(self.finished_size, self.total_size) else {
+            return;
+        };
+        progress::emit(TransferEvent::Progress(JobProgress {
+            conn_id: self.conn_id,
+            id: self.id,
+            file_num: self.file_num,
+            file_name: self
+                .files
+                .get(self.file_num as usize)
+                .map(|f| f.name.clone())
+                .unwrap_or_default(),
+            finished_size: self.finished_size,
+            total_size: self.total_size,
+            transferred: self.transferred,
+            speed,
+            eta,
+        }));
+    }
+
+    /// For write jobs when the peer reports done or an error, read jobs are
+    /// reported by `handle_read_jobs`.
+    pub fn report_done(&mut self, error: Option<String>) {
+        if error.is_none() && self.data_stream.is_some() {
+            self.report_file_finished();
+        }
+        if error.is_none() {
+            self.recreate_links();
+        }
+        progress::emit(TransferEvent::JobFinished {
+            conn_id: self.conn_id,
+            id: self.id,
+            error,
+        });
+    }
+
+    async fn write_at(&mut self, offset: u64, data: &[u8]) -> ResultType<()> {
+        let Some(size) = self.files.get(self.file_num as usize).map(|f| f.size) else {
+            bail!("Wrong file number");
+        };
+        let end = offset.checked_add(data.len() as u64);
+        if end.map_or(true, |end| end > size) {
+            bail!("Positioned block beyond the size {} of the file", size);
+        }
+        let Some(DataStream::FileStream(file)) = self.data_stream.as_mut() else {
+            bail!("Positioned block without file");
+        };
+        if self.written.is_empty() {
+            // what is before came in order, e.g. on resume
+            let pos = file.stream_position().await?;
+            if pos > 0 {
+                self.written.push((0, pos));
+            }
+        }
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+        file.write_all(data).await?;
+        add_range(&mut self.written, offset, offset + data.len() as u64);
+        Ok(())
+    }
+
+    // The blocks came out of order, hash the download file instead.
+    async fn hash_written(&mut self) -> ResultType<blake3::Hash> {
+        self.sync_data().await?;
+        let DataSource::FilePath(p) = &self.data_source else {
+            bail!("No file to hash");
+        };
+        if self.files.get(self.file_num as usize).is_none() {
+            bail!("Wrong file number");
+        }
+        let path = format!(
+            "{}.download",
+            get_string(&self.target(p, self.file_num as usize))
+        );
+        let hasher =
+            tokio::task::spawn_blocking(move || hash_file_prefix(&path, u64::MAX)).await??;
+        Ok(hasher.finalize())
+    }
+
+    /// Length of the start of the current file received without gap.
+    fn contiguous(&self) -> Option<u64> {
+        match self.written.first() {
+            None => None,
+            Some((0, end)) => Some(*end),
+            Some(_) => Some(0),
+        }
+    }
+
+    /// Split the files from `PARALLEL_MIN_FILE_SIZE` into `concurrency` ranges
+    /// sent interleaved, see `get_concurrency`. Only if the peer has
+    /// `Features.file_parallel`. Each `read` still returns one block, so other
+    /// jobs and channels get their turn as usual.
+    #[inline]
+    pub fn set_concurrency(&mut self, concurrency: usize) {
+        self.concurrency = concurrency.min(MAX_CONCURRENCY);
+    }
+
+    /// Accept positioned blocks, only if `Features.file_parallel` was sent to
+    /// the peer, they are rejected otherwise.
+    #[inline]
+    pub fn set_accept_positioned(&mut self, accept: bool) {
+        self.accept_positioned = accept;
+    }
+
+    /// Send the metadata of each file with its last block, see
+    /// `file_metadata::is_enabled`.
+    #[inline]
+    pub fn set_preserve_metadata(&mut self, preserve: bool) {
+        self.preserve_metadata = preserve;
+    }
+
+    /// Send the files as one archive if `archive::should_archive`, only if the
+    /// peer has `Features.file_archive`. The job then has a single entry with
+    /// an empty name, to announce with `FileDirectory.archive` or
+    /// `FileTransferReceiveRequest.archive`. Not with transfer policies, which
+    /// are enforced per file.
+    pub fn pack_as_archive(&mut self) -> bool {
+        let DataSource::FilePath(p) = &self.data_source else {
+            return false;
+        };
+        if self.archive
+            || self.verify_only
+            || self.file_num != 0
+            || self.data_stream.is_some()
+            || self.r#type != JobType::Generic
+            || !TransferPolicy::get().is_empty()
+            || !archive::should_archive(&self.files)
+        {
+            return false;
+        }
+        let root = p.clone();
+        let files = std::mem::take(&mut self.files);
+        let size = archive::archive_size(&files);
+        let modified_time = files.iter().map(|f| f.modified_time).max().unwrap_or(0);
+        self.data_stream = Some(DataStream::Pack(Box::new(archive::Packer::new(
+            root, files,
+        ))));
+        self.files = vec![FileEntry {
+            entry_type: FileType::File.into(),
+            size,
+            modified_time,
+            ..Default::default()
+        }];
+        self.total_size = size;
+        self.archive = true;
+        self.enable_overwrite_detection = false;
+        self.report_file_started();
+        true
+    }
+
+    /// The peer sends the folder as one archive, it is unpacked into the
+    /// destination folder.
+    #[inline]
+    pub fn set_archive(&mut self, archive: bool) {
+        self.archive = archive;
+        if archive {
+            self.enable_overwrite_detection = false;
+        }
+    }
+
+    /// Send the holes of sparse files without data if the peer has
+    /// `Features.file_sparse` and `OPTION_ENABLE_FILE_TRANSFER_SPARSE` is on.
+    #[inline]
+    pub fn set_peer_sparse(&mut self, supported: bool) {
+        self.sparse = supported
+            && Config::get_bool_option(crate::config::keys::OPTION_ENABLE_FILE_TRANSFER_SPARSE);
+    }
+
+    fn local_metadata(&self, file_num: usize) -> MessageField<FileMetadata> {
+        match &self.data_source {
+            DataSource::FilePath(p) if self.preserve_metadata && !self.archive => {
+                let path = Self::join(p, &self.files[file_num].name);
+                MessageField::some(file_metadata::read(&path))
+            }
+            _ => MessageField::none(),
+        }
+    }
+
+    fn apply_delta(&mut self, data: &[u8]) -> ResultType<Vec<u8>> {
+        let ops = delta::decode_ops(data)?;
+        let Some(base) = self.delta_base.as_mut() else {
+            bail!("No base file for delta");
+        };
+        delta::apply(&ops, base, self.delta_block_size)
+    }
+
+    /// Current file and offset in it, None between files.
+    pub async fn position(&mut self) -> ResultType<Option<(i32, u64)>> {
+        let contiguous = self.contiguous();
+        match self.data_stream.as_mut() {
+            Some(DataStream::FileStream(file)) => {
+                let pos = match contiguous {
+                    Some(len) => len,
+                    None => file.stream_position().await?,
+                };
+                Ok(Some((self.file_num, pos)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Make the data written so far durable, see `journal::Journal`.
+    pub async fn sync_data(&mut self) -> ResultType<()> {
+        if let Some(DataStream::FileStream(file)) = self.data_stream.as_mut() {
+            file.flush().await?;
+            file.sync_data().await?;
+        }
+        Ok(())
+    }
+
+    /// The codecs of the session, see `SessionCodecs::negotiate`.
+    #[inline]
+    pub fn set_codecs(&mut self, codecs: SessionCodecs) {
+        self.codecs = codecs;
+    }
+
+    #[inline]
+    pub fn set_peer_delta_flags(&mut self, flags: u32) {
+        self.peer_delta_flags = flags;
+    }
+
+    /// For `FileTransferSendConfirmRequest.delta_signature` when overwriting a
+    /// different existing file, None if the peer or the file is not suitable.
+    pub fn delta_signature(&mut self, file_num: i32) -> Option<Vec<u8>> {
+        if self.r#type != JobType::Generic || self.verify_only {
+            return None;
+        }
+        let DataSource::FilePath(p) = &self.data_source else {
+            return None;
+        };
+        let entry = self.files.get(file_num as usize)?;
+        let path = Self::join(p, &entry.name);
+        let size = provider().stat(&path).ok()?.size;
+        let block_size =
+            delta::choose_block_size(delta::block_size_flags(), self.peer_delta_flags, size)?;
+        if size < block_size as u64 {
+            return None;
+        }
+        let mut file = provider().open_read(&path).ok()?;
+        let signature = match delta::Signature::compute(&mut file, block_size) {
+            Ok(signature) => signature,
+            Err(err) => {
+                log::warn!("Failed to compute delta signature of {:?}: {}", path, err);
+                return None;
+            }
+        };
+        self.delta_block_size = block_size;
+        self.delta_base = Some(file);
+        Some(signature.to_bytes())
+    }
+
+    // Compare the hash from the sender with the file already here, nothing is written.
+    async fn verify_existing(&mut self, block: &FileTransferBlock) -> ResultType<()> {
+        if block.hash.is_empty() {
+            return Ok(());
+        }
+        let DataSource::FilePath(p) = &self.data_source else {
+            bail!("Nothing to verify in memory");
+        };
+        let Some(entry) = self.files.get(block.file_num as usize) else {
+            bail!("Wrong file number");
+        };
+        let path = check_path(&Self::join(p, &entry.name))?;
+        let size = entry.size;
+        let local = hash_file_blocking(path).await.ok();
+        self.finished_size += size;
+        self.file_num = block.file_num;
+        self.record_check(block.file_num, local, &block.hash);
         Ok(())
     }
 
+    fn record_check(&mut self, file_num: i32, local: Option<blake3::Hash>, remote: &[u8]) -> bool {
+        let verified = local.map_or(false, |h| h.as_bytes()[..] == remote[..]);
+        if !verified {
+            log::error!(
+                "Integrity check failed, id: {}, file_num: {}",
+                self.id,
+                file_num
+            );
+        }
+        self.checks.insert(
+            file_num,
+            FileCheck {
+                hash: local.map(|h| h.to_hex().to_string()).unwrap_or_default(),
+                verified,
+            },
+        );
+        verified
+    }
+
+    #[inline]
+    pub fn file_check(&self, file_num: i32) -> Option<&FileCheck> {
+        self.checks.get(&file_num)
+    }
+
     #[inline]
     pub fn join(p: &PathBuf, name: &str) -> PathBuf {
         if name.is_empty() {
@@ -701,8 +1637,68 @@ impl TransferJob {
         }
     }
 
+    // The path of `file_num` checked when it started, else joined.
+    fn target(&self, p: &PathBuf, file_num: usize) -> PathBuf {
+        match &self.target {
+            Some((n, path)) if *n as usize == file_num => path.clone(),
+            _ => Self::join(p, &self.files[file_num].name),
+        }
+    }
+
+    // `transfer_policy`, when a file starts. The quotas are of `conn_id`, which
+    // the owner of the job has to set.
+    fn admit_file(&self, file_num: usize) -> ResultType<()> {
+        let Some(policy) = self.policy() else {
+            return Ok(());
+        };
+        if self.conn_id == 0 && policy.max_session_bytes > 0 {
+            bail!("No connection to account the transfer quota to");
+        }
+        let entry = &self.files[file_num];
+        policy.admit(self.conn_id, &entry.name, entry.size)?;
+        Ok(())
+    }
+
+    // `transfer_policy`, the bytes of the file transferred.
+    fn charge(&self, bytes: u64) -> ResultType<()> {
+        if let Some(policy) = self.policy() {
+            policy.charge(self.conn_id, bytes)?;
+        }
+        Ok(())
+    }
+
+    fn policy(&self) -> Option<TransferPolicy> {
+        let policy = TransferPolicy::get();
+        if policy.is_empty() || self.r#type == JobType::Printer || self.verify_only {
+            return None;
+        }
+        Some(policy)
+    }
+
     pub async fn read(&mut self, stream: &mut Stream) -> ResultType<Option<FileTransferBlock>> {
+        let span = self.span.clone();
+        span.instrument(self.read_(stream)).await
+    }
+
+    async fn read_(&mut self, stream: &mut Stream) -> ResultType<Option<FileTransferBlock>> {
+        // recreated by the receiver
+        while self
+            .files
+            .get(self.file_num as usize)
+            .map_or(false, |f| !f.link_target.is_empty())
+        {
+            self.file_num += 1;
+        }
         let file_num = self.file_num as usize;
+        if self.data_stream.is_none()
+            && matches!(self.data_source, DataSource::FilePath(_))
+            && file_num < self.files.len()
+        {
+            if let Err(err) = self.admit_file(file_num) {
+                self.file_num += 1;
+                return Err(err);
+            }
+        }
         let name: &str;
         match &mut self.data_source {
             DataSource::FilePath(p) => {
@@ -712,9 +1708,24 @@ impl TransferJob {
                 };
                 name = &self.files[file_num].name;
                 if self.data_stream.is_none() {
-                    match File::open(Self::join(p, name)).await {
+                    match provider()
+                        .open_read(&Self::join(p, name))
+                        .map(File::from_std)
+                    {
                         Ok(file) => {
                             self.data_stream = Some(DataStream::FileStream(file));
+                            self.hasher.reset();
+                            self.delta_encoder = None;
+                            self.skip_compression = None;
+                            self.holes = None;
+                            // `name` borrows `files` until the block is read
+                            self.file_reported = false;
+                            Self::emit_file_started(
+                                self.conn_id,
+                                self.id,
+                                self.file_num,
+                                &self.files,
+                            );
                             self.file_confirmed = false;
                             self.file_is_waiting = false;
                         }
@@ -736,7 +1747,17 @@ impl TransferJob {
                 }
             }
         }
-        if self.r#type == JobType::Generic {
+        if self.verify_only && matches!(self.data_source, DataSource::FilePath(_)) {
+            return self.read_hash_only(file_num).await.map(Some);
+        }
+        let use_parallel = |job: &Self| {
+            job.concurrency > 1
+                && !job.archive
+                && job.delta_encoder.is_none()
+                && matches!(job.data_source, DataSource::FilePath(_))
+                && job.files[file_num].size >= PARALLEL_MIN_FILE_SIZE
+        };
+        if self.r#type != JobType::Printer {
             if self.enable_overwrite_detection && !self.file_confirmed() {
                 if !self.file_is_waiting() {
                     self.send_current_digest(stream).await?;
@@ -745,8 +1766,22 @@ impl TransferJob {
                 return Ok(None);
             }
         }
-        const BUF_SIZE: usize = 128 * 1024;
-        let mut buf: Vec<u8> = vec![0; BUF_SIZE];
+        if use_parallel(self) {
+            return self.read_parallel(file_num).await.map(Some);
+        }
+        // owned, reading holes borrows the job mutably
+        let name = name.to_owned();
+        if self.sparse && self.delta_encoder.is_none() {
+            if let Some(block) = self.read_hole(file_num).await? {
+                return Ok(Some(block));
+            }
+        }
+        // blocks do not span holes
+        let buf_size = self
+            .holes
+            .as_ref()
+            .map_or(BLOCK_SIZE, |h| h.data_len().min(BLOCK_SIZE as _) as _);
+        let mut buf: Vec<u8> = vec![0; buf_size];
         let mut compressed = false;
         let mut offset: usize = 0;
         loop {
@@ -766,30 +1801,52 @@ impl TransferJob {
                 }
                 Ok(n) => {
                     offset += n;
-                    if n == 0 || offset == BUF_SIZE {
+                    if n == 0 || offset == buf_size {
                         break;
                     }
                 }
             }
         }
         unsafe { buf.set_len(offset) };
+        if let Some(holes) = self.holes.as_mut() {
+            holes.pos += offset as u64;
+        }
+        let mut hash = Vec::new();
+        let mut is_delta = false;
+        let mut metadata = MessageField::none();
         if offset == 0 {
             if matches!(self.data_source, DataSource::MemoryCursor(_)) {
                 self.data_stream.take();
                 return Ok(None);
             }
+            hash = self.hasher.finalize().as_bytes().to_vec();
+            metadata = self.local_metadata(file_num);
+            if let Some(mut encoder) = self.delta_encoder.take() {
+                buf = delta::encode_ops(&encoder.finish());
+                is_delta = true;
+            }
+            self.report_file_finished();
             self.file_num += 1;
             self.data_stream = None;
             self.file_confirmed = false;
             self.file_is_waiting = false;
         } else {
             self.finished_size += offset as u64;
-            if matches!(self.data_source, DataSource::FilePath(_)) && !is_compressed_file(name) {
-                let tmp = compress(&buf);
-                if tmp.len() < buf.len() {
-                    buf = tmp;
-                    compressed = true;
-                }
+            if matches!(self.data_source, DataSource::FilePath(_)) {
+                self.charge(offset as u64)?;
+            }
+            self.hasher.update(&buf);
+            if self.skip_compression.is_none()
+                && (is_compressed_file(&name) || is_compressed_magic(&buf))
+            {
+                self.skip_compression = Some(true);
+            }
+            if let Some(encoder) = self.delta_encoder.as_mut() {
+                buf = delta::encode_ops(&encoder.update(&buf));
+                is_delta = true;
+            }
+            if matches!(self.data_source, DataSource::FilePath(_)) {
+                (buf, compressed) = self.compress_block(buf);
             }
             self.transferred += buf.len() as u64;
         }
@@ -798,17 +1855,185 @@ impl TransferJob {
             file_num: file_num as _,
             data: buf.into(),
             compressed,
+            hash: hash.into(),
+            is_delta,
+            metadata,
+            ..Default::default()
+        }))
+    }
+
+    // Sparse files: the hole at the current position is sent as its length.
+    async fn read_hole(&mut self, file_num: usize) -> ResultType<Option<FileTransferBlock>> {
+        let (DataSource::FilePath(p), Some(DataStream::FileStream(f))) =
+            (&self.data_source, self.data_stream.as_mut())
+        else {
+            return Ok(None);
+        };
+        if self.holes.is_none() {
+            let path = Self::join(p, &self.files[file_num].name);
+            self.holes = Some(Holes::open(&path, f.stream_position().await?));
+        }
+        let Some(holes) = self.holes.as_mut() else {
+            return Ok(None);
+        };
+        let hole = holes.skip_hole();
+        if hole == 0 {
+            return Ok(None);
+        }
+        f.seek(std::io::SeekFrom::Start(holes.pos)).await?;
+        hash_zeros(&mut self.hasher, hole);
+        self.finished_size += hole;
+        Ok(Some(FileTransferBlock {
+            id: self.id,
+            file_num: file_num as _,
+            hole,
             ..Default::default()
         }))
     }
 
+    fn compress_block(&mut self, buf: Vec<u8>) -> (Vec<u8>, bool) {
+        if self.skip_compression == Some(true) {
+            return (buf, false);
+        }
+        let tmp = self
+            .compression
+            .compress(self.codecs.encoder.compressor(), &buf);
+        if self.skip_compression.is_none() {
+            self.skip_compression = Some(is_incompressible(buf.len(), tmp.len()));
+        }
+        if tmp.len() < buf.len() {
+            (tmp, true)
+        } else {
+            (buf, false)
+        }
+    }
+
+    async fn read_parallel(&mut self, file_num: usize) -> ResultType<FileTransferBlock> {
+        let DataSource::FilePath(p) = &self.data_source else {
+            bail!("No file to read");
+        };
+        let entry = &self.files[file_num];
+        let path = Self::join(p, &entry.name);
+        if self.parallel.is_none() {
+            let start = match self.data_stream.as_mut() {
+                Some(DataStream::FileStream(f)) => f.stream_position().await?,
+                _ => 0,
+            };
+            let reader = ParallelReader::open(&path, start, entry.size, self.concurrency).await?;
+            self.parallel = Some(reader);
+        }
+        let next = match self.parallel.as_mut() {
+            Some(reader) => reader.next_block().await,
+            None => Ok(None),
+        };
+        match next {
+            Ok(Some((offset, buf))) => {
+                self.finished_size += buf.len() as u64;
+                self.charge(buf.len() as u64)?;
+                if self.skip_compression.is_none()
+                    && (is_compressed_file(&entry.name) || is_compressed_magic(&buf))
+                {
+                    self.skip_compression = Some(true);
+                }
+                let (buf, compressed) = self.compress_block(buf);
+                self.transferred += buf.len() as u64;
+                Ok(FileTransferBlock {
+                    id: self.id,
+                    file_num: file_num as _,
+                    data: buf.into(),
+                    compressed,
+                    offset,
+                    positioned: true,
+                    ..Default::default()
+                })
+            }
+            res => {
+                self.parallel = None;
+                self.file_num += 1;
+                self.data_stream = None;
+                self.file_confirmed = false;
+                self.file_is_waiting = false;
+                res?;
+                self.report_file_finished_at(file_num as _);
+                // the ranges were not hashed in order
+                let hash = hash_file_blocking(path).await?;
+                Ok(FileTransferBlock {
+                    id: self.id,
+                    file_num: file_num as _,
+                    hash: hash.as_bytes().to_vec().into(),
+                    metadata: self.local_metadata(file_num),
+                    ..Default::default()
+                })
+            }
+        }
+    }
+
+    // `verify_only`: hash the whole file here and send just the block ending it.
+    async fn read_hash_only(&mut self, file_num: usize) -> ResultType<FileTransferBlock> {
+        let path = match &self.data_source {
+            DataSource::FilePath(p) => Some(Self::join(p, &self.files[file_num].name)),
+            DataSource::MemoryCursor(_) => None,
+        };
+        if let Some(hash) = path.as_ref().and_then(|p| checksum_cache::get(p)) {
+            self.finished_size += self.files[file_num].size;
+            self.report_file_finished();
+            self.file_num += 1;
+            self.data_stream = None;
+            return Ok(FileTransferBlock {
+                id: self.id,
+                file_num: file_num as _,
+                hash: hash.as_bytes().to_vec().into(),
+                ..Default::default()
+            });
+        }
+        // of the file opened, before reading it
+        let meta = match self.data_stream.as_ref() {
+            Some(DataStream::FileStream(file)) => file.metadata().await.ok(),
+            _ => None,
+        };
+        let mut buf = vec![0u8; 128 * 1024];
+        loop {
+            let res = self
+                .data_stream
+                .as_mut()
+                .ok_or(anyhow!("data stream is None"))?
+                .read(&mut buf)
+                .await;
+            match res {
+                Ok(0) => break,
+                Ok(n) => {
+                    self.hasher.update(&buf[..n]);
+                    self.finished_size += n as u64;
+                }
+                Err(err) => {
+                    self.file_num += 1;
+                    self.data_stream = None;
+                    return Err(err.into());
+                }
+            }
+        }
+        let hash = self.hasher.finalize();
+        if let (Some(path), Some(meta)) = (path, meta) {
+            checksum_cache::insert(&path, &meta, hash);
+        }
+        self.report_file_finished();
+        self.file_num += 1;
+        self.data_stream = None;
+        Ok(FileTransferBlock {
+            id: self.id,
+            file_num: file_num as _,
+            hash: hash.as_bytes().to_vec().into(),
+            ..Default::default()
+        })
+    }
+
     // Only for generic job and file stream
     async fn send_current_digest(&mut self, stream: &mut Stream) -> ResultType<()> {
         let mut msg = Message::new();
         let mut resp = FileResponse::new();
         let meta = match self.data_stream.as_ref().ok_or(anyhow!("file is None"))? {
             DataStream::FileStream(file) => file.metadata().await?,
-            DataStream::BufStream(_) => bail!("No need to send digest for buf stream"),
+            _ => bail!("No need to send digest for buf stream"),
         };
         let last_modified = meta
             .modified()?
@@ -891,6 +2116,9 @@ impl TransferJob {
         if self.job_skipped() {
             return Some("skipped".to_string());
         }
+        if self.checks.values().any(|c| !c.verified) {
+            return Some("integrity check failed".to_string());
+        }
         None
     }
 
@@ -906,30 +2134,28 @@ impl TransferJob {
 
     async fn set_stream_offset(&mut self, file_num: usize, offset: u64) {
         if let DataSource::FilePath(p) = &self.data_source {
-            let entry = &self.files[file_num];
-            let path = Self::join(p, &entry.name);
+            let Ok(path) = check_path(&Self::join(p, &self.files[file_num].name)) else {
+                return;
+            };
+            self.target = Some((file_num as i32, path.clone()));
             let file_path = get_string(&path);
             let download_path = format!("{}.download", &file_path);
             let digest_path = format!("{}.digest", &file_path);
 
-            let mut f = if Path::new(&download_path).exists() && Path::new(&digest_path).exists() {
+            let exists = |x: &str| provider().stat(Path::new(x)).is_ok();
+            let (mut f, opened) = if exists(&download_path) && exists(&digest_path) {
                 // If both download and digest files exist, seek (writer) to the offset
-                match OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .open(&download_path)
-                    .await
-                {
-                    Ok(f) => f,
+                match provider().open_update(Path::new(&download_path)) {
+                    Ok(f) => (File::from_std(f), download_path),
                     Err(e) => {
                         log::warn!("Failed to open file {}: {}", download_path, e);
                         return;
                     }
                 }
-            } else if Path::new(&file_path).exists() {
+            } else if exists(&file_path) {
                 // If `file_path` exists, seek (reader) to the offset
-                match File::open(&file_path).await {
-                    Ok(f) => f,
+                match provider().open_read(Path::new(&file_path)) {
+                    Ok(f) => (File::from_std(f), file_path),
                     Err(e) => {
                         log::warn!("Failed to open file {}: {}", file_path, e);
                         return;
@@ -944,7 +2170,15 @@ impl TransferJob {
                 return;
             };
             if f.seek(std::io::SeekFrom::Start(offset)).await.is_ok() {
+                // the hash covers the whole file, including the skipped part
+                let path = opened.clone();
+                match tokio::task::spawn_blocking(move || hash_file_prefix(&path, offset)).await {
+                    Ok(Ok(hasher)) => self.hasher = hasher,
+                    Ok(Err(e)) => log::warn!("Failed to hash {}: {}", opened, e),
+                    Err(e) => log::warn!("Failed to hash {}: {}", opened, e),
+                }
                 self.data_stream = Some(DataStream::FileStream(f));
+                self.holes = None;
                 self.transferred += offset;
                 self.finished_size += offset;
             }
@@ -965,6 +2199,14 @@ impl TransferJob {
                 }
                 Some(file_transfer_send_confirm_request::Union::OffsetBlk(offset)) => {
                     self.set_file_confirmed(true);
+                    if offset == 0 && !r.delta_signature.is_empty() {
+                        match delta::Signature::from_bytes(&r.delta_signature) {
+                            Ok(signature) => {
+                                self.delta_encoder = Some(delta::Encoder::new(&signature))
+                            }
+                            Err(err) => log::warn!("Ignore delta signature: {}", err),
+                        }
+                    }
                     // If offset is greater than 0, we need to seek to the offset
                     if offset > 0 {
                         self.set_stream_offset(r.file_num as usize, offset as u64)
@@ -977,6 +2219,156 @@ impl TransferJob {
         true
     }
 
+    /// Record of the job for `TransferSerde`, to be taken periodically and on
+    /// disconnect. While receiving, the hashes of the data written so far are
+    /// also kept in the `.digest` file, so that it can be verified on resume.
+    /// The hashing runs on the blocking pool.
+    pub async fn checkpoint(&self) -> TransferJobRecord {
+        let file_num = self.file_num.max(0) as usize;
+        let mut files: Vec<TransferFileRecord> = self
+            .files
+            .iter()
+            .enumerate()
+            .map(|(i, f)| {
+                let check = self.checks.get(&(i as i32));
+                TransferFileRecord {
+                    name: f.name.clone(),
+                    size: f.size,
+                    modified: f.modified_time,
+                    offset: if i < file_num { f.size } else { 0 },
+                    blake3: check.map(|c| c.hash.clone()).unwrap_or_default(),
+                    verified: check.map(|c| c.verified),
+                    ..Default::default()
+                }
+            })
+            .collect();
+        if let (DataSource::FilePath(p), Some(file)) = (&self.data_source, files.get_mut(file_num))
+        {
+            let path = get_string(&Self::join(p, &file.name));
+            let contiguous = self.contiguous();
+            let res =
+                tokio::task::spawn_blocking(move || update_chunk_hashes(&path, contiguous)).await;
+            if let Ok(Some((len, hashes))) = res {
+                file.offset = ((hashes.len() * RESUME_CHUNK_SIZE) as u64).min(len);
+                file.chunk_hashes = hashes;
+            }
+        }
+        TransferJobRecord {
+            id: self.id,
+            remote: self.remote.clone(),
+            to: self.data_source.to_meta(),
+            show_hidden: self.show_hidden,
+            is_remote: self.is_remote,
+            file_num: self.file_num,
+            files,
+            is_mirror: self.r#type == JobType::Mirror,
+        }
+    }
+
+    fn files_from_record(record: &TransferJobRecord) -> Vec<FileEntry> {
+        record
+            .files
+            .iter()
+            .map(|f| FileEntry {
+                name: f.name.clone(),
+                size: f.size,
+                modified_time: f.modified,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    /// Recreate an interrupted write job after reconnect or restart. The
+    /// received data is verified against the checkpoint hashes when the sender
+    /// asks for the digest, see `is_write_need_confirmation`.
+    pub fn resume_write(record: &TransferJobRecord, enable_overwrite_detection: bool) -> Self {
+        let mut job = Self::new_write(
+            record.id,
+            JobType::Generic,
+            record.remote.clone(),
+            DataSource::FilePath(get_path(&record.to)),
+            record.file_num,
+            record.show_hidden,
+            record.is_remote,
+            Self::files_from_record(record),
+            enable_overwrite_detection,
+        );
+        job.is_resume = true;
+        job.set_finished_size_on_resume();
+        job
+    }
+
+    /// Recreate an interrupted read job, the files are listed again in case
+    /// they changed meanwhile.
+    pub fn resume_read(
+        record: &TransferJobRecord,
+        enable_overwrite_detection: bool,
+    ) -> ResultType<Self> {
+        let r#type = if record.is_mirror {
+            JobType::Mirror
+        } else {
+            JobType::Generic
+        };
+        let mut job = Self::new_read(
+            record.id,
+            r#type,
+            record.remote.clone(),
+            DataSource::FilePath(get_path(&record.to)),
+            record.file_num,
+            record.show_hidden,
+            record.is_remote,
+            enable_overwrite_detection,
+        )?;
+        if record.is_mirror {
+            // keep the list compared with the manifest, `file_num` indexes it
+            let mut current: HashMap<String, FileEntry> =
+                job.files.drain(..).map(|f| (f.name.clone(), f)).collect();
+            job.files = Self::files_from_record(record)
+                .into_iter()
+                .map(|f| current.remove(&f.name).unwrap_or(f))
+                .collect();
+            job.total_size = job.files.iter().map(|x| x.size).sum();
+        }
+        job.is_resume = true;
+        job.set_finished_size_on_resume();
+        Ok(job)
+    }
+
+    /// Read job sending only the files under `path` which are missing or
+    /// changed in the `manifest` of the destination, see `read_manifest`.
+    /// Files only in the destination are kept.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_mirror(
+        id: i32,
+        remote: String,
+        path: &str,
+        show_hidden: bool,
+        is_remote: bool,
+        manifest: &[ManifestEntry],
+        enable_overwrite_detection: bool,
+    ) -> ResultType<Self> {
+        let mut job = Self::new_read(
+            id,
+            JobType::Mirror,
+            remote,
+            DataSource::FilePath(get_path(path)),
+            0,
+            show_hidden,
+            is_remote,
+            enable_overwrite_detection,
+        )?;
+        let files = std::mem::take(&mut job.files);
+        job.files = mirror_changed_files(&get_path(path), files, manifest);
+        job.total_size = job.files.iter().map(|x| x.size).sum();
+        log::info!(
+            "mirror {}: {} files changed, {} in destination",
+            path,
+            job.files.len(),
+            manifest.len()
+        );
+        Ok(job)
+    }
+
     #[inline]
     pub fn gen_meta(&self) -> TransferJobMeta {
         TransferJobMeta {
@@ -1082,6 +2474,115 @@ pub fn new_send(
     msg_out
 }
 
+/// Ask for the hashes of the files under `path` only, to be compared by a
+/// write job with `verify_only` set.
+#[inline]
+pub fn new_send_verify(id: i32, path: String, include_hidden: bool) -> Message {
+    let mut action = FileAction::new();
+    action.set_send(FileTransferSendRequest {
+        id,
+        path,
+        include_hidden,
+        verify_only: true,
+        ..Default::default()
+    });
+    let mut msg_out = Message::new();
+    msg_out.set_file_action(action);
+    msg_out
+}
+
+#[inline]
+fn manifest_key(name: &str) -> String {
+    name.replace('\\', "/")
+}
+
+/// Entries of the files under `path` for `ReadManifest`, empty if it does not
+/// exist yet. Unfinished downloads are not listed.
+pub fn read_manifest(
+    path: &str,
+    include_hidden: bool,
+    with_hash: bool,
+) -> ResultType<Vec<ManifestEntry>> {
+    let base = get_path(path);
+    if provider().stat(&base).is_err() {
+        return Ok(Vec::new());
+    }
+    let files = get_recursive_files(path, include_hidden)?;
+    Ok(files
+        .into_iter()
+        .filter(|f| !f.name.ends_with(".download") && !f.name.ends_with(".digest"))
+        .map(|f| {
+            let hash = if with_hash {
+                checksum_cache::hash_content(&TransferJob::join(&base, &f.name))
+                    .map(|h| h.as_bytes().to_vec())
+                    .unwrap_or_default()
+            } else {
+                Vec::new()
+            };
+            ManifestEntry {
+                name: manifest_key(&f.name),
+                size: f.size,
+                modified_time: f.modified_time,
+                hash: hash.into(),
+                ..Default::default()
+            }
+        })
+        .collect())
+}
+
+/// The `files` under `base` which differ from the `manifest`: missing, with
+/// another size, or with another hash if known, another mtime otherwise.
+pub fn mirror_changed_files(
+    base: &Path,
+    files: Vec<FileEntry>,
+    manifest: &[ManifestEntry],
+) -> Vec<FileEntry> {
+    let remote: HashMap<String, &ManifestEntry> = manifest
+        .iter()
+        .map(|e| (manifest_key(&e.name), e))
+        .collect();
+    let base = base.to_path_buf();
+    files
+        .into_iter()
+        .filter(|f| match remote.get(&manifest_key(&f.name)) {
+            None => true,
+            Some(r) if r.size != f.size => true,
+            Some(r) if r.hash.is_empty() => r.modified_time != f.modified_time,
+            Some(r) => checksum_cache::hash_content(&TransferJob::join(&base, &f.name))
+                .map_or(true, |h| h.as_bytes()[..] != r.hash[..]),
+        })
+        .collect()
+}
+
+#[inline]
+pub fn new_read_manifest(id: i32, path: String, include_hidden: bool, with_hash: bool) -> Message {
+    let mut action = FileAction::new();
+    action.set_read_manifest(ReadManifest {
+        id,
+        path,
+        include_hidden,
+        with_hash,
+        ..Default::default()
+    });
+    let mut msg_out = Message::new();
+    msg_out.set_file_action(action);
+    msg_out
+}
+
+#[inline]
+pub fn new_manifest(id: i32, path: String, entries: Vec<ManifestEntry>) -> Message {
+    let mut resp = FileResponse::new();
+    resp.set_manifest(FileManifest {
+        id,
+        path,
+        entries,
+        ..Default::default()
+    });
+    let mut msg_out = Message::new();
+    msg_out.set_file_response(resp);
+    msg_out
+}
+
 #[inline]
 pub fn new_done(id: i32, file_num: i32) -> Message {
     let mut resp = FileResponse::new();
@@ -1130,12 +2631,15 @@ pub async fn handle_read_jobs(
             }
             Ok(Some(block)) => {
                 stream.send(&new_block(block)).await?;
+                job.report_progress();
             }
             Ok(None) => {
                 if job.job_completed() {
                     job_log = serialize_transfer_job(job, true, false, "");
                     finished.push(job.id());
-                    match job.job_error() {
+                    let error = job.job_error();
+                    job.report_done(error.clone());
+                    match error {
                         Some(err) => {
                             job_log = serialize_transfer_job(job, false, false, &err);
                             stream
@@ -1152,6 +2656,9 @@ pub async fn handle_read_jobs(
         // Break to handle jobs one by one.
         break;
     }
+    if !finished.is_empty() {
+        checksum_cache::flush();
+    }
     for id in finished {
         let _ = remove_job(id, jobs);
     }
@@ -1159,6 +2666,7 @@ pub async fn handle_read_jobs(
 }
 
 pub fn remove_all_empty_dir(path: &Path) -> ResultType<()> {
+    let path = &check_path(path)?;
     let fd = read_dir(path, true)?;
     for entry in fd.entries.iter() {
         match entry.entry_type.enum_value() {
@@ -1166,37 +2674,40 @@ pub fn remove_all_empty_dir(path: &Path) -> ResultType<()> {
                 remove_all_empty_dir(&path.join(&entry.name)).ok();
             }
             Ok(FileType::DirLink) | Ok(FileType::FileLink) => {
-                std::fs::remove_file(path.join(&entry.name)).ok();
+                provider().remove(&path.join(&entry.name)).ok();
             }
             _ => {}
         }
     }
-    std::fs::remove_dir(path).ok();
+    provider().remove_dir(path).ok();
     Ok(())
 }
 
 #[inline]
 pub fn remove_file(file: &str) -> ResultType<()> {
-    std::fs::remove_file(get_path(file))?;
-    Ok(())
+    let path = check_path(&get_path(file))?;
+    checksum_cache::invalidate(&path);
+    provider().remove(&path)
 }
 
 #[inline]
 pub fn create_dir(dir: &str) -> ResultType<()> {
-    std::fs::create_dir_all(get_path(dir))?;
-    Ok(())
+    provider().create_dir_all(&check_path(&get_path(dir))?)
 }
 
 #[inline]
 pub fn rename_file(path: &str, new_name: &str) -> ResultType<()> {
     let path = std::path::Path::new(&path);
-    if path.exists() {
+    if provider().stat(path).is_ok() {
         let dir = path
             .parent()
             .ok_or(anyhow!("Parent directoy of {path:?} not exists"))?;
         let new_path = dir.join(&new_name);
-        std::fs::rename(&path, &new_path)?;
-        Ok(())
+        let path = check_path(path)?;
+        let new_path = check_path(&new_path)?;
+        checksum_cache::invalidate(&path);
+        checksum_cache::invalidate(&new_path);
+        provider().rename(&path, &new_path)
     } else {
         bail!("{path:?} not exists");
     }
@@ -1209,12 +2720,108 @@ pub fn transform_windows_path(entries: &mut Vec<FileEntry>) {
     }
 }
 
+#[cfg(windows)]
+const INVALID_NAME_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
+#[cfg(not(windows))]
+const INVALID_NAME_CHARS: &[char] = &[];
+
+// Names Windows maps to devices, also with any extension, e.g. "nul.txt".
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+fn sanitize_component(name: &str) -> String {
+    use unicode_normalization::UnicodeNormalization;
+    let mut res: String = name
+        .nfc()
+        .map(|c| {
+            if c.is_control() || INVALID_NAME_CHARS.contains(&c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    if cfg!(windows) {
+        let trimmed = res.trim_end_matches(['.', ' ']).len();
+        if trimmed == 0 {
+            return "_".to_owned();
+        }
+        res.truncate(trimmed);
+        let stem = res.split('.').next().unwrap_or_default().trim_end();
+        if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+            res.insert(0, '_');
+        }
+    }
+    res
+}
+
+/// Maps a relative path received from the peer to one valid on this platform:
+/// both separators split components, "." and ".." are dropped, names are NFC
+/// normalized, and on Windows invalid characters, trailing dots and spaces,
+/// and reserved device names are rewritten.
+pub fn sanitize_remote_name(name: &str) -> String {
+    name.split(['/', '\\'])
+        .filter(|c| !c.is_empty() && *c != "." && *c != "..")
+        .map(sanitize_component)
+        .collect::<Vec<_>>()
+        .join(std::path::MAIN_SEPARATOR_STR)
+}
+
+// Windows and macOS file systems are case insensitive by default.
+fn collision_key(name: &str) -> String {
+    if cfg!(any(windows, target_os = "macos")) {
+        name.to_lowercase()
+    } else {
+        name.to_owned()
+    }
+}
+
+// "a/b.txt" -> "a/b (1).txt"
+fn numbered_name(name: &str, n: usize) -> String {
+    let (dir, file) = match name.rfind(std::path::MAIN_SEPARATOR) {
+        Some(i) => name.split_at(i + 1),
+        None => ("", name),
+    };
+    match file.rfind('.') {
+        Some(i) if i > 0 => format!("{}{} ({}){}", dir, &file[..i], n, &file[i..]),
+        _ => format!("{} ({})", name, n),
+    }
+}
+
+/// Sanitizes the names of received files. Names colliding after sanitizing,
+/// e.g. "a:b" and "a_b" on Windows, get a number appended instead of failing
+/// the transfer when the second one is written.
+pub fn sanitize_remote_names(files: &mut [FileEntry]) {
+    let mut used = std::collections::HashSet::new();
+    for entry in files.iter_mut() {
+        if entry.name.is_empty() {
+            // the single file of a job
+            continue;
+        }
+        let name = sanitize_remote_name(&entry.name);
+        let mut unique = name.clone();
+        let mut n = 0;
+        while !used.insert(collision_key(&unique)) {
+            n += 1;
+            unique = numbered_name(&name, n);
+        }
+        if unique != entry.name {
+            log::info!("Received file {:?} is written as {:?}", entry.name, unique);
+            entry.name = unique;
+        }
+    }
+}
+
 pub enum DigestCheckResult {
     IsSame,
     NeedConfirm(FileTransferDigest),
     NoSuchFile,
 }
 
+/// Blocking on resume, the received part of the `.download` file is hashed,
+/// call it off the async runtime, e.g. with `spawn_blocking`.
 #[inline]
 pub fn is_write_need_confirmation(
     is_resume: bool,
@@ -1224,18 +2831,36 @@ pub fn is_write_need_confirmation(
     let path = Path::new(file_path);
     let digest_file = format!("{}.digest", file_path);
     let download_file = format!("{}.download", file_path);
-    if is_resume && Path::new(&digest_file).exists() && Path::new(&download_file).exists() {
+    let download_stat = provider().stat(Path::new(&download_file));
+    if is_resume && download_stat.is_ok() {
         // If the digest file exists, it means the file was transferred before.
         // We can use the digest file to check whether the file is the same.
-        if let Ok(content) = std::fs::read_to_string(digest_file) {
-            if let Ok(local_digest) = serde_json::from_str::<FileDigest>(&content) {
+        if let Ok(content) = provider().read(Path::new(&digest_file)) {
+            if let Ok(local_digest) = serde_json::from_slice::<FileDigest>(&content) {
                 let is_identical = local_digest.modified == digest.last_modified
                     && local_digest.size == digest.file_size;
                 if is_identical {
-                    if let Ok(download_metadata) = std::fs::metadata(download_file) {
+                    if let Ok(download_stat) = download_stat {
                         // Get the file size of the local file
                         // Only send confirmation if the file is not empty.
-                        let transferred_size = download_metadata.len();
+                        let mut transferred_size = download_stat.size;
+                        if !local_digest.chunk_hashes.is_empty() {
+                            // Only keep what matches the checkpoint
+                            transferred_size =
+                                verified_length(&download_file, &local_digest.chunk_hashes);
+                            if transferred_size < download_stat.size {
+                                log::info!(
+                                    "Resume {} from verified offset {} of {}",
+                                    file_path,
+                                    transferred_size,
+                                    download_stat.size
+                                );
+                                provider()
+                                    .open_update(Path::new(&download_file))
+                                    .and_then(|f| Ok(f.set_len(transferred_size)?))
+                                    .ok();
+                            }
+                        }
                         if transferred_size > 0 {
                             return Ok(DigestCheckResult::NeedConfirm(FileTransferDigest {
                                 id: digest.id,
@@ -1253,23 +2878,23 @@ pub fn is_write_need_confirmation(
         }
     }
 
-    if path.exists() && path.is_file() {
-        let metadata = std::fs::metadata(path)?;
-        let modified_time = metadata.modified()?;
-        let remote_mt = Duration::from_secs(digest.last_modified);
-        let local_mt = modified_time.duration_since(UNIX_EPOCH)?;
+    let stat = provider().stat(path);
+    if let Some(stat) = stat
+        .ok()
+        .filter(|x| x.entry_type.enum_value() == Ok(FileType::File))
+    {
         // [Note]
         // We decide to give the decision whether to override the existing file to users,
         // which obey the behavior of the file manager in our system.
         let mut is_identical = false;
-        if remote_mt == local_mt && digest.file_size == metadata.len() {
+        if digest.last_modified == stat.modified_time && digest.file_size == stat.size {
             is_identical = true;
         }
         Ok(DigestCheckResult::NeedConfirm(FileTransferDigest {
             id: digest.id,
             file_num: digest.file_num,
-            last_modified: local_mt.as_secs(),
-            file_size: metadata.len(),
+            last_modified: stat.modified_time,
+            file_size: stat.size,
             is_identical,
             ..Default::default()
         }))
@@ -1295,3 +2920,131 @@ pub fn serialize_transfer_job(job: &TransferJob, done: bool, cancel: bool, error
     value["error"] = json!(error);
     serde_json::to_string(&value).unwrap_or_default()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verified_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = get_string(&dir.path().join("hbb_resume"));
+        let mut data = vec![1u8; RESUME_CHUNK_SIZE * 2 + 10];
+        std::fs::write(&path, &data).unwrap();
+        let hashes = hash_new_chunks(&path, &[]).unwrap();
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(hash_new_chunks(&path, &hashes[..1]).unwrap(), hashes);
+        assert_eq!(
+            verified_length(&path, &hashes),
+            RESUME_CHUNK_SIZE as u64 * 2
+        );
+        // corrupted second chunk
+        data[RESUME_CHUNK_SIZE + 1] = 2;
+        std::fs::write(&path, &data).unwrap();
+        assert_eq!(verified_length(&path, &hashes), RESUME_CHUNK_SIZE as u64);
+        std::fs::remove_file(&path).ok();
+        assert_eq!(verified_length(&path, &hashes), 0);
+    }
+
+    #[test]
+    fn test_add_range() {
+        let mut ranges = vec![];
+        add_range(&mut ranges, 10, 20);
+        add_range(&mut ranges, 30, 40);
+        assert_eq!(ranges, vec![(10, 20), (30, 40)]);
+        add_range(&mut ranges, 0, 10);
+        add_range(&mut ranges, 20, 30);
+        assert_eq!(ranges, vec![(0, 40)]);
+    }
+
+    #[test]
+    fn test_is_safe_link_target() {
+        assert!(is_safe_link_target("a/link", "b.txt"));
+        assert!(is_safe_link_target("a/link", "../c/d.txt"));
+        assert!(is_safe_link_target("a/b/link", "./../../e"));
+        assert!(!is_safe_link_target("a/link", "../../x"));
+        assert!(!is_safe_link_target("link", ".."));
+        assert!(!is_safe_link_target("link", "/etc/passwd"));
+        assert!(!is_safe_link_target("../link", "a"));
+        assert!(!is_safe_link_target("link", ""));
+    }
+
+    #[test]
+    fn test_sanitize_remote_name() {
+        let sep = std::path::MAIN_SEPARATOR_STR;
+        assert_eq!(sanitize_remote_name("a/../b\\c"), format!("a{sep}b{sep}c"));
+        assert_eq!(sanitize_remote_name("./x"), "x");
+        // NFD "é" becomes NFC
+        assert_eq!(sanitize_remote_name("e\u{301}.txt"), "\u{e9}.txt");
+        assert_eq!(sanitize_remote_name("a\tb"), "a_b");
+        #[cfg(windows)]
+        {
+            assert_eq!(sanitize_remote_name("nul.txt"), "_nul.txt");
+            assert_eq!(sanitize_remote_name("Com1"), "_Com1");
+            assert_eq!(sanitize_remote_name("a:b?. "), "a_b_");
+            assert_eq!(sanitize_remote_name("..."), "_");
+        }
+        let mut files: Vec<FileEntry> = ["a.txt", "./a.txt", "b", "b"]
+            .iter()
+            .map(|n| FileEntry {
+                name: n.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        sanitize_remote_names(&mut files);
+        let names: Vec<_> = files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["a.txt", "a (1).txt", "b", "b (1)"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_has_link_below() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        std::fs::create_dir_all(root.join("dir/sub")).unwrap();
+        std::os::unix::fs::symlink("/tmp", root.join("dir/link")).unwrap();
+        assert!(!has_link_below(root, "dir/sub/a.txt"));
+        assert!(!has_link_below(root, "dir/link"));
+        assert!(has_link_below(root, "dir/link/a.txt"));
+        assert!(!has_link_below(root, "new/a.txt"));
+    }
+
+    #[test]
+    fn test_mirror_changed_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path().to_path_buf();
+        std::fs::create_dir_all(base.join("sub")).unwrap();
+        std::fs::write(base.join("same.txt"), b"same").unwrap();
+        std::fs::write(base.join("sub/touched.txt"), b"touched").unwrap();
+        std::fs::write(base.join("changed.txt"), b"changed").unwrap();
+        std::fs::write(base.join("new.txt"), b"new").unwrap();
+        let dir = get_string(&base);
+        let mut manifest = read_manifest(&dir, false, true).unwrap();
+        manifest.retain(|e| e.name != "new.txt");
+        for e in manifest.iter_mut() {
+            if e.name == "sub/touched.txt" {
+                e.modified_time += 100;
+            }
+        }
+        std::fs::write(base.join("changed.txt"), b"CHANGED").unwrap();
+        let files = get_recursive_files(&dir, false).unwrap();
+        let mut changed: Vec<String> = mirror_changed_files(&base, files.clone(), &manifest)
+            .into_iter()
+            .map(|f| manifest_key(&f.name))
+            .collect();
+        changed.sort();
+        assert_eq!(changed, vec!["changed.txt", "new.txt"]);
+        // without hashes the mtime decides
+        for e in manifest.iter_mut() {
+            e.hash = Default::default();
+        }
+        let changed: Vec<String> = mirror_changed_files(&base, files, &manifest)
+            .into_iter()
+            .map(|f| manifest_key(&f.name))
+            .collect();
+        assert!(changed.contains(&"sub/touched.txt".to_owned()));
+        assert!(!changed.contains(&"same.txt".to_owned()));
+        drop(tmp);
+        assert!(read_manifest(&dir, false, false).unwrap().is_empty());
+    }
+}
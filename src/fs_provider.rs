@@ -0,0 +1,262 @@
+// Where `fs` lists, reads and writes files, so that storages without plain
+// paths (Android SAF, sandboxed iOS storage, cloud drives) can be plugged in
+// with `set_provider` without changing the transfer logic.
+//
+// Files are handed out as `std::fs::File`, backends without file descriptors
+// can spool to a temporary file. The scratch files of a transfer
+// (`.download`, `.digest`) and the links recreated go through the provider too.
+// https://doc.rust-lang.org/std/os/windows/fs/trait.MetadataExt.html
+#[cfg(windows)]
+use std::os::windows::prelude::*;
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    fs::{get_file_name, get_string},
+    message_proto::*,
+    ResultType,
+};
+
+pub trait FsProvider: Send + Sync {
+    /// Entries of the folder, links are not followed.
+    fn list(&self, path: &Path, include_hidden: bool) -> ResultType<FileDirectory>;
+    /// `File` or `Dir`, links are followed.
+    fn stat(&self, path: &Path) -> ResultType<FileEntry>;
+    fn open_read(&self, path: &Path) -> ResultType<std::fs::File>;
+    /// Created or truncated, a link at `path` is not followed.
+    fn open_write(&self, path: &Path) -> ResultType<std::fs::File>;
+    /// An existing file opened for writing without truncating, e.g. to resume
+    /// a download, a link at `path` is not followed.
+    fn open_update(&self, path: &Path) -> ResultType<std::fs::File>;
+    fn read(&self, path: &Path) -> ResultType<Vec<u8>>;
+    /// Created or replaced.
+    fn write(&self, path: &Path, data: &[u8]) -> ResultType<()>;
+    fn remove(&self, path: &Path) -> ResultType<()>;
+    fn rename(&self, from: &Path, to: &Path) -> ResultType<()>;
+    fn create_dir_all(&self, path: &Path) -> ResultType<()>;
+    /// Only if empty.
+    fn remove_dir(&self, path: &Path) -> ResultType<()>;
+    /// Whether `path` itself is a link, it is not followed.
+    fn is_link(&self, path: &Path) -> bool;
+    fn read_link(&self, path: &Path) -> ResultType<PathBuf>;
+    /// `dir` for a link to a folder, which Windows tells apart.
+    fn symlink(&self, target: &Path, path: &Path, dir: bool) -> ResultType<()>;
+    /// Absolute, with the links resolved.
+    fn canonicalize(&self, path: &Path) -> ResultType<PathBuf>;
+}
+
+// Not following a link at the path itself.
+fn no_follow(options: &mut std::fs::OpenOptions) -> &mut std::fs::OpenOptions {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_NOFOLLOW);
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        options.custom_flags(winapi::um::winbase::FILE_FLAG_OPEN_REPARSE_POINT);
+    }
+    options
+}
+
+fn modified_secs(meta: &std::fs::Metadata) -> u64 {
+    meta.modified()
+        .map(|x| {
+            x.duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .map(|x| x.as_secs())
+                .unwrap_or(0)
+        })
+        .unwrap_or(0)
+}
+
+/// The local file system, the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NativeFs;
+
+impl FsProvider for NativeFs {
+    fn list(&self, path: &Path, include_hidden: bool) -> ResultType<FileDirectory> {
+        let mut dir = FileDirectory {
+            path: get_string(path),
+            ..Default::default()
+        };
+        #[cfg(windows)]
+        if "/" == &get_string(path) {
+            let drives = unsafe { winapi::um::fileapi::GetLogicalDrives() };
+            for i in 0..32 {
+                if drives & (1 << i) != 0 {
+                    let name = format!(
+                        "{}:",
+                        std::char::from_u32('A' as u32 + i as u32).unwrap_or('A')
+                    );
+                    dir.entries.push(FileEntry {
+                        name,
+                        entry_type: FileType::DirDrive.into(),
+                        ..Default::default()
+                    });
+                }
+            }
+            return Ok(dir);
+        }
+        for entry in path.read_dir()?.flatten() {
+            let p = entry.path();
+            let name = p
+                .file_name()
+                .map(|p| p.to_str().unwrap_or(""))
+                .unwrap_or("")
+                .to_owned();
+            if name.is_empty() {
+                continue;
+            }
+            let mut is_hidden = false;
+            let meta;
+            if let Ok(tmp) = std::fs::symlink_metadata(&p) {
+                meta = tmp;
+            } else {
+                continue;
+            }
+            // docs.microsoft.com/en-us/windows/win32/fileio/file-attribute-constants
+            #[cfg(windows)]
+            if meta.file_attributes() & 0x2 != 0 {
+                is_hidden = true;
+            }
+            #[cfg(not(windows))]
+            if name.find('.').unwrap_or(usize::MAX) == 0 {
+                is_hidden = true;
+            }
+            if is_hidden && !include_hidden {
+                continue;
+            }
+            let (entry_type, size) = {
+                if p.is_dir() {
+                    if meta.file_type().is_symlink() {
+                        (FileType::DirLink.into(), 0)
+                    } else {
+                        (FileType::Dir.into(), 0)
+                    }
+                } else if meta.file_type().is_symlink() {
+                    (FileType::FileLink.into(), 0)
+                } else {
+                    (FileType::File.into(), meta.len())
+                }
+            };
+            let modified_time = modified_secs(&meta);
+            dir.entries.push(FileEntry {
+                name: get_file_name(&p),
+                entry_type,
+                is_hidden,
+                size,
+                modified_time,
+                ..Default::default()
+            });
+        }
+        Ok(dir)
+    }
+
+    fn stat(&self, path: &Path) -> ResultType<FileEntry> {
+        let meta = std::fs::metadata(path)?;
+        let (entry_type, size) = if meta.is_dir() {
+            (FileType::Dir, 0)
+        } else {
+            (FileType::File, meta.len())
+        };
+        Ok(FileEntry {
+            name: get_file_name(path),
+            entry_type: entry_type.into(),
+            size,
+            modified_time: modified_secs(&meta),
+            ..Default::default()
+        })
+    }
+
+    fn open_read(&self, path: &Path) -> ResultType<std::fs::File> {
+        Ok(std::fs::File::open(path)?)
+    }
+
+    fn open_write(&self, path: &Path) -> ResultType<std::fs::File> {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        Ok(no_follow(&mut options).open(path)?)
+    }
+
+    fn open_update(&self, path: &Path) -> ResultType<std::fs::File> {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true);
+        Ok(no_follow(&mut options).open(path)?)
+    }
+
+    fn read(&self, path: &Path) -> ResultType<Vec<u8>> {
+        Ok(std::fs::read(path)?)
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> ResultType<()> {
+        use std::io::Write;
+        Ok(self.open_write(path)?.write_all(data)?)
+    }
+
+    fn remove(&self, path: &Path) -> ResultType<()> {
+        Ok(std::fs::remove_file(path)?)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> ResultType<()> {
+        Ok(std::fs::rename(from, to)?)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> ResultType<()> {
+        Ok(std::fs::create_dir_all(path)?)
+    }
+
+    fn remove_dir(&self, path: &Path) -> ResultType<()> {
+        Ok(std::fs::remove_dir(path)?)
+    }
+
+    fn is_link(&self, path: &Path) -> bool {
+        std::fs::symlink_metadata(path).map_or(false, |m| m.file_type().is_symlink())
+    }
+
+    fn read_link(&self, path: &Path) -> ResultType<PathBuf> {
+        Ok(std::fs::read_link(path)?)
+    }
+
+    fn symlink(&self, target: &Path, path: &Path, dir: bool) -> ResultType<()> {
+        #[cfg(unix)]
+        {
+            let _ = dir;
+            Ok(std::os::unix::fs::symlink(target, path)?)
+        }
+        #[cfg(windows)]
+        {
+            if dir {
+                Ok(std::os::windows::fs::symlink_dir(target, path)?)
+            } else {
+                Ok(std::os::windows::fs::symlink_file(target, path)?)
+            }
+        }
+        #[cfg(not(any(unix, windows)))]
+        {
+            let _ = (target, path, dir);
+            crate::bail!("Links are not supported")
+        }
+    }
+
+    fn canonicalize(&self, path: &Path) -> ResultType<PathBuf> {
+        Ok(std::fs::canonicalize(path)?)
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref PROVIDER: RwLock<Arc<dyn FsProvider>> = RwLock::new(Arc::new(NativeFs));
+}
+
+#[inline]
+pub fn provider() -> Arc<dyn FsProvider> {
+    PROVIDER.read().unwrap().clone()
+}
+
+/// Replace the provider of all later operations, jobs already running keep
+/// their open files.
+pub fn set_provider(provider: Arc<dyn FsProvider>) {
+    *PROVIDER.write().unwrap() = provider;
+}
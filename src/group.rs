@@ -0,0 +1,185 @@
+// Access control from the cached groups of the api-server, see `config::Group`,
+// so the decision whether a user may do something on a peer is made locally,
+// e.g. before a connection, and offline.
+//
+// A user has a `Role`: an admin may do everything, an operator the actions
+// allowed on the device group of the peer, a viewer only view them, and both
+// the actions granted to them in `GroupUser::permissions`, still within those
+// allowed on the device group. A device group without `features` allows all, a
+// peer of a device group not in the cache, e.g. created since, nothing but to
+// an admin.
+use crate::config::{DeviceGroup, Group, GroupPeer, GroupUser};
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Admin,
+    Operator,
+    /// Also of an unknown role.
+    Viewer,
+}
+
+impl Role {
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "admin" => Role::Admin,
+            "operator" => Role::Operator,
+            _ => Role::Viewer,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    View,
+    Control,
+    FileTransfer,
+    Clipboard,
+    Audio,
+    Terminal,
+    PortForward,
+    Restart,
+    /// Changes the settings of the peer, e.g. its password.
+    Manage,
+}
+
+impl Action {
+    /// The name in `DeviceGroup::features` and `GroupUser::permissions`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::View => "view",
+            Action::Control => "control",
+            Action::FileTransfer => "file-transfer",
+            Action::Clipboard => "clipboard",
+            Action::Audio => "audio",
+            Action::Terminal => "terminal",
+            Action::PortForward => "port-forward",
+            Action::Restart => "restart",
+            Action::Manage => "manage",
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    // (generation, groups), the generation incremented by every `invalidate`
+    static ref CACHE: RwLock<(u64, Option<Arc<Group>>)> = Default::default();
+}
+
+impl GroupUser {
+    #[inline]
+    pub fn role(&self) -> Role {
+        Role::parse(&self.role)
+    }
+}
+
+impl DeviceGroup {
+    fn allows(&self, action: Action) -> bool {
+        self.features.is_empty() || self.features.iter().any(|x| x == action.as_str())
+    }
+}
+
+impl Group {
+    /// Whether `user` may do `action` on `peer`, false if either is unknown.
+    pub fn can(&self, user: &str, peer: &str, action: Action) -> bool {
+        let (Some(user), Some(peer)) = (
+            self.users.iter().find(|u| u.name == user),
+            self.peers.iter().find(|p| p.id == peer),
+        ) else {
+            return false;
+        };
+        self.user_can(user, peer, action)
+    }
+
+    fn user_can(&self, user: &GroupUser, peer: &GroupPeer, action: Action) -> bool {
+        let role = user.role();
+        if role == Role::Admin {
+            return true;
+        }
+        let allowed = peer.device_group_name.is_empty()
+            || self
+                .device_groups
+                .iter()
+                .find(|g| g.name == peer.device_group_name)
+                .map_or(false, |g| g.allows(action));
+        if !allowed {
+            return false;
+        }
+        let by_role = match role {
+            Role::Operator => action != Action::Manage,
+            _ => action == Action::View,
+        };
+        by_role || user.permissions.iter().any(|x| x == action.as_str())
+    }
+}
+
+/// `Group::can` with the cached groups.
+pub fn can(user: &str, peer: &str, action: Action) -> bool {
+    let (generation, cached) = CACHE.read().unwrap().clone();
+    let group = match cached {
+        Some(group) => group,
+        None => {
+            // loaded without the lock, not cached if invalidated meanwhile, it may
+            // have been loaded before the change
+            let group = Arc::new(Group::load());
+            let mut lock = CACHE.write().unwrap();
+            if lock.0 == generation {
+                lock.1 = Some(group.clone());
+            }
+            group
+        }
+    };
+    group.can(user, peer, action)
+}
+
+/// Called when the cached groups change.
+pub(crate) fn invalidate() {
+    let mut lock = CACHE.write().unwrap();
+    lock.0 += 1;
+    lock.1 = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_can() {
+        let user = |name: &str, role: &str, permissions: &[&str]| GroupUser {
+            name: name.to_owned(),
+            role: role.to_owned(),
+            permissions: permissions.iter().map(|x| x.to_string()).collect(),
+        };
+        let peer = |id: &str, device_group_name: &str| GroupPeer {
+            id: id.to_owned(),
+            device_group_name: device_group_name.to_owned(),
+            ..Default::default()
+        };
+        let group = Group {
+            users: vec![
+                user("root", "admin", &[]),
+                user("op", "operator", &[]),
+                user("guest", "", &["file-transfer"]),
+            ],
+            peers: vec![peer("1", "servers"), peer("2", ""), peer("4", "new")],
+            device_groups: vec![DeviceGroup {
+                name: "servers".to_owned(),
+                features: vec!["view".to_owned(), "control".to_owned()],
+            }],
+            ..Default::default()
+        };
+        assert!(group.can("root", "1", Action::Terminal));
+        assert!(group.can("op", "1", Action::Control));
+        assert!(!group.can("op", "1", Action::FileTransfer));
+        assert!(group.can("op", "2", Action::FileTransfer));
+        assert!(!group.can("op", "2", Action::Manage));
+        assert!(group.can("guest", "1", Action::View));
+        assert!(!group.can("guest", "1", Action::Control));
+        assert!(!group.can("guest", "1", Action::FileTransfer));
+        assert!(group.can("guest", "2", Action::FileTransfer));
+        assert!(!group.can("nobody", "2", Action::View));
+        assert!(!group.can("root", "3", Action::View));
+        // of a device group not known yet
+        assert!(!group.can("op", "4", Action::View));
+        assert!(group.can("root", "4", Action::Control));
+    }
+}
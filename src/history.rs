@@ -0,0 +1,256 @@
+// The recent sessions, kept in `<APP_NAME>_history.toml` apart from the
+// `PeerConfig`s, so the "recent" tab keeps the sessions of a deleted peer and can
+// be sorted by the real usage of the peers, the time spent with them, instead of
+// when their configs were last written.
+//
+// A session is added once it ends, then the oldest are pruned by the local
+// options `history-max-entries` and `history-max-days`.
+use crate::config::{keys, load_path, store_path, Config, LocalConfig, APP_NAME};
+use serde_derive::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf, sync::Mutex};
+
+const DEFAULT_MAX_ENTRIES: usize = 1000;
+const DEFAULT_MAX_DAYS: i64 = 365;
+const DAY_MS: i64 = 24 * 3600 * 1000;
+
+lazy_static::lazy_static! {
+    // against lost updates of concurrent sessions ending
+    static ref LOCK: Mutex<()> = Default::default();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Outgoing,
+    Incoming,
+}
+
+impl Default for Direction {
+    fn default() -> Self {
+        Self::Outgoing
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Session {
+    pub peer_id: String,
+    /// ms
+    pub start: i64,
+    pub end: i64,
+    pub direction: Direction,
+    /// e.g. "direct", "relay" or "websocket"
+    pub transport: String,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl Session {
+    #[inline]
+    pub fn duration_ms(&self) -> i64 {
+        (self.end - self.start).max(0)
+    }
+}
+
+/// 0 is no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pruning {
+    pub max_entries: usize,
+    pub max_days: i64,
+}
+
+impl Pruning {
+    pub fn from_options() -> Self {
+        let get = |k: &str| LocalConfig::get_option(k).trim().parse::<i64>().ok();
+        Self {
+            max_entries: get(keys::OPTION_HISTORY_MAX_ENTRIES)
+                .map_or(DEFAULT_MAX_ENTRIES, |x| x.max(0) as _),
+            max_days: get(keys::OPTION_HISTORY_MAX_DAYS).map_or(DEFAULT_MAX_DAYS, |x| x.max(0)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    /// The last session first.
+    Recent,
+    /// The most time spent first.
+    Duration,
+    /// The most sessions first.
+    Count,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PeerUsage {
+    pub peer_id: String,
+    pub sessions: usize,
+    pub total_ms: i64,
+    /// The end of the last session.
+    pub last: i64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct History {
+    /// In the order they ended.
+    #[serde(default)]
+    pub sessions: Vec<Session>,
+}
+
+impl History {
+    fn path() -> PathBuf {
+        let filename = format!("{}_history.toml", APP_NAME.read().unwrap().clone());
+        Config::path(filename)
+    }
+
+    pub fn load() -> Self {
+        load_path(Self::path())
+    }
+
+    fn store(&self) {
+        if let Err(err) = store_path(Self::path(), self) {
+            log::error!("Failed to store history: {}", err);
+        }
+    }
+
+    fn modify(f: impl FnOnce(&mut History)) {
+        let _lock = LOCK.lock().unwrap();
+        let mut history = Self::load();
+        f(&mut history);
+        history.store();
+    }
+
+    /// Records an ended session.
+    pub fn add(session: Session) {
+        Self::modify(|history| {
+            history.sessions.push(session);
+            history.prune(Pruning::from_options(), crate::get_time());
+        });
+    }
+
+    /// Removes the sessions of a peer, on request of the user, not when the peer
+    /// is deleted.
+    pub fn remove_peer(peer_id: &str) {
+        Self::modify(|history| history.sessions.retain(|s| s.peer_id != peer_id));
+    }
+
+    pub fn clear() {
+        Self::modify(|history| history.sessions.clear());
+    }
+
+    /// Returns the number of sessions removed.
+    pub fn prune(&mut self, pruning: Pruning, now: i64) -> usize {
+        let len = self.sessions.len();
+        if pruning.max_days > 0 {
+            let oldest = now - pruning.max_days * DAY_MS;
+            self.sessions.retain(|s| s.end >= oldest);
+        }
+        if pruning.max_entries > 0 && self.sessions.len() > pruning.max_entries {
+            let n = self.sessions.len() - pruning.max_entries;
+            self.sessions.drain(..n);
+        }
+        len - self.sessions.len()
+    }
+
+    /// The last `limit` sessions, the last first.
+    pub fn recent(&self, limit: usize) -> Vec<&Session> {
+        let mut res: Vec<&Session> = self.sessions.iter().collect();
+        res.sort_by(|a, b| b.end.cmp(&a.end));
+        res.truncate(limit);
+        res
+    }
+
+    /// The sessions of a peer, the last first.
+    pub fn of_peer(&self, peer_id: &str) -> Vec<&Session> {
+        let mut res: Vec<&Session> = self
+            .sessions
+            .iter()
+            .filter(|s| s.peer_id == peer_id)
+            .collect();
+        res.sort_by(|a, b| b.end.cmp(&a.end));
+        res
+    }
+
+    /// The usage of every peer in the history, for the "recent" tab.
+    pub fn peers(&self, sort: SortBy) -> Vec<PeerUsage> {
+        let mut map: HashMap<&str, PeerUsage> = HashMap::new();
+        for s in self.sessions.iter() {
+            let usage = map.entry(&s.peer_id).or_insert_with(|| PeerUsage {
+                peer_id: s.peer_id.clone(),
+                ..Default::default()
+            });
+            usage.sessions += 1;
+            usage.total_ms += s.duration_ms();
+            usage.last = usage.last.max(s.end);
+            usage.bytes += s.bytes_sent + s.bytes_received;
+        }
+        let mut res: Vec<PeerUsage> = map.into_values().collect();
+        match sort {
+            SortBy::Recent => res.sort_by(|a, b| b.last.cmp(&a.last)),
+            SortBy::Duration => res.sort_by(|a, b| b.total_ms.cmp(&a.total_ms)),
+            SortBy::Count => res.sort_by(|a, b| b.sessions.cmp(&a.sessions)),
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    fn session(peer_id: &str, start: i64, end: i64) -> Session {
+        Session {
+            peer_id: peer_id.to_owned(),
+            start,
+            end,
+            transport: "relay".to_owned(),
+            bytes_sent: 10,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        let _t = test_config();
+        let now = crate::get_time();
+        History::add(session("1", now - 5000, now - 4000));
+        History::add(session("2", now - 3000, now - 1000));
+        History::add(session("1", now - 900, now - 800));
+        let history = History::load();
+        assert_eq!(history.sessions.len(), 3);
+        assert_eq!(history.recent(2)[0].end, now - 800);
+        assert_eq!(history.of_peer("1").len(), 2);
+        let ids =
+            |sort| -> Vec<String> { history.peers(sort).into_iter().map(|u| u.peer_id).collect() };
+        assert_eq!(ids(SortBy::Recent), vec!["1", "2"]);
+        assert_eq!(ids(SortBy::Duration), vec!["2", "1"]);
+        assert_eq!(ids(SortBy::Count), vec!["1", "2"]);
+        History::remove_peer("1");
+        assert_eq!(History::load().sessions.len(), 1);
+        History::clear();
+        assert!(History::load().sessions.is_empty());
+
+        let mut history = History {
+            sessions: vec![
+                session("1", 0, DAY_MS),
+                session("2", 0, 5 * DAY_MS),
+                session("3", 0, 6 * DAY_MS),
+                session("4", 0, 7 * DAY_MS),
+            ],
+        };
+        let pruning = Pruning {
+            max_entries: 2,
+            max_days: 3,
+        };
+        assert_eq!(history.prune(pruning, 7 * DAY_MS), 2);
+        assert_eq!(history.sessions[0].peer_id, "3");
+        assert_eq!(
+            Pruning::from_options(),
+            Pruning {
+                max_entries: DEFAULT_MAX_ENTRIES,
+                max_days: DEFAULT_MAX_DAYS
+            }
+        );
+    }
+}
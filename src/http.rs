@@ -0,0 +1,346 @@
+// A minimal HTTP/1.1 client, for the few requests this crate makes itself, e.g.
+// the telemetry and the signed server list, without pulling in a full client.
+//
+// One request per connection ("Connection: close"), the response is read to
+// the end and a chunked body is decoded. `request` keeps the body in memory, at
+// most MAX_RESPONSE bytes, `request_to` streams it to a writer, e.g. a file, for
+// the larger ones, see `api::get`. The connection goes through the proxy of
+// `Config::get_socks_for` if any, like the other connections. No redirect, no
+// compression.
+use crate::{
+    bail,
+    config::Config,
+    proxy::Proxy,
+    tcp::{DynTcpStream, FramedStream},
+    ResultType,
+};
+use std::io::Write;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const MAX_RESPONSE: usize = 8 * 1024 * 1024;
+const MAX_HEAD: usize = 64 * 1024;
+const DEFAULT_TIMEOUT: u64 = 10_000;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// The first header of `name`, case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    #[inline]
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+pub async fn get(url: &str, headers: &[(&str, &str)]) -> ResultType<Response> {
+    request("GET", url, headers, &[], DEFAULT_TIMEOUT).await
+}
+
+pub async fn post_json(url: &str, body: &[u8]) -> ResultType<Response> {
+    let headers = [("Content-Type", "application/json")];
+    request("POST", url, &headers, body, DEFAULT_TIMEOUT).await
+}
+
+/// `timeout` in ms, for the whole request.
+pub async fn request(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    timeout: u64,
+) -> ResultType<Response> {
+    let mut buf = Vec::new();
+    let mut res = request_to(
+        method,
+        url,
+        headers,
+        body,
+        timeout,
+        &mut buf,
+        MAX_RESPONSE as _,
+    )
+    .await?;
+    res.body = buf;
+    Ok(res)
+}
+
+/// `request` with the body of the response written to `sink`, at most `max`
+/// bytes, instead of `Response::body`.
+pub async fn request_to<W: Write>(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    timeout: u64,
+    sink: &mut W,
+    max: u64,
+) -> ResultType<Response> {
+    let url = url::Url::parse(url)?;
+    let https = match url.scheme() {
+        "https" => true,
+        "http" => false,
+        scheme => bail!("Unsupported scheme {}", scheme),
+    };
+    let Some(host) = url.host_str() else {
+        bail!("No host in {}", url);
+    };
+    let port = url.port_or_known_default().unwrap_or(80);
+    let mut path = url.path().to_owned();
+    if let Some(query) = url.query() {
+        path.push('?');
+        path.push_str(query);
+    }
+    let mut request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", method, path, host);
+    for (k, v) in headers {
+        if format!("{}{}", k, v).contains(|c| c == '\r' || c == '\n') {
+            bail!("Invalid header {}", k);
+        }
+        request.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    if !body.is_empty() || method != "GET" {
+        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    request.push_str("Connection: close\r\n\r\n");
+    crate::timeout(timeout, async {
+        let stream = connect(host, port, timeout).await?;
+        if https {
+            let stream = tls_connect(host, stream).await?;
+            exchange(stream, request.as_bytes(), body, sink, max).await
+        } else {
+            exchange(stream, request.as_bytes(), body, sink, max).await
+        }
+    })
+    .await?
+}
+
+/// Directly or through the configured proxy.
+async fn connect(host: &str, port: u16, timeout: u64) -> ResultType<DynTcpStream> {
+    let target = format!("{}:{}", host, port);
+    let Some(conf) = Config::get_socks_for(&target) else {
+        let ip = host.trim_start_matches('[').trim_end_matches(']');
+        let stream = tokio::net::TcpStream::connect((ip, port)).await?;
+        return Ok(DynTcpStream(Box::new(stream)));
+    };
+    let conf = conf.resolve_credentials().await?;
+    let proxy = Proxy::from_conf(&conf, Some(timeout))?;
+    let FramedStream(framed, ..) = proxy.connect(target.as_str(), None).await?;
+    Ok(framed.into_inner())
+}
+
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+async fn tls_connect(
+    host: &str,
+    stream: DynTcpStream,
+) -> ResultType<tokio_native_tls::TlsStream<DynTcpStream>> {
+    let connector =
+        tokio_native_tls::TlsConnector::from(tokio_native_tls::native_tls::TlsConnector::new()?);
+    Ok(connector.connect(host, stream).await?)
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+async fn tls_connect(
+    host: &str,
+    stream: DynTcpStream,
+) -> ResultType<tokio_rustls::client::TlsStream<DynTcpStream>> {
+    use std::convert::TryFrom;
+    let config = rustls_platform_verifier::tls_config();
+    let domain = rustls_pki_types::ServerName::try_from(host)?.to_owned();
+    let connector = tokio_rustls::TlsConnector::from(std::sync::Arc::new(config));
+    Ok(connector.connect(domain, stream).await?)
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin, W: Write>(
+    mut stream: S,
+    request: &[u8],
+    body: &[u8],
+    sink: &mut W,
+    max: u64,
+) -> ResultType<Response> {
+    stream.write_all(request).await?;
+    stream.write_all(body).await?;
+    stream.flush().await?;
+    read_response(stream, sink, max).await
+}
+
+/// The head of the response, and its body to `sink`, decoded if chunked.
+async fn read_response<R: AsyncRead + Unpin, W: Write>(
+    stream: R,
+    sink: &mut W,
+    max: u64,
+) -> ResultType<Response> {
+    let mut reader = tokio::io::BufReader::new(stream);
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if reader.read_until(b'\n', &mut head).await? == 0 {
+            bail!("Connection closed before the response");
+        }
+        if head.len() > MAX_HEAD {
+            bail!("Response head larger than {} bytes", MAX_HEAD);
+        }
+    }
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut headers);
+    let httparse::Status::Complete(_) = response.parse(&head)? else {
+        bail!("Incomplete response head");
+    };
+    let res = Response {
+        status: response.code.unwrap_or(0),
+        headers: response
+            .headers
+            .iter()
+            .map(|h| {
+                (
+                    h.name.to_owned(),
+                    String::from_utf8_lossy(h.value).into_owned(),
+                )
+            })
+            .collect(),
+        body: vec![],
+    };
+    let mut body = Body {
+        sink,
+        written: 0,
+        max,
+    };
+    let chunked = res
+        .header("Transfer-Encoding")
+        .map_or(false, |x| x.to_lowercase().contains("chunked"));
+    if res.status == 204 || res.status == 304 {
+        // no body
+    } else if chunked {
+        loop {
+            let mut line = Vec::new();
+            reader.read_until(b'\n', &mut line).await?;
+            if !line.ends_with(b"\r\n") {
+                bail!("Truncated chunked body");
+            }
+            let line = std::str::from_utf8(&line)?;
+            // without extensions
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = u64::from_str_radix(size, 16)?;
+            if size == 0 {
+                break;
+            }
+            let mut crlf = [0u8; 2];
+            if body.copy(&mut reader, Some(size)).await? < size
+                || reader.read_exact(&mut crlf).await.is_err()
+            {
+                bail!("Truncated chunked body");
+            }
+        }
+    } else if let Some(len) = res.header("Content-Length") {
+        let len: u64 = len.trim().parse()?;
+        if body.copy(&mut reader, Some(len)).await? < len {
+            bail!("Connection closed before the end of the body");
+        }
+    } else {
+        body.copy(&mut reader, None).await?;
+    }
+    Ok(res)
+}
+
+struct Body<'a, W: Write> {
+    sink: &'a mut W,
+    written: u64,
+    max: u64,
+}
+
+impl<W: Write> Body<'_, W> {
+    /// Copies `len` bytes, to the end if None, returns the bytes copied.
+    async fn copy<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+        len: Option<u64>,
+    ) -> ResultType<u64> {
+        let mut chunk = [0u8; 8192];
+        let mut copied = 0;
+        loop {
+            let want = len.map_or(chunk.len() as u64, |len| {
+                (len - copied).min(chunk.len() as u64)
+            }) as usize;
+            if want == 0 {
+                return Ok(copied);
+            }
+            let n = match reader.read(&mut chunk[..want]).await {
+                Ok(n) => n,
+                // servers closing without close_notify
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => 0,
+                Err(err) => return Err(err.into()),
+            };
+            if n == 0 {
+                return Ok(copied);
+            }
+            self.written += n as u64;
+            if self.written > self.max {
+                bail!("Response larger than {} bytes", self.max);
+            }
+            self.sink.write_all(&chunk[..n])?;
+            copied += n as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse_response(buf: &[u8]) -> ResultType<Response> {
+        let mut body = vec![];
+        let mut res = read_response(buf, &mut body, MAX_RESPONSE as _).await?;
+        res.body = body;
+        Ok(res)
+    }
+
+    #[tokio::test]
+    async fn test_parse_response() {
+        let res = parse_response(
+            b"HTTP/1.1 200 OK\r\nETag: \"1\"\r\nContent-Length: 5\r\n\r\nhello, trailing",
+        )
+        .await
+        .unwrap();
+        assert!(res.is_success());
+        assert_eq!(res.header("etag"), Some("\"1\""));
+        assert_eq!(res.body, b"hello");
+        let res = parse_response(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n7;x=y\r\n, world\r\n0\r\n\r\n",
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.body, b"hello, world");
+        let res = parse_response(b"HTTP/1.1 304 Not Modified\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(res.status, 304);
+        assert!(res.body.is_empty());
+        assert!(
+            parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhe")
+                .await
+                .is_err()
+        );
+        assert!(
+            parse_response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel")
+                .await
+                .is_err()
+        );
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n").await.is_err());
+        let mut body = vec![];
+        let res = read_response(
+            &b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello"[..],
+            &mut body,
+            4,
+        )
+        .await;
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,304 @@
+// Write-ahead journal of transfer jobs, so that a crash mid-transfer can be
+// resumed from the last durable chunk or rolled back.
+//
+// One append-only file of json lines per job under `Config::path("journal")`.
+// A `Chunk` entry is only written after the data up to its offset has been
+// synced, and the entry itself is synced before returning. A torn last line is
+// ignored on recovery. The journal of a running job is written with tokio::fs,
+// `recover` is meant for startup and blocking.
+use crate::{
+    config::{Config, TransferJobRecord},
+    fs::{get_path, get_string, TransferJob, RESUME_CHUNK_SIZE},
+    ResultType,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader},
+    path::PathBuf,
+};
+use tokio::io::AsyncWriteExt;
+
+const DIR: &str = "journal";
+const EXT: &str = "journal";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Entry {
+    Begin {
+        peer: String,
+        is_write: bool,
+        record: TransferJobRecord,
+    },
+    // data of `file_num` up to `offset` is on disk
+    Chunk {
+        file_num: i32,
+        offset: u64,
+    },
+    FileDone {
+        file_num: i32,
+    },
+    End,
+}
+
+fn dir() -> PathBuf {
+    Config::path(DIR)
+}
+
+fn file_name(peer: &str, id: i32) -> String {
+    let peer: String = peer
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{}-{}.{}", peer, id, EXT)
+}
+
+pub struct Journal {
+    file: tokio::fs::File,
+    path: PathBuf,
+    file_num: i32,
+    // last journaled chunk of `file_num`
+    chunk: u64,
+}
+
+impl Journal {
+    /// Start the journal of `job`, replacing any previous one of the same job.
+    pub async fn begin(peer: &str, job: &TransferJob, is_write: bool) -> ResultType<Self> {
+        tokio::fs::create_dir_all(dir()).await?;
+        let path = dir().join(file_name(peer, job.id()));
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)
+            .await?;
+        let mut journal = Self {
+            file,
+            path,
+            file_num: job.file_num(),
+            chunk: 0,
+        };
+        journal
+            .append(&Entry::Begin {
+                peer: peer.to_owned(),
+                is_write,
+                record: job.checkpoint().await,
+            })
+            .await?;
+        Ok(journal)
+    }
+
+    async fn append(&mut self, entry: &Entry) -> ResultType<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes()).await?;
+        self.file.sync_data().await?;
+        Ok(())
+    }
+
+    /// Call after each block, an entry is only written when a new
+    /// `RESUME_CHUNK_SIZE` boundary is crossed, after syncing the data.
+    pub async fn on_progress(&mut self, job: &mut TransferJob) -> ResultType<()> {
+        let Some((file_num, offset)) = job.position().await? else {
+            return Ok(());
+        };
+        let chunk = offset / RESUME_CHUNK_SIZE as u64;
+        if file_num != self.file_num {
+            if file_num > self.file_num {
+                for n in self.file_num..file_num {
+                    self.append(&Entry::FileDone { file_num: n }).await?;
+                }
+            }
+            self.file_num = file_num;
+            self.chunk = 0;
+        }
+        if chunk > self.chunk {
+            job.sync_data().await?;
+            self.append(&Entry::Chunk { file_num, offset }).await?;
+            self.chunk = chunk;
+        }
+        Ok(())
+    }
+
+    /// The job completed, the journal is removed.
+    pub async fn finish(mut self) -> ResultType<()> {
+        self.append(&Entry::End).await?;
+        tokio::fs::remove_file(&self.path).await?;
+        Ok(())
+    }
+}
+
+/// An unfinished job found on startup.
+#[derive(Debug, Clone)]
+pub struct Recovered {
+    pub peer: String,
+    pub is_write: bool,
+    /// Offsets are those known to be durable.
+    pub record: TransferJobRecord,
+    path: PathBuf,
+}
+
+fn replay(path: &PathBuf) -> ResultType<Option<Recovered>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut recovered: Option<Recovered> = None;
+    for line in reader.lines() {
+        let Ok(entry) = serde_json::from_str::<Entry>(&line?) else {
+            // torn write
+            break;
+        };
+        match entry {
+            Entry::Begin {
+                peer,
+                is_write,
+                record,
+            } => {
+                recovered = Some(Recovered {
+                    peer,
+                    is_write,
+                    record,
+                    path: path.clone(),
+                });
+            }
+            Entry::Chunk { file_num, offset } => {
+                if let Some(r) = recovered.as_mut() {
+                    r.record.file_num = file_num;
+                    if let Some(f) = r.record.files.get_mut(file_num as usize) {
+                        f.offset = offset;
+                    }
+                }
+            }
+            Entry::FileDone { file_num } => {
+                if let Some(r) = recovered.as_mut() {
+                    if let Some(f) = r.record.files.get_mut(file_num as usize) {
+                        f.offset = f.size;
+                    }
+                    r.record.file_num = file_num + 1;
+                }
+            }
+            Entry::End => return Ok(None),
+        }
+    }
+    Ok(recovered)
+}
+
+/// Unfinished jobs of all peers, completed journals are cleaned up. The
+/// not synced tail of the files being received is cut off.
+pub fn recover() -> Vec<Recovered> {
+    let Ok(entries) = std::fs::read_dir(dir()) else {
+        return Vec::new();
+    };
+    let mut res = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().map_or(true, |x| x != EXT) {
+            continue;
+        }
+        match replay(&path) {
+            Ok(Some(r)) => {
+                if r.is_write {
+                    truncate_partial(&r.record);
+                }
+                res.push(r);
+            }
+            Ok(None) => {
+                std::fs::remove_file(&path).ok();
+            }
+            Err(err) => {
+                log::error!("Failed to read transfer journal {:?}: {}", path, err);
+            }
+        }
+    }
+    res
+}
+
+fn partial_path(record: &TransferJobRecord) -> Option<String> {
+    let file = record.files.get(record.file_num.max(0) as usize)?;
+    let path = TransferJob::join(&get_path(&record.to), &file.name);
+    Some(format!("{}.download", get_string(&path)))
+}
+
+fn truncate_partial(record: &TransferJobRecord) {
+    let Some(path) = partial_path(record) else {
+        return;
+    };
+    let offset = record.files[record.file_num.max(0) as usize].offset;
+    if let Ok(f) = OpenOptions::new().write(true).open(&path) {
+        if f.metadata().map_or(false, |m| m.len() > offset) {
+            f.set_len(offset).ok();
+        }
+    }
+}
+
+impl Recovered {
+    /// Give up the job, the partial file is removed with the journal.
+    pub fn rollback(self) {
+        if self.is_write {
+            if let Some(path) = partial_path(&self.record) {
+                std::fs::remove_file(&path).ok();
+                if let Some(base) = path.strip_suffix(".download") {
+                    std::fs::remove_file(format!("{}.digest", base)).ok();
+                }
+            }
+        }
+        std::fs::remove_file(&self.path).ok();
+    }
+
+    /// The job is resumed, a new journal is started by `Journal::begin`.
+    pub async fn discard(self) {
+        tokio::fs::remove_file(&self.path).await.ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TransferFileRecord;
+
+    #[test]
+    fn test_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hbb_journal.journal");
+        let record = TransferJobRecord {
+            id: 1,
+            files: vec![
+                TransferFileRecord {
+                    name: "a".to_owned(),
+                    size: 10,
+                    ..Default::default()
+                },
+                TransferFileRecord {
+                    name: "b".to_owned(),
+                    size: 1 << 30,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let mut data = String::new();
+        for entry in [
+            Entry::Begin {
+                peer: "123".to_owned(),
+                is_write: true,
+                record,
+            },
+            Entry::FileDone { file_num: 0 },
+            Entry::Chunk {
+                file_num: 1,
+                offset: 1 << 20,
+            },
+        ] {
+            data += &serde_json::to_string(&entry).unwrap();
+            data.push('\n');
+        }
+        data += "{\"op\":\"chunk\",\"file_n";
+        std::fs::write(&path, &data).unwrap();
+        let r = replay(&path).unwrap().unwrap();
+        assert_eq!(r.record.file_num, 1);
+        assert_eq!(r.record.files[0].offset, 10);
+        assert_eq!(r.record.files[1].offset, 1 << 20);
+        data = data[..data.rfind('\n').unwrap() + 1].to_owned();
+        data += &serde_json::to_string(&Entry::End).unwrap();
+        std::fs::write(&path, &data).unwrap();
+        assert!(replay(&path).unwrap().is_none());
+    }
+}
@@ -0,0 +1,171 @@
+// The key encrypting the secrets of the config on Android, in place of
+// `crate::get_uuid`, see `password_security`.
+//
+// There is no machine uid on Android, so the key falls back to the public key,
+// stored next to the config, and a copy of the files of a rooted device is
+// enough to decrypt the passwords. Once the app registers a keystore, a random
+// key is used instead, kept in KEY_FILE wrapped by a key of the Android Keystore
+// that never leaves the secure hardware. The app implements `Keystore` with JNI
+// calls, or registers two callbacks with `register_callbacks`, before the
+// config is first loaded.
+//
+// Secrets encrypted with the previous key are still decrypted with
+// `fallback_key`, and are encrypted with the new one when stored again.
+use crate::{bail, config::Config, ResultType};
+use sodiumoxide::randombytes::randombytes;
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+};
+use zeroize::Zeroizing;
+
+const KEY_FILE: &str = "config.key";
+const KEY_LEN: usize = 32;
+
+pub trait Keystore: Send + Sync {
+    /// Encrypts `key` with the key held by the keystore, e.g. AES-GCM with a key
+    /// of AndroidKeyStore.
+    fn wrap(&self, key: &[u8]) -> ResultType<Vec<u8>>;
+    fn unwrap(&self, wrapped: &[u8]) -> ResultType<Vec<u8>>;
+}
+
+type Callback = Box<dyn Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+struct Callbacks {
+    wrap: Callback,
+    unwrap: Callback,
+}
+
+impl Keystore for Callbacks {
+    fn wrap(&self, key: &[u8]) -> ResultType<Vec<u8>> {
+        let Some(wrapped) = (self.wrap)(key) else {
+            bail!("Failed to wrap the key with the keystore");
+        };
+        Ok(wrapped)
+    }
+
+    fn unwrap(&self, wrapped: &[u8]) -> ResultType<Vec<u8>> {
+        let Some(key) = (self.unwrap)(wrapped) else {
+            bail!("Failed to unwrap the key with the keystore");
+        };
+        Ok(key)
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref KEYSTORE: RwLock<Option<Arc<dyn Keystore>>> = Default::default();
+    static ref CONFIG_KEY: Mutex<Option<Zeroizing<Vec<u8>>>> = Default::default();
+}
+
+pub fn register(keystore: Arc<dyn Keystore>) {
+    *KEYSTORE.write().unwrap() = Some(keystore);
+    *CONFIG_KEY.lock().unwrap() = None;
+}
+
+/// `register` with the callbacks of the app, which return None on failure.
+pub fn register_callbacks<W, U>(wrap: W, unwrap: U)
+where
+    W: Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    U: Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+{
+    register(Arc::new(Callbacks {
+        wrap: Box::new(wrap),
+        unwrap: Box::new(unwrap),
+    }));
+}
+
+#[inline]
+pub fn is_registered() -> bool {
+    KEYSTORE.read().unwrap().is_some()
+}
+
+/// The key of the config, None if no keystore is registered or it fails, the
+/// public key is used then.
+pub fn config_key() -> Option<Vec<u8>> {
+    let keystore = KEYSTORE.read().unwrap().clone()?;
+    let mut lock = CONFIG_KEY.lock().unwrap();
+    if let Some(key) = lock.as_ref() {
+        return Some(key.to_vec());
+    }
+    match load_or_create(&Config::path(KEY_FILE), &*keystore) {
+        Ok(key) => {
+            *lock = Some(Zeroizing::new(key.clone()));
+            Some(key)
+        }
+        Err(err) => {
+            log::error!("Failed to get the config key from the keystore: {}", err);
+            None
+        }
+    }
+}
+
+/// The key used before the keystore was registered, None without keystore.
+pub fn fallback_key() -> Option<Vec<u8>> {
+    config_key()?;
+    Some(Config::get_key_uuid())
+}
+
+fn load_or_create(path: &Path, keystore: &dyn Keystore) -> ResultType<Vec<u8>> {
+    match fs::read(path) {
+        Ok(wrapped) => {
+            let key = keystore.unwrap(&wrapped)?;
+            if key.len() != KEY_LEN {
+                bail!("Invalid config key");
+            }
+            Ok(key)
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let key = randombytes(KEY_LEN);
+            let wrapped = keystore.wrap(&key)?;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            #[cfg(unix)]
+            {
+                use std::{io::Write, os::unix::fs::OpenOptionsExt};
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(path)?
+                    .write_all(&wrapped)?;
+            }
+            #[cfg(not(unix))]
+            {
+                fs::write(path, &wrapped)?;
+            }
+            Ok(key)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct XorKeystore(u8);
+
+    impl Keystore for XorKeystore {
+        fn wrap(&self, key: &[u8]) -> ResultType<Vec<u8>> {
+            Ok(key.iter().map(|x| x ^ self.0).collect())
+        }
+
+        fn unwrap(&self, wrapped: &[u8]) -> ResultType<Vec<u8>> {
+            self.wrap(wrapped)
+        }
+    }
+
+    #[test]
+    fn test_load_or_create() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(KEY_FILE);
+        let key = load_or_create(&path, &XorKeystore(0x5a)).unwrap();
+        assert_eq!(key.len(), KEY_LEN);
+        assert_ne!(fs::read(&path).unwrap(), key);
+        assert_eq!(load_or_create(&path, &XorKeystore(0x5a)).unwrap(), key);
+        // another keystore key can not unwrap it
+        assert_ne!(load_or_create(&path, &XorKeystore(0x33)).ok(), Some(key));
+    }
+}
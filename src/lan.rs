@@ -0,0 +1,133 @@
+use crate::{try_into_v4, AddrMangle};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// (ip, prefix length) of a local interface address.
+pub type LocalNet = (IpAddr, u8);
+
+#[cfg(not(any(target_os = "android", target_os = "ios")))]
+pub fn get_local_nets() -> Vec<LocalNet> {
+    let mut nets = vec![];
+    for iface in default_net::get_interfaces() {
+        for v4 in iface.ipv4 {
+            if !v4.addr.is_loopback() {
+                nets.push((IpAddr::V4(v4.addr), v4.prefix_len));
+            }
+        }
+        for v6 in iface.ipv6 {
+            if !v6.addr.is_loopback() {
+                nets.push((IpAddr::V6(v6.addr), v6.prefix_len));
+            }
+        }
+    }
+    nets
+}
+
+// Interface enumeration is not available in the mobile sandboxes,
+// we can only rely on the public address comparison there.
+#[cfg(any(target_os = "android", target_os = "ios"))]
+pub fn get_local_nets() -> Vec<LocalNet> {
+    vec![]
+}
+
+fn mask_v4(ip: Ipv4Addr, prefix: u8) -> u32 {
+    let prefix = prefix.min(32) as u32;
+    if prefix == 0 {
+        return 0;
+    }
+    u32::from(ip) & (u32::MAX << (32 - prefix))
+}
+
+fn mask_v6(ip: Ipv6Addr, prefix: u8) -> u128 {
+    let prefix = prefix.min(128) as u32;
+    if prefix == 0 {
+        return 0;
+    }
+    u128::from(ip) & (u128::MAX << (128 - prefix))
+}
+
+pub fn in_same_subnet(a: IpAddr, b: IpAddr, prefix: u8) -> bool {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => mask_v4(a, prefix) == mask_v4(b, prefix),
+        (IpAddr::V6(a), IpAddr::V6(b)) => mask_v6(a, prefix) == mask_v6(b, prefix),
+        _ => false,
+    }
+}
+
+/// Whether `ip` is reachable on one of our directly connected subnets.
+pub fn is_on_local_subnet(ip: IpAddr, nets: &[LocalNet]) -> bool {
+    let ip = try_into_v4(SocketAddr::new(ip, 0)).ip();
+    nets.iter()
+        .any(|(local, prefix)| *local != ip && in_same_subnet(*local, ip, *prefix))
+}
+
+/// Both peers are behind the same NAT, connecting to the public address
+/// would hairpin through the router (which many routers do not support).
+#[inline]
+pub fn is_same_public_ip(my_public: SocketAddr, peer_public: SocketAddr) -> bool {
+    try_into_v4(my_public).ip() == try_into_v4(peer_public).ip()
+}
+
+/// Pick the address to connect to from the registration data.
+/// `peer_local` is the mangled local address reported by the peer (`LocalAddr.local_addr`).
+/// The local address is only used behind the same public address, private subnets
+/// of different sites often overlap (e.g. 192.168.1.0/24 everywhere), so a match
+/// on our own subnets alone would connect to another machine.
+pub fn prefer_lan_addr(
+    my_public: Option<SocketAddr>,
+    peer_public: SocketAddr,
+    peer_local: &[u8],
+) -> SocketAddr {
+    if peer_local.is_empty() {
+        return peer_public;
+    }
+    let local = try_into_v4(AddrMangle::decode(peer_local));
+    if local.ip().is_unspecified() || local.port() == 0 {
+        return peer_public;
+    }
+    if my_public.map_or(false, |my| is_same_public_ip(my, peer_public)) {
+        log::debug!(
+            "Peer {} is on the same LAN, prefer local address {}",
+            peer_public,
+            local
+        );
+        return local;
+    }
+    peer_public
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subnet() {
+        let a: IpAddr = "192.168.1.10".parse().unwrap();
+        let b: IpAddr = "192.168.1.200".parse().unwrap();
+        let c: IpAddr = "192.168.2.1".parse().unwrap();
+        assert!(in_same_subnet(a, b, 24));
+        assert!(!in_same_subnet(a, c, 24));
+        assert!(in_same_subnet(a, c, 16));
+        assert!(in_same_subnet(a, c, 0));
+        let d: IpAddr = "fe80::1".parse().unwrap();
+        let e: IpAddr = "fe80::2".parse().unwrap();
+        assert!(in_same_subnet(d, e, 64));
+        assert!(!in_same_subnet(a, d, 0));
+        assert!(is_on_local_subnet(b, &[(a, 24)]));
+        assert!(!is_on_local_subnet(a, &[(a, 24)]));
+    }
+
+    #[test]
+    fn test_prefer_lan_addr() {
+        let my: SocketAddr = "1.2.3.4:1000".parse().unwrap();
+        let peer: SocketAddr = "1.2.3.4:2000".parse().unwrap();
+        let other: SocketAddr = "5.6.7.8:2000".parse().unwrap();
+        let local: SocketAddr = "10.0.0.5:21118".parse().unwrap();
+        let mangled = AddrMangle::encode(local);
+        assert_eq!(prefer_lan_addr(Some(my), peer, &mangled), local);
+        assert_eq!(prefer_lan_addr(Some(my), other, &mangled), other);
+        // the same private subnet at another site
+        assert_eq!(prefer_lan_addr(None, other, &mangled), other);
+        assert_eq!(prefer_lan_addr(None, peer, &mangled), peer);
+        assert_eq!(prefer_lan_addr(Some(my), peer, &[]), peer);
+    }
+}
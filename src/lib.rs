@@ -1,8 +1,5 @@
-pub mod compress;
-pub mod platform;
 pub mod protos;
 pub use bytes;
-use config::Config;
 pub use futures;
 pub use protobuf;
 pub use protos::message as message_proto;
@@ -17,56 +14,121 @@ use std::{
 };
 pub use tokio;
 pub use tokio_util;
-pub mod proxy;
-pub mod socket_client;
-pub mod tcp;
-pub mod udp;
 pub use env_logger;
 pub use log;
 pub mod bytes_codec;
 pub use anyhow::{self, bail};
 pub use futures_util;
-pub mod config;
-pub mod fs;
-pub mod mem;
 pub use lazy_static;
-#[cfg(not(any(target_os = "android", target_os = "ios")))]
-pub use mac_address;
 pub use rand;
 pub use regex;
-pub use sodiumoxide;
-pub use tokio_socks;
-pub use tokio_socks::IntoTargetAddr;
-pub use tokio_socks::TargetAddr;
-pub mod password_security;
 pub use chrono;
-pub use directories_next;
 pub use libc;
-pub mod keyboard;
 pub use base64;
-#[cfg(not(any(target_os = "android", target_os = "ios")))]
-pub use dlopen;
-#[cfg(not(any(target_os = "android", target_os = "ios")))]
-pub use machine_uid;
 pub use serde_derive;
 pub use serde_json;
 pub use sha2;
-pub use sysinfo;
 pub use thiserror;
 pub use toml;
 pub use uuid;
-pub mod fingerprint;
-pub use flexi_logger;
-pub mod websocket;
-pub mod stream;
-pub use stream::Stream;
-pub use whoami;
+pub mod settings_map;
+pub mod options;
+pub mod web_config;
+pub mod clock;
+#[cfg(any(test, feature = "netsim"))]
+pub mod netsim;
+#[cfg(target_arch = "wasm32")]
+mod wasm;
+
+// 浏览器（wasm32）中只有上面的模块，见 Cargo.toml
+cfg_if::cfg_if! {
+    if #[cfg(not(target_arch = "wasm32"))] {
+        pub mod compress;
+        pub mod platform;
+        use config::Config;
+        pub mod proxy;
+        pub mod system_proxy;
+        pub mod socket_client;
+        pub mod tcp;
+        pub mod udp;
+        pub mod error;
+        pub mod config;
+        pub mod ab;
+        pub mod group;
+        pub mod history;
+        pub mod recordings;
+        pub mod fs;
+        pub mod mem;
+        #[cfg(not(any(target_os = "android", target_os = "ios")))]
+        pub use mac_address;
+        pub use sodiumoxide;
+        pub use tokio_socks;
+        pub use tokio_socks::IntoTargetAddr;
+        pub use tokio_socks::TargetAddr;
+        pub mod password_security;
+        pub use directories_next;
+        pub mod keyboard;
+        #[cfg(not(any(target_os = "android", target_os = "ios")))]
+        pub use dlopen;
+        #[cfg(not(any(target_os = "android", target_os = "ios")))]
+        pub use machine_uid;
+        pub use sysinfo;
+        pub mod fingerprint;
+        #[cfg(not(any(target_os = "android", target_os = "ios")))]
+        pub mod machine_id;
+        pub use flexi_logger;
+        pub mod websocket;
+        pub mod stream;
+        pub mod session_resume;
+        pub mod lan;
+        pub mod wol;
+        pub mod register;
+        pub mod transport;
+        pub mod fec;
+        pub mod session_key;
+        pub mod cert;
+        pub mod rs_key;
+        pub mod ban;
+        pub mod acl;
+        pub mod security;
+        pub mod secrets;
+        pub mod keystore;
+        pub mod sandbox;
+        pub mod delta;
+        pub mod journal;
+        pub mod progress;
+        pub mod file_metadata;
+        pub mod backup;
+        pub mod transfer_policy;
+        pub mod fs_provider;
+        pub mod clipboard_file;
+        pub mod checksum_cache;
+        pub mod archive;
+        pub mod policy;
+        pub mod branding;
+        pub mod custom_config;
+        pub mod mdm;
+        pub mod trace;
+        pub mod logging;
+        pub mod diagnostics;
+        pub mod metrics;
+        pub mod telemetry;
+        pub mod http;
+        pub mod api;
+        pub mod server_list;
+        pub mod server_selector;
+        pub mod dns_config;
+        pub use stream::Stream;
+        pub use whoami;
+    }
+}
 
 pub type SessionID = uuid::Uuid;
 
+/// Of `clock::current()`.
 #[inline]
 pub async fn sleep(sec: f32) {
-    tokio::time::sleep(time::Duration::from_secs_f32(sec)).await;
+    clock::sleep(time::Duration::from_secs_f32(sec)).await;
 }
 
 #[macro_export]
@@ -133,8 +195,9 @@ impl AddrMangle {
         let addr = try_into_v4(addr);
         match addr {
             SocketAddr::V4(addr_v4) => {
-                let tm = (SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
+                // std::time::SystemTime::now() panics in the browser
+                let tm = (web_time::SystemTime::now()
+                    .duration_since(web_time::UNIX_EPOCH)
                     .unwrap_or(std::time::Duration::ZERO)
                     .as_micros() as u32) as u128;
                 let ip = u32::from_le_bytes(addr_v4.ip().octets()) as u128;
@@ -166,7 +229,10 @@ impl AddrMangle {
 
         if bytes.len() > 16 {
             if bytes.len() != 18 {
+                #[cfg(not(target_arch = "wasm32"))]
                 return Config::get_any_listen_addr(false);
+                #[cfg(target_arch = "wasm32")]
+                return SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
             }
             let tmp: [u8; 2] = bytes[16..].try_into().unwrap_or_default();
             let port = u16::from_le_bytes(tmp);
@@ -300,20 +366,19 @@ pub fn get_exe_time() -> SystemTime {
     })
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 pub fn get_uuid() -> Vec<u8> {
     #[cfg(not(any(target_os = "android", target_os = "ios")))]
     if let Ok(id) = machine_uid::get() {
         return id.into();
     }
-    Config::get_key_pair().1
+    Config::get_key_uuid()
 }
 
+/// Milliseconds since the unix epoch, of `clock::current()`.
 #[inline]
 pub fn get_time() -> i64 {
-    std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .map(|d| d.as_millis())
-        .unwrap_or(0) as _
+    clock::now_ms()
 }
 
 #[inline]
@@ -357,6 +422,7 @@ pub fn is_domain_port_str(id: &str) -> bool {
     }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 pub fn init_log(_is_async: bool, _name: &str) -> Option<flexi_logger::LoggerHandle> {
     static INIT: std::sync::Once = std::sync::Once::new();
     #[allow(unused_mut)]
@@ -365,7 +431,10 @@ pub fn init_log(_is_async: bool, _name: &str) -> Option<flexi_logger::LoggerHand
         #[cfg(debug_assertions)]
         {
             use env_logger::*;
-            init_from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, "info"));
+            Builder::from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, "info"))
+                .format(logging::env_logger_format)
+                .try_init()
+                .ok();
         }
         #[cfg(not(debug_assertions))]
         {
@@ -381,21 +450,26 @@ pub fn init_log(_is_async: bool, _name: &str) -> Option<flexi_logger::LoggerHand
             }
             use flexi_logger::*;
             if let Ok(x) = Logger::try_with_env_or_str("debug") {
+                let rotation = logging::rotation();
                 logger_holder = x
-                    .log_to_file(FileSpec::default().directory(path))
+                    .log_to_file(FileSpec::default().directory(path.clone()))
                     .write_mode(if _is_async {
                         WriteMode::Async
                     } else {
                         WriteMode::Direct
                     })
-                    .format(opt_format)
-                    .rotate(
-                        Criterion::Age(Age::Day),
-                        Naming::Timestamps,
-                        Cleanup::KeepLogFiles(31),
-                    )
+                    .format(logging::format)
+                    .rotate(rotation.criterion(), Naming::Timestamps, rotation.cleanup())
                     .start()
                     .ok();
+                if let Some(handle) = logger_holder.as_ref() {
+                    logging::set_handle(handle.clone());
+                    logging::spawn_size_guard(path);
+                    let spec = config::Config::get_option(config::keys::OPTION_LOG_LEVEL);
+                    if !spec.is_empty() {
+                        logging::set_spec(&spec).ok();
+                    }
+                }
             }
         }
     });
@@ -425,6 +499,7 @@ pub struct VersionCheckResponse {
 pub const VER_TYPE_RUSTDESK_CLIENT: &str = "rustdesk-client";
 pub const VER_TYPE_RUSTDESK_SERVER: &str = "rustdesk-server";
 
+#[cfg(not(target_arch = "wasm32"))]
 pub fn version_check_request(typ: String) -> (VersionCheckRequest, String) {
     const URL: &str = "https://api.rustdesk.com/version/latest";
 
@@ -448,8 +523,8 @@ pub fn version_check_request(typ: String) -> (VersionCheckRequest, String) {
 }
 
 pub fn time_based_rand() -> u32 {
-    let nanos = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
+    let nanos = web_time::SystemTime::now()
+        .duration_since(web_time::UNIX_EPOCH)
         .unwrap()
         .as_nanos();
 
@@ -0,0 +1,361 @@
+// Rotation of the log files `init_log` writes under `Config::log_path()`. A new
+// file is started each day or once the current one exceeds `max_file_size`,
+// rotated files are compressed and kept `keep_days`, and the oldest are removed
+// earlier when the directory exceeds `max_total_size`, so services running for
+// months do not fill the disk. Only rotated logs are removed, see `is_rotated`.
+//
+// Levels can be changed at runtime, e.g. to capture debug logs of a failing
+// connection, with `set_level` or the `OPTION_LOG_LEVEL` option.
+//
+// Configs and option maps are logged at debug and trace level, so secrets in
+// formatted messages are masked by `redact`, and values wrapped in `Redacted`
+// are never printed.
+use crate::ResultType;
+use flexi_logger::{DeferredNow, LogSpecBuilder, LogSpecification, LoggerHandle};
+use log::{LevelFilter, Record};
+use regex::Regex;
+use std::{
+    borrow::Cow,
+    fmt,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+const MB: u64 = 1024 * 1024;
+const SIZE_CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rotation {
+    pub max_file_size: u64,
+    /// rotated files kept uncompressed, the newest ones
+    pub keep_files: usize,
+    /// compressed files kept after those, a bound besides `keep_days` for
+    /// logs rotated by size many times a day
+    pub keep_compressed_files: usize,
+    /// rotated files older than this are removed
+    pub keep_days: u64,
+    pub max_total_size: u64,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self {
+            max_file_size: 20 * MB,
+            keep_files: 3,
+            keep_compressed_files: 1000,
+            keep_days: 31,
+            max_total_size: 500 * MB,
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref ROTATION: RwLock<Rotation> = Default::default();
+    static ref HANDLE: Mutex<Option<LoggerHandle>> = Default::default();
+    // what `init_log` started with, restored by an empty spec
+    static ref INITIAL_SPEC: Mutex<LogSpecification> = Mutex::new(
+        LogSpecification::env_or_parse("debug").unwrap_or_else(|_| LogSpecification::debug())
+    );
+    static ref SPEC: Mutex<Option<LogSpecification>> = Default::default();
+}
+
+/// Takes effect with the next `init_log`.
+pub fn set_rotation(rotation: Rotation) {
+    *ROTATION.write().unwrap() = rotation;
+}
+
+pub fn rotation() -> Rotation {
+    ROTATION.read().unwrap().clone()
+}
+
+impl Rotation {
+    pub(crate) fn criterion(&self) -> flexi_logger::Criterion {
+        flexi_logger::Criterion::AgeOrSize(flexi_logger::Age::Day, self.max_file_size)
+    }
+
+    pub(crate) fn cleanup(&self) -> flexi_logger::Cleanup {
+        flexi_logger::Cleanup::KeepLogAndCompressedFiles(
+            self.keep_files,
+            self.keep_compressed_files,
+        )
+    }
+}
+
+pub(crate) fn set_handle(handle: LoggerHandle) {
+    *HANDLE.lock().unwrap() = Some(handle);
+}
+
+/// Sets the level of a module, e.g. "hbb_common::fs", or the default with None.
+pub fn set_level(module: Option<&str>, level: LevelFilter) {
+    let mut spec = SPEC.lock().unwrap();
+    let current = spec
+        .clone()
+        .unwrap_or_else(|| INITIAL_SPEC.lock().unwrap().clone());
+    let mut builder = LogSpecBuilder::from_module_filters(current.module_filters());
+    match module {
+        Some(module) => builder.module(module, level),
+        None => builder.default(level),
+    };
+    let new = builder.build();
+    apply(&new);
+    *spec = Some(new);
+}
+
+/// A spec in the format of RUST_LOG, e.g. "info, hbb_common::fs = trace". Empty
+/// restores the levels `init_log` started with.
+pub fn set_spec(spec: &str) -> ResultType<()> {
+    let new = if spec.trim().is_empty() {
+        INITIAL_SPEC.lock().unwrap().clone()
+    } else {
+        LogSpecification::parse(spec)?
+    };
+    apply(&new);
+    *SPEC.lock().unwrap() = Some(new);
+    Ok(())
+}
+
+/// The current spec, in the format of `set_spec`.
+pub fn spec() -> String {
+    SPEC.lock()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| INITIAL_SPEC.lock().unwrap().clone())
+        .to_string()
+}
+
+fn apply(spec: &LogSpecification) {
+    // the records above the max level are dropped before reaching the logger
+    let max = spec
+        .module_filters()
+        .iter()
+        .map(|f| f.level_filter)
+        .max()
+        .unwrap_or(LevelFilter::Off);
+    log::set_max_level(max);
+    if let Some(handle) = HANDLE.lock().unwrap().as_ref() {
+        handle.set_new_spec(spec.clone());
+    }
+}
+
+const MASK: &str = "***";
+
+lazy_static::lazy_static! {
+    // a secret key, then `: `, `=` or `":` and the value, which may be quoted, a
+    // byte array or a tuple of them like `key_pair`
+    static ref SECRET: Regex = Regex::new(concat!(
+        r#"(?i)\b(?P<key>[\w-]*(?:password|passwd|token|secret)[\w-]*|unlock_pin[\w-]*|pin|"#,
+        r#"(?:old_)?key_pair|private_key|sk|salt|otp|authorization|api[_-]key)"#,
+        r#"(?P<sep>"?\s*(?::|=)\s*)"#,
+        r#"(?P<value>"(?:[^"\\]|\\.)*"|'[^']*'|\[[^\]]*\]|\((?:\s*\[[^\]]*\]\s*,?)+\s*\)|[^\s,;&}\)]+)"#,
+    ))
+    .unwrap();
+}
+
+/// Masks the values of password, token, pin and key fields in Debug, JSON,
+/// TOML and query string output.
+pub fn redact(message: &str) -> Cow<'_, str> {
+    SECRET.replace_all(message, |c: &regex::Captures| {
+        format!("{}{}{}", &c["key"], &c["sep"], MASK)
+    })
+}
+
+/// Debug and Display print a mask instead of the value.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct Redacted<T>(pub T);
+
+impl<T> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(MASK)
+    }
+}
+
+impl<T> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(MASK)
+    }
+}
+
+/// `flexi_logger::opt_format` with `redact`.
+pub(crate) fn format(
+    w: &mut dyn Write,
+    now: &mut DeferredNow,
+    record: &Record,
+) -> std::io::Result<()> {
+    write!(
+        w,
+        "[{}] {} [{}:{}] {}",
+        now.format("%Y-%m-%d %H:%M:%S%.6f"),
+        record.level(),
+        record.file().unwrap_or("<unnamed>"),
+        record.line().unwrap_or(0),
+        redact(&record.args().to_string())
+    )
+}
+
+/// For the env_logger of debug builds.
+pub(crate) fn env_logger_format(
+    buf: &mut env_logger::fmt::Formatter,
+    record: &Record,
+) -> std::io::Result<()> {
+    writeln!(
+        buf,
+        "[{} {} {}] {}",
+        buf.timestamp_micros(),
+        record.level(),
+        record.target(),
+        redact(&record.args().to_string())
+    )
+}
+
+lazy_static::lazy_static! {
+    // `Naming::Timestamps`, not the `_rCURRENT` file being written
+    static ref ROTATED: Regex = Regex::new(
+        r"_r\d{4}-\d{2}-\d{2}_\d{2}-\d{2}-\d{2}(\.restart-\d+)?\.log(\.gz)?$"
+    )
+    .unwrap();
+}
+
+/// Whether `name` is a log file rotated by flexi_logger, the only files the
+/// guards below remove.
+pub fn is_rotated(name: &str) -> bool {
+    ROTATED.is_match(name)
+}
+
+// The rotated logs of `dir`, the oldest first.
+fn rotated_logs(dir: &Path) -> Vec<(PathBuf, u64, SystemTime)> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files: Vec<(PathBuf, u64, SystemTime)> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| is_rotated(&e.file_name().to_string_lossy()))
+        .filter_map(|e| {
+            let meta = e.metadata().ok()?;
+            if !meta.is_file() {
+                return None;
+            }
+            Some((e.path(), meta.len(), meta.modified().ok()?))
+        })
+        .collect();
+    files.sort_by_key(|f| f.2);
+    files
+}
+
+/// Removes the oldest rotated logs of `dir` until it holds at most `max`
+/// bytes of them. Returns the bytes removed.
+pub fn enforce_total_size(dir: &Path, max: u64) -> u64 {
+    let files = rotated_logs(dir);
+    let mut total: u64 = files.iter().map(|f| f.1).sum();
+    let mut removed = 0;
+    for (path, size, _) in files {
+        if total <= max {
+            break;
+        }
+        if std::fs::remove_file(&path).is_ok() {
+            total -= size;
+            removed += size;
+        }
+    }
+    removed
+}
+
+/// Removes the rotated logs of `dir` modified more than `days` before `now`.
+/// Returns the bytes removed.
+pub fn enforce_retention(dir: &Path, days: u64, now: SystemTime) -> u64 {
+    let max_age = Duration::from_secs(days * 24 * 3600);
+    rotated_logs(dir)
+        .into_iter()
+        .filter(|f| now.duration_since(f.2).map_or(false, |x| x > max_age))
+        .filter(|f| std::fs::remove_file(&f.0).is_ok())
+        .map(|f| f.1)
+        .sum()
+}
+
+/// Checks the age and size of the logs in `dir` periodically, flexi_logger
+/// only limits the count.
+pub(crate) fn spawn_size_guard(dir: PathBuf) {
+    std::thread::spawn(move || loop {
+        let rotation = rotation();
+        let removed = enforce_retention(&dir, rotation.keep_days, SystemTime::now())
+            + enforce_total_size(&dir, rotation.max_total_size);
+        if removed > 0 {
+            log::info!("Removed {} bytes of old logs in {:?}", removed, dir);
+        }
+        std::thread::sleep(SIZE_CHECK_INTERVAL);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let cases = [
+            (
+                r#"Config { id: "123", password: "abc", salt: "x1" }"#,
+                r#"Config { id: "123", password: ***, salt: *** }"#,
+            ),
+            (
+                r#"{"os-password": "p w", "alias": "a"}"#,
+                r#"{"os-password": ***, "alias": "a"}"#,
+            ),
+            (
+                r#"{"access_token":"t.k","user":"u"}"#,
+                r#"{"access_token":***,"user":"u"}"#,
+            ),
+            ("key_pair: ([1, 2], [3, 4]), ok", "key_pair: ***, ok"),
+            ("unlock_pin = '1234'", "unlock_pin = ***"),
+            ("GET /api?token=abc&id=1", "GET /api?token=***&id=1"),
+            ("ping: 12ms, spinner: 3", "ping: 12ms, spinner: 3"),
+        ];
+        for (input, output) in cases {
+            assert_eq!(redact(input), output);
+        }
+        assert_eq!(format!("{:?}", Redacted("secret")), MASK);
+    }
+
+    #[test]
+    fn test_set_level() {
+        set_spec("info").unwrap();
+        set_level(Some("hbb_common::fs"), LevelFilter::Trace);
+        assert_eq!(log::max_level(), LevelFilter::Trace);
+        assert!(spec().contains("hbb_common::fs"));
+        set_level(Some("hbb_common::fs"), LevelFilter::Warn);
+        assert_eq!(log::max_level(), LevelFilter::Info);
+        assert!(set_spec("hbb_common=nonsense").is_err());
+        set_spec("").unwrap();
+    }
+
+    #[test]
+    fn test_enforce_total_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let name = |i: i64| format!("app_r2024-01-0{}_00-00-00.log", i + 1);
+        for i in 0..5 {
+            let path = dir.join(name(i));
+            std::fs::write(&path, vec![0u8; 100]).unwrap();
+            let t = filetime::FileTime::from_unix_time(1_000_000 + i * 24 * 3600, 0);
+            filetime::set_file_mtime(&path, t).unwrap();
+        }
+        // not rotated logs
+        std::fs::write(dir.join("app_rCURRENT.log"), vec![0u8; 100]).unwrap();
+        std::fs::write(dir.join("notes.txt"), vec![0u8; 100]).unwrap();
+        assert!(is_rotated("app_r2024-01-01_00-00-00.restart-0001.log.gz"));
+        assert!(!is_rotated("app_rCURRENT.log"));
+
+        assert_eq!(enforce_total_size(dir, 1000), 0);
+        assert_eq!(enforce_total_size(dir, 250), 300);
+        assert!(!dir.join(name(0)).exists());
+        assert!(dir.join(name(3)).exists());
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000 + 5 * 24 * 3600);
+        assert_eq!(enforce_retention(dir, 1, now), 100);
+        assert!(!dir.join(name(3)).exists());
+        assert!(dir.join(name(4)).exists());
+        assert_eq!(enforce_total_size(dir, 0), 100);
+        assert!(dir.join("app_rCURRENT.log").exists());
+        assert!(dir.join("notes.txt").exists());
+    }
+}
@@ -0,0 +1,159 @@
+// The identity of this machine the auto ID is derived from, see
+// `Config::get_auto_id`.
+//
+// The MAC address used before changes with USB Ethernet adapters, docks and MAC
+// randomization, and so did the ID of a machine without a stored one, e.g. after
+// a reinstall. The UUID of the platform is stable: the SMBIOS UUID on Windows,
+// /etc/machine-id on Linux and IOPlatformUUID on macOS. The MAC address is only
+// used if there is none.
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// The UUID of the SMBIOS system information, Windows.
+    Smbios,
+    /// `machine_uid`: MachineGuid, /etc/machine-id or IOPlatformUUID.
+    MachineUid,
+    Mac,
+}
+
+/// The identity and where it is from, None if nothing is available.
+pub fn get() -> Option<(Vec<u8>, Source)> {
+    #[cfg(windows)]
+    if let Some(uuid) = smbios() {
+        return Some((uuid.to_vec(), Source::Smbios));
+    }
+    if let Ok(id) = machine_uid::get() {
+        let id = id.trim().to_owned();
+        if !id.is_empty() {
+            return Some((id.into_bytes(), Source::MachineUid));
+        }
+    }
+    if let Ok(Some(mac)) = mac_address::get_mac_address() {
+        return Some((mac.bytes().to_vec(), Source::Mac));
+    }
+    None
+}
+
+/// The auto ID of this machine, the same after a reinstall.
+pub fn auto_id() -> Option<u32> {
+    let (id, source) = get()?;
+    log::info!("Auto id from {:?}", source);
+    Some(match source {
+        // as before, the IDs of machines falling back to the MAC do not change
+        Source::Mac => id_of_mac(&id),
+        _ => id_of(&id),
+    })
+}
+
+const ID_MASK: u32 = 0x1FFFFFFF;
+
+fn id_of(identity: &[u8]) -> u32 {
+    // hashed, the ID is public and the identity is not
+    let mut hasher = Sha256::new();
+    hasher.update(b"rustdesk-auto-id");
+    hasher.update(identity);
+    let hash = hasher.finalize();
+    u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) & ID_MASK
+}
+
+fn id_of_mac(mac: &[u8]) -> u32 {
+    let mut id = 0u32;
+    for x in mac.iter().skip(2) {
+        id = (id << 8) | (*x as u32);
+    }
+    id & ID_MASK
+}
+
+#[cfg(windows)]
+fn smbios() -> Option<[u8; 16]> {
+    use winapi::um::sysinfoapi::GetSystemFirmwareTable;
+    const RSMB: u32 = u32::from_be_bytes(*b"RSMB");
+    let size = unsafe { GetSystemFirmwareTable(RSMB, 0, std::ptr::null_mut(), 0) };
+    if size == 0 {
+        return None;
+    }
+    let mut buf = vec![0u8; size as usize];
+    let n = unsafe { GetSystemFirmwareTable(RSMB, 0, buf.as_mut_ptr() as _, size) };
+    if n == 0 || n > size {
+        return None;
+    }
+    smbios_uuid(&buf[..n as usize])
+}
+
+/// The UUID of the system information structure (type 1) of the RawSMBIOSData
+/// returned by GetSystemFirmwareTable, None if not set.
+#[cfg(any(windows, test))]
+fn smbios_uuid(raw: &[u8]) -> Option<[u8; 16]> {
+    use std::convert::TryInto;
+    // the table follows a header of 8 bytes
+    let table = raw.get(8..)?;
+    let mut i = 0;
+    while i + 4 <= table.len() {
+        let (typ, len) = (table[i], table[i + 1] as usize);
+        if len < 4 || typ == 127 {
+            return None;
+        }
+        if typ == 1 && len >= 0x19 {
+            let uuid: [u8; 16] = table.get(i + 8..i + 24)?.try_into().ok()?;
+            // placeholders of the vendor
+            if uuid.iter().all(|x| *x == 0) || uuid.iter().all(|x| *x == 0xFF) {
+                return None;
+            }
+            return Some(uuid);
+        }
+        // the strings after the formatted area end with two NULs
+        let mut j = i + len;
+        while j + 1 < table.len() && (table[j] != 0 || table[j + 1] != 0) {
+            j += 1;
+        }
+        i = j + 2;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn structure(typ: u8, formatted: &[u8], strings: &[&str]) -> Vec<u8> {
+        let mut v = vec![typ, (formatted.len() + 4) as u8, 0, 0];
+        v.extend_from_slice(formatted);
+        for s in strings {
+            v.extend_from_slice(s.as_bytes());
+            v.push(0);
+        }
+        if strings.is_empty() {
+            v.push(0);
+        }
+        v.push(0);
+        v
+    }
+
+    #[test]
+    fn test_smbios_uuid() {
+        let uuid: Vec<u8> = (1..=16).collect();
+        let mut system = vec![1, 2, 3, 4];
+        system.extend_from_slice(&uuid);
+        system.push(6);
+        let mut raw = vec![0u8; 8];
+        raw.extend(structure(0, &[1, 2, 0, 0x00, 0xE8], &["Vendor", "1.0"]));
+        raw.extend(structure(1, &system, &["Maker", "Product"]));
+        raw.extend(structure(127, &[], &[]));
+        assert_eq!(smbios_uuid(&raw).map(|x| x.to_vec()), Some(uuid));
+        let mut system = vec![1, 2, 3, 4];
+        system.extend_from_slice(&[0xFF; 16]);
+        system.push(6);
+        let mut raw = vec![0u8; 8];
+        raw.extend(structure(1, &system, &[]));
+        assert_eq!(smbios_uuid(&raw), None);
+    }
+
+    #[test]
+    fn test_auto_id() {
+        assert_eq!(id_of(b"abc"), id_of(b"abc"));
+        assert_ne!(id_of(b"abc"), id_of(b"abd"));
+        assert!(id_of(b"abc") <= ID_MASK);
+        assert_eq!(id_of_mac(&[0, 0, 1, 2, 3, 4]), 0x01020304);
+    }
+}
@@ -0,0 +1,290 @@
+// Configuration distributed by MDM, so a stock client picks up the server, the
+// key and the policies of an organization without a custom build.
+//
+// The payload is a dictionary like the config of a custom client: the options at
+// its top level and in "override-settings" are applied as overwrite settings,
+// those in "default-settings" as default settings, and the same for the display
+// and local settings. Booleans become "Y" and "N", numbers their decimal string.
+// - macOS: the managed preferences of the app, written by a configuration
+//   profile with a payload of the bundle id, see `load`.
+// - Android: the managed configuration of the app, read by the app from
+//   RestrictionsManager and passed as JSON to `apply_json` on every change.
+//
+// Applied as a layer over the settings of a custom client, see
+// `settings_map::Layer`, below the group policies: a setting replaced is
+// restored once it is not managed anymore.
+use crate::{
+    bail,
+    options::{
+        DEFAULT_DISPLAY_SETTINGS, DEFAULT_LOCAL_SETTINGS, DEFAULT_SETTINGS,
+        OVERWRITE_DISPLAY_SETTINGS, OVERWRITE_LOCAL_SETTINGS, OVERWRITE_SETTINGS,
+    },
+    settings_map::{Layer, SettingsMap},
+    ResultType,
+};
+use serde_json::Value;
+use std::{collections::HashMap, sync::Mutex};
+
+type Map = HashMap<String, String>;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ManagedConfig {
+    pub override_settings: Map,
+    pub default_settings: Map,
+    pub override_display_settings: Map,
+    pub default_display_settings: Map,
+    pub override_local_settings: Map,
+    pub default_local_settings: Map,
+}
+
+lazy_static::lazy_static! {
+    static ref APPLIED: Mutex<ManagedConfig> = Default::default();
+}
+
+impl ManagedConfig {
+    pub fn from_json(s: &str) -> ResultType<Self> {
+        Self::from_value(serde_json::from_str(s)?)
+    }
+
+    /// A plist, XML or binary, e.g. of /Library/Managed Preferences.
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub fn from_plist(data: &[u8]) -> ResultType<Self> {
+        let v = plist::Value::from_reader(std::io::Cursor::new(data))?;
+        Self::from_value(plist_to_json(v))
+    }
+
+    fn from_value(v: Value) -> ResultType<Self> {
+        let Value::Object(top) = v else {
+            bail!("The managed configuration is not a dictionary");
+        };
+        let mut res = Self::default();
+        for (k, v) in top {
+            if let Some(map) = res.section(&k) {
+                let Value::Object(section) = v else {
+                    log::warn!("Managed {} is not a dictionary", k);
+                    continue;
+                };
+                for (k, v) in section {
+                    insert(map, k, &v);
+                }
+            } else {
+                insert(&mut res.override_settings, k, &v);
+            }
+        }
+        Ok(res)
+    }
+
+    fn section(&mut self, k: &str) -> Option<&mut Map> {
+        Some(match k {
+            "override-settings" => &mut self.override_settings,
+            "default-settings" => &mut self.default_settings,
+            "override-display-settings" => &mut self.override_display_settings,
+            "default-display-settings" => &mut self.default_display_settings,
+            "override-local-settings" => &mut self.override_local_settings,
+            "default-local-settings" => &mut self.default_local_settings,
+            _ => return None,
+        })
+    }
+
+    /// `other` wins, e.g. the preferences of the user over those of the device.
+    #[cfg(target_os = "macos")]
+    fn extend(&mut self, other: ManagedConfig) {
+        self.override_settings.extend(other.override_settings);
+        self.default_settings.extend(other.default_settings);
+        self.override_display_settings
+            .extend(other.override_display_settings);
+        self.default_display_settings
+            .extend(other.default_display_settings);
+        self.override_local_settings
+            .extend(other.override_local_settings);
+        self.default_local_settings
+            .extend(other.default_local_settings);
+    }
+
+    fn targets(&self) -> [(&'static SettingsMap, &Map); 6] {
+        [
+            (&OVERWRITE_SETTINGS, &self.override_settings),
+            (&DEFAULT_SETTINGS, &self.default_settings),
+            (&OVERWRITE_DISPLAY_SETTINGS, &self.override_display_settings),
+            (&DEFAULT_DISPLAY_SETTINGS, &self.default_display_settings),
+            (&OVERWRITE_LOCAL_SETTINGS, &self.override_local_settings),
+            (&DEFAULT_LOCAL_SETTINGS, &self.default_local_settings),
+        ]
+    }
+}
+
+fn insert(map: &mut Map, k: String, v: &Value) {
+    let v = match v {
+        Value::String(s) => s.clone(),
+        Value::Bool(b) => if *b { "Y" } else { "N" }.to_owned(),
+        Value::Number(n) => n.to_string(),
+        _ => {
+            log::warn!("Unsupported value of managed option {}", k);
+            return;
+        }
+    };
+    map.insert(k, v);
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn plist_to_json(v: plist::Value) -> Value {
+    match v {
+        plist::Value::Dictionary(d) => {
+            Value::Object(d.into_iter().map(|(k, v)| (k, plist_to_json(v))).collect())
+        }
+        plist::Value::Array(a) => Value::Array(a.into_iter().map(plist_to_json).collect()),
+        plist::Value::Boolean(b) => Value::Bool(b),
+        plist::Value::Integer(i) => match (i.as_signed(), i.as_unsigned()) {
+            (Some(i), _) => i.into(),
+            (_, Some(u)) => u.into(),
+            _ => Value::Null,
+        },
+        plist::Value::Real(f) => f.into(),
+        plist::Value::String(s) => Value::String(s),
+        _ => Value::Null,
+    }
+}
+
+/// Applies `config` in place of the one applied before, returns whether it
+/// changed.
+pub fn apply(config: ManagedConfig) -> bool {
+    let mut applied = APPLIED.lock().unwrap();
+    if *applied == config {
+        return false;
+    }
+    for (target, settings) in config.targets().iter() {
+        target.set_layer(Layer::Mdm, (*settings).clone());
+    }
+    log::info!(
+        "Managed configuration applied, {} override settings",
+        config.override_settings.len()
+    );
+    *applied = config;
+    true
+}
+
+/// Applies the managed configuration of Android, e.g. the restrictions bundle
+/// converted to JSON. An empty string removes it.
+pub fn apply_json(s: &str) -> ResultType<bool> {
+    let config = if s.trim().is_empty() {
+        ManagedConfig::default()
+    } else {
+        ManagedConfig::from_json(s)?
+    };
+    Ok(apply(config))
+}
+
+/// The managed configuration applied.
+pub fn get() -> ManagedConfig {
+    APPLIED.lock().unwrap().clone()
+}
+
+/// Loads and applies the managed preferences of the app, those of the device
+/// and, winning, of the user. Returns whether any were found.
+#[cfg(target_os = "macos")]
+pub fn load() -> bool {
+    let bundle_id = format!(
+        "{}.{}",
+        crate::config::ORG.read().unwrap(),
+        crate::config::APP_NAME.read().unwrap().to_lowercase()
+    );
+    let dir = std::path::Path::new("/Library/Managed Preferences");
+    let file = format!("{}.plist", bundle_id);
+    let mut config = ManagedConfig::default();
+    let mut found = false;
+    for path in [
+        dir.join(&file),
+        dir.join(crate::whoami::username()).join(&file),
+    ] {
+        let Ok(data) = std::fs::read(&path) else {
+            continue;
+        };
+        match ManagedConfig::from_plist(&data) {
+            Ok(c) => {
+                config.extend(c);
+                found = true;
+            }
+            Err(err) => log::error!("Failed to parse {}: {}", path.display(), err),
+        }
+    }
+    apply(config);
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{test_config, Config};
+
+    #[test]
+    fn test_managed_config() {
+        let _t = test_config();
+        // of the custom client
+        OVERWRITE_SETTINGS
+            .write()
+            .unwrap()
+            .insert("relay-server".to_owned(), "relay.custom.com".to_owned());
+        let json = r#"{
+            "custom-rendezvous-server": "rs.example.com",
+            "key": "abc=",
+            "relay-server": "relay.example.com",
+            "enable-audio": false,
+            "direct-access-port": 21118,
+            "default-settings": {"theme": "dark"},
+            "override-local-settings": {"lang": "de"}
+        }"#;
+        assert!(apply_json(json).unwrap());
+        assert!(!apply_json(json).unwrap());
+        assert_eq!(
+            Config::get_option("custom-rendezvous-server"),
+            "rs.example.com"
+        );
+        assert_eq!(Config::get_option("enable-audio"), "N");
+        assert_eq!(Config::get_option("direct-access-port"), "21118");
+        assert_eq!(DEFAULT_SETTINGS.get("theme").as_deref(), Some("dark"));
+        assert_eq!(OVERWRITE_LOCAL_SETTINGS.get("lang").as_deref(), Some("de"));
+        assert!(apply_json(r#"{"key": "def="}"#).unwrap());
+        assert_eq!(Config::get_option("key"), "def=");
+        assert_eq!(Config::get_option("custom-rendezvous-server"), "");
+        assert_eq!(Config::get_option("relay-server"), "relay.custom.com");
+        assert!(!DEFAULT_SETTINGS.contains_key("theme"));
+        assert!(apply_json("").unwrap());
+        assert!(apply_json("[]").is_err());
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    #[test]
+    fn test_plist() {
+        let plist = br#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0">
+<dict>
+    <key>relay-server</key><string>relay.example.com</string>
+    <key>allow-auto-disconnect</key><true/>
+    <key>override-display-settings</key>
+    <dict><key>view-only</key><true/></dict>
+</dict>
+</plist>"#;
+        let config = ManagedConfig::from_plist(plist).unwrap();
+        assert_eq!(
+            config
+                .override_settings
+                .get("relay-server")
+                .map(|x| x.as_str()),
+            Some("relay.example.com")
+        );
+        assert_eq!(
+            config
+                .override_settings
+                .get("allow-auto-disconnect")
+                .map(|x| x.as_str()),
+            Some("Y")
+        );
+        assert_eq!(
+            config
+                .override_display_settings
+                .get("view-only")
+                .map(|x| x.as_str()),
+            Some("Y")
+        );
+    }
+}
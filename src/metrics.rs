@@ -0,0 +1,385 @@
+// Counters, gauges and histograms of connections, bytes, latencies and transfer
+// jobs in the Prometheus text format, so self-hosters can monitor unattended
+// machines. `render` returns the text, `serve` exposes it on a local HTTP
+// endpoint, which is not started by default.
+//
+// Metrics are registered once by name and labels, the handles are cheap atomics
+// to update on hot paths.
+use crate::ResultType;
+use std::{
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+const PREFIX: &str = "hbb_";
+/// Seconds, for connect and handshake durations.
+pub const DURATION_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10.];
+/// Milliseconds, for server latencies.
+pub const LATENCY_BUCKETS: &[f64] = &[5., 10., 25., 50., 100., 250., 500., 1000., 2500.];
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    #[inline]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    #[inline]
+    pub fn set(&self, v: i64) {
+        self.0.store(v, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn add(&self, n: i64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    pub fn dec(&self) {
+        self.add(-1);
+    }
+
+    #[inline]
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Increments now and decrements when the guard is dropped, e.g. for the
+    /// lifetime of a connection.
+    pub fn track(self: &Arc<Self>) -> GaugeGuard {
+        self.inc();
+        GaugeGuard(self.clone())
+    }
+}
+
+pub struct GaugeGuard(Arc<Gauge>);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    // not cumulative, summed up by `render`
+    buckets: Vec<AtomicU64>,
+    // f64 bits
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, v: f64) {
+        if let Some(i) = self.bounds.iter().position(|b| v <= *b) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        let _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| {
+                Some((f64::from_bits(x) + v).to_bits())
+            });
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> f64 {
+        f64::from_bits(self.sum.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Clone)]
+enum Metric {
+    Counter(Arc<Counter>),
+    Gauge(Arc<Gauge>),
+    Histogram(Arc<Histogram>),
+}
+
+impl Metric {
+    fn kind(&self) -> &'static str {
+        match self {
+            Metric::Counter(_) => "counter",
+            Metric::Gauge(_) => "gauge",
+            Metric::Histogram(_) => "histogram",
+        }
+    }
+}
+
+struct Entry {
+    name: String,
+    help: String,
+    // rendered, e.g. `transport="tcp"`
+    labels: String,
+    metric: Metric,
+}
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: RwLock<Vec<Entry>> = Default::default();
+    pub static ref CONNECTIONS_TCP: Arc<Counter> =
+        counter("connections_total", "Outgoing connections established.", &[("transport", "tcp")]);
+    pub static ref CONNECTIONS_WS: Arc<Counter> =
+        counter("connections_total", "Outgoing connections established.", &[("transport", "ws")]);
+    pub static ref CONNECT_ERRORS: Arc<Counter> =
+        counter("connect_errors_total", "Outgoing connections failed.", &[]);
+    pub static ref CONNECT_SECONDS: Arc<Histogram> =
+        histogram("connect_seconds", "Time to establish outgoing connections.", &[], DURATION_BUCKETS);
+    /// Maintained by `tcp::FramedStream` and `websocket::WsFramedStream`, from
+    /// their creation to their drop.
+    pub static ref ACTIVE_CONNECTIONS: Arc<Gauge> =
+        gauge("active_connections", "Connections currently open.", &[]);
+    pub static ref BYTES_SENT: Arc<Counter> =
+        counter("bytes_sent_total", "Bytes sent on streams, before encryption.", &[]);
+    pub static ref BYTES_RECEIVED: Arc<Counter> =
+        counter("bytes_received_total", "Bytes received on streams, after decryption.", &[]);
+    pub static ref RENDEZVOUS_LATENCY: Arc<Histogram> =
+        histogram("rendezvous_latency_ms", "Latency to the rendezvous servers.", &[], LATENCY_BUCKETS);
+    pub static ref TRANSFER_JOBS_READ: Arc<Counter> =
+        counter("transfer_jobs_total", "File transfer jobs started.", &[("direction", "read")]);
+    pub static ref TRANSFER_JOBS_WRITE: Arc<Counter> =
+        counter("transfer_jobs_total", "File transfer jobs started.", &[("direction", "write")]);
+}
+
+fn register(
+    name: &str,
+    help: &str,
+    labels: &[(&str, &str)],
+    new: impl FnOnce() -> Metric,
+) -> Metric {
+    let name = format!("{}{}", PREFIX, name);
+    let labels = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut registry = REGISTRY.write().unwrap();
+    if let Some(e) = registry
+        .iter()
+        .find(|e| e.name == name && e.labels == labels)
+    {
+        return e.metric.clone();
+    }
+    let metric = new();
+    registry.push(Entry {
+        name,
+        help: help.to_owned(),
+        labels,
+        metric: metric.clone(),
+    });
+    metric
+}
+
+/// Registers a counter, or returns the one of the same name and labels. The
+/// name is prefixed with `hbb_`. Panics if registered as another kind.
+pub fn counter(name: &str, help: &str, labels: &[(&str, &str)]) -> Arc<Counter> {
+    match register(name, help, labels, || Metric::Counter(Default::default())) {
+        Metric::Counter(c) => c,
+        m => panic!("metric {} is a {}", name, m.kind()),
+    }
+}
+
+pub fn gauge(name: &str, help: &str, labels: &[(&str, &str)]) -> Arc<Gauge> {
+    match register(name, help, labels, || Metric::Gauge(Default::default())) {
+        Metric::Gauge(g) => g,
+        m => panic!("metric {} is a {}", name, m.kind()),
+    }
+}
+
+/// `bounds` are the upper bounds of the buckets, ascending, without +Inf.
+pub fn histogram(
+    name: &str,
+    help: &str,
+    labels: &[(&str, &str)],
+    bounds: &'static [f64],
+) -> Arc<Histogram> {
+    match register(name, help, labels, || {
+        Metric::Histogram(Arc::new(Histogram::new(bounds)))
+    }) {
+        Metric::Histogram(h) => h,
+        m => panic!("metric {} is a {}", name, m.kind()),
+    }
+}
+
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn with_labels(labels: &str, extra: &str) -> String {
+    match (labels.is_empty(), extra.is_empty()) {
+        (true, true) => String::new(),
+        (false, true) => format!("{{{}}}", labels),
+        (true, false) => format!("{{{}}}", extra),
+        (false, false) => format!("{{{},{}}}", labels, extra),
+    }
+}
+
+/// All metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    // the statics register on first use
+    lazy_static::initialize(&CONNECTIONS_TCP);
+    lazy_static::initialize(&CONNECTIONS_WS);
+    lazy_static::initialize(&CONNECT_ERRORS);
+    lazy_static::initialize(&CONNECT_SECONDS);
+    lazy_static::initialize(&ACTIVE_CONNECTIONS);
+    lazy_static::initialize(&BYTES_SENT);
+    lazy_static::initialize(&BYTES_RECEIVED);
+    lazy_static::initialize(&RENDEZVOUS_LATENCY);
+    lazy_static::initialize(&TRANSFER_JOBS_READ);
+    lazy_static::initialize(&TRANSFER_JOBS_WRITE);
+    let registry = REGISTRY.read().unwrap();
+    let mut out = String::new();
+    let mut described: Vec<&str> = Vec::new();
+    for e in registry.iter() {
+        if described.contains(&e.name.as_str()) {
+            continue;
+        }
+        described.push(&e.name);
+        writeln!(out, "# HELP {} {}", e.name, e.help).ok();
+        writeln!(out, "# TYPE {} {}", e.name, e.metric.kind()).ok();
+        // all series of a name are grouped together
+        for e in registry.iter().filter(|x| x.name == e.name) {
+            let labels = with_labels(&e.labels, "");
+            match &e.metric {
+                Metric::Counter(c) => {
+                    writeln!(out, "{}{} {}", e.name, labels, c.get()).ok();
+                }
+                Metric::Gauge(g) => {
+                    writeln!(out, "{}{} {}", e.name, labels, g.get()).ok();
+                }
+                Metric::Histogram(h) => {
+                    let mut cumulative = 0;
+                    for (bound, bucket) in h.bounds.iter().zip(h.buckets.iter()) {
+                        cumulative += bucket.load(Ordering::Relaxed);
+                        let le = with_labels(&e.labels, &format!("le=\"{}\"", bound));
+                        writeln!(out, "{}_bucket{} {}", e.name, le, cumulative).ok();
+                    }
+                    let le = with_labels(&e.labels, "le=\"+Inf\"");
+                    writeln!(out, "{}_bucket{} {}", e.name, le, h.count()).ok();
+                    writeln!(out, "{}_sum{} {}", e.name, labels, h.sum()).ok();
+                    writeln!(out, "{}_count{} {}", e.name, labels, h.count()).ok();
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Serves `render` on `GET /metrics`. Bind to a loopback address unless the
+/// port is protected otherwise, the metrics are not authenticated.
+pub async fn serve(addr: SocketAddr) -> ResultType<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("Metrics listening on {}", listener.local_addr()?);
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_REQUEST_SIZE];
+            let mut len = 0;
+            // the headers are ignored, only the request line matters
+            while len < buf.len() {
+                match stream.read(&mut buf[len..]).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => len += n,
+                }
+                if buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+                    break;
+                }
+            }
+            let mut headers = [httparse::EMPTY_HEADER; 32];
+            let mut req = httparse::Request::new(&mut headers);
+            let response = match req.parse(&buf[..len]) {
+                Ok(_) if req.method == Some("GET") && req.path == Some("/metrics") => {
+                    let body = render();
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                }
+                Ok(_) => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_owned(),
+                Err(_) => {
+                    "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        .to_owned()
+                }
+            };
+            stream.write_all(response.as_bytes()).await.ok();
+            stream.shutdown().await.ok();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let c = counter("test_requests_total", "Requests.", &[("code", "200")]);
+        c.add(3);
+        assert!(Arc::ptr_eq(
+            &c,
+            &counter("test_requests_total", "Requests.", &[("code", "200")])
+        ));
+        counter("test_requests_total", "Requests.", &[("code", "404")]).inc();
+        let g = gauge("test_active", "Active.", &[]);
+        {
+            let _guard = g.track();
+            assert_eq!(g.get(), 1);
+        }
+        assert_eq!(g.get(), 0);
+        let h = histogram("test_seconds", "Durations.", &[], &[0.1, 1.]);
+        h.observe(0.0625);
+        h.observe(0.5);
+        h.observe(5.);
+        let text = render();
+        assert!(text.contains("# TYPE hbb_test_requests_total counter\nhbb_test_requests_total{code=\"200\"} 3\nhbb_test_requests_total{code=\"404\"} 1\n"));
+        assert!(text.contains("hbb_test_active 0\n"));
+        assert!(text.contains("hbb_test_seconds_bucket{le=\"0.1\"} 1\nhbb_test_seconds_bucket{le=\"1\"} 2\nhbb_test_seconds_bucket{le=\"+Inf\"} 3\nhbb_test_seconds_sum 5.5625\nhbb_test_seconds_count 3\n"));
+        assert!(text.contains("# TYPE hbb_connections_total counter"));
+    }
+}
@@ -0,0 +1,392 @@
+// Simulated network conditions for tests: latency, jitter, packet loss and a
+// bandwidth cap. `SimStream` wraps any stream, e.g. one end of
+// `tokio::io::duplex` passed to `FramedStream::from`, and `SimUdpSocket` a UDP
+// socket, with `Shaper::datagram` deciding the fate of each packet.
+//
+// Decisions come from a seeded RNG and deadlines from `tokio::time`, so with
+// `tokio::time::pause()` a test runs the same way every time, without waiting.
+//
+// Only built for tests and with the `netsim` feature.
+use bytes::Bytes;
+use futures::task::AtomicWaker;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf},
+    net::UdpSocket,
+    sync::mpsc,
+    time::Instant,
+};
+
+/// Bytes accepted by `SimStream` before writes wait for delivery.
+const MAX_QUEUED: usize = 256 * 1024;
+const MIN_RETRANSMIT: Duration = Duration::from_millis(200);
+// a stream with 100% loss would never deliver
+const MAX_STREAM_LOSS: f64 = 0.99;
+
+#[derive(Debug, Clone, Default)]
+pub struct Conditions {
+    /// One way.
+    pub latency: Duration,
+    /// Added to the latency, uniform in [0, jitter].
+    pub jitter: Duration,
+    /// Probability in [0, 1] a packet is lost. Lost stream segments are
+    /// retransmitted after a timeout, as by TCP.
+    pub loss: f64,
+    /// Bytes per second, 0 for unlimited.
+    pub bandwidth: u64,
+    pub seed: u64,
+}
+
+impl Conditions {
+    pub fn lan() -> Self {
+        Self {
+            latency: Duration::from_millis(1),
+            bandwidth: 100 * 1024 * 1024,
+            ..Default::default()
+        }
+    }
+
+    pub fn mobile() -> Self {
+        Self {
+            latency: Duration::from_millis(80),
+            jitter: Duration::from_millis(40),
+            loss: 0.02,
+            bandwidth: 1024 * 1024,
+            ..Default::default()
+        }
+    }
+
+    pub fn satellite() -> Self {
+        Self {
+            latency: Duration::from_millis(300),
+            jitter: Duration::from_millis(50),
+            loss: 0.01,
+            bandwidth: 512 * 1024,
+            ..Default::default()
+        }
+    }
+}
+
+/// The state of one direction of a link.
+pub struct Shaper {
+    conditions: Conditions,
+    rng: StdRng,
+    // when the link is free to transmit again
+    next_free: Instant,
+    // streams deliver in order
+    last_delivery: Instant,
+}
+
+impl Shaper {
+    pub fn new(conditions: Conditions) -> Self {
+        let now = Instant::now();
+        Self {
+            rng: StdRng::seed_from_u64(conditions.seed),
+            conditions,
+            next_free: now,
+            last_delivery: now,
+        }
+    }
+
+    // the time to put `len` bytes on the link
+    fn transmit(&mut self, now: Instant, len: usize) -> Duration {
+        let bandwidth = self.conditions.bandwidth;
+        let start = self.next_free.max(now);
+        if bandwidth > 0 {
+            self.next_free = start + Duration::from_secs_f64(len as f64 / bandwidth as f64);
+        } else {
+            self.next_free = start;
+        }
+        self.next_free - now
+    }
+
+    fn propagation(&mut self) -> Duration {
+        let jitter = self.conditions.jitter.as_micros() as u64;
+        let jitter = if jitter > 0 {
+            self.rng.gen_range(0..=jitter)
+        } else {
+            0
+        };
+        self.conditions.latency + Duration::from_micros(jitter)
+    }
+
+    fn lost(&mut self, max: f64) -> bool {
+        let loss = self.conditions.loss.clamp(0., max);
+        loss > 0. && self.rng.gen_bool(loss)
+    }
+
+    /// The delay of a datagram of `len` bytes sent now, None if it is lost.
+    pub fn datagram(&mut self, len: usize) -> Option<Duration> {
+        let transmit = self.transmit(Instant::now(), len);
+        if self.lost(1.) {
+            return None;
+        }
+        Some(transmit + self.propagation())
+    }
+
+    /// When a stream segment of `len` bytes sent now is delivered.
+    pub fn segment(&mut self, len: usize) -> Instant {
+        let now = Instant::now();
+        let mut delay = self.transmit(now, len) + self.propagation();
+        let retransmit = (self.conditions.latency * 2).max(MIN_RETRANSMIT);
+        while self.lost(MAX_STREAM_LOSS) {
+            delay += retransmit;
+        }
+        self.last_delivery = self.last_delivery.max(now + delay);
+        self.last_delivery
+    }
+}
+
+/// Delays the writes to `inner`, reads are passed through. Wrap both ends for
+/// conditions in both directions.
+///
+/// The writes are delivered by a task spawned on the tokio runtime when they
+/// are due, whether or not the stream is flushed or written again, as the
+/// kernel sends the buffer of a socket. A flush only reports a broken link, and
+/// a write waits while MAX_QUEUED bytes are not delivered yet.
+pub struct SimStream<S> {
+    read: ReadHalf<S>,
+    // None once shut down
+    tx: Option<mpsc::UnboundedSender<(Instant, Bytes)>>,
+    shaper: Shaper,
+    link: Arc<Link>,
+}
+
+// shared with the delivery task
+#[derive(Default)]
+struct Link {
+    queued: AtomicUsize,
+    broken: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl<S: AsyncRead + AsyncWrite + Send + 'static> SimStream<S> {
+    pub fn new(inner: S, conditions: Conditions) -> Self {
+        let (read, write) = tokio::io::split(inner);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let link = Arc::new(Link::default());
+        tokio::spawn(deliver(write, rx, link.clone()));
+        Self {
+            read,
+            tx: Some(tx),
+            shaper: Shaper::new(conditions),
+            link,
+        }
+    }
+}
+
+async fn deliver<S: AsyncWrite>(
+    mut write: WriteHalf<S>,
+    mut rx: mpsc::UnboundedReceiver<(Instant, Bytes)>,
+    link: Arc<Link>,
+) {
+    while let Some((deadline, data)) = rx.recv().await {
+        tokio::time::sleep_until(deadline).await;
+        if write.write_all(&data).await.is_err() || write.flush().await.is_err() {
+            link.broken.store(true, Ordering::SeqCst);
+            link.waker.wake();
+            return;
+        }
+        link.queued.fetch_sub(data.len(), Ordering::SeqCst);
+        link.waker.wake();
+    }
+    // after the segments queued before the shutdown
+    write.shutdown().await.ok();
+}
+
+impl<S> SimStream<S> {
+    // ready when less than `limit` bytes are queued
+    fn poll_queued(&self, cx: &mut Context<'_>, limit: usize) -> Poll<io::Result<()>> {
+        self.link.waker.register(cx.waker());
+        if self.link.broken.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if self.link.queued.load(Ordering::SeqCst) < limit {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for SimStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.read).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for SimStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        futures::ready!(self.poll_queued(cx, MAX_QUEUED))?;
+        let this = &mut *self;
+        let Some(tx) = this.tx.as_ref() else {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        };
+        let deadline = this.shaper.segment(buf.len());
+        this.link.queued.fetch_add(buf.len(), Ordering::SeqCst);
+        if tx.send((deadline, Bytes::copy_from_slice(buf))).is_err() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.link.broken.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // the delivery task shuts `inner` down once the queue is delivered
+        self.tx = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A UDP socket whose datagrams are sent with the delay decided by a `Shaper`,
+/// or dropped. Receives are passed through, wrap both ends for conditions in
+/// both directions.
+pub struct SimUdpSocket {
+    socket: Arc<UdpSocket>,
+    shaper: Mutex<Shaper>,
+}
+
+impl SimUdpSocket {
+    pub fn new(socket: UdpSocket, conditions: Conditions) -> Self {
+        Self {
+            socket: Arc::new(socket),
+            shaper: Mutex::new(Shaper::new(conditions)),
+        }
+    }
+
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Returns at once like a real send, the datagram is sent by a task spawned
+    /// on the tokio runtime after its delay. A lost datagram is not reported.
+    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let Some(delay) = self.shaper.lock().unwrap().datagram(buf.len()) else {
+            return Ok(buf.len());
+        };
+        let socket = self.socket.clone();
+        let data = buf.to_vec();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            socket.send_to(&data, addr).await.ok();
+        });
+        Ok(buf.len())
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.socket.recv_from(buf).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sim_stream() {
+        let (a, mut b) = tokio::io::duplex(1024 * 1024);
+        let mut a = SimStream::new(
+            a,
+            Conditions {
+                latency: Duration::from_millis(50),
+                bandwidth: 10_000,
+                ..Default::default()
+            },
+        );
+        let start = Instant::now();
+        // delivered without a flush
+        a.write_all(&[1u8; 1000]).await.unwrap();
+        a.write_all(&[2u8; 1000]).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(1));
+        let mut buf = vec![0u8; 2000];
+        b.read_exact(&mut buf).await.unwrap();
+        // 2000 bytes at 10000 B/s, then the latency
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(250) && elapsed < Duration::from_millis(260));
+        assert_eq!(&buf[..1000], &[1u8; 1000][..]);
+        assert_eq!(&buf[1000..], &[2u8; 1000][..]);
+        a.shutdown().await.unwrap();
+        assert_eq!(b.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_datagram() {
+        let conditions = Conditions {
+            latency: Duration::from_millis(20),
+            jitter: Duration::from_millis(10),
+            loss: 0.2,
+            seed: 7,
+            ..Default::default()
+        };
+        let run = || {
+            let mut shaper = Shaper::new(conditions.clone());
+            (0..1000).map(|_| shaper.datagram(100)).collect::<Vec<_>>()
+        };
+        let first = run();
+        assert_eq!(first, run());
+        let lost = first.iter().filter(|x| x.is_none()).count();
+        assert!(lost > 150 && lost < 250, "{}", lost);
+        for delay in first.into_iter().flatten() {
+            assert!(delay >= Duration::from_millis(20) && delay <= Duration::from_millis(30));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sim_udp_socket() {
+        let conditions = Conditions {
+            latency: Duration::from_millis(5),
+            jitter: Duration::from_millis(5),
+            loss: 0.2,
+            seed: 3,
+            ..Default::default()
+        };
+        let a = SimUdpSocket::new(
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            conditions.clone(),
+        );
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = b.local_addr().unwrap();
+        for i in 0..100u8 {
+            a.send_to(&[i], addr).await.unwrap();
+        }
+        // the same decisions from the same seed
+        let mut shaper = Shaper::new(conditions);
+        let expected = (0..100).filter_map(|_| shaper.datagram(1)).count();
+        let mut buf = [0u8; 16];
+        let mut received = 0;
+        while let Ok(res) =
+            tokio::time::timeout(Duration::from_millis(500), b.recv_from(&mut buf)).await
+        {
+            assert_eq!(res.unwrap().1, a.get_ref().local_addr().unwrap());
+            received += 1;
+        }
+        assert_eq!(received, expected);
+        assert!(expected > 60 && expected < 95, "{}", expected);
+    }
+}
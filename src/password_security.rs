@@ -1,9 +1,27 @@
-use crate::config::Config;
-use sodiumoxide::base64;
-use std::sync::{Arc, RwLock};
+use crate::config::{keys, Config, HARD_SETTINGS, OVERWRITE_SETTINGS};
+use sodiumoxide::{
+    base64,
+    crypto::{pwhash::argon2id13, secretstream},
+    randombytes::randombytes,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+};
+use zeroize::{Zeroize, Zeroizing};
 
 lazy_static::lazy_static! {
     pub static ref TEMPORARY_PASSWORD:Arc<RwLock<String>> = Arc::new(RwLock::new(get_auto_password()));
+    static ref KDF_PARAMS: RwLock<KdfParams> = Default::default();
+    // One salt per process, so that the slow derivation runs once for all fields.
+    static ref KDF_SALT: Vec<u8> = randombytes(argon2id13::SALTBYTES);
+    // by params, salt and the key derived from
+    static ref KDF_KEYS: Mutex<HashMap<(KdfParams, Vec<u8>, Vec<u8>), secretstream::Key>> = Default::default();
+}
+
+thread_local! {
+    // set while decrypting with `keystore::fallback_key`
+    static KEY_OVERRIDE: std::cell::RefCell<Option<Zeroizing<Vec<u8>>>> = Default::default();
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -21,12 +39,31 @@ pub enum ApproveMode {
 }
 
 fn get_auto_password() -> String {
-    let len = temporary_password_length();
-    if Config::get_bool_option(crate::config::keys::OPTION_ALLOW_NUMERNIC_ONE_TIME_PASSWORD) {
-        Config::get_auto_numeric_password(len)
-    } else {
-        Config::get_auto_password(len)
+    let policy = PasswordPolicy::get();
+    let len = temporary_password_length().max(policy.min_length);
+    if Config::get_bool_option(crate::config::keys::OPTION_ALLOW_NUMERNIC_ONE_TIME_PASSWORD)
+        && !policy.disallow_numeric
+        && !policy.require_lower
+    {
+        return Config::get_auto_numeric_password(len);
+    }
+    // Lower case letters and digits, the other classes only if required, as they
+    // are harder to type.
+    let mut chars: Vec<char> = "23456789abcdefghijkmnpqrstuvwxyz".chars().collect();
+    if policy.require_upper {
+        chars.extend("ABCDEFGHJKLMNPQRSTUVWXYZ".chars());
+    }
+    if policy.require_symbol {
+        chars.extend("!#%+-=@_".chars());
     }
+    let mut password = Config::get_auto_password_with_chars(len, &chars);
+    for _ in 0..100 {
+        if policy.check(&password).is_ok() {
+            break;
+        }
+        password = Config::get_auto_password_with_chars(len, &chars);
+    }
+    password
 }
 
 // Should only be called in server
@@ -91,18 +128,242 @@ pub fn hide_cm() -> bool {
         && crate::config::option2bool("allow-hide-cm", &Config::get_option("allow-hide-cm"))
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordViolation {
+    TooShort(usize),
+    MissingLower,
+    MissingUpper,
+    MissingDigit,
+    MissingSymbol,
+    NumericOnly,
+}
+
+impl std::fmt::Display for PasswordViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooShort(n) => write!(f, "Password must be at least {} characters", n),
+            Self::MissingLower => write!(f, "Password must contain a lower case letter"),
+            Self::MissingUpper => write!(f, "Password must contain an upper case letter"),
+            Self::MissingDigit => write!(f, "Password must contain a digit"),
+            Self::MissingSymbol => write!(f, "Password must contain a symbol"),
+            Self::NumericOnly => write!(f, "Password must not be numeric only"),
+        }
+    }
+}
+
+/// Password rules of the deployment. They are only taken from
+/// HARD_SETTINGS and OVERWRITE_SETTINGS, so the user can not lower them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub require_lower: bool,
+    pub require_upper: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    pub disallow_numeric: bool,
+    // 0: never expires
+    pub expiry_days: u32,
+}
+
+fn policy_option(k: &str) -> String {
+    if let Some(v) = HARD_SETTINGS.read().unwrap().get(k) {
+        return v.clone();
+    }
+    OVERWRITE_SETTINGS
+        .read()
+        .unwrap()
+        .get(k)
+        .cloned()
+        .unwrap_or_default()
+}
+
+impl PasswordPolicy {
+    pub fn get() -> Self {
+        let classes = policy_option(keys::OPTION_PASSWORD_REQUIRED_CLASSES);
+        let has = |c: &str| classes.split(',').any(|x| x.trim() == c);
+        Self {
+            min_length: policy_option(keys::OPTION_PASSWORD_MIN_LENGTH)
+                .parse()
+                .unwrap_or(0),
+            require_lower: has("lower"),
+            require_upper: has("upper"),
+            require_digit: has("digit"),
+            require_symbol: has("symbol"),
+            disallow_numeric: crate::config::option2bool(
+                keys::OPTION_PASSWORD_DISALLOW_NUMERIC,
+                &policy_option(keys::OPTION_PASSWORD_DISALLOW_NUMERIC),
+            ),
+            expiry_days: policy_option(keys::OPTION_PASSWORD_EXPIRY_DAYS)
+                .parse()
+                .unwrap_or(0),
+        }
+    }
+
+    /// All violations at once, so the UI can show them together.
+    pub fn check(&self, password: &str) -> Result<(), Vec<PasswordViolation>> {
+        let mut res = vec![];
+        if password.chars().count() < self.min_length {
+            res.push(PasswordViolation::TooShort(self.min_length));
+        }
+        let mut checks: Vec<(fn(char) -> bool, PasswordViolation)> = vec![];
+        if self.require_lower {
+            checks.push((char::is_lowercase, PasswordViolation::MissingLower));
+        }
+        if self.require_upper {
+            checks.push((char::is_uppercase, PasswordViolation::MissingUpper));
+        }
+        if self.require_digit {
+            checks.push((
+                |c: char| c.is_ascii_digit(),
+                PasswordViolation::MissingDigit,
+            ));
+        }
+        if self.require_symbol {
+            checks.push((
+                |c: char| !c.is_alphanumeric() && !c.is_whitespace(),
+                PasswordViolation::MissingSymbol,
+            ));
+        }
+        for (f, violation) in checks {
+            if !password.chars().any(f) {
+                res.push(violation);
+            }
+        }
+        if self.disallow_numeric
+            && !password.is_empty()
+            && password.chars().all(|c| c.is_ascii_digit())
+        {
+            res.push(PasswordViolation::NumericOnly);
+        }
+        if res.is_empty() {
+            Ok(())
+        } else {
+            Err(res)
+        }
+    }
+
+    /// `set_time` is when the password was set (ms), 0 if unknown, e.g. set by
+    /// an older version, which is not expired.
+    pub fn is_expired(&self, set_time: i64, now: i64) -> bool {
+        if self.expiry_days == 0 || set_time == 0 {
+            return false;
+        }
+        now - set_time > self.expiry_days as i64 * 24 * 3600 * 1000
+    }
+}
+
 const VERSION_LEN: usize = 2;
+// Every format gets its own version, old versions are only decrypted and then
+// stored again with the current one.
+// "00": secretbox keyed by the machine uuid with a zero nonce.
+// "01": secretbox with a random nonce, the key is derived from the machine
+// uuid with Argon2id. The parameters and salt are stored with the data, so that
+// changing them does not break old data.
+// "02": as "01" with XChaCha20-Poly1305, the parameters and salt authenticated
+// as associated data.
+// "03": as "02" chunked with secretstream.
+const VERSION_ARGON2ID: &str = "01";
+const VERSION_XCHACHA: &str = "02";
+const VERSION_AEAD: &str = "03";
+const KDF_HEADER_LEN: usize = 8 + argon2id13::SALTBYTES;
+const CHUNK_SIZE: usize = 4096;
+// Refuse crafted data asking for too much time or memory, each field would
+// otherwise block the loading of the config.
+const MAX_KDF_OPS_LIMIT: u32 = 8;
+const MAX_KDF_MEM_LIMIT: u32 = 256 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KdfParams {
+    pub ops_limit: u32,
+    // in bytes
+    pub mem_limit: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            ops_limit: argon2id13::OPSLIMIT_INTERACTIVE.0 as _,
+            mem_limit: argon2id13::MEMLIMIT_INTERACTIVE.0 as _,
+        }
+    }
+}
+
+impl KdfParams {
+    fn is_valid(&self) -> bool {
+        (1..=MAX_KDF_OPS_LIMIT).contains(&self.ops_limit)
+            && (8192..=MAX_KDF_MEM_LIMIT).contains(&self.mem_limit)
+    }
+}
+
+/// Cost of the key derivation for newly encrypted data,
+/// e.g. lower memory cost on mobile devices.
+pub fn set_kdf_params(params: KdfParams) {
+    if !params.is_valid() {
+        log::error!("Invalid kdf params: {:?}", params);
+        return;
+    }
+    *KDF_PARAMS.write().unwrap() = params;
+}
+
+pub fn kdf_params() -> KdfParams {
+    *KDF_PARAMS.read().unwrap()
+}
+
+/// Argon2id verification hash of a password or PIN, empty for empty input.
+pub fn hash_secret(secret: &str) -> String {
+    if secret.is_empty() {
+        return String::default();
+    }
+    let params = kdf_params();
+    match argon2id13::pwhash(
+        secret.as_bytes(),
+        argon2id13::OpsLimit(params.ops_limit as _),
+        argon2id13::MemLimit(params.mem_limit as _),
+    ) {
+        Ok(hash) => String::from_utf8_lossy(&hash.0)
+            .trim_end_matches('\0')
+            .to_owned(),
+        Err(_) => {
+            log::error!("Failed to hash secret");
+            String::default()
+        }
+    }
+}
 
+pub fn verify_secret_hash(hash: &str, secret: &str) -> bool {
+    if hash.is_empty() || hash.len() >= argon2id13::HASHEDPASSWORDBYTES {
+        return false;
+    }
+    let mut buf = [0u8; argon2id13::HASHEDPASSWORDBYTES];
+    buf[..hash.len()].copy_from_slice(hash.as_bytes());
+    argon2id13::pwhash_verify(&argon2id13::HashedPassword(buf), secret.as_bytes())
+}
+
+fn is_supported_version(version: &str) -> bool {
+    version == "00"
+        || version == VERSION_ARGON2ID
+        || version == VERSION_XCHACHA
+        || version == VERSION_AEAD
+}
+
+// "01" and "02" are only read, new data is never sealed with them.
+fn is_encryptable_version(version: &str) -> bool {
+    version == "00" || version == VERSION_AEAD
+}
+
+// max_len only limits the legacy version "00", "03" is chunked and has no limit.
 pub fn encrypt_str_or_original(s: &str, version: &str, max_len: usize) -> String {
-    if decrypt_str_or_original(s, version).1 {
+    let (mut plain, succ, _) = decrypt_str_or_original(s, version);
+    plain.zeroize();
+    if succ {
         log::error!("Duplicate encryption!");
         return s.to_owned();
     }
-    if s.chars().count() > max_len {
+    if version != VERSION_AEAD && s.chars().count() > max_len {
         return String::default();
     }
-    if version == "00" {
-        if let Ok(s) = encrypt(s.as_bytes()) {
+    if is_encryptable_version(version) {
+        if let Ok(s) = encrypt(s.as_bytes(), version) {
             return version.to_owned() + &s;
         }
     }
@@ -115,13 +376,15 @@ pub fn encrypt_str_or_original(s: &str, version: &str, max_len: usize) -> String
 // note: s.len() return length in bytes, s.chars().count() return char count
 //       &[..2] return the left 2 bytes, s.chars().take(2) return the left 2 chars
 pub fn decrypt_str_or_original(s: &str, current_version: &str) -> (String, bool, bool) {
-    if s.len() > VERSION_LEN {
-        if s.starts_with("00") {
-            if let Ok(v) = decrypt(s[VERSION_LEN..].as_bytes()) {
+    if s.len() > VERSION_LEN && s.is_char_boundary(VERSION_LEN) {
+        let version = &s[..VERSION_LEN];
+        if is_supported_version(version) {
+            if let Ok((v, fallback)) = decrypt(s[VERSION_LEN..].as_bytes(), version) {
+                let v = Zeroizing::new(v);
                 return (
                     String::from_utf8_lossy(&v).to_string(),
                     true,
-                    "00" != current_version,
+                    version != current_version || fallback,
                 );
             }
         }
@@ -131,15 +394,17 @@ pub fn decrypt_str_or_original(s: &str, current_version: &str) -> (String, bool,
 }
 
 pub fn encrypt_vec_or_original(v: &[u8], version: &str, max_len: usize) -> Vec<u8> {
-    if decrypt_vec_or_original(v, version).1 {
+    let (mut plain, succ, _) = decrypt_vec_or_original(v, version);
+    plain.zeroize();
+    if succ {
         log::error!("Duplicate encryption!");
         return v.to_owned();
     }
-    if v.len() > max_len {
+    if version != VERSION_AEAD && v.len() > max_len {
         return vec![];
     }
-    if version == "00" {
-        if let Ok(s) = encrypt(v) {
+    if is_encryptable_version(version) {
+        if let Ok(s) = encrypt(v, version) {
             let mut version = version.to_owned().into_bytes();
             version.append(&mut s.into_bytes());
             return version;
@@ -154,9 +419,9 @@ pub fn encrypt_vec_or_original(v: &[u8], version: &str, max_len: usize) -> Vec<u
 pub fn decrypt_vec_or_original(v: &[u8], current_version: &str) -> (Vec<u8>, bool, bool) {
     if v.len() > VERSION_LEN {
         let version = String::from_utf8_lossy(&v[..VERSION_LEN]);
-        if version == "00" {
-            if let Ok(v) = decrypt(&v[VERSION_LEN..]) {
-                return (v, true, version != current_version);
+        if is_supported_version(&version) {
+            if let Ok((v, fallback)) = decrypt(&v[VERSION_LEN..], &version) {
+                return (v, true, version != current_version || fallback);
             }
         }
     }
@@ -164,19 +429,175 @@ pub fn decrypt_vec_or_original(v: &[u8], current_version: &str) -> (Vec<u8>, boo
     (v.to_owned(), false, !v.is_empty())
 }
 
-fn encrypt(v: &[u8]) -> Result<String, ()> {
-    if !v.is_empty() {
-        symmetric_crypt(v, true).map(|v| base64::encode(v, base64::Variant::Original))
+fn encrypt(v: &[u8], version: &str) -> Result<String, ()> {
+    if v.is_empty() {
+        return Err(());
+    }
+    let data = if version == VERSION_AEAD {
+        aead_crypt(v, true)?
+    } else {
+        symmetric_crypt(v, true)?
+    };
+    Ok(base64::encode(data, base64::Variant::Original))
+}
+
+// bool: whether decrypted with the fallback key, to be encrypted again
+fn decrypt(v: &[u8], version: &str) -> Result<(Vec<u8>, bool), ()> {
+    if v.is_empty() {
+        return Err(());
+    }
+    let v = base64::decode(v, base64::Variant::Original)?;
+    let run = || {
+        if version == VERSION_AEAD {
+            aead_crypt(&v, false)
+        } else if version == VERSION_ARGON2ID || version == VERSION_XCHACHA {
+            legacy_kdf_open(&v, version)
+        } else {
+            symmetric_crypt(&v, false)
+        }
+    };
+    match run() {
+        Ok(v) => Ok((v, false)),
+        Err(_) => with_fallback_key(run).map(|v| (v, true)).ok_or(()),
+    }
+}
+
+fn encryption_key() -> Zeroizing<Vec<u8>> {
+    KEY_OVERRIDE
+        .with(|k| k.borrow().clone())
+        .unwrap_or_else(|| Zeroizing::new(config_key()))
+}
+
+// The key of the encrypted fields, not `get_uuid` on Android once a keystore is
+// registered, see `keystore`. Never to be exposed as the uuid.
+fn config_key() -> Vec<u8> {
+    #[cfg(target_os = "android")]
+    if let Some(key) = crate::keystore::config_key() {
+        return key;
+    }
+    crate::get_uuid()
+}
+
+// Runs `f` with the key used before the keystore was registered, None if there
+// is none or `f` fails.
+fn with_fallback_key<R>(f: impl FnOnce() -> Result<R, ()>) -> Option<R> {
+    if KEY_OVERRIDE.with(|k| k.borrow().is_some()) {
+        return None;
+    }
+    let key = crate::keystore::fallback_key()?;
+    KEY_OVERRIDE.with(|k| *k.borrow_mut() = Some(Zeroizing::new(key)));
+    let res = f();
+    KEY_OVERRIDE.with(|k| *k.borrow_mut() = None);
+    res.ok()
+}
+
+fn derive_key(params: KdfParams, salt: &[u8]) -> Result<secretstream::Key, ()> {
+    if !params.is_valid() {
+        return Err(());
+    }
+    let uuid = encryption_key();
+    let cache_key = (params, salt.to_vec(), uuid.to_vec());
+    if let Some(key) = KDF_KEYS.lock().unwrap().get(&cache_key) {
+        return Ok(key.clone());
+    }
+    let salt = argon2id13::Salt::from_slice(salt).ok_or(())?;
+    let mut key = secretstream::Key([0; secretstream::KEYBYTES]);
+    argon2id13::derive_key(
+        &mut key.0,
+        &uuid,
+        &salt,
+        argon2id13::OpsLimit(params.ops_limit as _),
+        argon2id13::MemLimit(params.mem_limit as _),
+    )?;
+    KDF_KEYS.lock().unwrap().insert(cache_key, key.clone());
+    Ok(key)
+}
+
+// ops_limit (u32 le) | mem_limit (u32 le) | salt | stream header | chunks
+// Chunked with secretstream, so there is no length limit and truncated or
+// reordered chunks are detected.
+fn aead_crypt(data: &[u8], encrypt: bool) -> Result<Vec<u8>, ()> {
+    use secretstream::{Header, Stream, Tag, ABYTES, HEADERBYTES};
+    use std::convert::TryInto;
+
+    if encrypt {
+        let params = kdf_params();
+        let key = derive_key(params, &KDF_SALT)?;
+        let mut out = Vec::with_capacity(
+            KDF_HEADER_LEN + HEADERBYTES + data.len() + (data.len() / CHUNK_SIZE + 1) * ABYTES,
+        );
+        out.extend_from_slice(&params.ops_limit.to_le_bytes());
+        out.extend_from_slice(&params.mem_limit.to_le_bytes());
+        out.extend_from_slice(&KDF_SALT);
+        let ad = out.clone();
+        let (mut stream, header) = Stream::init_push(&key)?;
+        out.extend_from_slice(&header.0);
+        let mut chunks = data.chunks(CHUNK_SIZE).peekable();
+        while let Some(chunk) = chunks.next() {
+            let tag = if chunks.peek().is_none() {
+                Tag::Final
+            } else {
+                Tag::Message
+            };
+            out.extend(stream.push(chunk, Some(&ad), tag)?);
+        }
+        Ok(out)
     } else {
-        Err(())
+        if data.len() <= KDF_HEADER_LEN + HEADERBYTES {
+            return Err(());
+        }
+        let params = KdfParams {
+            ops_limit: u32::from_le_bytes(data[0..4].try_into().map_err(|_| ())?),
+            mem_limit: u32::from_le_bytes(data[4..8].try_into().map_err(|_| ())?),
+        };
+        let (ad, data) = data.split_at(KDF_HEADER_LEN);
+        let (header, data) = data.split_at(HEADERBYTES);
+        let header = Header::from_slice(header).ok_or(())?;
+        let key = derive_key(params, &ad[8..])?;
+        let mut stream = Stream::init_pull(&header, &key)?;
+        let mut out = Vec::with_capacity(data.len());
+        for chunk in data.chunks(CHUNK_SIZE + ABYTES) {
+            if stream.is_finalized() {
+                return Err(());
+            }
+            let (mut m, _) = stream.pull(chunk, Some(ad))?;
+            out.extend_from_slice(&m);
+            m.zeroize();
+        }
+        if !stream.is_finalized() {
+            // truncated
+            out.zeroize();
+            return Err(());
+        }
+        Ok(out)
     }
 }
 
-fn decrypt(v: &[u8]) -> Result<Vec<u8>, ()> {
-    if !v.is_empty() {
-        base64::decode(v, base64::Variant::Original).and_then(|v| symmetric_crypt(&v, false))
+// ops_limit (u32 le) | mem_limit (u32 le) | salt | nonce | ciphertext,
+// of the versions "01" and "02", only decrypted.
+fn legacy_kdf_open(data: &[u8], version: &str) -> Result<Vec<u8>, ()> {
+    use sodiumoxide::crypto::{aead::xchacha20poly1305_ietf as aead, secretbox};
+    use std::convert::TryInto;
+
+    if data.len() <= KDF_HEADER_LEN + secretbox::NONCEBYTES {
+        return Err(());
+    }
+    let params = KdfParams {
+        ops_limit: u32::from_le_bytes(data[0..4].try_into().map_err(|_| ())?),
+        mem_limit: u32::from_le_bytes(data[4..8].try_into().map_err(|_| ())?),
+    };
+    let (ad, data) = data.split_at(KDF_HEADER_LEN);
+    let key = derive_key(params, &ad[8..])?;
+    if version == VERSION_ARGON2ID {
+        let (nonce, data) = data.split_at(secretbox::NONCEBYTES);
+        let nonce = secretbox::Nonce::from_slice(nonce).ok_or(())?;
+        let key = secretbox::Key::from_slice(&key.0).ok_or(())?;
+        secretbox::open(data, &nonce, &key)
     } else {
-        Err(())
+        let (nonce, data) = data.split_at(aead::NONCEBYTES);
+        let nonce = aead::Nonce::from_slice(nonce).ok_or(())?;
+        let key = aead::Key::from_slice(&key.0).ok_or(())?;
+        aead::open(data, Some(ad), &nonce, &key)
     }
 }
 
@@ -184,20 +605,214 @@ pub fn symmetric_crypt(data: &[u8], encrypt: bool) -> Result<Vec<u8>, ()> {
     use sodiumoxide::crypto::secretbox;
     use std::convert::TryInto;
 
-    let mut keybuf = crate::get_uuid();
+    let mut keybuf = encryption_key();
     keybuf.resize(secretbox::KEYBYTES, 0);
-    let key = secretbox::Key(keybuf.try_into().map_err(|_| ())?);
+    let key = secretbox::Key(keybuf.as_slice().try_into().map_err(|_| ())?);
     let nonce = secretbox::Nonce([0; secretbox::NONCEBYTES]);
 
     if encrypt {
         Ok(secretbox::seal(data, &nonce, &key))
     } else {
         secretbox::open(data, &nonce, &key)
+            .or_else(|_| with_fallback_key(|| symmetric_crypt(data, false)).ok_or(()))
     }
 }
 
 mod test {
 
+    #[test]
+    fn test_aead() {
+        use super::*;
+
+        set_kdf_params(KdfParams {
+            ops_limit: 1,
+            mem_limit: 1 << 20,
+        });
+        let data = "1ü1111";
+        let encrypted = encrypt_str_or_original(data, VERSION_AEAD, 128);
+        assert_eq!(VERSION_AEAD, &encrypted[..2]);
+        assert_ne!(encrypted, encrypt_str_or_original(data, VERSION_AEAD, 128));
+        let (decrypted, succ, store) = decrypt_str_or_original(&encrypted, VERSION_AEAD);
+        assert_eq!(data, decrypted);
+        assert!(succ);
+        assert!(!store);
+        // old data still decrypts and is marked to be stored again
+        let old = encrypt_str_or_original(data, "00", 128);
+        let (decrypted, succ, store) = decrypt_str_or_original(&old, VERSION_AEAD);
+        assert_eq!(data, decrypted);
+        assert!(succ);
+        assert!(store);
+        // longer than max_len and several chunks
+        let long = "ü".repeat(CHUNK_SIZE * 2 + 7);
+        let encrypted = encrypt_str_or_original(&long, VERSION_AEAD, 128);
+        assert_eq!(decrypt_str_or_original(&encrypted, VERSION_AEAD).0, long);
+        let raw = base64::decode(&encrypted[2..], base64::Variant::Original).unwrap();
+        let truncated = VERSION_AEAD.to_owned()
+            + &base64::encode(
+                &raw[..raw.len() - (CHUNK_SIZE + secretstream::ABYTES)],
+                base64::Variant::Original,
+            );
+        assert!(!decrypt_str_or_original(&truncated, VERSION_AEAD).1);
+        // header is authenticated
+        let mut raw = base64::decode(&encrypted[2..], base64::Variant::Original).unwrap();
+        raw[0] ^= 2;
+        let tampered = VERSION_AEAD.to_owned() + &base64::encode(raw, base64::Variant::Original);
+        assert!(!decrypt_str_or_original(&tampered, VERSION_AEAD).1);
+        let data = data.as_bytes().to_vec();
+        let encrypted = encrypt_vec_or_original(&data, VERSION_AEAD, 128);
+        assert_eq!(decrypt_vec_or_original(&encrypted, VERSION_AEAD).0, data);
+        // crafted costs are refused before deriving
+        let mut raw = base64::decode(&encrypted[2..], base64::Variant::Original).unwrap();
+        raw[0..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(aead_crypt(&raw, false).is_err());
+        assert!(!KdfParams {
+            ops_limit: 1,
+            mem_limit: u32::MAX,
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn test_encrypt_versions() {
+        use super::*;
+
+        set_kdf_params(KdfParams {
+            ops_limit: 1,
+            mem_limit: 1 << 20,
+        });
+        for version in ["00", VERSION_AEAD] {
+            let encrypted = encrypt_str_or_original("data", version, 128);
+            assert_eq!(&encrypted[..2], version);
+            let (decrypted, succ, store) = decrypt_str_or_original(&encrypted, version);
+            assert_eq!(decrypted, "data");
+            assert!(succ);
+            assert!(!store);
+            let encrypted = encrypt_vec_or_original(b"data", version, 128);
+            let (decrypted, succ, store) = decrypt_vec_or_original(&encrypted, version);
+            assert_eq!(decrypted, b"data");
+            assert!(succ);
+            assert!(!store);
+        }
+        for version in [VERSION_ARGON2ID, VERSION_XCHACHA] {
+            assert_eq!(encrypt_str_or_original("data", version, 128), "data");
+            assert_eq!(encrypt_vec_or_original(b"data", version, 128), b"data");
+        }
+    }
+
+    #[test]
+    fn test_legacy_versions() {
+        use super::*;
+        use sodiumoxide::crypto::{aead::xchacha20poly1305_ietf as aead, secretbox};
+
+        let params = KdfParams {
+            ops_limit: 1,
+            mem_limit: 1 << 20,
+        };
+        let key = derive_key(params, &KDF_SALT).unwrap();
+        let mut header = params.ops_limit.to_le_bytes().to_vec();
+        header.extend_from_slice(&params.mem_limit.to_le_bytes());
+        header.extend_from_slice(&KDF_SALT);
+        let encode = |version: &str, data: Vec<u8>| {
+            version.to_owned() + &base64::encode(data, base64::Variant::Original)
+        };
+
+        let nonce = secretbox::gen_nonce();
+        let mut v1 = header.clone();
+        v1.extend_from_slice(&nonce.0);
+        v1.extend(secretbox::seal(
+            b"v1",
+            &nonce,
+            &secretbox::Key::from_slice(&key.0).unwrap(),
+        ));
+        let nonce = aead::gen_nonce();
+        let mut v2 = header.clone();
+        v2.extend_from_slice(&nonce.0);
+        v2.extend(aead::seal(
+            b"v2",
+            Some(&header),
+            &nonce,
+            &aead::Key::from_slice(&key.0).unwrap(),
+        ));
+        for (s, plain) in [(encode("01", v1), "v1"), (encode("02", v2), "v2")] {
+            let (decrypted, succ, store) = decrypt_str_or_original(&s, VERSION_AEAD);
+            assert_eq!(decrypted, plain);
+            assert!(succ);
+            assert!(store);
+        }
+    }
+
+    #[test]
+    fn test_secret_hash() {
+        use super::*;
+
+        set_kdf_params(KdfParams {
+            ops_limit: 1,
+            mem_limit: 1 << 20,
+        });
+        let hash = hash_secret("123456");
+        assert!(hash.starts_with("$argon2id$"));
+        assert_ne!(hash, hash_secret("123456"));
+        assert!(verify_secret_hash(&hash, "123456"));
+        assert!(!verify_secret_hash(&hash, "1234567"));
+        assert!(!verify_secret_hash("", ""));
+        assert!(hash_secret("").is_empty());
+    }
+
+    #[test]
+    fn test_password_policy() {
+        use super::*;
+
+        let policy = PasswordPolicy {
+            min_length: 8,
+            require_upper: true,
+            require_symbol: true,
+            disallow_numeric: true,
+            expiry_days: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.check("1234"),
+            Err(vec![
+                PasswordViolation::TooShort(8),
+                PasswordViolation::MissingUpper,
+                PasswordViolation::MissingSymbol,
+                PasswordViolation::NumericOnly,
+            ])
+        );
+        assert!(policy.check("Abcdefg!").is_ok());
+        assert!(PasswordPolicy::default().check("1").is_ok());
+        assert!(!policy.is_expired(1, 24 * 3600 * 1000 + 1));
+        assert!(policy.is_expired(1, 24 * 3600 * 1000 + 2));
+        assert!(!policy.is_expired(0, i64::MAX));
+        assert!(!PasswordPolicy::default().is_expired(1, i64::MAX));
+    }
+
+    #[test]
+    fn test_policy_setters() {
+        use super::*;
+        use crate::config::test_config;
+
+        let _t = test_config();
+        OVERWRITE_SETTINGS.write().unwrap().extend([
+            (keys::OPTION_PASSWORD_MIN_LENGTH.to_owned(), "10".to_owned()),
+            (
+                keys::OPTION_PASSWORD_REQUIRED_CLASSES.to_owned(),
+                "upper,symbol".to_owned(),
+            ),
+        ]);
+        let policy = PasswordPolicy::get();
+        for _ in 0..10 {
+            assert!(policy.check(&get_auto_password()).is_ok());
+        }
+        assert!(Config::set_permanent_password_checked("short").is_err());
+        Config::set_permanent_password("short");
+        assert_eq!(Config::get_permanent_password(), "");
+        let valid = "Abcdefghij!";
+        assert!(Config::set_permanent_password_checked(valid).is_ok());
+        assert!(Config::verify_permanent_password(valid));
+        assert!(!Config::is_permanent_password_expired());
+    }
+
     #[test]
     fn test() {
         use super::*;
@@ -0,0 +1,73 @@
+// FreeBSD, OpenBSD and NetBSD, e.g. jump hosts. There is no systemd, so there is
+// no loginctl to look up sessions, the display server is told by the
+// environment instead.
+use std::{ffi::CStr, path::PathBuf};
+
+pub const DISPLAY_SERVER_WAYLAND: &str = "wayland";
+pub const DISPLAY_SERVER_X11: &str = "x11";
+
+/// The home of the user behind root, e.g. who ran `sudo` or `su` without `-`, in
+/// the password database. None if not run as root, or by root itself.
+pub fn user_home() -> Option<PathBuf> {
+    if unsafe { libc::geteuid() } != 0 {
+        return None;
+    }
+    let name = std::env::var("SUDO_USER")
+        .ok()
+        .filter(|x| !x.is_empty())
+        .or_else(login_name)?;
+    if name == "root" {
+        return None;
+    }
+    home_of(&name)
+}
+
+/// The user logged in on the controlling terminal.
+fn login_name() -> Option<String> {
+    let name = unsafe { libc::getlogin() };
+    if name.is_null() {
+        return None;
+    }
+    Some(
+        unsafe { CStr::from_ptr(name) }
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+fn home_of(name: &str) -> Option<PathBuf> {
+    let name = std::ffi::CString::new(name).ok()?;
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut res: *mut libc::passwd = std::ptr::null_mut();
+    let ret = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut pwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut res,
+        )
+    };
+    if ret != 0 || res.is_null() || pwd.pw_dir.is_null() {
+        return None;
+    }
+    let dir = unsafe { CStr::from_ptr(pwd.pw_dir) };
+    Some(PathBuf::from(dir.to_string_lossy().into_owned()))
+}
+
+/// Empty if headless.
+pub fn get_display_server() -> String {
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        DISPLAY_SERVER_WAYLAND.to_owned()
+    } else if std::env::var_os("DISPLAY").is_some() {
+        DISPLAY_SERVER_X11.to_owned()
+    } else {
+        "".to_owned()
+    }
+}
+
+#[inline]
+pub fn is_x11_or_headless() -> bool {
+    get_display_server() != DISPLAY_SERVER_WAYLAND
+}
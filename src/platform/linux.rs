@@ -136,6 +136,42 @@ pub fn get_display_server() -> String {
     }
 }
 
+/// See `super::display_environment`.
+pub fn display_environment() -> super::DisplayEnvironment {
+    use super::DisplayServer;
+    // the greeter on Wayland is not skipped, it is what `x11_required` is about
+    let values = get_values_of_seat0_with_gdm_wayland(&[0, 2]);
+    let (session_id, username) = (values[0].clone(), values[1].clone());
+    let session_type = if let Ok(forced) = std::env::var("RUSTDESK_FORCED_DISPLAY_SERVER") {
+        forced
+    } else if session_id.is_empty() {
+        "".to_owned()
+    } else {
+        run_loginctl(Some(vec!["show-session", "-p", "Type", &session_id]))
+            .map(|output| {
+                String::from_utf8_lossy(&output.stdout)
+                    .replace("Type=", "")
+                    .trim()
+                    .to_lowercase()
+            })
+            .unwrap_or_default()
+    };
+    let server = match session_type.as_str() {
+        DISPLAY_SERVER_X11 => DisplayServer::X11,
+        DISPLAY_SERVER_WAYLAND => DisplayServer::Wayland,
+        // e.g. startx in a tty, which logind does not know about
+        _ => DisplayServer::from_env(),
+    };
+    super::DisplayEnvironment {
+        server,
+        login_screen: is_gdm_user(&username),
+        session_type,
+        session_id,
+        username,
+        desktop: std::env::var(XDG_CURRENT_DESKTOP).unwrap_or_default(),
+    }
+}
+
 pub fn get_display_server_of_session(session: &str) -> String {
     let mut display_server = if let Ok(output) =
         run_loginctl(Some(vec!["show-session", "-p", "Type", session]))
@@ -350,4 +386,114 @@ mod tests {
             run_cmds("whoami").unwrap()
         );
     }
+
+    #[test]
+    fn test_is_on_battery() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let supply = |name: &str, files: &[(&str, &str)]| {
+            let p = dir.join(name);
+            std::fs::create_dir_all(&p).unwrap();
+            for (k, v) in files {
+                std::fs::write(p.join(k), v).unwrap();
+            }
+        };
+        assert!(!is_on_battery(dir));
+        supply(
+            "hid-mouse-battery",
+            &[("type", "Battery\n"), ("scope", "Device\n")],
+        );
+        assert!(!is_on_battery(dir));
+        supply("BAT0", &[("type", "Battery\n")]);
+        supply("AC", &[("type", "Mains\n"), ("online", "1\n")]);
+        assert!(!is_on_battery(dir));
+        supply("AC", &[("online", "0\n")]);
+        assert!(is_on_battery(dir));
+    }
+}
+
+/// The group of `systemd-inhibit` and its command.
+struct Inhibitor(std::process::Child);
+
+impl Drop for Inhibitor {
+    fn drop(&mut self) {
+        // killing systemd-inhibit alone would leave its command running
+        unsafe { libc::killpg(self.0.id() as _, libc::SIGTERM) };
+        self.0.wait().ok();
+    }
+}
+
+/// See `super::keep_awake`, a logind inhibitor lock held by `systemd-inhibit`
+/// until its group is killed, shown with the reason by `systemd-inhibit --list`.
+/// Its command is `cat` reading a pipe of this process, so it also ends if this
+/// process dies without dropping the guard.
+pub fn keep_awake(reason: &str) -> ResultType<Box<dyn std::any::Any + Send>> {
+    use std::{os::unix::process::CommandExt, process::Stdio};
+    let app = crate::config::APP_NAME.read().unwrap().clone();
+    let child = Command::new("systemd-inhibit")
+        .arg("--what=sleep:idle")
+        .arg(format!("--who={}", app))
+        .arg(format!("--why={}", reason))
+        .arg("--mode=block")
+        .arg("cat")
+        .process_group(0)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    Ok(Box::new(Inhibitor(child)))
+}
+
+/// See `super::network_profile`, the metered state guessed by NetworkManager,
+/// e.g. for a tethered phone, and the power supplies of sysfs.
+pub fn network_profile() -> super::NetworkProfile {
+    super::NetworkProfile {
+        metered: is_metered(),
+        on_battery: is_on_battery(std::path::Path::new("/sys/class/power_supply")),
+    }
+}
+
+fn is_metered() -> bool {
+    // NMMetered: 1 yes, 3 guessed yes
+    let Ok(output) = Command::new("busctl")
+        .args(&[
+            "get-property",
+            "org.freedesktop.NetworkManager",
+            "/org/freedesktop/NetworkManager",
+            "org.freedesktop.NetworkManager",
+            "Metered",
+        ])
+        .output()
+    else {
+        return false;
+    };
+    let v = String::from_utf8_lossy(&output.stdout);
+    output.status.success() && matches!(v.trim(), "u 1" | "u 3")
+}
+
+/// On battery if there is a battery and no mains supply is online.
+fn is_on_battery(dir: &std::path::Path) -> bool {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return false;
+    };
+    let read = |p: std::path::PathBuf| std::fs::read_to_string(p).unwrap_or_default();
+    let mut has_battery = false;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match read(path.join("type")).trim() {
+            "Mains" | "USB" => {
+                if read(path.join("online")).trim() == "1" {
+                    return false;
+                }
+            }
+            "Battery" => {
+                // the batteries of mice and keyboards do not power the machine
+                if read(path.join("scope")).trim() != "Device" {
+                    has_battery = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    has_battery
 }
@@ -53,3 +53,78 @@ pub fn alert(
     })?;
     Ok(result.button)
 }
+
+#[link(name = "IOKit", kind = "framework")]
+extern "C" {
+    fn IOPMAssertionCreateWithName(
+        assertion_type: core_foundation::string::CFStringRef,
+        level: u32,
+        name: core_foundation::string::CFStringRef,
+        id: *mut u32,
+    ) -> i32;
+    fn IOPMAssertionRelease(id: u32) -> i32;
+}
+
+// kIOPMAssertionLevelOn
+const ASSERTION_LEVEL_ON: u32 = 255;
+
+struct PowerAssertion(u32);
+
+impl Drop for PowerAssertion {
+    fn drop(&mut self) {
+        unsafe { IOPMAssertionRelease(self.0) };
+    }
+}
+
+/// See `super::keep_awake`, an IOPMAssertion as held by `caffeinate -i`, shown
+/// with the reason by `pmset -g assertions`.
+pub fn keep_awake(reason: &str) -> ResultType<Box<dyn std::any::Any + Send>> {
+    use core_foundation::{base::TCFType, string::CFString};
+    let typ = CFString::new("PreventUserIdleSystemSleep");
+    let name = CFString::new(reason);
+    let mut id = 0u32;
+    let res = unsafe {
+        IOPMAssertionCreateWithName(
+            typ.as_concrete_TypeRef(),
+            ASSERTION_LEVEL_ON,
+            name.as_concrete_TypeRef(),
+            &mut id,
+        )
+    };
+    if res != 0 {
+        crate::bail!("IOPMAssertionCreateWithName failed: {}", res);
+    }
+    Ok(Box::new(PowerAssertion(id)))
+}
+
+#[link(name = "IOKit", kind = "framework")]
+extern "C" {
+    fn IOPSCopyPowerSourcesInfo() -> core_foundation::base::CFTypeRef;
+    fn IOPSGetProvidingPowerSourceType(
+        snapshot: core_foundation::base::CFTypeRef,
+    ) -> core_foundation::string::CFStringRef;
+}
+
+/// See `super::network_profile`. Whether the network is metered is only known to
+/// NWPath of the app, which reports it with `super::set_network_profile`.
+pub fn network_profile() -> super::NetworkProfile {
+    use core_foundation::{
+        base::{CFType, TCFType},
+        string::CFString,
+    };
+    let on_battery = unsafe {
+        let snapshot = IOPSCopyPowerSourcesInfo();
+        if snapshot.is_null() {
+            false
+        } else {
+            let snapshot = CFType::wrap_under_create_rule(snapshot);
+            let typ = IOPSGetProvidingPowerSourceType(snapshot.as_CFTypeRef());
+            // kIOPMBatteryPowerKey
+            !typ.is_null() && CFString::wrap_under_get_rule(typ).to_string() == "Battery Power"
+        }
+    };
+    super::NetworkProfile {
+        metered: false,
+        on_battery,
+    }
+}
@@ -1,6 +1,9 @@
 #[cfg(target_os = "linux")]
 pub mod linux;
 
+#[cfg(any(target_os = "freebsd", target_os = "openbsd", target_os = "netbsd"))]
+pub mod bsd;
+
 #[cfg(target_os = "macos")]
 pub mod macos;
 
@@ -66,6 +69,8 @@ extern "C" fn breakdown_signal_handler(sig: i32) {
             callback()
         }
     }
+    // e.g. the options set above
+    crate::config::flush_pending_stores();
     exit(0);
 }
 
@@ -79,3 +84,397 @@ where
         libc::signal(libc::SIGSEGV, breakdown_signal_handler as _);
     }
 }
+
+/// How the process was started, which decides where its config is stored if
+/// `Config::set_config_root` is not called.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceContext {
+    User,
+    /// Running as SYSTEM, e.g. by the service control manager.
+    WindowsService,
+    /// Started by systemd as a system unit, not a user unit.
+    SystemdUnit,
+    /// Started by launchd as a daemon, not an agent.
+    LaunchdDaemon,
+}
+
+#[cfg(windows)]
+pub fn service_context() -> ServiceContext {
+    // the profile of SYSTEM, the account of services
+    let is_system = dirs_next::home_dir().map_or(false, |home| {
+        home.to_string_lossy()
+            .to_lowercase()
+            .ends_with("system32\\config\\systemprofile")
+    });
+    if is_system {
+        ServiceContext::WindowsService
+    } else {
+        ServiceContext::User
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn service_context() -> ServiceContext {
+    // set by systemd for the processes of a unit, root excludes user units
+    if std::env::var_os("INVOCATION_ID").is_some() && unsafe { libc::geteuid() } == 0 {
+        ServiceContext::SystemdUnit
+    } else {
+        ServiceContext::User
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn service_context() -> ServiceContext {
+    // agents run as the user, daemons as root with launchd as parent
+    if unsafe { libc::geteuid() == 0 && libc::getppid() == 1 } {
+        ServiceContext::LaunchdDaemon
+    } else {
+        ServiceContext::User
+    }
+}
+
+#[cfg(not(any(windows, target_os = "linux", target_os = "macos")))]
+pub fn service_context() -> ServiceContext {
+    ServiceContext::User
+}
+
+/// The packaging confining the app, whose view of the file system and /tmp is not
+/// the one of the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SandboxKind {
+    Flatpak,
+    Snap,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SandboxInfo {
+    /// None if not confined.
+    pub kind: Option<SandboxKind>,
+    pub app_id: String,
+    /// Writable directories of the app, used instead of the ones of the host.
+    pub config_dir: Option<std::path::PathBuf>,
+    pub data_dir: Option<std::path::PathBuf>,
+    /// Private to the user and the app, for the IPC sockets.
+    pub runtime_dir: Option<std::path::PathBuf>,
+}
+
+impl SandboxInfo {
+    #[inline]
+    pub fn is_sandboxed(&self) -> bool {
+        self.kind.is_some()
+    }
+
+    /// Whether /tmp is the one of the host, so other processes can reach a socket
+    /// created there.
+    #[inline]
+    pub fn has_shared_tmp(&self) -> bool {
+        self.kind.is_none()
+    }
+
+    /// Whether a system service can be installed and managed, e.g. with systemctl.
+    #[inline]
+    pub fn can_install_service(&self) -> bool {
+        self.kind.is_none()
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref SANDBOX_INFO: SandboxInfo = detect_sandbox(
+        |k| std::env::var_os(k).filter(|v| !v.is_empty()),
+        std::path::Path::new("/.flatpak-info").exists(),
+    );
+}
+
+/// Detected once, the environment of a sandbox does not change.
+pub fn sandbox_info() -> &'static SandboxInfo {
+    &SANDBOX_INFO
+}
+
+#[cfg(target_os = "linux")]
+fn detect_sandbox<F>(env: F, has_flatpak_info: bool) -> SandboxInfo
+where
+    F: Fn(&str) -> Option<std::ffi::OsString>,
+{
+    use std::path::PathBuf;
+    let dir = |k: &str| env(k).map(PathBuf::from);
+    if has_flatpak_info || env("FLATPAK_ID").is_some() {
+        let app_id = env("FLATPAK_ID")
+            .map(|v| v.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        // XDG_* point into ~/.var/app/<id>, $XDG_RUNTIME_DIR/app/<id> is shared
+        // with the host
+        return SandboxInfo {
+            kind: Some(SandboxKind::Flatpak),
+            config_dir: dir("XDG_CONFIG_HOME"),
+            data_dir: dir("XDG_DATA_HOME"),
+            runtime_dir: dir("XDG_RUNTIME_DIR").map(|d| d.join("app").join(&app_id)),
+            app_id,
+        };
+    }
+    if let (Some(_), Some(name)) = (env("SNAP"), env("SNAP_NAME")) {
+        // SNAP_USER_DATA is per revision, SNAP_USER_COMMON is kept across them,
+        // XDG_RUNTIME_DIR is /run/user/<uid>/snap.<name>
+        return SandboxInfo {
+            kind: Some(SandboxKind::Snap),
+            app_id: name.to_string_lossy().into_owned(),
+            config_dir: dir("SNAP_USER_DATA").map(|d| d.join(".config")),
+            data_dir: dir("SNAP_USER_COMMON"),
+            runtime_dir: dir("XDG_RUNTIME_DIR"),
+        };
+    }
+    SandboxInfo::default()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn detect_sandbox<F>(_env: F, _has_flatpak_info: bool) -> SandboxInfo
+where
+    F: Fn(&str) -> Option<std::ffi::OsString>,
+{
+    SandboxInfo::default()
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, ffi::OsString, path::PathBuf};
+
+    fn detect(vars: &[(&str, &str)], has_flatpak_info: bool) -> SandboxInfo {
+        let vars: HashMap<_, _> = vars.iter().copied().collect();
+        detect_sandbox(|k| vars.get(k).map(OsString::from), has_flatpak_info)
+    }
+
+    #[test]
+    fn test_detect_sandbox() {
+        let info = detect(&[("HOME", "/home/a")], false);
+        assert!(!info.is_sandboxed() && info.has_shared_tmp());
+        let info = detect(
+            &[
+                ("FLATPAK_ID", "com.rustdesk.RustDesk"),
+                (
+                    "XDG_CONFIG_HOME",
+                    "/home/a/.var/app/com.rustdesk.RustDesk/config",
+                ),
+                ("XDG_RUNTIME_DIR", "/run/user/1000"),
+            ],
+            true,
+        );
+        assert_eq!(info.kind, Some(SandboxKind::Flatpak));
+        assert!(!info.has_shared_tmp() && !info.can_install_service());
+        assert_eq!(
+            info.runtime_dir,
+            Some(PathBuf::from("/run/user/1000/app/com.rustdesk.RustDesk"))
+        );
+        let info = detect(
+            &[
+                ("SNAP", "/snap/rustdesk/1"),
+                ("SNAP_NAME", "rustdesk"),
+                ("SNAP_USER_DATA", "/home/a/snap/rustdesk/1"),
+                ("SNAP_USER_COMMON", "/home/a/snap/rustdesk/common"),
+            ],
+            false,
+        );
+        assert_eq!(info.kind, Some(SandboxKind::Snap));
+        assert_eq!(info.app_id, "rustdesk");
+        assert_eq!(
+            info.config_dir,
+            Some(PathBuf::from("/home/a/snap/rustdesk/1/.config"))
+        );
+        assert_eq!(info.runtime_dir, None);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayServer {
+    X11,
+    Wayland,
+    /// The display of Windows or macOS.
+    Native,
+    /// Headless, e.g. a server without a graphical session.
+    None,
+}
+
+impl DisplayServer {
+    /// From the environment of this process, for sessions logind does not know.
+    pub fn from_env() -> Self {
+        if std::env::var_os("WAYLAND_DISPLAY").map_or(false, |v| !v.is_empty()) {
+            Self::Wayland
+        } else if std::env::var_os("DISPLAY").map_or(false, |v| !v.is_empty()) {
+            Self::X11
+        } else {
+            Self::None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DisplayEnvironment {
+    pub server: DisplayServer,
+    /// The type of the session as told by logind, e.g. "x11", "wayland", "tty" or
+    /// "unspecified", empty if unknown.
+    pub session_type: String,
+    /// The active session on seat0, empty if none.
+    pub session_id: String,
+    pub username: String,
+    /// XDG_CURRENT_DESKTOP, e.g. "GNOME" or "KDE".
+    pub desktop: String,
+    /// The greeter of the display manager, e.g. gdm or sddm, is shown.
+    pub login_screen: bool,
+}
+
+impl DisplayEnvironment {
+    #[inline]
+    pub fn is_headless(&self) -> bool {
+        self.server == DisplayServer::None
+    }
+
+    /// Whether to show `config::LINK_DOCS_X11_REQUIRED`: the login screen on Wayland
+    /// can not be captured.
+    #[inline]
+    pub fn x11_required(&self) -> bool {
+        self.server == DisplayServer::Wayland && self.login_screen
+    }
+}
+
+/// The display to capture, probed the same way for all callers. Not cached, it
+/// changes with logins and logouts.
+#[cfg(target_os = "linux")]
+pub fn display_environment() -> DisplayEnvironment {
+    linux::display_environment()
+}
+
+#[cfg(any(target_os = "freebsd", target_os = "openbsd", target_os = "netbsd"))]
+pub fn display_environment() -> DisplayEnvironment {
+    // no logind, only the environment tells
+    let server = DisplayServer::from_env();
+    DisplayEnvironment {
+        server,
+        session_type: match server {
+            DisplayServer::X11 => bsd::DISPLAY_SERVER_X11.to_owned(),
+            DisplayServer::Wayland => bsd::DISPLAY_SERVER_WAYLAND.to_owned(),
+            _ => "".to_owned(),
+        },
+        session_id: "".to_owned(),
+        username: crate::whoami::username(),
+        desktop: std::env::var("XDG_CURRENT_DESKTOP").unwrap_or_default(),
+        login_screen: false,
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd"
+)))]
+pub fn display_environment() -> DisplayEnvironment {
+    DisplayEnvironment {
+        server: DisplayServer::Native,
+        session_type: "".to_owned(),
+        session_id: "".to_owned(),
+        username: crate::whoami::username(),
+        desktop: "".to_owned(),
+        login_screen: false,
+    }
+}
+
+/// Keeps the machine from sleeping while held, e.g. by an incoming session, so the
+/// remote machine does not go to sleep mid-session. Released on drop, the display
+/// may still turn off.
+pub struct KeepAwake {
+    _guard: Box<dyn std::any::Any + Send>,
+}
+
+/// None if disabled with `keys::OPTION_KEEP_AWAKE_DURING_INCOMING_SESSIONS`, not
+/// supported by the platform or failed.
+pub fn keep_awake(reason: &str) -> Option<KeepAwake> {
+    use crate::config::{keys, option2bool, Config};
+    let k = keys::OPTION_KEEP_AWAKE_DURING_INCOMING_SESSIONS;
+    if !option2bool(k, &Config::get_option(k)) {
+        return None;
+    }
+    #[cfg(windows)]
+    let res = windows::keep_awake(reason);
+    #[cfg(target_os = "macos")]
+    let res = macos::keep_awake(reason);
+    #[cfg(target_os = "linux")]
+    let res = linux::keep_awake(reason);
+    #[cfg(not(any(windows, target_os = "macos", target_os = "linux")))]
+    let res: crate::ResultType<Box<dyn std::any::Any + Send>> = {
+        let _ = reason;
+        Err(crate::anyhow::anyhow!("not supported"))
+    };
+    match res {
+        Ok(guard) => Some(KeepAwake { _guard: guard }),
+        Err(err) => {
+            ::log::error!("Failed to keep the machine awake: {}", err);
+            None
+        }
+    }
+}
+
+/// The cost of the connection and the power source, for the quality and keepalive
+/// logic to spare cellular data and the battery.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetworkProfile {
+    /// Charged by volume or over its data limit, e.g. cellular or a tethered hotspot.
+    pub metered: bool,
+    pub on_battery: bool,
+}
+
+impl NetworkProfile {
+    #[inline]
+    pub fn is_constrained(&self) -> bool {
+        self.metered || self.on_battery
+    }
+
+    /// The interval of the registration to the rendezvous server, `REG_INTERVAL`
+    /// unless metered. Doubled, the NAT mappings of most carriers are kept longer.
+    pub fn reg_interval(&self) -> i64 {
+        if self.metered {
+            crate::config::REG_INTERVAL * 2
+        } else {
+            crate::config::REG_INTERVAL
+        }
+    }
+}
+
+// (probed at, profile) and the one reported by the app
+lazy_static::lazy_static! {
+    static ref NETWORK_PROFILE: std::sync::Mutex<Option<(std::time::Instant, NetworkProfile)>> =
+        Default::default();
+    static ref REPORTED_NETWORK_PROFILE: std::sync::RwLock<Option<NetworkProfile>> =
+        Default::default();
+}
+
+const NETWORK_PROFILE_TTL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Reported by the app from the APIs of the OS, used instead of probing, e.g.
+/// ConnectivityManager.isActiveNetworkMetered and BatteryManager on Android, or
+/// NWPath.isExpensive on macOS and iOS. None to probe again.
+pub fn set_network_profile(profile: Option<NetworkProfile>) {
+    *REPORTED_NETWORK_PROFILE.write().unwrap() = profile;
+}
+
+/// The current profile, probed at most every 30 seconds. Not metered and on AC if
+/// unknown.
+pub fn network_profile() -> NetworkProfile {
+    if let Some(profile) = *REPORTED_NETWORK_PROFILE.read().unwrap() {
+        return profile;
+    }
+    if let Some((at, profile)) = *NETWORK_PROFILE.lock().unwrap() {
+        if at.elapsed() < NETWORK_PROFILE_TTL {
+            return profile;
+        }
+    }
+    // not locked while probing, busctl or WMI may take seconds
+    #[cfg(windows)]
+    let profile = windows::network_profile();
+    #[cfg(target_os = "macos")]
+    let profile = macos::network_profile();
+    #[cfg(target_os = "linux")]
+    let profile = linux::network_profile();
+    #[cfg(not(any(windows, target_os = "macos", target_os = "linux")))]
+    let profile = NetworkProfile::default();
+    *NETWORK_PROFILE.lock().unwrap() = Some((std::time::Instant::now(), profile));
+    profile
+}
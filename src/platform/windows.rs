@@ -1,3 +1,4 @@
+use crate::{bail, ResultType};
 use std::{
     collections::VecDeque,
     sync::{Arc, Mutex},
@@ -196,3 +197,121 @@ pub fn is_windows_version_or_greater(
 
     result == TRUE
 }
+
+/// See `super::keep_awake`. The execution state belongs to the calling thread, so
+/// it is set by a thread of its own that lives as long as the guard.
+pub fn keep_awake(_reason: &str) -> ResultType<Box<dyn std::any::Any + Send>> {
+    use std::sync::mpsc::channel;
+    use winapi::um::{
+        winbase::SetThreadExecutionState,
+        winnt::{ES_CONTINUOUS, ES_SYSTEM_REQUIRED},
+    };
+    let (tx, rx) = channel::<()>();
+    let (started_tx, started_rx) = channel();
+    std::thread::spawn(move || {
+        let ok = unsafe { SetThreadExecutionState(ES_CONTINUOUS | ES_SYSTEM_REQUIRED) } != 0;
+        started_tx.send(ok).ok();
+        if ok {
+            // returns once the sender, the guard, is dropped
+            rx.recv().ok();
+            unsafe { SetThreadExecutionState(ES_CONTINUOUS) };
+        }
+    });
+    if !started_rx.recv().unwrap_or(false) {
+        bail!("SetThreadExecutionState failed");
+    }
+    Ok(Box::new(tx))
+}
+
+/// See `super::network_profile`.
+pub fn network_profile() -> super::NetworkProfile {
+    use winapi::um::winbase::{GetSystemPowerStatus, SYSTEM_POWER_STATUS};
+    let mut status: SYSTEM_POWER_STATUS = unsafe { std::mem::zeroed() };
+    // 255 if unknown, e.g. on a desktop
+    let on_battery =
+        unsafe { GetSystemPowerStatus(&mut status) } != FALSE && status.ACLineStatus == 0;
+    super::NetworkProfile {
+        metered: connection_cost().map_or(false, is_metered_cost),
+        on_battery,
+    }
+}
+
+// NLM_CONNECTION_COST of netlistmgr.h
+const NLM_CONNECTION_COST_UNRESTRICTED: DWORD = 0x1;
+const NLM_CONNECTION_COST_OVERDATALIMIT: DWORD = 0x10000;
+const NLM_CONNECTION_COST_ROAMING: DWORD = 0x40000;
+
+fn is_metered_cost(cost: DWORD) -> bool {
+    // 0 if unknown
+    cost != 0
+        && (cost & NLM_CONNECTION_COST_UNRESTRICTED == 0
+            || cost & (NLM_CONNECTION_COST_OVERDATALIMIT | NLM_CONNECTION_COST_ROAMING) != 0)
+}
+
+// the part of INetworkCostManager used, not in winapi
+#[repr(C)]
+struct INetworkCostManagerVtbl {
+    parent: winapi::um::unknwnbase::IUnknownVtbl,
+    get_cost: unsafe extern "system" fn(
+        this: *mut INetworkCostManager,
+        cost: *mut DWORD,
+        dest: *mut std::ffi::c_void,
+    ) -> winapi::shared::winerror::HRESULT,
+}
+
+#[repr(C)]
+struct INetworkCostManager {
+    vtbl: *const INetworkCostManagerVtbl,
+}
+
+/// The cost of the machine-wide connection, as shown by the "metered connection"
+/// switch of the settings.
+fn connection_cost() -> Option<DWORD> {
+    use std::ptr::null_mut;
+    use winapi::{
+        shared::{guiddef::GUID, winerror::SUCCEEDED},
+        um::{
+            combaseapi::{CoCreateInstance, CoInitializeEx, CoUninitialize},
+            objbase::COINIT_MULTITHREADED,
+            unknwnbase::IUnknown,
+            wtypesbase::CLSCTX_ALL,
+        },
+    };
+    const NLM_DATA4: [u8; 8] = [0x8d, 0x69, 0x19, 0x9f, 0xdb, 0xa5, 0x72, 0x3b];
+    const CLSID_NETWORK_LIST_MANAGER: GUID = GUID {
+        Data1: 0xdcb00c01,
+        Data2: 0x570f,
+        Data3: 0x4a9b,
+        Data4: NLM_DATA4,
+    };
+    const IID_INETWORK_COST_MANAGER: GUID = GUID {
+        Data1: 0xdcb00008,
+        Data2: 0x570f,
+        Data3: 0x4a9b,
+        Data4: NLM_DATA4,
+    };
+    // fails with RPC_E_CHANGED_MODE on a thread of another apartment, still usable
+    let initialized = SUCCEEDED(unsafe { CoInitializeEx(null_mut(), COINIT_MULTITHREADED) });
+    let mut manager: *mut INetworkCostManager = null_mut();
+    let mut cost = None;
+    unsafe {
+        let hr = CoCreateInstance(
+            &CLSID_NETWORK_LIST_MANAGER,
+            null_mut(),
+            CLSCTX_ALL,
+            &IID_INETWORK_COST_MANAGER,
+            &mut manager as *mut _ as *mut _,
+        );
+        if SUCCEEDED(hr) && !manager.is_null() {
+            let mut v: DWORD = 0;
+            if SUCCEEDED(((*(*manager).vtbl).get_cost)(manager, &mut v, null_mut())) {
+                cost = Some(v);
+            }
+            ((*(*manager).vtbl).parent.Release)(manager as *mut IUnknown);
+        }
+        if initialized {
+            CoUninitialize();
+        }
+    }
+    cost
+}
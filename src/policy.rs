@@ -0,0 +1,316 @@
+// Settings managed by Group Policy, read from the values an ADMX template writes
+// under HKLM\Software\Policies\<APP_NAME> on Windows.
+//
+// The values of the key are options of `Config`, e.g. "direct-access-port" or
+// "approve-mode", those of the subkeys "Display" and "Local" options of the
+// display settings and of `LocalConfig`. They are applied as the highest layer of
+// the overwrite settings, see `settings_map::Layer`, so they can not be changed in
+// the app, nor by the managed configuration or the dns config. The policies of
+// the machine win over those of the user under HKCU, and both over the overwrite
+// settings of a custom client, which are restored once a policy is removed. To be
+// applied after the settings of a custom client are loaded.
+//
+// There are no group policies on other platforms, `read` returns none.
+use crate::{
+    options::{
+        is_bool_option, OVERWRITE_DISPLAY_SETTINGS, OVERWRITE_LOCAL_SETTINGS, OVERWRITE_SETTINGS,
+    },
+    settings_map::Layer,
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+type Map = HashMap<String, String>;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Policies {
+    pub settings: Map,
+    pub display_settings: Map,
+    pub local_settings: Map,
+}
+
+impl Policies {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.settings.is_empty()
+            && self.display_settings.is_empty()
+            && self.local_settings.is_empty()
+    }
+
+    /// `other` wins, e.g. the policies of the machine over those of the user.
+    fn extend(&mut self, other: Policies) {
+        self.settings.extend(other.settings);
+        self.display_settings.extend(other.display_settings);
+        self.local_settings.extend(other.local_settings);
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref APPLIED: Mutex<Policies> = Default::default();
+}
+
+static WATCHING: AtomicBool = AtomicBool::new(false);
+
+/// The policies in effect.
+pub fn get() -> Policies {
+    APPLIED.lock().unwrap().clone()
+}
+
+/// Whether the option `k` of `Config` is set by a policy, to show it as managed.
+pub fn is_managed(k: &str) -> bool {
+    APPLIED.lock().unwrap().settings.contains_key(k)
+}
+
+/// Reads the policies again and applies them, returns whether they changed, e.g.
+/// after `gpupdate`.
+pub fn refresh() -> bool {
+    apply(read())
+}
+
+/// Refreshes on every change of the policies in a thread, once per process.
+pub fn start_watcher() {
+    if WATCHING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    refresh();
+    #[cfg(windows)]
+    for root in [registry::Root::Machine, registry::Root::User] {
+        std::thread::spawn(move || {
+            registry::watch(root, || {
+                refresh();
+            })
+        });
+    }
+}
+
+fn apply(policies: Policies) -> bool {
+    let mut applied = APPLIED.lock().unwrap();
+    if *applied == policies {
+        return false;
+    }
+    OVERWRITE_SETTINGS.set_layer(Layer::Policy, policies.settings.clone());
+    OVERWRITE_DISPLAY_SETTINGS.set_layer(Layer::Policy, policies.display_settings.clone());
+    OVERWRITE_LOCAL_SETTINGS.set_layer(Layer::Policy, policies.local_settings.clone());
+    log::info!("Group policies applied: {:?}", policies);
+    *applied = policies;
+    true
+}
+
+/// A REG_DWORD as an option: 1 and 0 for "Y" and "N" of a boolean option, as
+/// written by the enabled and disabled states of a policy, else the number, e.g.
+/// for "direct-access-port".
+fn dword_to_option(k: &str, v: u32) -> String {
+    if is_bool_option(k) {
+        if v == 0 { "N" } else { "Y" }.to_owned()
+    } else {
+        v.to_string()
+    }
+}
+
+#[cfg(windows)]
+fn read() -> Policies {
+    let app = crate::config::APP_NAME.read().unwrap().clone();
+    let path = format!("{}\\{}", registry::POLICIES, app);
+    let mut policies = registry::read(registry::Root::User, &path);
+    policies.extend(registry::read(registry::Root::Machine, &path));
+    policies
+}
+
+#[cfg(not(windows))]
+fn read() -> Policies {
+    Policies::default()
+}
+
+#[cfg(windows)]
+mod registry {
+    use super::{dword_to_option, Map, Policies};
+    use std::{ffi::OsStr, os::windows::ffi::OsStrExt, ptr};
+    use winapi::{
+        shared::{
+            minwindef::{DWORD, HKEY, TRUE},
+            winerror::{ERROR_MORE_DATA, ERROR_SUCCESS},
+        },
+        um::{
+            winnt::{
+                KEY_NOTIFY, KEY_READ, REG_DWORD, REG_EXPAND_SZ, REG_NOTIFY_CHANGE_LAST_SET,
+                REG_NOTIFY_CHANGE_NAME, REG_SZ,
+            },
+            winreg::{
+                RegCloseKey, RegEnumValueW, RegNotifyChangeKeyValue, RegOpenKeyExW,
+                HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE,
+            },
+        },
+    };
+
+    pub const POLICIES: &str = "Software\\Policies";
+
+    #[derive(Debug, Clone, Copy)]
+    pub enum Root {
+        Machine,
+        User,
+    }
+
+    impl Root {
+        fn hkey(self) -> HKEY {
+            match self {
+                Root::Machine => HKEY_LOCAL_MACHINE,
+                Root::User => HKEY_CURRENT_USER,
+            }
+        }
+    }
+
+    fn wide(s: &str) -> Vec<u16> {
+        OsStr::new(s).encode_wide().chain(Some(0)).collect()
+    }
+
+    struct Key(HKEY);
+
+    impl Key {
+        fn open(root: Root, path: &str, access: DWORD) -> Option<Self> {
+            let mut hkey: HKEY = ptr::null_mut();
+            let ret =
+                unsafe { RegOpenKeyExW(root.hkey(), wide(path).as_ptr(), 0, access, &mut hkey) };
+            (ret == ERROR_SUCCESS as i32).then(|| Key(hkey))
+        }
+    }
+
+    impl Drop for Key {
+        fn drop(&mut self) {
+            unsafe { RegCloseKey(self.0) };
+        }
+    }
+
+    fn values(root: Root, path: &str) -> Map {
+        let mut res = Map::new();
+        let Some(key) = Key::open(root, path, KEY_READ) else {
+            return res;
+        };
+        let mut name = vec![0u16; 16384];
+        let mut data = vec![0u8; 1024];
+        let mut i = 0;
+        loop {
+            let mut name_len = name.len() as DWORD;
+            let mut data_len = data.len() as DWORD;
+            let mut typ: DWORD = 0;
+            let ret = unsafe {
+                RegEnumValueW(
+                    key.0,
+                    i,
+                    name.as_mut_ptr(),
+                    &mut name_len,
+                    ptr::null_mut(),
+                    &mut typ,
+                    data.as_mut_ptr(),
+                    &mut data_len,
+                )
+            } as DWORD;
+            if ret == ERROR_MORE_DATA {
+                data.resize(data_len as usize, 0);
+                continue;
+            }
+            // ERROR_NO_MORE_ITEMS after the last one
+            if ret != ERROR_SUCCESS {
+                break;
+            }
+            i += 1;
+            let k = String::from_utf16_lossy(&name[..name_len as usize]);
+            let data = &data[..data_len as usize];
+            let v = match typ {
+                REG_SZ | REG_EXPAND_SZ => {
+                    let wide: Vec<u16> = data
+                        .chunks_exact(2)
+                        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                        .take_while(|x| *x != 0)
+                        .collect();
+                    String::from_utf16_lossy(&wide)
+                }
+                REG_DWORD if data.len() == 4 => {
+                    dword_to_option(&k, u32::from_le_bytes([data[0], data[1], data[2], data[3]]))
+                }
+                _ => {
+                    log::warn!("Unsupported type {} of policy {}", typ, k);
+                    continue;
+                }
+            };
+            res.insert(k, v);
+        }
+        res
+    }
+
+    pub fn read(root: Root, path: &str) -> Policies {
+        Policies {
+            settings: values(root, path),
+            display_settings: values(root, &format!("{}\\Display", path)),
+            local_settings: values(root, &format!("{}\\Local", path)),
+        }
+    }
+
+    /// Calls `on_change` on every change under the policies key, until an error.
+    /// The whole tree is watched, the key of the app may not exist yet.
+    pub fn watch(root: Root, mut on_change: impl FnMut()) {
+        let Some(key) = Key::open(root, POLICIES, KEY_NOTIFY) else {
+            log::error!("Failed to open the policies key of {:?}", root);
+            return;
+        };
+        loop {
+            let ret = unsafe {
+                RegNotifyChangeKeyValue(
+                    key.0,
+                    TRUE,
+                    REG_NOTIFY_CHANGE_LAST_SET | REG_NOTIFY_CHANGE_NAME,
+                    ptr::null_mut(),
+                    0,
+                )
+            };
+            if ret != ERROR_SUCCESS as i32 {
+                log::error!("Failed to watch the policies of {:?}: {}", root, ret);
+                break;
+            }
+            on_change();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{test_config, Config},
+        options::keys,
+    };
+
+    #[test]
+    fn test_apply() {
+        let _t = test_config();
+        // of the custom client
+        OVERWRITE_SETTINGS
+            .write()
+            .unwrap()
+            .insert(keys::OPTION_APPROVE_MODE.to_owned(), "click".to_owned());
+        let mut policies = Policies::default();
+        policies
+            .settings
+            .insert(keys::OPTION_APPROVE_MODE.to_owned(), "password".to_owned());
+        policies.settings.insert(
+            keys::OPTION_DIRECT_ACCESS_PORT.to_owned(),
+            dword_to_option(keys::OPTION_DIRECT_ACCESS_PORT, 21118),
+        );
+        assert!(apply(policies.clone()));
+        assert!(!apply(policies.clone()));
+        assert!(is_managed(keys::OPTION_APPROVE_MODE));
+        assert_eq!(Config::get_option(keys::OPTION_APPROVE_MODE), "password");
+        assert_eq!(Config::get_option(keys::OPTION_DIRECT_ACCESS_PORT), "21118");
+        policies.settings.remove(keys::OPTION_APPROVE_MODE);
+        assert!(apply(policies));
+        assert_eq!(Config::get_option(keys::OPTION_APPROVE_MODE), "click");
+        assert!(apply(Policies::default()));
+        assert!(!OVERWRITE_SETTINGS.contains_key(keys::OPTION_DIRECT_ACCESS_PORT));
+        assert_eq!(dword_to_option("enable-audio", 0), "N");
+        assert_eq!(dword_to_option(keys::OPTION_DIRECT_SERVER, 1), "Y");
+    }
+}
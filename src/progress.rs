@@ -0,0 +1,164 @@
+// Typed progress of transfer jobs for UIs and scripting, instead of diffing
+// the strings of `fs::serialize_transfer_job`.
+//
+// Events are broadcast to all subscribers. Job ids are only unique per
+// connection, so a job is identified by `TransferEvent::job`.
+//
+// A receiver lagging behind loses the oldest events and is told so by
+// `RecvError::Lagged`. The next `Progress` of a job carries its totals again,
+// but lost `FileStarted`, `FileFinished` and `JobFinished` are not repeated.
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+const CAPACITY: usize = 1024;
+/// Progress of a job is reported at most once per interval.
+pub const MIN_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobProgress {
+    pub conn_id: i32,
+    pub id: i32,
+    pub file_num: i32,
+    pub file_name: String,
+    pub finished_size: u64,
+    pub total_size: u64,
+    pub transferred: u64,
+    /// bytes per second, smoothed
+    pub speed: f64,
+    pub eta: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransferEvent {
+    Progress(JobProgress),
+    FileStarted {
+        conn_id: i32,
+        id: i32,
+        file_num: i32,
+        name: String,
+        size: u64,
+    },
+    FileFinished {
+        conn_id: i32,
+        id: i32,
+        file_num: i32,
+        name: String,
+    },
+    JobFinished {
+        conn_id: i32,
+        id: i32,
+        error: Option<String>,
+    },
+}
+
+impl TransferEvent {
+    /// (connection, job id) of the job.
+    pub fn job(&self) -> (i32, i32) {
+        match self {
+            TransferEvent::Progress(p) => (p.conn_id, p.id),
+            TransferEvent::FileStarted { conn_id, id, .. }
+            | TransferEvent::FileFinished { conn_id, id, .. }
+            | TransferEvent::JobFinished { conn_id, id, .. } => (*conn_id, *id),
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref SENDER: broadcast::Sender<TransferEvent> = broadcast::channel(CAPACITY).0;
+}
+
+pub fn subscribe() -> broadcast::Receiver<TransferEvent> {
+    SENDER.subscribe()
+}
+
+/// Skip building events nobody listens to.
+#[inline]
+pub fn has_subscribers() -> bool {
+    SENDER.receiver_count() > 0
+}
+
+#[inline]
+pub fn emit(event: TransferEvent) {
+    // fails only without receivers
+    SENDER.send(event).ok();
+}
+
+/// Rate and ETA of a job.
+#[derive(Debug, Default)]
+pub struct Tracker {
+    last: Option<(Instant, u64)>,
+    speed: f64,
+}
+
+impl Tracker {
+    /// (speed, eta), None if the last sample is less than `MIN_INTERVAL` ago.
+    pub fn sample(&mut self, finished: u64, total: u64) -> Option<(f64, Option<Duration>)> {
+        self.sample_at(Instant::now(), finished, total)
+    }
+
+    fn sample_at(
+        &mut self,
+        now: Instant,
+        finished: u64,
+        total: u64,
+    ) -> Option<(f64, Option<Duration>)> {
+        if let Some((time, size)) = self.last {
+            let elapsed = now.saturating_duration_since(time);
+            if elapsed < MIN_INTERVAL {
+                return None;
+            }
+            let rate = finished.saturating_sub(size) as f64 / elapsed.as_secs_f64();
+            self.speed = if self.speed == 0.0 {
+                rate
+            } else {
+                self.speed * 0.7 + rate * 0.3
+            };
+        }
+        self.last = Some((now, finished));
+        let eta = if self.speed > 0.0 {
+            Some(Duration::from_secs_f64(
+                total.saturating_sub(finished) as f64 / self.speed,
+            ))
+        } else {
+            None
+        };
+        Some((self.speed, eta))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracker() {
+        let mut tracker = Tracker::default();
+        let start = Instant::now();
+        assert_eq!(tracker.sample_at(start, 0, 1000), Some((0.0, None)));
+        assert_eq!(tracker.sample_at(start + MIN_INTERVAL / 2, 50, 1000), None);
+        let (speed, eta) = tracker
+            .sample_at(start + Duration::from_secs(1), 100, 1000)
+            .unwrap();
+        assert_eq!(speed, 100.0);
+        assert_eq!(eta, Some(Duration::from_secs(9)));
+        let (speed, _) = tracker
+            .sample_at(start + Duration::from_secs(2), 300, 1000)
+            .unwrap();
+        assert!(speed > 100.0 && speed < 200.0);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let mut rx = subscribe();
+        assert!(has_subscribers());
+        let event = TransferEvent::JobFinished {
+            conn_id: 2,
+            id: 1,
+            error: None,
+        };
+        emit(event.clone());
+        let received = rx.recv().await.unwrap();
+        assert_eq!(received, event);
+        assert_eq!(received.job(), (2, 1));
+    }
+}
@@ -13,15 +13,9 @@ use tokio_native_tls::{native_tls, TlsConnector, TlsStream};
 #[cfg(not(any(target_os = "windows", target_os = "macos")))]
 use tokio_rustls::{client::TlsStream, TlsConnector};
 use tokio_socks::{tcp::Socks5Stream, IntoTargetAddr};
-use tokio_util::codec::Framed;
 use url::Url;
 
-use crate::{
-    bytes_codec::BytesCodec,
-    config::Socks5Server,
-    tcp::{DynTcpStream, FramedStream},
-    ResultType,
-};
+use crate::{config::Socks5Server, tcp::FramedStream, ResultType};
 
 #[derive(Debug, ThisError)]
 pub enum ProxyError {
@@ -56,7 +50,6 @@ const MAXIMUM_RESPONSE_HEADERS: usize = 16;
 const DEFINE_TIME_OUT: u64 = 600;
 
 pub trait IntoUrl {
-
     // Besides parsing as a valid `Url`, the `Url` must be a valid
     // `http::Uri`, in that it makes sense to use in a network request.
     fn into_url(self) -> Result<Url, ProxyError>;
@@ -323,6 +316,8 @@ impl Proxy {
         };
     }
 
+    /// `conf` with its `keyring:` references resolved, see
+    /// `Socks5Server::resolve_credentials`.
     pub fn from_conf(conf: &Socks5Server, ms_timeout: Option<u64>) -> Result<Self, ProxyError> {
         let mut proxy;
         match ms_timeout {
@@ -380,23 +375,13 @@ impl Proxy {
                 info!("Connect to remote http proxy server: {}", proxy);
                 let stream =
                     super::timeout(self.ms_timeout, self.http_connect(stream, target)).await??;
-                Ok(FramedStream(
-                    Framed::new(DynTcpStream(Box::new(stream)), BytesCodec::new()),
-                    addr,
-                    None,
-                    0,
-                ))
+                Ok(FramedStream::from(stream, addr))
             }
             ProxyScheme::Https { .. } => {
                 info!("Connect to remote https proxy server: {}", proxy);
                 let stream =
                     super::timeout(self.ms_timeout, self.https_connect(stream, target)).await??;
-                Ok(FramedStream(
-                    Framed::new(DynTcpStream(Box::new(stream)), BytesCodec::new()),
-                    addr,
-                    None,
-                    0,
-                ))
+                Ok(FramedStream::from(stream, addr))
             }
             ProxyScheme::Socks5 { .. } => {
                 info!("Connect to remote socket5 proxy server: {}", proxy);
@@ -418,12 +403,7 @@ impl Proxy {
                     )
                     .await??
                 };
-                Ok(FramedStream(
-                    Framed::new(DynTcpStream(Box::new(stream)), BytesCodec::new()),
-                    addr,
-                    None,
-                    0,
-                ))
+                Ok(FramedStream::from(stream, addr))
             }
         };
     }
@@ -0,0 +1,419 @@
+// An index of the session recordings under the option `video-save-directory`,
+// in `<APP_NAME>_recordings.toml`, so they can be listed and searched without
+// scanning the directory and opening every file.
+//
+// The recorder adds a file once it is closed, `sync` reconciles the index with
+// the directory, e.g. at startup, from the metadata of the files only. The peer,
+// the direction and the start are parsed from the name the recorder gives the
+// files, e.g. "incoming_123456789_20240131235959123_display0_vp9.webm", the end
+// is when the file was last written. The peer id may contain '_', so the fixed
+// width start is searched from the right. `cleanup` removes the oldest recordings
+// beyond the local options `recording-max-days` and `recording-max-size`, except
+// those still open or written in the last RECENT_MS, i.e. being recorded.
+use crate::config::{keys, load_path, store_path, Config, LocalConfig, APP_NAME};
+use chrono::{Local, NaiveDate, TimeZone};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+const EXTENSIONS: [&str; 2] = ["webm", "mp4"];
+const DAY_MS: i64 = 24 * 3600 * 1000;
+const MB: u64 = 1024 * 1024;
+const RECENT_MS: i64 = 60_000;
+const TIME_LEN: usize = 17;
+
+lazy_static::lazy_static! {
+    static ref LOCK: Mutex<()> = Default::default();
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Recording {
+    pub path: String,
+    pub peer_id: String,
+    pub incoming: bool,
+    /// ms
+    pub start: i64,
+    pub end: i64,
+    pub size: u64,
+}
+
+impl Recording {
+    #[inline]
+    pub fn duration_ms(&self) -> i64 {
+        (self.end - self.start).max(0)
+    }
+
+    /// None if it is not a recording.
+    pub fn from_file(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        if !EXTENSIONS.contains(&ext.as_str()) {
+            return None;
+        }
+        let (incoming, peer_id, start) = parse_name(path.file_stem()?.to_str()?)?;
+        let meta = std::fs::metadata(path).ok()?;
+        if !meta.is_file() {
+            return None;
+        }
+        let end = meta
+            .modified()
+            .ok()?
+            .duration_since(UNIX_EPOCH)
+            .ok()?
+            .as_millis() as i64;
+        Some(Self {
+            path: path.to_string_lossy().into_owned(),
+            peer_id,
+            incoming,
+            start,
+            end: end.max(start),
+            size: meta.len(),
+        })
+    }
+}
+
+/// "incoming_<peer id>_<local time to ms>_...", the time as "%Y%m%d%H%M%S%3f",
+/// the last part of its width.
+fn parse_name(stem: &str) -> Option<(bool, String, i64)> {
+    let (direction, rest) = stem.split_once('_')?;
+    let incoming = match direction {
+        "incoming" => true,
+        "outgoing" => false,
+        _ => return None,
+    };
+    let parts: Vec<&str> = rest.split('_').collect();
+    let i = parts
+        .iter()
+        .rposition(|x| x.len() == TIME_LEN && x.bytes().all(|b| b.is_ascii_digit()))?;
+    let peer_id = parts[..i].join("_");
+    if peer_id.is_empty() {
+        return None;
+    }
+    let time = parts[i];
+    let n = |r: std::ops::Range<usize>| time[r].parse::<u32>().ok();
+    let time = NaiveDate::from_ymd_opt(n(0..4)? as _, n(4..6)?, n(6..8)?)?.and_hms_milli_opt(
+        n(8..10)?,
+        n(10..12)?,
+        n(12..14)?,
+        n(14..17)?,
+    )?;
+    let start = Local
+        .from_local_datetime(&time)
+        .earliest()?
+        .timestamp_millis();
+    Some((incoming, peer_id, start))
+}
+
+/// Whether `path` is open, by this process on Linux, by any on Windows, where
+/// it is opened without sharing.
+#[cfg(target_os = "linux")]
+fn is_open(path: &Path) -> bool {
+    let Ok(path) = path.canonicalize() else {
+        return false;
+    };
+    let Ok(fds) = std::fs::read_dir("/proc/self/fd") else {
+        return false;
+    };
+    fds.filter_map(|x| std::fs::read_link(x.ok()?.path()).ok())
+        .any(|x| x == path)
+}
+
+#[cfg(windows)]
+fn is_open(path: &Path) -> bool {
+    use std::os::windows::fs::OpenOptionsExt;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    match std::fs::OpenOptions::new()
+        .read(true)
+        .share_mode(0)
+        .open(path)
+    {
+        Ok(_) => false,
+        Err(err) => err.raw_os_error() == Some(ERROR_SHARING_VIOLATION),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn is_open(_path: &Path) -> bool {
+    false
+}
+
+/// Whether the recorder may still be writing `path`, from its metadata now,
+/// the index may be older.
+fn is_recording(path: &Path, now: i64) -> bool {
+    let modified = std::fs::metadata(path)
+        .and_then(|x| x.modified())
+        .ok()
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map(|x| x.as_millis() as i64);
+    match modified {
+        Some(modified) => now - modified < RECENT_MS || is_open(path),
+        None => false,
+    }
+}
+
+/// The conditions all set must match.
+#[derive(Debug, Default, Clone)]
+pub struct Query {
+    pub peer_id: Option<String>,
+    pub incoming: Option<bool>,
+    /// ms, started after
+    pub since: Option<i64>,
+    /// ms, started before
+    pub until: Option<i64>,
+}
+
+impl Query {
+    fn matches(&self, r: &Recording) -> bool {
+        self.peer_id.as_ref().map_or(true, |x| *x == r.peer_id)
+            && self.incoming.map_or(true, |x| x == r.incoming)
+            && self.since.map_or(true, |x| r.start >= x)
+            && self.until.map_or(true, |x| r.start < x)
+    }
+}
+
+/// 0 is no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    pub max_days: i64,
+    /// bytes
+    pub max_size: u64,
+}
+
+impl Retention {
+    pub fn from_options() -> Self {
+        let get = |k: &str| {
+            LocalConfig::get_option(k)
+                .trim()
+                .parse::<u64>()
+                .unwrap_or_default()
+        };
+        Self {
+            max_days: get(keys::OPTION_RECORDING_MAX_DAYS) as _,
+            max_size: get(keys::OPTION_RECORDING_MAX_SIZE).saturating_mul(MB),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordingIndex {
+    #[serde(default)]
+    pub recordings: Vec<Recording>,
+}
+
+impl RecordingIndex {
+    fn path() -> PathBuf {
+        let filename = format!("{}_recordings.toml", APP_NAME.read().unwrap().clone());
+        Config::path(filename)
+    }
+
+    pub fn load() -> Self {
+        load_path(Self::path())
+    }
+
+    fn store(&self) {
+        if let Err(err) = store_path(Self::path(), self) {
+            log::error!("Failed to store the recording index: {}", err);
+        }
+    }
+
+    fn modify<T>(f: impl FnOnce(&mut RecordingIndex) -> T) -> T {
+        let _lock = LOCK.lock().unwrap();
+        let mut index = Self::load();
+        let res = f(&mut index);
+        index.store();
+        res
+    }
+
+    /// The directory of the option, None if not set.
+    pub fn dir() -> Option<PathBuf> {
+        let dir = LocalConfig::get_option(keys::OPTION_VIDEO_SAVE_DIRECTORY);
+        (!dir.is_empty()).then(|| PathBuf::from(dir))
+    }
+
+    /// Adds a recording once its file is closed, returns false if it is not one.
+    pub fn add(path: &Path) -> bool {
+        let Some(recording) = Recording::from_file(path) else {
+            return false;
+        };
+        Self::modify(|index| {
+            index.recordings.retain(|r| r.path != recording.path);
+            index.recordings.push(recording);
+        });
+        true
+    }
+
+    /// Adds the recordings of `dir` not indexed, and removes those whose files are
+    /// gone. Returns the number of changes.
+    pub fn sync(dir: &Path) -> usize {
+        let files: Vec<Recording> = match std::fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|e| Recording::from_file(&e.ok()?.path()))
+                .collect(),
+            Err(err) => {
+                log::debug!("Failed to read {}: {}", dir.display(), err);
+                vec![]
+            }
+        };
+        Self::modify(|index| {
+            let len = index.recordings.len();
+            index.recordings.retain(|r| Path::new(&r.path).is_file());
+            let mut n = len - index.recordings.len();
+            for file in files {
+                match index.recordings.iter_mut().find(|r| r.path == file.path) {
+                    Some(r) if *r == file => {}
+                    Some(r) => {
+                        *r = file;
+                        n += 1;
+                    }
+                    None => {
+                        index.recordings.push(file);
+                        n += 1;
+                    }
+                }
+            }
+            n
+        })
+    }
+
+    /// The matching recordings, the last first.
+    pub fn search(&self, query: &Query) -> Vec<&Recording> {
+        let mut res: Vec<&Recording> = self
+            .recordings
+            .iter()
+            .filter(|r| query.matches(r))
+            .collect();
+        res.sort_by(|a, b| b.start.cmp(&a.start));
+        res
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.recordings.iter().map(|r| r.size).sum()
+    }
+
+    /// The recordings beyond `retention`, the oldest first.
+    fn expired(&self, retention: Retention, now: i64) -> Vec<&Recording> {
+        let mut all: Vec<&Recording> = self.recordings.iter().collect();
+        all.sort_by_key(|r| r.start);
+        let mut n = 0;
+        if retention.max_days > 0 {
+            let oldest = now - retention.max_days * DAY_MS;
+            n = all.iter().take_while(|r| r.end < oldest).count();
+        }
+        if retention.max_size > 0 {
+            let mut size: u64 = all[n..].iter().map(|r| r.size).sum();
+            while size > retention.max_size && n < all.len() {
+                size -= all[n].size;
+                n += 1;
+            }
+        }
+        all.truncate(n);
+        all
+    }
+
+    /// Deletes the recordings beyond `retention` but those being recorded,
+    /// returns the files deleted.
+    pub fn cleanup(retention: Retention, now: i64) -> Vec<PathBuf> {
+        Self::modify(|index| {
+            let mut deleted = vec![];
+            for r in index.expired(retention, now) {
+                let path = PathBuf::from(&r.path);
+                if is_recording(&path, now) {
+                    log::debug!("Skip {} being recorded", r.path);
+                    continue;
+                }
+                match std::fs::remove_file(&path) {
+                    Ok(_) => deleted.push(path),
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => deleted.push(path),
+                    // e.g. still open
+                    Err(err) => log::warn!("Failed to delete {}: {}", r.path, err),
+                }
+            }
+            index
+                .recordings
+                .retain(|r| !deleted.iter().any(|x| *x == Path::new(&r.path)));
+            deleted
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    #[test]
+    fn test_index() {
+        let _t = test_config();
+        let (incoming, peer_id, start) =
+            parse_name("incoming_123456789_20240131235959123_display0_vp9").unwrap();
+        assert!(incoming);
+        assert_eq!(peer_id, "123456789");
+        let time = Local.timestamp_millis_opt(start).unwrap();
+        assert_eq!(
+            time.format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+            "2024-01-31 23:59:59.123"
+        );
+        assert!(parse_name("incoming_1_2024").is_none());
+        assert!(parse_name("screenshot_1_20240131235959123").is_none());
+        assert!(parse_name("incoming_20240131235959123_display0").is_none());
+        let (_, peer_id, start2) =
+            parse_name("outgoing_my_pc_01_20240131235959123_display0_vp9").unwrap();
+        assert_eq!(peer_id, "my_pc_01");
+        assert_eq!(start2, start);
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let names = [
+            "outgoing_1_20240101000000000_display0_vp9.webm",
+            "incoming_2_20240102000000000_display0_vp9.webm",
+            "outgoing_1_20240103000000000_display0_av1.mp4",
+        ];
+        for (i, name) in names.iter().enumerate() {
+            std::fs::write(dir.join(name), vec![0u8; (i + 1) * 10]).unwrap();
+        }
+        std::fs::write(dir.join("notes.txt"), b"x").unwrap();
+        assert_eq!(RecordingIndex::sync(&dir), 3);
+        assert_eq!(RecordingIndex::sync(&dir), 0);
+        let index = RecordingIndex::load();
+        assert_eq!(index.total_size(), 60);
+        let query = Query {
+            peer_id: Some("1".to_owned()),
+            ..Default::default()
+        };
+        let found = index.search(&query);
+        assert_eq!(found.len(), 2);
+        assert!(found[0].path.ends_with(names[2]));
+        let query = Query {
+            incoming: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(index.search(&query).len(), 1);
+
+        // the oldest two exceed 30 bytes with the last one, but were just written
+        let retention = Retention {
+            max_days: 0,
+            max_size: 30,
+        };
+        let now = crate::get_time();
+        assert!(RecordingIndex::cleanup(retention, now).is_empty());
+        let file = std::fs::File::open(dir.join(names[0])).unwrap();
+        let deleted = RecordingIndex::cleanup(retention, now + 2 * RECENT_MS);
+        if cfg!(any(target_os = "linux", windows)) {
+            // still open
+            assert_eq!(deleted, vec![dir.join(names[1])]);
+            drop(file);
+            let deleted = RecordingIndex::cleanup(retention, now + 2 * RECENT_MS);
+            assert_eq!(deleted, vec![dir.join(names[0])]);
+        } else {
+            assert_eq!(deleted, vec![dir.join(names[0]), dir.join(names[1])]);
+        }
+        assert!(!dir.join(names[1]).exists());
+        assert!(!dir.join(names[0]).exists());
+        assert_eq!(RecordingIndex::load().recordings.len(), 1);
+        std::fs::remove_file(dir.join(names[2])).unwrap();
+        assert_eq!(RecordingIndex::sync(&dir), 1);
+    }
+}
@@ -0,0 +1,262 @@
+use crate::{
+    bail,
+    config::{Config, Status},
+    get_time,
+    protobuf::MessageField,
+    rendezvous_proto::*,
+    ResultType,
+};
+use sodiumoxide::crypto::sign;
+use std::sync::Mutex;
+
+// Upper bound of the counters handed out, persisted in blocks so the status
+// file is not written on every registration.
+const STATUS_COUNTER: &str = "register-counter";
+const COUNTER_BLOCK: u64 = 1000;
+/// Accepted difference between the sender's and the verifier's clock.
+pub const MAX_CLOCK_SKEW: i64 = 5 * 60 * 1000;
+
+lazy_static::lazy_static! {
+    // (last used, reserved up to)
+    static ref COUNTER: Mutex<(u64, u64)> = Default::default();
+}
+
+fn next_counter() -> u64 {
+    let mut counter = COUNTER.lock().unwrap();
+    if counter.0 >= counter.1 {
+        let start = counter
+            .0
+            .max(Status::get(STATUS_COUNTER).parse().unwrap_or(0));
+        *counter = (start, start + COUNTER_BLOCK);
+        Status::set(STATUS_COUNTER, counter.1.to_string());
+    }
+    counter.0 += 1;
+    counter.0
+}
+
+fn put_field(data: &mut Vec<u8>, field: &[u8]) {
+    data.extend_from_slice(&(field.len() as u32).to_le_bytes());
+    data.extend_from_slice(field);
+}
+
+/// Rendezvous messages carrying a `MessageAuth`.
+pub trait AuthenticatedMessage {
+    const KIND: &'static [u8];
+    /// The fields covered by the signature, length prefixed.
+    fn signed_body(&self) -> Vec<u8>;
+    fn auth(&self) -> Option<&MessageAuth>;
+    fn set_auth(&mut self, auth: MessageAuth);
+}
+
+impl AuthenticatedMessage for RegisterPeer {
+    const KIND: &'static [u8] = b"register-peer";
+
+    fn signed_body(&self) -> Vec<u8> {
+        let mut data = vec![];
+        put_field(&mut data, self.id.as_bytes());
+        put_field(&mut data, &self.serial.to_le_bytes());
+        for id in self.extra_ids.iter() {
+            put_field(&mut data, id.as_bytes());
+        }
+        data
+    }
+
+    fn auth(&self) -> Option<&MessageAuth> {
+        self.auth.as_ref()
+    }
+
+    fn set_auth(&mut self, auth: MessageAuth) {
+        self.auth = MessageField::some(auth);
+    }
+}
+
+impl AuthenticatedMessage for RegisterPk {
+    const KIND: &'static [u8] = b"register-pk";
+
+    fn signed_body(&self) -> Vec<u8> {
+        let mut data = vec![];
+        put_field(&mut data, self.id.as_bytes());
+        put_field(&mut data, &self.uuid);
+        put_field(&mut data, &self.pk);
+        put_field(&mut data, self.old_id.as_bytes());
+        put_field(&mut data, &[self.no_register_device as u8]);
+        for id in self.extra_ids.iter() {
+            put_field(&mut data, id.as_bytes());
+        }
+        data
+    }
+
+    fn auth(&self) -> Option<&MessageAuth> {
+        self.auth.as_ref()
+    }
+
+    fn set_auth(&mut self, auth: MessageAuth) {
+        self.auth = MessageField::some(auth);
+    }
+}
+
+impl AuthenticatedMessage for PunchHoleRequest {
+    const KIND: &'static [u8] = b"punch-hole-request";
+
+    fn signed_body(&self) -> Vec<u8> {
+        let mut data = vec![];
+        put_field(&mut data, self.id.as_bytes());
+        put_field(&mut data, &self.nat_type.value().to_le_bytes());
+        put_field(&mut data, self.licence_key.as_bytes());
+        put_field(&mut data, &self.conn_type.value().to_le_bytes());
+        put_field(&mut data, self.token.as_bytes());
+        put_field(&mut data, self.version.as_bytes());
+        put_field(&mut data, &self.udp_port.to_le_bytes());
+        put_field(&mut data, &[self.force_relay as u8]);
+        put_field(&mut data, &self.upnp_port.to_le_bytes());
+        put_field(&mut data, &self.socket_addr_v6);
+        data
+    }
+
+    fn auth(&self) -> Option<&MessageAuth> {
+        self.auth.as_ref()
+    }
+
+    fn set_auth(&mut self, auth: MessageAuth) {
+        self.auth = MessageField::some(auth);
+    }
+}
+
+fn signed_data<T: AuthenticatedMessage>(msg: &T, counter: u64, timestamp: i64) -> Vec<u8> {
+    let mut data = vec![];
+    put_field(&mut data, T::KIND);
+    put_field(&mut data, &counter.to_le_bytes());
+    put_field(&mut data, &timestamp.to_le_bytes());
+    data.extend(msg.signed_body());
+    data
+}
+
+fn sign_message_with<T: AuthenticatedMessage>(
+    msg: &mut T,
+    sk: &[u8],
+    counter: u64,
+    timestamp: i64,
+) -> ResultType<()> {
+    let Some(sk) = sign::SecretKey::from_slice(sk) else {
+        bail!("Invalid sign key");
+    };
+    let signature = sign::sign_detached(&signed_data(msg, counter, timestamp), &sk);
+    msg.set_auth(MessageAuth {
+        counter,
+        timestamp,
+        signature: signature.to_bytes().to_vec().into(),
+        ..Default::default()
+    });
+    Ok(())
+}
+
+/// Sign with the next persisted counter, call it after the last change of `msg`.
+pub fn sign_message<T: AuthenticatedMessage>(msg: &mut T, sk: &[u8]) -> ResultType<()> {
+    sign_message_with(msg, sk, next_counter(), get_time())
+}
+
+/// For the server side, `last_counter` is the highest counter accepted so far
+/// from this sender. Returns the counter to remember on success.
+pub fn verify_message<T: AuthenticatedMessage>(
+    msg: &T,
+    pk: &[u8],
+    last_counter: u64,
+    now: i64,
+) -> ResultType<u64> {
+    let Some(auth) = msg.auth() else {
+        bail!("Message is not signed");
+    };
+    if auth.counter <= last_counter {
+        bail!(
+            "Replayed message, counter {} <= {}",
+            auth.counter,
+            last_counter
+        );
+    }
+    if (now - auth.timestamp).abs() > MAX_CLOCK_SKEW {
+        bail!("Message timestamp out of range");
+    }
+    let (Some(pk), Ok(sig)) = (
+        sign::PublicKey::from_slice(pk),
+        sign::Signature::from_bytes(&auth.signature),
+    ) else {
+        bail!("Invalid key or signature");
+    };
+    if !sign::verify_detached(&sig, &signed_data(msg, auth.counter, auth.timestamp), &pk) {
+        bail!("Invalid message signature");
+    }
+    Ok(auth.counter)
+}
+
+fn sign_or_log<T: AuthenticatedMessage>(msg: &mut T) {
+    if let Err(err) = sign_message(msg, &Config::get_key_pair().0) {
+        log::error!("Failed to sign rendezvous message: {}", err);
+    }
+}
+
+/// The interval to send `new_register_peer` at, `REG_INTERVAL`, longer on a
+/// metered network, see `NetworkProfile::reg_interval`.
+pub fn reg_interval() -> i64 {
+    crate::platform::network_profile().reg_interval()
+}
+
+/// Build the `RegisterPeer` message sent every `reg_interval()`,
+/// carrying the aliases so the machine can be reached by any of them.
+pub fn new_register_peer(serial: i32) -> RendezvousMessage {
+    let mut ids = Config::get_register_ids();
+    let id = ids.remove(0);
+    let mut msg = RegisterPeer {
+        id,
+        serial,
+        extra_ids: ids,
+        ..Default::default()
+    };
+    sign_or_log(&mut msg);
+    let mut msg_out = RendezvousMessage::new();
+    msg_out.set_register_peer(msg);
+    msg_out
+}
+
+pub fn new_register_pk(uuid: Vec<u8>, pk: Vec<u8>, old_id: String) -> RendezvousMessage {
+    let mut ids = Config::get_register_ids();
+    let id = ids.remove(0);
+    let mut msg = RegisterPk {
+        id,
+        uuid: uuid.into(),
+        pk: pk.into(),
+        old_id,
+        no_register_device: Config::no_register_device(),
+        extra_ids: ids,
+        ..Default::default()
+    };
+    sign_or_log(&mut msg);
+    let mut msg_out = RendezvousMessage::new();
+    msg_out.set_register_pk(msg);
+    msg_out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_auth() {
+        let (pk, sk) = sign::gen_keypair();
+        let mut msg = RegisterPk {
+            id: "123456789".to_owned(),
+            pk: pk.0.to_vec().into(),
+            ..Default::default()
+        };
+        assert!(verify_message(&msg, &pk.0, 0, 1000).is_err());
+        sign_message_with(&mut msg, &sk.0, 5, 1000).unwrap();
+        assert_eq!(verify_message(&msg, &pk.0, 4, 1000).unwrap(), 5);
+        // replay
+        assert!(verify_message(&msg, &pk.0, 5, 1000).is_err());
+        assert!(verify_message(&msg, &pk.0, 4, 1000 + MAX_CLOCK_SKEW + 1).is_err());
+        let (other, _) = sign::gen_keypair();
+        assert!(verify_message(&msg, &other.0, 4, 1000).is_err());
+        // hijack attempt with a captured signature
+        msg.id = "987654321".to_owned();
+        assert!(verify_message(&msg, &pk.0, 4, 1000).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+// Accepted rendezvous server public keys.
+//
+// Instead of a single key, the keyring holds the configured key, the builtin
+// `branding::rs_pub_key` and the keys previously trusted on this machine. A previously
+// seen key stays valid for `ROTATION_WINDOW` after it was last the configured or
+// builtin key, so the server can be moved to a new key while older clients still
+// verify. Verifying with a previously seen key does not extend its window, and it
+// is only accepted for the rendezvous server it was seen with, so the builtin key
+// is not accepted by a custom server.
+use crate::{
+    branding,
+    config::{keys, Config, Status},
+    get_time,
+};
+use serde_derive::{Deserialize, Serialize};
+use sodiumoxide::{base64, crypto::sign};
+
+const STATUS_KEY: &str = "rs-pub-keys-seen";
+pub const ROTATION_WINDOW: i64 = 90 * 24 * 3600 * 1000;
+const REFRESH_INTERVAL: i64 = 24 * 3600 * 1000;
+const MAX_SEEN_KEYS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeySource {
+    Configured,
+    Builtin,
+    Seen,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RsKey {
+    pub key: String,
+    pub source: KeySource,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct SeenKey {
+    key: String,
+    last_used: i64,
+    /// The option `custom-rendezvous-server` it was seen with, empty for the public one.
+    #[serde(default)]
+    server: String,
+}
+
+fn server() -> String {
+    Config::get_option(keys::OPTION_CUSTOM_RENDEZVOUS_SERVER)
+}
+
+fn load_seen() -> Vec<SeenKey> {
+    serde_json::from_str(&Status::get(STATUS_KEY)).unwrap_or_default()
+}
+
+fn store_seen(seen: &[SeenKey]) {
+    Status::set(STATUS_KEY, serde_json::to_string(seen).unwrap_or_default());
+}
+
+fn decode(key: &str) -> Option<sign::PublicKey> {
+    let data = base64::decode(key.trim(), base64::Variant::Original).ok()?;
+    sign::PublicKey::from_slice(&data)
+}
+
+/// The key to use when only one is needed, i.e. the configured key or the
+/// builtin one.
+pub fn primary() -> String {
+    let key = Config::get_option(keys::OPTION_KEY);
+    if key.is_empty() {
+        branding::rs_pub_key()
+    } else {
+        key
+    }
+}
+
+/// All currently accepted keys, most preferred first.
+pub fn get_keys() -> Vec<RsKey> {
+    get_keys_at(get_time())
+}
+
+fn get_keys_at(now: i64) -> Vec<RsKey> {
+    let mut res: Vec<RsKey> = vec![];
+    let mut push = |key: String, source| {
+        if !key.is_empty() && !res.iter().any(|x| x.key == key) && decode(&key).is_some() {
+            res.push(RsKey { key, source });
+        }
+    };
+    push(Config::get_option(keys::OPTION_KEY), KeySource::Configured);
+    // a custom server must not accept messages signed by the public server
+    if res.is_empty() {
+        push(branding::rs_pub_key(), KeySource::Builtin);
+    }
+    let server = server();
+    for x in load_seen() {
+        if x.server == server && now - x.last_used <= ROTATION_WINDOW {
+            push(x.key, KeySource::Seen);
+        }
+    }
+    res
+}
+
+fn remember(key: &str, now: i64) {
+    let server = server();
+    let mut seen = load_seen();
+    // avoid writing the status file on every connection
+    if let Some(x) = seen.first() {
+        if x.key == key && x.server == server && now - x.last_used < REFRESH_INTERVAL {
+            return;
+        }
+    }
+    seen.retain(|x| (x.key != key || x.server != server) && now - x.last_used <= ROTATION_WINDOW);
+    seen.insert(
+        0,
+        SeenKey {
+            key: key.to_owned(),
+            last_used: now,
+            server,
+        },
+    );
+    seen.truncate(MAX_SEEN_KEYS);
+    store_seen(&seen);
+}
+
+/// Verify `signed` (e.g. the signed id/pk from the rendezvous server) against
+/// every accepted key, returns the message and the key that verified it.
+pub fn verify(signed: &[u8]) -> Option<(Vec<u8>, RsKey)> {
+    let now = get_time();
+    for key in get_keys_at(now) {
+        let Some(pk) = decode(&key.key) else {
+            continue;
+        };
+        if let Ok(data) = sign::verify(signed, &pk) {
+            if key.source == KeySource::Seen {
+                log::info!("Message verified with a previously trusted rendezvous key");
+            } else {
+                remember(&key.key, now);
+            }
+            return Some((data, key));
+        }
+    }
+    None
+}
+
+/// Forget the previously seen keys, e.g. after a key compromise.
+pub fn clear_seen() {
+    store_seen(&[]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    fn keypair() -> (String, sign::SecretKey) {
+        let (pk, sk) = sign::gen_keypair();
+        (base64::encode(pk.0, base64::Variant::Original), sk)
+    }
+
+    #[test]
+    fn test_rotation() {
+        let _t = test_config();
+        let (old, old_sk) = keypair();
+        let (new, _) = keypair();
+        Config::set_option(keys::OPTION_KEY.to_owned(), old.clone());
+        let signed = sign::sign(b"id", &old_sk);
+        assert_eq!(verify(&signed).unwrap().1.source, KeySource::Configured);
+        Config::set_option(keys::OPTION_KEY.to_owned(), new);
+        let now = get_time();
+        // the old key is only accepted within the window since it was configured
+        for _ in 0..3 {
+            assert_eq!(verify(&signed).unwrap().1.source, KeySource::Seen);
+        }
+        assert!(get_keys_at(now + ROTATION_WINDOW - 1000)
+            .iter()
+            .any(|x| x.key == old));
+        assert!(!get_keys_at(now + ROTATION_WINDOW + 1000)
+            .iter()
+            .any(|x| x.key == old));
+    }
+
+    #[test]
+    fn test_server_scope() {
+        let _t = test_config();
+        let (builtin, sk) = keypair();
+        remember(&builtin, get_time());
+        assert!(get_keys().iter().any(|x| x.key == builtin));
+        Config::set_option(
+            keys::OPTION_CUSTOM_RENDEZVOUS_SERVER.to_owned(),
+            "rs.example.com".to_owned(),
+        );
+        let (custom, _) = keypair();
+        Config::set_option(keys::OPTION_KEY.to_owned(), custom.clone());
+        let keys: Vec<String> = get_keys().into_iter().map(|x| x.key).collect();
+        assert_eq!(keys, vec![custom]);
+        assert!(verify(&sign::sign(b"id", &sk)).is_none());
+    }
+}
@@ -0,0 +1,164 @@
+// Restrict file operations to the directories in `OPTION_FILE_TRANSFER_ALLOWED_PATHS`.
+//
+// Paths are normalized lexically and the existing part is canonicalized, so
+// neither `..` nor symlinks inside an allowed directory can escape it.
+use crate::{
+    bail,
+    config::{keys, Config},
+    error::{Error, Result},
+    ResultType,
+};
+use std::path::{Component, Path, PathBuf};
+
+fn parse_roots(v: &str) -> Vec<PathBuf> {
+    v.split(|c| c == '\n' || c == ';')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .filter_map(|x| match resolve(Path::new(x)) {
+            Ok(p) => Some(p),
+            Err(err) => {
+                log::error!("Ignore allowed path {}: {}", x, err);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Empty if file operations are not restricted.
+pub fn allowed_roots() -> Vec<PathBuf> {
+    parse_roots(&Config::get_option(
+        keys::OPTION_FILE_TRANSFER_ALLOWED_PATHS,
+    ))
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    !Config::get_option(keys::OPTION_FILE_TRANSFER_ALLOWED_PATHS)
+        .trim()
+        .is_empty()
+}
+
+fn normalize(path: &Path) -> ResultType<PathBuf> {
+    if !path.is_absolute() {
+        bail!("Path must be absolute: {}", path.display());
+    }
+    let mut res = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::Prefix(_) | Component::RootDir => res.push(c.as_os_str()),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !res.pop() || !res.has_root() {
+                    bail!("Invalid path: {}", path.display());
+                }
+            }
+            Component::Normal(x) => res.push(x),
+        }
+    }
+    Ok(res)
+}
+
+// Canonicalize the longest existing ancestor, the rest does not exist yet
+// (e.g. the target of an upload) so it can not be a symlink.
+pub(crate) fn resolve(path: &Path) -> ResultType<PathBuf> {
+    let mut existing = normalize(path)?;
+    let mut rest = vec![];
+    loop {
+        match std::fs::canonicalize(&existing) {
+            Ok(p) => {
+                existing = p;
+                break;
+            }
+            // exists but can not be resolved, e.g. a dangling symlink
+            Err(err) if std::fs::symlink_metadata(&existing).is_ok() => {
+                bail!("Failed to resolve {}: {}", existing.display(), err);
+            }
+            Err(_) => {}
+        }
+        match existing.file_name() {
+            Some(name) => rest.push(name.to_owned()),
+            None => break,
+        }
+        existing.pop();
+    }
+    for name in rest.into_iter().rev() {
+        existing.push(name);
+    }
+    Ok(existing)
+}
+
+pub(crate) fn is_within(path: &Path, root: &Path) -> bool {
+    #[cfg(windows)]
+    {
+        let lower = |p: &Path| PathBuf::from(p.to_string_lossy().to_lowercase());
+        lower(path).starts_with(lower(root))
+    }
+    #[cfg(not(windows))]
+    {
+        path.starts_with(root)
+    }
+}
+
+fn check_path_in(path: &Path, roots: &[PathBuf]) -> Result<PathBuf> {
+    let resolved = resolve(path)?;
+    if roots.iter().any(|r| is_within(&resolved, r)) {
+        return Ok(resolved);
+    }
+    Err(Error::PolicyDenied(format!(
+        "Access denied: {} is outside the allowed paths",
+        path.display()
+    )))
+}
+
+/// Fails with `Error::PolicyDenied` if `path` is outside the allowed directories,
+/// returns the resolved path otherwise. Without restriction, relative paths are accepted as is.
+pub fn check_path(path: &Path) -> Result<PathBuf> {
+    if !is_enabled() {
+        return Ok(path.to_path_buf());
+    }
+    let roots = allowed_roots();
+    if roots.is_empty() {
+        return Err(Error::PolicyDenied(
+            "Access denied: none of the allowed paths is valid".to_owned(),
+        ));
+    }
+    check_path_in(path, &roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = std::fs::canonicalize(dir.path()).unwrap();
+        let allowed = base.join("allowed");
+        let other = base.join("other");
+        std::fs::create_dir_all(allowed.join("sub")).unwrap();
+        std::fs::create_dir_all(&other).unwrap();
+        let roots = parse_roots(&format!("{};", allowed.display()));
+        assert_eq!(roots.len(), 1);
+        assert!(check_path_in(&allowed.join("sub"), &roots).is_ok());
+        assert_eq!(
+            check_path_in(&allowed.join("sub/./../new/file.txt"), &roots).unwrap(),
+            allowed.join("new/file.txt")
+        );
+        assert!(check_path_in(&allowed.join("../other"), &roots).is_err());
+        assert!(check_path_in(&allowed.join("sub/../../other/x"), &roots).is_err());
+        assert!(check_path_in(&other, &roots).is_err());
+        assert!(check_path_in(Path::new("relative"), &roots).is_err());
+        // prefix of the name only
+        assert!(check_path_in(&base.join("allowed2"), &roots).is_err());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&other, allowed.join("link")).unwrap();
+            assert!(check_path_in(&allowed.join("link/x"), &roots).is_err());
+            std::os::unix::fs::symlink(allowed.join("sub"), allowed.join("inner")).unwrap();
+            assert_eq!(
+                check_path_in(&allowed.join("inner/x"), &roots).unwrap(),
+                allowed.join("sub/x")
+            );
+        }
+    }
+}
@@ -0,0 +1,530 @@
+// Secrets kept in a store of the OS instead of the config files: the permanent
+// password, the unlock PIN and the private key. The config file holds `MARKER`
+// in their place, so copying the file does not copy them.
+//
+// The store is selected by platform, the Keychain on macOS. Without one, or if
+// writing to it fails, the secrets are encrypted into the config as before, and
+// are moved into the store on the next write once it works.
+//
+// A secret marked in the config but missing in the store, e.g. the Keychain item
+// was deleted, is empty until it can be loaded. The marker is kept on the next
+// write and no new key pair is generated, see `Config::get_unavailable_secrets`.
+//
+// The secrets of the logged-in user, e.g. the keys of the account caches, are
+// kept by `user_store`, which falls back to a store of the OS scoped to the user
+// where the config has none: DPAPI on Windows and the Secret Service on Linux.
+use crate::ResultType;
+use std::sync::{Arc, RwLock};
+
+/// In the config instead of a secret held by the store.
+pub const MARKER: &str = "@secret-store";
+
+pub trait SecretStore: Send + Sync {
+    fn get(&self, name: &str) -> ResultType<Option<Vec<u8>>>;
+    fn set(&self, name: &str, value: &[u8]) -> ResultType<()>;
+    fn delete(&self, name: &str) -> ResultType<()>;
+}
+
+lazy_static::lazy_static! {
+    static ref STORE: RwLock<Option<Arc<dyn SecretStore>>> = RwLock::new(default_store());
+}
+
+#[cfg(all(target_os = "macos", not(test)))]
+fn default_store() -> Option<Arc<dyn SecretStore>> {
+    Some(Arc::new(keychain::Keychain))
+}
+
+// tests must not touch the secrets of the user
+#[cfg(any(not(target_os = "macos"), test))]
+fn default_store() -> Option<Arc<dyn SecretStore>> {
+    None
+}
+
+/// Replaces the store of the platform, None to keep the secrets in the config,
+/// e.g. for a portable installation. To be called before the config is loaded.
+pub fn set_store(store: Option<Arc<dyn SecretStore>>) {
+    *STORE.write().unwrap() = store;
+}
+
+pub fn store() -> Option<Arc<dyn SecretStore>> {
+    STORE.read().unwrap().clone()
+}
+
+/// The store of `set_store`, else the one of the OS for the current user, None
+/// if there is neither.
+pub fn user_store() -> Option<Arc<dyn SecretStore>> {
+    store().or_else(os_user_store)
+}
+
+#[cfg(all(target_os = "macos", not(test)))]
+fn os_user_store() -> Option<Arc<dyn SecretStore>> {
+    Some(Arc::new(keychain::Keychain))
+}
+
+#[cfg(all(windows, not(test)))]
+fn os_user_store() -> Option<Arc<dyn SecretStore>> {
+    Some(Arc::new(dpapi::Dpapi))
+}
+
+#[cfg(all(
+    any(
+        target_os = "linux",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd"
+    ),
+    not(test)
+))]
+fn os_user_store() -> Option<Arc<dyn SecretStore>> {
+    Some(Arc::new(secret_service::SecretService))
+}
+
+#[cfg(any(test, target_os = "android", target_os = "ios"))]
+fn os_user_store() -> Option<Arc<dyn SecretStore>> {
+    None
+}
+
+/// Returns false if there is no store or it failed.
+pub fn put(name: &str, value: &[u8]) -> bool {
+    let Some(store) = store() else {
+        return false;
+    };
+    match store.set(name, value) {
+        Ok(_) => true,
+        Err(err) => {
+            log::error!("Failed to put secret {} into the store: {}", name, err);
+            false
+        }
+    }
+}
+
+/// `get`, an error if there is no store, it failed or it does not have `name`.
+pub fn load(name: &str) -> ResultType<Vec<u8>> {
+    let Some(store) = store() else {
+        crate::bail!("No secret store for secret {}", name);
+    };
+    match store.get(name)? {
+        Some(v) => Ok(v),
+        None => crate::bail!("Secret {} is missing in the secret store", name),
+    }
+}
+
+pub fn get(name: &str) -> Option<Vec<u8>> {
+    match store()?.get(name) {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("Failed to get secret {} from the store: {}", name, err);
+            None
+        }
+    }
+}
+
+pub fn remove(name: &str) {
+    if let Some(store) = store() {
+        if let Err(err) = store.delete(name) {
+            log::error!("Failed to remove secret {} from the store: {}", name, err);
+        }
+    }
+}
+
+/// The credentials are kept apart from the secrets of the config, their names
+/// are prefixed with it in the store and in the credential manager of the OS.
+pub const CREDENTIAL_NAMESPACE: &str = "credential:";
+
+/// The names of the secrets of the config, never resolved as credentials.
+fn is_reserved(name: &str) -> bool {
+    use crate::config::{
+        SECRET_CONFIG_INTEGRITY, SECRET_PASSWORD, SECRET_PRIVATE_KEY, SECRET_UNLOCK_PIN,
+    };
+    [
+        SECRET_PASSWORD,
+        SECRET_UNLOCK_PIN,
+        SECRET_PRIVATE_KEY,
+        SECRET_CONFIG_INTEGRITY,
+    ]
+    .contains(&name)
+        || name.ends_with("-cache-key")
+}
+
+/// A secret the user added to the credential manager of the OS, referenced from
+/// the config by name, e.g. the proxy password as `keyring:<name>`, see
+/// `Socks5Server::resolve_credentials`. The store of `set_store` is asked first.
+/// - macOS: `security add-generic-password -s <APP_NAME> -a credential:<name> -w`
+/// - Windows: `cmdkey /generic:credential:<name> /user:<user> /pass`
+/// - Linux: `secret-tool store --label=<name> service <APP_NAME> account credential:<name>`
+///
+/// Looked up on a blocking thread, the credential manager may wait for the
+/// keyring to be unlocked.
+pub async fn get_credential(name: &str) -> ResultType<String> {
+    if name.is_empty() || is_reserved(name) {
+        crate::bail!("Invalid credential name {}", name);
+    }
+    let key = format!("{}{}", CREDENTIAL_NAMESPACE, name);
+    let v = tokio::task::spawn_blocking(move || {
+        get(&key)
+            .and_then(|v| String::from_utf8(v).ok())
+            .or_else(|| os_credential(&key))
+    })
+    .await?;
+    match v {
+        Some(v) => Ok(v),
+        None => crate::bail!("Credential {} is not in the credential store", name),
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn os_credential(name: &str) -> Option<String> {
+    let v = keychain::Keychain.get(name).ok()??;
+    String::from_utf8(v).ok()
+}
+
+#[cfg(windows)]
+fn os_credential(name: &str) -> Option<String> {
+    use std::{ffi::OsStr, os::windows::ffi::OsStrExt, ptr};
+    use winapi::um::wincred::{CredFree, CredReadW, CRED_TYPE_GENERIC, PCREDENTIALW};
+    let target: Vec<u16> = OsStr::new(name).encode_wide().chain(Some(0)).collect();
+    let mut cred: PCREDENTIALW = ptr::null_mut();
+    if unsafe { CredReadW(target.as_ptr(), CRED_TYPE_GENERIC, 0, &mut cred) } == 0 {
+        return None;
+    }
+    let (ptr, len) = unsafe { ((*cred).CredentialBlob, (*cred).CredentialBlobSize as usize) };
+    let blob = if ptr.is_null() {
+        &[][..]
+    } else {
+        unsafe { std::slice::from_raw_parts(ptr, len) }
+    };
+    // UTF-16, as written by cmdkey and the Credential Manager
+    let wide: Vec<u16> = blob
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    let v = String::from_utf16(&wide).ok();
+    unsafe { CredFree(cred as _) };
+    v
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd"
+))]
+fn os_credential(name: &str) -> Option<String> {
+    // the Secret Service of the desktop, e.g. GNOME Keyring or KWallet
+    let app = crate::config::APP_NAME.read().unwrap().clone();
+    let output = std::process::Command::new("secret-tool")
+        .args(&["lookup", "service", app.as_str(), "account", name])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let v = String::from_utf8(output.stdout).ok()?;
+    Some(v.trim_end_matches('\n').to_owned())
+}
+
+#[cfg(any(target_os = "android", target_os = "ios"))]
+fn os_credential(_name: &str) -> Option<String> {
+    None
+}
+
+#[cfg(target_os = "macos")]
+mod keychain {
+    use super::SecretStore;
+    use crate::{config::APP_NAME, ResultType};
+    use security_framework::{
+        access_control::{ProtectionMode, SecAccessControl},
+        passwords::{
+            delete_generic_password, get_generic_password, set_generic_password_options,
+            PasswordOptions,
+        },
+    };
+
+    // errSecItemNotFound
+    const NOT_FOUND: i32 = -25300;
+
+    /// Generic passwords of the login Keychain, with the app name as service. They
+    /// are only accessible after the first unlock since boot, and not synced or
+    /// migrated to another device.
+    pub struct Keychain;
+
+    fn service() -> String {
+        APP_NAME.read().unwrap().clone()
+    }
+
+    impl SecretStore for Keychain {
+        fn get(&self, name: &str) -> ResultType<Option<Vec<u8>>> {
+            match get_generic_password(&service(), name) {
+                Ok(v) => Ok(Some(v)),
+                Err(err) if err.code() == NOT_FOUND => Ok(None),
+                Err(err) => Err(err.into()),
+            }
+        }
+
+        fn set(&self, name: &str, value: &[u8]) -> ResultType<()> {
+            let mut options = PasswordOptions::new_generic_password(&service(), name);
+            options.set_access_control(SecAccessControl::create_with_protection(
+                Some(ProtectionMode::AccessibleAfterFirstUnlockThisDeviceOnly),
+                0,
+            )?);
+            // an existing item keeps its access control, replace it
+            self.delete(name)?;
+            set_generic_password_options(value, options)?;
+            Ok(())
+        }
+
+        fn delete(&self, name: &str) -> ResultType<()> {
+            match delete_generic_password(&service(), name) {
+                Err(err) if err.code() != NOT_FOUND => Err(err.into()),
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod dpapi {
+    use super::SecretStore;
+    use crate::{bail, config::Config, ResultType};
+    use std::ptr;
+    use winapi::um::{
+        dpapi::{CryptProtectData, CryptUnprotectData, CRYPTPROTECT_UI_FORBIDDEN},
+        winbase::LocalFree,
+        wincrypt::DATA_BLOB,
+    };
+
+    /// Files of the config dir, encrypted with the key of the Windows user, so
+    /// only the same user on this machine can decrypt them.
+    pub struct Dpapi;
+
+    fn path(name: &str) -> std::path::PathBuf {
+        Config::path(format!("{}.dpapi", name))
+    }
+
+    fn crypt(data: &[u8], protect: bool) -> ResultType<Vec<u8>> {
+        let mut input = DATA_BLOB {
+            cbData: data.len() as _,
+            pbData: data.as_ptr() as *mut _,
+        };
+        let mut output = DATA_BLOB {
+            cbData: 0,
+            pbData: ptr::null_mut(),
+        };
+        let ok = unsafe {
+            if protect {
+                CryptProtectData(
+                    &mut input,
+                    ptr::null(),
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    CRYPTPROTECT_UI_FORBIDDEN,
+                    &mut output,
+                )
+            } else {
+                CryptUnprotectData(
+                    &mut input,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    CRYPTPROTECT_UI_FORBIDDEN,
+                    &mut output,
+                )
+            }
+        };
+        if ok == 0 {
+            bail!("DPAPI failed: {}", std::io::Error::last_os_error());
+        }
+        let res =
+            unsafe { std::slice::from_raw_parts(output.pbData, output.cbData as usize).to_vec() };
+        unsafe { LocalFree(output.pbData as _) };
+        Ok(res)
+    }
+
+    impl SecretStore for Dpapi {
+        fn get(&self, name: &str) -> ResultType<Option<Vec<u8>>> {
+            match std::fs::read(path(name)) {
+                Ok(data) => Ok(Some(crypt(&data, false)?)),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err.into()),
+            }
+        }
+
+        fn set(&self, name: &str, value: &[u8]) -> ResultType<()> {
+            crate::config::store_bytes(path(name), &crypt(value, true)?)
+        }
+
+        fn delete(&self, name: &str) -> ResultType<()> {
+            match std::fs::remove_file(path(name)) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd"
+))]
+mod secret_service {
+    use super::SecretStore;
+    use crate::{bail, config::APP_NAME, ResultType};
+    use sodiumoxide::base64;
+    use std::{
+        io::Write,
+        process::{Command, Stdio},
+    };
+
+    /// Items of the Secret Service of the desktop, e.g. GNOME Keyring or KWallet,
+    /// with `secret-tool`, the value in base64. Fails without a session bus.
+    pub struct SecretService;
+
+    fn attributes(name: &str) -> [String; 4] {
+        [
+            "service".to_owned(),
+            APP_NAME.read().unwrap().clone(),
+            "account".to_owned(),
+            name.to_owned(),
+        ]
+    }
+
+    impl SecretStore for SecretService {
+        fn get(&self, name: &str) -> ResultType<Option<Vec<u8>>> {
+            let output = Command::new("secret-tool")
+                .arg("lookup")
+                .args(&attributes(name))
+                .stdin(Stdio::null())
+                .output()?;
+            // also 1 if missing
+            if !output.status.success() || output.stdout.is_empty() {
+                return Ok(None);
+            }
+            let v = String::from_utf8_lossy(&output.stdout);
+            match base64::decode(v.trim(), base64::Variant::Original) {
+                Ok(v) => Ok(Some(v)),
+                Err(_) => bail!("Invalid secret {} in the Secret Service", name),
+            }
+        }
+
+        fn set(&self, name: &str, value: &[u8]) -> ResultType<()> {
+            let mut child = Command::new("secret-tool")
+                .arg("store")
+                .arg(format!("--label={}", name))
+                .args(&attributes(name))
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()?;
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin.write_all(base64::encode(value, base64::Variant::Original).as_bytes())?;
+            }
+            if !child.wait()?.success() {
+                bail!("Failed to store secret {} in the Secret Service", name);
+            }
+            Ok(())
+        }
+
+        fn delete(&self, name: &str) -> ResultType<()> {
+            let status = Command::new("secret-tool")
+                .arg("clear")
+                .args(&attributes(name))
+                .stdin(Stdio::null())
+                .status()?;
+            if !status.success() {
+                bail!("Failed to remove secret {} from the Secret Service", name);
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, sync::Mutex};
+
+    #[derive(Default)]
+    struct MemoryStore(Mutex<HashMap<String, Vec<u8>>>);
+
+    impl SecretStore for MemoryStore {
+        fn get(&self, name: &str) -> ResultType<Option<Vec<u8>>> {
+            Ok(self.0.lock().unwrap().get(name).cloned())
+        }
+
+        fn set(&self, name: &str, value: &[u8]) -> ResultType<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(name.to_owned(), value.to_vec());
+            Ok(())
+        }
+
+        fn delete(&self, name: &str) -> ResultType<()> {
+            self.0.lock().unwrap().remove(name);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_secret_store() {
+        use crate::config::{reset, test_config, Config, Config2};
+        let t = test_config();
+        assert!(!put("a", b"1"));
+        set_store(Some(Arc::new(MemoryStore::default())));
+        assert!(put("a", b"1"));
+        assert_eq!(get("a"), Some(b"1".to_vec()));
+        remove("a");
+        assert_eq!(get("a"), None);
+        Config::set_unlock_pin("123456");
+        assert_eq!(get("unlock-pin"), Some(b"123456".to_vec()));
+        let file = String::from_utf8(t.file(&Config2::file()).unwrap()).unwrap();
+        assert!(file.contains(MARKER) && !file.contains("123456"));
+        reset();
+        assert_eq!(Config::get_unlock_pin(), "123456");
+        // the item is missing, the marker is kept on the next store
+        let store = super::store().unwrap();
+        store.delete("unlock-pin").unwrap();
+        reset();
+        assert_eq!(Config::get_unlock_pin(), "");
+        assert_eq!(Config::get_unavailable_secrets(), vec!["unlock-pin"]);
+        Config::set_nat_type(2);
+        let file = String::from_utf8(t.file(&Config2::file()).unwrap()).unwrap();
+        assert!(file.contains(MARKER));
+        store.set("unlock-pin", b"123456").unwrap();
+        reset();
+        assert_eq!(Config::get_unlock_pin(), "123456");
+        // no new key pair
+        let content = format!("key_pair = [{:?}, [1, 2, 3]]\n", MARKER.as_bytes());
+        t.set_file(&Config::file(), content.as_bytes());
+        reset();
+        assert!(Config::try_get_key_pair().is_err());
+        let file = String::from_utf8(t.file(&Config::file()).unwrap()).unwrap();
+        assert!(file.contains(&format!("{:?}", MARKER.as_bytes())));
+        store.set("private-key", b"sk").unwrap();
+        reset();
+        assert_eq!(Config::get_key_pair(), (b"sk".to_vec(), vec![1, 2, 3]));
+        // proxy credentials referenced by name, apart from the secrets of the config
+        use crate::config::Socks5Server;
+        assert!(put("credential:hbb-test-proxy", b"pw"));
+        let mut socks = Socks5Server {
+            proxy: "127.0.0.1:1080".to_owned(),
+            username: "user".to_owned(),
+            password: "keyring:hbb-test-proxy".to_owned(),
+        };
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let resolved = rt.block_on(socks.resolve_credentials()).unwrap();
+        assert_eq!(
+            (resolved.username.as_str(), resolved.password.as_str()),
+            ("user", "pw")
+        );
+        remove("credential:hbb-test-proxy");
+        assert!(rt.block_on(socks.resolve_credentials()).is_err());
+        socks.password = "keyring:unlock-pin".to_owned();
+        assert!(rt.block_on(socks.resolve_credentials()).is_err());
+        set_store(None);
+    }
+}
@@ -0,0 +1,110 @@
+// Registry of security relevant events, for audit logs and alerting.
+//
+// Subscribers are called synchronously on the thread that changed the state,
+// after the config locks are released, so they may read the config but should
+// hand slow work (e.g. network requests) to another thread. The exceptions are
+// `ConfigTampered`, `ConfigRecovered` and `ConfigLost`, which are emitted while
+// the config is being loaded.
+use bytes::Bytes;
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecurityEvent {
+    /// `host` is None for the global flag, which resets all hosts if false.
+    KeyConfirmed {
+        host: Option<String>,
+        confirmed: bool,
+    },
+    KeyRotated,
+    TrustedDeviceAdded {
+        id: String,
+        hwid: Bytes,
+    },
+    TrustedDevicesRemoved {
+        hwids: Vec<Bytes>,
+    },
+    TrustedDevicesCleared,
+    PasswordChanged,
+    UnlockPinChanged,
+    FailedPinAttempt,
+    ConfigTampered {
+        path: String,
+    },
+    /// A corrupted config file was restored from its backup.
+    ConfigRecovered {
+        path: String,
+    },
+    /// A corrupted config file had no valid backup, the defaults are used.
+    ConfigLost {
+        path: String,
+    },
+    /// From `ban::record_failure`, `key` is the remote id or ip.
+    Banned {
+        key: String,
+    },
+}
+
+pub type Subscriber = Arc<dyn Fn(&SecurityEvent) + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+lazy_static::lazy_static! {
+    static ref SUBSCRIBERS: RwLock<(u64, Vec<(SubscriptionId, Subscriber)>)> = Default::default();
+}
+
+pub fn subscribe<F>(f: F) -> SubscriptionId
+where
+    F: Fn(&SecurityEvent) + Send + Sync + 'static,
+{
+    let mut lock = SUBSCRIBERS.write().unwrap();
+    lock.0 += 1;
+    let id = SubscriptionId(lock.0);
+    lock.1.push((id, Arc::new(f)));
+    id
+}
+
+pub fn unsubscribe(id: SubscriptionId) {
+    SUBSCRIBERS.write().unwrap().1.retain(|(x, _)| *x != id);
+}
+
+pub fn emit(event: SecurityEvent) {
+    log::info!("Security event: {:?}", event);
+    // not holding the lock, so subscribers can (un)subscribe or emit
+    let subscribers: Vec<Subscriber> = SUBSCRIBERS
+        .read()
+        .unwrap()
+        .1
+        .iter()
+        .map(|(_, f)| f.clone())
+        .collect();
+    for f in subscribers {
+        f(&event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_subscribe() {
+        let got = Arc::new(Mutex::new(vec![]));
+        let got2 = got.clone();
+        let id = subscribe(move |e| {
+            if let SecurityEvent::Banned { key } = e {
+                got2.lock().unwrap().push(key.clone());
+            }
+        });
+        emit(SecurityEvent::Banned {
+            key: "a".to_owned(),
+        });
+        emit(SecurityEvent::PasswordChanged);
+        unsubscribe(id);
+        emit(SecurityEvent::Banned {
+            key: "b".to_owned(),
+        });
+        assert_eq!(*got.lock().unwrap(), vec!["a".to_owned()]);
+    }
+}
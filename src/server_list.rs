@@ -0,0 +1,243 @@
+// The rendezvous servers published by the operator in a signed manifest, fetched
+// from the option `server-list-url`, so servers can be added and retired without
+// a new client:
+//
+//     {"payload": "<base64 of the list>", "signature": "<base64 of its detached signature>"}
+//
+// the list being
+//
+//     {"serial": 4, "servers": ["rs-1.example.com", "[2001:db8::1]:21116"], "expire": 1767225600000,
+//      "regions": {"rs-1.example.com": "emea", "[2001:db8::1]:21116": "apac"}}
+//
+// It is verified with the option `server-list-pub-key` if set, else with the
+// accepted keys of the rendezvous server, see `rs_key`, and applied to the option
+// `rendezvous-servers` only if its serial is newer, so an older manifest can not
+// be replayed. The serial is kept apart from the one of the config updates of the
+// rendezvous server, `Config::get_server_list_serial`, as the two are numbered
+// independently. The server in use and the latencies are reset, so a retired
+// server is not kept and the nearest of the new ones is chosen. `expire` (ms, 0
+// for none) bounds how long a manifest may be served. The optional `regions` are
+// applied to the option `rendezvous-server-regions`, see `server_selector`.
+use crate::{
+    bail,
+    config::{keys, Config},
+    rs_key,
+    server_selector::Region,
+    ResultType,
+};
+use serde_derive::Deserialize;
+use sodiumoxide::{base64, crypto::sign};
+use std::collections::HashMap;
+
+pub const MAX_SERVERS: usize = 32;
+const MAX_SERVER_LEN: usize = 255;
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    payload: String,
+    signature: String,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+pub struct ServerList {
+    pub serial: i32,
+    pub servers: Vec<String>,
+    /// ms
+    #[serde(default)]
+    pub expire: i64,
+    /// server -> region
+    #[serde(default)]
+    pub regions: HashMap<String, String>,
+}
+
+impl ServerList {
+    /// Verifies `manifest` with any of `keys`, in base64, and validates the list.
+    pub fn parse(manifest: &[u8], keys: &[String], now: i64) -> ResultType<Self> {
+        let manifest: Manifest = serde_json::from_slice(manifest)?;
+        let decode = |x: &str| base64::decode(x.trim(), base64::Variant::Original);
+        let (Ok(payload), Ok(sig)) = (decode(&manifest.payload), decode(&manifest.signature))
+        else {
+            bail!("Invalid encoding of the server list");
+        };
+        let Ok(sig) = sign::Signature::from_bytes(&sig) else {
+            bail!("Invalid signature of the server list");
+        };
+        let verified = keys.iter().any(|key| {
+            decode(key)
+                .ok()
+                .and_then(|x| sign::PublicKey::from_slice(&x))
+                .map_or(false, |pk| sign::verify_detached(&sig, &payload, &pk))
+        });
+        if !verified {
+            bail!("Invalid signature of the server list");
+        }
+        let list: ServerList = serde_json::from_slice(&payload)?;
+        list.validate(now)?;
+        Ok(list)
+    }
+
+    fn validate(&self, now: i64) -> ResultType<()> {
+        if self.expire > 0 && self.expire < now {
+            bail!("Expired server list {}", self.serial);
+        }
+        if self.servers.is_empty() || self.servers.len() > MAX_SERVERS {
+            bail!("Invalid number of servers: {}", self.servers.len());
+        }
+        if let Some(server) = self.servers.iter().find(|x| !is_valid_server(x)) {
+            bail!("Invalid server {}", server);
+        }
+        if let Some(server) = self.regions.keys().find(|x| !self.servers.contains(x)) {
+            bail!("Region of an unlisted server {}", server);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn is_newer(&self) -> bool {
+        self.serial > Config::get_server_list_serial()
+    }
+
+    /// Returns false if the list is not newer than the servers in use.
+    pub fn apply(&self) -> bool {
+        if !self.is_newer() {
+            return false;
+        }
+        Config::set_option("rendezvous-servers".to_owned(), self.servers.join(","));
+        // unknown regions are ignored, they may be of a newer version
+        let mut regions: Vec<String> = self
+            .regions
+            .iter()
+            .filter(|(_, region)| Region::parse(region).is_some())
+            .map(|(server, region)| format!("{}={}", server, region))
+            .collect();
+        regions.sort();
+        Config::set_option(
+            keys::OPTION_RENDEZVOUS_SERVER_REGIONS.to_owned(),
+            regions.join(","),
+        );
+        Config::set_server_list_serial(self.serial);
+        Config::reset_rendezvous_server();
+        log::info!(
+            "Rendezvous servers updated to {:?}, serial {}",
+            self.servers,
+            self.serial
+        );
+        true
+    }
+}
+
+/// "host" or "host:port", the host a name or an ipv4 address, with a dot as
+/// `Config::get_rendezvous_servers` expects, or an ipv6 address, "[ip]:port"
+/// with a port, see `socket_client::check_port`.
+pub(crate) fn is_valid_server(server: &str) -> bool {
+    use std::net::{Ipv6Addr, SocketAddrV6};
+    if crate::is_ipv6_str(server) {
+        return server.parse::<Ipv6Addr>().is_ok()
+            || server
+                .parse::<SocketAddrV6>()
+                .map_or(false, |x| x.port() != 0);
+    }
+    let (host, port) = match server.split_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (server, None),
+    };
+    if port.map_or(false, |x| x.parse::<u16>().map_or(true, |x| x == 0)) {
+        return false;
+    }
+    host.len() <= MAX_SERVER_LEN
+        && host.contains('.')
+        && !host.starts_with('.')
+        && !host.ends_with('.')
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
+
+/// The keys a manifest is verified with.
+pub fn keys() -> Vec<String> {
+    let key = Config::get_option(keys::OPTION_SERVER_LIST_PUB_KEY);
+    if !key.is_empty() {
+        return vec![key];
+    }
+    rs_key::get_keys().into_iter().map(|x| x.key).collect()
+}
+
+/// Fetches and applies the manifest of the option `server-list-url`, returns
+/// whether the servers changed.
+pub async fn update() -> ResultType<bool> {
+    let url = Config::get_option(keys::OPTION_SERVER_LIST_URL);
+    if url.is_empty() {
+        return Ok(false);
+    }
+    if !url.starts_with("https://") {
+        bail!("The server list must be fetched over https");
+    }
+    let res = crate::http::get(&url, &[("Accept", "application/json")]).await?;
+    if !res.is_success() {
+        bail!("HTTP status {} of the server list", res.status);
+    }
+    let list = ServerList::parse(&res.body, &keys(), crate::get_time())?;
+    Ok(list.apply())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    fn manifest(list: &str, sk: &sign::SecretKey) -> Vec<u8> {
+        let encode = |x: &[u8]| base64::encode(x, base64::Variant::Original);
+        let sig = sign::sign_detached(list.as_bytes(), sk);
+        serde_json::json!({
+            "payload": encode(list.as_bytes()),
+            "signature": encode(sig.to_bytes().as_ref()),
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_server_list() {
+        let _t = test_config();
+        let (pk, sk) = sign::gen_keypair();
+        let keys = vec![base64::encode(&pk.0, base64::Variant::Original)];
+        let list = r#"{"serial": 10, "servers": ["rs-1.example.com", "rs-2.example.com:21116"], "expire": 2000, "regions": {"rs-1.example.com": "emea"}}"#;
+        let data = manifest(list, &sk);
+        let parsed = ServerList::parse(&data, &keys, 1000).unwrap();
+        assert_eq!(parsed.servers.len(), 2);
+        assert!(ServerList::parse(&data, &keys, 3000).is_err());
+        let (_, other) = sign::gen_keypair();
+        assert!(ServerList::parse(&manifest(list, &other), &keys, 1000).is_err());
+        for server in [
+            "localhost",
+            "rs.example.com:0",
+            "a.com,b.com",
+            "rs .example.com",
+        ] {
+            let list = format!(r#"{{"serial": 10, "servers": ["{}"]}}"#, server);
+            assert!(ServerList::parse(&manifest(&list, &sk), &keys, 1000).is_err());
+        }
+        assert!(is_valid_server("10.0.0.1"));
+        assert!(is_valid_server("2001:db8::1"));
+        assert!(is_valid_server("[2001:db8::1]:21116"));
+        assert!(!is_valid_server("[2001:db8::1]:0"));
+        assert!(!is_valid_server("2001:db8::1:21116:x"));
+
+        let serial = Config::get_serial();
+        Config::update_latency("rs-0.example.com", 10);
+        assert!(parsed.apply());
+        assert_eq!(Config::get_server_list_serial(), 10);
+        assert_eq!(Config::get_serial(), serial);
+        assert!(Config::get_latencies().is_empty());
+        assert_eq!(
+            Config::get_rendezvous_servers(),
+            vec!["rs-1.example.com", "rs-2.example.com:21116"]
+        );
+        assert_eq!(
+            crate::server_selector::regions().get("rs-1.example.com"),
+            Some(&Region::Emea)
+        );
+        // replayed
+        assert!(!parsed.apply());
+    }
+}
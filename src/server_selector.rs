@@ -0,0 +1,170 @@
+// Which rendezvous server to start with, instead of always the first of the
+// list, when the nearest one is not known yet, i.e. on the first run or after
+// the list changed.
+//
+// A server measured by `Config::update_latency` is preferred by its latency.
+// Before any measurement, a server tagged with the region of this machine is
+// preferred, the tags being given by the option `rendezvous-server-regions`,
+// e.g. "rs-us.example.com=americas,rs-eu.example.com=emea", or by the signed
+// server list, see `server_list`. The region of this machine is only a hint
+// from its UTC offset. `probe` measures all the servers at once, so the nearest
+// is found without trying them one after the other.
+use crate::config::{keys, Config};
+use std::collections::HashMap;
+
+const PROBE_TIMEOUT: u64 = 3_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Region {
+    Americas,
+    /// Europe, the Middle East and Africa.
+    Emea,
+    /// Asia and the Pacific.
+    Apac,
+}
+
+impl Region {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "americas" => Some(Region::Americas),
+            "emea" => Some(Region::Emea),
+            "apac" => Some(Region::Apac),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Region::Americas => "americas",
+            Region::Emea => "emea",
+            Region::Apac => "apac",
+        }
+    }
+
+    /// `offset` in seconds east of UTC.
+    pub fn from_utc_offset(offset: i32) -> Self {
+        match offset / 3600 {
+            h if h <= -3 => Region::Americas,
+            h if h <= 3 => Region::Emea,
+            _ => Region::Apac,
+        }
+    }
+
+    /// The region of this machine, from the UTC offset of its local time.
+    pub fn local() -> Self {
+        Self::from_utc_offset(chrono::Local::now().offset().local_minus_utc())
+    }
+}
+
+/// "server=region,...", unknown regions are ignored.
+pub fn parse_regions(s: &str) -> HashMap<String, Region> {
+    s.split(',')
+        .filter_map(|x| {
+            let (server, region) = x.split_once('=')?;
+            Some((server.trim().to_owned(), Region::parse(region)?))
+        })
+        .filter(|(server, _)| !server.is_empty())
+        .collect()
+}
+
+pub fn regions() -> HashMap<String, Region> {
+    parse_regions(&Config::get_option(keys::OPTION_RENDEZVOUS_SERVER_REGIONS))
+}
+
+/// `servers` from the most to the least preferred: the measured by latency, then
+/// the not measured of `region`, the other not measured, and the not reachable,
+/// each in the order of the list.
+pub fn order(
+    servers: &[String],
+    latencies: &HashMap<String, i64>,
+    regions: &HashMap<String, Region>,
+    region: Region,
+) -> Vec<String> {
+    let mut res: Vec<(usize, i64, &String)> = servers
+        .iter()
+        .enumerate()
+        .map(|(i, server)| {
+            let rank = match latencies.get(server) {
+                Some(latency) if *latency > 0 => *latency,
+                // not reachable
+                Some(_) => i64::MAX,
+                None if regions.get(server) == Some(&region) => i64::MAX - 2,
+                None => i64::MAX - 1,
+            };
+            (i, rank, server)
+        })
+        .collect();
+    res.sort_by_key(|(i, rank, _)| (*rank, *i));
+    res.into_iter()
+        .map(|(_, _, server)| server.clone())
+        .collect()
+}
+
+/// The server to start with, None if `servers` is empty.
+pub fn nearest(servers: &[String]) -> Option<String> {
+    let latencies: HashMap<String, i64> = Config::get_latencies().into_iter().collect();
+    order(servers, &latencies, &regions(), Region::local())
+        .into_iter()
+        .next()
+}
+
+/// Measures the latency of every server with a TCP connection to its
+/// rendezvous port, and records it with `Config::update_latency`, which then
+/// switches to the nearest. -1 if it could not be reached.
+pub async fn probe(servers: &[String]) -> Vec<(String, i64)> {
+    let probes =
+        servers.iter().map(|server| async move {
+            let addr = crate::socket_client::check_port(server, crate::branding::rendezvous_port());
+            let start = std::time::Instant::now();
+            let latency =
+                match crate::timeout(PROBE_TIMEOUT, tokio::net::TcpStream::connect(addr.as_str()))
+                    .await
+                {
+                    Ok(Ok(_)) => (start.elapsed().as_millis() as i64).max(1),
+                    _ => -1,
+                };
+            Config::update_latency(server, latency);
+            (server.clone(), latency)
+        });
+    futures::future::join_all(probes).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order() {
+        assert_eq!(Region::from_utc_offset(-5 * 3600), Region::Americas);
+        assert_eq!(Region::from_utc_offset(3600), Region::Emea);
+        assert_eq!(Region::from_utc_offset(19800), Region::Apac);
+        let servers: Vec<String> = [
+            "rs-us.example.com",
+            "rs-eu.example.com",
+            "rs-ap.example.com",
+        ]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+        let regions = parse_regions(
+            "rs-us.example.com=americas, rs-eu.example.com=EMEA,rs-ap.example.com=mars,=apac",
+        );
+        assert_eq!(regions.len(), 2);
+        let mut latencies = HashMap::new();
+        assert_eq!(
+            order(&servers, &latencies, &regions, Region::Emea)[0],
+            "rs-eu.example.com"
+        );
+        assert_eq!(order(&servers, &latencies, &regions, Region::Apac), servers);
+        latencies.insert("rs-ap.example.com".to_owned(), 30);
+        latencies.insert("rs-us.example.com".to_owned(), -1);
+        assert_eq!(
+            order(&servers, &latencies, &regions, Region::Americas),
+            vec![
+                "rs-ap.example.com",
+                "rs-eu.example.com",
+                "rs-us.example.com"
+            ]
+        );
+    }
+}
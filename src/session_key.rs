@@ -0,0 +1,189 @@
+// Session key agreement on top of the stored sign key pair.
+//
+// The Ed25519 keys are converted to X25519, both sides exchange ephemeral keys
+// and mix ee, es, se (like Noise KK), so the session keys are only known to the
+// holders of the two pinned sign keys and are forward secret.
+use crate::{bail, config::Config, ResultType};
+use sodiumoxide::{
+    crypto::{
+        generichash,
+        scalarmult::curve25519::{self as dh, GroupElement, Scalar},
+        secretbox::{self, Key, Nonce},
+        sign::ed25519,
+    },
+    randombytes::randombytes_into,
+};
+
+const KDF_CONTEXT: &[u8] = b"hbb session key v1";
+
+pub fn sign_pk_to_x25519(pk: &[u8]) -> Option<GroupElement> {
+    let pk = ed25519::PublicKey::from_slice(pk)?;
+    let pk = ed25519::to_curve25519_pk(&pk).ok()?;
+    GroupElement::from_slice(&pk.0)
+}
+
+pub fn sign_sk_to_x25519(sk: &[u8]) -> Option<Scalar> {
+    let sk = ed25519::SecretKey::from_slice(sk)?;
+    let sk = ed25519::to_curve25519_sk(&sk).ok()?;
+    Scalar::from_slice(&sk.0)
+}
+
+pub struct Handshake {
+    initiator: bool,
+    static_sk: Scalar,
+    peer_static_pk: GroupElement,
+    ephemeral_sk: Scalar,
+    ephemeral_pk: GroupElement,
+}
+
+impl Handshake {
+    /// Use the local sign key pair, `peer_pk` is the pinned sign pk of the peer.
+    pub fn new(initiator: bool, peer_pk: &[u8]) -> ResultType<Self> {
+        Self::with_sign_sk(initiator, &Config::get_key_pair().0, peer_pk)
+    }
+
+    pub fn with_sign_sk(initiator: bool, sign_sk: &[u8], peer_pk: &[u8]) -> ResultType<Self> {
+        let Some(static_sk) = sign_sk_to_x25519(sign_sk) else {
+            bail!("Invalid local sign key");
+        };
+        let Some(peer_static_pk) = sign_pk_to_x25519(peer_pk) else {
+            bail!("Invalid peer sign key");
+        };
+        let mut ephemeral_sk = Scalar([0u8; dh::SCALARBYTES]);
+        randombytes_into(&mut ephemeral_sk.0);
+        let ephemeral_pk = dh::scalarmult_base(&ephemeral_sk);
+        Ok(Self {
+            initiator,
+            static_sk,
+            peer_static_pk,
+            ephemeral_sk,
+            ephemeral_pk,
+        })
+    }
+
+    /// To be sent to the peer.
+    #[inline]
+    pub fn ephemeral_pk(&self) -> Vec<u8> {
+        self.ephemeral_pk.0.to_vec()
+    }
+
+    pub fn finish(self, peer_ephemeral_pk: &[u8]) -> ResultType<SessionKeys> {
+        let Some(peer_e) = GroupElement::from_slice(peer_ephemeral_pk) else {
+            bail!("Invalid peer ephemeral key");
+        };
+        let ee = dh::scalarmult(&self.ephemeral_sk, &peer_e);
+        // es: initiator ephemeral with responder static, se: the reverse
+        let (es, se, transcript) = if self.initiator {
+            (
+                dh::scalarmult(&self.ephemeral_sk, &self.peer_static_pk),
+                dh::scalarmult(&self.static_sk, &peer_e),
+                [self.ephemeral_pk.0, peer_e.0],
+            )
+        } else {
+            (
+                dh::scalarmult(&self.static_sk, &peer_e),
+                dh::scalarmult(&self.ephemeral_sk, &self.peer_static_pk),
+                [peer_e.0, self.ephemeral_pk.0],
+            )
+        };
+        let (Ok(ee), Ok(es), Ok(se)) = (ee, es, se) else {
+            bail!("Key agreement failed: weak peer key");
+        };
+        let mut state = generichash::State::new(Some(64), Some(KDF_CONTEXT))
+            .map_err(|_| anyhow::anyhow!("Failed to init kdf"))?;
+        for x in [&ee.0, &es.0, &se.0, &transcript[0], &transcript[1]] {
+            state
+                .update(x)
+                .map_err(|_| anyhow::anyhow!("Failed to derive session key"))?;
+        }
+        let digest = state
+            .finalize()
+            .map_err(|_| anyhow::anyhow!("Failed to derive session key"))?;
+        let digest = digest.as_ref();
+        let (Some(a), Some(b)) = (
+            Key::from_slice(&digest[..secretbox::KEYBYTES]),
+            Key::from_slice(&digest[secretbox::KEYBYTES..secretbox::KEYBYTES * 2]),
+        ) else {
+            bail!("Failed to derive session key");
+        };
+        // a: initiator -> responder, b: responder -> initiator
+        let (tx, rx) = if self.initiator { (a, b) } else { (b, a) };
+        Ok(SessionKeys {
+            tx,
+            rx,
+            tx_seq: 0,
+            rx_seq: 0,
+        })
+    }
+}
+
+/// One key per direction with its own counter, so nonces never repeat.
+pub struct SessionKeys {
+    tx: Key,
+    rx: Key,
+    tx_seq: u64,
+    rx_seq: u64,
+}
+
+impl SessionKeys {
+    fn nonce(seq: u64) -> Nonce {
+        let mut nonce = Nonce([0u8; secretbox::NONCEBYTES]);
+        nonce.0[..8].copy_from_slice(&seq.to_le_bytes());
+        nonce
+    }
+
+    pub fn seal(&mut self, data: &[u8]) -> ResultType<Vec<u8>> {
+        if self.tx_seq == u64::MAX {
+            bail!("Session key exhausted");
+        }
+        self.tx_seq += 1;
+        Ok(secretbox::seal(data, &Self::nonce(self.tx_seq), &self.tx))
+    }
+
+    /// Messages must be opened in the order they were sealed.
+    pub fn open(&mut self, data: &[u8]) -> ResultType<Vec<u8>> {
+        if self.rx_seq == u64::MAX {
+            bail!("Session key exhausted");
+        }
+        let Ok(res) = secretbox::open(data, &Self::nonce(self.rx_seq + 1), &self.rx) else {
+            bail!("Decryption error");
+        };
+        self.rx_seq += 1;
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sodiumoxide::crypto::sign;
+
+    #[test]
+    fn test_handshake() {
+        let (pk_a, sk_a) = sign::gen_keypair();
+        let (pk_b, sk_b) = sign::gen_keypair();
+        let a = Handshake::with_sign_sk(true, &sk_a.0, &pk_b.0).unwrap();
+        let b = Handshake::with_sign_sk(false, &sk_b.0, &pk_a.0).unwrap();
+        let (ea, eb) = (a.ephemeral_pk(), b.ephemeral_pk());
+        let mut ka = a.finish(&eb).unwrap();
+        let mut kb = b.finish(&ea).unwrap();
+        for i in 0..3u8 {
+            let msg = vec![i; 10];
+            assert_eq!(kb.open(&ka.seal(&msg).unwrap()).unwrap(), msg);
+            assert_eq!(ka.open(&kb.seal(&msg).unwrap()).unwrap(), msg);
+        }
+        // replayed message
+        let sealed = ka.seal(b"x").unwrap();
+        assert!(kb.open(&sealed).is_ok());
+        assert!(kb.open(&sealed).is_err());
+
+        // peer does not hold the pinned key
+        let (pk_c, _) = sign::gen_keypair();
+        let a = Handshake::with_sign_sk(true, &sk_a.0, &pk_c.0).unwrap();
+        let b = Handshake::with_sign_sk(false, &sk_b.0, &pk_a.0).unwrap();
+        let (ea, eb) = (a.ephemeral_pk(), b.ephemeral_pk());
+        let mut ka = a.finish(&eb).unwrap();
+        let mut kb = b.finish(&ea).unwrap();
+        assert!(kb.open(&ka.seal(b"x").unwrap()).is_err());
+    }
+}
@@ -0,0 +1,293 @@
+use crate::{
+    bail, get_time,
+    message_proto::*,
+    protobuf::Message as _,
+    tcp::Encrypt,
+    ResultType, Stream,
+};
+use sodiumoxide::{
+    crypto::{auth::hmacsha256, secretbox},
+    randombytes::randombytes,
+};
+use std::{collections::HashMap, sync::Mutex};
+
+// Tickets live a bit longer than a mobile handover (Wi-Fi -> LTE) usually takes.
+pub const TICKET_TTL: i32 = 120_000;
+const TOKEN_LEN: usize = 16;
+const RESUME_TIMEOUT: u64 = 6_000;
+
+lazy_static::lazy_static! {
+    static ref TICKETS: Mutex<HashMap<Vec<u8>, ServerTicket>> = Default::default();
+}
+
+struct ServerTicket {
+    secret: hmacsha256::Key,
+    encrypt: Encrypt,
+    last_nonce: u64,
+    expire: i64,
+}
+
+/// Kept by the controlling side, received in `SessionTicket`.
+#[derive(Debug, Clone, Default)]
+pub struct ClientTicket {
+    pub token: Vec<u8>,
+    secret: Vec<u8>,
+    pub expire: i64,
+}
+
+fn proof(secret: &hmacsha256::Key, token: &[u8], nonce: u64) -> Vec<u8> {
+    let mut data = token.to_vec();
+    data.extend_from_slice(&nonce.to_le_bytes());
+    hmacsha256::authenticate(&data, secret).0.to_vec()
+}
+
+/// The key of a resumed session, from the key of the broken one and the nonce
+/// of the resume, so the counters restart at 0 without reusing a nonce of the
+/// old key on either side.
+fn resume_key(key: &secretbox::Key, token: &[u8], nonce: u64) -> secretbox::Key {
+    let mut data = b"session-resume".to_vec();
+    data.extend_from_slice(token);
+    data.extend_from_slice(&nonce.to_le_bytes());
+    let tag = hmacsha256::authenticate(&data, &hmacsha256::Key(key.0));
+    secretbox::Key(tag.0)
+}
+
+fn to_key(secret: &[u8]) -> Option<hmacsha256::Key> {
+    hmacsha256::Key::from_slice(secret)
+}
+
+fn remove_expired(tickets: &mut HashMap<Vec<u8>, ServerTicket>) {
+    let now = get_time();
+    tickets.retain(|_, t| t.expire > now);
+}
+
+/// Controlled side: issue a ticket for the current secured session.
+/// Call again after every successful resume, the old ticket is single-use.
+pub fn issue_ticket(stream: &Stream) -> ResultType<Message> {
+    let Some(encrypt) = stream.get_encrypt() else {
+        bail!("Session is not secured");
+    };
+    let token = randombytes(TOKEN_LEN);
+    let secret = hmacsha256::gen_key();
+    let mut tickets = TICKETS.lock().unwrap();
+    remove_expired(&mut tickets);
+    tickets.insert(
+        token.clone(),
+        ServerTicket {
+            secret: secret.clone(),
+            encrypt,
+            last_nonce: 0,
+            expire: get_time() + TICKET_TTL as i64,
+        },
+    );
+    let mut msg = Message::new();
+    msg.set_session_ticket(SessionTicket {
+        token: token.into(),
+        secret: secret.0.to_vec().into(),
+        ttl: TICKET_TTL,
+        ..Default::default()
+    });
+    Ok(msg)
+}
+
+/// Controlled side: refresh the stored cipher state, so that the counters
+/// are up to date when the socket breaks.
+pub fn update_ticket(token: &[u8], stream: &Stream) {
+    if let Some(encrypt) = stream.get_encrypt() {
+        if let Some(t) = TICKETS.lock().unwrap().get_mut(token) {
+            t.encrypt = encrypt;
+            t.expire = get_time() + TICKET_TTL as i64;
+        }
+    }
+}
+
+pub fn revoke_ticket(token: &[u8]) {
+    TICKETS.lock().unwrap().remove(token);
+}
+
+/// Controlled side: verify a `SessionResume` received on a new socket and
+/// restore the cipher state on it.
+pub async fn accept_resume(stream: &mut Stream, req: &SessionResume) -> ResultType<()> {
+    let res = verify_resume(req);
+    let mut msg = Message::new();
+    let mut resp = SessionResumeResponse::new();
+    match &res {
+        Ok(_) => {
+            resp.ok = true;
+        }
+        Err(err) => {
+            resp.error = err.to_string();
+        }
+    }
+    msg.set_session_resume_response(resp);
+    stream.send(&msg).await?;
+    stream.set_encrypt(res?);
+    Ok(())
+}
+
+fn verify_resume(req: &SessionResume) -> ResultType<Encrypt> {
+    let mut tickets = TICKETS.lock().unwrap();
+    remove_expired(&mut tickets);
+    let Some(ticket) = tickets.get_mut(&req.token[..]) else {
+        bail!("Unknown or expired session ticket");
+    };
+    if req.nonce <= ticket.last_nonce {
+        bail!("Replayed session resume");
+    }
+    let Some(tag) = hmacsha256::Tag::from_slice(&req.proof) else {
+        bail!("Invalid session resume proof");
+    };
+    let mut data = req.token.to_vec();
+    data.extend_from_slice(&req.nonce.to_le_bytes());
+    if !hmacsha256::verify(&tag, &data, &ticket.secret) {
+        bail!("Invalid session resume proof");
+    }
+    ticket.last_nonce = req.nonce;
+    // Messages lost with the old socket are dropped, both directions restart
+    // with a fresh key, the nonce being newer than any resume before.
+    Ok(Encrypt::new(resume_key(
+        &ticket.encrypt.0,
+        &req.token,
+        req.nonce,
+    )))
+}
+
+impl ClientTicket {
+    pub fn from_proto(t: &SessionTicket) -> Self {
+        Self {
+            token: t.token.to_vec(),
+            secret: t.secret.to_vec(),
+            expire: get_time() + t.ttl as i64,
+        }
+    }
+
+    #[inline]
+    pub fn is_valid(&self) -> bool {
+        !self.token.is_empty() && self.expire > get_time()
+    }
+
+    /// Controlling side: resume the session on `stream`, a freshly connected
+    /// socket to the same peer. `encrypt` is the cipher state of the broken stream.
+    pub async fn resume(&self, stream: &mut Stream, encrypt: Encrypt) -> ResultType<()> {
+        if !self.is_valid() {
+            bail!("Session ticket expired");
+        }
+        let Some(secret) = to_key(&self.secret) else {
+            bail!("Invalid session ticket");
+        };
+        let nonce = get_time() as u64;
+        let mut msg = Message::new();
+        msg.set_session_resume(SessionResume {
+            token: self.token.clone().into(),
+            nonce,
+            proof: proof(&secret, &self.token, nonce).into(),
+            ..Default::default()
+        });
+        stream.send(&msg).await?;
+        let Some(Ok(bytes)) = stream.next_timeout(RESUME_TIMEOUT).await else {
+            bail!("Timeout waiting for session resume response");
+        };
+        let msg = Message::parse_from_bytes(&bytes)?;
+        match msg.union {
+            Some(message::Union::SessionResumeResponse(resp)) => {
+                if !resp.ok {
+                    bail!("Session resume refused: {}", resp.error);
+                }
+                stream.set_encrypt(Encrypt::new(resume_key(&encrypt.0, &self.token, nonce)));
+                Ok(())
+            }
+            _ => bail!("Unexpected response to session resume"),
+        }
+    }
+}
+
+/// Reconnect with `connect` and resume the logical session, retrying a few times
+/// while the network is switching.
+pub async fn reconnect_and_resume<F, Fut>(
+    ticket: &ClientTicket,
+    encrypt: Encrypt,
+    mut connect: F,
+    retries: usize,
+) -> ResultType<Stream>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = ResultType<Stream>>,
+{
+    let mut last_err = None;
+    for i in 0..retries.max(1) {
+        if i > 0 {
+            crate::sleep(1.0).await;
+        }
+        match connect().await {
+            Ok(mut stream) => match ticket.resume(&mut stream, encrypt.clone()).await {
+                Ok(()) => return Ok(stream),
+                Err(err) => {
+                    log::warn!("Failed to resume session: {}", err);
+                    last_err = Some(err);
+                }
+            },
+            Err(err) => {
+                log::debug!("Failed to reconnect: {}", err);
+                last_err = Some(err);
+            }
+        }
+        if !ticket.is_valid() {
+            break;
+        }
+    }
+    Err(last_err.unwrap_or_else(|| crate::anyhow::anyhow!("Failed to resume session")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ticket(key: &secretbox::Key) -> (Vec<u8>, hmacsha256::Key) {
+        let token = randombytes(TOKEN_LEN);
+        let secret = hmacsha256::gen_key();
+        TICKETS.lock().unwrap().insert(
+            token.clone(),
+            ServerTicket {
+                secret: secret.clone(),
+                encrypt: Encrypt(key.clone(), 42, 17),
+                last_nonce: 0,
+                expire: get_time() + TICKET_TTL as i64,
+            },
+        );
+        (token, secret)
+    }
+
+    fn request(token: &[u8], secret: &hmacsha256::Key, nonce: u64) -> SessionResume {
+        SessionResume {
+            token: token.to_vec().into(),
+            nonce,
+            proof: proof(secret, token, nonce).into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_resume_key() {
+        let key = secretbox::gen_key();
+        let (token, secret) = ticket(&key);
+        let encrypt = verify_resume(&request(&token, &secret, 1)).unwrap();
+        assert_eq!((encrypt.1, encrypt.2), (0, 0));
+        assert!(encrypt.0 != key);
+        // the controlling side derives the same key
+        let mut client = Encrypt::new(resume_key(&key, &token, 1));
+        let mut server = encrypt;
+        let mut data = bytes::BytesMut::from(&client.enc(b"hello")[..]);
+        server.dec(&mut data).unwrap();
+        assert_eq!(&data[..], b"hello");
+
+        // replayed
+        assert!(verify_resume(&request(&token, &secret, 1)).is_err());
+        // every resume has its own key
+        let again = verify_resume(&request(&token, &secret, 2)).unwrap();
+        assert!(again.0 != server.0);
+        let (_, other) = ticket(&key);
+        assert!(verify_resume(&request(&token, &other, 3)).is_err());
+        revoke_ticket(&token);
+        assert!(verify_resume(&request(&token, &secret, 4)).is_err());
+    }
+}
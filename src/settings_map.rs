@@ -0,0 +1,222 @@
+// Option maps read on hot paths, e.g. per frame through `Config::get_option`.
+//
+// Readers load an `Arc` snapshot without locking, writers clone the map and
+// swap it in when the guard is dropped. Writes are rare (startup, custom client
+// settings), so the clone is cheap compared with a lock on every read.
+//
+// `read()` and `write()` keep the `RwLock` signatures, `.read().unwrap()` works
+// as before.
+//
+// The settings managed outside the app, e.g. by group policies, are set as
+// layers with `set_layer`: a key is the value of the highest layer having it,
+// else the value it had before any layer set it, e.g. of a custom client, so
+// the sources neither clobber each other nor what they replaced.
+use arc_swap::ArcSwap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+type Map = HashMap<String, String>;
+
+/// The sources of managed settings, from the lowest to the highest precedence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Layer {
+    /// See `dns_config`.
+    Dns,
+    /// See `mdm`.
+    Mdm,
+    /// See `policy`.
+    Policy,
+}
+
+#[derive(Default)]
+struct Layers {
+    layers: BTreeMap<Layer, Map>,
+    // the values replaced by a layer, None if there was none
+    replaced: HashMap<String, Option<String>>,
+}
+
+#[derive(Default)]
+pub struct SettingsMap {
+    map: ArcSwap<Map>,
+    // serializes writers, readers never take it
+    writer: Mutex<()>,
+    layers: Mutex<Layers>,
+}
+
+pub struct ReadGuard(arc_swap::Guard<Arc<Map>>);
+
+impl Deref for ReadGuard {
+    type Target = Map;
+
+    fn deref(&self) -> &Map {
+        &self.0
+    }
+}
+
+pub struct WriteGuard<'a> {
+    owner: &'a SettingsMap,
+    map: Map,
+    _lock: MutexGuard<'a, ()>,
+}
+
+impl Deref for WriteGuard<'_> {
+    type Target = Map;
+
+    fn deref(&self) -> &Map {
+        &self.map
+    }
+}
+
+impl DerefMut for WriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Map {
+        &mut self.map
+    }
+}
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        // a writer panicking halfway does not store what it changed so far
+        if std::thread::panicking() {
+            return;
+        }
+        self.owner
+            .map
+            .store(Arc::new(std::mem::take(&mut self.map)));
+    }
+}
+
+impl SettingsMap {
+    pub fn new(map: Map) -> Self {
+        Self {
+            map: ArcSwap::from_pointee(map),
+            writer: Default::default(),
+            layers: Default::default(),
+        }
+    }
+
+    pub fn read(&self) -> Result<ReadGuard, Infallible> {
+        Ok(ReadGuard(self.map.load()))
+    }
+
+    pub fn write(&self) -> Result<WriteGuard<'_>, Infallible> {
+        // a writer panicked, it did not store anything
+        let lock = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(WriteGuard {
+            owner: self,
+            map: (**self.map.load()).clone(),
+            _lock: lock,
+        })
+    }
+
+    /// The current snapshot, unaffected by later writes.
+    #[inline]
+    pub fn snapshot(&self) -> Arc<Map> {
+        self.map.load_full()
+    }
+
+    #[inline]
+    pub fn get(&self, k: &str) -> Option<String> {
+        self.map.load().get(k).cloned()
+    }
+
+    #[inline]
+    pub fn contains_key(&self, k: &str) -> bool {
+        self.map.load().contains_key(k)
+    }
+
+    /// Removes the settings and the layers.
+    pub fn clear(&self) {
+        let mut layers = self.layers.lock().unwrap();
+        *layers = Default::default();
+        self.write().unwrap().clear();
+    }
+
+    /// Sets the settings of `layer` in place of those it set before.
+    pub fn set_layer(&self, layer: Layer, settings: Map) {
+        let mut lock = self.layers.lock().unwrap();
+        let layers = &mut *lock;
+        let mut w = self.write().unwrap();
+        let old = layers.layers.remove(&layer).unwrap_or_default();
+        for k in settings.keys() {
+            if !layers.replaced.contains_key(k) {
+                layers.replaced.insert(k.clone(), w.get(k).cloned());
+            }
+        }
+        let mut changed: Vec<String> = old.into_keys().collect();
+        changed.extend(settings.keys().cloned());
+        if !settings.is_empty() {
+            layers.layers.insert(layer, settings);
+        }
+        for k in changed {
+            let top = layers
+                .layers
+                .values()
+                .rev()
+                .find_map(|x| x.get(&k))
+                .cloned();
+            // restored once no layer has it
+            match top.or_else(|| layers.replaced.remove(&k).flatten()) {
+                Some(v) => w.insert(k, v),
+                None => w.remove(&k),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings_map() {
+        let settings = SettingsMap::default();
+        let before = settings.snapshot();
+        settings
+            .write()
+            .unwrap()
+            .insert("a".to_owned(), "1".to_owned());
+        assert!(before.is_empty());
+        assert_eq!(settings.get("a"), Some("1".to_owned()));
+        {
+            let mut w = settings.write().unwrap();
+            w.insert("b".to_owned(), "2".to_owned());
+            // not visible before the guard is dropped
+            assert!(!settings.contains_key("b"));
+        }
+        assert_eq!(settings.read().unwrap().len(), 2);
+        let copy: Map = settings.read().unwrap().clone();
+        assert_eq!(copy.get("b").map(|x| x.as_str()), Some("2"));
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut w = settings.write().unwrap();
+            w.clear();
+            panic!("halfway");
+        }));
+        assert!(res.is_err());
+        assert_eq!(settings.read().unwrap().len(), 2);
+        settings.write().unwrap().clear();
+        assert!(settings.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_layers() {
+        let map = |x: &[(&str, &str)]| -> Map {
+            x.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let settings = SettingsMap::new(map(&[("a", "custom")]));
+        settings.set_layer(Layer::Policy, map(&[("a", "policy")]));
+        settings.set_layer(Layer::Dns, map(&[("a", "dns"), ("b", "dns")]));
+        assert_eq!(settings.get("a").as_deref(), Some("policy"));
+        assert_eq!(settings.get("b").as_deref(), Some("dns"));
+        settings.set_layer(Layer::Policy, Map::new());
+        assert_eq!(settings.get("a").as_deref(), Some("dns"));
+        settings.set_layer(Layer::Dns, Map::new());
+        assert_eq!(settings.get("a").as_deref(), Some("custom"));
+        assert!(!settings.contains_key("b"));
+    }
+}
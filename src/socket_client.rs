@@ -1,6 +1,9 @@
 use crate::{
-    config::{Config, NetworkType},
+    config::{Config, NetworkType, Socks5Server},
+    metrics,
     tcp::FramedStream,
+    telemetry,
+    trace::Span,
     udp::FramedSocket,
     websocket::{self, check_ws, is_ws_endpoint},
     ResultType, Stream,
@@ -130,12 +133,31 @@ pub async fn connect_tcp<
     ms_timeout: u64,
 ) -> ResultType<crate::Stream> {
     let target_str = check_ws(&target.to_string());
-    if is_ws_endpoint(&target_str) {
-        return Ok(Stream::WebSocket(
-            websocket::WsFramedStream::new(target_str, None, None, ms_timeout).await?,
-        ));
+    let span = Span::connect(&target_str);
+    let start = std::time::Instant::now();
+    let transport = if is_ws_endpoint(&target_str) {
+        telemetry::Transport::Websocket
+    } else {
+        telemetry::Transport::Tcp
+    };
+    let res = if transport == telemetry::Transport::Websocket {
+        span.instrument(websocket::WsFramedStream::new(
+            target_str, None, None, ms_timeout,
+        ))
+        .await
+        .map(Stream::WebSocket)
+    } else {
+        span.instrument(connect_tcp_local(target, None, ms_timeout))
+            .await
+    };
+    match &res {
+        Ok(Stream::WebSocket(_)) => metrics::CONNECTIONS_WS.inc(),
+        Ok(Stream::Tcp(_)) => metrics::CONNECTIONS_TCP.inc(),
+        Err(_) => metrics::CONNECT_ERRORS.inc(),
     }
-    connect_tcp_local(target, None, ms_timeout).await
+    metrics::CONNECT_SECONDS.observe(start.elapsed().as_secs_f64());
+    telemetry::record_connection(transport, &res, start.elapsed());
+    res
 }
 
 // This function connects directly to the target without checking for websocket endpoints.
@@ -147,7 +169,7 @@ pub async fn connect_tcp_local<
     local: Option<SocketAddr>,
     ms_timeout: u64,
 ) -> ResultType<Stream> {
-    if let Some(conf) = Config::get_socks() {
+    if let Some(conf) = Config::get_socks_for(&target.to_string()) {
         return Ok(Stream::Tcp(
             FramedStream::connect(target, local, &conf, ms_timeout).await?,
         ));
@@ -220,22 +242,28 @@ pub async fn new_udp_for(
     target: &str,
     ms_timeout: u64,
 ) -> ResultType<(FramedSocket, TargetAddr<'static>)> {
-    let (ipv4, target) = if NetworkType::Direct == Config::get_network_type() {
+    let socks = Config::get_socks_for(target);
+    let (ipv4, target) = if socks.is_none() {
         let addr = test_target(target).await?;
         (addr.is_ipv4(), addr.into_target_addr()?)
     } else {
         (true, target.into_target_addr()?)
     };
     Ok((
-        new_udp(Config::get_any_listen_addr(ipv4), ms_timeout).await?,
+        new_udp(Config::get_any_listen_addr(ipv4), socks, ms_timeout).await?,
         target.to_owned(),
     ))
 }
 
-async fn new_udp<T: ToSocketAddrs>(local: T, ms_timeout: u64) -> ResultType<FramedSocket> {
-    match Config::get_socks() {
+async fn new_udp<T: ToSocketAddrs>(
+    local: T,
+    socks: Option<Socks5Server>,
+    ms_timeout: u64,
+) -> ResultType<FramedSocket> {
+    match socks {
         None => Ok(FramedSocket::new(local).await?),
         Some(conf) => {
+            let conf = conf.resolve_credentials().await?;
             let socket = FramedSocket::new_proxy(
                 conf.proxy.as_str(),
                 local,
@@ -252,7 +280,7 @@ async fn new_udp<T: ToSocketAddrs>(local: T, ms_timeout: u64) -> ResultType<Fram
 pub async fn rebind_udp_for(
     target: &str,
 ) -> ResultType<Option<(FramedSocket, TargetAddr<'static>)>> {
-    if Config::get_network_type() != NetworkType::Direct {
+    if Config::get_socks_for(target).is_some() {
         return Ok(None);
     }
     let addr = test_target(target).await?;
@@ -1,4 +1,4 @@
-use crate::{config, tcp, websocket, ResultType};
+use crate::{config, metrics, tcp, websocket, ResultType};
 use sodiumoxide::crypto::secretbox::Key;
 use std::net::SocketAddr;
 use tokio::net::TcpStream;
@@ -28,6 +28,7 @@ impl Stream {
 
     #[inline]
     pub async fn send_bytes(&mut self, bytes: bytes::Bytes) -> ResultType<()> {
+        metrics::BYTES_SENT.add(bytes.len() as _);
         match self {
             Stream::WebSocket(s) => s.send_bytes(bytes).await,
             Stream::Tcp(s) => s.send_bytes(bytes).await,
@@ -36,6 +37,7 @@ impl Stream {
 
     #[inline]
     pub async fn send_raw(&mut self, bytes: Vec<u8>) -> ResultType<()> {
+        metrics::BYTES_SENT.add(bytes.len() as _);
         match self {
             Stream::WebSocket(s) => s.send_raw(bytes).await,
             Stream::Tcp(s) => s.send_raw(bytes).await,
@@ -50,6 +52,22 @@ impl Stream {
         }
     }
 
+    #[inline]
+    pub fn get_encrypt(&self) -> Option<tcp::Encrypt> {
+        match self {
+            Stream::WebSocket(s) => s.get_encrypt(),
+            Stream::Tcp(s) => s.get_encrypt(),
+        }
+    }
+
+    #[inline]
+    pub fn set_encrypt(&mut self, encrypt: tcp::Encrypt) {
+        match self {
+            Stream::WebSocket(s) => s.set_encrypt(encrypt),
+            Stream::Tcp(s) => s.set_encrypt(encrypt),
+        }
+    }
+
     #[inline]
     pub fn is_secured(&self) -> bool {
         match self {
@@ -63,10 +81,14 @@ impl Stream {
         &mut self,
         timeout: u64,
     ) -> Option<Result<bytes::BytesMut, std::io::Error>> {
-        match self {
+        let res = match self {
             Stream::WebSocket(s) => s.next_timeout(timeout).await,
             Stream::Tcp(s) => s.next_timeout(timeout).await,
+        };
+        if let Some(Ok(bytes)) = &res {
+            metrics::BYTES_RECEIVED.add(bytes.len() as _);
         }
+        res
     }
 
     /// establish connect from websocket
@@ -86,19 +108,22 @@ impl Stream {
     /// send message
     #[inline]
     pub async fn send(&mut self, msg: &impl protobuf::Message) -> ResultType<()> {
-        match self {
-            Self::WebSocket(ws) => ws.send(msg).await,
-            Self::Tcp(tcp) => tcp.send(msg).await,
-        }
+        // counted by `send_raw` from the bytes serialized anyway, without sizing
+        // the message again
+        self.send_raw(msg.write_to_bytes()?).await
     }
 
     /// receive message
     #[inline]
     pub async fn next(&mut self) -> Option<Result<bytes::BytesMut, std::io::Error>> {
-        match self {
+        let res = match self {
             Self::WebSocket(ws) => ws.next().await,
             Self::Tcp(tcp) => tcp.next().await,
+        };
+        if let Some(Ok(bytes)) = &res {
+            metrics::BYTES_RECEIVED.add(bytes.len() as _);
         }
+        res
     }
 
     #[inline]
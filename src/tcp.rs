@@ -1,4 +1,11 @@
-use crate::{bail, bytes_codec::BytesCodec, ResultType, config::Socks5Server, proxy::Proxy};
+use crate::{
+    bail,
+    bytes_codec::BytesCodec,
+    config::Socks5Server,
+    metrics::{GaugeGuard, ACTIVE_CONNECTIONS},
+    proxy::Proxy,
+    ResultType,
+};
 use anyhow::Context as AnyhowCtx;
 use bytes::{BufMut, Bytes, BytesMut};
 use futures::{SinkExt, StreamExt};
@@ -34,6 +41,22 @@ pub struct FramedStream(
     pub u64,
 );
 
+// Counted in `ACTIVE_CONNECTIONS` while open, inside the boxed stream so that
+// the public fields of `FramedStream` are kept.
+struct Tracked<S> {
+    stream: S,
+    _active: GaugeGuard,
+}
+
+impl<S: TcpStreamTrait> Tracked<S> {
+    fn new(stream: S) -> Self {
+        Self {
+            stream,
+            _active: ACTIVE_CONNECTIONS.track(),
+        }
+    }
+}
+
 impl Deref for FramedStream {
     type Target = Framed<DynTcpStream, BytesCodec>;
 
@@ -98,12 +121,8 @@ impl FramedStream {
                 {
                     stream.set_nodelay(true).ok();
                     let addr = stream.local_addr()?;
-                    return Ok(Self(
-                        Framed::new(DynTcpStream(Box::new(stream)), BytesCodec::new()),
-                        addr,
-                        None,
-                        0,
-                    ));
+                    let stream = DynTcpStream(Box::new(Tracked::new(stream)));
+                    return Ok(Self(Framed::new(stream, BytesCodec::new()), addr, None, 0));
                 }
             }
         }
@@ -119,7 +138,8 @@ impl FramedStream {
     where
         T: IntoTargetAddr<'t>,
     {
-        let proxy = Proxy::from_conf(proxy_conf, Some(ms_timeout))?;
+        let proxy_conf = proxy_conf.resolve_credentials().await?;
+        let proxy = Proxy::from_conf(&proxy_conf, Some(ms_timeout))?;
         proxy.connect::<T>(target, local_addr).await
     }
 
@@ -132,12 +152,8 @@ impl FramedStream {
     }
 
     pub fn from(stream: impl TcpStreamTrait + Send + Sync + 'static, addr: SocketAddr) -> Self {
-        Self(
-            Framed::new(DynTcpStream(Box::new(stream)), BytesCodec::new()),
-            addr,
-            None,
-            0,
-        )
+        let stream = DynTcpStream(Box::new(Tracked::new(stream)));
+        Self(Framed::new(stream, BytesCodec::new()), addr, None, 0)
     }
 
     pub fn set_raw(&mut self) {
@@ -200,6 +216,14 @@ impl FramedStream {
         self.2 = Some(Encrypt::new(key));
     }
 
+    pub fn get_encrypt(&self) -> Option<Encrypt> {
+        self.2.clone()
+    }
+
+    pub fn set_encrypt(&mut self, encrypt: Encrypt) {
+        self.2 = Some(encrypt);
+    }
+
     fn get_nonce(seqnum: u64) -> Nonce {
         let mut nonce = Nonce([0u8; secretbox::NONCEBYTES]);
         nonce.0[..std::mem::size_of_val(&seqnum)].copy_from_slice(&seqnum.to_le_bytes());
@@ -291,6 +315,34 @@ impl AsyncWrite for DynTcpStream {
     }
 }
 
+impl<S: TcpStreamTrait> AsyncRead for Tracked<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        AsyncRead::poll_read(Pin::new(&mut self.stream), cx, buf)
+    }
+}
+
+impl<S: TcpStreamTrait> AsyncWrite for Tracked<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.stream), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.stream), cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_shutdown(Pin::new(&mut self.stream), cx)
+    }
+}
+
 impl<R: AsyncRead + AsyncWrite + Unpin> TcpStreamTrait for R {}
 
 impl Encrypt {
@@ -342,3 +394,34 @@ impl Encrypt {
         Ok(Key(key))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netsim::{Conditions, SimStream};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_encrypted_over_mobile_link() {
+        let (a, b) = tokio::io::duplex(1024 * 1024);
+        let addr: SocketAddr = "127.0.0.1:21116".parse().unwrap();
+        let conditions = Conditions {
+            seed: 1,
+            ..Conditions::mobile()
+        };
+        let mut a = FramedStream::from(SimStream::new(a, conditions), addr);
+        let mut b = FramedStream::from(b, addr);
+        let key = secretbox::gen_key();
+        a.set_key(key.clone());
+        b.set_key(key);
+        let start = tokio::time::Instant::now();
+        for i in 0..100u8 {
+            a.send_bytes(Bytes::from(vec![i; 1000])).await.unwrap();
+        }
+        for i in 0..100u8 {
+            let bytes = b.next_timeout(30_000).await.unwrap().unwrap();
+            assert_eq!(&bytes[..], &[i; 1000][..]);
+        }
+        // 100 KB at 1 MB/s, then at least the latency
+        assert!(start.elapsed() >= std::time::Duration::from_millis(170));
+    }
+}
@@ -0,0 +1,340 @@
+// Coarse connectivity events, for self-hosters to debug a fleet, e.g. which
+// transport clients end up with and how often connections fail.
+//
+// Nothing is recorded unless `OPTION_ALLOW_TELEMETRY` is "Y". Records are then
+// appended to `telemetry.jsonl` in the log directory, posted to
+// `OPTION_TELEMETRY_URL` if set, and passed to the sinks added with `add_sink`.
+//
+// A record is one JSON object, and holds nothing else than:
+//   time        milliseconds since the unix epoch
+//   version     version of this crate
+//   os, arch    e.g. "linux", "x86_64"
+//   event       "connection" or "nat_type", with
+//     connection: success, transport ("tcp" or "websocket"), elapsed_ms and
+//                 failure ("timeout", "refused", "proxy", "io" or "other") if
+//                 not successful
+//     nat_type:   nat_type ("UNKNOWN_NAT", "ASYMMETRIC" or "SYMMETRIC")
+// In particular no id, address, host name or user name.
+//
+// The HTTP sink posts a JSON array of the records every FLUSH_INTERVAL.
+use crate::{
+    config::{keys, Config},
+    ResultType,
+};
+use serde_derive::Serialize;
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+const FILE_NAME: &str = "telemetry.jsonl";
+/// Size of the file sink before it is rotated to `.1`.
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+/// Records kept for the HTTP sink, the oldest are dropped if the endpoint is down.
+const MAX_QUEUED: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    Tcp,
+    Websocket,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Connection {
+        success: bool,
+        transport: Transport,
+        elapsed_ms: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        failure: Option<&'static str>,
+    },
+    NatType {
+        nat_type: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Record {
+    pub time: i64,
+    pub version: &'static str,
+    pub os: &'static str,
+    pub arch: &'static str,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+pub trait Sink: Send + Sync {
+    /// Called on the thread recording the event, should not block.
+    fn record(&self, record: &Record);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SinkId(u64);
+
+lazy_static::lazy_static! {
+    static ref SINKS: RwLock<(u64, Vec<(SinkId, Arc<dyn Sink>)>)> = Default::default();
+    static ref FILE_SINK: FileSink = FileSink::new(Config::log_path().join(FILE_NAME));
+    static ref HTTP_SINK: Mutex<Option<Arc<HttpSink>>> = Default::default();
+}
+
+pub fn add_sink(sink: Arc<dyn Sink>) -> SinkId {
+    let mut lock = SINKS.write().unwrap();
+    lock.0 += 1;
+    let id = SinkId(lock.0);
+    lock.1.push((id, sink));
+    id
+}
+
+pub fn remove_sink(id: SinkId) {
+    SINKS.write().unwrap().1.retain(|(x, _)| *x != id);
+}
+
+#[inline]
+pub fn is_enabled() -> bool {
+    Config::get_bool_option(keys::OPTION_ALLOW_TELEMETRY)
+}
+
+pub fn record(event: Event) {
+    if !is_enabled() {
+        return;
+    }
+    let record = Record {
+        time: crate::get_time(),
+        version: env!("CARGO_PKG_VERSION"),
+        os: std::env::consts::OS,
+        arch: std::env::consts::ARCH,
+        event,
+    };
+    FILE_SINK.record(&record);
+    if let Some(sink) = http_sink() {
+        sink.record(&record);
+    }
+    let sinks: Vec<Arc<dyn Sink>> = SINKS
+        .read()
+        .unwrap()
+        .1
+        .iter()
+        .map(|(_, x)| x.clone())
+        .collect();
+    for sink in sinks {
+        sink.record(&record);
+    }
+}
+
+/// `transport` the one tried, `res` does not tell it on failure.
+pub fn record_connection(transport: Transport, res: &ResultType<crate::Stream>, elapsed: Duration) {
+    record(Event::Connection {
+        success: res.is_ok(),
+        transport,
+        elapsed_ms: elapsed.as_millis() as _,
+        failure: res.as_ref().err().map(failure_kind),
+    });
+}
+
+fn failure_kind(err: &anyhow::Error) -> &'static str {
+    if err.is::<tokio::time::error::Elapsed>() {
+        return "timeout";
+    }
+    if err.is::<crate::proxy::ProxyError>() {
+        return "proxy";
+    }
+    match err.downcast_ref::<std::io::Error>().map(|x| x.kind()) {
+        Some(std::io::ErrorKind::TimedOut) => "timeout",
+        Some(std::io::ErrorKind::ConnectionRefused) => "refused",
+        Some(_) => "io",
+        None => "other",
+    }
+}
+
+/// The sink of `OPTION_TELEMETRY_URL`, recreated if it changes.
+fn http_sink() -> Option<Arc<HttpSink>> {
+    let url = Config::get_option(keys::OPTION_TELEMETRY_URL);
+    let mut lock = HTTP_SINK.lock().unwrap();
+    if url.is_empty() {
+        *lock = None;
+        return None;
+    }
+    match &*lock {
+        Some(sink) if sink.url == url => {}
+        _ => *lock = Some(Arc::new(HttpSink::new(url))),
+    }
+    lock.clone()
+}
+
+enum FileOp {
+    Line(String),
+    // answered once the lines before are written
+    Flush(mpsc::Sender<()>),
+}
+
+/// Appends the records as JSON lines, on a thread of its own, as records come
+/// from async code, e.g. `socket_client::connect_tcp`.
+pub struct FileSink {
+    tx: Mutex<mpsc::Sender<FileOp>>,
+}
+
+impl FileSink {
+    pub fn new(path: PathBuf) -> Self {
+        let (tx, rx) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("telemetry-file".to_owned())
+            .spawn(move || {
+                for op in rx {
+                    match op {
+                        FileOp::Line(line) => {
+                            if let Err(err) = Self::write(&path, &line) {
+                                log::debug!(
+                                    "Failed to write telemetry to {}: {}",
+                                    path.display(),
+                                    err
+                                );
+                            }
+                        }
+                        FileOp::Flush(done) => {
+                            done.send(()).ok();
+                        }
+                    }
+                }
+            })
+            .ok();
+        Self { tx: Mutex::new(tx) }
+    }
+
+    /// Blocks until the records before are written.
+    pub fn flush(&self) {
+        let (done, rx) = mpsc::channel();
+        if self.tx.lock().unwrap().send(FileOp::Flush(done)).is_ok() {
+            rx.recv().ok();
+        }
+    }
+
+    fn write(path: &Path, line: &str) -> std::io::Result<()> {
+        if fs::metadata(path).map_or(false, |m| m.len() > MAX_FILE_SIZE) {
+            let mut rotated = path.as_os_str().to_owned();
+            rotated.push(".1");
+            fs::rename(path, rotated)?;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        file.write_all(line.as_bytes())
+    }
+}
+
+impl Sink for FileSink {
+    fn record(&self, record: &Record) {
+        let Ok(mut line) = serde_json::to_string(record) else {
+            return;
+        };
+        line.push('\n');
+        self.tx.lock().unwrap().send(FileOp::Line(line)).ok();
+    }
+}
+
+/// Posts the records in batches, needs a tokio runtime, records outside of one
+/// are sent with the next batch.
+pub struct HttpSink {
+    url: String,
+    queue: Arc<Mutex<(Vec<Record>, bool)>>,
+}
+
+impl HttpSink {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            queue: Default::default(),
+        }
+    }
+}
+
+impl Sink for HttpSink {
+    fn record(&self, record: &Record) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.0.len() >= MAX_QUEUED {
+            queue.0.remove(0);
+        }
+        queue.0.push(record.clone());
+        // a flush is scheduled
+        if queue.1 {
+            return;
+        }
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        queue.1 = true;
+        let (url, queue) = (self.url.clone(), self.queue.clone());
+        handle.spawn(async move {
+            crate::clock::sleep(FLUSH_INTERVAL).await;
+            let records = {
+                let mut queue = queue.lock().unwrap();
+                queue.1 = false;
+                std::mem::take(&mut queue.0)
+            };
+            let Ok(body) = serde_json::to_vec(&records) else {
+                return;
+            };
+            match crate::http::post_json(&url, &body).await {
+                Ok(res) if res.is_success() => {}
+                Ok(res) => log::debug!("Failed to post telemetry to {}: {}", url, res.status),
+                Err(err) => log::debug!("Failed to post telemetry to {}: {}", url, err),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_payload() {
+        let record = Record {
+            time: 1,
+            version: "1.0.0",
+            os: "linux",
+            arch: "x86_64",
+            event: Event::Connection {
+                success: false,
+                transport: Transport::Websocket,
+                elapsed_ms: 30,
+                failure: Some("timeout"),
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"time":1,"version":"1.0.0","os":"linux","arch":"x86_64","event":"connection","success":false,"transport":"websocket","elapsed_ms":30,"failure":"timeout"}"#
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(FILE_NAME);
+        let sink = FileSink::new(path.clone());
+        sink.record(&record);
+        sink.record(&Record {
+            event: Event::NatType {
+                nat_type: "SYMMETRIC".to_owned(),
+            },
+            ..record
+        });
+        sink.flush();
+        let content = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].ends_with(r#""event":"nat_type","nat_type":"SYMMETRIC"}"#));
+    }
+
+    #[test]
+    fn test_failure_kind() {
+        let err: anyhow::Error = std::io::Error::from(std::io::ErrorKind::ConnectionRefused).into();
+        assert_eq!(failure_kind(&err), "refused");
+        assert_eq!(failure_kind(&anyhow::anyhow!("x")), "other");
+    }
+}
@@ -0,0 +1,78 @@
+// Spans of the connections, transport upgrades and transfer jobs made by this
+// crate, for latency breakdowns with a `tracing` subscriber, e.g. a flame graph
+// layer. Enabled by the `tracing` feature, without it `Span` is a no-op so
+// callers need no cfg.
+use std::future::Future;
+
+#[derive(Clone, Debug, Default)]
+pub struct Span {
+    #[cfg(feature = "tracing")]
+    inner: tracing::Span,
+}
+
+/// Exits the span when dropped, do not hold it across an await, use
+/// `Span::instrument` instead.
+pub struct Entered<'a> {
+    #[cfg(feature = "tracing")]
+    _inner: tracing::span::Entered<'a>,
+    #[cfg(not(feature = "tracing"))]
+    _span: std::marker::PhantomData<&'a Span>,
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! span {
+    ($($arg:tt)*) => {
+        Span {
+            inner: tracing::info_span!($($arg)*),
+        }
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! span {
+    ($($arg:tt)*) => {
+        Span::default()
+    };
+}
+
+#[allow(unused_variables)]
+impl Span {
+    pub fn connect(target: &str) -> Self {
+        span!("connect", target)
+    }
+
+    pub fn transport_upgrade() -> Self {
+        span!("transport_upgrade")
+    }
+
+    pub fn transfer_job(id: i32, remote: &str, is_read: bool) -> Self {
+        span!("transfer_job", id, remote, is_read)
+    }
+
+    pub fn enter(&self) -> Entered<'_> {
+        Entered {
+            #[cfg(feature = "tracing")]
+            _inner: self.inner.enter(),
+            #[cfg(not(feature = "tracing"))]
+            _span: std::marker::PhantomData,
+        }
+    }
+
+    /// Runs `fut` inside the span, also across awaits.
+    pub async fn instrument<F: Future>(self, fut: F) -> F::Output {
+        #[cfg(feature = "tracing")]
+        {
+            tracing::Instrument::instrument(fut, self.inner).await
+        }
+        #[cfg(not(feature = "tracing"))]
+        {
+            fut.await
+        }
+    }
+
+    /// An event in the span, e.g. the error ending a connection attempt.
+    pub fn event(&self, message: &str) {
+        #[cfg(feature = "tracing")]
+        tracing::info!(parent: &self.inner, "{}", message);
+    }
+}
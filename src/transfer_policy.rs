@@ -0,0 +1,262 @@
+// Limits on file transfers set by the administrator, only read from
+// HARD_SETTINGS so that the user can not lift them. Enforced by `fs` when a
+// file starts, in both directions.
+//
+// Usage is the bytes actually transferred, charged by `charge` as the blocks
+// go, per connection and per local day. The daily usage is kept in
+// `<APP_NAME>_transfer_usage.toml` so that a restart does not reset it. The
+// errors of rejected files can be told apart with `downcast_ref::<Rejection>()`,
+// or `error::Error::classify` as `PolicyDenied`.
+use crate::config::{keys, load_path, store_path, Config, APP_NAME, HARD_SETTINGS};
+use serde_derive::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf, sync::Mutex};
+
+const MB: u64 = 1024 * 1024;
+const DAY_MS: i64 = 24 * 3600 * 1000;
+// The daily usage is stored after this many bytes, and when a session ends.
+const STORE_INTERVAL: u64 = MB;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    FileTooLarge { size: u64, max: u64 },
+    BlockedExtension(String),
+    SessionQuotaExceeded { max: u64 },
+    DailyQuotaExceeded { max: u64 },
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FileTooLarge { size, max } => {
+                write!(
+                    f,
+                    "File of {} bytes exceeds the limit of {} bytes",
+                    size, max
+                )
+            }
+            Self::BlockedExtension(ext) => write!(f, "Files of type .{} are blocked", ext),
+            Self::SessionQuotaExceeded { max } => {
+                write!(f, "Transfer quota of {} bytes per session exceeded", max)
+            }
+            Self::DailyQuotaExceeded { max } => {
+                write!(f, "Transfer quota of {} bytes per day exceeded", max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// 0 means no limit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransferPolicy {
+    pub max_file_size: u64,
+    pub max_session_bytes: u64,
+    pub max_daily_bytes: u64,
+    /// lower case, without dot
+    pub blocked_extensions: Vec<String>,
+}
+
+fn hard_option(k: &str) -> String {
+    HARD_SETTINGS
+        .read()
+        .unwrap()
+        .get(k)
+        .cloned()
+        .unwrap_or_default()
+}
+
+fn mb_option(k: &str) -> u64 {
+    hard_option(k)
+        .parse::<u64>()
+        .unwrap_or(0)
+        .saturating_mul(MB)
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Usage {
+    day: i64,
+    daily: u64,
+    #[serde(skip)]
+    sessions: HashMap<i32, u64>,
+    #[serde(skip)]
+    stored: u64,
+}
+
+impl Usage {
+    fn path() -> PathBuf {
+        Config::path(format!("{}_transfer_usage.toml", APP_NAME.read().unwrap()))
+    }
+
+    fn load() -> Self {
+        let mut usage: Usage = load_path(Self::path());
+        usage.stored = usage.daily;
+        usage
+    }
+
+    fn store(&mut self) {
+        if let Err(err) = store_path(Self::path(), &*self) {
+            log::error!("Failed to store the transfer usage: {}", err);
+        }
+        self.stored = self.daily;
+    }
+
+    fn roll(&mut self) {
+        let day = today();
+        if self.day != day {
+            self.day = day;
+            self.daily = 0;
+            self.stored = 0;
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref USAGE: Mutex<Usage> = Mutex::new(Usage::load());
+}
+
+fn today() -> i64 {
+    let offset = chrono::Local::now().offset().local_minus_utc() as i64 * 1000;
+    (crate::get_time() + offset) / DAY_MS
+}
+
+impl TransferPolicy {
+    pub fn get() -> Self {
+        Self {
+            max_file_size: mb_option(keys::OPTION_FILE_TRANSFER_MAX_FILE_SIZE),
+            max_session_bytes: mb_option(keys::OPTION_FILE_TRANSFER_MAX_SESSION_SIZE),
+            max_daily_bytes: mb_option(keys::OPTION_FILE_TRANSFER_MAX_DAILY_SIZE),
+            blocked_extensions: hard_option(keys::OPTION_FILE_TRANSFER_BLOCKED_EXTENSIONS)
+                .split(',')
+                .map(|x| x.trim().trim_start_matches('.').to_lowercase())
+                .filter(|x| !x.is_empty())
+                .collect(),
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Limits on the file alone.
+    pub fn check_file(&self, name: &str, size: u64) -> Result<(), Rejection> {
+        if self.max_file_size > 0 && size > self.max_file_size {
+            return Err(Rejection::FileTooLarge {
+                size,
+                max: self.max_file_size,
+            });
+        }
+        let ext = std::path::Path::new(name)
+            .extension()
+            .map(|x| x.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if !ext.is_empty() && self.blocked_extensions.contains(&ext) {
+            return Err(Rejection::BlockedExtension(ext));
+        }
+        Ok(())
+    }
+
+    /// Check the file, and whether its declared size still fits the quotas of
+    /// `conn_id`, nothing is charged.
+    pub fn admit(&self, conn_id: i32, name: &str, size: u64) -> Result<(), Rejection> {
+        self.check_file(name, size)?;
+        let mut usage = USAGE.lock().unwrap();
+        usage.roll();
+        let session = usage.sessions.get(&conn_id).cloned().unwrap_or(0);
+        self.check_quotas(
+            session.saturating_add(size),
+            usage.daily.saturating_add(size),
+        )
+    }
+
+    /// Charge `bytes` transferred to the quotas of `conn_id`, fails once one is
+    /// exceeded, e.g. by a file larger than declared.
+    pub fn charge(&self, conn_id: i32, bytes: u64) -> Result<(), Rejection> {
+        let mut usage = USAGE.lock().unwrap();
+        usage.roll();
+        let session = usage.sessions.get(&conn_id).cloned().unwrap_or(0) + bytes;
+        usage.sessions.insert(conn_id, session);
+        usage.daily += bytes;
+        if usage.daily >= usage.stored + STORE_INTERVAL {
+            usage.store();
+        }
+        self.check_quotas(session, usage.daily)
+    }
+
+    fn check_quotas(&self, session: u64, daily: u64) -> Result<(), Rejection> {
+        if self.max_session_bytes > 0 && session > self.max_session_bytes {
+            return Err(Rejection::SessionQuotaExceeded {
+                max: self.max_session_bytes,
+            });
+        }
+        if self.max_daily_bytes > 0 && daily > self.max_daily_bytes {
+            return Err(Rejection::DailyQuotaExceeded {
+                max: self.max_daily_bytes,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// The connection is closed, its session quota is released.
+pub fn end_session(conn_id: i32) {
+    let mut usage = USAGE.lock().unwrap();
+    usage.sessions.remove(&conn_id);
+    if usage.daily != usage.stored {
+        usage.store();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admit() {
+        let _t = crate::config::test_config();
+        let policy = TransferPolicy {
+            max_file_size: 100,
+            max_session_bytes: 150,
+            max_daily_bytes: 0,
+            blocked_extensions: vec!["exe".to_owned()],
+        };
+        let conn_id = -12345;
+        assert_eq!(
+            policy.admit(conn_id, "a.bin", 101),
+            Err(Rejection::FileTooLarge {
+                size: 101,
+                max: 100
+            })
+        );
+        assert_eq!(
+            policy.admit(conn_id, "dir/setup.EXE", 1),
+            Err(Rejection::BlockedExtension("exe".to_owned()))
+        );
+        assert!(policy.admit(conn_id, "a.bin", 100).is_ok());
+        // only what is transferred is charged
+        assert!(policy.admit(conn_id, "a.bin", 100).is_ok());
+        assert!(policy.charge(conn_id, 100).is_ok());
+        assert_eq!(
+            policy.admit(conn_id, "b.bin", 60),
+            Err(Rejection::SessionQuotaExceeded { max: 150 })
+        );
+        // larger than declared
+        assert!(policy.admit(conn_id, "b.bin", 10).is_ok());
+        assert_eq!(
+            policy.charge(conn_id, 60),
+            Err(Rejection::SessionQuotaExceeded { max: 150 })
+        );
+        end_session(conn_id);
+        assert!(policy.admit(conn_id, "b.bin", 60).is_ok());
+        end_session(conn_id);
+        let daily = TransferPolicy {
+            max_daily_bytes: 1,
+            ..Default::default()
+        };
+        assert!(daily.admit(conn_id, "c.bin", 1).is_err());
+        // kept across restarts
+        assert!(Usage::load().daily >= 160);
+    }
+}
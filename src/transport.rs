@@ -0,0 +1,144 @@
+use crate::{
+    bail,
+    message_proto::*,
+    protobuf::Message as _,
+    rendezvous_proto::{RelayResponse, RequestRelay},
+    tcp::Encrypt,
+    trace::Span,
+    ResultType, Stream,
+};
+use sodiumoxide::crypto::{auth::hmacsha256, kx, secretbox};
+
+/// Fresh X25519 keys are exchanged inside the existing secure channel and mixed
+/// into the session key, so a relay that later obtains the long-term keys still
+/// cannot decrypt recorded traffic.
+pub const CAP_EPHEMERAL_KX: u32 = 1;
+pub const SUPPORTED_CAPS: u32 = CAP_EPHEMERAL_KX;
+const UPGRADE_TIMEOUT: u64 = 6_000;
+
+#[inline]
+pub fn negotiate(peer_caps: u32) -> u32 {
+    SUPPORTED_CAPS & peer_caps
+}
+
+#[inline]
+pub fn set_relay_request_caps(req: &mut RequestRelay) {
+    req.transport_caps = SUPPORTED_CAPS;
+}
+
+#[inline]
+pub fn set_relay_response_caps(res: &mut RelayResponse) {
+    res.transport_caps = SUPPORTED_CAPS;
+}
+
+// Both directions share one key in `Encrypt`, so the two kx session keys are
+// combined, keyed with the old session key to bind the upgrade to it.
+fn derive_key(old: &secretbox::Key, c2s: &kx::SessionKey, s2c: &kx::SessionKey) -> secretbox::Key {
+    let mut data = Vec::with_capacity(kx::SESSIONKEYBYTES * 2);
+    data.extend_from_slice(&c2s.0);
+    data.extend_from_slice(&s2c.0);
+    let tag = hmacsha256::authenticate(&data, &hmacsha256::Key(old.0));
+    secretbox::Key(tag.0)
+}
+
+fn upgrade_msg(pk: &kx::PublicKey) -> Message {
+    let mut msg = Message::new();
+    msg.set_crypto_upgrade(CryptoUpgrade {
+        caps: SUPPORTED_CAPS,
+        pk: pk.0.to_vec().into(),
+        ..Default::default()
+    });
+    msg
+}
+
+fn to_pk(pk: &[u8]) -> ResultType<kx::PublicKey> {
+    let Some(pk) = kx::PublicKey::from_slice(pk) else {
+        bail!("Invalid crypto upgrade public key");
+    };
+    Ok(pk)
+}
+
+fn current_encrypt(stream: &Stream) -> ResultType<Encrypt> {
+    let Some(encrypt) = stream.get_encrypt() else {
+        bail!("Crypto upgrade requires a secured stream");
+    };
+    Ok(encrypt)
+}
+
+/// Initiating side, called right after the secure handshake.
+/// Returns false if the peer does not support the upgrade, the stream keeps
+/// the handshake key in that case.
+pub async fn upgrade(stream: &mut Stream, peer_caps: u32) -> ResultType<bool> {
+    Span::transport_upgrade()
+        .instrument(upgrade_(stream, peer_caps))
+        .await
+}
+
+async fn upgrade_(stream: &mut Stream, peer_caps: u32) -> ResultType<bool> {
+    if negotiate(peer_caps) & CAP_EPHEMERAL_KX == 0 {
+        return Ok(false);
+    }
+    let encrypt = current_encrypt(stream)?;
+    let (pk, sk) = kx::gen_keypair();
+    stream.send(&upgrade_msg(&pk)).await?;
+    let Some(Ok(bytes)) = stream.next_timeout(UPGRADE_TIMEOUT).await else {
+        bail!("Timeout waiting for crypto upgrade");
+    };
+    let msg = Message::parse_from_bytes(&bytes)?;
+    let Some(message::Union::CryptoUpgrade(res)) = msg.union else {
+        bail!("Unexpected response to crypto upgrade");
+    };
+    if negotiate(res.caps) & CAP_EPHEMERAL_KX == 0 {
+        return Ok(false);
+    }
+    let server_pk = to_pk(&res.pk)?;
+    let Ok((rx, tx)) = kx::client_session_keys(&pk, &sk, &server_pk) else {
+        bail!("Crypto upgrade failed: invalid peer key");
+    };
+    stream.set_encrypt(Encrypt::new(derive_key(&encrypt.0, &tx, &rx)));
+    log::info!("Transport encryption upgraded with ephemeral keys");
+    Ok(true)
+}
+
+/// Responding side, called with the `CryptoUpgrade` received from the peer.
+/// The response is still sealed with the handshake key, the new key takes
+/// effect for everything after it.
+pub async fn accept_upgrade(stream: &mut Stream, req: &CryptoUpgrade) -> ResultType<bool> {
+    let encrypt = current_encrypt(stream)?;
+    if negotiate(req.caps) & CAP_EPHEMERAL_KX == 0 {
+        let mut msg = Message::new();
+        msg.set_crypto_upgrade(CryptoUpgrade::new());
+        stream.send(&msg).await?;
+        return Ok(false);
+    }
+    let (pk, sk) = kx::gen_keypair();
+    let client_pk = to_pk(&req.pk)?;
+    let Ok((rx, tx)) = kx::server_session_keys(&pk, &sk, &client_pk) else {
+        bail!("Crypto upgrade failed: invalid peer key");
+    };
+    stream.send(&upgrade_msg(&pk)).await?;
+    stream.set_encrypt(Encrypt::new(derive_key(&encrypt.0, &rx, &tx)));
+    log::info!("Transport encryption upgraded with ephemeral keys");
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derive_key() {
+        let old = secretbox::gen_key();
+        let (cpk, csk) = kx::gen_keypair();
+        let (spk, ssk) = kx::gen_keypair();
+        let (crx, ctx) = kx::client_session_keys(&cpk, &csk, &spk).unwrap();
+        let (srx, stx) = kx::server_session_keys(&spk, &ssk, &cpk).unwrap();
+        let a = derive_key(&old, &ctx, &crx);
+        let b = derive_key(&old, &srx, &stx);
+        assert_eq!(a, b);
+        assert_ne!(a, old);
+        assert_ne!(a, derive_key(&secretbox::gen_key(), &srx, &stx));
+        assert_eq!(negotiate(0), 0);
+        assert_eq!(negotiate(u32::MAX), SUPPORTED_CAPS);
+    }
+}
@@ -0,0 +1,60 @@
+// What differs in the browser (wasm32): the timers, as those of tokio use
+// std::time::Instant which panics there, and the LocalStorage of
+// `web_config`. The modules not built for wasm32 are listed in lib.rs.
+use crate::{clock::Sleep, web_config::Store};
+use std::time::Duration;
+use wasm_bindgen::JsValue;
+
+/// `setTimeout` of the page. The future is not Send, but there is only the
+/// thread of the page.
+pub(crate) fn sleep(duration: Duration) -> Sleep {
+    let ms = duration.as_millis().min(i32::MAX as u128) as i32;
+    let promise = js_sys::Promise::new(&mut |resolve, _| {
+        let scheduled = web_sys::window().map_or(false, |window| {
+            window
+                .set_timeout_with_callback_and_timeout_and_arguments_0(&resolve, ms)
+                .is_ok()
+        });
+        if !scheduled {
+            log::error!("Failed to set a timeout of {} ms", ms);
+            resolve.call0(&JsValue::NULL).ok();
+        }
+    });
+    let future = wasm_bindgen_futures::JsFuture::from(promise);
+    Box::pin(send_wrapper::SendWrapper::new(async move {
+        future.await.ok();
+    }))
+}
+
+/// `window.localStorage`, the keys prefixed to share the origin with the page.
+pub struct LocalStorage {
+    pub prefix: String,
+}
+
+impl LocalStorage {
+    // not kept, `web_sys::Storage` is not Send
+    fn storage() -> Option<web_sys::Storage> {
+        web_sys::window()?.local_storage().ok()?
+    }
+}
+
+impl Store for LocalStorage {
+    fn get(&self, key: &str) -> Option<String> {
+        Self::storage()?
+            .get_item(&format!("{}{}", self.prefix, key))
+            .ok()?
+    }
+
+    fn set(&self, key: &str, value: &str) {
+        let Some(storage) = Self::storage() else {
+            log::error!("LocalStorage is not available");
+            return;
+        };
+        if storage
+            .set_item(&format!("{}{}", self.prefix, key), value)
+            .is_err()
+        {
+            log::error!("Failed to write {} to LocalStorage", key);
+        }
+    }
+}
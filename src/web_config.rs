@@ -0,0 +1,180 @@
+// The options of a client running in a browser, built for wasm32 where
+// `config::Config` is not available: there is no file system for its files and
+// no libsodium to encrypt them.
+//
+// The options and local options are resolved like `Config::get_option` and
+// `LocalConfig::get_option`, with the overwrite and default settings of
+// `crate::options`, and are kept in a `Store`, the LocalStorage of the page on
+// wasm32 and in memory elsewhere. Passwords and keys are not stored here, the
+// page keeps them in its own protected storage if at all.
+use crate::options::{
+    get_or, is_option_can_save, option2bool, DEFAULT_LOCAL_SETTINGS, DEFAULT_SETTINGS,
+    OVERWRITE_LOCAL_SETTINGS, OVERWRITE_SETTINGS,
+};
+use crate::settings_map::SettingsMap;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+};
+
+const OPTIONS: &str = "options";
+const LOCAL_OPTIONS: &str = "local-options";
+
+pub trait Store: Send + Sync {
+    fn get(&self, key: &str) -> Option<String>;
+    fn set(&self, key: &str, value: &str);
+}
+
+#[derive(Default)]
+pub struct MemoryStore(Mutex<HashMap<String, String>>);
+
+impl Store for MemoryStore {
+    fn get(&self, key: &str) -> Option<String> {
+        self.0.lock().unwrap().get(key).cloned()
+    }
+
+    fn set(&self, key: &str, value: &str) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), value.to_owned());
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub use crate::wasm::LocalStorage;
+
+#[cfg(target_arch = "wasm32")]
+fn default_store() -> Arc<dyn Store> {
+    Arc::new(LocalStorage {
+        prefix: "rustdesk-".to_owned(),
+    })
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn default_store() -> Arc<dyn Store> {
+    Arc::new(MemoryStore::default())
+}
+
+struct State {
+    store: Arc<dyn Store>,
+    options: HashMap<String, String>,
+    local_options: HashMap<String, String>,
+}
+
+impl State {
+    fn load(store: Arc<dyn Store>) -> Self {
+        let load = |key| {
+            store
+                .get(key)
+                .and_then(|v| serde_json::from_str(&v).ok())
+                .unwrap_or_default()
+        };
+        Self {
+            options: load(OPTIONS),
+            local_options: load(LOCAL_OPTIONS),
+            store,
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref STATE: RwLock<State> = RwLock::new(State::load(default_store()));
+}
+
+/// Replaces the store and loads the options from it, e.g. a `LocalStorage` with
+/// the prefix of the app.
+pub fn set_store(store: Arc<dyn Store>) {
+    *STATE.write().unwrap() = State::load(store);
+}
+
+fn set(local: bool, overwrite: &SettingsMap, defaults: &SettingsMap, k: String, v: String) {
+    let mut lock = STATE.write().unwrap();
+    let state = &mut *lock;
+    let options = if local {
+        &mut state.local_options
+    } else {
+        &mut state.options
+    };
+    let changed = if v.is_empty() || !is_option_can_save(overwrite, &k, defaults, &v) {
+        options.remove(&k).is_some()
+    } else if options.get(&k) != Some(&v) {
+        options.insert(k, v);
+        true
+    } else {
+        false
+    };
+    if changed {
+        let v = serde_json::to_string(options).unwrap_or_default();
+        state
+            .store
+            .set(if local { LOCAL_OPTIONS } else { OPTIONS }, &v);
+    }
+}
+
+pub fn get_option(k: &str) -> String {
+    get_or(
+        &OVERWRITE_SETTINGS,
+        &STATE.read().unwrap().options,
+        &DEFAULT_SETTINGS,
+        k,
+    )
+    .unwrap_or_default()
+}
+
+#[inline]
+pub fn get_bool_option(k: &str) -> bool {
+    option2bool(k, &get_option(k))
+}
+
+pub fn set_option(k: String, v: String) {
+    set(false, &OVERWRITE_SETTINGS, &DEFAULT_SETTINGS, k, v);
+}
+
+pub fn get_options() -> HashMap<String, String> {
+    let mut res = DEFAULT_SETTINGS.read().unwrap().clone();
+    res.extend(STATE.read().unwrap().options.clone());
+    res.extend(OVERWRITE_SETTINGS.read().unwrap().clone());
+    res
+}
+
+pub fn get_local_option(k: &str) -> String {
+    get_or(
+        &OVERWRITE_LOCAL_SETTINGS,
+        &STATE.read().unwrap().local_options,
+        &DEFAULT_LOCAL_SETTINGS,
+        k,
+    )
+    .unwrap_or_default()
+}
+
+pub fn set_local_option(k: String, v: String) {
+    set(
+        true,
+        &OVERWRITE_LOCAL_SETTINGS,
+        &DEFAULT_LOCAL_SETTINGS,
+        k,
+        v,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_web_options() {
+        let store = Arc::new(MemoryStore::default());
+        set_store(store.clone());
+        set_option("web-test-server".to_owned(), "rs.example.com".to_owned());
+        set_local_option("theme".to_owned(), "dark".to_owned());
+        assert_eq!(get_option("web-test-server"), "rs.example.com");
+        assert!(!get_bool_option("allow-websocket"));
+        // reloaded from the store, e.g. a reload of the page
+        set_store(store.clone());
+        assert_eq!(get_option("web-test-server"), "rs.example.com");
+        assert_eq!(get_local_option("theme"), "dark");
+        set_option("web-test-server".to_owned(), "".to_owned());
+        assert_eq!(store.get(OPTIONS).as_deref(), Some("{}"));
+    }
+}
@@ -1,6 +1,8 @@
 use crate::{
+    branding,
     config::keys::OPTION_RELAY_SERVER,
-    config::{use_ws, Config, Socks5Server, RELAY_PORT, RENDEZVOUS_PORT},
+    config::{use_ws, Config, Socks5Server},
+    metrics::{GaugeGuard, ACTIVE_CONNECTIONS},
     protobuf::Message,
     socket_client::split_host_port,
     sodiumoxide::crypto::secretbox::Key,
@@ -26,6 +28,7 @@ pub struct WsFramedStream {
     addr: SocketAddr,
     encrypt: Option<Encrypt>,
     send_timeout: u64,
+    _active: GaugeGuard,
 }
 
 impl WsFramedStream {
@@ -60,6 +63,7 @@ impl WsFramedStream {
             addr,
             encrypt: None,
             send_timeout: ms_timeout,
+            _active: ACTIVE_CONNECTIONS.track(),
         };
 
         Ok(ws)
@@ -81,6 +85,7 @@ impl WsFramedStream {
             addr,
             encrypt: None,
             send_timeout: 0,
+            _active: ACTIVE_CONNECTIONS.track(),
         })
     }
 
@@ -99,6 +104,16 @@ impl WsFramedStream {
         self.encrypt = Some(Encrypt::new(key));
     }
 
+    #[inline]
+    pub fn get_encrypt(&self) -> Option<Encrypt> {
+        self.encrypt.clone()
+    }
+
+    #[inline]
+    pub fn set_encrypt(&mut self, encrypt: Encrypt) {
+        self.encrypt = Some(encrypt);
+    }
+
     #[inline]
     pub fn is_secured(&self) -> bool {
         self.encrypt.is_some()
@@ -218,10 +233,10 @@ pub fn check_ws(endpoint: &str) -> String {
     let relay_server = Config::get_option(OPTION_RELAY_SERVER);
     let rendezvous_port = split_host_port(&custom_rendezvous_server)
         .map(|(_, p)| p)
-        .unwrap_or(RENDEZVOUS_PORT);
+        .unwrap_or_else(branding::rendezvous_port);
     let relay_port = split_host_port(&relay_server)
         .map(|(_, p)| p)
-        .unwrap_or(RELAY_PORT);
+        .unwrap_or_else(branding::relay_port);
 
     let (relay, dst_port) = if endpoint_port == rendezvous_port {
         // rendezvous
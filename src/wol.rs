@@ -0,0 +1,385 @@
+// Wake-on-LAN for the peers found by LAN discovery, see `config::LanPeers`.
+//
+// The magic packet, 6 bytes of 0xFF followed by the MAC 16 times, is broadcast on
+// the WOL ports. Routers do not forward broadcasts, so a target on another subnet
+// is woken by an online peer of its subnet instead: the peer gets a
+// `PeerDiscovery` with `CMD` on its discovery port, and broadcasts the packet on
+// its network with `handle_discovery`.
+//
+// A request makes the peer broadcast, so it is only served if signed by one of
+// its trusted devices, recent, for at most MAX_MACS MACs, and at most RATE_LIMIT
+// times per RATE_WINDOW.
+use crate::{
+    bail, branding,
+    config::{Config, DiscoveryPeer, LanPeers, TrustedDevice},
+    lan::{get_local_nets, in_same_subnet, LocalNet},
+    protobuf::Message as _,
+    rendezvous_proto::{PeerDiscovery, RendezvousMessage},
+    ResultType,
+};
+use serde_derive::{Deserialize, Serialize};
+use sodiumoxide::{base64, crypto::sign};
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// The `cmd` of a `PeerDiscovery` asking to wake the MACs in `mac`, comma separated.
+pub const CMD: &str = "wol";
+pub const MAX_MACS: usize = 8;
+const RATE_LIMIT: usize = 5;
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+/// ms, how old a request may be.
+const MAX_AGE: i64 = 60_000;
+/// The discard and echo ports, the usual ones of network cards.
+const PORTS: [u16; 2] = [9, 7];
+/// The prefix assumed for the subnet of a peer, its netmask is not discovered.
+const PEER_PREFIX: u8 = 24;
+
+pub type Mac = [u8; 6];
+
+lazy_static::lazy_static! {
+    static ref SERVED: Mutex<VecDeque<Instant>> = Default::default();
+}
+
+/// In `misc` of a request, JSON.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Auth {
+    /// The id of the requesting peer.
+    from: String,
+    /// ms
+    time: i64,
+    /// base64 of the detached signature of `signed_data`.
+    sig: String,
+}
+
+/// "aa:bb:cc:dd:ee:ff" or "AA-BB-CC-DD-EE-FF".
+pub fn parse_mac(s: &str) -> Option<Mac> {
+    let mut mac = [0u8; 6];
+    let mut parts = s.trim().split(|c| c == ':' || c == '-');
+    for x in mac.iter_mut() {
+        let part = parts.next()?;
+        if part.len() != 2 {
+            return None;
+        }
+        *x = u8::from_str_radix(part, 16).ok()?;
+    }
+    if parts.next().is_some() || mac == [0u8; 6] {
+        return None;
+    }
+    Some(mac)
+}
+
+pub fn magic_packet(mac: &Mac) -> [u8; 102] {
+    let mut packet = [0xFFu8; 102];
+    for chunk in packet[6..].chunks_exact_mut(6) {
+        chunk.copy_from_slice(mac);
+    }
+    packet
+}
+
+fn broadcast_v4(ip: Ipv4Addr, prefix: u8) -> Ipv4Addr {
+    let host = u32::MAX.checked_shr(prefix as u32).unwrap_or(0);
+    Ipv4Addr::from(u32::from(ip) | host)
+}
+
+/// The broadcast addresses the packets are sent to: the limited broadcast, and
+/// the directed broadcasts of the local subnets, which leave through the right
+/// interface on hosts with several.
+fn broadcast_addrs(nets: &[LocalNet]) -> Vec<Ipv4Addr> {
+    let mut addrs = vec![Ipv4Addr::BROADCAST];
+    for (ip, prefix) in nets {
+        if let IpAddr::V4(ip) = ip {
+            let addr = broadcast_v4(*ip, *prefix);
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+    addrs
+}
+
+/// Broadcasts the magic packets of `macs` on the local networks, returns the
+/// number of datagrams sent.
+pub fn send(macs: &[Mac]) -> ResultType<usize> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    let mut n = 0;
+    for addr in broadcast_addrs(&get_local_nets()) {
+        for port in PORTS.iter().copied() {
+            for mac in macs {
+                match socket.send_to(&magic_packet(mac), (addr, port)) {
+                    Ok(_) => n += 1,
+                    Err(err) => log::debug!("Failed to send magic packet to {}: {}", addr, err),
+                }
+            }
+        }
+    }
+    if n == 0 {
+        bail!("Failed to send magic packets");
+    }
+    Ok(n)
+}
+
+/// Where LAN discovery listens, the same port for all peers.
+#[inline]
+pub fn discovery_port() -> u16 {
+    (branding::rendezvous_port() + 3) as u16
+}
+
+fn peer_ips(peer: &DiscoveryPeer) -> Vec<IpAddr> {
+    peer.ip_mac
+        .keys()
+        .filter_map(|ip| ip.parse().ok())
+        .collect()
+}
+
+/// The online peers on the subnets of `target`, with their addresses there.
+fn relays<'a>(target: &DiscoveryPeer, peers: &'a [DiscoveryPeer]) -> Vec<(&'a str, IpAddr)> {
+    let target_ips = peer_ips(target);
+    let mut res = vec![];
+    for peer in peers {
+        if !peer.online || peer.is_same_peer(target) {
+            continue;
+        }
+        for ip in peer_ips(peer) {
+            if target_ips
+                .iter()
+                .any(|t| *t != ip && in_same_subnet(*t, ip, PEER_PREFIX))
+            {
+                res.push((peer.id.as_str(), ip));
+            }
+        }
+    }
+    res
+}
+
+/// Wakes the peer of `peer_id` found by LAN discovery, for the "wake" button of
+/// a peer card. The packets are broadcast here, and sent by the online peers of
+/// its subnet if it is not on ours. Whether it wakes up is not known, the caller
+/// watches its online state.
+pub fn wake(peer_id: &str) -> ResultType<()> {
+    let peers = LanPeers::load().peers;
+    let Some(target) = peers.iter().find(|p| p.id == peer_id) else {
+        bail!("Peer {} is not found on LAN", peer_id);
+    };
+    let macs: Vec<Mac> = target
+        .ip_mac
+        .values()
+        .filter_map(|m| parse_mac(m))
+        .collect();
+    if macs.is_empty() {
+        bail!("No MAC address of peer {}", peer_id);
+    }
+    let mut sent = send(&macs).is_ok();
+    let nets = get_local_nets();
+    let on_our_subnet = peer_ips(target).iter().any(|ip| {
+        nets.iter()
+            .any(|(local, prefix)| in_same_subnet(*local, *ip, *prefix))
+    });
+    if !on_our_subnet {
+        let mut msg = RendezvousMessage::new();
+        msg.set_peer_discovery(PeerDiscovery {
+            cmd: CMD.to_owned(),
+            mac: target
+                .ip_mac
+                .values()
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(","),
+            id: peer_id.to_owned(),
+            ..Default::default()
+        });
+        let mut discovery = msg.take_peer_discovery();
+        let (sk, _) = Config::get_key_pair();
+        if !sign_request(&mut discovery, &Config::get_id(), &sk, crate::get_time()) {
+            bail!("Failed to sign the request to wake {}", peer_id);
+        }
+        msg.set_peer_discovery(discovery);
+        let data = msg.write_to_bytes()?;
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        for (id, ip) in relays(target, &peers) {
+            match socket.send_to(&data, SocketAddr::new(ip, discovery_port())) {
+                Ok(_) => {
+                    log::info!("Asked {} to wake {}", id, peer_id);
+                    sent = true;
+                }
+                Err(err) => log::debug!("Failed to ask {} to wake {}: {}", id, peer_id, err),
+            }
+        }
+    }
+    if !sent {
+        bail!("Failed to wake {}", peer_id);
+    }
+    Ok(())
+}
+
+fn signed_data(msg: &PeerDiscovery, from: &str, time: i64) -> Vec<u8> {
+    format!("{}\n{}\n{}\n{}\n{}", msg.cmd, msg.mac, msg.id, from, time).into_bytes()
+}
+
+/// Signs `msg` as peer `from` with its sign key `sk`.
+fn sign_request(msg: &mut PeerDiscovery, from: &str, sk: &[u8], now: i64) -> bool {
+    let Some(sk) = sign::SecretKey::from_slice(sk) else {
+        return false;
+    };
+    let sig = sign::sign_detached(&signed_data(msg, from, now), &sk);
+    let auth = Auth {
+        from: from.to_owned(),
+        time: now,
+        sig: base64::encode(sig.to_bytes(), base64::Variant::Original),
+    };
+    msg.misc = serde_json::to_string(&auth).unwrap_or_default();
+    true
+}
+
+/// Ok if `msg` is recent and signed by one of `devices`.
+fn authenticate(msg: &PeerDiscovery, devices: &[TrustedDevice], now: i64) -> ResultType<()> {
+    let auth: Auth = serde_json::from_str(&msg.misc)?;
+    if (now - auth.time).abs() > MAX_AGE {
+        bail!("Outdated request of {}", auth.from);
+    }
+    let Some(sig) = base64::decode(&auth.sig, base64::Variant::Original)
+        .ok()
+        .and_then(|x| sign::Signature::from_bytes(&x).ok())
+    else {
+        bail!("Invalid signature of {}", auth.from);
+    };
+    let data = signed_data(msg, &auth.from, auth.time);
+    let verified = devices
+        .iter()
+        .filter(|d| d.id == auth.from && !d.outdate())
+        .filter_map(|d| sign::PublicKey::from_slice(&d.pk))
+        .any(|pk| sign::verify_detached(&sig, &data, &pk));
+    if !verified {
+        bail!("{} is not a trusted device", auth.from);
+    }
+    Ok(())
+}
+
+/// Whether one more request may be served at `now`.
+fn take(served: &mut VecDeque<Instant>, now: Instant) -> bool {
+    while served
+        .front()
+        .map_or(false, |x| now.duration_since(*x) >= RATE_WINDOW)
+    {
+        served.pop_front();
+    }
+    if served.len() >= RATE_LIMIT {
+        return false;
+    }
+    served.push_back(now);
+    true
+}
+
+/// Handles a `PeerDiscovery` received by the discovery listener, returns false
+/// if it is not a request to wake a peer.
+pub fn handle_discovery(msg: &PeerDiscovery) -> bool {
+    if msg.cmd != CMD {
+        return false;
+    }
+    if msg.mac.split(',').count() > MAX_MACS {
+        log::warn!("Ignored a request to wake more than {} MACs", MAX_MACS);
+        return true;
+    }
+    if let Err(err) = authenticate(msg, &Config::get_trusted_devices(), crate::get_time()) {
+        log::warn!("Ignored a request to wake {}: {}", msg.id, err);
+        return true;
+    }
+    if !take(&mut SERVED.lock().unwrap(), crate::clock::instant()) {
+        log::warn!("Ignored a request to wake {}, too many requests", msg.id);
+        return true;
+    }
+    let macs: Vec<Mac> = msg.mac.split(',').filter_map(parse_mac).collect();
+    if !macs.is_empty() {
+        log::info!("Waking {} for a peer", msg.id);
+        if let Err(err) = send(&macs) {
+            log::error!("Failed to wake {}: {}", msg.id, err);
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(id: &str, online: bool, ips: &[&str]) -> DiscoveryPeer {
+        DiscoveryPeer {
+            id: id.to_owned(),
+            online,
+            ip_mac: ips
+                .iter()
+                .map(|ip| (ip.to_string(), "aa:bb:cc:dd:ee:ff".to_owned()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_magic_packet() {
+        let mac = parse_mac("AA-bb-cc-dd-ee-01").unwrap();
+        assert_eq!(mac, [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x01]);
+        assert_eq!(parse_mac("aa:bb:cc:dd:ee"), None);
+        assert_eq!(parse_mac("aa:bb:cc:dd:ee:ff:00"), None);
+        assert_eq!(parse_mac("00:00:00:00:00:00"), None);
+        let packet = magic_packet(&mac);
+        assert_eq!(&packet[..6], &[0xFF; 6]);
+        assert!(packet[6..].chunks(6).all(|c| c == mac));
+    }
+
+    #[test]
+    fn test_relays() {
+        let target = peer("1", false, &["192.168.2.10"]);
+        let peers = vec![
+            target.clone(),
+            peer("2", true, &["192.168.2.20", "10.0.0.2"]),
+            peer("3", false, &["192.168.2.30"]),
+            peer("4", true, &["192.168.1.40"]),
+        ];
+        let ip: IpAddr = "192.168.2.20".parse().unwrap();
+        assert_eq!(relays(&target, &peers), vec![("2", ip)]);
+        let nets = vec![("192.168.1.5".parse().unwrap(), 24)];
+        assert_eq!(
+            broadcast_addrs(&nets),
+            vec![Ipv4Addr::BROADCAST, Ipv4Addr::new(192, 168, 1, 255)]
+        );
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let (pk, sk) = sign::gen_keypair();
+        let now = crate::get_time();
+        let devices = vec![TrustedDevice {
+            id: "2".to_owned(),
+            time: now,
+            pk: pk.0.to_vec().into(),
+            ..Default::default()
+        }];
+        let mut msg = PeerDiscovery {
+            cmd: CMD.to_owned(),
+            mac: "aa:bb:cc:dd:ee:ff".to_owned(),
+            id: "1".to_owned(),
+            ..Default::default()
+        };
+        assert!(authenticate(&msg, &devices, now).is_err());
+        assert!(sign_request(&mut msg, "2", &sk.0, now));
+        assert!(authenticate(&msg, &devices, now).is_ok());
+        assert!(authenticate(&msg, &devices, now + MAX_AGE + 1).is_err());
+        assert!(authenticate(&msg, &[], now).is_err());
+        let mut tampered = msg.clone();
+        tampered.mac = "aa:bb:cc:dd:ee:01".to_owned();
+        assert!(authenticate(&tampered, &devices, now).is_err());
+        let (_, other) = sign::gen_keypair();
+        assert!(sign_request(&mut msg, "2", &other.0, now));
+        assert!(authenticate(&msg, &devices, now).is_err());
+
+        let mut served = VecDeque::new();
+        let start = Instant::now();
+        assert!((0..RATE_LIMIT).all(|_| take(&mut served, start)));
+        assert!(!take(&mut served, start + Duration::from_secs(1)));
+        assert!(take(&mut served, start + RATE_WINDOW));
+    }
+}